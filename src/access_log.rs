@@ -0,0 +1,327 @@
+//! The access log: one line per request, in Apache's combined log format
+//! or as JSON, for the tools ops already point at web server logs. It is
+//! kept apart from the metrics log, which carries tokens and routing, and
+//! from the application log.
+
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::body::{Body, HttpBody};
+use axum::extract::Request;
+use axum::response::Response;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body::{Frame, SizeHint};
+use serde_json::json;
+
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::listeners::Ingress;
+use crate::metrics_log::RotatingFile;
+use crate::scrub::Scrubber;
+
+/// What the access log says about one request. The proxy fills in the
+/// model, provider, and key as it learns them.
+#[derive(Debug, Clone)]
+pub struct AccessEntry {
+    pub request_id: String,
+    pub time: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub listener: Option<String>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// The virtual key presented, by name.
+    pub key: Option<String>,
+    /// Who sent the request, as the TUI's Clients tab groups it.
+    pub client: Option<String>,
+    /// The `croxy bench` run the request belongs to.
+    pub bench: Option<String>,
+    pub model: Option<String>,
+    pub provider: Option<String>,
+    pub status: u16,
+    pub duration: Duration,
+    /// Response body bytes sent to the client.
+    pub bytes: u64,
+    start: Instant,
+}
+
+impl AccessEntry {
+    pub fn new(request_id: &str, request: &Request) -> Self {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            request_id: request_id.to_string(),
+            time: Utc::now(),
+            method: request.method().to_string(),
+            path: request
+                .uri()
+                .path_and_query()
+                .map_or_else(|| request.uri().path(), |pq| pq.as_str())
+                .to_string(),
+            version: format!("{:?}", request.version()),
+            listener: request
+                .extensions()
+                .get::<Ingress>()
+                .and_then(|ingress| ingress.tag.clone()),
+            user_agent: header(http::header::USER_AGENT),
+            referer: header(http::header::REFERER),
+            key: None,
+            client: None,
+            bench: header(http::header::HeaderName::from_static(crate::bench::HEADER)),
+            model: None,
+            provider: None,
+            status: 0,
+            duration: Duration::ZERO,
+            bytes: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// The entry as a line in `format`.
+    pub fn line(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Combined => format!(
+                "- - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" request_id={} model=\"{}\" provider={} duration_ms={}",
+                self.key.as_deref().unwrap_or("-"),
+                self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                quoted(&self.path),
+                self.version,
+                self.status,
+                self.bytes,
+                quoted(self.referer.as_deref().unwrap_or("-")),
+                quoted(self.user_agent.as_deref().unwrap_or("-")),
+                self.request_id,
+                quoted(self.model.as_deref().unwrap_or("-")),
+                self.provider.as_deref().unwrap_or("-"),
+                self.duration.as_millis(),
+            ),
+            AccessLogFormat::Json => json!({
+                "ts": self.time,
+                "request_id": self.request_id,
+                "method": self.method,
+                "path": self.path,
+                "version": self.version,
+                "status": self.status,
+                "bytes": self.bytes,
+                "duration_ms": self.duration.as_millis() as u64,
+                "model": self.model,
+                "provider": self.provider,
+                "key": self.key,
+                "client": self.client,
+                "listener": self.listener,
+                "user_agent": self.user_agent,
+                "referer": self.referer,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Escapes a value for a quoted combined-log field.
+fn quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Where access lines go. Disabled logs drop them.
+#[derive(Default)]
+pub struct AccessLog {
+    file: Option<Mutex<RotatingFile>>,
+    format: AccessLogFormat,
+    scrubber: Arc<Scrubber>,
+}
+
+impl AccessLog {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Opens `[logging.access]`'s file, or a disabled log when it is off.
+    /// Lines pass through `scrubber`, since paths can carry keys.
+    pub fn from_config(config: &AccessLogConfig, scrubber: Arc<Scrubber>) -> io::Result<Self> {
+        if !config.enabled {
+            return Ok(Self::disabled());
+        }
+        let file = RotatingFile::open(
+            PathBuf::from(&config.path),
+            config.max_size_mb * 1024 * 1024,
+            config.max_files,
+            None,
+        )?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+            format: config.format,
+            scrubber,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Writes `entry` now, timing it from when the request arrived.
+    pub fn record(&self, mut entry: AccessEntry) {
+        let Some(ref file) = self.file else {
+            return;
+        };
+        entry.duration = entry.start.elapsed();
+        let line = entry.line(self.format);
+        let line = self.scrubber.scrub(&line);
+        if let Err(e) = file
+            .lock()
+            .expect("access log lock poisoned")
+            .write_line(&line)
+        {
+            tracing::warn!("failed to write access log: {e}");
+        }
+    }
+
+    /// Writes `entry` once `response`'s body has been sent, or the client
+    /// has gone, so streams are logged with their full duration and size.
+    pub fn finish(self: &Arc<Self>, mut entry: AccessEntry, response: Response) -> Response {
+        if !self.is_enabled() {
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        entry.status = parts.status.as_u16();
+        let body = Counted {
+            inner: body,
+            pending: Some((self.clone(), entry)),
+            bytes: 0,
+        };
+        Response::from_parts(parts, Body::new(body))
+    }
+}
+
+/// A response body that counts what passes through it and writes the
+/// access line when it ends or is dropped.
+struct Counted {
+    inner: Body,
+    pending: Option<(Arc<AccessLog>, AccessEntry)>,
+    bytes: u64,
+}
+
+impl Counted {
+    fn done(&mut self) {
+        if let Some((log, mut entry)) = self.pending.take() {
+            entry.bytes = self.bytes;
+            log.record(entry);
+        }
+    }
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match poll {
+            Poll::Ready(Some(Ok(ref frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.bytes += data.len() as u64;
+                }
+            }
+            Poll::Ready(None) => self.done(),
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/v1/messages?beta=true")
+            .header("user-agent", "claude-cli/2.0 \"test\"")
+            .body(Body::empty())
+            .unwrap();
+        let mut entry = AccessEntry::new("1a-7", &request);
+        entry.time = "2026-10-16T09:30:05Z".parse().unwrap();
+        entry.model = Some("claude-opus-4-6".to_string());
+        entry.provider = Some("anthropic".to_string());
+        entry.status = 200;
+        entry.bytes = 512;
+        entry.duration = Duration::from_millis(1523);
+        entry
+    }
+
+    #[test]
+    fn combined_lines_follow_apache_with_croxy_fields_appended() {
+        assert_eq!(
+            entry().line(AccessLogFormat::Combined),
+            "- - - [16/Oct/2026:09:30:05 +0000] \"POST /v1/messages?beta=true HTTP/1.1\" 200 512 \
+             \"-\" \"claude-cli/2.0 \\\"test\\\"\" request_id=1a-7 model=\"claude-opus-4-6\" \
+             provider=anthropic duration_ms=1523"
+        );
+    }
+
+    #[test]
+    fn json_lines_carry_every_field() {
+        let mut entry = entry();
+        entry.key = Some("ci".to_string());
+        let line: serde_json::Value =
+            serde_json::from_str(&entry.line(AccessLogFormat::Json)).unwrap();
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 512);
+        assert_eq!(line["duration_ms"], 1523);
+        assert_eq!(line["provider"], "anthropic");
+        assert_eq!(line["key"], "ci");
+        assert_eq!(line["listener"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn responses_are_logged_once_their_body_is_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let config = AccessLogConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            format: AccessLogFormat::Json,
+            ..Default::default()
+        };
+        let log = Arc::new(AccessLog::from_config(&config, Arc::default()).unwrap());
+        let response = log.finish(entry(), Response::new(Body::from("hello")));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let line: serde_json::Value =
+            serde_json::from_str(std::fs::read_to_string(&path).unwrap().trim()).unwrap();
+        assert_eq!(line["bytes"], 5);
+        assert_eq!(line["status"], 200);
+    }
+}
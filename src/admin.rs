@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Extension, Query, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::balance::Health;
+use crate::body_sizes::BodySizeReport;
+use crate::config::{Config, ServerConfig};
+use crate::control::{self, Command, Controller, Reply, Viewers};
+use crate::keys::{self, KeyStore, QuotaUsage};
+use crate::listeners::ConnectionStreams;
+use crate::metrics::MetricsStore;
+use crate::ratelimits::RateLimit;
+use crate::tenants::TenantUsage;
+use crate::tokens::AuthStatus;
+use crate::warm::Warmth;
+
+/// Path prefix for the runtime control endpoints served on the proxy
+/// listeners. Requests under it never reach a provider.
+pub const PREFIX: &str = "/_croxy";
+
+/// Answer from endpoints only served with `admin.token` set.
+pub const NEEDS_TOKEN: &str = "this endpoint is only served with admin.token set";
+
+/// Whether to serve the admin API with `token`: not when `[admin]` is off,
+/// nor without a token when a listener is reachable from other machines.
+pub fn serves(config: &Config, token: Option<&str>) -> bool {
+    if !config.admin.enabled {
+        return false;
+    }
+    if token.is_none() {
+        if let Some(address) = exposed_address(&config.server) {
+            warn!(
+                %address,
+                "not serving the admin API: set admin.token to serve it beyond loopback"
+            );
+            return false;
+        }
+        info!("admin.token is unset, so remote attach and admin commands are off");
+    }
+    true
+}
+
+/// A TCP address in `server` that other machines can reach. Without a
+/// token the admin API isn't served when there is one, as anyone who can
+/// reach it could drive croxy.
+pub fn exposed_address(server: &ServerConfig) -> Option<String> {
+    let listeners = server.listeners.iter().filter_map(|l| l.address.clone());
+    server
+        .tcp
+        .then(|| format!("{}:{}", server.host, server.port))
+        .into_iter()
+        .chain(listeners)
+        .find(|address| {
+            let host = address
+                .rsplit_once(':')
+                .map_or(address.as_str(), |(host, _)| host);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            host != "localhost" && !host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+        })
+}
+
+pub struct AdminState {
+    pub metrics: Arc<MetricsStore>,
+    /// Bearer token required on every admin request, when set.
+    pub token: Option<String>,
+    /// Flips to true when the server starts draining, ending open streams.
+    pub shutdown: watch::Receiver<bool>,
+    pub viewers: Viewers,
+    /// Runs viewer commands; `None` when the embedding binary doesn't
+    /// support them.
+    pub controller: Option<Arc<Controller>>,
+    pub audit: Arc<AuditLog>,
+    /// Virtual keys, whose holders may ask for their own status.
+    pub keys: Arc<KeyStore>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub version: String,
+    pub viewers: usize,
+    #[serde(default)]
+    pub tool_results_truncated: u64,
+    #[serde(default)]
+    pub tool_calls_repaired: u64,
+    #[serde(default)]
+    pub classifications_skipped: u64,
+    #[serde(default)]
+    pub requests_deduplicated: u64,
+    #[serde(default)]
+    pub streams_stalled: u64,
+    #[serde(default)]
+    pub stream_stall_ms: u64,
+    #[serde(default)]
+    pub connections_rejected: u64,
+    /// Client connections with requests in flight, and how many each
+    /// carries.
+    #[serde(default)]
+    pub connections: Vec<ConnectionStreams>,
+    /// The latest rate limits each provider reported.
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimit>,
+    /// The latest warm-up ping to each provider.
+    #[serde(default)]
+    pub warmth: Vec<Warmth>,
+    /// Each provider's recent latency and error rate.
+    #[serde(default)]
+    pub health: Vec<Health>,
+    /// Token use of each client with a quota.
+    #[serde(default)]
+    pub quotas: Vec<QuotaUsage>,
+    /// The latest token fetch of each provider with `token_command` or
+    /// `oauth`, or on Vertex AI.
+    #[serde(default)]
+    pub auth: Vec<AuthStatus>,
+    /// How close bodies have come to `server.max_body_size`.
+    #[serde(default)]
+    pub body_sizes: Option<BodySizeReport>,
+    /// Responses the client went away from before the end, by model.
+    #[serde(default)]
+    pub client_aborts: BTreeMap<String, u64>,
+    /// Usage of each virtual key. A status scoped to one tenant carries
+    /// only its entry and leaves everything else empty.
+    #[serde(default)]
+    pub tenants: Vec<TenantUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatusQuery {
+    /// Scope the status to the virtual key of this name.
+    pub tenant: Option<String>,
+}
+
+/// The tenant a virtual key holder's admin request is scoped to.
+#[derive(Debug, Clone)]
+struct Tenant(String);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RetentionSettings {
+    pub retention_minutes: u64,
+    pub display_minutes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionUpdate {
+    pub retention_minutes: Option<u64>,
+    pub display_minutes: Option<u64>,
+}
+
+/// Builds the admin router, to be nested at [`PREFIX`].
+pub fn router(state: Arc<AdminState>) -> Router {
+    Router::new()
+        .route("/retention", get(get_retention).put(put_retention))
+        .route("/metrics", get(get_metrics))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .merge(
+            Router::new()
+                .route("/stream", get(stream))
+                .route("/command", post(command))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_configured_token,
+                )),
+        )
+        .merge(Router::new().route("/status", get(get_status)).route_layer(
+            middleware::from_fn_with_state(state.clone(), require_token_or_key),
+        ))
+        .with_state(state)
+}
+
+async fn require_token(
+    State(state): State<Arc<AdminState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !authorized(&state, &request) {
+        return unauthorized(&state, &request);
+    }
+    next.run(request).await
+}
+
+/// Like [`require_token`], but with no token set the endpoint isn't served
+/// at all: commands and the record stream are for the admin alone.
+async fn require_configured_token(
+    State(state): State<Arc<AdminState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.token.is_none() {
+        return (StatusCode::NOT_FOUND, NEEDS_TOKEN).into_response();
+    }
+    require_token(State(state), request, next).await
+}
+
+/// Like [`require_token`], but also lets in the holder of a virtual key,
+/// scoped to their own tenant.
+async fn require_token_or_key(
+    State(state): State<Arc<AdminState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !authorized(&state, &request) {
+        let Some(key) = keys::presented(request.headers()).and_then(|k| state.keys.identify(k))
+        else {
+            return unauthorized(&state, &request);
+        };
+        request.extensions_mut().insert(Tenant(key.name));
+    }
+    next.run(request).await
+}
+
+/// Whether `request` carries the admin token, or none is required.
+fn authorized(state: &AdminState, request: &Request) -> bool {
+    let Some(ref token) = state.token else {
+        return true;
+    };
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token)
+}
+
+fn unauthorized(state: &AdminState, request: &Request) -> Response {
+    state.audit.record(AuditEvent::AdminAuthFailed {
+        path: request.uri().path().to_string(),
+    });
+    (StatusCode::UNAUTHORIZED, "admin token required").into_response()
+}
+
+fn retention_settings(metrics: &MetricsStore) -> RetentionSettings {
+    RetentionSettings {
+        retention_minutes: metrics.retention().as_secs() / 60,
+        display_minutes: metrics.window_minutes(),
+    }
+}
+
+async fn get_status(
+    State(state): State<Arc<AdminState>>,
+    Query(query): Query<StatusQuery>,
+    tenant: Option<Extension<Tenant>>,
+) -> Result<Json<Status>, (StatusCode, String)> {
+    let version = env!("CARGO_PKG_VERSION").to_string();
+    let names = state.keys.names();
+    // A key holder sees only their own tenant, whatever they ask for
+    if let Some(name) = tenant.map(|Extension(Tenant(name))| name).or(query.tenant) {
+        if !names.contains(&name) {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("no virtual key named '{name}'"),
+            ));
+        }
+        return Ok(Json(Status {
+            version,
+            tenants: vec![tenant_usage(&state, &name)],
+            ..Status::default()
+        }));
+    }
+    let (streams_stalled, stall) = state.metrics.stalled_streams();
+    Ok(Json(Status {
+        version,
+        viewers: state.viewers.count(),
+        tool_results_truncated: state.metrics.tool_results_truncated(),
+        tool_calls_repaired: state.metrics.tool_calls_repaired(),
+        classifications_skipped: state.metrics.classifications_skipped(),
+        requests_deduplicated: state.metrics.requests_deduplicated(),
+        streams_stalled,
+        stream_stall_ms: stall.as_millis() as u64,
+        connections_rejected: state.metrics.connections_rejected(),
+        connections: state.metrics.connections(),
+        rate_limits: state.metrics.rate_limits(),
+        warmth: state.metrics.warmth(),
+        health: state.metrics.health(),
+        quotas: state.metrics.quotas(),
+        auth: state.metrics.auth(),
+        body_sizes: state.metrics.body_sizes(),
+        client_aborts: state.metrics.client_aborts(),
+        tenants: names
+            .iter()
+            .map(|name| tenant_usage(&state, name))
+            .collect(),
+    }))
+}
+
+fn tenant_usage(state: &AdminState, name: &str) -> TenantUsage {
+    let budget_usd = state
+        .keys
+        .budgets()
+        .into_iter()
+        .find(|(key, _, _)| key == name)
+        .map(|(_, _, budget)| budget);
+    TenantUsage {
+        spent_usd: state.keys.spent(name),
+        budget_usd,
+        quota: state
+            .keys
+            .quota_usage()
+            .into_iter()
+            .find(|quota| quota.client == name),
+        ..TenantUsage::from_records(name, &state.metrics.retained())
+    }
+}
+
+/// Request counts and latencies for Prometheus to scrape, when
+/// `[telemetry.prometheus]` is enabled.
+async fn get_metrics(State(state): State<Arc<AdminState>>) -> Response {
+    match state.metrics.prometheus() {
+        Some(text) => ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_retention(State(state): State<Arc<AdminState>>) -> Json<RetentionSettings> {
+    Json(retention_settings(&state.metrics))
+}
+
+async fn put_retention(
+    State(state): State<Arc<AdminState>>,
+    Json(update): Json<RetentionUpdate>,
+) -> Result<Json<RetentionSettings>, (StatusCode, String)> {
+    if update.retention_minutes == Some(0) || update.display_minutes == Some(0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "minutes must be greater than 0".to_string(),
+        ));
+    }
+    if let Some(minutes) = update.retention_minutes {
+        state
+            .metrics
+            .set_retention(Duration::from_secs(minutes.saturating_mul(60)));
+    }
+    if let Some(minutes) = update.display_minutes {
+        state
+            .metrics
+            .set_window(Duration::from_secs(minutes.saturating_mul(60)));
+    }
+    let settings = retention_settings(&state.metrics);
+    tracing::info!(
+        retention_minutes = settings.retention_minutes,
+        display_minutes = settings.display_minutes,
+        "retention updated"
+    );
+    Ok(Json(settings))
+}
+
+/// The control stream for remote `croxy attach --host`: a snapshot, then
+/// new and updated records, one JSON message per line.
+async fn stream(State(state): State<Arc<AdminState>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(control::body_stream(
+            state.metrics.clone(),
+            state.shutdown.clone(),
+            &state.viewers,
+        )),
+    )
+        .into_response()
+}
+
+async fn command(
+    State(state): State<Arc<AdminState>>,
+    Json(command): Json<Command>,
+) -> Result<Json<Reply>, (StatusCode, String)> {
+    match state.controller {
+        Some(ref controller) => Ok(Json(controller.execute(&command, "admin api"))),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            "commands are not available on this instance".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,293 @@
+//! Trouble the TUI shows on its alert line: providers failing most of
+//! their requests, virtual keys near or past their budget, and rate
+//! limits nearly used up. Alerts are worked out afresh from current state,
+//! so each one lasts as long as its cause, with the thresholds
+//! `[notifications]` notifies at.
+
+use std::collections::HashMap;
+
+use crate::config::{Config, NotificationsConfig};
+use crate::keys::KeyStore;
+use crate::metrics::MetricsStore;
+
+/// Requests a provider must have seen before its error rate means much.
+const MIN_SAMPLES: u64 = 3;
+
+/// Share of a rate limit left that alerts, for providers without
+/// `ratelimit_reserve`.
+const DEFAULT_RESERVE: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    /// What the alert is about, e.g. `down:anthropic`; the same while its
+    /// cause lasts, so it stays acknowledged.
+    pub id: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Something wrong now. The TUI shows these as alerts and
+/// `[notifications]` reports them as they start and stop.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// A provider failing at least the down error rate of its requests.
+    Down { provider: String, error_rate: f64 },
+    /// A virtual key past the budget threshold, or its whole budget.
+    Budget {
+        key: String,
+        spent: f64,
+        budget: f64,
+    },
+    /// A provider with less than `reserve` of its rate limit left.
+    RateLimit { provider: String, reserve: f64 },
+}
+
+impl Condition {
+    /// The provider or key the condition is about.
+    pub fn subject(&self) -> &str {
+        match self {
+            Condition::Down { provider, .. } | Condition::RateLimit { provider, .. } => provider,
+            Condition::Budget { key, .. } => key,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Condition::Down { .. } => Severity::Critical,
+            Condition::Budget { spent, budget, .. } if spent >= budget => Severity::Critical,
+            Condition::Budget { .. } | Condition::RateLimit { .. } => Severity::Warning,
+        }
+    }
+
+    fn id(&self) -> String {
+        let kind = match self {
+            Condition::Down { .. } => "down",
+            Condition::Budget { spent, budget, .. } if spent >= budget => "budget",
+            Condition::Budget { .. } => "budget-near",
+            Condition::RateLimit { .. } => "ratelimit",
+        };
+        format!("{kind}:{}", self.subject())
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Down {
+                provider,
+                error_rate,
+            } => write!(
+                f,
+                "{provider} is down: {:.0}% of recent requests failed",
+                error_rate * 100.0
+            ),
+            Condition::Budget { key, spent, budget } => {
+                write!(
+                    f,
+                    "key '{key}' has spent ${spent:.2} of its ${budget:.2} budget"
+                )
+            }
+            Condition::RateLimit { provider, reserve } => write!(
+                f,
+                "{provider} has less than {:.0}% of its rate limit left",
+                reserve * 100.0
+            ),
+        }
+    }
+}
+
+impl From<Condition> for Alert {
+    fn from(condition: Condition) -> Self {
+        Self {
+            id: condition.id(),
+            severity: condition.severity(),
+            message: condition.to_string(),
+        }
+    }
+}
+
+/// When to alert.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds {
+    pub down_error_rate: f64,
+    pub budget_threshold: f64,
+    /// Each provider's `ratelimit_reserve`.
+    pub reserves: HashMap<String, f64>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        let notifications = NotificationsConfig::default();
+        Self {
+            down_error_rate: notifications.down_error_rate,
+            budget_threshold: notifications.budget_threshold,
+            reserves: HashMap::new(),
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            down_error_rate: config.notifications.down_error_rate,
+            budget_threshold: config.notifications.budget_threshold,
+            reserves: config
+                .providers
+                .iter()
+                .filter_map(|(name, p)| Some((name.clone(), p.ratelimit_reserve?)))
+                .collect(),
+        }
+    }
+}
+
+/// Providers failing at least `error_rate` of their recent requests.
+pub fn down(metrics: &MetricsStore, error_rate: f64) -> Vec<Condition> {
+    metrics
+        .health()
+        .into_iter()
+        .filter(|health| health.samples >= MIN_SAMPLES && health.error_rate >= error_rate)
+        .map(|health| Condition::Down {
+            provider: health.provider,
+            error_rate: health.error_rate,
+        })
+        .collect()
+}
+
+/// Keys that have spent at least `threshold` of their budget.
+pub fn over_budget(keys: &KeyStore, threshold: f64) -> Vec<Condition> {
+    keys.budgets()
+        .into_iter()
+        .filter(|(_, spent, budget)| *spent >= budget * threshold)
+        .map(|(key, spent, budget)| Condition::Budget { key, spent, budget })
+        .collect()
+}
+
+/// Providers with less than their reserve of a rate limit left.
+fn rate_limited(metrics: &MetricsStore, reserves: &HashMap<String, f64>) -> Vec<Condition> {
+    metrics
+        .rate_limits()
+        .into_iter()
+        .filter_map(|limit| {
+            let reserve = reserves
+                .get(&limit.provider)
+                .copied()
+                .unwrap_or(DEFAULT_RESERVE);
+            limit
+                .nearly_exhausted(reserve)
+                .then_some(Condition::RateLimit {
+                    provider: limit.provider,
+                    reserve,
+                })
+        })
+        .collect()
+}
+
+/// The alerts that apply now, most severe first. Budgets are only
+/// checked with `keys`.
+pub fn active(
+    metrics: &MetricsStore,
+    keys: Option<&KeyStore>,
+    thresholds: &Thresholds,
+) -> Vec<Alert> {
+    let mut alerts: Vec<Alert> = down(metrics, thresholds.down_error_rate)
+        .into_iter()
+        .chain(
+            keys.map(|keys| over_budget(keys, thresholds.budget_threshold))
+                .unwrap_or_default(),
+        )
+        .chain(rate_limited(metrics, &thresholds.reserves))
+        .map(Alert::from)
+        .collect();
+    alerts.sort_by_key(|a| std::cmp::Reverse(a.severity));
+    alerts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::RequestRecord;
+    use crate::ratelimits::{Quota, RateLimit};
+    use chrono::Utc;
+    use std::time::Duration;
+
+    fn record(provider: &str, status: u16) -> RequestRecord {
+        RequestRecord {
+            provider: provider.to_string(),
+            status,
+            ..RequestRecord::sample()
+        }
+    }
+
+    #[test]
+    fn failing_providers_and_spent_quotas_alert() {
+        let metrics = MetricsStore::new(Duration::from_secs(600));
+        for _ in 0..3 {
+            metrics.record(record("ollama", 503));
+            metrics.record(record("anthropic", 200));
+        }
+        metrics.record_rate_limit(RateLimit {
+            provider: "anthropic".to_string(),
+            updated: Utc::now(),
+            requests: Some(Quota {
+                limit: Some(100),
+                remaining: 5,
+                reset: None,
+            }),
+            tokens: None,
+            input_tokens: None,
+            output_tokens: None,
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        for name in ["ci", "dev", "ops"] {
+            crate::keys::create(
+                &path,
+                crate::keys::NewKey {
+                    name: name.to_string(),
+                    budget_usd: Some(10.0),
+                    requests_per_minute: None,
+                    routes: Vec::new(),
+                },
+            )
+            .unwrap();
+        }
+        let keys = KeyStore::open(path, false);
+        keys.charge("ci", 12.0);
+        keys.charge("dev", 8.5);
+        keys.charge("ops", 1.0);
+
+        let alerts = active(&metrics, Some(&keys), &Thresholds::default());
+        let ids: Vec<_> = alerts.iter().map(|a| a.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "down:ollama",
+                "budget:ci",
+                "budget-near:dev",
+                "ratelimit:anthropic"
+            ]
+        );
+        assert_eq!(
+            alerts[0].message,
+            "ollama is down: 100% of recent requests failed"
+        );
+        assert_eq!(
+            alerts[3].message,
+            "anthropic has less than 10% of its rate limit left"
+        );
+
+        // A reserve set on the provider replaces the default
+        let thresholds = Thresholds {
+            reserves: HashMap::from([("anthropic".to_string(), 0.02)]),
+            ..Thresholds::default()
+        };
+        assert_eq!(active(&metrics, None, &thresholds).len(), 1);
+    }
+}
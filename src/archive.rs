@@ -0,0 +1,239 @@
+//! Archive of model outputs, for building a dataset from everyday use.
+//! Complete responses on routes with `archive = true` are saved with the
+//! request that produced them and the assistant's final text, assembled
+//! from the event stream when the response was streamed. Everything saved
+//! is scrubbed of secrets first, and anything over `max_bytes` is cut.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::compare::ResponseSummary;
+use crate::config::{ArchiveConfig, ArchiveFormat};
+use crate::metrics::RequestRecord;
+use crate::scrub::Scrubber;
+
+/// One archived response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub request_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// The model that answered, after any rewrite.
+    pub model: String,
+    pub provider: String,
+    pub route: Option<String>,
+    /// The request as it was sent, after middleware. Left out when it's
+    /// over `max_bytes`.
+    pub request: Option<Value>,
+    pub text: String,
+    pub stop_reason: Option<String>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Whether the request was left out or the text cut to fit `max_bytes`.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+enum Sink {
+    Jsonl(Mutex<File>),
+    Files(PathBuf),
+}
+
+pub struct Archive {
+    sink: Sink,
+    max_bytes: usize,
+    scrubber: Arc<Scrubber>,
+}
+
+/// What the archive keeps of a request until its response arrives.
+pub struct Pending {
+    pub model: String,
+    pub route: Option<String>,
+    pub request: Option<Value>,
+}
+
+impl Archive {
+    pub fn open(config: &ArchiveConfig, scrubber: Arc<Scrubber>) -> Result<Self, String> {
+        let path = config
+            .path
+            .as_deref()
+            .ok_or("archive.path must be set to archive responses")?;
+        let path = crate::secrets::expand_home(path);
+        let sink = match config.format {
+            ArchiveFormat::Jsonl => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .map_err(|e| format!("failed to create {}: {e}", parent.display()))?;
+                }
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+                Sink::Jsonl(Mutex::new(file))
+            }
+            ArchiveFormat::Files => {
+                std::fs::create_dir_all(&path)
+                    .map_err(|e| format!("failed to create {}: {e}", path.display()))?;
+                Sink::Files(path)
+            }
+        };
+        Ok(Self {
+            sink,
+            max_bytes: config.max_bytes,
+            scrubber,
+        })
+    }
+
+    /// Saves the response `body` to `pending`, if it's a complete one.
+    pub fn finish(&self, pending: Pending, record: &RequestRecord, body: &[u8]) {
+        if record.status >= 400 || record.cutoff.is_some() || record.chaos.is_some() {
+            return;
+        }
+        let entry = self.entry(pending, record, body);
+        if let Err(e) = self.save(&entry) {
+            tracing::warn!("failed to archive response: {e}");
+        }
+    }
+
+    fn entry(&self, pending: Pending, record: &RequestRecord, body: &[u8]) -> Entry {
+        let summary = ResponseSummary::read(body);
+        let mut truncated = false;
+        let request = pending.request.and_then(|mut request| {
+            self.scrubber.scrub_value(&mut request);
+            if request.to_string().len() > self.max_bytes {
+                truncated = true;
+                return None;
+            }
+            Some(request)
+        });
+        let mut text = self.scrubber.scrub(&summary.text).into_owned();
+        if text.len() > self.max_bytes {
+            let mut end = self.max_bytes;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            truncated = true;
+        }
+        Entry {
+            request_id: record.request_id.clone(),
+            timestamp: record.wallclock,
+            model: pending.model,
+            provider: record.provider.clone(),
+            route: pending.route,
+            request,
+            text,
+            stop_reason: summary.stop_reason,
+            input_tokens: reported(summary.input_tokens, record.input_tokens),
+            output_tokens: reported(summary.output_tokens, record.output_tokens),
+            truncated,
+        }
+    }
+
+    fn save(&self, entry: &Entry) -> std::io::Result<()> {
+        let content = serde_json::to_string(entry)?;
+        match self.sink {
+            Sink::Jsonl(ref file) => {
+                let mut file = file.lock().expect("archive lock poisoned");
+                writeln!(file, "{content}")
+            }
+            Sink::Files(ref dir) => {
+                let id = entry.request_id.as_deref().unwrap_or("unknown");
+                std::fs::write(dir.join(format!("{id}.json")), content)
+            }
+        }
+    }
+}
+
+/// The token count the response reported, or else the record's estimate.
+fn reported(tokens: u64, estimate: u64) -> u64 {
+    if tokens > 0 { tokens } else { estimate }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> RequestRecord {
+        RequestRecord {
+            request_id: Some("1a-1".to_string()),
+            ..RequestRecord::sample()
+        }
+    }
+
+    fn pending(request: Value) -> Pending {
+        Pending {
+            model: "claude-opus-4-6".to_string(),
+            route: Some("coding".to_string()),
+            request: Some(request),
+        }
+    }
+
+    fn archive(dir: &std::path::Path, format: ArchiveFormat, max_bytes: usize) -> Archive {
+        let config = ArchiveConfig {
+            enabled: true,
+            path: Some(dir.join("archive").to_string_lossy().to_string()),
+            format,
+            max_bytes,
+        };
+        Archive::open(&config, Arc::new(Scrubber::default())).unwrap()
+    }
+
+    #[test]
+    fn streamed_responses_are_assembled_and_scrubbed() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = archive(dir.path(), ArchiveFormat::Jsonl, 1024);
+        let stream = concat!(
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"text\":\"Use key \"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"text\":\"sk-ant-abcdefghijkl\"}}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":7}}\n\n",
+        );
+        let request = serde_json::json!({"messages": [{"role": "user", "content": "hi"}]});
+        archive.finish(pending(request.clone()), &record(), stream.as_bytes());
+
+        // Failed requests aren't archived
+        let mut failed = record();
+        failed.status = 529;
+        archive.finish(pending(request.clone()), &failed, b"{}");
+
+        let saved = std::fs::read_to_string(dir.path().join("archive")).unwrap();
+        let entries: Vec<Entry> = saved
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].text, "Use key [REDACTED]");
+        assert_eq!(entries[0].stop_reason.as_deref(), Some("end_turn"));
+        assert_eq!(entries[0].request, Some(request));
+        assert_eq!(entries[0].route.as_deref(), Some("coding"));
+        assert!(!entries[0].truncated);
+    }
+
+    #[test]
+    fn oversized_entries_are_cut_to_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = archive(dir.path(), ArchiveFormat::Files, 8);
+        let body = serde_json::json!({
+            "content": [{"type": "text", "text": "héllo wörld"}],
+            "stop_reason": "end_turn"
+        });
+        let request =
+            serde_json::json!({"messages": [{"role": "user", "content": "a long prompt"}]});
+        archive.finish(pending(request), &record(), body.to_string().as_bytes());
+
+        let saved = std::fs::read(dir.path().join("archive/1a-1.json")).unwrap();
+        let entry: Entry = serde_json::from_slice(&saved).unwrap();
+        assert_eq!(entry.text, "héllo w");
+        assert_eq!(entry.request, None);
+        assert!(entry.truncated);
+    }
+}
@@ -1,65 +1,52 @@
+use std::fs::{self, File, Metadata};
 use std::io::{BufRead, BufReader, Seek, SeekFrom};
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use chrono::Utc;
 
 use crate::config::MetricsLogConfig;
+use crate::history::{Entry, log_paths};
 use crate::metrics::{MetricsStore, RequestRecord, RoutingMethod};
-use crate::metrics_log::rotated_path;
-
-#[derive(Debug, Deserialize)]
-struct LogEntry {
-    timestamp: DateTime<Utc>,
-    model: String,
-    provider: String,
-    routing_method: Option<String>,
-    status: u16,
-    duration_ms: u64,
-    input_tokens: u64,
-    output_tokens: u64,
-    error: Option<String>,
-}
 
 pub fn parse_log_entry(line: &str) -> Option<RequestRecord> {
-    let entry: LogEntry = serde_json::from_str(line).ok()?;
+    let entry: Entry = serde_json::from_str(line).ok()?;
     let age = (Utc::now() - entry.timestamp).to_std().ok()?;
     let timestamp = Instant::now().checked_sub(age)?;
     Some(RequestRecord {
         id: 0,
+        request_id: entry.request_id,
         timestamp,
         wallclock: entry.timestamp,
         model: entry.model,
         provider: entry.provider,
-        routing_method: match entry.routing_method.as_deref() {
-            Some("pattern") => RoutingMethod::Pattern,
-            Some("auto") => RoutingMethod::Auto,
-            _ => RoutingMethod::Default,
-        },
+        routing_method: RoutingMethod::from_name(entry.routing_method.as_deref().unwrap_or("")),
         status: entry.status,
         duration: Duration::from_millis(entry.duration_ms),
         input_tokens: entry.input_tokens,
         output_tokens: entry.output_tokens,
         error_body: entry.error,
+        batch_size: entry.batch_size,
+        redactions: entry.redactions,
+        listener: entry.listener,
+        client: entry.client,
+        cutoff: entry.cutoff,
+        chaos: entry.chaos,
+        bench: entry.bench,
+        client_aborted: entry.client_aborted,
+        near_limit: entry.near_limit,
+        timings: entry.timings,
     })
 }
 
 pub fn load_history(config: &MetricsLogConfig, store: &MetricsStore) {
-    let base = Path::new(&config.path);
     let cutoff =
         Utc::now() - chrono::Duration::from_std(store.window()).unwrap_or(chrono::Duration::zero());
 
-    // Read rotated files oldest-first: .max_files, .max_files-1, ..., .1, then current
-    let mut paths = Vec::new();
-    for i in (1..=config.max_files).rev() {
-        paths.push(rotated_path(base, i));
-    }
-    paths.push(base.to_path_buf());
-
-    for path in paths {
+    for path in log_paths(config) {
         let file = match std::fs::File::open(&path) {
             Ok(f) => f,
             Err(_) => continue,
@@ -84,47 +71,87 @@ pub fn load_history(config: &MetricsLogConfig, store: &MetricsStore) {
     }
 }
 
-pub fn tail_log(path: &Path, store: Arc<MetricsStore>, stop: Arc<AtomicBool>) {
-    let mut position: u64 = match std::fs::metadata(path) {
-        Ok(m) => m.len(),
-        Err(_) => 0,
-    };
-
-    while !stop.load(Ordering::Relaxed) {
-        std::thread::sleep(Duration::from_millis(250));
+/// Follows the metrics log across rotation. The open handle is kept between
+/// polls so lines written just before the file is renamed away are still
+/// read, and the path's inode is compared to notice the replacement.
+struct Tailer {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+    identity: Option<(u64, u64)>,
+    position: u64,
+}
 
-        let file = match std::fs::File::open(path) {
-            Ok(f) => f,
-            Err(_) => continue,
-        };
+fn identity(meta: &Metadata) -> (u64, u64) {
+    (meta.dev(), meta.ino())
+}
 
-        let file_len = match file.metadata() {
-            Ok(m) => m.len(),
-            Err(_) => continue,
+impl Tailer {
+    /// Starts at the end of the current file; earlier entries are loaded by
+    /// [`load_history`].
+    fn new(path: &Path) -> Self {
+        let mut tailer = Self {
+            path: path.to_path_buf(),
+            reader: None,
+            identity: None,
+            position: 0,
         };
-
-        // Detect rotation: file shrunk
-        if file_len < position {
-            position = 0;
+        if let Ok(file) = File::open(path)
+            && let Ok(meta) = file.metadata()
+        {
+            tailer.identity = Some(identity(&meta));
+            tailer.position = meta.len();
+            tailer.reader = Some(BufReader::new(file));
         }
+        tailer
+    }
 
-        if file_len == position {
-            continue;
-        }
+    fn poll(&mut self, store: &MetricsStore) {
+        // Finish the file we hold first: after a rename it may have lines
+        // the logger wrote before switching to the new file.
+        self.read_available(store);
 
-        let mut reader = BufReader::new(file);
-        if reader.seek(SeekFrom::Start(position)).is_err() {
-            continue;
+        let Ok(meta) = fs::metadata(&self.path) else {
+            return;
+        };
+        if Some(identity(&meta)) != self.identity {
+            let Ok(file) = File::open(&self.path) else {
+                return;
+            };
+            let Ok(meta) = file.metadata() else {
+                return;
+            };
+            self.identity = Some(identity(&meta));
+            self.reader = Some(BufReader::new(file));
+            self.position = 0;
+            self.read_available(store);
+        } else if meta.len() < self.position {
+            // Truncated in place
+            self.position = 0;
+            self.read_available(store);
         }
+    }
 
-        let mut line = String::new();
+    fn read_available(&mut self, store: &MetricsStore) {
+        let Some(ref mut reader) = self.reader else {
+            return;
+        };
+        if reader.seek(SeekFrom::Start(self.position)).is_err() {
+            return;
+        }
+        let mut line = Vec::new();
         loop {
             line.clear();
-            match reader.read_line(&mut line) {
-                Ok(0) => break,
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
                 Ok(n) => {
-                    position += n as u64;
-                    let trimmed = line.trim();
+                    // A line without its newline is still being written;
+                    // read it whole on the next poll.
+                    if line.last() != Some(&b'\n') {
+                        break;
+                    }
+                    self.position += n as u64;
+                    let text = String::from_utf8_lossy(&line);
+                    let trimmed = text.trim();
                     if trimmed.is_empty() {
                         continue;
                     }
@@ -132,16 +159,24 @@ pub fn tail_log(path: &Path, store: Arc<MetricsStore>, stop: Arc<AtomicBool>) {
                         store.record(record);
                     }
                 }
-                Err(_) => break,
             }
         }
     }
 }
 
+pub fn tail_log(path: &Path, store: Arc<MetricsStore>, stop: Arc<AtomicBool>) {
+    let mut tailer = Tailer::new(path);
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(250));
+        tailer.poll(&store);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use crate::metrics_log::rotated_path;
+    use std::io::Write;
 
     fn recent_timestamp() -> String {
         Utc::now().to_rfc3339()
@@ -303,4 +338,113 @@ mod tests {
 
         assert_eq!(store.snapshot().len(), 0);
     }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn models(store: &MetricsStore) -> Vec<String> {
+        store.snapshot().into_iter().map(|r| r.model).collect()
+    }
+
+    #[test]
+    fn tail_starts_at_end_of_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("metrics.jsonl");
+        let ts = recent_timestamp();
+        append(&base, &format!("{}\n", make_entry(&ts, "history", None)));
+
+        let store = MetricsStore::new(Duration::from_secs(3600));
+        let mut tailer = Tailer::new(&base);
+        tailer.poll(&store);
+        assert!(models(&store).is_empty());
+
+        append(&base, &format!("{}\n", make_entry(&ts, "live", None)));
+        tailer.poll(&store);
+        assert_eq!(models(&store), vec!["live"]);
+    }
+
+    #[test]
+    fn tail_follows_rename_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("metrics.jsonl");
+        let ts = recent_timestamp();
+        append(&base, &format!("{}\n", make_entry(&ts, "history", None)));
+
+        let store = MetricsStore::new(Duration::from_secs(3600));
+        let mut tailer = Tailer::new(&base);
+
+        // Written just before rotation, then the new file outgrows the old
+        // position before the next poll.
+        append(&base, &format!("{}\n", make_entry(&ts, "before", None)));
+        fs::rename(&base, rotated_path(&base, 1)).unwrap();
+        let mut lines = String::new();
+        for i in 0..3 {
+            lines.push_str(&make_entry(&ts, &format!("after{i}"), None));
+            lines.push('\n');
+        }
+        append(&base, &lines);
+        tailer.poll(&store);
+
+        assert_eq!(models(&store), vec!["before", "after0", "after1", "after2"]);
+    }
+
+    #[test]
+    fn tail_restarts_after_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("metrics.jsonl");
+        let ts = recent_timestamp();
+        append(&base, &format!("{}\n", make_entry(&ts, "history", None)));
+
+        let store = MetricsStore::new(Duration::from_secs(3600));
+        let mut tailer = Tailer::new(&base);
+        fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&base)
+            .unwrap();
+        append(&base, &format!("{}\n", make_entry(&ts, "new", None)));
+        tailer.poll(&store);
+
+        assert_eq!(models(&store), vec!["new"]);
+    }
+
+    #[test]
+    fn tail_waits_for_complete_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("metrics.jsonl");
+        let ts = recent_timestamp();
+        let entry = make_entry(&ts, "split", None);
+        let (head, rest) = entry.split_at(entry.len() / 2);
+
+        let store = MetricsStore::new(Duration::from_secs(3600));
+        let mut tailer = Tailer::new(&base);
+        append(&base, head);
+        tailer.poll(&store);
+        assert!(models(&store).is_empty());
+
+        append(&base, &format!("{rest}\n"));
+        tailer.poll(&store);
+        assert_eq!(models(&store), vec!["split"]);
+    }
+
+    #[test]
+    fn tail_picks_up_file_created_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("metrics.jsonl");
+        let ts = recent_timestamp();
+
+        let store = MetricsStore::new(Duration::from_secs(3600));
+        let mut tailer = Tailer::new(&base);
+        tailer.poll(&store);
+        append(&base, &format!("{}\n", make_entry(&ts, "first", None)));
+        tailer.poll(&store);
+
+        assert_eq!(models(&store), vec!["first"]);
+    }
 }
@@ -0,0 +1,203 @@
+//! The audit log: an append-only JSONL record of administrative and
+//! security-relevant events, such as config reloads, route changes,
+//! rejected credentials, and keys being minted. It is kept apart from the
+//! metrics log, which records traffic, and rotates on its own limits.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::AuditLogConfig;
+use crate::control::Command;
+use crate::keys::Denied;
+use crate::metrics_log::RotatingFile;
+
+/// Something worth an audit line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// An operator command from the TUI, the control socket, or the admin
+    /// API: route toggles, forced providers, reloads, drains.
+    Command {
+        source: String,
+        #[serde(flatten)]
+        command: Command,
+        ok: bool,
+        message: String,
+    },
+    /// A request without a virtual key where one is required, or with a
+    /// key croxy doesn't know.
+    AuthFailed {
+        path: String,
+        listener: Option<String>,
+        reason: String,
+    },
+    /// A known virtual key turned away by its budget, rate limit, or routes.
+    KeyDenied {
+        key: String,
+        reason: &'static str,
+        message: String,
+        path: String,
+        listener: Option<String>,
+    },
+    /// An admin API request without the admin token.
+    AdminAuthFailed {
+        path: String,
+    },
+    /// A TCP connection from outside `server.allow_cidrs`.
+    ConnectionRejected {
+        peer: String,
+    },
+    KeyCreated {
+        key: String,
+        budget_usd: Option<f64>,
+        requests_per_minute: Option<u32>,
+        routes: Vec<String>,
+    },
+    KeyRevoked {
+        key: String,
+    },
+}
+
+impl AuditEvent {
+    /// The event for a request turned away by the key store.
+    pub fn denied(denied: &Denied, path: &str, listener: Option<&str>) -> Self {
+        let (path, listener) = (path.to_string(), listener.map(String::from));
+        match denied {
+            Denied::Unknown => AuditEvent::AuthFailed {
+                path,
+                listener,
+                reason: denied.to_string(),
+            },
+            Denied::OverBudget { name, .. }
+            | Denied::RateLimited { name, .. }
+            | Denied::Route { name, .. }
+            | Denied::OverQuota { name, .. } => AuditEvent::KeyDenied {
+                key: name.clone(),
+                reason: denied.reason(),
+                message: denied.to_string(),
+                path,
+                listener,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Where audit events go. Disabled logs drop them.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Opens `[logging.audit]`'s file, or a disabled log when it is off.
+    pub fn from_config(config: &AuditLogConfig) -> io::Result<Self> {
+        if !config.enabled {
+            return Ok(Self::disabled());
+        }
+        let file = RotatingFile::open(
+            PathBuf::from(&config.path),
+            config.max_size_mb * 1024 * 1024,
+            config.max_files,
+            None,
+        )?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let Some(ref file) = self.file else {
+            return;
+        };
+        let line = Line {
+            ts: Utc::now(),
+            event: &event,
+        };
+        let line = serde_json::to_string(&line).expect("audit events serialize");
+        if let Err(e) = file.lock().expect("audit lock poisoned").write_line(&line) {
+            tracing::warn!("failed to write audit log: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_appended_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let config = AuditLogConfig {
+            enabled: true,
+            path: path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let audit = AuditLog::from_config(&config).unwrap();
+        audit.record(AuditEvent::Command {
+            source: "admin".to_string(),
+            command: Command::SetRouteEnabled {
+                index: 2,
+                enabled: false,
+            },
+            ok: true,
+            message: "route 2 disabled".to_string(),
+        });
+        let over_budget = Denied::OverBudget {
+            name: "ci".to_string(),
+            budget_usd: 5.0,
+        };
+        audit.record(AuditEvent::denied(
+            &over_budget,
+            "/v1/messages",
+            Some("lan"),
+        ));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "command");
+        assert_eq!(lines[0]["command"], "set_route_enabled");
+        assert_eq!(lines[0]["index"], 2);
+        assert_eq!(lines[0]["source"], "admin");
+        assert!(lines[0]["ts"].is_string());
+        assert_eq!(lines[1]["event"], "key_denied");
+        assert_eq!(lines[1]["key"], "ci");
+        assert_eq!(lines[1]["reason"], "over_budget");
+        assert_eq!(lines[1]["listener"], "lan");
+    }
+
+    #[test]
+    fn disabled_logs_write_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AuditLogConfig {
+            enabled: false,
+            path: dir.path().join("audit.jsonl").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        AuditLog::from_config(&config)
+            .unwrap()
+            .record(AuditEvent::KeyRevoked {
+                key: "ci".to_string(),
+            });
+        assert!(!dir.path().join("audit.jsonl").exists());
+    }
+}
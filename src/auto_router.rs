@@ -205,6 +205,7 @@ mod tests {
             url: url.to_string(),
             model: "test-model".to_string(),
             timeout_ms: 2000,
+            local: None,
         }
     }
 
@@ -0,0 +1,289 @@
+//! `[auto_router.local]`: the auto-router's classifier run as a child of
+//! croxy, so there is no second service to keep alive. The command starts
+//! with croxy, is polled until it serves, and is health-checked from then
+//! on. When it exits or stops answering it is started again, waiting twice
+//! as long after each failure so a command that can't start doesn't spin.
+//!
+//! The command runs in a process group of its own, and stopping it signals
+//! the whole group, so whatever `sh -c` started goes down with it.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{Signal, killpg};
+use nix::unistd::Pid;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{Mutex, watch};
+use tracing::{debug, info, warn};
+
+use crate::config::LocalAutoRouterConfig;
+
+/// The wait before the first restart.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// A process that ran this long before failing gets a quick restart, as
+/// whatever went wrong wasn't at startup.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// Failed checks in a row after which a serving process is restarted.
+const MAX_FAILED_CHECKS: u32 = 3;
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a starting process is checked for whether it serves yet.
+const STARTUP_POLL: Duration = Duration::from_secs(1);
+/// How long the process has to exit on SIGTERM before it gets SIGKILL.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts the classifier and keeps it running.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    config: LocalAutoRouterConfig,
+    health_url: String,
+    /// Whether a health check needs a 2xx, rather than any answer.
+    strict: bool,
+}
+
+/// A running classifier.
+struct Process {
+    child: Child,
+    group: Pid,
+    /// The last line it wrote to stderr, to explain a crash.
+    last_line: Arc<Mutex<Option<String>>>,
+}
+
+impl Supervisor {
+    /// `url` is `auto_router.url`, the classifier's endpoint.
+    pub fn new(config: &LocalAutoRouterConfig, url: &str) -> Result<Self, String> {
+        if config.command.trim().is_empty() {
+            return Err("command is empty".to_string());
+        }
+        let (health_url, strict) = match config.health_url {
+            Some(ref health_url) => (health_url.clone(), true),
+            None => (origin(url)?, false),
+        };
+        Ok(Self {
+            config: config.clone(),
+            health_url,
+            strict,
+        })
+    }
+
+    /// Runs the classifier until `shutdown` turns true, then stops it.
+    pub async fn run(self, mut shutdown: watch::Receiver<bool>) {
+        let client = reqwest::Client::new();
+        let max_backoff = Duration::from_secs(self.config.max_backoff_secs).max(INITIAL_BACKOFF);
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let reason = match self.spawn() {
+                Ok(mut process) => {
+                    info!(
+                        pid = process.group.as_raw(),
+                        command = %self.config.command,
+                        "started auto router"
+                    );
+                    tokio::select! {
+                        status = process.child.wait() => {
+                            let status = match status {
+                                Ok(status) => format!("exited with {status}"),
+                                Err(e) => format!("failed: {e}"),
+                            };
+                            match process.last_line.lock().await.take() {
+                                Some(line) => format!("{status}: {line}"),
+                                None => status,
+                            }
+                        }
+                        reason = self.watch(&client) => {
+                            process.stop().await;
+                            reason
+                        }
+                        () = stopped(&mut shutdown) => {
+                            process.stop().await;
+                            return;
+                        }
+                    }
+                }
+                Err(e) => format!("failed to start: {e}"),
+            };
+            if started.elapsed() >= STABLE_AFTER {
+                backoff = INITIAL_BACKOFF;
+            }
+            warn!("auto router {reason}, restarting in {}s", backoff.as_secs());
+            tokio::select! {
+                () = tokio::time::sleep(backoff) => {}
+                () = stopped(&mut shutdown) => return,
+            }
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    fn spawn(&self) -> std::io::Result<Process> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.config.command)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()?;
+        let group = Pid::from_raw(child.id().map_or(0, |id| id as i32));
+        let last_line = Arc::new(Mutex::new(None));
+        if let Some(stderr) = child.stderr.take() {
+            let last_line = last_line.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    debug!(target: "croxy::auto_router", "{line}");
+                    if !line.trim().is_empty() {
+                        *last_line.lock().await = Some(line);
+                    }
+                }
+            });
+        }
+        Ok(Process {
+            child,
+            group,
+            last_line,
+        })
+    }
+
+    /// Waits for the process to start serving, then checks it until it
+    /// stops; returns why it is being restarted.
+    async fn watch(&self, client: &reqwest::Client) -> String {
+        let startup = Duration::from_secs(self.config.startup_timeout_secs);
+        let deadline = Instant::now() + startup;
+        while !self.healthy(client).await {
+            if Instant::now() >= deadline {
+                return format!("wasn't serving after {}s", startup.as_secs());
+            }
+            tokio::time::sleep(STARTUP_POLL).await;
+        }
+        info!(url = %self.health_url, "auto router is serving");
+
+        let interval = Duration::from_secs(self.config.health_interval_secs.max(1));
+        let mut failed = 0;
+        loop {
+            tokio::time::sleep(interval).await;
+            if self.healthy(client).await {
+                failed = 0;
+            } else {
+                failed += 1;
+                if failed >= MAX_FAILED_CHECKS {
+                    return format!("failed {failed} health checks in a row");
+                }
+            }
+        }
+    }
+
+    async fn healthy(&self, client: &reqwest::Client) -> bool {
+        match client
+            .get(&self.health_url)
+            .timeout(CHECK_TIMEOUT)
+            .send()
+            .await
+        {
+            Ok(response) => !self.strict || response.status().is_success(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Process {
+    /// Asks the process group to exit, then makes it.
+    async fn stop(&mut self) {
+        let _ = killpg(self.group, Signal::SIGTERM);
+        if tokio::time::timeout(STOP_TIMEOUT, self.child.wait())
+            .await
+            .is_err()
+        {
+            let _ = killpg(self.group, Signal::SIGKILL);
+            let _ = self.child.wait().await;
+        }
+    }
+}
+
+impl Drop for Process {
+    /// Croxy exiting without stopping the process shouldn't leave it behind.
+    fn drop(&mut self) {
+        if matches!(self.child.try_wait(), Ok(None)) {
+            let _ = killpg(self.group, Signal::SIGTERM);
+        }
+    }
+}
+
+/// Resolves once `shutdown` turns true or its sender is gone.
+async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+/// The scheme, host, and port of `url`.
+fn origin(url: &str) -> Result<String, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid URL '{url}': {e}"))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(parsed.origin().ascii_serialization()),
+        scheme => Err(format!(
+            "unsupported scheme '{scheme}' in '{url}', expected http or https"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(command: String) -> LocalAutoRouterConfig {
+        LocalAutoRouterConfig {
+            command,
+            max_backoff_secs: 1,
+            ..LocalAutoRouterConfig::default()
+        }
+    }
+
+    #[test]
+    fn health_checks_default_to_the_classifiers_host() {
+        let url = "http://127.0.0.1:11434/v1/chat/completions";
+        let supervisor = Supervisor::new(&config("ollama serve".to_string()), url).unwrap();
+        assert_eq!(supervisor.health_url, "http://127.0.0.1:11434");
+        assert!(!supervisor.strict);
+
+        let explicit = LocalAutoRouterConfig {
+            health_url: Some("http://127.0.0.1:8081/health".to_string()),
+            ..config("llama-server".to_string())
+        };
+        let supervisor = Supervisor::new(&explicit, url).unwrap();
+        assert_eq!(supervisor.health_url, "http://127.0.0.1:8081/health");
+        assert!(supervisor.strict);
+
+        assert_eq!(
+            Supervisor::new(&config(" ".to_string()), url).unwrap_err(),
+            "command is empty"
+        );
+    }
+
+    #[tokio::test]
+    async fn crashed_processes_are_restarted_until_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let starts = dir.path().join("starts");
+        let command = format!("echo started >> '{}'; exit 1", starts.display());
+        let supervisor =
+            Supervisor::new(&config(command), "http://127.0.0.1:9/v1/chat/completions").unwrap();
+        let (stop, shutdown) = watch::channel(false);
+        let running = tokio::spawn(supervisor.run(shutdown));
+
+        let count = || {
+            std::fs::read_to_string(&starts)
+                .map(|s| s.lines().count())
+                .unwrap_or(0)
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while count() < 2 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(count() >= 2, "restarted {} times", count());
+
+        stop.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("supervisor stops on shutdown")
+            .unwrap();
+    }
+}
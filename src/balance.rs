@@ -0,0 +1,168 @@
+//! Picks the member of a provider group that serves each request. Every
+//! provider's latency and error rate are tracked as moving averages over
+//! its recent requests, and each request goes to the better of two members
+//! drawn at random, so traffic drifts toward whichever machine is doing
+//! best without piling onto it.
+
+use std::time::Duration;
+
+use ring::rand::SecureRandom;
+use serde::{Deserialize, Serialize};
+
+/// Weight of the newest request in the moving averages.
+const ALPHA: f64 = 0.2;
+
+/// A provider's recent performance, as selection sees it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    pub provider: String,
+    /// Moving average duration of successful requests.
+    pub latency_ms: f64,
+    /// Moving average of the share of requests that failed.
+    pub error_rate: f64,
+    /// Requests seen.
+    pub samples: u64,
+}
+
+impl Health {
+    pub fn new(provider: &str) -> Self {
+        Self {
+            provider: provider.to_string(),
+            latency_ms: 0.0,
+            error_rate: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Folds in a request that finished with `status` after `duration`.
+    /// Only 5xx responses and 429s count as failures; other 4xx responses
+    /// are the client's doing.
+    pub fn observe(&mut self, status: u16, duration: Duration) {
+        let failed = status >= 500 || status == 429;
+        let error = if failed { 1.0 } else { 0.0 };
+        let latency = duration.as_secs_f64() * 1000.0;
+        if self.samples == 0 {
+            self.error_rate = error;
+        } else {
+            self.error_rate += ALPHA * (error - self.error_rate);
+        }
+        // Failures are often quick and would make a failing member look
+        // fast, so latency follows successes alone
+        if !failed {
+            if self.latency_ms == 0.0 {
+                self.latency_ms = latency;
+            } else {
+                self.latency_ms += ALPHA * (latency - self.latency_ms);
+            }
+        }
+        self.samples += 1;
+    }
+
+    /// Expected time per successful response; lower is better. A member
+    /// that has only ever failed scores infinity, so it loses to any member
+    /// that works, while members not tried yet score 0 so they get tried.
+    pub fn score(&self) -> f64 {
+        if self.samples > 0 && self.latency_ms == 0.0 {
+            return f64::INFINITY;
+        }
+        self.latency_ms / (1.0 - self.error_rate).max(0.01)
+    }
+}
+
+/// Picks one of `members`: the better-scoring of two drawn at random, by
+/// `health`. Members `health` knows nothing about score 0.
+pub fn pick<'a>(
+    members: &'a [String],
+    health: impl Fn(&str) -> Option<Health>,
+    random: &dyn SecureRandom,
+) -> Option<&'a str> {
+    let score = |name: &str| health(name).map_or(0.0, |h| h.score());
+    match members {
+        [] => None,
+        [only] => Some(only),
+        _ => {
+            let mut bytes = [0u8; 8];
+            if random.fill(&mut bytes).is_err() {
+                return members.first().map(String::as_str);
+            }
+            let (draw, n) = (u64::from_le_bytes(bytes), members.len());
+            let first = (draw as u32) as usize % n;
+            // Draw the second from the others, so the two always differ
+            let offset = (draw >> 32) as usize % (n - 1);
+            let second = (first + 1 + offset) % n;
+            let (a, b) = (&members[first], &members[second]);
+            Some(if score(b) < score(a) { b } else { a })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    #[test]
+    fn averages_favor_recent_requests_and_count_failures() {
+        let mut health = Health::new("a");
+        health.observe(200, Duration::from_millis(100));
+        assert_eq!(health.latency_ms, 100.0);
+        assert_eq!(health.error_rate, 0.0);
+
+        health.observe(200, Duration::from_millis(600));
+        assert_eq!(health.latency_ms, 200.0);
+
+        // Failures raise the error rate but leave latency alone, and
+        // client errors aren't failures
+        health.observe(502, Duration::from_millis(1));
+        health.observe(400, Duration::from_millis(1000));
+        assert!((health.error_rate - 0.16).abs() < 1e-9);
+        assert!(health.latency_ms > 200.0);
+        assert_eq!(health.samples, 4);
+    }
+
+    #[test]
+    fn picks_the_better_of_two_members() {
+        let members = vec!["slow".to_string(), "fast".to_string()];
+        let health = |name: &str| {
+            let mut health = Health::new(name);
+            let ms = if name == "fast" { 50 } else { 900 };
+            health.observe(200, Duration::from_millis(ms));
+            Some(health)
+        };
+        let random = SystemRandom::new();
+        for _ in 0..20 {
+            assert_eq!(pick(&members, health, &random), Some("fast"));
+        }
+
+        // A fast member that keeps failing loses to a slow one that works
+        let failing = |name: &str| {
+            let mut health = health(name).unwrap();
+            if name == "fast" {
+                for _ in 0..20 {
+                    health.observe(503, Duration::from_millis(5));
+                }
+            }
+            Some(health)
+        };
+        assert_eq!(pick(&members, failing, &random), Some("slow"));
+
+        // As does one that has never succeeded, down since croxy started
+        let down = |name: &str| {
+            let mut health = Health::new(name);
+            if name == "fast" {
+                health.observe(502, Duration::from_millis(1));
+            } else {
+                health.observe(200, Duration::from_millis(900));
+            }
+            Some(health)
+        };
+        for _ in 0..20 {
+            assert_eq!(pick(&members, down, &random), Some("slow"));
+        }
+        // But a member not tried yet is still tried before one that works
+        let untried = |name: &str| health(name).filter(|_| name == "slow");
+        assert_eq!(pick(&members, untried, &random), Some("fast"));
+        assert_eq!(pick(&members[..1], |_| None, &random), Some("slow"));
+        assert_eq!(pick(&[], |_| None, &random), None);
+    }
+}
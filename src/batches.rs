@@ -0,0 +1,258 @@
+//! The Message Batches API. A batch carries a model per request inside its
+//! body, so routing looks at every request rather than a top-level model,
+//! and later calls about a batch go to the provider that created it.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+use http::Method;
+use serde_json::Value;
+
+use crate::config::ApiFormat;
+use crate::router::{Endpoint, RequestFields, ResolvedRoute, Router};
+
+pub const BATCHES_PATH: &str = "/v1/messages/batches";
+
+/// How many batch IDs are remembered before the oldest are forgotten.
+const MAX_REMEMBERED: usize = 10_000;
+
+/// A call to the Message Batches API.
+#[derive(Debug, PartialEq)]
+pub enum BatchCall<'a> {
+    /// `POST /v1/messages/batches`
+    Create,
+    /// Retrieving, cancelling, deleting, or reading the results of a batch.
+    Existing(&'a str),
+}
+
+impl<'a> BatchCall<'a> {
+    /// `None` for anything that isn't about a single batch, including
+    /// listing batches.
+    pub fn of(method: &Method, path: &'a str) -> Option<Self> {
+        let rest = path.strip_prefix(BATCHES_PATH)?;
+        if rest.is_empty() || rest == "/" {
+            return (method == Method::POST).then_some(BatchCall::Create);
+        }
+        let id = rest.strip_prefix('/')?.split('/').next()?;
+        (!id.is_empty()).then_some(BatchCall::Existing(id))
+    }
+}
+
+/// Where a batch goes once each of its requests has been routed.
+pub struct RoutedBatch {
+    pub route: ResolvedRoute,
+    /// The requested models, comma-separated, for metrics.
+    pub models: String,
+    pub size: usize,
+}
+
+/// Routes every request in a batch by its model, applying each route's
+/// model rewrite and sampling overrides to `body` in place. All requests must go to the same provider, and that
+/// provider must speak the Messages API itself.
+pub fn route_batch(router: &Router, body: &mut Value) -> Result<RoutedBatch, String> {
+    let requests = body["requests"]
+        .as_array_mut()
+        .ok_or("batch body has no requests array")?;
+    let mut chosen: Option<(ResolvedRoute, String)> = None;
+    let mut models: Vec<String> = Vec::new();
+    for request in requests.iter_mut() {
+        let custom_id = request["custom_id"].as_str().unwrap_or("?").to_string();
+        let params = &mut request["params"];
+        let model = params["model"].as_str().unwrap_or_default().to_string();
+        let route = router.resolve_fields(&model, Endpoint::Other, RequestFields::of(Some(params)));
+        match chosen {
+            Some((ref first, ref first_id)) if first.provider_name != route.provider_name => {
+                return Err(format!(
+                    "batch mixes providers: request '{first_id}' goes to '{}' but request \
+                     '{custom_id}' ({model}) goes to '{}'; submit a batch per provider",
+                    first.provider_name, route.provider_name
+                ));
+            }
+            Some(_) => {}
+            None => {
+                if route.api_format != ApiFormat::Anthropic {
+                    return Err(format!(
+                        "provider '{}' uses api_format \"{}\", which has no batches API",
+                        route.provider_name, route.api_format
+                    ));
+                }
+                chosen = Some((route.clone(), custom_id));
+            }
+        }
+        if let Some(ref new_model) = route.model_rewrite {
+            params["model"] = Value::String(new_model.clone());
+        }
+        route.sampling.apply(params);
+        if !models.contains(&model) {
+            models.push(model);
+        }
+    }
+    let (mut route, _) = chosen.ok_or("batch has no requests")?;
+    // Rewrites were applied per request; the body has no top-level model.
+    route.model_rewrite = None;
+    Ok(RoutedBatch {
+        route,
+        models: models.join(","),
+        size: requests.len(),
+    })
+}
+
+/// Which provider each batch was created on, so later calls about it
+/// reach the same place.
+#[derive(Default)]
+pub struct BatchOwners {
+    inner: RwLock<(HashMap<String, String>, VecDeque<String>)>,
+}
+
+impl BatchOwners {
+    pub fn remember(&self, batch_id: &str, provider: &str) {
+        let mut guard = self.inner.write().expect("batch owners lock poisoned");
+        let (owners, order) = &mut *guard;
+        if owners
+            .insert(batch_id.to_string(), provider.to_string())
+            .is_none()
+        {
+            order.push_back(batch_id.to_string());
+        }
+        while order.len() > MAX_REMEMBERED {
+            if let Some(oldest) = order.pop_front() {
+                owners.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn owner(&self, batch_id: &str) -> Option<String> {
+        self.inner
+            .read()
+            .expect("batch owners lock poisoned")
+            .0
+            .get(batch_id)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+    use serde_json::json;
+
+    fn router() -> Router {
+        let config: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [provider.anthropic]
+                url = "https://api.anthropic.com"
+                [provider.local]
+                url = "http://localhost:11434"
+                [provider.native]
+                url = "http://localhost:11434"
+                api_format = "ollama"
+                [[routes]]
+                pattern = "opus"
+                provider = "anthropic"
+                model = "claude-opus-4-1"
+                [[routes]]
+                pattern = "haiku"
+                provider = "local"
+                [[routes]]
+                pattern = "native"
+                provider = "native"
+                [default]
+                provider = "anthropic"
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        Router::from_config(&config).unwrap()
+    }
+
+    fn batch(models: &[&str]) -> Value {
+        let requests: Vec<Value> = models
+            .iter()
+            .enumerate()
+            .map(|(i, model)| {
+                json!({
+                    "custom_id": format!("req-{i}"),
+                    "params": {"model": model, "max_tokens": 10, "messages": []}
+                })
+            })
+            .collect();
+        json!({"requests": requests})
+    }
+
+    #[test]
+    fn calls_are_recognized_by_path() {
+        assert_eq!(
+            BatchCall::of(&Method::POST, "/v1/messages/batches"),
+            Some(BatchCall::Create)
+        );
+        assert_eq!(BatchCall::of(&Method::GET, "/v1/messages/batches"), None);
+        assert_eq!(
+            BatchCall::of(&Method::GET, "/v1/messages/batches/msgbatch_1/results"),
+            Some(BatchCall::Existing("msgbatch_1"))
+        );
+        assert_eq!(
+            BatchCall::of(&Method::POST, "/v1/messages/batches/msgbatch_1/cancel"),
+            Some(BatchCall::Existing("msgbatch_1"))
+        );
+        assert_eq!(BatchCall::of(&Method::POST, "/v1/messages"), None);
+    }
+
+    #[test]
+    fn requests_are_rewritten_per_route() {
+        let router = router();
+        let mut body = batch(&["claude-opus-4", "claude-sonnet-4-5", "claude-opus-4"]);
+        let routed = route_batch(&router, &mut body).unwrap();
+        assert_eq!(routed.route.provider_name, "anthropic");
+        assert_eq!(routed.route.model_rewrite, None);
+        assert_eq!(routed.size, 3);
+        assert_eq!(routed.models, "claude-opus-4,claude-sonnet-4-5");
+        assert_eq!(body["requests"][0]["params"]["model"], "claude-opus-4-1");
+        assert_eq!(body["requests"][1]["params"]["model"], "claude-sonnet-4-5");
+    }
+
+    #[test]
+    fn mixed_providers_are_rejected() {
+        let err = route_batch(
+            &router(),
+            &mut batch(&["claude-opus-4", "claude-haiku-4-5"]),
+        )
+        .err()
+        .unwrap();
+        assert!(
+            err.starts_with(
+                "batch mixes providers: request 'req-0' goes to 'anthropic' but request \
+                 'req-1' (claude-haiku-4-5) goes to 'local'"
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn translated_providers_are_rejected() {
+        let err = route_batch(&router(), &mut batch(&["native"]))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            "provider 'native' uses api_format \"ollama\", which has no batches API"
+        );
+        let err = route_batch(&router(), &mut json!({"requests": []}))
+            .err()
+            .unwrap();
+        assert_eq!(err, "batch has no requests");
+    }
+
+    #[test]
+    fn owners_forget_the_oldest() {
+        let owners = BatchOwners::default();
+        for i in 0..=MAX_REMEMBERED {
+            owners.remember(&format!("b{i}"), "local");
+        }
+        assert_eq!(owners.owner("b0"), None);
+        assert_eq!(owners.owner("b1").as_deref(), Some("local"));
+    }
+}
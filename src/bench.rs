@@ -0,0 +1,251 @@
+//! `croxy bench`: synthetic traffic through the running proxy, to measure
+//! what a route's providers deliver under load. Each request carries
+//! [`HEADER`] with the run's ID; croxy strips it before forwarding, tags
+//! the request's record with it so the TUI's statistics can leave bench
+//! traffic out, and answers with [`PROVIDER_HEADER`] so results can be
+//! split by the provider that served them.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::compare::ResponseSummary;
+use crate::metrics::MetricsStore;
+
+/// Request header naming the bench run a request belongs to.
+pub const HEADER: &str = "x-croxy-bench";
+
+/// Response header naming the provider that served a bench request.
+pub const PROVIDER_HEADER: &str = "x-croxy-provider";
+
+/// What to send, and how hard.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub model: String,
+    pub concurrency: usize,
+    pub requests: usize,
+    pub prompt: String,
+    pub max_tokens: u64,
+}
+
+/// How one request went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Empty when the request never reached a provider.
+    pub provider: String,
+    pub status: u16,
+    pub duration: Duration,
+    /// Time to the first streamed text, for a successful response.
+    pub ttft: Option<Duration>,
+    pub output_tokens: u64,
+    pub error: Option<String>,
+}
+
+/// A new run's ID, unique enough to tell runs apart in the metrics log.
+pub fn run_id() -> String {
+    format!("bench-{}", chrono::Utc::now().format("%Y%m%d%H%M%S"))
+}
+
+/// Sends `options.requests` streaming Messages requests to the proxy at
+/// `url`, `options.concurrency` at a time, and returns how each went.
+pub async fn run(
+    client: &reqwest::Client,
+    url: &str,
+    api_key: Option<&str>,
+    run_id: &str,
+    options: &Options,
+) -> Vec<Sample> {
+    let body = serde_json::json!({
+        "model": options.model,
+        "max_tokens": options.max_tokens,
+        "stream": true,
+        "messages": [{"role": "user", "content": options.prompt}],
+    });
+    let next = Arc::new(AtomicUsize::new(0));
+    let workers = (0..options.concurrency.clamp(1, options.requests.max(1))).map(|_| {
+        let next = next.clone();
+        let body = &body;
+        async move {
+            let mut samples = Vec::new();
+            while next.fetch_add(1, Ordering::Relaxed) < options.requests {
+                let mut request = client
+                    .post(url)
+                    .header("anthropic-version", "2023-06-01")
+                    .header(HEADER, run_id)
+                    .json(body);
+                if let Some(key) = api_key {
+                    request = request.header("x-api-key", key);
+                }
+                samples.push(send(request).await);
+            }
+            samples
+        }
+    });
+    futures::future::join_all(workers)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Sample {
+    let start = Instant::now();
+    let failed = |provider: String, status, error| Sample {
+        provider,
+        status,
+        duration: start.elapsed(),
+        ttft: None,
+        output_tokens: 0,
+        error: Some(error),
+    };
+    let mut response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => return failed(String::new(), 0, e.to_string()),
+    };
+    let status = response.status().as_u16();
+    let provider = response
+        .headers()
+        .get(PROVIDER_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let mut body = Vec::new();
+    let mut ttft = None;
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                body.extend_from_slice(&chunk);
+                // Checked against the whole body, as an event can span chunks
+                if ttft.is_none() && contains(&body, b"content_block_delta") {
+                    ttft = Some(start.elapsed());
+                }
+            }
+            Ok(None) => break,
+            Err(e) => return failed(provider, status, e.to_string()),
+        }
+    }
+    if status >= 400 {
+        let error = String::from_utf8_lossy(&body).chars().take(200).collect();
+        return failed(provider, status, error);
+    }
+    Sample {
+        provider,
+        status,
+        duration: start.elapsed(),
+        ttft,
+        output_tokens: ResponseSummary::read(&body).output_tokens,
+        error: None,
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// One provider's share of a run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProviderReport {
+    pub provider: String,
+    pub requests: usize,
+    pub errors: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+    pub ttft_p50_ms: Option<u64>,
+    pub ttft_p95_ms: Option<u64>,
+    pub output_tokens: u64,
+    /// Requests finished per second of the run.
+    pub requests_per_sec: f64,
+    /// Output tokens generated per second of the run.
+    pub tokens_per_sec: f64,
+}
+
+/// `samples` summarized by provider, busiest first, over a run that took
+/// `elapsed`. Requests that never reached a provider are listed under
+/// `-`.
+pub fn report(samples: &[Sample], elapsed: Duration) -> Vec<ProviderReport> {
+    let mut groups: BTreeMap<&str, Vec<&Sample>> = BTreeMap::new();
+    for sample in samples {
+        let name = match sample.provider.as_str() {
+            "" => "-",
+            name => name,
+        };
+        groups.entry(name).or_default().push(sample);
+    }
+    let secs = elapsed.as_secs_f64().max(0.001);
+    let ms = |durations: &[Duration], p| {
+        MetricsStore::duration_percentile(durations, p).as_millis() as u64
+    };
+    let mut reports: Vec<ProviderReport> = groups
+        .into_iter()
+        .map(|(provider, samples)| {
+            let durations: Vec<Duration> = samples.iter().map(|s| s.duration).collect();
+            let ttfts: Vec<Duration> = samples.iter().filter_map(|s| s.ttft).collect();
+            let output_tokens = samples.iter().map(|s| s.output_tokens).sum();
+            ProviderReport {
+                provider: provider.to_string(),
+                requests: samples.len(),
+                errors: samples.iter().filter(|s| s.error.is_some()).count(),
+                p50_ms: ms(&durations, 50),
+                p95_ms: ms(&durations, 95),
+                p99_ms: ms(&durations, 99),
+                ttft_p50_ms: (!ttfts.is_empty()).then(|| ms(&ttfts, 50)),
+                ttft_p95_ms: (!ttfts.is_empty()).then(|| ms(&ttfts, 95)),
+                output_tokens,
+                requests_per_sec: samples.len() as f64 / secs,
+                tokens_per_sec: output_tokens as f64 / secs,
+            }
+        })
+        .collect();
+    reports.sort_by_key(|r| std::cmp::Reverse(r.requests));
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(provider: &str, duration_ms: u64, ttft_ms: Option<u64>, tokens: u64) -> Sample {
+        Sample {
+            provider: provider.to_string(),
+            status: 200,
+            duration: Duration::from_millis(duration_ms),
+            ttft: ttft_ms.map(Duration::from_millis),
+            output_tokens: tokens,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn samples_are_reported_per_provider() {
+        let mut failed = sample("", 5, None, 0);
+        failed.status = 0;
+        failed.error = Some("connection refused".to_string());
+        let samples = vec![
+            sample("ollama", 1000, Some(200), 50),
+            sample("ollama", 2000, Some(400), 100),
+            sample("ollama", 3000, Some(600), 150),
+            sample("anthropic", 500, Some(100), 20),
+            failed,
+        ];
+        let reports = report(&samples, Duration::from_secs(10));
+
+        assert_eq!(reports.len(), 3);
+        let ollama = &reports[0];
+        assert_eq!(ollama.provider, "ollama");
+        assert_eq!(ollama.requests, 3);
+        assert_eq!(ollama.errors, 0);
+        assert_eq!((ollama.p50_ms, ollama.p99_ms), (2000, 3000));
+        assert_eq!(ollama.ttft_p50_ms, Some(400));
+        assert_eq!(ollama.output_tokens, 300);
+        assert!((ollama.tokens_per_sec - 30.0).abs() < f64::EPSILON);
+        assert!((ollama.requests_per_sec - 0.3).abs() < 1e-9);
+
+        let unrouted = reports.iter().find(|r| r.provider == "-").unwrap();
+        assert_eq!(unrouted.errors, 1);
+        assert_eq!(unrouted.ttft_p50_ms, None);
+    }
+}
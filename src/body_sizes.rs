@@ -0,0 +1,352 @@
+//! How close bodies come to `server.max_body_size`: request bodies, and
+//! the translated responses croxy reads in full, counted into buckets by
+//! their share of the limit, for each route and in all. Without this the
+//! limit is tuned by guesswork until requests start failing, so croxy
+//! warns once too many requests come near it and `croxy status` suggests
+//! a larger one.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Share of `max_body_size` past which a body counts as near the limit.
+pub const NEAR_LIMIT: f64 = 0.8;
+
+/// Upper bounds of the buckets, as shares of `max_body_size`; a last
+/// bucket holds bodies over the limit.
+pub const BUCKETS: [f64; 5] = [0.1, 0.25, 0.5, NEAR_LIMIT, 1.0];
+
+/// Requests to see before their share near the limit means much.
+const MIN_SAMPLES: u64 = 20;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Whether a body was sent to croxy or received from a provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+/// Sizes of the bodies seen in one direction.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SizeStats {
+    pub count: u64,
+    pub total_bytes: u64,
+    pub largest: u64,
+    /// Bodies within each of [`BUCKETS`], then those over the limit.
+    pub buckets: [u64; BUCKETS.len() + 1],
+}
+
+impl SizeStats {
+    fn observe(&mut self, bytes: u64, limit: u64) {
+        let share = bytes as f64 / limit as f64;
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| share <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_bytes += bytes;
+        self.largest = self.largest.max(bytes);
+    }
+
+    /// Bodies over [`NEAR_LIMIT`] of the limit, including those over it.
+    pub fn near_limit(&self) -> u64 {
+        self.buckets[BUCKETS.len() - 1..].iter().sum()
+    }
+
+    /// `label`, then how many bodies there were and how they spread over
+    /// the buckets.
+    fn line(&self, label: &str) -> String {
+        if self.count == 0 {
+            return format!("{label}: none");
+        }
+        let bounds = BUCKETS
+            .iter()
+            .map(|bound| format!("<={:.0}%", bound * 100.0))
+            .chain(["over".to_string()]);
+        let buckets: Vec<String> = bounds
+            .zip(self.buckets)
+            .map(|(bound, count)| format!("{bound} {count}"))
+            .collect();
+        format!(
+            "{label}: {}, largest {}, {:.0}% near the limit ({})",
+            self.count,
+            format_bytes(self.largest),
+            self.near_limit_percent(),
+            buckets.join(", ")
+        )
+    }
+
+    /// Percentage of bodies near the limit.
+    pub fn near_limit_percent(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.near_limit() as f64 * 100.0 / self.count as f64
+    }
+}
+
+/// The bodies one route has seen: by route name, or provider for routes
+/// without one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteSizes {
+    pub route: String,
+    pub requests: SizeStats,
+    pub responses: SizeStats,
+}
+
+/// Body sizes since croxy started, as `GET /_croxy/status` reports them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BodySizeReport {
+    pub max_body_size: u64,
+    pub requests: SizeStats,
+    pub responses: SizeStats,
+    #[serde(default)]
+    pub routes: Vec<RouteSizes>,
+    /// A larger `max_body_size` to try, when too many requests come near
+    /// the limit.
+    #[serde(default)]
+    pub hint: Option<String>,
+}
+
+impl BodySizeReport {
+    /// The report as `croxy status` prints it: a line for all requests,
+    /// all responses, and each route.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "max_body_size {}",
+            format_bytes(self.max_body_size)
+        )];
+        lines.push(self.requests.line("requests"));
+        lines.push(self.responses.line("responses"));
+        for route in &self.routes {
+            lines.push(route.requests.line(&format!("  {} requests", route.route)));
+            if route.responses.count > 0 {
+                lines.push(
+                    route
+                        .responses
+                        .line(&format!("  {} responses", route.route)),
+                );
+            }
+        }
+        lines
+    }
+}
+
+/// Counts body sizes against the limit and decides when they're worth a
+/// warning.
+#[derive(Debug, Clone)]
+pub struct BodySizes {
+    limit: u64,
+    /// Percentage of requests near the limit worth a warning.
+    warn_percent: f64,
+    requests: SizeStats,
+    responses: SizeStats,
+    routes: BTreeMap<String, (SizeStats, SizeStats)>,
+    /// Whether the share near the limit has been warned about, until it
+    /// falls back under.
+    warned: bool,
+}
+
+impl BodySizes {
+    pub fn new(limit: usize, warn_percent: f64) -> Self {
+        Self {
+            limit: limit.max(1) as u64,
+            warn_percent,
+            requests: SizeStats::default(),
+            responses: SizeStats::default(),
+            routes: BTreeMap::new(),
+            warned: false,
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn warn_percent(&self) -> f64 {
+        self.warn_percent
+    }
+
+    /// Counts a body of `bytes`, under `route` when it was routed. Returns
+    /// a warning the first time the share of requests near the limit
+    /// reaches `warn_percent`.
+    pub fn observe(
+        &mut self,
+        direction: Direction,
+        route: Option<&str>,
+        bytes: usize,
+    ) -> Option<String> {
+        let (bytes, limit) = (bytes as u64, self.limit);
+        let route = route.map(|route| self.routes.entry(route.to_string()).or_default());
+        let (all, route) = match direction {
+            Direction::Request => (&mut self.requests, route.map(|(requests, _)| requests)),
+            Direction::Response => (&mut self.responses, route.map(|(_, responses)| responses)),
+        };
+        all.observe(bytes, limit);
+        if let Some(route) = route {
+            route.observe(bytes, limit);
+        }
+        if direction == Direction::Response {
+            return None;
+        }
+        let crowded = self.crowded();
+        if crowded && !self.warned {
+            self.warned = true;
+            return Some(format!(
+                "{:.0}% of requests were over {:.0}% of max_body_size ({})",
+                self.requests.near_limit_percent(),
+                NEAR_LIMIT * 100.0,
+                format_bytes(self.limit)
+            ));
+        }
+        self.warned &= crowded;
+        None
+    }
+
+    /// Whether enough requests come near the limit to raise it.
+    fn crowded(&self) -> bool {
+        self.requests.count >= MIN_SAMPLES
+            && self.requests.near_limit() > 0
+            && self.requests.near_limit_percent() >= self.warn_percent
+    }
+
+    /// A larger `max_body_size` to try, with why, when too many requests
+    /// come near the limit: half again the largest body seen, in whole
+    /// MiB.
+    pub fn hint(&self) -> Option<String> {
+        if !self.crowded() {
+            return None;
+        }
+        let wanted = self.requests.largest.max(self.limit) * 3 / 2;
+        let suggested = wanted.div_ceil(MIB) * MIB;
+        Some(format!(
+            "{:.0}% of requests were over {:.0}% of max_body_size ({}), the largest {}; \
+             try max_body_size = {suggested} ({})",
+            self.requests.near_limit_percent(),
+            NEAR_LIMIT * 100.0,
+            format_bytes(self.limit),
+            format_bytes(self.requests.largest),
+            format_bytes(suggested)
+        ))
+    }
+
+    pub fn report(&self) -> BodySizeReport {
+        BodySizeReport {
+            max_body_size: self.limit,
+            requests: self.requests.clone(),
+            responses: self.responses.clone(),
+            routes: self
+                .routes
+                .iter()
+                .map(|(route, (requests, responses))| RouteSizes {
+                    route: route.clone(),
+                    requests: requests.clone(),
+                    responses: responses.clone(),
+                })
+                .collect(),
+            hint: self.hint(),
+        }
+    }
+}
+
+/// `bytes` in the largest unit that keeps it over 1.
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= MIB => format!("{:.1} MiB", b as f64 / MIB as f64),
+        b if b >= 1024 => format!("{:.1} KiB", b as f64 / 1024.0),
+        b => format!("{b} B"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_are_counted_by_share_of_the_limit() {
+        let mut sizes = BodySizes::new(1000, 5.0);
+        for bytes in [50, 100, 300, 790, 900, 1000, 4000] {
+            sizes.observe(Direction::Request, Some("coding"), bytes);
+        }
+        sizes.observe(Direction::Request, None, 20);
+        sizes.observe(Direction::Response, Some("coding"), 850);
+
+        let report = sizes.report();
+        assert_eq!(report.requests.buckets, [3, 0, 1, 1, 2, 1]);
+        assert_eq!(report.requests.count, 8);
+        assert_eq!(report.requests.largest, 4000);
+        assert_eq!(report.requests.near_limit(), 3);
+        assert_eq!(report.responses.buckets, [0, 0, 0, 0, 1, 0]);
+        assert_eq!(report.routes.len(), 1);
+        assert_eq!(report.routes[0].route, "coding");
+        assert_eq!(report.routes[0].requests.count, 7);
+        assert_eq!(report.routes[0].responses.count, 1);
+    }
+
+    #[test]
+    fn crowding_the_limit_is_warned_about_once_and_hinted_at() {
+        let mut sizes = BodySizes::new(10 * MIB as usize, 10.0);
+        let mut warnings = Vec::new();
+        for _ in 0..18 {
+            warnings.extend(sizes.observe(Direction::Request, None, 1024));
+        }
+        for _ in 0..2 {
+            warnings.extend(sizes.observe(Direction::Request, None, 9 * MIB as usize));
+        }
+        assert_eq!(
+            warnings,
+            ["10% of requests were over 80% of max_body_size (10.0 MiB)"]
+        );
+        assert_eq!(
+            sizes.hint().unwrap(),
+            "10% of requests were over 80% of max_body_size (10.0 MiB), the largest 9.0 MiB; \
+             try max_body_size = 15728640 (15.0 MiB)"
+        );
+        assert!(
+            sizes
+                .observe(Direction::Request, None, 9 * MIB as usize)
+                .is_none()
+        );
+
+        // Once the share falls back under, it's warned about again
+        for _ in 0..20 {
+            sizes.observe(Direction::Request, None, 1024);
+        }
+        assert_eq!(sizes.hint(), None);
+        let mut warnings = Vec::new();
+        for _ in 0..3 {
+            warnings.extend(sizes.observe(Direction::Request, None, 9 * MIB as usize));
+        }
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn reports_print_a_line_per_route() {
+        let mut sizes = BodySizes::new(1000, 5.0);
+        sizes.observe(Direction::Request, Some("coding"), 50);
+        sizes.observe(Direction::Request, Some("coding"), 900);
+        assert_eq!(
+            sizes.report().lines(),
+            [
+                "max_body_size 1000 B",
+                "requests: 2, largest 900 B, 50% near the limit \
+                 (<=10% 1, <=25% 0, <=50% 0, <=80% 0, <=100% 1, over 0)",
+                "responses: none",
+                "  coding requests: 2, largest 900 B, 50% near the limit \
+                 (<=10% 1, <=25% 0, <=50% 0, <=80% 0, <=100% 1, over 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn few_requests_are_not_enough_for_a_hint() {
+        let mut sizes = BodySizes::new(1000, 5.0);
+        for _ in 0..5 {
+            assert_eq!(sizes.observe(Direction::Request, None, 990), None);
+        }
+        assert_eq!(sizes.hint(), None);
+    }
+}
@@ -0,0 +1,197 @@
+//! Prompt caching breakpoints. Anthropic caches the prefix of a request up
+//! to each `cache_control` marker, in the order tools, system, messages, so
+//! markers after long tool definitions and system prompts make repeat
+//! requests cheaper.
+
+use serde_json::{Map, Value, json};
+
+use crate::config::CacheControl;
+
+/// Prefixes shorter than this (estimated) are below the smallest cacheable
+/// size, so marking them would do nothing.
+pub const MIN_CACHEABLE_TOKENS: usize = 1024;
+
+/// Applies `mode` to a Messages request body. Returns whether the body
+/// changed.
+pub fn apply(mode: CacheControl, body: &mut Value) -> bool {
+    match mode {
+        CacheControl::Passthrough => false,
+        CacheControl::Inject => inject(body),
+        CacheControl::Strip => strip(body),
+    }
+}
+
+fn estimated_tokens(value: &Value) -> usize {
+    value.to_string().len() / 4
+}
+
+/// Marks the end of the tool definitions and of the system prompt, each
+/// when the prefix up to it is large enough to cache. Requests that already
+/// carry a breakpoint are left to the client.
+pub fn inject(body: &mut Value) -> bool {
+    if has_breakpoint(body) {
+        return false;
+    }
+    let mut changed = false;
+    let mut prefix = 0;
+    if let Some(tools) = body.get_mut("tools").and_then(Value::as_array_mut)
+        && !tools.is_empty()
+    {
+        prefix += tools.iter().map(estimated_tokens).sum::<usize>();
+        if prefix >= MIN_CACHEABLE_TOKENS
+            && let Some(last) = tools.last_mut().and_then(Value::as_object_mut)
+        {
+            last.insert("cache_control".to_string(), ephemeral());
+            changed = true;
+        }
+    }
+    if let Some(system) = body.get_mut("system") {
+        prefix += estimated_tokens(system);
+        if prefix >= MIN_CACHEABLE_TOKENS {
+            // A plain string has nowhere to hold the marker
+            if let Value::String(text) = system {
+                *system = json!([{"type": "text", "text": text}]);
+            }
+            if let Some(last) = system
+                .as_array_mut()
+                .and_then(|blocks| blocks.last_mut())
+                .and_then(Value::as_object_mut)
+            {
+                last.insert("cache_control".to_string(), ephemeral());
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+fn ephemeral() -> Value {
+    json!({"type": "ephemeral"})
+}
+
+/// Visits the objects a breakpoint can sit on: tools, system blocks, and
+/// content blocks, including those nested in tool results.
+fn each_markable(body: &mut Value, f: &mut impl FnMut(&mut Map<String, Value>)) {
+    let Some(fields) = body.as_object_mut() else {
+        return;
+    };
+    for (key, value) in fields.iter_mut() {
+        match (key.as_str(), value) {
+            ("tools" | "system", Value::Array(items)) => {
+                items
+                    .iter_mut()
+                    .filter_map(Value::as_object_mut)
+                    .for_each(&mut *f);
+            }
+            ("messages", Value::Array(messages)) => {
+                for message in messages {
+                    let Some(Value::Array(blocks)) = message.get_mut("content") else {
+                        continue;
+                    };
+                    for block in blocks.iter_mut().filter_map(Value::as_object_mut) {
+                        f(block);
+                        if let Some(Value::Array(nested)) = block.get_mut("content") {
+                            nested
+                                .iter_mut()
+                                .filter_map(Value::as_object_mut)
+                                .for_each(&mut *f);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn has_breakpoint(body: &mut Value) -> bool {
+    let mut found = false;
+    each_markable(body, &mut |object| {
+        found |= object.contains_key("cache_control");
+    });
+    found
+}
+
+/// Removes every breakpoint. Tool schemas are left alone, where a
+/// `cache_control` property would be the tool's own.
+pub fn strip(body: &mut Value) -> bool {
+    let mut changed = false;
+    each_markable(body, &mut |object| {
+        changed |= object.remove("cache_control").is_some();
+    });
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_text() -> String {
+        "x".repeat(MIN_CACHEABLE_TOKENS * 4)
+    }
+
+    #[test]
+    fn inject_marks_large_system_prompt() {
+        let mut body =
+            json!({"system": long_text(), "messages": [{"role": "user", "content": "hi"}]});
+        assert!(apply(CacheControl::Inject, &mut body));
+        assert_eq!(body["system"][0]["text"], long_text());
+        assert_eq!(body["system"][0]["cache_control"]["type"], "ephemeral");
+    }
+
+    #[test]
+    fn inject_marks_last_tool_and_system() {
+        let tool = |name: &str| json!({"name": name, "description": long_text(), "input_schema": {"type": "object"}});
+        let mut body = json!({
+            "tools": [tool("a"), tool("b")],
+            "system": [{"type": "text", "text": "short"}],
+            "messages": []
+        });
+        assert!(inject(&mut body));
+        assert!(body["tools"][0].get("cache_control").is_none());
+        assert_eq!(body["tools"][1]["cache_control"]["type"], "ephemeral");
+        // The system prompt is short, but the prefix through it is not
+        assert_eq!(body["system"][0]["cache_control"]["type"], "ephemeral");
+    }
+
+    #[test]
+    fn inject_skips_small_and_client_marked_requests() {
+        let mut small = json!({"system": "be brief", "messages": []});
+        assert!(!inject(&mut small));
+        assert_eq!(small["system"], "be brief");
+
+        let mut marked = json!({
+            "system": long_text(),
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "hi", "cache_control": {"type": "ephemeral"}}
+            ]}]
+        });
+        assert!(!inject(&mut marked));
+        assert!(marked["system"].is_string());
+    }
+
+    #[test]
+    fn strip_removes_markers_but_not_schema_properties() {
+        let mut body = json!({
+            "system": [{"type": "text", "text": "s", "cache_control": {"type": "ephemeral"}}],
+            "tools": [{
+                "name": "t",
+                "input_schema": {"type": "object", "properties": {"cache_control": {"type": "string"}}},
+                "cache_control": {"type": "ephemeral"}
+            }],
+            "messages": [{"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": [
+                    {"type": "text", "text": "r", "cache_control": {"type": "ephemeral"}}
+                ]}
+            ]}]
+        });
+        assert!(apply(CacheControl::Strip, &mut body));
+        assert!(!body.to_string().contains("ephemeral"));
+        assert!(
+            body["tools"][0]["input_schema"]["properties"]
+                .get("cache_control")
+                .is_some()
+        );
+        assert!(!strip(&mut body));
+    }
+}
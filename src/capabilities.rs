@@ -0,0 +1,106 @@
+//! What each provider's API offers, as declared in
+//! `[provider.<name>.capabilities]`. A request needing something its
+//! provider lacks is answered with a 501 naming what's missing, or sent
+//! to the provider's `capability_fallback`, rather than forwarded to a
+//! backend that would 404 on it.
+
+use crate::batches::BATCHES_PATH;
+use crate::config::Capabilities;
+use crate::router::Endpoint;
+
+const FILES_PATH: &str = "/v1/files";
+
+/// An Anthropic API feature a provider may not offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    CountTokens,
+    Batches,
+    Files,
+    Streaming,
+}
+
+impl Capability {
+    /// What a request to `path` needs, if anything. `streams` says whether
+    /// it asks for a streamed response.
+    pub fn needed(path: &str, streams: bool) -> Option<Self> {
+        if path == BATCHES_PATH || path.starts_with(&format!("{BATCHES_PATH}/")) {
+            return Some(Capability::Batches);
+        }
+        if path == FILES_PATH || path.starts_with(&format!("{FILES_PATH}/")) {
+            return Some(Capability::Files);
+        }
+        match Endpoint::of(path) {
+            Endpoint::CountTokens => Some(Capability::CountTokens),
+            Endpoint::Messages if streams => Some(Capability::Streaming),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::CountTokens => "count_tokens",
+            Capability::Batches => "batches",
+            Capability::Files => "files",
+            Capability::Streaming => "streaming",
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::CountTokens => self.count_tokens,
+            Capability::Batches => self.batches,
+            Capability::Files => self.files,
+            Capability::Streaming => self.streaming,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_the_capability_their_endpoint_belongs_to() {
+        let needed = Capability::needed;
+        assert_eq!(
+            needed("/v1/messages/count_tokens", false),
+            Some(Capability::CountTokens)
+        );
+        assert_eq!(
+            needed("/v1/messages/batches", false),
+            Some(Capability::Batches)
+        );
+        assert_eq!(
+            needed("/v1/messages/batches/msgbatch_1/results", false),
+            Some(Capability::Batches)
+        );
+        assert_eq!(needed("/v1/files/file_1", false), Some(Capability::Files));
+        assert_eq!(needed("/v1/messages", true), Some(Capability::Streaming));
+        assert_eq!(needed("/v1/messages", false), None);
+        assert_eq!(needed("/v1/models", true), None);
+        assert_eq!(needed("/v1/filesystem", false), None);
+    }
+
+    #[test]
+    fn everything_is_supported_unless_turned_off() {
+        let all = Capabilities::default();
+        let no_batches = Capabilities {
+            batches: false,
+            ..Capabilities::default()
+        };
+        for capability in [
+            Capability::CountTokens,
+            Capability::Batches,
+            Capability::Files,
+            Capability::Streaming,
+        ] {
+            assert!(all.supports(capability));
+            assert_eq!(
+                no_batches.supports(capability),
+                capability != Capability::Batches
+            );
+        }
+    }
+}
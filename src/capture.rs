@@ -0,0 +1,233 @@
+//! Request capture for `croxy replay`. With `[capture]` enabled, each
+//! proxied request is saved with its outcome as `<request id>.json`, and
+//! the oldest files are removed beyond `max_requests`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::metrics::RequestRecord;
+
+/// How a request went, as recorded or as replayed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Outcome {
+    pub provider: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl Outcome {
+    pub fn from_record(record: &RequestRecord) -> Self {
+        Self {
+            provider: record.provider.clone(),
+            status: record.status,
+            duration_ms: record.duration.as_millis() as u64,
+            input_tokens: record.input_tokens,
+            output_tokens: record.output_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capture {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    /// The request's `anthropic-*` headers. Credentials are never saved.
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<Value>,
+    pub outcome: Option<Outcome>,
+}
+
+impl Capture {
+    pub fn new(
+        id: &str,
+        method: &http::Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: Option<Value>,
+    ) -> Self {
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("anthropic-"))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        Self {
+            id: id.to_string(),
+            timestamp: Utc::now(),
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+            body,
+            outcome: None,
+        }
+    }
+
+    /// The model the client asked for.
+    pub fn model(&self) -> &str {
+        self.body
+            .as_ref()
+            .and_then(|b| b["model"].as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Where captures are written, pruned to the newest `max_requests`.
+pub struct CaptureStore {
+    dir: PathBuf,
+    max_requests: usize,
+    saved: Mutex<VecDeque<PathBuf>>,
+}
+
+impl CaptureStore {
+    pub fn open(dir: PathBuf, max_requests: usize) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("failed to create {}: {e}", dir.display()))?;
+        let saved = list_files(&dir).into_iter().collect();
+        Ok(Self {
+            dir,
+            max_requests,
+            saved: Mutex::new(saved),
+        })
+    }
+
+    pub fn start(self: &Arc<Self>, capture: Capture) -> PendingCapture {
+        PendingCapture {
+            store: self.clone(),
+            capture,
+        }
+    }
+
+    fn save(&self, capture: &Capture) {
+        let path = self.dir.join(format!("{}.json", capture.id));
+        let content = serde_json::to_vec(capture).expect("capture serializes");
+        if let Err(e) = std::fs::write(&path, content) {
+            tracing::warn!("failed to save capture {}: {e}", path.display());
+            return;
+        }
+        let mut saved = self.saved.lock().expect("capture lock poisoned");
+        saved.push_back(path);
+        while saved.len() > self.max_requests {
+            if let Some(oldest) = saved.pop_front() {
+                let _ = std::fs::remove_file(oldest);
+            }
+        }
+    }
+}
+
+/// A captured request waiting for its outcome.
+pub struct PendingCapture {
+    store: Arc<CaptureStore>,
+    capture: Capture,
+}
+
+impl PendingCapture {
+    pub fn finish(mut self, record: &RequestRecord) {
+        self.capture.outcome = Some(Outcome::from_record(record));
+        self.store.save(&self.capture);
+    }
+}
+
+/// Capture files in `dir`, oldest first.
+fn list_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<_> = entries
+        .flatten()
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect();
+    files.sort();
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+pub fn load(dir: &Path, id: &str) -> Result<Capture, String> {
+    let path = dir.join(format!("{id}.json"));
+    let content = std::fs::read_to_string(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => format!("no capture '{id}' in {}", dir.display()),
+        _ => format!("failed to read {}: {e}", path.display()),
+    })?;
+    serde_json::from_str(&content).map_err(|e| format!("invalid {}: {e}", path.display()))
+}
+
+/// The newest `limit` captures, newest first.
+pub fn recent(dir: &Path, limit: usize) -> Vec<Capture> {
+    list_files(dir)
+        .iter()
+        .rev()
+        .take(limit)
+        .filter_map(|path| serde_json::from_slice(&std::fs::read(path).ok()?).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn captures_keep_anthropic_headers_but_not_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "sk-ant-secret".parse().unwrap());
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("anthropic-version", "2023-06-01".parse().unwrap());
+        let capture = Capture::new(
+            "a-1",
+            &http::Method::POST,
+            "/v1/messages",
+            &headers,
+            Some(serde_json::json!({"model": "claude-opus-4-6"})),
+        );
+        assert_eq!(capture.model(), "claude-opus-4-6");
+        assert_eq!(
+            capture.headers.into_iter().collect::<Vec<_>>(),
+            vec![("anthropic-version".to_string(), "2023-06-01".to_string())]
+        );
+    }
+
+    #[test]
+    fn finished_captures_are_saved_and_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(CaptureStore::open(dir.path().to_path_buf(), 2).unwrap());
+        for id in ["a-1", "a-2", "a-3"] {
+            let capture = Capture::new(
+                id,
+                &http::Method::POST,
+                "/v1/messages",
+                &HeaderMap::new(),
+                None,
+            );
+            store.start(capture).finish(&RequestRecord {
+                duration: Duration::from_millis(1500),
+                output_tokens: 20,
+                ..RequestRecord::sample()
+            });
+        }
+        assert!(
+            load(dir.path(), "a-1")
+                .unwrap_err()
+                .starts_with("no capture 'a-1'")
+        );
+        let capture = load(dir.path(), "a-3").unwrap();
+        assert_eq!(
+            capture.outcome,
+            Some(Outcome {
+                provider: "anthropic".to_string(),
+                status: 200,
+                duration_ms: 1500,
+                input_tokens: 100,
+                output_tokens: 20,
+            })
+        );
+        assert_eq!(recent(dir.path(), 10).len(), 2);
+    }
+}
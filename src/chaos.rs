@@ -0,0 +1,237 @@
+//! `[chaos]`: fault injection for testing how clients cope with a degraded
+//! provider. Requests matching a rule can be held back before they are
+//! forwarded, answered with an injected error instead, or have their
+//! streamed response dropped partway. Every request a fault touched is
+//! flagged in its metrics record, and left out of the TUI's statistics.
+
+use std::time::Duration;
+
+use http::StatusCode;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::{ChaosConfig, ChaosRuleConfig};
+use crate::router::ResolvedRoute;
+
+/// Truncated streams are dropped at a random point within this many bytes.
+const TRUNCATE_WITHIN: u64 = 4096;
+
+struct Rule {
+    routes: Vec<String>,
+    latency: Option<(Duration, f64)>,
+    errors: Option<(Vec<StatusCode>, f64)>,
+    truncate: f64,
+}
+
+impl Rule {
+    fn from_config(config: &ChaosRuleConfig) -> Result<Self, String> {
+        let probability = |p: f64, field: &str| {
+            if (0.0..=1.0).contains(&p) {
+                Ok(p)
+            } else {
+                Err(format!("{field} must be between 0 and 1"))
+            }
+        };
+        let latency_probability = probability(config.latency_probability, "latency_probability")?;
+        let error_probability = probability(config.error_probability, "error_probability")?;
+        let truncate = probability(config.truncate_probability, "truncate_probability")?;
+        let statuses = config
+            .error_statuses
+            .iter()
+            .map(|&status| match StatusCode::from_u16(status) {
+                Ok(code) if code.is_client_error() || code.is_server_error() => Ok(code),
+                _ => Err(format!("error status {status} is not a 4xx or 5xx")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if error_probability > 0.0 && statuses.is_empty() {
+            return Err("error_probability needs at least one of error_statuses".to_string());
+        }
+        Ok(Self {
+            routes: config.routes.clone(),
+            latency: config
+                .latency_ms
+                .map(|ms| (Duration::from_millis(ms), latency_probability)),
+            errors: (error_probability > 0.0).then_some((statuses, error_probability)),
+            truncate,
+        })
+    }
+
+    fn matches(&self, provider: &str, route_name: Option<&str>) -> bool {
+        self.routes.is_empty()
+            || self
+                .routes
+                .iter()
+                .any(|name| route_name == Some(name) || provider == name)
+    }
+}
+
+/// Checks one `[[chaos.rules]]` entry.
+pub fn check(config: &ChaosRuleConfig) -> Result<(), String> {
+    Rule::from_config(config).map(|_| ())
+}
+
+/// What to do to one request.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Fault {
+    pub latency: Option<Duration>,
+    /// Answer with this instead of forwarding.
+    pub error: Option<StatusCode>,
+    /// Drop a streamed response after this many bytes.
+    pub truncate_after: Option<u64>,
+}
+
+impl Fault {
+    /// How the fault is flagged in metrics, or `None` when there isn't one.
+    pub fn label(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(latency) = self.latency {
+            parts.push(format!("latency {}ms", latency.as_millis()));
+        }
+        if let Some(status) = self.error {
+            parts.push(format!("error {}", status.as_u16()));
+        }
+        if self.truncate_after.is_some() {
+            parts.push("truncated".to_string());
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+pub struct Chaos {
+    rules: Vec<Rule>,
+    random: SystemRandom,
+}
+
+impl Chaos {
+    /// `None` when chaos is off or has no rules.
+    pub fn from_config(config: &ChaosConfig) -> Result<Option<Self>, String> {
+        if !config.enabled || config.rules.is_empty() {
+            return Ok(None);
+        }
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| Rule::from_config(rule).map_err(|e| format!("chaos.rules.{i}: {e}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Self {
+            rules,
+            random: SystemRandom::new(),
+        }))
+    }
+
+    /// Rolls the dice for a request on `route`.
+    pub fn roll(&self, route: &ResolvedRoute) -> Fault {
+        let (provider, name) = (&route.provider_name, route.route_name.as_deref());
+        self.roll_with(provider, name, || {
+            let mut bytes = [0u8; 8];
+            // Without randomness, no fault is injected
+            match self.random.fill(&mut bytes) {
+                Ok(()) => (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64,
+                Err(_) => 1.0,
+            }
+        })
+    }
+
+    /// `random` yields numbers in `[0, 1)`.
+    fn roll_with(
+        &self,
+        provider: &str,
+        route_name: Option<&str>,
+        mut random: impl FnMut() -> f64,
+    ) -> Fault {
+        let Some(rule) = self.rules.iter().find(|r| r.matches(provider, route_name)) else {
+            return Fault::default();
+        };
+        let mut fault = Fault::default();
+        if let Some((latency, p)) = rule.latency
+            && random() < p
+        {
+            fault.latency = Some(latency);
+        }
+        if let Some((ref statuses, p)) = rule.errors
+            && random() < p
+        {
+            let pick = (random() * statuses.len() as f64) as usize;
+            fault.error = Some(statuses[pick.min(statuses.len() - 1)]);
+            return fault;
+        }
+        if random() < rule.truncate {
+            fault.truncate_after = Some((random() * TRUNCATE_WITHIN as f64) as u64 + 1);
+        }
+        fault
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn chaos(toml: &str) -> Result<Option<Chaos>, String> {
+        #[derive(serde::Deserialize)]
+        struct Wrapper {
+            chaos: ChaosConfig,
+        }
+        let wrapper: Wrapper = Figment::from(Toml::string(toml)).extract().unwrap();
+        Chaos::from_config(&wrapper.chaos)
+    }
+
+    #[test]
+    fn rules_apply_to_their_routes_by_chance() {
+        let chaos = chaos(
+            r#"
+            [chaos]
+            enabled = true
+            [[chaos.rules]]
+            routes = ["opus"]
+            latency_ms = 1500
+            error_probability = 0.5
+            error_statuses = [429, 529]
+            [[chaos.rules]]
+            routes = ["ollama"]
+            truncate_probability = 0.1
+            "#,
+        )
+        .unwrap()
+        .unwrap();
+
+        // Rolls below each probability hit; the next picks the status
+        let mut rolls = [0.0, 0.4, 0.9].into_iter();
+        let fault = chaos.roll_with("anthropic", Some("opus"), || rolls.next().unwrap());
+        assert_eq!(fault.latency, Some(Duration::from_millis(1500)));
+        assert_eq!(fault.error, Some(StatusCode::from_u16(529).unwrap()));
+        assert_eq!(fault.label().as_deref(), Some("latency 1500ms, error 529"));
+
+        let mut rolls = [0.0, 0.6, 0.0].into_iter();
+        let fault = chaos.roll_with("anthropic", Some("opus"), || rolls.next().unwrap());
+        assert_eq!(fault.error, None);
+        assert_eq!(fault.truncate_after, None);
+
+        let mut rolls = [0.05, 0.5].into_iter();
+        let fault = chaos.roll_with("ollama", None, || rolls.next().unwrap());
+        assert_eq!(fault.truncate_after, Some(2049));
+        assert_eq!(fault.label().as_deref(), Some("truncated"));
+
+        let fault = chaos.roll_with("anthropic", Some("haiku"), || 0.0);
+        assert_eq!(fault, Fault::default());
+        assert_eq!(fault.label(), None);
+    }
+
+    #[test]
+    fn rules_are_validated() {
+        assert!(
+            chaos("[chaos]\nenabled = false\n[[chaos.rules]]\nerror_probability = 2.0\n")
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            chaos("[chaos]\nenabled = true\n[[chaos.rules]]\nerror_probability = 2.0\n").err(),
+            Some("chaos.rules.0: error_probability must be between 0 and 1".to_string())
+        );
+        assert_eq!(
+            chaos("[chaos]\nenabled = true\n[[chaos.rules]]\nerror_statuses = [200]\n").err(),
+            Some("chaos.rules.0: error status 200 is not a 4xx or 5xx".to_string())
+        );
+    }
+}
@@ -1,5 +1,17 @@
 use std::fs;
+use std::io::IsTerminal;
 use std::path::Path;
+use std::process::Command;
+
+use figment::Figment;
+use figment::providers::{Format, Toml};
+
+use crate::config::Config;
+use crate::error::ExitStatus;
+use crate::router::Router;
+
+/// Prefix for environment variables that override config values.
+const ENV_PREFIX: &str = "CROXY_";
 
 fn format_toml_value(value: &toml_edit::Value) -> String {
     if let Some(s) = value.as_str() {
@@ -13,6 +25,45 @@ fn format_toml_value(value: &toml_edit::Value) -> String {
     }
 }
 
+/// `item` as JSON, for `--json` output. Dates are kept as strings.
+fn toml_to_json(item: &toml_edit::Item) -> serde_json::Value {
+    use serde_json::Value as Json;
+    match item {
+        toml_edit::Item::None => Json::Null,
+        toml_edit::Item::Value(value) => value_to_json(value),
+        toml_edit::Item::Table(table) => Json::Object(
+            table
+                .iter()
+                .map(|(key, item)| (key.to_string(), toml_to_json(item)))
+                .collect(),
+        ),
+        toml_edit::Item::ArrayOfTables(array) => Json::Array(
+            array
+                .iter()
+                .map(|table| toml_to_json(&toml_edit::Item::Table(table.clone())))
+                .collect(),
+        ),
+    }
+}
+
+fn value_to_json(value: &toml_edit::Value) -> serde_json::Value {
+    use serde_json::Value as Json;
+    match value {
+        toml_edit::Value::String(s) => Json::from(s.value().as_str()),
+        toml_edit::Value::Integer(n) => Json::from(*n.value()),
+        toml_edit::Value::Float(f) => Json::from(*f.value()),
+        toml_edit::Value::Boolean(b) => Json::from(*b.value()),
+        toml_edit::Value::Datetime(d) => Json::from(d.value().to_string()),
+        toml_edit::Value::Array(array) => Json::Array(array.iter().map(value_to_json).collect()),
+        toml_edit::Value::InlineTable(table) => Json::Object(
+            table
+                .iter()
+                .map(|(key, value)| (key.to_string(), value_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
 fn parse_toml_value(raw: &str) -> toml_edit::Item {
     if raw == "true" {
         toml_edit::value(true)
@@ -20,48 +71,252 @@ fn parse_toml_value(raw: &str) -> toml_edit::Item {
         toml_edit::value(false)
     } else if let Ok(n) = raw.parse::<i64>() {
         toml_edit::value(n)
+    } else if (raw.starts_with('{') || raw.starts_with('['))
+        && let Ok(v) = raw.parse::<toml_edit::Value>()
+    {
+        // Inline tables and arrays, e.g. '{ url = "http://localhost:11434" }'
+        toml_edit::Item::Value(v)
     } else {
         toml_edit::value(raw)
     }
 }
 
-pub fn config_set(config_path: &Path, key: &str, value: &str) {
+/// Turns an inline table value into a standard `[table]` so entries added
+/// from the CLI look like hand-written ones.
+fn into_table(item: toml_edit::Item) -> Option<toml_edit::Table> {
+    match item {
+        toml_edit::Item::Value(toml_edit::Value::InlineTable(t)) => {
+            let mut table = t.into_table();
+            table.fmt();
+            Some(table)
+        }
+        toml_edit::Item::Table(t) => Some(t),
+        _ => None,
+    }
+}
+
+/// Sets `segments` under `table`. Numeric segments index into arrays of
+/// tables (`routes.0.model`) and a trailing `name[]` appends a new table.
+fn set_in_table(
+    table: &mut dyn toml_edit::TableLike,
+    segments: &[&str],
+    value: toml_edit::Item,
+) -> Result<(), String> {
+    let Some((&seg, rest)) = segments.split_first() else {
+        return Err("empty key".to_string());
+    };
+
+    if let Some(name) = seg.strip_suffix("[]") {
+        if !rest.is_empty() {
+            return Err(format!("'{seg}' must be the last key segment"));
+        }
+        let entry = into_table(value).ok_or_else(|| {
+            format!("appending to '{name}' needs a table value, e.g. '{{ key = \"value\" }}'")
+        })?;
+        if !table.contains_key(name) {
+            table.insert(
+                name,
+                toml_edit::Item::ArrayOfTables(toml_edit::ArrayOfTables::new()),
+            );
+        }
+        table
+            .get_mut(name)
+            .and_then(|item| item.as_array_of_tables_mut())
+            .ok_or_else(|| format!("'{name}' is not an array of tables"))?
+            .push(entry);
+        return Ok(());
+    }
+
+    if rest.is_empty() {
+        let value = match value {
+            toml_edit::Item::Value(toml_edit::Value::InlineTable(_)) => {
+                toml_edit::Item::Table(into_table(value).expect("inline table converts"))
+            }
+            v => v,
+        };
+        table.insert(seg, value);
+        return Ok(());
+    }
+
+    if !table.contains_key(seg) {
+        if let Ok(i) = rest[0].parse::<usize>() {
+            return Err(format!("{seg}.{i} is out of range (0 entries)"));
+        }
+        // Implicit, so `a.b.c` doesn't leave an empty `[a]` header behind
+        let mut child = toml_edit::Table::new();
+        child.set_implicit(true);
+        table.insert(seg, toml_edit::Item::Table(child));
+    }
+    match table.get_mut(seg).expect("inserted above") {
+        toml_edit::Item::ArrayOfTables(entries) => {
+            let (&index, rest) = rest.split_first().expect("rest is non-empty");
+            let len = entries.len();
+            let i: usize = index
+                .parse()
+                .map_err(|_| format!("'{seg}' is an array; use {seg}.N or {seg}[]"))?;
+            let entry = entries
+                .get_mut(i)
+                .ok_or_else(|| format!("{seg}.{i} is out of range ({len} entries)"))?;
+            if rest.is_empty() {
+                *entry = into_table(value)
+                    .ok_or_else(|| format!("replacing {seg}.{i} needs a table value"))?;
+                return Ok(());
+            }
+            set_in_table(entry, rest, value)
+        }
+        item => {
+            let child = item
+                .as_table_like_mut()
+                .ok_or_else(|| format!("key segment '{seg}' is not a table"))?;
+            set_in_table(child, rest, value)
+        }
+    }
+}
+
+/// Checks that content still fits the `Config` schema (types and, in strict
+/// mode, known keys). Semantic checks are left out so a config can be built
+/// up one `set` at a time.
+fn check_schema(content: &str) -> Result<(), String> {
+    let file = Figment::from(Toml::string(content));
+    let config: Config = crate::config::apply_route_defaults(file.clone())
+        .extract()
+        .map_err(|e| format!("invalid config: {e}"))?;
+    if config.strict {
+        let value = file
+            .extract::<serde_json::Value>()
+            .map_err(|e| format!("invalid config: {e}"))?;
+        let unknown = crate::validate::unknown_keys::<Config>(value);
+        if !unknown.is_empty() {
+            return Err(format!(
+                "invalid config: unknown key '{}'",
+                unknown.join("', '")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Applies `key = value` to config content and returns the updated TOML,
+/// refusing changes that don't fit the config schema.
+pub fn config_assign(content: &str, key: &str, value: &str) -> Result<String, String> {
     let segments: Vec<&str> = key.split('.').collect();
     if segments.iter().any(|s| s.is_empty()) {
-        eprintln!("invalid key: {key}");
-        std::process::exit(1);
+        return Err(format!("invalid key: {key}"));
     }
 
+    let mut doc: toml_edit::DocumentMut = content
+        .parse()
+        .map_err(|e| format!("failed to parse config: {e}"))?;
+    set_in_table(doc.as_table_mut(), &segments, parse_toml_value(value))?;
+
+    let updated = doc.to_string();
+    check_schema(&updated)?;
+    Ok(updated)
+}
+
+pub fn config_set(config_path: &Path, key: &str, value: &str, json: bool) {
     let content = fs::read_to_string(config_path).unwrap_or_default();
-    let mut doc: toml_edit::DocumentMut = content.parse().unwrap_or_else(|e| {
-        eprintln!("failed to parse {}: {e}", config_path.display());
-        std::process::exit(1);
+    let updated =
+        config_assign(&content, key, value).unwrap_or_else(|e| ExitStatus::Config.fail(e));
+
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent).unwrap_or_else(|e| {
+            ExitStatus::Failure.fail(format!("failed to create {}: {e}", parent.display()))
+        });
+    }
+    fs::write(config_path, updated).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", config_path.display()))
     });
+    if json {
+        let value = toml_to_json(&parse_toml_value(value));
+        println!("{:#}", serde_json::json!({"key": key, "value": value}));
+    }
+}
 
-    let table_segments = &segments[..segments.len() - 1];
-    let leaf = segments[segments.len() - 1];
+/// Fields for a new `[[routes]]` entry.
+pub struct NewRoute {
+    pub provider: String,
+    pub pattern: Option<String>,
+    pub model: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
 
-    let mut current = doc.as_table_mut();
-    for &seg in table_segments {
-        if !current.contains_key(seg) {
-            current.insert(seg, toml_edit::Item::Table(toml_edit::Table::new()));
+impl NewRoute {
+    fn to_inline_table(&self) -> String {
+        let mut table = toml_edit::InlineTable::new();
+        for (key, value) in [
+            ("name", &self.name),
+            ("description", &self.description),
+            ("pattern", &self.pattern),
+            ("provider", &Some(self.provider.clone())),
+            ("model", &self.model),
+        ] {
+            if let Some(v) = value {
+                table.insert(key, v.as_str().into());
+            }
         }
-        current = current[seg].as_table_mut().unwrap_or_else(|| {
-            eprintln!("key segment '{seg}' is not a table");
-            std::process::exit(1);
-        });
+        table.to_string()
     }
-    current[leaf] = parse_toml_value(value);
+}
 
-    if let Some(parent) = config_path.parent() {
-        fs::create_dir_all(parent).unwrap_or_else(|e| {
-            eprintln!("failed to create {}: {e}", parent.display());
-            std::process::exit(1);
-        });
+/// Appends a route, warning if its provider isn't configured yet.
+pub fn config_add_route(config_path: &Path, route: &NewRoute, json: bool) {
+    let content = fs::read_to_string(config_path).unwrap_or_default();
+    if config_lookup(&content, &format!("provider.{}.url", route.provider)).is_err() {
+        eprintln!(
+            "warning: provider '{}' is not configured yet, add it with `croxy config set provider.{}.url URL`",
+            route.provider, route.provider
+        );
+    }
+    let table = route.to_inline_table();
+    config_set(config_path, "routes[]", &table, false);
+    if json {
+        let route = toml_to_json(&parse_toml_value(&table));
+        println!("{:#}", serde_json::json!({ "route": route }));
+    }
+}
+
+/// Replaces the `[[pins]]` entry for `pattern` in config content with one
+/// to `provider`, or removes it with `None`.
+pub fn config_pin(content: &str, pattern: &str, provider: Option<&str>) -> Result<String, String> {
+    let mut doc: toml_edit::DocumentMut = content
+        .parse()
+        .map_err(|e| format!("failed to parse config: {e}"))?;
+    let pins = doc
+        .entry("pins")
+        .or_insert(toml_edit::Item::ArrayOfTables(
+            toml_edit::ArrayOfTables::new(),
+        ))
+        .as_array_of_tables_mut()
+        .ok_or("'pins' is not an array of tables")?;
+    let before = pins.len();
+    pins.retain(|pin| pin.get("pattern").and_then(|p| p.as_str()) != Some(pattern));
+    match provider {
+        Some(provider) => {
+            let mut pin = toml_edit::Table::new();
+            pin.insert("pattern", toml_edit::value(pattern));
+            pin.insert("provider", toml_edit::value(provider));
+            pins.push(pin);
+        }
+        None if pins.len() == before => return Err(format!("no saved pin for '{pattern}'")),
+        None => {}
     }
-    fs::write(config_path, doc.to_string()).unwrap_or_else(|e| {
-        eprintln!("failed to write {}: {e}", config_path.display());
-        std::process::exit(1);
+    if pins.is_empty() {
+        doc.remove("pins");
+    }
+    let updated = doc.to_string();
+    check_schema(&updated)?;
+    Ok(updated)
+}
+
+/// Saves a pin to the config file, or removes it with `None`.
+pub fn config_save_pin(config_path: &Path, pattern: &str, provider: Option<&str>) {
+    let content = fs::read_to_string(config_path).unwrap_or_default();
+    let updated =
+        config_pin(&content, pattern, provider).unwrap_or_else(|e| ExitStatus::Config.fail(e));
+    fs::write(config_path, updated).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", config_path.display()))
     });
 }
 
@@ -84,27 +339,269 @@ pub fn config_lookup(content: &str, key: &str) -> Result<String, String> {
         .ok_or_else(|| format!("key '{key}' is a table, not a value"))
 }
 
-pub fn config_get(config_path: &Path, key: &str) {
-    let content = match fs::read_to_string(config_path) {
+/// The value at `key` as JSON. Unlike `config_lookup`, a table is returned
+/// whole.
+pub fn config_lookup_json(content: &str, key: &str) -> Result<serde_json::Value, String> {
+    let doc: toml_edit::DocumentMut = content
+        .parse()
+        .map_err(|e| format!("failed to parse config: {e}"))?;
+
+    let mut current = doc.as_item();
+    for seg in key.split('.') {
+        current = current
+            .get(seg)
+            .ok_or_else(|| format!("key not found: {key}"))?;
+    }
+    Ok(toml_to_json(current))
+}
+
+fn read_config_file(config_path: &Path) -> String {
+    match fs::read_to_string(config_path) {
         Ok(c) => c,
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            eprintln!(
-                "config file not found: {}\nhint: run `croxy init` to create one",
-                config_path.display()
-            );
-            std::process::exit(1);
+            eprintln!("error: config file not found: {}", config_path.display());
+            eprintln!("hint: run `croxy init` to create one");
+            ExitStatus::Config.exit();
         }
         Err(e) => {
-            eprintln!("failed to read {}: {e}", config_path.display());
-            std::process::exit(1);
+            ExitStatus::Failure.fail(format!("failed to read {}: {e}", config_path.display()));
         }
-    };
+    }
+}
+
+pub fn config_get(config_path: &Path, key: &str, json: bool) {
+    let content = read_config_file(config_path);
 
+    if json {
+        match config_lookup_json(&content, key) {
+            Ok(value) => println!("{:#}", serde_json::json!({"key": key, "value": value})),
+            Err(e) => {
+                ExitStatus::Config.fail(e);
+            }
+        }
+        return;
+    }
     match config_lookup(&content, key) {
         Ok(value) => println!("{value}"),
         Err(e) => {
-            eprintln!("{e}");
-            std::process::exit(1);
+            ExitStatus::Config.fail(e);
+        }
+    }
+}
+
+/// Removes a key (or an entire table) from the config document and returns
+/// the updated TOML.
+pub fn config_remove(content: &str, key: &str) -> Result<String, String> {
+    let mut doc: toml_edit::DocumentMut = content
+        .parse()
+        .map_err(|e| format!("failed to parse config: {e}"))?;
+
+    let segments: Vec<&str> = key.split('.').collect();
+    if segments.iter().any(|s| s.is_empty()) {
+        return Err(format!("invalid key: {key}"));
+    }
+
+    let table_segments = &segments[..segments.len() - 1];
+    let leaf = segments[segments.len() - 1];
+
+    let mut current: Option<&mut dyn toml_edit::TableLike> = Some(doc.as_table_mut());
+    for &seg in table_segments {
+        current = current
+            .and_then(|t| t.get_mut(seg))
+            .and_then(|item| item.as_table_like_mut());
+    }
+
+    current
+        .and_then(|t| t.remove(leaf))
+        .ok_or_else(|| format!("key not found: {key}"))?;
+
+    Ok(doc.to_string())
+}
+
+pub fn config_unset(config_path: &Path, key: &str, json: bool) {
+    let content = read_config_file(config_path);
+
+    let updated = config_remove(&content, key).unwrap_or_else(|e| ExitStatus::Config.fail(e));
+
+    fs::write(config_path, updated).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", config_path.display()))
+    });
+    if json {
+        println!("{:#}", serde_json::json!({ "key": key }));
+    }
+}
+
+fn flatten_item(prefix: &str, item: &toml_edit::Item, out: &mut Vec<(String, String)>) {
+    if let Some(table) = item.as_table_like() {
+        for (key, child) in table.iter() {
+            let path = if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{prefix}.{key}")
+            };
+            flatten_item(&path, child, out);
+        }
+    } else if let Some(array) = item.as_array_of_tables() {
+        for (i, table) in array.iter().enumerate() {
+            for (key, child) in table.iter() {
+                flatten_item(&format!("{prefix}.{i}.{key}"), child, out);
+            }
+        }
+    } else if let Some(value) = item.as_value() {
+        out.push((prefix.to_string(), format_toml_value(value)));
+    }
+}
+
+/// Flattens the config document into dot-separated `(key, value)` pairs in
+/// document order. Array-of-tables entries are indexed (`routes.0.provider`).
+pub fn config_flatten(content: &str) -> Result<Vec<(String, String)>, String> {
+    let doc: toml_edit::DocumentMut = content
+        .parse()
+        .map_err(|e| format!("failed to parse config: {e}"))?;
+
+    let mut out = Vec::new();
+    flatten_item("", doc.as_item(), &mut out);
+    Ok(out)
+}
+
+/// Maps `CROXY_*` environment variables to the dot-separated config keys they
+/// override, mirroring the `Env::prefixed("CROXY_").split("_")` provider.
+pub fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = vars
+        .filter_map(|(name, value)| {
+            let rest = name.strip_prefix(ENV_PREFIX)?;
+            if rest.is_empty() {
+                return None;
+            }
+            Some((rest.to_lowercase().replace('_', "."), value))
+        })
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+pub fn config_list(config_path: &Path, json: bool) {
+    let content = fs::read_to_string(config_path).unwrap_or_default();
+    let mut entries = config_flatten(&content).unwrap_or_else(|e| ExitStatus::Config.fail(e));
+
+    if json {
+        let mut list: Vec<serde_json::Value> = entries
+            .into_iter()
+            .map(|(key, value)| serde_json::json!({"key": key, "value": value, "env": null}))
+            .collect();
+        for (key, value) in env_overrides(std::env::vars()) {
+            let env = format!("{ENV_PREFIX}{}", env_var_name(&key));
+            let entry = serde_json::json!({"key": key, "value": value, "env": env});
+            match list.iter_mut().find(|e| e["key"] == key) {
+                Some(existing) => *existing = entry,
+                None => list.push(entry),
+            }
+        }
+        println!("{:#}", serde_json::Value::Array(list));
+        return;
+    }
+
+    for (key, value) in env_overrides(std::env::vars()) {
+        let annotated = format!("{value}  (env: {ENV_PREFIX}{})", env_var_name(&key));
+        match entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = annotated,
+            None => entries.push((key, annotated)),
+        }
+    }
+
+    for (key, value) in entries {
+        println!("{key} = {value}");
+    }
+}
+
+fn env_var_name(key: &str) -> String {
+    key.to_uppercase().replace('.', "_")
+}
+
+/// Checks that the given TOML parses into a `Config` and builds a valid router.
+/// Validates config content as if saved in `base_dir`, so relative includes
+/// resolve the same way they will at startup. All problems are reported.
+pub fn validate_config_str(content: &str, base_dir: &Path) -> Result<(), String> {
+    let file = crate::config::config_figment_from_str(content, base_dir)
+        .map_err(|e| format!("invalid config: {e}"))?;
+    let config: Config = crate::config::apply_route_defaults(file.clone())
+        .extract()
+        .map_err(|e| format!("invalid config: {e}"))?;
+    let report = crate::validate::validate(&file, &config);
+    if !report.is_ok() {
+        return Err(format!("invalid config:\n  {}", report.errors.join("\n  ")));
+    }
+    Router::from_config(&config).map_err(|e| format!("invalid config: {e}"))?;
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{prompt} [Y/n] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    !answer.trim().eq_ignore_ascii_case("n")
+}
+
+/// Opens the config in `$EDITOR` (falling back to `$VISUAL`, then `vi`) on a
+/// scratch copy, and only writes it back once it validates.
+pub fn config_edit(config_path: &Path) {
+    let editor = std::env::var("EDITOR")
+        .or_else(|_| std::env::var("VISUAL"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    let original = fs::read_to_string(config_path).unwrap_or_default();
+    let scratch = config_path.with_extension("toml.edit");
+    fs::write(&scratch, &original).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", scratch.display()))
+    });
+
+    loop {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{editor} \"$1\""))
+            .arg("sh")
+            .arg(&scratch)
+            .status()
+            .unwrap_or_else(|e| {
+                ExitStatus::Failure.fail(format!("failed to launch editor '{editor}': {e}"))
+            });
+        if !status.success() {
+            let _ = fs::remove_file(&scratch);
+            ExitStatus::Failure.fail(format!("editor exited with {status}, config unchanged"));
+        }
+
+        let edited = fs::read_to_string(&scratch).unwrap_or_default();
+        if edited == original {
+            let _ = fs::remove_file(&scratch);
+            eprintln!("no changes");
+            return;
+        }
+
+        let base_dir = config_path.parent().unwrap_or(Path::new("."));
+        match validate_config_str(&edited, base_dir) {
+            Ok(()) => {
+                fs::write(config_path, edited).unwrap_or_else(|e| {
+                    ExitStatus::Failure
+                        .fail(format!("failed to write {}: {e}", config_path.display()));
+                });
+                let _ = fs::remove_file(&scratch);
+                eprintln!("saved {}", config_path.display());
+                return;
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                if !confirm("re-open editor?") {
+                    ExitStatus::Config.fail(format!(
+                        "config unchanged, edits kept in {}",
+                        scratch.display()
+                    ));
+                }
+            }
         }
     }
 }
@@ -120,7 +617,7 @@ mod tests {
         if !initial.is_empty() {
             fs::write(&path, initial).unwrap();
         }
-        config_set(&path, key, value);
+        config_set(&path, key, value, false);
         let content = fs::read_to_string(&path).unwrap();
         content.parse().unwrap()
     }
@@ -131,6 +628,116 @@ mod tests {
         assert_eq!(doc["logging"]["metrics"]["enabled"].as_bool(), Some(true));
     }
 
+    #[test]
+    fn set_does_not_leave_empty_parent_headers() {
+        let out = config_assign("", "provider.ollama.url", "http://localhost:11434").unwrap();
+        assert_eq!(out, "[provider.ollama]\nurl = \"http://localhost:11434\"\n");
+    }
+
+    #[test]
+    fn set_inline_table_creates_standard_table() {
+        let out = config_assign("", "provider.corp", "{ url = \"https://corp\" }").unwrap();
+        let doc: toml_edit::DocumentMut = out.parse().unwrap();
+        assert!(doc["provider"]["corp"].is_table());
+        assert_eq!(
+            doc["provider"]["corp"]["url"].as_str(),
+            Some("https://corp")
+        );
+    }
+
+    #[test]
+    fn set_appends_and_indexes_routes() {
+        let out = config_assign(
+            "",
+            "routes[]",
+            "{ pattern = \"opus\", provider = \"anthropic\" }",
+        )
+        .unwrap();
+        let out = config_assign(
+            &out,
+            "routes[]",
+            "{ pattern = \"sonnet\", provider = \"ollama\" }",
+        )
+        .unwrap();
+        let out = config_assign(&out, "routes.1.model", "qwen3-coder:30b").unwrap();
+        let doc: toml_edit::DocumentMut = out.parse().unwrap();
+        let routes = doc["routes"].as_array_of_tables().unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes.get(0).unwrap()["pattern"].as_str(), Some("opus"));
+        assert_eq!(
+            routes.get(1).unwrap()["model"].as_str(),
+            Some("qwen3-coder:30b")
+        );
+    }
+
+    #[test]
+    fn set_out_of_range_index_errors() {
+        let err = config_assign("", "routes.0.model", "x").unwrap_err();
+        assert!(err.contains("out of range"), "{err}");
+    }
+
+    #[test]
+    fn append_requires_table_value() {
+        let err = config_assign("", "routes[]", "opus").unwrap_err();
+        assert!(err.contains("needs a table value"), "{err}");
+    }
+
+    #[test]
+    fn set_rejects_schema_violations() {
+        let err = config_assign("", "server.prot", "3100").unwrap_err();
+        assert!(err.contains("unknown key 'server.prot'"), "{err}");
+        let err = config_assign("", "server.port", "not-a-port").unwrap_err();
+        assert!(err.contains("invalid config"), "{err}");
+        let err = config_assign("", "routes[]", "{ pattern = \"x\" }").unwrap_err();
+        assert!(err.contains("provider"), "{err}");
+    }
+
+    #[test]
+    fn set_allows_unknown_keys_when_not_strict() {
+        assert!(config_assign("strict = false\n", "custom.key", "1").is_ok());
+    }
+
+    #[test]
+    fn new_route_renders_inline_table() {
+        let route = NewRoute {
+            provider: "ollama".to_string(),
+            pattern: Some("sonnet|haiku".to_string()),
+            model: Some("qwen3-coder:30b".to_string()),
+            name: None,
+            description: None,
+        };
+        let out = config_assign("", "routes[]", &route.to_inline_table()).unwrap();
+        assert_eq!(
+            out,
+            "[[routes]]\npattern = \"sonnet|haiku\"\nprovider = \"ollama\"\nmodel = \"qwen3-coder:30b\"\n"
+        );
+    }
+
+    #[test]
+    fn pins_are_replaced_and_removed_by_pattern() {
+        let initial = "[[pins]]\npattern = \"opus\"\nprovider = \"anthropic\"\n";
+        let pinned = config_pin(initial, "sonnet", Some("ollama")).unwrap();
+        let pinned = config_pin(&pinned, "opus", Some("ollama")).unwrap();
+        let doc: toml_edit::DocumentMut = pinned.parse().unwrap();
+        let pins: Vec<_> = doc["pins"]
+            .as_array_of_tables()
+            .unwrap()
+            .iter()
+            .map(|pin| {
+                (
+                    pin["pattern"].as_str().unwrap(),
+                    pin["provider"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(pins, [("sonnet", "ollama"), ("opus", "ollama")]);
+
+        let unpinned = config_pin(&pinned, "sonnet", None).unwrap();
+        let unpinned = config_pin(&unpinned, "opus", None).unwrap();
+        assert!(!unpinned.contains("pins"));
+        assert!(config_pin(&unpinned, "opus", None).is_err());
+    }
+
     #[test]
     fn set_preserves_existing_values() {
         let doc = set_and_parse(
@@ -144,8 +751,8 @@ mod tests {
 
     #[test]
     fn set_bool_value() {
-        let doc = set_and_parse("", "flag", "true");
-        assert_eq!(doc["flag"].as_bool(), Some(true));
+        let doc = set_and_parse("", "auto_router.enabled", "true");
+        assert_eq!(doc["auto_router"]["enabled"].as_bool(), Some(true));
     }
 
     #[test]
@@ -180,4 +787,91 @@ mod tests {
         let err = config_lookup(toml, "server").unwrap_err();
         assert!(err.contains("table, not a value"));
     }
+
+    #[test]
+    fn get_json_keeps_types_and_tables() {
+        let toml = "[server]\nport = 3100\ntcp = true\n\n[[routes]]\npattern = \"opus\"\nprovider = \"a\"\n";
+        assert_eq!(
+            config_lookup_json(toml, "server").unwrap(),
+            serde_json::json!({"port": 3100, "tcp": true})
+        );
+        assert_eq!(
+            config_lookup_json(toml, "routes").unwrap(),
+            serde_json::json!([{"pattern": "opus", "provider": "a"}])
+        );
+        assert!(config_lookup_json(toml, "server.host").is_err());
+    }
+
+    #[test]
+    fn remove_leaf_key() {
+        let toml = "[server]\nhost = \"127.0.0.1\"\nport = 3100\n";
+        let updated = config_remove(toml, "server.port").unwrap();
+        assert_eq!(config_lookup(&updated, "server.host").unwrap(), "127.0.0.1");
+        assert!(config_lookup(&updated, "server.port").is_err());
+    }
+
+    #[test]
+    fn remove_whole_table() {
+        let toml = "[server]\nport = 3100\n[provider.a]\nurl = \"http://a\"\n";
+        let updated = config_remove(toml, "provider.a").unwrap();
+        assert!(!updated.contains("http://a"));
+        assert_eq!(config_lookup(&updated, "server.port").unwrap(), "3100");
+    }
+
+    #[test]
+    fn remove_missing_key_errors() {
+        let err = config_remove("[server]\nport = 3100\n", "server.host").unwrap_err();
+        assert!(err.contains("key not found"));
+        let err = config_remove("", "a.b.c").unwrap_err();
+        assert!(err.contains("key not found"));
+    }
+
+    #[test]
+    fn flatten_lists_nested_and_array_keys() {
+        let toml = r#"
+[server]
+port = 3100
+[provider.a]
+url = "http://a"
+[[routes]]
+pattern = "opus"
+provider = "a"
+"#;
+        let entries = config_flatten(toml).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("server.port".to_string(), "3100".to_string()),
+                ("provider.a.url".to_string(), "http://a".to_string()),
+                ("routes.0.pattern".to_string(), "opus".to_string()),
+                ("routes.0.provider".to_string(), "a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn env_overrides_map_to_keys() {
+        let vars = vec![
+            ("CROXY_SERVER_PORT".to_string(), "8080".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+            ("CROXY_".to_string(), "ignored".to_string()),
+        ];
+        assert_eq!(
+            env_overrides(vars.into_iter()),
+            vec![("server.port".to_string(), "8080".to_string())]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_default_provider() {
+        let err =
+            validate_config_str("[default]\nprovider = \"missing\"\n", Path::new(".")).unwrap_err();
+        assert!(err.contains("not found"), "got: {err}");
+    }
+
+    #[test]
+    fn validate_accepts_minimal_config() {
+        let toml = "[provider.anthropic]\nurl = \"https://api.anthropic.com\"\n";
+        assert!(validate_config_str(toml, Path::new(".")).is_ok());
+    }
 }
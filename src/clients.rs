@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::config::{Config, ProviderConfig};
+use crate::secrets::expand_home;
+
+tokio::task_local! {
+    /// Where a connection opened for the request being sent records how
+    /// long it took.
+    static CONNECT_TIME: Arc<OnceLock<Duration>>;
+}
+
+/// Runs `send`, a request on one of these clients, returning how long it
+/// spent opening a new connection, or `None` if it reused an idle one.
+pub async fn timing_connect<F: Future>(send: F) -> (F::Output, Option<Duration>) {
+    let connect_time = Arc::new(OnceLock::new());
+    let output = CONNECT_TIME.scope(connect_time.clone(), send).await;
+    (output, connect_time.get().copied())
+}
+
+/// Times each connection the client opens, for [`timing_connect`].
+#[derive(Clone)]
+struct TimeConnects;
+
+impl<S> Layer<S> for TimeConnects {
+    type Service = TimedConnector<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimedConnector(inner)
+    }
+}
+
+#[derive(Clone)]
+struct TimedConnector<S>(S);
+
+impl<S, R> Service<R> for TimedConnector<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        // Connections are opened while the request that needs one is
+        // being sent, so its slot is in scope
+        let connect_time = CONNECT_TIME.try_with(Arc::clone).ok();
+        let started = Instant::now();
+        let connecting = self.0.call(request);
+        Box::pin(async move {
+            let connected = connecting.await;
+            if let Some(connect_time) = connect_time {
+                let _ = connect_time.set(started.elapsed());
+            }
+            connected
+        })
+    }
+}
+
+/// Settings shared by every outbound client: no system proxy (croxy is
+/// usually itself the proxy in ANTHROPIC_BASE_URL), no redirects, timed
+/// connects, and HTTP/1.1 unless a provider opts into HTTP/2.
+pub fn client_builder() -> reqwest::ClientBuilder {
+    base_builder().http1_only()
+}
+
+fn base_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .no_proxy()
+        .redirect(reqwest::redirect::Policy::none())
+        .connector_layer(TimeConnects)
+}
+
+pub fn default_client() -> reqwest::Client {
+    client_builder()
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+fn has_custom_transport(provider: &ProviderConfig) -> bool {
+    provider.proxy_url.is_some()
+        || provider.ca_cert.is_some()
+        || provider.insecure_skip_verify
+        || provider.http2
+        || provider.pool_max_idle_per_host.is_some()
+        || provider.pool_idle_timeout_secs.is_some()
+        || provider.keepalive_secs.is_some()
+}
+
+/// Builds a client for a provider's proxy, TLS, HTTP/2, and connection
+/// pool settings.
+pub fn provider_client(name: &str, provider: &ProviderConfig) -> Result<reqwest::Client, String> {
+    // Over TLS, HTTP/2 is negotiated and falls back to HTTP/1.1; plain
+    // HTTP has no negotiation, so the server must speak it.
+    let mut builder = match (provider.http2, provider.url.starts_with("http://")) {
+        (true, true) => base_builder().http2_prior_knowledge(),
+        (true, false) => base_builder(),
+        (false, _) => client_builder(),
+    };
+
+    if let Some(max) = provider.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if let Some(secs) = provider.pool_idle_timeout_secs {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = provider.keepalive_secs {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+        if provider.http2 {
+            builder = builder
+                .http2_keep_alive_interval(Duration::from_secs(secs))
+                .http2_keep_alive_while_idle(true);
+        }
+    }
+
+    if let Some(ref url) = provider.proxy_url {
+        let proxy = reqwest::Proxy::all(url)
+            .map_err(|e| format!("provider '{name}': invalid proxy_url: {e}"))?;
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(ref path) = provider.ca_cert {
+        let path = expand_home(path);
+        let pem = fs::read(&path).map_err(|e| {
+            format!(
+                "provider '{name}': failed to read ca_cert {}: {e}",
+                path.display()
+            )
+        })?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| format!("provider '{name}': invalid ca_cert {}: {e}", path.display()))?;
+        if certs.is_empty() {
+            return Err(format!(
+                "provider '{name}': no certificates in ca_cert {}",
+                path.display()
+            ));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if provider.insecure_skip_verify {
+        tracing::warn!(provider = name, "TLS certificate verification disabled");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("provider '{name}': failed to build HTTP client: {e}"))
+}
+
+/// Builds clients for providers with custom transport settings. Providers
+/// without any share the default client.
+pub fn provider_clients(config: &Config) -> Result<HashMap<String, reqwest::Client>, String> {
+    config
+        .providers
+        .iter()
+        .filter(|(_, provider)| has_custom_transport(provider))
+        .map(|(name, provider)| Ok((name.clone(), provider_client(name, provider)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn config(toml: &str) -> Config {
+        Figment::new().merge(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[test]
+    fn only_customized_providers_get_clients() {
+        let cfg = config(
+            r#"
+            [provider.plain]
+            url = "https://api.anthropic.com"
+            [provider.corp]
+            url = "https://llm.corp.internal"
+            proxy_url = "http://proxy.corp.internal:3128"
+            [provider.lab]
+            url = "https://10.0.0.5"
+            insecure_skip_verify = true
+            "#,
+        );
+        let clients = provider_clients(&cfg).unwrap();
+        let mut names: Vec<&str> = clients.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["corp", "lab"]);
+    }
+
+    #[test]
+    fn pool_and_http2_settings_get_clients() {
+        let cfg = config(
+            r#"
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            http2 = true
+            keepalive_secs = 30
+            [provider.ollama]
+            url = "http://localhost:11434"
+            pool_max_idle_per_host = 4
+            pool_idle_timeout_secs = 300
+            "#,
+        );
+        let clients = provider_clients(&cfg).unwrap();
+        let mut names: Vec<&str> = clients.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["anthropic", "ollama"]);
+    }
+
+    #[test]
+    fn invalid_proxy_url_names_provider() {
+        let cfg = config(
+            r#"
+            [provider.corp]
+            url = "https://llm.corp.internal"
+            proxy_url = "not a url"
+            "#,
+        );
+        let err = provider_clients(&cfg).unwrap_err();
+        assert!(err.contains("provider 'corp'"), "{err}");
+        assert!(err.contains("proxy_url"), "{err}");
+    }
+
+    #[test]
+    fn missing_ca_cert_is_an_error() {
+        let cfg = config(
+            r#"
+            [provider.corp]
+            url = "https://llm.corp.internal"
+            ca_cert = "/nonexistent/ca.pem"
+            "#,
+        );
+        let err = provider_clients(&cfg).unwrap_err();
+        assert!(err.contains("failed to read ca_cert"), "{err}");
+    }
+
+    #[test]
+    fn ca_cert_without_certificates_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        fs::write(&path, "not a certificate\n").unwrap();
+        let cfg = config(&format!(
+            r#"
+            [provider.corp]
+            url = "https://llm.corp.internal"
+            ca_cert = "{}"
+            "#,
+            path.display()
+        ));
+        assert!(provider_clients(&cfg).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+//! Dual-send comparison. Requests matching a `[[compare]]` rule are also
+//! sent to a candidate provider. The client only ever gets the primary
+//! response; both outcomes are logged side by side for offline review and
+//! summarized in the TUI.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::CompareConfig;
+use crate::metrics::MetricsStore;
+use crate::metrics_log::RotatingFile;
+
+const LOG_MAX_SIZE: u64 = 50 * 1024 * 1024;
+const LOG_MAX_FILES: u32 = 5;
+
+/// One `[[compare]]` entry.
+pub struct Rule {
+    pattern: Regex,
+    pub provider: String,
+    pub model: Option<String>,
+    sample: f64,
+    seen: AtomicU64,
+}
+
+impl Rule {
+    /// Whether the next matching request is compared. Sampling is spread
+    /// evenly: with `sample = 0.25`, every fourth request is.
+    fn take(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        (n * self.sample).floor() != ((n + 1.0) * self.sample).floor()
+    }
+}
+
+pub struct Comparer {
+    rules: Vec<Rule>,
+    log: Option<Mutex<RotatingFile>>,
+}
+
+impl Comparer {
+    /// `None` when there are no rules. Comparisons are appended to
+    /// `log_path` as JSON lines.
+    pub fn from_config(
+        rules: &[CompareConfig],
+        log_path: Option<PathBuf>,
+    ) -> Result<Option<Self>, String> {
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    pattern: Regex::new(&rule.pattern)
+                        .map_err(|e| format!("invalid compare pattern '{}': {e}", rule.pattern))?,
+                    provider: rule.provider.clone(),
+                    model: rule.model.clone(),
+                    sample: rule.sample,
+                    seen: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let log = log_path
+            .map(|path| {
+                RotatingFile::open(path.clone(), LOG_MAX_SIZE, LOG_MAX_FILES, None)
+                    .map(Mutex::new)
+                    .map_err(|e| format!("failed to open {}: {e}", path.display()))
+            })
+            .transpose()?;
+        Ok(Some(Self { rules, log }))
+    }
+
+    /// The rule a request for `model` is compared under, if it is sampled.
+    pub fn select(&self, model: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(model))
+            .filter(|rule| rule.take())
+    }
+
+    /// Logs `comparison` in full and keeps its stats for the TUI.
+    pub fn record(&self, comparison: Comparison, metrics: &MetricsStore) {
+        if let Some(ref log) = self.log
+            && let Ok(line) = serde_json::to_string(&comparison)
+            && let Err(e) = log
+                .lock()
+                .expect("compare log lock poisoned")
+                .write_line(&line)
+        {
+            tracing::warn!("failed to write comparison log: {e}");
+        }
+        metrics.record_comparison(comparison.without_text());
+    }
+}
+
+/// How one provider answered a compared request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Side {
+    pub provider: String,
+    pub model: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub stop_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String,
+}
+
+impl Side {
+    pub fn ok(&self) -> bool {
+        self.status < 400
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    pub wallclock: DateTime<Utc>,
+    pub request_id: String,
+    /// The model the client asked for.
+    pub model: String,
+    pub primary: Side,
+    pub candidate: Side,
+    /// Word overlap of the two response texts, from 0 to 1.
+    pub similarity: f64,
+}
+
+impl Comparison {
+    pub fn new(request_id: &str, model: &str, primary: Side, candidate: Side) -> Self {
+        Self {
+            wallclock: Utc::now(),
+            request_id: request_id.to_string(),
+            model: model.to_string(),
+            similarity: similarity(&primary.text, &candidate.text),
+            primary,
+            candidate,
+        }
+    }
+
+    fn without_text(mut self) -> Self {
+        self.primary.text.clear();
+        self.candidate.text.clear();
+        self
+    }
+}
+
+/// The parts of a Messages response, streamed or not, that are compared.
+#[derive(Debug, Default, PartialEq)]
+pub struct ResponseSummary {
+    pub text: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub stop_reason: Option<String>,
+}
+
+impl ResponseSummary {
+    pub fn read(body: &[u8]) -> Self {
+        if let Ok(message) = serde_json::from_slice::<Value>(body) {
+            return Self::from_message(&message);
+        }
+        let mut summary = Self::default();
+        let text = String::from_utf8_lossy(body);
+        for data in text.lines().filter_map(|l| l.strip_prefix("data:")) {
+            let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+                continue;
+            };
+            match event["type"].as_str() {
+                Some("message_start") => {
+                    summary.input_tokens = event["message"]["usage"]["input_tokens"]
+                        .as_u64()
+                        .unwrap_or(0);
+                }
+                Some("content_block_delta") => {
+                    if let Some(delta) = event["delta"]["text"].as_str() {
+                        summary.text.push_str(delta);
+                    }
+                }
+                Some("message_delta") => {
+                    if let Some(output) = event["usage"]["output_tokens"].as_u64() {
+                        summary.output_tokens = output;
+                    }
+                    if let Some(reason) = event["delta"]["stop_reason"].as_str() {
+                        summary.stop_reason = Some(reason.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        summary
+    }
+
+    pub fn from_message(message: &Value) -> Self {
+        Self {
+            text: crate::translate::text_of(&message["content"]),
+            input_tokens: message["usage"]["input_tokens"].as_u64().unwrap_or(0),
+            output_tokens: message["usage"]["output_tokens"].as_u64().unwrap_or(0),
+            stop_reason: message["stop_reason"].as_str().map(String::from),
+        }
+    }
+}
+
+/// Jaccard similarity of the words in `a` and `b`.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let words = |text: &str| -> std::collections::HashSet<String> {
+        text.split_whitespace().map(str::to_lowercase).collect()
+    };
+    let (a, b) = (words(a), words(b));
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f64 / a.union(&b).count() as f64
+}
+
+/// Totals for one primary and candidate provider pair.
+#[derive(Debug, PartialEq)]
+pub struct PairSummary {
+    pub primary: String,
+    pub candidate: String,
+    pub count: usize,
+    pub candidate_errors: usize,
+    pub primary_p50: Duration,
+    pub candidate_p50: Duration,
+    pub primary_output_tokens: u64,
+    pub candidate_output_tokens: u64,
+    /// Mean similarity of the comparisons where both sides succeeded.
+    pub similarity: Option<f64>,
+}
+
+pub fn summarize(comparisons: &[Comparison]) -> Vec<PairSummary> {
+    let mut pairs: HashMap<(&str, &str), Vec<&Comparison>> = HashMap::new();
+    for c in comparisons {
+        pairs
+            .entry((&c.primary.provider, &c.candidate.provider))
+            .or_default()
+            .push(c);
+    }
+    let mut summaries: Vec<PairSummary> = pairs
+        .into_iter()
+        .map(|((primary, candidate), group)| {
+            let durations = |side: fn(&Comparison) -> &Side| -> Vec<Duration> {
+                group
+                    .iter()
+                    .map(|c| Duration::from_millis(side(c).duration_ms))
+                    .collect()
+            };
+            let both_ok: Vec<f64> = group
+                .iter()
+                .filter(|c| c.primary.ok() && c.candidate.ok())
+                .map(|c| c.similarity)
+                .collect();
+            PairSummary {
+                primary: primary.to_string(),
+                candidate: candidate.to_string(),
+                count: group.len(),
+                candidate_errors: group.iter().filter(|c| !c.candidate.ok()).count(),
+                primary_p50: MetricsStore::duration_percentile(&durations(|c| &c.primary), 50),
+                candidate_p50: MetricsStore::duration_percentile(&durations(|c| &c.candidate), 50),
+                primary_output_tokens: group.iter().map(|c| c.primary.output_tokens).sum(),
+                candidate_output_tokens: group.iter().map(|c| c.candidate.output_tokens).sum(),
+                similarity: (!both_ok.is_empty())
+                    .then(|| both_ok.iter().sum::<f64>() / both_ok.len() as f64),
+            }
+        })
+        .collect();
+    summaries.sort_by(|a, b| b.count.cmp(&a.count).then(a.candidate.cmp(&b.candidate)));
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn side(provider: &str, status: u16, duration_ms: u64, text: &str) -> Side {
+        Side {
+            provider: provider.to_string(),
+            status,
+            duration_ms,
+            output_tokens: 10,
+            text: text.to_string(),
+            ..Side::default()
+        }
+    }
+
+    #[test]
+    fn sampling_spreads_evenly() {
+        let comparer = Comparer::from_config(
+            &[CompareConfig {
+                pattern: "sonnet".to_string(),
+                provider: "local".to_string(),
+                model: None,
+                sample: 0.25,
+            }],
+            None,
+        )
+        .unwrap()
+        .unwrap();
+        let taken = (0..8)
+            .filter(|_| comparer.select("claude-sonnet-4-5").is_some())
+            .count();
+        assert_eq!(taken, 2);
+        assert!(comparer.select("claude-opus-4-1").is_none());
+    }
+
+    #[test]
+    fn summary_reads_json_and_sse_responses() {
+        let message = json!({
+            "content": [{"type": "text", "text": "hello there"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 5, "output_tokens": 2}
+        });
+        let summary = ResponseSummary::read(message.to_string().as_bytes());
+        assert_eq!(summary.text, "hello there");
+        assert_eq!(summary.stop_reason.as_deref(), Some("end_turn"));
+
+        let sse = "event: message_start\n\
+            data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":7}}}\n\n\
+            data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"hel\"}}\n\n\
+            data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"lo\"}}\n\n\
+            data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"max_tokens\"},\"usage\":{\"output_tokens\":9}}\n\n";
+        assert_eq!(
+            ResponseSummary::read(sse.as_bytes()),
+            ResponseSummary {
+                text: "hello".to_string(),
+                input_tokens: 7,
+                output_tokens: 9,
+                stop_reason: Some("max_tokens".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn similarity_is_word_overlap() {
+        assert_eq!(similarity("", ""), 1.0);
+        assert_eq!(similarity("a b", "A B"), 1.0);
+        assert_eq!(similarity("a b c d", "a b"), 0.5);
+        assert_eq!(similarity("a", ""), 0.0);
+    }
+
+    #[test]
+    fn summaries_group_by_provider_pair() {
+        let comparisons = vec![
+            Comparison::new(
+                "r1",
+                "sonnet",
+                side("anthropic", 200, 1000, "a b"),
+                side("local", 200, 3000, "a b"),
+            ),
+            Comparison::new(
+                "r2",
+                "sonnet",
+                side("anthropic", 200, 2000, "a b"),
+                side("local", 500, 100, ""),
+            ),
+        ];
+        let summaries = summarize(&comparisons);
+        assert_eq!(summaries.len(), 1);
+        let pair = &summaries[0];
+        assert_eq!((pair.count, pair.candidate_errors), (2, 1));
+        assert_eq!(pair.similarity, Some(1.0));
+        assert_eq!(pair.candidate_output_tokens, 20);
+    }
+}
@@ -1,16 +1,33 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use figment::Figment;
+use figment::providers::{Format, Serialized, Toml};
+use figment::value::Dict;
 use serde::Deserialize;
 
-#[derive(Debug, Default, Deserialize)]
+use crate::error::CroxyError;
+
+#[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub server: ServerConfig,
     #[serde(default, rename = "provider")]
     pub providers: HashMap<String, ProviderConfig>,
+    /// `[group.NAME]` sets of providers a route can name in place of one.
+    #[serde(default, rename = "group")]
+    pub groups: HashMap<String, GroupConfig>,
     #[serde(default)]
     pub routes: Vec<RouteConfig>,
+    /// Keys every route takes unless it sets its own; see
+    /// [`apply_route_defaults`].
+    #[serde(default)]
+    pub route_defaults: RouteDefaults,
+    /// `[[pins]]` saved by `croxy pin --save`, checked before the routes.
+    #[serde(default)]
+    pub pins: Vec<PinConfig>,
     #[serde(default)]
     pub default: DefaultRoute,
     #[serde(default)]
@@ -19,6 +36,825 @@ pub struct Config {
     pub logging: LoggingConfig,
     #[serde(default)]
     pub retention: RetentionConfig,
+    #[serde(default)]
+    pub instance: InstanceConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub tool_results: ToolResultsConfig,
+    #[serde(default)]
+    pub response_headers: ResponseHeadersConfig,
+    #[serde(default)]
+    pub keys: KeysConfig,
+    /// `[clients.NAME]` limits and pins for the virtual key named NAME.
+    #[serde(default)]
+    pub clients: HashMap<String, ClientConfig>,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    /// `[[compare]]` rules for sending requests to a second provider.
+    #[serde(default)]
+    pub compare: Vec<CompareConfig>,
+    /// `[[middleware]]` stages requests pass through before forwarding.
+    #[serde(default)]
+    pub middleware: Vec<MiddlewareConfig>,
+    #[serde(default)]
+    pub script: ScriptConfig,
+    #[serde(default)]
+    pub chaos: ChaosConfig,
+    #[serde(default)]
+    pub context_guard: ContextGuardConfig,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    #[serde(default)]
+    pub tui: TuiConfig,
+    /// `[models.NAME]` limits for models whose names start with NAME, over
+    /// the built-in ones.
+    #[serde(default)]
+    pub models: HashMap<String, ModelLimits>,
+    /// Extra config files merged underneath this one; see [`config_figment`].
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Reject unknown keys instead of warning about them.
+    #[serde(default = "default_strict")]
+    pub strict: bool,
+    /// Named overlays (`[profiles.NAME]`) merged over the rest of the config
+    /// when selected; see [`apply_profile`].
+    #[serde(default)]
+    pub profiles: HashMap<String, Config>,
+    /// Profile applied when none is given on the command line.
+    pub profile: Option<String>,
+}
+
+fn default_strict() -> bool {
+    true
+}
+
+impl Config {
+    /// Whether the default or any route sends requests to `provider`,
+    /// directly or through a group.
+    pub fn routes_to(&self, provider: &str) -> bool {
+        self.default.provider == provider
+            || self
+                .default
+                .endpoints()
+                .any(|(_, d)| d.provider == provider)
+            || self.routes.iter().any(|route| {
+                route.provider == provider
+                    || self
+                        .groups
+                        .get(&route.provider)
+                        .is_some_and(|group| group.members.iter().any(|m| m == provider))
+            })
+    }
+}
+
+#[derive(Deserialize)]
+struct Includes {
+    #[serde(default)]
+    include: Vec<String>,
+}
+
+/// Builds the figment for a config file and the files it includes.
+///
+/// Includes are resolved relative to the including file and may use `*` and
+/// `?` in the file name (matches are taken in lexical order). A file takes
+/// precedence over everything it includes, and later includes take
+/// precedence over earlier ones. Tables are merged key by key; arrays such as
+/// `routes` are concatenated with the higher-precedence file's entries first,
+/// so its routes are tried before included ones. A missing top-level file is
+/// treated as empty.
+pub fn config_figment(path: &Path) -> Result<Figment, CroxyError> {
+    if !path.exists() {
+        return Ok(Figment::from(Toml::file(path)));
+    }
+    let mut files = Vec::new();
+    collect_config_files(path, &mut Vec::new(), &mut files).map_err(CroxyError::Config)?;
+    Ok(files
+        .iter()
+        .rev()
+        .fold(Figment::new(), |fig, file| fig.adjoin(Toml::file(file))))
+}
+
+/// Picks the profile to apply: the command line wins over `CROXY_PROFILE`,
+/// which wins over a `profile` key in the config file.
+pub fn select_profile(cli: Option<&str>, env: Option<&str>, file: &Figment) -> Option<String> {
+    cli.or(env)
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .or_else(|| file.extract_inner::<String>("profile").ok())
+}
+
+/// Merges `[profiles.NAME]` over the rest of `file`. Tables such as
+/// `provider` are merged key by key, while arrays such as `routes` are
+/// replaced by the profile's.
+pub fn apply_profile(file: Figment, profile: Option<&str>) -> Result<Figment, CroxyError> {
+    let Some(name) = profile else {
+        return Ok(file);
+    };
+    let profiles: HashMap<String, figment::value::Value> =
+        file.extract_inner("profiles").unwrap_or_default();
+    if !profiles.contains_key(name) {
+        let mut available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        available.sort();
+        return Err(CroxyError::Config(if available.is_empty() {
+            format!("profile '{name}' not found: config has no [profiles]")
+        } else {
+            format!(
+                "profile '{name}' not found (available: {})",
+                available.join(", ")
+            )
+        }));
+    }
+    let overlay = file.focus(&format!("profiles.{name}"));
+    Ok(file.merge(overlay))
+}
+
+/// Route keys [`apply_route_defaults`] doesn't copy, which only make
+/// sense for one route.
+const OWN_ROUTE_KEYS: &[&str] = &["name", "description", "pattern", "user_id"];
+
+/// Fills in the keys each `[[routes]]` entry leaves out from
+/// `[route_defaults]`, so routes sharing a provider and limits only spell
+/// out what differs. Apply after [`apply_profile`], so a profile's routes
+/// inherit too.
+pub fn apply_route_defaults(file: Figment) -> Figment {
+    let defaults: Dict = file.extract_inner("route_defaults").unwrap_or_default();
+    if defaults.is_empty() {
+        return file;
+    }
+    // Malformed routes are left for extraction to report
+    let Ok(mut routes) = file.extract_inner::<Vec<Dict>>("routes") else {
+        return file;
+    };
+    for route in &mut routes {
+        for (key, value) in &defaults {
+            if !OWN_ROUTE_KEYS.contains(&key.as_str()) {
+                route.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    file.merge(Serialized::default("routes", routes))
+}
+
+/// Like [`config_figment`] for unsaved content, resolving includes against
+/// `base_dir`.
+pub fn config_figment_from_str(content: &str, base_dir: &Path) -> Result<Figment, CroxyError> {
+    included_files(content, base_dir)
+        .map(|files| {
+            files
+                .iter()
+                .rev()
+                .fold(Figment::from(Toml::string(content)), |fig, file| {
+                    fig.adjoin(Toml::file(file))
+                })
+        })
+        .map_err(CroxyError::Config)
+}
+
+fn included_files(content: &str, base_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for include in parse_includes(content, "config")? {
+        for file in expand_include(base_dir, &include)? {
+            collect_config_files(&file, &mut Vec::new(), &mut files)?;
+        }
+    }
+    Ok(files)
+}
+
+fn parse_includes(content: &str, origin: &str) -> Result<Vec<String>, String> {
+    Figment::from(Toml::string(content))
+        .extract::<Includes>()
+        .map(|i| i.include)
+        .map_err(|e| format!("invalid include in {origin}: {e}"))
+}
+
+/// Appends `path`'s includes (depth first) and then `path` itself to `out`,
+/// lowest precedence first.
+fn collect_config_files(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    out: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    if stack.contains(&canonical) {
+        return Err(format!("include cycle at {}", path.display()));
+    }
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+
+    stack.push(canonical);
+    for include in parse_includes(&content, &path.display().to_string())? {
+        for file in expand_include(base_dir, &include)? {
+            collect_config_files(&file, stack, out)?;
+        }
+    }
+    stack.pop();
+
+    out.push(path.to_path_buf());
+    Ok(())
+}
+
+/// Resolves one `include` entry. Literal paths must exist; patterns may match
+/// nothing.
+fn expand_include(base_dir: &Path, include: &str) -> Result<Vec<PathBuf>, String> {
+    let path = crate::secrets::expand_home(include);
+    let path = if path.is_absolute() {
+        path
+    } else {
+        base_dir.join(path)
+    };
+
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !file_name.contains(['*', '?']) {
+        if !path.is_file() {
+            return Err(format!("included file not found: {}", path.display()));
+        }
+        return Ok(vec![path]);
+    }
+
+    let pattern = file_name
+        .chars()
+        .map(|c| match c {
+            '*' => "[^/]*".to_string(),
+            '?' => "[^/]".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect::<String>();
+    let re = regex::Regex::new(&format!("^{pattern}$"))
+        .map_err(|e| format!("invalid include pattern '{include}': {e}"))?;
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(Vec::new());
+    };
+    let mut matches: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .filter(|p| {
+            p.file_name()
+                .is_some_and(|n| re.is_match(&n.to_string_lossy()))
+        })
+        .collect();
+    matches.sort();
+    Ok(matches)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminConfig {
+    /// Serve runtime control endpoints under `/_croxy/`.
+    #[serde(default = "default_admin_enabled")]
+    pub enabled: bool,
+    /// Bearer token required by admin endpoints.
+    pub token: Option<String>,
+    /// File holding the token, in place of `token`.
+    pub token_file: Option<String>,
+    /// `service/account` of a keychain entry holding the token.
+    pub token_keychain: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_admin_enabled(),
+            token: None,
+            token_file: None,
+            token_keychain: None,
+        }
+    }
+}
+
+fn default_admin_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TelemetryConfig {
+    #[serde(default)]
+    pub prometheus: PrometheusConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PrometheusConfig {
+    /// Serve request counts and latencies at `/_croxy/metrics`.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Upper bounds of the latency histogram's buckets, in milliseconds.
+    #[serde(default = "default_latency_buckets_ms")]
+    pub latency_buckets_ms: Vec<u64>,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            latency_buckets_ms: default_latency_buckets_ms(),
+        }
+    }
+}
+
+fn default_latency_buckets_ms() -> Vec<u64> {
+    crate::prometheus::DEFAULT_LATENCY_BUCKETS_MS.to_vec()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InstanceConfig {
+    /// Namespaces the pid file, logs, and default port so several croxy
+    /// instances can run side by side.
+    pub name: Option<String>,
+}
+
+/// Checks that an instance name is safe to use as a directory name.
+pub fn validate_instance_name(name: &str) -> Result<(), String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid instance name '{name}': use letters, digits, '-' or '_'"
+        ))
+    }
+}
+
+/// Default port for a named instance, derived from the name so it is stable
+/// across runs and distinct from the unnamed instance's port.
+pub fn instance_port(name: &str) -> u16 {
+    // FNV-1a
+    let mut hash: u32 = 0x811c_9dc5;
+    for b in name.bytes() {
+        hash ^= u32::from(b);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    default_port() + 1 + (hash % 899) as u16
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ToolResultsConfig {
+    /// Tool result text longer than this many bytes is cut down to it.
+    /// Unset leaves tool results alone.
+    pub max_size: Option<usize>,
+    /// With `max_size` set, request bodies up to this size are read so
+    /// their tool results can be cut below `server.max_body_size`.
+    #[serde(default = "default_tool_results_max_request_size")]
+    pub max_request_size: usize,
+}
+
+impl Default for ToolResultsConfig {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            max_request_size: default_tool_results_max_request_size(),
+        }
+    }
+}
+
+fn default_tool_results_max_request_size() -> usize {
+    100 * 1024 * 1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompareConfig {
+    /// Regex matched against the requested model.
+    pub pattern: String,
+    /// Candidate provider that also receives matching requests.
+    pub provider: String,
+    /// Model to request from the candidate.
+    pub model: Option<String>,
+    /// Fraction of matching requests to compare.
+    #[serde(default = "default_compare_sample")]
+    pub sample: f64,
+}
+
+fn default_compare_sample() -> f64 {
+    1.0
+}
+
+/// One `[[middleware]]` stage. Which of the other fields apply depends on
+/// `type`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MiddlewareConfig {
+    #[serde(rename = "type")]
+    pub kind: MiddlewareKind,
+    /// Providers whose requests pass through this stage. Empty means all.
+    #[serde(default)]
+    pub providers: Vec<String>,
+    /// `redact`: regex for text to replace.
+    pub pattern: Option<String>,
+    /// `redact`: literal strings to replace, ignoring case.
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// `redact`: what matches are replaced with.
+    #[serde(default = "default_redact_replacement")]
+    pub replacement: String,
+    /// `set_header`, `remove_header`: the header.
+    pub name: Option<String>,
+    /// `set_header`: its value.
+    pub value: Option<String>,
+    /// `max_tokens`: the most output tokens a request may ask for.
+    pub max: Option<u64>,
+    /// `system_prompt`: text added to the request's system prompt.
+    pub text: Option<String>,
+    /// `system_prompt`: whether `text` goes before or after the client's.
+    #[serde(default)]
+    pub position: PromptPosition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MiddlewareKind {
+    Redact,
+    SetHeader,
+    RemoveHeader,
+    MaxTokens,
+    SystemPrompt,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptPosition {
+    Prepend,
+    #[default]
+    Append,
+}
+
+fn default_redact_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+/// The Lua routing hook; needs croxy built with the `scripting` feature.
+#[derive(Debug, Deserialize)]
+pub struct ScriptConfig {
+    /// Lua file defining `on_request`. Unset disables the hook.
+    pub path: Option<String>,
+    /// How long one call may run before it is stopped.
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            timeout_ms: default_script_timeout_ms(),
+        }
+    }
+}
+
+fn default_script_timeout_ms() -> u64 {
+    50
+}
+
+/// Providers serving the same models, which a route can send requests to
+/// as one. Each request goes to whichever of two members picked at random
+/// has been faster and more reliable lately.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GroupConfig {
+    pub members: Vec<String>,
+}
+
+/// The model that summarizes messages for routes with
+/// `context_guard = "summarize"`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ContextGuardConfig {
+    pub summary_provider: Option<String>,
+    pub summary_model: Option<String>,
+}
+
+/// `[notifications]`: a command croxy runs when something happens that
+/// is worth interrupting someone for.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationsConfig {
+    /// Program and arguments to run; the message is passed as one more
+    /// argument. Empty turns notifications off.
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default = "default_notify_on")]
+    pub on: Vec<NotifyOn>,
+    /// Share of a virtual key's budget spent before it notifies.
+    #[serde(default = "default_budget_threshold")]
+    pub budget_threshold: f64,
+    /// Requests running at least this long notify when they finish.
+    #[serde(default = "default_long_request_secs")]
+    pub long_request_secs: u64,
+    /// Recent error rate at which a provider counts as down.
+    #[serde(default = "default_down_error_rate")]
+    pub down_error_rate: f64,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            command: Vec::new(),
+            on: default_notify_on(),
+            budget_threshold: default_budget_threshold(),
+            long_request_secs: default_long_request_secs(),
+            down_error_rate: default_down_error_rate(),
+        }
+    }
+}
+
+/// `[tui]`: the dashboard.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TuiConfig {
+    #[serde(default)]
+    pub keys: TuiKeys,
+    #[serde(default)]
+    pub live_log: LiveLogConfig,
+}
+
+/// `[tui.live_log]`: what the Overview tab's Live Log shows of each
+/// request.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LiveLogConfig {
+    /// Columns, in order.
+    #[serde(default = "default_live_log_columns")]
+    pub columns: Vec<LiveLogColumn>,
+    /// Prefixes cut from model names, such as `claude-`.
+    #[serde(default)]
+    pub strip_prefixes: Vec<String>,
+    /// Cut a trailing date, such as `-20250514`, from model names.
+    #[serde(default)]
+    pub strip_dates: bool,
+}
+
+impl Default for LiveLogConfig {
+    fn default() -> Self {
+        Self {
+            columns: default_live_log_columns(),
+            strip_prefixes: Vec::new(),
+            strip_dates: false,
+        }
+    }
+}
+
+/// A column of the Live Log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiveLogColumn {
+    Age,
+    Model,
+    Provider,
+    Route,
+    Status,
+    Duration,
+    Tokens,
+    Client,
+    Id,
+}
+
+fn default_live_log_columns() -> Vec<LiveLogColumn> {
+    vec![
+        LiveLogColumn::Age,
+        LiveLogColumn::Model,
+        LiveLogColumn::Provider,
+        LiveLogColumn::Route,
+        LiveLogColumn::Status,
+        LiveLogColumn::Duration,
+        LiveLogColumn::Tokens,
+    ]
+}
+
+/// `[tui.keys]`: keys for each action, replacing its defaults. Actions
+/// left out keep theirs.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TuiKeys {
+    pub quit: Option<KeyBinding>,
+    pub detach: Option<KeyBinding>,
+    pub overview: Option<KeyBinding>,
+    pub models: Option<KeyBinding>,
+    pub providers: Option<KeyBinding>,
+    pub errors: Option<KeyBinding>,
+    pub compare: Option<KeyBinding>,
+    pub clients: Option<KeyBinding>,
+    pub next_tab: Option<KeyBinding>,
+    pub prev_tab: Option<KeyBinding>,
+    pub scroll_down: Option<KeyBinding>,
+    pub scroll_up: Option<KeyBinding>,
+    pub scroll_top: Option<KeyBinding>,
+    pub scroll_bottom: Option<KeyBinding>,
+    pub routing: Option<KeyBinding>,
+    pub force_provider: Option<KeyBinding>,
+    pub reload: Option<KeyBinding>,
+    pub drain: Option<KeyBinding>,
+    pub alerts: Option<KeyBinding>,
+    pub toggle_route: Option<KeyBinding>,
+    pub pin: Option<KeyBinding>,
+}
+
+/// One key, such as `"q"`, `"down"`, or `"ctrl-d"`, or several.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum KeyBinding {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeyBinding {
+    pub fn keys(&self) -> &[String] {
+        match self {
+            KeyBinding::One(key) => std::slice::from_ref(key),
+            KeyBinding::Many(keys) => keys,
+        }
+    }
+}
+
+/// A model's context window and output limit, in tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct ModelLimits {
+    pub context_window: Option<u64>,
+    pub max_output_tokens: Option<u64>,
+}
+
+/// What `[notifications]` notifies about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    /// A provider started failing most requests, or recovered.
+    ProviderDown,
+    /// A virtual key crossed `budget_threshold` of its budget.
+    Budget,
+    /// A request ran past `long_request_secs`.
+    LongRequest,
+}
+
+impl NotifyOn {
+    pub fn name(self) -> &'static str {
+        match self {
+            NotifyOn::ProviderDown => "provider_down",
+            NotifyOn::Budget => "budget",
+            NotifyOn::LongRequest => "long_request",
+        }
+    }
+}
+
+fn default_notify_on() -> Vec<NotifyOn> {
+    vec![
+        NotifyOn::ProviderDown,
+        NotifyOn::Budget,
+        NotifyOn::LongRequest,
+    ]
+}
+
+fn default_budget_threshold() -> f64 {
+    0.8
+}
+
+fn default_long_request_secs() -> u64 {
+    120
+}
+
+fn default_down_error_rate() -> f64 {
+    0.5
+}
+
+/// Fault injection, for testing clients against a degraded provider.
+#[derive(Debug, Default, Deserialize)]
+pub struct ChaosConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// `[[chaos.rules]]`; the first rule matching a request's route applies.
+    #[serde(default)]
+    pub rules: Vec<ChaosRuleConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChaosRuleConfig {
+    /// Route names or provider names the rule applies to; empty matches all.
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Delay added before the request is forwarded.
+    pub latency_ms: Option<u64>,
+    #[serde(default = "default_latency_probability")]
+    pub latency_probability: f64,
+    /// Chance of answering with one of `error_statuses` instead of
+    /// forwarding.
+    #[serde(default)]
+    pub error_probability: f64,
+    #[serde(default = "default_error_statuses")]
+    pub error_statuses: Vec<u16>,
+    /// Chance of cutting a streamed response off partway.
+    #[serde(default)]
+    pub truncate_probability: f64,
+}
+
+fn default_latency_probability() -> f64 {
+    1.0
+}
+
+fn default_error_statuses() -> Vec<u16> {
+    vec![429, 500]
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaptureConfig {
+    /// Save each request and its outcome for `croxy replay`.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Defaults to `captures/` in the instance's state directory.
+    pub dir: Option<String>,
+    #[serde(default = "default_capture_max_requests")]
+    pub max_requests: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            max_requests: default_capture_max_requests(),
+        }
+    }
+}
+
+fn default_capture_max_requests() -> usize {
+    1000
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveConfig {
+    /// Save the responses of routes with `archive = true`.
+    #[serde(default)]
+    pub enabled: bool,
+    /// A JSONL file, or with `format = "files"` a directory.
+    pub path: Option<String>,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Requests and response text longer than this are left out or cut.
+    #[serde(default = "default_archive_max_bytes")]
+    pub max_bytes: usize,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            format: ArchiveFormat::default(),
+            max_bytes: default_archive_max_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// One line per response, appended to `path`.
+    #[default]
+    Jsonl,
+    /// One `<request id>.json` file per response, in the directory `path`.
+    Files,
+}
+
+fn default_archive_max_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Which provider response headers are forwarded to clients, and headers
+/// added to every response.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResponseHeadersConfig {
+    /// Header names to forward, a trailing `*` matching a prefix. Empty
+    /// forwards all.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Header names never forwarded, even when allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Headers set on every response croxy sends.
+    #[serde(default)]
+    pub add: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KeysConfig {
+    /// Reject requests that don't present a virtual key from `croxy key
+    /// create`. Otherwise they pass through with their own credentials.
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Token quotas for one client, counted in UTC days and months. Input and
+/// output tokens both count; requests are refused once a quota is used up.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClientConfig {
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+    /// Pins that apply only to this client's requests, ahead of `[[pins]]`.
+    #[serde(default)]
+    pub pins: Vec<PinConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,6 +863,8 @@ pub struct RetentionConfig {
     pub enabled: bool,
     #[serde(default = "default_retention_minutes")]
     pub minutes: u64,
+    /// How much history the dashboard shows; defaults to `minutes`.
+    pub display_minutes: Option<u64>,
 }
 
 impl Default for RetentionConfig {
@@ -34,6 +872,18 @@ impl Default for RetentionConfig {
         Self {
             enabled: default_retention_enabled(),
             minutes: default_retention_minutes(),
+            display_minutes: None,
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// How long requests are kept; a year when retention is disabled.
+    pub fn duration(&self) -> Duration {
+        if self.enabled {
+            Duration::from_secs(self.minutes.saturating_mul(60))
+        } else {
+            Duration::from_secs(365 * 24 * 60 * 60)
         }
     }
 }
@@ -50,6 +900,59 @@ fn default_retention_minutes() -> u64 {
 pub struct LoggingConfig {
     #[serde(default)]
     pub metrics: MetricsLogConfig,
+    /// Format of the application (tracing) log.
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub app: AppLogConfig,
+    #[serde(default)]
+    pub audit: AuditLogConfig,
+    #[serde(default)]
+    pub access: AccessLogConfig,
+    #[serde(default)]
+    pub crash: CrashConfig,
+    /// Regexes whose matches are scrubbed from logs, request records, and
+    /// captures, on top of credential headers and provider keys.
+    #[serde(default)]
+    pub redact_patterns: Vec<String>,
+}
+
+/// Rotation limits for the application log (`croxy.log`).
+#[derive(Debug, Deserialize)]
+pub struct AppLogConfig {
+    #[serde(default = "default_app_log_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_app_log_max_files")]
+    pub max_files: u32,
+    pub max_age_hours: Option<u64>,
+}
+
+impl Default for AppLogConfig {
+    fn default() -> Self {
+        Self {
+            max_size_mb: default_app_log_max_size_mb(),
+            max_files: default_app_log_max_files(),
+            max_age_hours: None,
+        }
+    }
+}
+
+fn default_app_log_max_size_mb() -> u64 {
+    10
+}
+
+fn default_app_log_max_files() -> u32 {
+    3
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with span fields such as `request_id`
+    Json,
 }
 
 #[derive(Debug, Deserialize)]
@@ -64,17 +967,133 @@ pub struct MetricsLogConfig {
     pub max_files: u32,
 }
 
-impl Default for MetricsLogConfig {
+impl Default for MetricsLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_metrics_log_path(),
+            max_size_mb: default_max_size_mb(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+/// The audit log of administrative and security-relevant events.
+#[derive(Debug, Deserialize)]
+pub struct AuditLogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_audit_log_path")]
+    pub path: String,
+    #[serde(default = "default_app_log_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_audit_log_path(),
+            max_size_mb: default_app_log_max_size_mb(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+fn default_audit_log_path() -> String {
+    dirs::home_dir()
+        .map(|h| h.join(".config/croxy/logs/audit.jsonl"))
+        .unwrap_or_else(|| PathBuf::from("/tmp/croxy/logs/audit.jsonl"))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// The access log: one line per request, for tools that read web server
+/// logs.
+#[derive(Debug, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_access_log_path")]
+    pub path: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_access_log_path(),
+            format: AccessLogFormat::default(),
+            max_size_mb: default_max_size_mb(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache's combined log format, with croxy's fields appended
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// Bundles written when croxy panics.
+#[derive(Debug, Deserialize)]
+pub struct CrashConfig {
+    #[serde(default = "default_crash_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_crash_path")]
+    pub path: String,
+    /// Lines of the application log kept for a bundle.
+    #[serde(default = "default_crash_lines")]
+    pub lines: usize,
+}
+
+impl Default for CrashConfig {
     fn default() -> Self {
         Self {
-            enabled: false,
-            path: default_metrics_log_path(),
-            max_size_mb: default_max_size_mb(),
-            max_files: default_max_files(),
+            enabled: default_crash_enabled(),
+            path: default_crash_path(),
+            lines: default_crash_lines(),
         }
     }
 }
 
+fn default_crash_enabled() -> bool {
+    true
+}
+
+fn default_crash_path() -> String {
+    dirs::home_dir()
+        .map(|h| h.join(".config/croxy/crash"))
+        .unwrap_or_else(|| PathBuf::from("/tmp/croxy/crash"))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn default_crash_lines() -> usize {
+    100
+}
+
+fn default_access_log_path() -> String {
+    dirs::home_dir()
+        .map(|h| h.join(".config/croxy/logs/access.log"))
+        .unwrap_or_else(|| PathBuf::from("/tmp/croxy/logs/access.log"))
+        .to_string_lossy()
+        .to_string()
+}
+
 fn default_metrics_log_path() -> String {
     dirs::home_dir()
         .map(|h| h.join(".config/croxy/logs/metrics.jsonl"))
@@ -99,6 +1118,65 @@ pub struct ServerConfig {
     pub port: u16,
     #[serde(default = "default_max_body_size")]
     pub max_body_size: usize,
+    /// Percentage of requests over 80% of `max_body_size` that is worth a
+    /// warning, and a larger limit in `croxy status`.
+    #[serde(default = "default_body_size_warn_percent")]
+    pub body_size_warn_percent: f64,
+    /// Unix domain socket path to listen on, in addition to TCP.
+    pub socket: Option<String>,
+    /// Set to false to serve only on `socket`.
+    #[serde(default = "default_tcp")]
+    pub tcp: bool,
+    /// How long to wait for in-flight requests on shutdown or takeover.
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// How long a TCP connection sits idle before keep-alive probes check
+    /// its client is still there; 0 turns them off.
+    #[serde(default = "default_keepalive_secs")]
+    pub keepalive_secs: u64,
+    /// Bytes of a streamed response read ahead of a slow client before
+    /// reading from the provider pauses.
+    #[serde(default = "default_stream_buffer_size")]
+    pub stream_buffer_size: usize,
+    /// Send an SSE comment on event streams that have been idle this long,
+    /// so clients with read timeouts don't give up on a slow provider.
+    pub sse_heartbeat_secs: Option<u64>,
+    /// Longest a request may take end to end, from reading its body to the
+    /// last byte of a streamed response.
+    pub request_timeout_ms: Option<u64>,
+    /// More places to serve on, besides `host`:`port` and `socket`.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Networks TCP clients may connect from, as CIDRs or addresses.
+    /// Loopback is always allowed; empty allows everyone.
+    #[serde(default)]
+    pub allow_cidrs: Vec<String>,
+    /// Header naming the client, for requests without a virtual key, as
+    /// the TUI's Clients tab groups them. Otherwise they're grouped by the
+    /// address they came from.
+    pub client_header: Option<String>,
+    /// Answer Messages requests with malformed bodies with a 400 naming
+    /// the field at fault, instead of forwarding them.
+    #[serde(default)]
+    pub validate_requests: bool,
+}
+
+/// An extra `[[server.listeners]]` entry: a TCP address or a unix socket.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListenerConfig {
+    /// `host:port` to listen on.
+    pub address: Option<String>,
+    /// Unix domain socket path to listen on.
+    pub socket: Option<String>,
+    /// Recorded with each request that arrives here.
+    pub tag: Option<String>,
+    /// PEM certificate chain and PKCS#8 key, to serve HTTPS on `address`.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Reject requests here that don't present a virtual key, even when
+    /// `keys.required` is off.
+    #[serde(default)]
+    pub require_key: bool,
 }
 
 impl Default for ServerConfig {
@@ -107,8 +1185,70 @@ impl Default for ServerConfig {
             host: default_host(),
             port: default_port(),
             max_body_size: default_max_body_size(),
+            body_size_warn_percent: default_body_size_warn_percent(),
+            socket: None,
+            tcp: default_tcp(),
+            drain_timeout_secs: default_drain_timeout_secs(),
+            keepalive_secs: default_keepalive_secs(),
+            stream_buffer_size: default_stream_buffer_size(),
+            sse_heartbeat_secs: None,
+            request_timeout_ms: None,
+            listeners: Vec::new(),
+            allow_cidrs: Vec::new(),
+            client_header: None,
+            validate_requests: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Address clients should connect to, mapping wildcard binds to loopback.
+    pub fn client_addr(&self) -> String {
+        let host = match self.host.as_str() {
+            "0.0.0.0" => "127.0.0.1",
+            "::" => "::1",
+            other => other,
+        };
+        format!("{host}:{}", self.port)
+    }
+
+    /// Base URL clients should use: plain HTTP over TCP when enabled,
+    /// otherwise the `http+unix://` form with a percent-encoded socket path.
+    pub fn base_url(&self) -> String {
+        match (&self.socket, self.tcp) {
+            (Some(socket), false) => format!("http+unix://{}", percent_encode(socket)),
+            _ => format!("http://{}", self.client_addr()),
+        }
+    }
+
+    /// The idle time before TCP keep-alive probes, when they're on.
+    pub fn keepalive(&self) -> Option<Duration> {
+        (self.keepalive_secs > 0).then(|| Duration::from_secs(self.keepalive_secs))
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
         }
     }
+    out
+}
+
+fn default_tcp() -> bool {
+    true
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_keepalive_secs() -> u64 {
+    60
 }
 
 fn default_host() -> String {
@@ -123,14 +1263,275 @@ fn default_max_body_size() -> usize {
     10 * 1024 * 1024
 }
 
-#[derive(Debug, Deserialize)]
+fn default_body_size_warn_percent() -> f64 {
+    5.0
+}
+
+fn default_stream_buffer_size() -> usize {
+    256 * 1024
+}
+
+/// Wire format a provider speaks. Requests arrive in Anthropic Messages
+/// format and are translated for providers using anything else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiFormat {
+    #[default]
+    Anthropic,
+    /// Ollama's native `/api/chat`.
+    Ollama,
+    /// Google's Generative Language API, keyed by `api_key`.
+    Gemini,
+    /// Claude on Google Vertex AI, authenticated with Google credentials.
+    Vertex,
+    /// Azure OpenAI chat completions, keyed by `api_key`.
+    Azure,
+}
+
+impl std::fmt::Display for ApiFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiFormat::Anthropic => write!(f, "anthropic"),
+            ApiFormat::Ollama => write!(f, "ollama"),
+            ApiFormat::Gemini => write!(f, "gemini"),
+            ApiFormat::Vertex => write!(f, "vertex"),
+            ApiFormat::Azure => write!(f, "azure"),
+        }
+    }
+}
+
+/// What to do when a route rewrites to a model its Ollama server hasn't
+/// pulled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingModels {
+    /// Log a warning.
+    Warn,
+    /// Ask the server to pull it.
+    Pull,
+    /// Switch the route off until the model is there, so its requests go
+    /// to the next matching route or the default.
+    Fallback,
+}
+
+/// The Anthropic API features a provider offers, all of them unless
+/// turned off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Capabilities {
+    #[serde(default = "default_supported")]
+    pub count_tokens: bool,
+    #[serde(default = "default_supported")]
+    pub batches: bool,
+    #[serde(default = "default_supported")]
+    pub files: bool,
+    #[serde(default = "default_supported")]
+    pub streaming: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            count_tokens: true,
+            batches: true,
+            files: true,
+            streaming: true,
+        }
+    }
+}
+
+fn default_supported() -> bool {
+    true
+}
+
+/// What to do with `cache_control` prompt caching breakpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheControl {
+    /// Forward requests as the client sent them.
+    #[default]
+    Passthrough,
+    /// Add breakpoints after large tool definitions and system prompts
+    /// when the client set none.
+    Inject,
+    /// Remove breakpoints, for providers that reject the field.
+    Strip,
+}
+
+/// What a route does with a conversation too long for its provider's
+/// `context_window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextGuard {
+    /// Drop the oldest messages.
+    Trim,
+    /// Replace the oldest messages with a summary from
+    /// `[context_guard].summary_model`, trimming if that fails.
+    Summarize,
+}
+
+/// What a route does with a request identical to one it's still serving,
+/// such as a client retrying before the first attempt has finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dedup {
+    /// Send the second request the first one's response as well.
+    Attach,
+    /// Turn the second request away with a 409.
+    Reject,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct ProviderConfig {
     pub url: String,
     #[serde(default)]
     pub strip_auth: bool,
     pub api_key: Option<String>,
+    /// Path to a file holding the API key (`~/` is expanded).
+    pub api_key_file: Option<String>,
+    /// OS keychain entry holding the API key, as `"service/account"`.
+    pub api_key_keychain: Option<String>,
+    /// Command printing a short-lived bearer token, run again once the
+    /// token is `token_ttl_secs` old.
+    pub token_command: Option<Vec<String>>,
+    pub token_ttl_secs: Option<u64>,
+    /// OAuth client credentials grant for a short-lived bearer token.
+    pub oauth: Option<OAuthConfig>,
     #[serde(default)]
     pub stub_count_tokens: bool,
+    /// Outbound HTTP(S) proxy for this provider only.
+    pub proxy_url: Option<String>,
+    /// PEM file with extra root certificates to trust for this provider.
+    pub ca_cert: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// Use HTTP/2: negotiated over TLS, or assumed for `http://` URLs.
+    #[serde(default)]
+    pub http2: bool,
+    /// Idle connections kept open per host.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds an idle connection is kept before it is closed.
+    pub pool_idle_timeout_secs: Option<u64>,
+    /// Seconds between TCP keepalive probes, and HTTP/2 pings with `http2`.
+    pub keepalive_secs: Option<u64>,
+    #[serde(default)]
+    pub api_format: ApiFormat,
+    /// Google Cloud project, for `api_format = "vertex"`.
+    pub project: Option<String>,
+    /// Vertex AI region, e.g. `us-east5` or `global`.
+    pub region: Option<String>,
+    /// Service account or authorized user JSON for Vertex AI, instead of
+    /// Application Default Credentials.
+    pub credentials_file: Option<String>,
+    /// Azure OpenAI `api-version` query parameter.
+    pub api_version: Option<String>,
+    #[serde(default)]
+    pub cache_control: CacheControl,
+    /// Once less than this fraction of the provider's reported rate limit
+    /// remains, requests are rerouted to `ratelimit_fallback`, or held
+    /// until the limit resets.
+    pub ratelimit_reserve: Option<f64>,
+    pub ratelimit_fallback: Option<String>,
+    /// Hold requests the provider answers with 429 and retry them once it
+    /// says they may be, waiting up to this long in all.
+    pub retry_429_max_wait_secs: Option<u64>,
+    /// Ping the provider this often to keep its model loaded.
+    pub warm_interval_secs: Option<u64>,
+    /// Model warm-up pings load, if not the one the provider's first route
+    /// rewrites to.
+    pub warm_model: Option<String>,
+    /// Tokens the provider's model takes in, for routes with a
+    /// `context_guard`.
+    pub context_window: Option<u64>,
+    /// Headers sent with every request to this provider, such as a pinned
+    /// `anthropic-version`. A client's own header of the same name wins.
+    #[serde(default)]
+    pub default_headers: HashMap<String, String>,
+    /// What the provider's API offers. Requests needing something it
+    /// lacks are answered with a 501, or sent to `capability_fallback`.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    pub capability_fallback: Option<String>,
+    /// Check the models this Ollama server has pulled against the routes
+    /// that rewrite to it, and what to do about one that's missing.
+    pub missing_models: Option<MissingModels>,
+    /// How often to check, with `missing_models`.
+    pub model_check_secs: Option<u64>,
+    /// Hugging Face `tokenizer.json` that output tokens are counted with
+    /// when the provider doesn't report them.
+    pub tokenizer: Option<String>,
+}
+
+/// `[provider.NAME.oauth]`: where to get a token with the OAuth client
+/// credentials grant.
+#[derive(Clone, Deserialize)]
+pub struct OAuthConfig {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Path to a file holding the client secret (`~/` is expanded).
+    pub client_secret_file: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl std::fmt::Debug for OAuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuthConfig")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("client_secret_file", &self.client_secret_file)
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+// Hand-written so the API key never ends up in debug output or logs.
+impl std::fmt::Debug for ProviderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProviderConfig")
+            .field("url", &self.url)
+            .field("strip_auth", &self.strip_auth)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("api_key_file", &self.api_key_file)
+            .field("api_key_keychain", &self.api_key_keychain)
+            .field("token_command", &self.token_command)
+            .field("token_ttl_secs", &self.token_ttl_secs)
+            .field("oauth", &self.oauth)
+            .field("stub_count_tokens", &self.stub_count_tokens)
+            .field("proxy_url", &self.proxy_url)
+            .field("ca_cert", &self.ca_cert)
+            .field("insecure_skip_verify", &self.insecure_skip_verify)
+            .field("http2", &self.http2)
+            .field("pool_max_idle_per_host", &self.pool_max_idle_per_host)
+            .field("pool_idle_timeout_secs", &self.pool_idle_timeout_secs)
+            .field("keepalive_secs", &self.keepalive_secs)
+            .field("api_format", &self.api_format)
+            .field("project", &self.project)
+            .field("region", &self.region)
+            .field("credentials_file", &self.credentials_file)
+            .field("api_version", &self.api_version)
+            .field("cache_control", &self.cache_control)
+            .field("ratelimit_reserve", &self.ratelimit_reserve)
+            .field("ratelimit_fallback", &self.ratelimit_fallback)
+            .field("retry_429_max_wait_secs", &self.retry_429_max_wait_secs)
+            .field("warm_interval_secs", &self.warm_interval_secs)
+            .field("warm_model", &self.warm_model)
+            .field("context_window", &self.context_window)
+            // Values may be credentials too
+            .field(
+                "default_headers",
+                &self.default_headers.keys().collect::<Vec<_>>(),
+            )
+            .field("capabilities", &self.capabilities)
+            .field("capability_fallback", &self.capability_fallback)
+            .field("missing_models", &self.missing_models)
+            .field("model_check_secs", &self.model_check_secs)
+            .field("tokenizer", &self.tokenizer)
+            .finish()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -143,6 +1544,10 @@ pub struct AutoRouterConfig {
     pub model: String,
     #[serde(default = "default_auto_router_timeout_ms")]
     pub timeout_ms: u64,
+    /// Runs the classifier as a child of croxy rather than as a separate
+    /// service.
+    #[serde(default)]
+    pub local: Option<LocalAutoRouterConfig>,
 }
 
 impl Default for AutoRouterConfig {
@@ -152,6 +1557,7 @@ impl Default for AutoRouterConfig {
             url: String::new(),
             model: String::new(),
             timeout_ms: default_auto_router_timeout_ms(),
+            local: None,
         }
     }
 }
@@ -160,29 +1566,146 @@ fn default_auto_router_timeout_ms() -> u64 {
     2000
 }
 
+/// `[auto_router.local]`: a classifier process croxy starts, health-checks,
+/// and restarts when it dies.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LocalAutoRouterConfig {
+    /// Run with `sh -c`, so it can be a full command line.
+    pub command: String,
+    /// Must answer 2xx while the process is serving. Unset, any answer
+    /// from the host in `auto_router.url` will do.
+    pub health_url: Option<String>,
+    pub health_interval_secs: u64,
+    /// How long the process may take to start serving, model load included.
+    pub startup_timeout_secs: u64,
+    /// Restarts wait twice as long each time, up to this.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for LocalAutoRouterConfig {
+    fn default() -> Self {
+        Self {
+            command: String::new(),
+            health_url: None,
+            health_interval_secs: 10,
+            startup_timeout_secs: 120,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+/// Sends models matching `pattern` to `provider`, whatever the routes say.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PinConfig {
+    pub pattern: String,
+    pub provider: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RouteConfig {
     pub name: Option<String>,
     pub description: Option<String>,
     pub pattern: Option<String>,
+    /// Only requests whose `metadata.user_id` matches this regex take the
+    /// route.
+    pub user_id: Option<String>,
     pub provider: String,
     pub model: Option<String>,
+    /// Cut responses off past this many bytes.
+    pub max_response_bytes: Option<u64>,
+    /// Cut responses off this many seconds after the request arrived.
+    pub max_stream_secs: Option<u64>,
+    /// Sampling parameters sent in place of the client's.
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u64>,
+    pub max_tokens: Option<u64>,
+    pub context_guard: Option<ContextGuard>,
+    pub dedup: Option<Dedup>,
+    /// Save this route's responses to `[archive]`.
+    #[serde(default)]
+    pub archive: bool,
+    /// Fix malformed tool call JSON in this route's responses.
+    #[serde(default)]
+    pub repair_tool_calls: bool,
+}
+
+/// `[route_defaults]`: the [`RouteConfig`] keys that can be shared.
+#[derive(Debug, Default, Deserialize)]
+pub struct RouteDefaults {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub max_response_bytes: Option<u64>,
+    pub max_stream_secs: Option<u64>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u64>,
+    pub max_tokens: Option<u64>,
+    pub context_guard: Option<ContextGuard>,
+    pub dedup: Option<Dedup>,
+    pub archive: Option<bool>,
+    pub repair_tool_calls: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DefaultRoute {
     #[serde(default = "default_provider")]
     pub provider: String,
+    /// Model unmatched requests to `provider` are rewritten to.
+    pub model: Option<String>,
+    /// Overrides the provider's `strip_auth` for unmatched requests.
+    pub strip_auth: Option<bool>,
+    /// Sent in place of the provider's API key for unmatched requests.
+    pub api_key: Option<String>,
+    pub max_response_bytes: Option<u64>,
+    pub max_stream_secs: Option<u64>,
+    /// Providers for unmatched requests of one kind, in place of
+    /// `provider`: `[default.models]` and the like.
+    pub messages: Option<EndpointDefault>,
+    pub count_tokens: Option<EndpointDefault>,
+    pub models: Option<EndpointDefault>,
+    pub other: Option<EndpointDefault>,
 }
 
 impl Default for DefaultRoute {
     fn default() -> Self {
         Self {
             provider: default_provider(),
+            model: None,
+            strip_auth: None,
+            api_key: None,
+            max_response_bytes: None,
+            max_stream_secs: None,
+            messages: None,
+            count_tokens: None,
+            models: None,
+            other: None,
         }
     }
 }
 
+impl DefaultRoute {
+    /// The per-endpoint defaults that are set, by their table name.
+    pub fn endpoints(&self) -> impl Iterator<Item = (&'static str, &EndpointDefault)> {
+        [
+            ("messages", &self.messages),
+            ("count_tokens", &self.count_tokens),
+            ("models", &self.models),
+            ("other", &self.other),
+        ]
+        .into_iter()
+        .filter_map(|(name, default)| Some((name, default.as_ref()?)))
+    }
+}
+
+/// The default for one kind of request. Response limits still come from
+/// `[default]`.
+#[derive(Debug, Deserialize)]
+pub struct EndpointDefault {
+    pub provider: String,
+}
+
 fn default_provider() -> String {
     "anthropic".to_string()
 }
@@ -208,6 +1731,7 @@ mod tests {
                 strip_auth = true
                 api_key = "ollama"
                 stub_count_tokens = true
+                api_format = "ollama"
                 [[routes]]
                 pattern = "opus"
                 provider = "anthropic"
@@ -228,6 +1752,8 @@ mod tests {
         assert!(cfg.providers["ollama"].strip_auth);
         assert_eq!(cfg.providers["ollama"].api_key.as_deref(), Some("ollama"));
         assert!(cfg.providers["ollama"].stub_count_tokens);
+        assert_eq!(cfg.providers["ollama"].api_format, ApiFormat::Ollama);
+        assert_eq!(cfg.providers["anthropic"].api_format, ApiFormat::Anthropic);
         assert!(!cfg.providers["anthropic"].strip_auth);
         assert_eq!(cfg.providers["anthropic"].api_key, None);
         assert_eq!(cfg.routes.len(), 2);
@@ -466,4 +1992,326 @@ mod tests {
         assert!(cfg.routes[0].pattern.is_none());
         assert_eq!(cfg.routes[0].name.as_deref(), Some("coding"));
     }
+
+    #[test]
+    fn socket_config_parses() {
+        let cfg: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [server]
+                socket = "/run/user/1000/croxy.sock"
+                tcp = false
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            cfg.server.socket.as_deref(),
+            Some("/run/user/1000/croxy.sock")
+        );
+        assert!(!cfg.server.tcp);
+        assert_eq!(
+            cfg.server.base_url(),
+            "http+unix://%2Frun%2Fuser%2F1000%2Fcroxy.sock"
+        );
+    }
+
+    #[test]
+    fn base_url_prefers_tcp_when_enabled() {
+        let cfg: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [server]
+                host = "0.0.0.0"
+                socket = "/tmp/croxy.sock"
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        assert!(cfg.server.tcp);
+        assert_eq!(cfg.server.base_url(), "http://127.0.0.1:3100");
+    }
+
+    #[test]
+    fn drain_timeout_defaults_and_overrides() {
+        let cfg: Config = Figment::new().merge(Toml::string("")).extract().unwrap();
+        assert_eq!(cfg.server.drain_timeout_secs, 30);
+
+        let cfg: Config = Figment::new()
+            .merge(Toml::string("[server]\ndrain_timeout_secs = 5"))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.server.drain_timeout_secs, 5);
+    }
+
+    #[test]
+    fn instance_name_parses() {
+        let cfg: Config = Figment::new()
+            .merge(Toml::string("[instance]\nname = \"work\"\n"))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.instance.name.as_deref(), Some("work"));
+    }
+
+    #[test]
+    fn instance_port_is_stable_and_offset() {
+        let port = instance_port("work");
+        assert_eq!(port, instance_port("work"));
+        assert!((3101..=3999).contains(&port));
+        assert_ne!(instance_port("work"), instance_port("personal"));
+    }
+
+    #[test]
+    fn instance_names_are_validated() {
+        assert!(validate_instance_name("work-2").is_ok());
+        assert!(validate_instance_name("").is_err());
+        assert!(validate_instance_name("../etc").is_err());
+    }
+
+    #[test]
+    fn log_format_defaults_to_text() {
+        let cfg: Config = Figment::new().merge(Toml::string("")).extract().unwrap();
+        assert_eq!(cfg.logging.format, LogFormat::Text);
+    }
+
+    #[test]
+    fn log_format_parses_json() {
+        let cfg: Config = Figment::new()
+            .merge(Toml::string("[logging]\nformat = \"json\"\n"))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.logging.format, LogFormat::Json);
+    }
+
+    #[test]
+    fn app_log_config_parses() {
+        let cfg: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [logging.app]
+                max_size_mb = 5
+                max_files = 2
+                max_age_hours = 24
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.logging.app.max_size_mb, 5);
+        assert_eq!(cfg.logging.app.max_files, 2);
+        assert_eq!(cfg.logging.app.max_age_hours, Some(24));
+    }
+
+    #[test]
+    fn app_log_defaults_when_omitted() {
+        let cfg: Config = Figment::new().merge(Toml::string("")).extract().unwrap();
+        assert_eq!(cfg.logging.app.max_size_mb, 10);
+        assert_eq!(cfg.logging.app.max_files, 3);
+        assert_eq!(cfg.logging.app.max_age_hours, None);
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn includes_merge_with_including_file_winning() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "providers.toml",
+            "[server]\nport = 4000\nhost = \"0.0.0.0\"\n[provider.anthropic]\nurl = \"https://api.anthropic.com\"\n",
+        );
+        let main = write(
+            dir.path(),
+            "config.toml",
+            "include = [\"providers.toml\"]\n[server]\nport = 5000\n",
+        );
+        let cfg: Config = config_figment(&main).unwrap().extract().unwrap();
+        assert_eq!(cfg.server.port, 5000);
+        assert_eq!(cfg.server.host, "0.0.0.0");
+        assert!(cfg.providers.contains_key("anthropic"));
+    }
+
+    #[test]
+    fn include_globs_are_ordered_and_routes_prefer_later_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "routes.d/10-base.toml",
+            "[[routes]]\npattern = \"opus\"\nprovider = \"base\"\n",
+        );
+        write(
+            dir.path(),
+            "routes.d/20-local.toml",
+            "[[routes]]\npattern = \"opus\"\nprovider = \"local\"\n",
+        );
+        write(dir.path(), "routes.d/notes.txt", "not toml");
+        let main = write(
+            dir.path(),
+            "config.toml",
+            "include = [\"routes.d/*.toml\"]\n[[routes]]\npattern = \"haiku\"\nprovider = \"main\"\n",
+        );
+        let cfg: Config = config_figment(&main).unwrap().extract().unwrap();
+        let providers: Vec<&str> = cfg.routes.iter().map(|r| r.provider.as_str()).collect();
+        assert_eq!(providers, vec!["main", "local", "base"]);
+    }
+
+    #[test]
+    fn missing_literal_include_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = write(dir.path(), "config.toml", "include = [\"nope.toml\"]\n");
+        let err = config_figment(&main).unwrap_err().to_string();
+        assert!(err.contains("included file not found"), "{err}");
+    }
+
+    #[test]
+    fn unmatched_include_glob_is_fine() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = write(
+            dir.path(),
+            "config.toml",
+            "include = [\"routes.d/*.toml\"]\n",
+        );
+        let cfg: Config = config_figment(&main).unwrap().extract().unwrap();
+        assert!(cfg.routes.is_empty());
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", "include = [\"b.toml\"]\n");
+        write(dir.path(), "b.toml", "include = [\"a.toml\"]\n");
+        let main = write(dir.path(), "config.toml", "include = [\"a.toml\"]\n");
+        let err = config_figment(&main).unwrap_err().to_string();
+        assert!(err.contains("include cycle"), "{err}");
+    }
+
+    #[test]
+    fn missing_top_level_file_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg: Config = config_figment(&dir.path().join("absent.toml"))
+            .unwrap()
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.server.port, 3100);
+    }
+
+    const PROFILES: &str = r#"
+        [provider.anthropic]
+        url = "https://api.anthropic.com"
+        [provider.ollama]
+        url = "http://localhost:11434"
+        [[routes]]
+        pattern = "sonnet"
+        provider = "ollama"
+
+        [profiles.work.provider.anthropic]
+        url = "https://llm-gateway.corp.internal"
+        [[profiles.work.routes]]
+        pattern = "haiku"
+        provider = "anthropic"
+
+        [profiles.home]
+        "#;
+
+    #[test]
+    fn profile_overrides_providers_and_replaces_routes() {
+        let file = Figment::from(Toml::string(PROFILES));
+        let cfg: Config = apply_profile(file, Some("work"))
+            .unwrap()
+            .extract()
+            .unwrap();
+        assert_eq!(
+            cfg.providers["anthropic"].url,
+            "https://llm-gateway.corp.internal"
+        );
+        assert_eq!(cfg.providers["ollama"].url, "http://localhost:11434");
+        assert_eq!(cfg.routes.len(), 1);
+        assert_eq!(cfg.routes[0].pattern.as_deref(), Some("haiku"));
+    }
+
+    #[test]
+    fn no_profile_leaves_config_unchanged() {
+        let file = Figment::from(Toml::string(PROFILES));
+        let cfg: Config = apply_profile(file, None).unwrap().extract().unwrap();
+        assert_eq!(cfg.providers["anthropic"].url, "https://api.anthropic.com");
+        assert_eq!(cfg.routes[0].pattern.as_deref(), Some("sonnet"));
+    }
+
+    #[test]
+    fn unknown_profile_lists_available() {
+        let file = Figment::from(Toml::string(PROFILES));
+        let err = apply_profile(file, Some("cafe")).unwrap_err().to_string();
+        assert_eq!(err, "profile 'cafe' not found (available: home, work)");
+    }
+
+    #[test]
+    fn profile_selection_precedence() {
+        let file = Figment::from(Toml::string("profile = \"home\""));
+        assert_eq!(
+            select_profile(Some("work"), Some("cafe"), &file).as_deref(),
+            Some("work")
+        );
+        assert_eq!(
+            select_profile(None, Some("cafe"), &file).as_deref(),
+            Some("cafe")
+        );
+        assert_eq!(select_profile(None, None, &file).as_deref(), Some("home"));
+        assert_eq!(
+            select_profile(None, Some(""), &Figment::new()).as_deref(),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_inherit_what_they_leave_out_from_route_defaults() {
+        let file = Figment::from(Toml::string(
+            r#"
+            [route_defaults]
+            provider = "ollama"
+            max_stream_secs = 300
+            temperature = 0.2
+            repair_tool_calls = true
+            pattern = "ignored"
+
+            [[routes]]
+            pattern = "haiku"
+            model = "qwen3:8b"
+
+            [[routes]]
+            pattern = "sonnet"
+            provider = "anthropic"
+            repair_tool_calls = false
+            "#,
+        ));
+        let cfg: Config = apply_route_defaults(file).extract().unwrap();
+        let (haiku, sonnet) = (&cfg.routes[0], &cfg.routes[1]);
+        assert_eq!(haiku.pattern.as_deref(), Some("haiku"));
+        assert_eq!(haiku.provider, "ollama");
+        assert_eq!(haiku.model.as_deref(), Some("qwen3:8b"));
+        assert_eq!(haiku.max_stream_secs, Some(300));
+        assert_eq!(haiku.temperature, Some(0.2));
+        assert!(haiku.repair_tool_calls);
+        assert_eq!(sonnet.pattern.as_deref(), Some("sonnet"));
+        assert_eq!(sonnet.provider, "anthropic");
+        assert_eq!(sonnet.max_stream_secs, Some(300));
+        assert!(!sonnet.repair_tool_calls);
+    }
+
+    #[test]
+    fn profile_routes_inherit_route_defaults() {
+        let file = Figment::from(Toml::string(&format!(
+            "{PROFILES}\n[route_defaults]\nmax_tokens = 4096\n"
+        )));
+        let cfg: Config = apply_route_defaults(apply_profile(file, Some("work")).unwrap())
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.routes[0].pattern.as_deref(), Some("haiku"));
+        assert_eq!(cfg.routes[0].max_tokens, Some(4096));
+    }
 }
@@ -0,0 +1,207 @@
+//! Keeps long conversations inside a provider's context window. A model
+//! with a 32k window rejects a long agent session outright, so routes with
+//! a `context_guard` drop the oldest messages before forwarding, or have a
+//! cheaper model summarize them.
+
+use serde_json::{Value, json};
+
+/// Tokens left free for the summary that stands in for dropped messages.
+pub const SUMMARY_TOKENS: u64 = 1024;
+
+const SUMMARY_PROMPT: &str = "Summarize the earlier part of a conversation between a user and \
+    an AI assistant, which is about to be dropped to save space. Keep the user's goals, \
+    decisions made, files and identifiers mentioned, and work still outstanding. Be concise.";
+
+fn estimated_tokens(value: &Value) -> u64 {
+    value.to_string().len() as u64 / 4
+}
+
+/// Whether the conversation may start at `message`: a user turn that isn't
+/// answering tool calls made in messages that would be gone.
+fn starts_turn(message: &Value) -> bool {
+    message["role"] == "user"
+        && !message["content"]
+            .as_array()
+            .is_some_and(|blocks| blocks.iter().any(|block| block["type"] == "tool_result"))
+}
+
+/// How many of a Messages request's oldest messages to drop for it to fit
+/// `window` tokens, with its system prompt, tools, `max_tokens`, and
+/// `reserve` more. `None` when it fits already or can't be cut. The cut
+/// always falls where a user turn starts, and when nothing fits it keeps
+/// only the latest turn.
+pub fn cut(body: &Value, window: u64, reserve: u64) -> Option<usize> {
+    let messages = body["messages"].as_array()?;
+    let fixed = estimated_tokens(&body["system"])
+        + estimated_tokens(&body["tools"])
+        + body["max_tokens"].as_u64().unwrap_or(0)
+        + reserve;
+    let sizes: Vec<u64> = messages.iter().map(estimated_tokens).collect();
+    let mut remaining: u64 = sizes.iter().sum();
+    if fixed + remaining <= window {
+        return None;
+    }
+    let mut best = None;
+    for (index, message) in messages.iter().enumerate().skip(1) {
+        remaining -= sizes[index - 1];
+        if !starts_turn(message) {
+            continue;
+        }
+        best = Some(index);
+        if fixed + remaining <= window {
+            break;
+        }
+    }
+    best
+}
+
+/// Removes the first `count` messages, returning them.
+pub fn trim(body: &mut Value, count: usize) -> Vec<Value> {
+    match body["messages"].as_array_mut() {
+        Some(messages) => messages.drain(..count.min(messages.len())).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// A request asking `model` to summarize `messages`.
+pub fn summary_request(model: &str, messages: &[Value]) -> Value {
+    json!({
+        "model": model,
+        "max_tokens": SUMMARY_TOKENS,
+        "system": SUMMARY_PROMPT,
+        "messages": [{"role": "user", "content": transcript(messages)}],
+    })
+}
+
+/// Puts `summary` ahead of the first message left.
+pub fn prepend_summary(body: &mut Value, summary: &str) {
+    let Some(first) = body["messages"].get_mut(0) else {
+        return;
+    };
+    let note = json!({
+        "type": "text",
+        "text": format!("Summary of the earlier conversation:\n{summary}"),
+    });
+    let blocks = match first["content"].take() {
+        Value::String(text) => vec![note, json!({"type": "text", "text": text})],
+        Value::Array(mut blocks) => {
+            blocks.insert(0, note);
+            blocks
+        }
+        _ => vec![note],
+    };
+    first["content"] = Value::Array(blocks);
+}
+
+/// Messages as plain text, one line per block.
+fn transcript(messages: &[Value]) -> String {
+    let mut text = String::new();
+    for message in messages {
+        let role = message["role"].as_str().unwrap_or("user");
+        let blocks = match message["content"] {
+            Value::String(ref content) => vec![content.clone()],
+            Value::Array(ref blocks) => blocks.iter().filter_map(block_text).collect(),
+            _ => Vec::new(),
+        };
+        for block in blocks {
+            text.push_str(&format!("{role}: {block}\n"));
+        }
+    }
+    text
+}
+
+fn block_text(block: &Value) -> Option<String> {
+    match block["type"].as_str()? {
+        "text" => block["text"].as_str().map(str::to_string),
+        "tool_use" => Some(format!(
+            "[called {} with {}]",
+            block["name"].as_str().unwrap_or("a tool"),
+            block["input"]
+        )),
+        "tool_result" => Some(match block["content"] {
+            Value::String(ref content) => format!("[tool result: {content}]"),
+            Value::Array(ref blocks) => format!(
+                "[tool result: {}]",
+                blocks
+                    .iter()
+                    .filter_map(block_text)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            _ => "[tool result]".to_string(),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Value {
+        let filler = "x".repeat(400);
+        json!({
+            "model": "m",
+            "max_tokens": 100,
+            "system": "be brief",
+            "messages": [
+                {"role": "user", "content": format!("first {filler}")},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "read", "input": {"path": "a.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": filler.clone()}
+                ]},
+                {"role": "assistant", "content": format!("second {filler}")},
+                {"role": "user", "content": "latest"},
+            ]
+        })
+    }
+
+    #[test]
+    fn fitting_conversations_are_left_alone() {
+        assert_eq!(cut(&conversation(), 10_000, 0), None);
+        assert_eq!(cut(&json!({"max_tokens": 10}), 5, 0), None);
+    }
+
+    #[test]
+    fn cuts_fall_where_a_user_turn_starts() {
+        let body = conversation();
+        // Dropping the first message would be enough, but the tool call
+        // and result after it can't lead, so the cut moves on to the next
+        // user turn.
+        assert_eq!(cut(&body, 400, 0), Some(4));
+        // Nothing fits; only the latest turn is kept
+        assert_eq!(cut(&body, 10, 0), Some(4));
+        assert_eq!(cut(&body, 400, 10_000), Some(4));
+
+        let mut body = body;
+        let dropped = trim(&mut body, 4);
+        assert_eq!(dropped.len(), 4);
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": "latest"}])
+        );
+    }
+
+    #[test]
+    fn summaries_lead_the_first_kept_message() {
+        let mut body = conversation();
+        let dropped = trim(&mut body, 4);
+        let request = summary_request("haiku", &dropped);
+        let transcript = request["messages"][0]["content"].as_str().unwrap();
+        assert!(transcript.starts_with("user: first x"));
+        assert!(transcript.contains("assistant: [called read with {\"path\":\"a.rs\"}]"));
+        assert!(transcript.contains("user: [tool result: xxx"));
+        assert_eq!(request["max_tokens"], SUMMARY_TOKENS);
+
+        prepend_summary(&mut body, "they read a.rs");
+        assert_eq!(
+            body["messages"][0]["content"],
+            json!([
+                {"type": "text", "text": "Summary of the earlier conversation:\nthey read a.rs"},
+                {"type": "text", "text": "latest"},
+            ])
+        );
+    }
+}
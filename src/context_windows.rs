@@ -0,0 +1,231 @@
+//! How many tokens each model takes in and gives back. Requests that won't
+//! fit the model they are sent to fail upstream, or are silently truncated
+//! by Ollama, so croxy checks each one against the model's limits and
+//! flags those that come close.
+//!
+//! Limits come from `[models.NAME]`, then the provider's `context_window`,
+//! then a built-in table of common models, each matched by the longest
+//! name prefix.
+
+use std::collections::HashMap;
+
+use crate::config::{Config, ModelLimits};
+
+/// Share of the context window a request must fill to count as near it.
+pub const NEAR_LIMIT: f64 = 0.9;
+
+/// Name prefix, context window, and output limit of well-known models.
+/// Local models are listed with the window they were trained for, which
+/// is usually more than Ollama gives them unless told otherwise.
+const BUILT_IN: &[(&str, u64, Option<u64>)] = &[
+    ("claude-3-haiku", 200_000, Some(4_096)),
+    ("claude-3-opus", 200_000, Some(4_096)),
+    ("claude-3-5-haiku", 200_000, Some(8_192)),
+    ("claude-3-5-sonnet", 200_000, Some(8_192)),
+    ("claude-3-7-sonnet", 200_000, Some(64_000)),
+    ("claude-haiku-4", 200_000, Some(64_000)),
+    ("claude-sonnet-4", 200_000, Some(64_000)),
+    ("claude-opus-4-20250514", 200_000, Some(32_000)),
+    ("claude-opus-4-0", 200_000, Some(32_000)),
+    ("claude-opus-4-1", 200_000, Some(32_000)),
+    ("claude-opus-4-5", 200_000, Some(64_000)),
+    ("claude", 200_000, None),
+    ("gpt-4o", 128_000, Some(16_384)),
+    ("gpt-4.1", 1_047_576, Some(32_768)),
+    ("gpt-oss", 131_072, None),
+    ("gemini-2.0-flash", 1_048_576, Some(8_192)),
+    ("gemini-2.5", 1_048_576, Some(65_536)),
+    ("qwen3-coder", 262_144, None),
+    ("qwen3", 40_960, None),
+    ("qwen2.5-coder", 32_768, None),
+    ("qwen2.5", 32_768, None),
+    ("llama3.1", 131_072, None),
+    ("llama3.2", 131_072, None),
+    ("llama3.3", 131_072, None),
+    ("llama3", 8_192, None),
+    ("codellama", 16_384, None),
+    ("deepseek-r1", 131_072, None),
+    ("deepseek-coder-v2", 163_840, None),
+    ("devstral", 131_072, None),
+    ("mistral-nemo", 131_072, None),
+    ("mistral", 32_768, None),
+    ("gemma3", 131_072, None),
+    ("phi4", 16_384, None),
+];
+
+/// A model's limits, as far as they are known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub context_window: u64,
+    pub max_output_tokens: Option<u64>,
+}
+
+/// How a request measures up to its model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    Fits,
+    /// Fills at least [`NEAR_LIMIT`] of the window.
+    Near,
+    /// Needs more than the window holds.
+    Over,
+}
+
+impl Limits {
+    /// How a request of `input_tokens`, asking for up to `max_tokens`
+    /// back, measures up to these limits.
+    pub fn fit(&self, input_tokens: u64, max_tokens: Option<u64>) -> Fit {
+        let needed = input_tokens + max_tokens.unwrap_or(0);
+        if needed > self.context_window {
+            Fit::Over
+        } else if needed as f64 >= self.context_window as f64 * NEAR_LIMIT {
+            Fit::Near
+        } else {
+            Fit::Fits
+        }
+    }
+}
+
+/// The built-in limits with `[models]` over them.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    overrides: HashMap<String, ModelLimits>,
+}
+
+impl Registry {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            overrides: config.models.clone(),
+        }
+    }
+
+    /// `model`'s limits, with `provider_window` (the provider's
+    /// `context_window`) in place of the built-in window. `None` when
+    /// nothing gives the model a window.
+    pub fn limits(&self, model: &str, provider_window: Option<u64>) -> Option<Limits> {
+        // Namespaced names like `openai/gpt-oss-20b` are matched by the
+        // model's own name
+        let name = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+        let configured = longest_prefix(
+            self.overrides
+                .iter()
+                .map(|(prefix, limits)| (prefix.to_lowercase(), *limits)),
+            &name,
+        )
+        .unwrap_or_default();
+        let built_in = longest_prefix(
+            BUILT_IN
+                .iter()
+                .map(|&(prefix, context_window, max_output_tokens)| {
+                    (
+                        prefix.to_string(),
+                        ModelLimits {
+                            context_window: Some(context_window),
+                            max_output_tokens,
+                        },
+                    )
+                }),
+            &name,
+        )
+        .unwrap_or_default();
+        Some(Limits {
+            context_window: configured
+                .context_window
+                .or(provider_window)
+                .or(built_in.context_window)?,
+            max_output_tokens: configured.max_output_tokens.or(built_in.max_output_tokens),
+        })
+    }
+}
+
+/// The limits of the longest prefix of `name` among `entries`.
+fn longest_prefix(
+    entries: impl Iterator<Item = (String, ModelLimits)>,
+    name: &str,
+) -> Option<ModelLimits> {
+    entries
+        .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, limits)| limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn registry(toml: &str) -> Registry {
+        let config: Config = Figment::new().merge(Toml::string(toml)).extract().unwrap();
+        Registry::from_config(&config)
+    }
+
+    #[test]
+    fn models_match_their_longest_built_in_prefix() {
+        let registry = Registry::default();
+        let limits = |model| registry.limits(model, None);
+        assert_eq!(
+            limits("claude-opus-4-5-20251101"),
+            Some(Limits {
+                context_window: 200_000,
+                max_output_tokens: Some(64_000),
+            })
+        );
+        assert_eq!(
+            limits("claude-opus-4-1-20250805")
+                .unwrap()
+                .max_output_tokens,
+            Some(32_000)
+        );
+        assert_eq!(limits("qwen3-coder:30b").unwrap().context_window, 262_144);
+        assert_eq!(limits("qwen3:8b").unwrap().context_window, 40_960);
+        assert_eq!(
+            limits("openai/gpt-oss-20b").unwrap().context_window,
+            131_072
+        );
+        assert_eq!(limits("my-finetune"), None);
+    }
+
+    #[test]
+    fn configured_limits_win_over_the_provider_and_built_in_ones() {
+        let registry = registry(
+            r#"
+            [models.qwen3-coder]
+            max_output_tokens = 8192
+            [models."qwen3-coder:480b"]
+            context_window = 1000000
+            [models.my-finetune]
+            context_window = 16384
+            "#,
+        );
+        assert_eq!(
+            registry.limits("qwen3-coder:30b", Some(32_768)),
+            Some(Limits {
+                context_window: 32_768,
+                max_output_tokens: Some(8_192),
+            })
+        );
+        assert_eq!(
+            registry.limits("qwen3-coder:480b", Some(32_768)),
+            Some(Limits {
+                context_window: 1_000_000,
+                max_output_tokens: None,
+            })
+        );
+        assert_eq!(
+            registry.limits("my-finetune", None).unwrap().context_window,
+            16_384
+        );
+    }
+
+    #[test]
+    fn requests_are_near_the_limit_from_ninety_percent() {
+        let limits = Limits {
+            context_window: 10_000,
+            max_output_tokens: None,
+        };
+        assert_eq!(limits.fit(5_000, Some(1_000)), Fit::Fits);
+        assert_eq!(limits.fit(8_000, Some(1_000)), Fit::Near);
+        assert_eq!(limits.fit(10_000, None), Fit::Near);
+        assert_eq!(limits.fit(9_500, Some(1_000)), Fit::Over);
+    }
+}
@@ -0,0 +1,1223 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::sync::{Notify, broadcast, watch};
+
+use crate::admin::{NEEDS_TOKEN, PREFIX, Status};
+use crate::audit::AuditEvent;
+use crate::balance::Health;
+use crate::compare::Comparison;
+use crate::error::CroxyError;
+use crate::events::RequestEvent;
+use crate::keys::QuotaUsage;
+use crate::listeners;
+use crate::metrics::{MetricsStore, RequestRecord, RoutingMethod, Timings};
+use crate::proxy::AppState;
+use crate::ratelimits::RateLimit;
+use crate::router::{PinInfo, RouteInfo, Router};
+use crate::tokens::AuthStatus;
+use crate::warm::Warmth;
+
+/// A request record as sent over the control channel. `age_ms` rather than
+/// a wall-clock time positions the record, so viewers are immune to clock
+/// differences with the daemon.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireRecord {
+    pub id: u64,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    pub age_ms: u64,
+    pub wallclock: DateTime<Utc>,
+    pub model: String,
+    pub provider: String,
+    pub routing_method: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub error: Option<String>,
+    pub batch_size: Option<usize>,
+    #[serde(default)]
+    pub redactions: usize,
+    #[serde(default)]
+    pub listener: Option<String>,
+    #[serde(default)]
+    pub client: Option<String>,
+    #[serde(default)]
+    pub cutoff: Option<String>,
+    #[serde(default)]
+    pub chaos: Option<String>,
+    #[serde(default)]
+    pub bench: Option<String>,
+    #[serde(default)]
+    pub client_aborted: Option<u64>,
+    #[serde(default)]
+    pub near_limit: bool,
+    #[serde(default)]
+    pub timings: Timings,
+}
+
+impl WireRecord {
+    pub fn from_record(record: &RequestRecord) -> Self {
+        Self {
+            id: record.id,
+            request_id: record.request_id.clone(),
+            age_ms: record.timestamp.elapsed().as_millis() as u64,
+            wallclock: record.wallclock,
+            model: record.model.clone(),
+            provider: record.provider.clone(),
+            routing_method: record.routing_method.to_string(),
+            status: record.status,
+            duration_ms: record.duration.as_millis() as u64,
+            input_tokens: record.input_tokens,
+            output_tokens: record.output_tokens,
+            error: record.error_body.clone(),
+            batch_size: record.batch_size,
+            redactions: record.redactions,
+            listener: record.listener.clone(),
+            client: record.client.clone(),
+            cutoff: record.cutoff.clone(),
+            chaos: record.chaos.clone(),
+            bench: record.bench.clone(),
+            client_aborted: record.client_aborted,
+            near_limit: record.near_limit,
+            timings: record.timings,
+        }
+    }
+
+    pub fn into_record(self) -> RequestRecord {
+        let age = Duration::from_millis(self.age_ms);
+        RequestRecord {
+            id: self.id,
+            request_id: self.request_id,
+            timestamp: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            wallclock: self.wallclock,
+            model: self.model,
+            provider: self.provider,
+            routing_method: RoutingMethod::from_name(&self.routing_method),
+            status: self.status,
+            duration: Duration::from_millis(self.duration_ms),
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+            error_body: self.error,
+            batch_size: self.batch_size,
+            redactions: self.redactions,
+            listener: self.listener,
+            client: self.client,
+            cutoff: self.cutoff,
+            chaos: self.chaos,
+            bench: self.bench,
+            client_aborted: self.client_aborted,
+            near_limit: self.near_limit,
+            timings: self.timings,
+        }
+    }
+}
+
+/// One line of the control stream, newline-delimited JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Sent once on connect: every record the daemon holds and its
+    /// retention settings, so the viewer shows exactly what the daemon's
+    /// own TUI would.
+    Snapshot {
+        retention_secs: u64,
+        window_secs: u64,
+        records: Vec<WireRecord>,
+        #[serde(default)]
+        comparisons: Vec<Comparison>,
+        #[serde(default)]
+        rate_limits: Vec<RateLimit>,
+        #[serde(default)]
+        warmth: Vec<Warmth>,
+        #[serde(default)]
+        health: Vec<Health>,
+        #[serde(default)]
+        quotas: Vec<QuotaUsage>,
+        #[serde(default)]
+        auth: Vec<AuthStatus>,
+        /// Providers whose requests are priced.
+        #[serde(default)]
+        billable: Vec<String>,
+    },
+    /// The daemon's retention or display window changed.
+    Settings {
+        retention_secs: u64,
+        window_secs: u64,
+    },
+    /// A record that is new or changed since it was last sent, e.g. a
+    /// streaming request that has finished.
+    Record(WireRecord),
+    /// A dual-send comparison that has completed.
+    Comparison(Comparison),
+    /// A provider reported new rate limits.
+    RateLimit(RateLimit),
+    /// A provider's model was pinged to keep it loaded.
+    Warmth(Warmth),
+    /// A provider's recent latency and error rate changed.
+    Health(Health),
+    /// A client used tokens toward its quotas.
+    Quota(QuotaUsage),
+    /// A provider's bearer token was fetched, or failed to be.
+    Auth(AuthStatus),
+    /// The outcome of a [`Command`] the viewer sent on this connection.
+    Reply(Reply),
+    /// A stage of a request's life, sent after [`Command::Events`].
+    Event(RequestEvent),
+}
+
+/// An operator command sent by an attached viewer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Report routing state without changing anything.
+    Status,
+    SetRouteEnabled {
+        index: usize,
+        enabled: bool,
+    },
+    /// Send all traffic to a provider, or restore normal routing with `None`.
+    ForceProvider {
+        provider: Option<String>,
+    },
+    /// Send models matching `pattern` to `provider` until unpinned.
+    Pin {
+        pattern: String,
+        provider: String,
+    },
+    Unpin {
+        pattern: String,
+    },
+    Reload,
+    /// Stop accepting requests and exit once in-flight ones finish.
+    Drain,
+    /// Send request lifecycle events on this control socket connection
+    /// from now on, in place of records. Does nothing elsewhere.
+    Events,
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Status => write!(f, "status"),
+            Command::SetRouteEnabled {
+                index,
+                enabled: true,
+            } => write!(f, "enable route {index}"),
+            Command::SetRouteEnabled {
+                index,
+                enabled: false,
+            } => write!(f, "disable route {index}"),
+            Command::ForceProvider {
+                provider: Some(provider),
+            } => write!(f, "force all traffic to {provider}"),
+            Command::ForceProvider { provider: None } => write!(f, "restore normal routing"),
+            Command::Pin { pattern, provider } => write!(f, "pin {pattern} to {provider}"),
+            Command::Unpin { pattern } => write!(f, "unpin {pattern}"),
+            Command::Reload => write!(f, "reload config"),
+            Command::Drain => write!(f, "drain and stop croxy"),
+            Command::Events => write!(f, "stream request events"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoutingState {
+    pub forced: Option<String>,
+    pub providers: Vec<String>,
+    pub routes: Vec<RouteInfo>,
+    #[serde(default)]
+    pub pins: Vec<PinInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    pub message: String,
+    /// Routing state after the command ran.
+    pub routing: Option<RoutingState>,
+}
+
+impl Reply {
+    pub fn failed(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+            routing: None,
+        }
+    }
+}
+
+/// Rebuilds the router from the config file for [`Command::Reload`].
+pub type ReloadFn = Box<dyn Fn() -> Result<Router, CroxyError> + Send + Sync>;
+
+/// Executes viewer commands against the running proxy. Every command that
+/// changes something is written to the application log under the
+/// `croxy::audit` target, and to the audit log when it is enabled.
+pub struct Controller {
+    state: Arc<AppState>,
+    reload: ReloadFn,
+    drain: Arc<Notify>,
+}
+
+impl Controller {
+    pub fn new(state: Arc<AppState>, reload: ReloadFn, drain: Arc<Notify>) -> Self {
+        Self {
+            state,
+            reload,
+            drain,
+        }
+    }
+
+    pub fn routing_state(&self) -> RoutingState {
+        let router = self.state.router();
+        RoutingState {
+            forced: router.forced_provider(),
+            providers: router.provider_names(),
+            routes: router.routes(),
+            pins: router.pins(),
+        }
+    }
+
+    /// Runs `command`; `source` names where it came from for the audit log.
+    pub fn execute(&self, command: &Command, source: &str) -> Reply {
+        let result = match command {
+            Command::Status | Command::Events => Ok(String::new()),
+            Command::SetRouteEnabled { index, enabled } => self
+                .state
+                .router()
+                .set_route_enabled(*index, *enabled)
+                .map(|()| {
+                    format!(
+                        "route {index} {}",
+                        if *enabled { "enabled" } else { "disabled" }
+                    )
+                }),
+            Command::ForceProvider { provider } => self
+                .state
+                .router()
+                .force_provider(provider.as_deref())
+                .map(|()| match provider {
+                    Some(name) => format!("all traffic forced to {name}"),
+                    None => "normal routing restored".to_string(),
+                }),
+            Command::Pin { pattern, provider } => self
+                .state
+                .router()
+                .pin(pattern, provider)
+                .map(|()| format!("{pattern} pinned to {provider}")),
+            Command::Unpin { pattern } => self
+                .state
+                .router()
+                .unpin(pattern)
+                .map(|()| format!("{pattern} unpinned")),
+            Command::Reload => (self.reload)().map(|router| {
+                router.keep_pins(&self.state.router());
+                self.state.replace_router(router);
+                "config reloaded, runtime overrides other than pins cleared".to_string()
+            }),
+            Command::Drain => {
+                self.drain.notify_one();
+                Ok("draining, croxy exits once in-flight requests finish".to_string())
+            }
+        };
+
+        if !matches!(command, Command::Status | Command::Events) {
+            match result {
+                Ok(ref message) => {
+                    tracing::info!(target: "croxy::audit", source, command = %command, "{message}")
+                }
+                Err(ref e) => {
+                    tracing::warn!(target: "croxy::audit", source, command = %command, error = %e, "control command failed")
+                }
+            }
+            self.state.audit.record(AuditEvent::Command {
+                source: source.to_string(),
+                command: command.clone(),
+                ok: result.is_ok(),
+                message: match result {
+                    Ok(ref message) => message.clone(),
+                    Err(ref e) => e.to_string(),
+                },
+            });
+        }
+
+        let (ok, message) = match result {
+            Ok(message) => (true, message),
+            Err(e) => (false, e.to_string()),
+        };
+        Reply {
+            ok,
+            message,
+            routing: Some(self.routing_state()),
+        }
+    }
+}
+
+/// Lets an attached TUI send commands to the daemon and collect replies,
+/// whether it is connected over the control socket or the admin API.
+pub struct CommandChannel {
+    send: Box<dyn Fn(Command) + Send>,
+    replies: mpsc::Receiver<Reply>,
+}
+
+impl CommandChannel {
+    pub fn new(send: Box<dyn Fn(Command) + Send>, replies: mpsc::Receiver<Reply>) -> Self {
+        Self { send, replies }
+    }
+
+    pub fn send(&self, command: Command) {
+        (self.send)(command);
+    }
+
+    pub fn try_recv(&self) -> Option<Reply> {
+        self.replies.try_recv().ok()
+    }
+}
+
+/// Counts viewers attached over the control socket or the admin stream.
+/// Each viewer has its own [`Feed`], so any number can watch at once.
+#[derive(Clone, Default)]
+pub struct Viewers(Arc<AtomicUsize>);
+
+impl Viewers {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Registers a viewer until the returned guard is dropped.
+    pub fn join(&self) -> ViewerGuard {
+        let count = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::info!(viewers = count, "viewer attached");
+        ViewerGuard(self.0.clone())
+    }
+}
+
+pub struct ViewerGuard(Arc<AtomicUsize>);
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        let count = self.0.fetch_sub(1, Ordering::Relaxed) - 1;
+        tracing::info!(viewers = count, "viewer detached");
+    }
+}
+
+/// Binds the control socket, replacing a stale socket file.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if fs::symlink_metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+    listeners::bind_unix(path)
+}
+
+/// Accepts viewers on the control socket until the task is dropped.
+pub async fn serve(
+    listener: UnixListener,
+    metrics: Arc<MetricsStore>,
+    viewers: Viewers,
+    controller: Option<Arc<Controller>>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("control socket accept failed: {e}");
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        let controller = controller.clone();
+        let guard = viewers.join();
+        tokio::spawn(async move {
+            let _guard = guard;
+            if let Err(e) = serve_connection(stream, metrics, controller).await {
+                tracing::debug!("control connection closed: {e}");
+            }
+        });
+    }
+}
+
+/// Tracks what one viewer has been sent and produces the messages that
+/// bring it up to date with the store.
+pub struct Feed {
+    metrics: Arc<MetricsStore>,
+    changes: watch::Receiver<u64>,
+    sent: HashMap<u64, (u64, Duration)>,
+    settings: (Duration, Duration),
+    /// How many comparisons have been sent.
+    comparisons_sent: u64,
+    rate_limits_sent: HashMap<String, DateTime<Utc>>,
+    warmth_sent: HashMap<String, DateTime<Utc>>,
+    /// Samples behind the health last sent for each provider.
+    health_sent: HashMap<String, u64>,
+    quotas_sent: HashMap<String, QuotaUsage>,
+    auth_sent: HashMap<String, DateTime<Utc>>,
+}
+
+impl Feed {
+    pub fn new(metrics: Arc<MetricsStore>) -> Self {
+        Self {
+            changes: metrics.changes(),
+            metrics,
+            sent: HashMap::new(),
+            settings: (Duration::ZERO, Duration::ZERO),
+            comparisons_sent: 0,
+            rate_limits_sent: HashMap::new(),
+            warmth_sent: HashMap::new(),
+            health_sent: HashMap::new(),
+            quotas_sent: HashMap::new(),
+            auth_sent: HashMap::new(),
+        }
+    }
+
+    /// The snapshot a viewer receives on connect.
+    pub fn snapshot(&mut self) -> Message {
+        self.changes.mark_unchanged();
+        let records = self.metrics.retained();
+        self.sent = records
+            .iter()
+            .map(|r| (r.id, (r.output_tokens, r.duration)))
+            .collect();
+        self.settings = (self.metrics.retention(), self.metrics.window());
+        let (comparisons, total) = self.metrics.comparisons_since(0);
+        self.comparisons_sent = total;
+        let rate_limits = self.metrics.rate_limits();
+        self.rate_limits_sent = rate_limits
+            .iter()
+            .map(|limit| (limit.provider.clone(), limit.updated))
+            .collect();
+        let warmth = self.metrics.warmth();
+        self.warmth_sent = warmth
+            .iter()
+            .map(|warmth| (warmth.provider.clone(), warmth.checked))
+            .collect();
+        let health = self.metrics.health();
+        self.health_sent = health
+            .iter()
+            .map(|health| (health.provider.clone(), health.samples))
+            .collect();
+        let quotas = self.metrics.quotas();
+        self.quotas_sent = quotas
+            .iter()
+            .map(|usage| (usage.client.clone(), usage.clone()))
+            .collect();
+        let auth = self.metrics.auth();
+        self.auth_sent = auth
+            .iter()
+            .map(|status| (status.provider.clone(), status.checked))
+            .collect();
+        let mut billable: Vec<String> = self.metrics.billable().into_iter().collect();
+        billable.sort();
+        Message::Snapshot {
+            retention_secs: self.settings.0.as_secs(),
+            window_secs: self.settings.1.as_secs(),
+            records: records.iter().map(WireRecord::from_record).collect(),
+            comparisons,
+            rate_limits,
+            warmth,
+            health,
+            quotas,
+            auth,
+            billable,
+        }
+    }
+
+    /// Waits until the store changes and returns records that are new or
+    /// changed since they were last sent. Changes that land while a viewer
+    /// is busy are coalesced into one batch.
+    pub async fn next(&mut self) -> Vec<Message> {
+        if self.changes.changed().await.is_err() {
+            // The store owns the sender and we hold the store.
+            futures::future::pending::<()>().await;
+        }
+        self.changes.mark_unchanged();
+        let mut messages = Vec::new();
+        let settings = (self.metrics.retention(), self.metrics.window());
+        if settings != self.settings {
+            self.settings = settings;
+            messages.push(Message::Settings {
+                retention_secs: settings.0.as_secs(),
+                window_secs: settings.1.as_secs(),
+            });
+        }
+        let records = self.metrics.retained();
+        for record in &records {
+            let state = (record.output_tokens, record.duration);
+            if self.sent.get(&record.id) == Some(&state) {
+                continue;
+            }
+            self.sent.insert(record.id, state);
+            messages.push(Message::Record(WireRecord::from_record(record)));
+        }
+        if self.sent.len() > records.len() {
+            self.sent
+                .retain(|id, _| records.iter().any(|r| r.id == *id));
+        }
+        let (comparisons, total) = self.metrics.comparisons_since(self.comparisons_sent);
+        self.comparisons_sent = total;
+        messages.extend(comparisons.into_iter().map(Message::Comparison));
+        for limit in self.metrics.rate_limits() {
+            if self.rate_limits_sent.get(&limit.provider) != Some(&limit.updated) {
+                self.rate_limits_sent
+                    .insert(limit.provider.clone(), limit.updated);
+                messages.push(Message::RateLimit(limit));
+            }
+        }
+        for warmth in self.metrics.warmth() {
+            if self.warmth_sent.get(&warmth.provider) != Some(&warmth.checked) {
+                self.warmth_sent
+                    .insert(warmth.provider.clone(), warmth.checked);
+                messages.push(Message::Warmth(warmth));
+            }
+        }
+        for health in self.metrics.health() {
+            if self.health_sent.get(&health.provider) != Some(&health.samples) {
+                self.health_sent
+                    .insert(health.provider.clone(), health.samples);
+                messages.push(Message::Health(health));
+            }
+        }
+        for usage in self.metrics.quotas() {
+            if self.quotas_sent.get(&usage.client) != Some(&usage) {
+                self.quotas_sent.insert(usage.client.clone(), usage.clone());
+                messages.push(Message::Quota(usage));
+            }
+        }
+        for status in self.metrics.auth() {
+            if self.auth_sent.get(&status.provider) != Some(&status.checked) {
+                self.auth_sent
+                    .insert(status.provider.clone(), status.checked);
+                messages.push(Message::Auth(status));
+            }
+        }
+        messages
+    }
+}
+
+/// Serializes a message as one line of the stream.
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut line = serde_json::to_vec(message).expect("control message serializes");
+    line.push(b'\n');
+    line
+}
+
+/// Streams the snapshot and then every new or changed record to one viewer,
+/// answering any commands it sends, until the viewer disconnects. A viewer
+/// that sends [`Command::Events`] gets lifecycle events instead.
+pub async fn serve_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    metrics: Arc<MetricsStore>,
+    controller: Option<Arc<Controller>>,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    let mut feed = Feed::new(metrics.clone());
+    writer.write_all(&encode(&feed.snapshot())).await?;
+    writer.flush().await?;
+    loop {
+        let messages = tokio::select! {
+            messages = feed.next() => messages,
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                if serde_json::from_str(&line).ok() == Some(Command::Events) {
+                    return serve_events(writer, lines, metrics).await;
+                }
+                vec![Message::Reply(run_command(&line, controller.as_deref()))]
+            }
+        };
+        if messages.is_empty() {
+            continue;
+        }
+        for message in &messages {
+            writer.write_all(&encode(message)).await?;
+        }
+        writer.flush().await?;
+    }
+}
+
+/// Streams lifecycle events to a client that asked for them, until it
+/// disconnects. Events it falls too far behind to receive are skipped.
+async fn serve_events<R, W>(
+    mut writer: W,
+    mut lines: tokio::io::Lines<R>,
+    metrics: Arc<MetricsStore>,
+) -> io::Result<()>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut events = metrics.lifecycle();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    writer.write_all(&encode(&Message::Event(event))).await?;
+                    writer.flush().await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::debug!(missed, "events client fell behind");
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            line = lines.next_line() => {
+                if line?.is_none() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn run_command(line: &str, controller: Option<&Controller>) -> Reply {
+    let command: Command = match serde_json::from_str(line) {
+        Ok(command) => command,
+        Err(e) => return Reply::failed(format!("invalid command: {e}")),
+    };
+    match controller {
+        Some(controller) => controller.execute(&command, "control socket"),
+        None => Reply::failed("commands are not available on this instance"),
+    }
+}
+
+/// The control stream as an HTTP body, for viewers attaching over TCP
+/// through the admin API. Ends when `shutdown` flips so it doesn't hold up
+/// draining.
+pub fn body_stream(
+    metrics: Arc<MetricsStore>,
+    shutdown: watch::Receiver<bool>,
+    viewers: &Viewers,
+) -> impl futures::Stream<Item = Result<Bytes, Infallible>> + Send + use<> {
+    let mut feed = Feed::new(metrics);
+    let first = Bytes::from(encode(&feed.snapshot()));
+    // The guard lives in the stream state, so dropping the body when the
+    // viewer disconnects unregisters it.
+    let guard = viewers.join();
+    futures::stream::once(async move { Ok(first) })
+        .chain(futures::stream::unfold(
+            (feed, guard),
+            |(mut feed, guard)| async move {
+                loop {
+                    let messages = feed.next().await;
+                    if !messages.is_empty() {
+                        let chunk: Vec<u8> = messages.iter().flat_map(encode).collect();
+                        return Some((Ok(Bytes::from(chunk)), (feed, guard)));
+                    }
+                }
+            },
+        ))
+        .take_until(shutting_down(shutdown))
+}
+
+async fn shutting_down(mut shutdown: watch::Receiver<bool>) {
+    if shutdown.wait_for(|draining| *draining).await.is_err() {
+        // Sender gone without a shutdown: keep streaming.
+        futures::future::pending::<()>().await;
+    }
+}
+
+fn apply_settings(store: &MetricsStore, retention_secs: u64, window_secs: u64) {
+    store.set_retention(Duration::from_secs(retention_secs));
+    store.set_window(Duration::from_secs(window_secs));
+}
+
+/// Applies one line of the stream to `store`, ignoring lines it can't
+/// parse. Command replies are returned for the caller to show.
+pub fn apply_line(line: &str, store: &MetricsStore) -> Option<Reply> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    match serde_json::from_str(line) {
+        Ok(Message::Snapshot {
+            retention_secs,
+            window_secs,
+            records,
+            comparisons,
+            rate_limits,
+            warmth,
+            health,
+            quotas,
+            auth,
+            billable,
+        }) => {
+            apply_settings(store, retention_secs, window_secs);
+            for record in records {
+                store.upsert(record.into_record());
+            }
+            for comparison in comparisons {
+                store.record_comparison(comparison);
+            }
+            for limit in rate_limits {
+                store.record_rate_limit(limit);
+            }
+            for warmth in warmth {
+                store.record_warmth(warmth);
+            }
+            for health in health {
+                store.record_health(health);
+            }
+            for usage in quotas {
+                store.record_quota(usage);
+            }
+            for status in auth {
+                store.record_auth(status);
+            }
+            store.set_billable(billable.into_iter().collect());
+        }
+        Ok(Message::Settings {
+            retention_secs,
+            window_secs,
+        }) => apply_settings(store, retention_secs, window_secs),
+        Ok(Message::Record(record)) => store.upsert(record.into_record()),
+        Ok(Message::Comparison(comparison)) => store.record_comparison(comparison),
+        Ok(Message::RateLimit(limit)) => store.record_rate_limit(limit),
+        Ok(Message::Warmth(warmth)) => store.record_warmth(warmth),
+        Ok(Message::Health(health)) => store.record_health(health),
+        Ok(Message::Quota(usage)) => store.record_quota(usage),
+        Ok(Message::Auth(status)) => store.record_auth(status),
+        Ok(Message::Reply(reply)) => return Some(reply),
+        Ok(Message::Event(_)) | Err(_) => {}
+    }
+    None
+}
+
+/// Connects to a remote daemon's admin API, failing early on a bad
+/// address or token so the caller can report it before starting the TUI.
+pub async fn connect_remote(host: &str, token: Option<&str>) -> Result<reqwest::Response, String> {
+    let url = format!("http://{host}{PREFIX}/stream");
+    let mut request = crate::clients::default_client().get(&url);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("failed to connect to {host}: {e}"))?;
+    match response.status() {
+        reqwest::StatusCode::OK => Ok(response),
+        reqwest::StatusCode::UNAUTHORIZED => Err(format!(
+            "{host} requires an admin token (pass --token or set CROXY_ADMIN_TOKEN)"
+        )),
+        reqwest::StatusCode::NOT_FOUND => match response.text().await.unwrap_or_default() {
+            needs_token if needs_token == NEEDS_TOKEN => Err(format!(
+                "{host} only allows attaching with admin.token set in its config"
+            )),
+            _ => Err(format!(
+                "{host} does not serve the admin API (admin.enabled = false?)"
+            )),
+        },
+        status => Err(format!("{host} returned {status}")),
+    }
+}
+
+/// Fetches `GET /_croxy/status` from the daemon at `host`, scoped to the
+/// virtual key named `tenant` if given.
+pub async fn remote_status(
+    host: &str,
+    token: Option<&str>,
+    tenant: Option<&str>,
+) -> Result<Status, String> {
+    let url = format!("http://{host}{PREFIX}/status");
+    let mut request = crate::clients::default_client().get(&url);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(tenant) = tenant {
+        request = request.query(&[("tenant", tenant)]);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("failed to connect to {host}: {e}"))?;
+    match response.status() {
+        reqwest::StatusCode::OK => response
+            .json()
+            .await
+            .map_err(|e| format!("invalid status from {host}: {e}")),
+        reqwest::StatusCode::UNAUTHORIZED => Err(format!(
+            "{host} requires an admin token (set admin.token or CROXY_ADMIN_TOKEN) or a virtual key"
+        )),
+        reqwest::StatusCode::NOT_FOUND => match response.text().await.unwrap_or_default() {
+            unknown if unknown.starts_with("no virtual key") => Err(unknown),
+            _ => Err(format!(
+                "{host} does not serve the admin API (admin.enabled = false?)"
+            )),
+        },
+        status => Err(format!("{host} returned {status}")),
+    }
+}
+
+/// Applies a remote control stream to `store` until the connection ends.
+pub async fn follow_remote(mut response: reqwest::Response, store: &MetricsStore) {
+    let mut buf = Vec::new();
+    while let Ok(Some(chunk)) = response.chunk().await {
+        buf.extend_from_slice(&chunk);
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            apply_line(&String::from_utf8_lossy(&line), store);
+        }
+    }
+}
+
+/// Commands for a remote daemon, sent as `POST /_croxy/command`.
+pub fn remote_commands(host: &str, token: Option<String>) -> CommandChannel {
+    let (tx, rx) = mpsc::channel();
+    let runtime = tokio::runtime::Handle::current();
+    let client = crate::clients::default_client();
+    let url = format!("http://{host}{PREFIX}/command");
+    let send = move |command: Command| {
+        let mut request = client.post(&url).json(&command);
+        if let Some(ref token) = token {
+            request = request.bearer_auth(token);
+        }
+        let tx = tx.clone();
+        runtime.spawn(async move {
+            let reply = match request.send().await {
+                Ok(response) => response
+                    .json::<Reply>()
+                    .await
+                    .unwrap_or_else(|e| Reply::failed(format!("invalid reply: {e}"))),
+                Err(e) => Reply::failed(format!("failed to send command: {e}")),
+            };
+            let _ = tx.send(reply);
+        });
+    };
+    CommandChannel::new(Box::new(send), rx)
+}
+
+/// Applies control messages from `reader` to `store` until the daemon
+/// closes the connection, passing command replies to `on_reply`.
+pub fn follow<R: BufRead>(
+    reader: R,
+    store: &MetricsStore,
+    mut on_reply: impl FnMut(Reply),
+) -> io::Result<()> {
+    for line in reader.lines() {
+        if let Some(reply) = apply_line(&line?, store) {
+            on_reply(reply);
+        }
+    }
+    Ok(())
+}
+
+/// Follows a local daemon's control socket on a background thread,
+/// returning a channel for commands over the same connection.
+pub fn attach_local(
+    stream: std::os::unix::net::UnixStream,
+    store: Arc<MetricsStore>,
+) -> io::Result<CommandChannel> {
+    let writer = std::sync::Mutex::new(stream.try_clone()?);
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let reader = io::BufReader::new(stream);
+        if let Err(e) = follow(reader, &store, |reply| {
+            let _ = tx.send(reply);
+        }) {
+            tracing::debug!("control connection lost: {e}");
+        }
+    });
+    let send = move |command: Command| {
+        let line = encode_command(&command);
+        if let Ok(mut writer) = writer.lock() {
+            let _ = io::Write::write_all(&mut *writer, &line);
+        }
+    };
+    Ok(CommandChannel::new(Box::new(send), rx))
+}
+
+/// The records in the snapshot a daemon sends on connect, and the
+/// providers whose requests it prices.
+pub fn snapshot_records(reader: impl BufRead) -> io::Result<(Vec<RequestRecord>, HashSet<String>)> {
+    let Some(line) = reader.lines().next().transpose()? else {
+        return Ok((Vec::new(), HashSet::new()));
+    };
+    let Ok(Message::Snapshot {
+        records, billable, ..
+    }) = serde_json::from_str(&line)
+    else {
+        return Err(io::Error::other("expected a snapshot from the daemon"));
+    };
+    let records = records.into_iter().map(WireRecord::into_record).collect();
+    Ok((records, billable.into_iter().collect()))
+}
+
+/// The completed events of the finished requests in the snapshot a daemon
+/// sends on connect, oldest first.
+pub fn completed_events(reader: impl BufRead) -> io::Result<Vec<RequestEvent>> {
+    let (mut records, _) = snapshot_records(reader)?;
+    records.retain(|record| record.request_id.is_some() && !record.duration.is_zero());
+    records.sort_by_key(|record| record.wallclock + record.duration);
+    Ok(records.iter().map(RequestEvent::completed).collect())
+}
+
+/// Asks the daemon on `stream` for lifecycle events and hands each to
+/// `each` until the connection ends or `each` fails.
+pub fn follow_events(
+    stream: std::os::unix::net::UnixStream,
+    mut each: impl FnMut(RequestEvent) -> io::Result<()>,
+) -> io::Result<()> {
+    io::Write::write_all(&mut &stream, &encode_command(&Command::Events))?;
+    for line in io::BufReader::new(&stream).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(Message::Event(event)) => each(event)?,
+            // Daemons without events answer the command as one they don't know
+            Ok(Message::Reply(reply)) if !reply.ok => return Err(io::Error::other(reply.message)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Sends `command` to the daemon on `stream` and waits for its reply.
+pub fn send_command(
+    stream: std::os::unix::net::UnixStream,
+    command: &Command,
+) -> io::Result<Reply> {
+    io::Write::write_all(&mut &stream, &encode_command(command))?;
+    for line in io::BufReader::new(&stream).lines() {
+        // The snapshot and any records come first
+        if let Ok(Message::Reply(reply)) = serde_json::from_str(&line?) {
+            return Ok(reply);
+        }
+    }
+    Err(io::Error::other(
+        "the daemon closed the connection without replying",
+    ))
+}
+
+fn encode_command(command: &Command) -> Vec<u8> {
+    let mut line = serde_json::to_vec(command).expect("command serializes");
+    line.push(b'\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn wire_record_round_trips() {
+        let mut record = RequestRecord::sample();
+        record.id = 3;
+        let wire = WireRecord::from_record(&record);
+        let line = serde_json::to_string(&Message::Record(wire.clone())).unwrap();
+        assert!(line.contains(r#""type":"record""#), "{line}");
+        let Message::Record(parsed) = serde_json::from_str(&line).unwrap() else {
+            panic!("expected a record message");
+        };
+        let back = parsed.into_record();
+        assert_eq!(back.id, 3);
+        assert_eq!(back.routing_method, RoutingMethod::Pattern);
+        assert_eq!(back.duration, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn follow_applies_snapshot_then_updates() {
+        let mut pending = RequestRecord::sample();
+        pending.id = 1;
+        let mut done = pending.clone();
+        done.output_tokens = 900;
+
+        let lines = [
+            Message::Snapshot {
+                retention_secs: 7200,
+                window_secs: 600,
+                records: vec![WireRecord::from_record(&pending)],
+                comparisons: Vec::new(),
+                rate_limits: Vec::new(),
+                warmth: Vec::new(),
+                health: Vec::new(),
+                quotas: Vec::new(),
+                auth: Vec::new(),
+                billable: Vec::new(),
+            },
+            Message::Record(WireRecord::from_record(&done)),
+        ]
+        .iter()
+        .map(|m| serde_json::to_string(m).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+        let store = MetricsStore::new(Duration::from_secs(60));
+        follow(BufReader::new(lines.as_bytes()), &store, |_| {}).unwrap();
+        let snap = store.snapshot();
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap[0].output_tokens, 900);
+        assert_eq!(store.retention(), Duration::from_secs(7200));
+        assert_eq!(store.window(), Duration::from_secs(600));
+    }
+
+    #[tokio::test]
+    async fn connection_sends_snapshot_and_new_records() {
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        metrics.record(RequestRecord::sample());
+
+        let (client, server) = tokio::net::UnixStream::pair().unwrap();
+        let task = tokio::spawn(serve_connection(server, metrics.clone(), None));
+        metrics.record(RequestRecord::sample());
+
+        let client = client.into_std().unwrap();
+        client.set_nonblocking(false).unwrap();
+        let store = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        let mirror = store.clone();
+        let reader =
+            tokio::task::spawn_blocking(move || follow(BufReader::new(client), &mirror, |_| {}));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        task.abort();
+        let _ = task.await;
+        reader.await.unwrap().unwrap();
+        assert_eq!(store.snapshot().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn feed_pushes_pending_requests_and_finalization() {
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        let mut feed = Feed::new(metrics.clone());
+        feed.snapshot();
+
+        let id = metrics.record_pending(RequestRecord::sample());
+        let messages = tokio::time::timeout(Duration::from_millis(100), feed.next())
+            .await
+            .expect("pending request pushed without polling");
+        assert!(matches!(&messages[..], [Message::Record(r)] if r.id == id));
+
+        metrics.finalize_stream(id, 4321, Duration::from_secs(2));
+        let messages = tokio::time::timeout(Duration::from_millis(100), feed.next())
+            .await
+            .expect("finalization pushed");
+        assert!(matches!(
+            &messages[..],
+            [Message::Record(r), Message::Health(h)] if r.output_tokens == 4321 && h.samples == 1
+        ));
+    }
+
+    #[test]
+    fn viewers_are_counted_until_guard_drops() {
+        let viewers = Viewers::default();
+        let first = viewers.join();
+        let second = viewers.clone().join();
+        assert_eq!(viewers.count(), 2);
+        drop(first);
+        assert_eq!(viewers.count(), 1);
+        drop(second);
+        assert_eq!(viewers.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshot_includes_records_outside_the_window() {
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(3600)));
+        let mut old = RequestRecord::sample();
+        old.timestamp = Instant::now() - Duration::from_secs(1200);
+        metrics.record(old);
+        metrics.set_window(Duration::from_secs(600));
+
+        let mut feed = Feed::new(metrics.clone());
+        let Message::Snapshot {
+            retention_secs,
+            window_secs,
+            records,
+            ..
+        } = feed.snapshot()
+        else {
+            panic!("expected a snapshot");
+        };
+        assert_eq!((retention_secs, window_secs), (3600, 600));
+        assert_eq!(records.len(), 1);
+
+        metrics.set_window(Duration::from_secs(1800));
+        let messages = tokio::time::timeout(Duration::from_millis(100), feed.next())
+            .await
+            .expect("settings change pushed");
+        assert_eq!(
+            messages,
+            vec![Message::Settings {
+                retention_secs: 3600,
+                window_secs: 1800,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_asking_for_events_get_them_instead_of_records() {
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        let (client, server) = tokio::net::UnixStream::pair().unwrap();
+        let task = tokio::spawn(serve_connection(server, metrics.clone(), None));
+
+        let client = client.into_std().unwrap();
+        client.set_nonblocking(false).unwrap();
+        let reader = tokio::task::spawn_blocking(move || {
+            let mut events = Vec::new();
+            let result = follow_events(client, |event| {
+                events.push(event);
+                Err(io::Error::other("got one"))
+            });
+            (result, events)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        metrics.record(RequestRecord {
+            request_id: Some("abc-1".to_string()),
+            ..RequestRecord::sample()
+        });
+
+        let (result, events) = reader.await.unwrap();
+        task.abort();
+        assert_eq!(result.unwrap_err().to_string(), "got one");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].stage, crate::events::Stage::Completed);
+        assert_eq!(events[0].request_id, "abc-1");
+    }
+
+    #[test]
+    fn completed_events_come_from_finished_requests_in_the_snapshot() {
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        let request = |id: &str, duration| RequestRecord {
+            request_id: Some(id.to_string()),
+            duration,
+            ..RequestRecord::sample()
+        };
+        metrics.record(request("abc-1", Duration::from_millis(900)));
+        metrics.record_pending(request("abc-2", Duration::ZERO));
+        metrics.record(RequestRecord::sample());
+
+        let snapshot = encode(&Feed::new(metrics).snapshot());
+        let events = completed_events(&snapshot[..]).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].request_id, "abc-1");
+        assert_eq!(events[0].elapsed_ms, Some(900));
+        assert!(completed_events(&b"{}\n"[..]).is_err());
+    }
+
+    #[test]
+    fn snapshot_records_carry_the_billable_providers() {
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        metrics.set_billable(HashSet::from(["anthropic".to_string()]));
+        metrics.record(RequestRecord::sample());
+
+        let snapshot = encode(&Feed::new(metrics).snapshot());
+        let (records, billable) = snapshot_records(&snapshot[..]).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].model, RequestRecord::sample().model);
+        assert_eq!(billable, HashSet::from(["anthropic".to_string()]));
+        assert!(snapshot_records(&b""[..]).unwrap().0.is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+//! Crash bundles. When any croxy thread panics -- a request handler, the
+//! TUI, the proxy itself -- a directory is written under
+//! `logging.crash.path` holding the panic and its backtrace, the metrics
+//! snapshot an attached viewer would receive, the streams still open, and
+//! the last lines of the application log. A daemon that dies overnight
+//! leaves more behind than an empty `croxy.log`.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, mpsc};
+use std::time::Duration;
+
+use chrono::Utc;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::control::{Feed, WireRecord};
+use crate::metrics::MetricsStore;
+
+/// How long the panic hook waits for a bundle before giving up. A panic
+/// while the metrics lock is held would otherwise hang the process.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set while a bundle is being written, so a panic while writing one
+/// doesn't try to write another.
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+/// The most recent lines of the application log.
+pub struct LogTail {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl LogTail {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, text: &str) {
+        let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        lines.iter().cloned().collect()
+    }
+}
+
+/// A tracing writer that copies each event into a `LogTail` on its way to
+/// `inner`.
+pub struct TailWriter<M> {
+    inner: M,
+    tail: Arc<LogTail>,
+}
+
+impl<M> TailWriter<M> {
+    pub fn new(inner: M, tail: Arc<LogTail>) -> Self {
+        Self { inner, tail }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for TailWriter<M> {
+    type Writer = Tailing<'a, M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Tailing {
+            writer: self.inner.make_writer(),
+            tail: &self.tail,
+        }
+    }
+}
+
+pub struct Tailing<'a, W> {
+    writer: W,
+    tail: &'a LogTail,
+}
+
+impl<W: Write> Write for Tailing<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tail.push(&String::from_utf8_lossy(buf));
+        self.writer.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes crash bundles for one process.
+pub struct CrashReporter {
+    dir: PathBuf,
+    metrics: Arc<MetricsStore>,
+    tail: Arc<LogTail>,
+}
+
+impl CrashReporter {
+    pub fn new(dir: impl Into<PathBuf>, metrics: Arc<MetricsStore>, tail: Arc<LogTail>) -> Self {
+        Self {
+            dir: dir.into(),
+            metrics,
+            tail,
+        }
+    }
+
+    /// Writes a bundle for `panic` into a new timestamped directory and
+    /// returns its path.
+    pub fn write_bundle(&self, panic: &str) -> io::Result<PathBuf> {
+        let now = Utc::now();
+        let bundle = self.dir.join(format!(
+            "{}-{}",
+            now.format("%Y%m%dT%H%M%S%.3fZ"),
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&bundle)?;
+
+        std::fs::write(bundle.join("panic.txt"), panic)?;
+
+        let snapshot = Feed::new(self.metrics.clone()).snapshot();
+        write_json(&bundle.join("snapshot.json"), &snapshot)?;
+
+        // Streams are recorded when they start and given a duration when
+        // they finish, so those without one were still open.
+        let active: Vec<WireRecord> = self
+            .metrics
+            .retained()
+            .iter()
+            .filter(|record| record.duration.is_zero())
+            .map(WireRecord::from_record)
+            .collect();
+        write_json(&bundle.join("active.json"), &active)?;
+
+        let mut log = self.tail.lines().join("\n");
+        log.push('\n');
+        std::fs::write(bundle.join("log.txt"), log)?;
+
+        Ok(bundle)
+    }
+
+    /// Writes a bundle whenever a thread panics, then runs the hook that
+    /// was installed before.
+    pub fn install(self) {
+        let reporter = Arc::new(self);
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+            if WRITING.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            match reporter.write_in_background(describe(info)) {
+                Some(Ok(path)) => eprintln!("crash bundle written to {}", path.display()),
+                Some(Err(e)) => eprintln!("failed to write crash bundle: {e}"),
+                None => eprintln!("gave up writing crash bundle"),
+            }
+            WRITING.store(false, Ordering::SeqCst);
+        }));
+    }
+
+    /// Writes the bundle on its own thread, so a lock held by the
+    /// panicking thread can only cost `WRITE_TIMEOUT`.
+    fn write_in_background(self: &Arc<Self>, panic: String) -> Option<io::Result<PathBuf>> {
+        let (tx, rx) = mpsc::channel();
+        let reporter = self.clone();
+        std::thread::Builder::new()
+            .name("croxy-crash".to_string())
+            .spawn(move || {
+                let _ = tx.send(reporter.write_bundle(&panic));
+            })
+            .ok()?;
+        rx.recv_timeout(WRITE_TIMEOUT).ok()
+    }
+}
+
+fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(value).map_err(io::Error::other)?;
+    std::fs::write(path, json)
+}
+
+/// The panic message, where it happened, and a backtrace.
+fn describe(info: &PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("(no message)");
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+        .unwrap_or_else(|| "unknown".to_string());
+    let thread = std::thread::current();
+    format!(
+        "croxy {} panicked at {}\nthread: {}\nlocation: {location}\nmessage: {message}\n\n{}\n",
+        env!("CARGO_PKG_VERSION"),
+        Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+        thread.name().unwrap_or("<unnamed>"),
+        std::backtrace::Backtrace::force_capture(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::RequestRecord;
+
+    fn record(duration: Duration) -> RequestRecord {
+        RequestRecord {
+            duration,
+            ..RequestRecord::sample()
+        }
+    }
+
+    #[test]
+    fn tail_keeps_the_latest_lines() {
+        let tail = Arc::new(LogTail::new(3));
+        let writer = TailWriter::new(Mutex::new(Vec::new()), tail.clone());
+        for i in 0..5 {
+            writer
+                .make_writer()
+                .write_all(format!("line {i}\n").as_bytes())
+                .unwrap();
+        }
+        assert_eq!(tail.lines(), ["line 2", "line 3", "line 4"]);
+        assert_eq!(
+            writer.inner.into_inner().unwrap().len(),
+            "line 0\n".len() * 5
+        );
+    }
+
+    #[test]
+    fn bundle_holds_panic_snapshot_active_streams_and_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(MetricsStore::new(Duration::from_secs(3600)));
+        metrics.record(record(Duration::from_millis(500)));
+        let open = metrics.record_pending(record(Duration::ZERO));
+        let tail = Arc::new(LogTail::new(100));
+        tail.push("INFO croxy: listening\n");
+
+        let reporter = CrashReporter::new(dir.path(), metrics, tail);
+        let bundle = reporter.write_bundle("message: boom\n").unwrap();
+
+        assert!(bundle.starts_with(dir.path()));
+        assert_eq!(
+            std::fs::read_to_string(bundle.join("panic.txt")).unwrap(),
+            "message: boom\n"
+        );
+        let snapshot: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(bundle.join("snapshot.json")).unwrap()).unwrap();
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(snapshot["records"].as_array().unwrap().len(), 2);
+        let active: Vec<WireRecord> =
+            serde_json::from_slice(&std::fs::read(bundle.join("active.json")).unwrap()).unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, open);
+        assert_eq!(
+            std::fs::read_to_string(bundle.join("log.txt")).unwrap(),
+            "INFO croxy: listening\n"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Collapses identical requests while the first is still being served.
+//! Clients retry when a response is slow, and each retry would otherwise
+//! be sent, and billed, again. With `dedup = "attach"` a retry gets the
+//! first request's response, replayed from its start; with
+//! `dedup = "reject"` it gets a 409.
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use axum::body::Body;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::Response;
+use bytes::Bytes;
+use futures::StreamExt;
+use tokio::sync::watch;
+
+/// A response as far as it has arrived.
+#[derive(Default)]
+struct Progress {
+    head: Option<(StatusCode, HeaderMap)>,
+    chunks: Vec<Bytes>,
+    done: bool,
+}
+
+/// Requests being served on routes that de-duplicate, by [`key`].
+#[derive(Default)]
+pub struct InFlight {
+    requests: Mutex<HashMap<u64, watch::Receiver<Progress>>>,
+}
+
+pub enum Joined {
+    /// Nothing identical is in flight, so the request goes ahead.
+    First(Leader),
+    /// An identical request is already in flight.
+    Duplicate(Follower),
+}
+
+impl InFlight {
+    pub fn join(self: &Arc<Self>, key: u64) -> Joined {
+        let mut requests = self.requests.lock().expect("in-flight lock poisoned");
+        if let Some(progress) = requests.get(&key) {
+            return Joined::Duplicate(Follower(progress.clone()));
+        }
+        let (progress, receiver) = watch::channel(Progress::default());
+        requests.insert(key, receiver);
+        Joined::First(Leader {
+            key,
+            progress,
+            in_flight: self.clone(),
+        })
+    }
+}
+
+/// Identifies a request by the provider and path it's going to, the
+/// credentials it carries, and its body, so requests from different
+/// clients are never mistaken for each other.
+pub fn key(provider: &str, path: &str, headers: &HeaderMap, body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    provider.hash(&mut hasher);
+    path.hash(&mut hasher);
+    for name in ["authorization", "x-api-key"] {
+        headers.get(name).map(|v| v.as_bytes()).hash(&mut hasher);
+    }
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The first of a set of identical requests. Duplicates can attach until
+/// its response has been sent in full, or it's dropped.
+pub struct Leader {
+    key: u64,
+    progress: watch::Sender<Progress>,
+    in_flight: Arc<InFlight>,
+}
+
+impl Leader {
+    /// Passes `response` on, keeping a copy for any duplicates.
+    pub fn share(self, response: Response) -> Response {
+        let (parts, body) = response.into_parts();
+        let head = (parts.status, parts.headers.clone());
+        self.progress.send_modify(|p| p.head = Some(head));
+        let body = futures::stream::unfold(
+            (body.into_data_stream(), self),
+            |(mut body, leader)| async move {
+                let chunk = body.next().await;
+                leader.progress.send_modify(|p| match chunk {
+                    Some(Ok(ref bytes)) => p.chunks.push(bytes.clone()),
+                    Some(Err(_)) => {}
+                    None => p.done = true,
+                });
+                Some((chunk?, (body, leader)))
+            },
+        );
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.in_flight
+            .requests
+            .lock()
+            .expect("in-flight lock poisoned")
+            .remove(&self.key);
+    }
+}
+
+/// A request identical to one already in flight.
+pub struct Follower(watch::Receiver<Progress>);
+
+impl Follower {
+    /// The first request's response, from its start, or `None` if that
+    /// request failed before it had one. Should the first request end
+    /// before its response does, so does this one, with an error.
+    pub async fn response(mut self) -> Option<Response> {
+        let (status, headers) = loop {
+            if let Some(ref head) = self.0.borrow_and_update().head {
+                break head.clone();
+            }
+            self.0.changed().await.ok()?;
+        };
+        let body = futures::stream::unfold(Some((self.0, 0)), |state| async move {
+            let (mut progress, sent) = state?;
+            loop {
+                let (next, done) = {
+                    let p = progress.borrow_and_update();
+                    (p.chunks.get(sent).cloned(), p.done)
+                };
+                if let Some(chunk) = next {
+                    return Some((Ok(chunk), Some((progress, sent + 1))));
+                }
+                if done {
+                    return None;
+                }
+                if progress.changed().await.is_err() {
+                    let ended = std::io::Error::other("the original request ended early");
+                    return Some((Err(ended), None));
+                }
+            }
+        });
+        let mut response = Response::new(Body::from_stream(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &'static str) -> Response {
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = StatusCode::ACCEPTED;
+        response
+    }
+
+    async fn text(response: Response) -> Result<String, axum::Error> {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        Ok(String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn duplicates_get_the_first_response() {
+        let in_flight = Arc::new(InFlight::default());
+        let Joined::First(leader) = in_flight.join(1) else {
+            panic!("nothing was in flight");
+        };
+        let Joined::Duplicate(follower) = in_flight.join(1) else {
+            panic!("the first request was in flight");
+        };
+        assert!(matches!(in_flight.join(2), Joined::First(_)));
+
+        let shared = leader.share(response("hello"));
+        let replayed = tokio::spawn(follower.response());
+        assert_eq!(text(shared).await.unwrap(), "hello");
+        let replayed = replayed.await.unwrap().unwrap();
+        assert_eq!(replayed.status(), StatusCode::ACCEPTED);
+        assert_eq!(text(replayed).await.unwrap(), "hello");
+        assert!(in_flight.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicates_of_a_failed_request_get_nothing() {
+        let in_flight = Arc::new(InFlight::default());
+        let Joined::First(leader) = in_flight.join(1) else {
+            panic!("nothing was in flight");
+        };
+        let Joined::Duplicate(follower) = in_flight.join(1) else {
+            panic!("the first request was in flight");
+        };
+        drop(leader);
+        assert!(follower.response().await.is_none());
+        assert!(matches!(in_flight.join(1), Joined::First(_)));
+    }
+
+    #[test]
+    fn keys_differ_by_credentials() {
+        let mut headers = HeaderMap::new();
+        let anonymous = key("anthropic", "/v1/messages", &headers, b"{}");
+        headers.insert("x-api-key", "sk-1".parse().unwrap());
+        let first = key("anthropic", "/v1/messages", &headers, b"{}");
+        assert_ne!(anonymous, first);
+        assert_eq!(first, key("anthropic", "/v1/messages", &headers, b"{}"));
+        assert_ne!(first, key("anthropic", "/v1/messages", &headers, b"{ }"));
+    }
+}
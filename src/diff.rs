@@ -0,0 +1,262 @@
+//! `croxy diff`: two windows of the metrics log side by side, per model or
+//! provider, so the effect of a routing change shows as numbers: how
+//! latency, error rate, and tokens per request moved from a baseline
+//! window to a later one.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::history::Entry;
+use crate::metrics::MetricsStore;
+
+/// What `croxy diff` groups requests by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    Model,
+    Provider,
+}
+
+impl GroupBy {
+    pub fn name(self) -> &'static str {
+        match self {
+            GroupBy::Model => "model",
+            GroupBy::Provider => "provider",
+        }
+    }
+
+    fn key(self, entry: &Entry) -> &str {
+        match self {
+            GroupBy::Model => &entry.model,
+            GroupBy::Provider => &entry.provider,
+        }
+    }
+}
+
+/// A span of time, from `from` up to `to`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Window {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl Window {
+    /// The `length` ending `ago` before now.
+    pub fn ending(ago: chrono::Duration, length: chrono::Duration) -> Self {
+        let to = Utc::now() - ago;
+        Self {
+            from: to - length,
+            to,
+        }
+    }
+
+    /// The same span, `by` earlier.
+    pub fn shifted(self, by: chrono::Duration) -> Self {
+        Self {
+            from: self.from - by,
+            to: self.to - by,
+        }
+    }
+
+    fn contains(&self, at: DateTime<Utc>) -> bool {
+        self.from <= at && at < self.to
+    }
+}
+
+/// The requests of one group in one window.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct WindowStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl WindowStats {
+    fn from_entries(entries: &[&Entry]) -> Self {
+        let durations: Vec<Duration> = entries
+            .iter()
+            .map(|e| Duration::from_millis(e.duration_ms))
+            .collect();
+        let percentile = |p| MetricsStore::duration_percentile(&durations, p).as_millis() as u64;
+        Self {
+            requests: entries.len() as u64,
+            errors: entries.iter().filter(|e| e.status >= 400).count() as u64,
+            p50_ms: percentile(50),
+            p95_ms: percentile(95),
+            input_tokens: entries.iter().map(|e| e.input_tokens).sum(),
+            output_tokens: entries.iter().map(|e| e.output_tokens).sum(),
+        }
+    }
+
+    /// Percentage of requests that failed.
+    pub fn error_rate(&self) -> f64 {
+        self.errors as f64 * 100.0 / self.requests.max(1) as f64
+    }
+
+    pub fn tokens_per_request(&self) -> u64 {
+        (self.input_tokens + self.output_tokens) / self.requests.max(1)
+    }
+}
+
+/// One group's requests in both windows.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupDiff {
+    pub name: String,
+    pub baseline: WindowStats,
+    pub current: WindowStats,
+}
+
+impl GroupDiff {
+    /// The requests, p50, p95, error rate, and tokens per request columns:
+    /// each the baseline's value, the current one, and the change.
+    pub fn columns(&self) -> [String; 5] {
+        let (before, after) = (&self.baseline, &self.current);
+        let ms = |ms: u64| crate::tui::views::format_duration(Duration::from_millis(ms));
+        let seen = before.requests > 0 && after.requests > 0;
+        let measure = |before: u64, after: u64, show: &dyn Fn(u64) -> String| {
+            let change = if seen {
+                percent_change(before as f64, after as f64)
+            } else {
+                String::new()
+            };
+            format!("{} -> {} {change}", show(before), show(after))
+                .trim_end()
+                .to_string()
+        };
+        let error_change = if seen {
+            format!("{:+.1}pp", after.error_rate() - before.error_rate())
+        } else {
+            String::new()
+        };
+        [
+            format!("{} -> {}", before.requests, after.requests),
+            measure(before.p50_ms, after.p50_ms, &ms),
+            measure(before.p95_ms, after.p95_ms, &ms),
+            format!(
+                "{:.1}% -> {:.1}% {error_change}",
+                before.error_rate(),
+                after.error_rate()
+            )
+            .trim_end()
+            .to_string(),
+            measure(
+                before.tokens_per_request(),
+                after.tokens_per_request(),
+                &crate::tui::views::format_tokens,
+            ),
+        ]
+    }
+}
+
+/// `after` as a signed percentage change from `before`.
+fn percent_change(before: f64, after: f64) -> String {
+    if before == 0.0 {
+        return if after == 0.0 { "+0%" } else { "new" }.to_string();
+    }
+    format!("{:+.0}%", (after - before) * 100.0 / before)
+}
+
+/// The entries in each window, grouped `by`, busiest group first. Faults
+/// `[chaos]` injected and requests `croxy bench` sent are left out.
+pub fn diff(entries: &[Entry], by: GroupBy, baseline: Window, current: Window) -> Vec<GroupDiff> {
+    let mut groups: BTreeMap<&str, (Vec<&Entry>, Vec<&Entry>)> = BTreeMap::new();
+    for entry in entries
+        .iter()
+        .filter(|e| e.chaos.is_none() && e.bench.is_none())
+    {
+        let in_baseline = baseline.contains(entry.timestamp);
+        if !in_baseline && !current.contains(entry.timestamp) {
+            continue;
+        }
+        let (before, after) = groups.entry(by.key(entry)).or_default();
+        if in_baseline {
+            before.push(entry);
+        } else {
+            after.push(entry);
+        }
+    }
+    let mut diffs: Vec<GroupDiff> = groups
+        .into_iter()
+        .map(|(name, (before, after))| GroupDiff {
+            name: name.to_string(),
+            baseline: WindowStats::from_entries(&before),
+            current: WindowStats::from_entries(&after),
+        })
+        .collect();
+    diffs.sort_by_key(|d| std::cmp::Reverse(d.baseline.requests + d.current.requests));
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(minutes_ago: i64, model: &str, status: u16, duration_ms: u64) -> Entry {
+        Entry {
+            timestamp: Utc::now() - chrono::Duration::minutes(minutes_ago),
+            request_id: None,
+            model: model.to_string(),
+            provider: "anthropic".to_string(),
+            routing_method: None,
+            status,
+            duration_ms,
+            input_tokens: 1000,
+            output_tokens: 200,
+            error: None,
+            batch_size: None,
+            redactions: 0,
+            listener: None,
+            client: None,
+            cutoff: None,
+            chaos: None,
+            bench: None,
+            client_aborted: None,
+            near_limit: false,
+            timings: Default::default(),
+        }
+    }
+
+    #[test]
+    fn windows_are_compared_per_group() {
+        let entries = vec![
+            // The hour before last
+            entry(100, "opus", 200, 2000),
+            entry(90, "opus", 200, 2000),
+            entry(80, "opus", 500, 4000),
+            entry(70, "opus", 200, 2000),
+            // The last hour
+            entry(50, "opus", 200, 1000),
+            entry(40, "opus", 200, 1000),
+            entry(30, "haiku", 200, 300),
+            // Neither
+            entry(200, "opus", 500, 9000),
+        ];
+        let hour = chrono::Duration::hours(1);
+        let current = Window::ending(chrono::Duration::zero(), hour);
+        let diffs = diff(&entries, GroupBy::Model, current.shifted(hour), current);
+
+        assert_eq!(diffs.len(), 2);
+        let opus = &diffs[0];
+        assert_eq!(opus.name, "opus");
+        assert_eq!(opus.baseline.requests, 4);
+        assert_eq!(opus.baseline.errors, 1);
+        assert_eq!(opus.current.requests, 2);
+        assert_eq!(
+            opus.columns(),
+            [
+                "4 -> 2",
+                "2.00s -> 1.00s -50%",
+                "4.00s -> 1.00s -75%",
+                "25.0% -> 0.0% -25.0pp",
+                "1.2K -> 1.2K +0%",
+            ]
+        );
+        assert_eq!(diffs[1].columns()[0], "0 -> 1");
+        assert_eq!(diffs[1].columns()[1], "0ms -> 300ms");
+    }
+}
@@ -0,0 +1,167 @@
+//! Errors library callers can match on. Each carries the message the
+//! binary prints, or the proxy sends back to the client.
+
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CroxyError {
+    /// The config can't be read or doesn't hold together.
+    #[error("{0}")]
+    Config(String),
+    /// A route or provider asked for by name or index doesn't exist.
+    #[error("{0}")]
+    Routing(String),
+    /// The client's request can't be proxied as sent.
+    #[error("{0}")]
+    Request(String),
+    /// A provider couldn't be reached, or its response couldn't be used.
+    #[error("{0}")]
+    Upstream(String),
+    /// The request ran past `server.request_timeout_ms`.
+    #[error("{0}")]
+    Timeout(String),
+    /// Something croxy should have been able to do failed.
+    #[error("{0}")]
+    Internal(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl CroxyError {
+    /// The HTTP status a proxied request failing this way is answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Request(_) => StatusCode::BAD_REQUEST,
+            Self::Routing(_) => StatusCode::NOT_FOUND,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Config(_) | Self::Internal(_) | Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl CroxyError {
+    /// The status the `croxy` binary exits with when it fails this way.
+    pub fn exit_status(&self) -> ExitStatus {
+        match self {
+            Self::Config(_) => ExitStatus::Config,
+            Self::Upstream(_) | Self::Timeout(_) => ExitStatus::Provider,
+            Self::Routing(_) | Self::Request(_) | Self::Internal(_) | Self::Io(_) => {
+                ExitStatus::Failure
+            }
+        }
+    }
+}
+
+/// What the `croxy` binary's exit status means, so scripts can branch on
+/// it. 2 is left to clap for command-line usage errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Failure,
+    Config,
+    AlreadyRunning,
+    NotRunning,
+    Bind,
+    Provider,
+}
+
+impl ExitStatus {
+    pub const ALL: [ExitStatus; 6] = [
+        ExitStatus::Failure,
+        ExitStatus::Config,
+        ExitStatus::AlreadyRunning,
+        ExitStatus::NotRunning,
+        ExitStatus::Bind,
+        ExitStatus::Provider,
+    ];
+
+    pub fn code(self) -> i32 {
+        match self {
+            Self::Failure => 1,
+            Self::Config => 3,
+            Self::AlreadyRunning => 4,
+            Self::NotRunning => 5,
+            Self::Bind => 6,
+            Self::Provider => 7,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Failure => "any other failure",
+            Self::Config => "the config is missing, invalid, or lacks a needed setting",
+            Self::AlreadyRunning => "croxy is already running",
+            Self::NotRunning => "croxy is not running",
+            Self::Bind => "a listener could not be bound",
+            Self::Provider => "a provider could not be reached or rejected the request",
+        }
+    }
+
+    pub fn exit(self) -> ! {
+        std::process::exit(self.code())
+    }
+
+    /// Prints `error: {message}` to stderr and exits with this status.
+    pub fn fail(self, message: impl std::fmt::Display) -> ! {
+        eprintln!("error: {message}");
+        self.exit()
+    }
+
+    /// The exit statuses, as listed in `croxy --help`.
+    pub fn help() -> String {
+        let mut lines = vec![(0, "success"), (2, "invalid command-line usage")];
+        lines.extend(Self::ALL.iter().map(|s| (s.code(), s.description())));
+        lines.sort_by_key(|(code, _)| *code);
+        let mut help = "Exit status:".to_string();
+        for (code, description) in lines {
+            help.push_str(&format!("\n  {code}  {description}"));
+        }
+        help
+    }
+}
+
+impl IntoResponse for CroxyError {
+    fn into_response(self) -> Response {
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_errors_answer_with_their_status_and_message() {
+        let response = CroxyError::Upstream("provider unreachable".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            CroxyError::Request("missing request body".to_string()).status(),
+            StatusCode::BAD_REQUEST
+        );
+        let io = CroxyError::from(std::io::Error::other("disk full"));
+        assert_eq!(io.to_string(), "disk full");
+        assert_eq!(io.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn exit_statuses_are_distinct_and_listed_in_order() {
+        let mut codes: Vec<i32> = ExitStatus::ALL.iter().map(|s| s.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), ExitStatus::ALL.len());
+        assert!(!codes.contains(&0) && !codes.contains(&2));
+
+        let help = ExitStatus::help();
+        let listed: Vec<&str> = help
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(listed, ["0", "1", "2", "3", "4", "5", "6", "7"]);
+        assert_eq!(
+            CroxyError::Config("bad".to_string()).exit_status(),
+            ExitStatus::Config
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Request lifecycle events, the firehose `croxy events` prints. The proxy
+//! announces each request as it is received, routed, answered, and
+//! completed, and clients on the control socket can ask for these instead
+//! of the records the TUI mirrors.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::RequestRecord;
+
+/// Where a request is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// croxy has read the request line and headers.
+    Received,
+    /// A provider was chosen for the request.
+    Routed,
+    /// The provider's response started arriving.
+    FirstByte,
+    /// The response has been sent in full, or the request failed.
+    Completed,
+}
+
+/// One stage of one request. Fields a stage doesn't know yet are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestEvent {
+    pub stage: Stage,
+    pub time: DateTime<Utc>,
+    pub request_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// The model the client asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Time since the request was received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RequestEvent {
+    pub fn new(stage: Stage, request_id: &str) -> Self {
+        Self {
+            stage,
+            time: Utc::now(),
+            request_id: request_id.to_string(),
+            method: None,
+            path: None,
+            client: None,
+            model: None,
+            provider: None,
+            route: None,
+            routing_method: None,
+            status: None,
+            elapsed_ms: None,
+            input_tokens: None,
+            output_tokens: None,
+            error: None,
+        }
+    }
+
+    /// The `completed` event for a request's final metrics record.
+    pub fn completed(record: &RequestRecord) -> Self {
+        Self {
+            time: record.wallclock + record.duration,
+            client: record.client.clone(),
+            model: Some(record.model.clone()),
+            provider: Some(record.provider.clone()),
+            routing_method: Some(record.routing_method.to_string()),
+            status: Some(record.status),
+            elapsed_ms: Some(record.duration.as_millis() as u64),
+            input_tokens: Some(record.input_tokens),
+            output_tokens: Some(record.output_tokens),
+            error: record.error_body.clone(),
+            ..Self::new(
+                Stage::Completed,
+                record.request_id.as_deref().unwrap_or_default(),
+            )
+        }
+    }
+
+    /// Whether the event reports an error status.
+    pub fn is_error(&self) -> bool {
+        self.status.is_some_and(|status| status >= 400)
+    }
+}
+
+/// Which events `croxy events --filter` prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EventFilter {
+    /// Events carrying an error status: the first byte and completion of
+    /// requests that failed
+    Errors,
+}
+
+impl EventFilter {
+    pub fn matches(self, event: &RequestEvent) -> bool {
+        match self {
+            EventFilter::Errors => event.is_error(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn record(status: u16) -> RequestRecord {
+        RequestRecord {
+            request_id: Some("abc-1".to_string()),
+            status,
+            duration: Duration::from_millis(1500),
+            output_tokens: 40,
+            ..RequestRecord::sample()
+        }
+    }
+
+    #[test]
+    fn events_leave_out_what_their_stage_does_not_know() {
+        let event = RequestEvent {
+            method: Some("POST".to_string()),
+            path: Some("/v1/messages".to_string()),
+            ..RequestEvent::new(Stage::Received, "abc-1")
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["stage"], "received");
+        assert_eq!(json["request_id"], "abc-1");
+        assert_eq!(json["path"], "/v1/messages");
+        assert!(json.get("status").is_none());
+        assert!(json.get("provider").is_none());
+        assert_eq!(serde_json::from_value::<RequestEvent>(json).unwrap(), event);
+    }
+
+    #[test]
+    fn completed_events_carry_the_records_outcome() {
+        let event = RequestEvent::completed(&record(200));
+        assert_eq!(event.stage, Stage::Completed);
+        assert_eq!(event.request_id, "abc-1");
+        assert_eq!(event.status, Some(200));
+        assert_eq!(event.elapsed_ms, Some(1500));
+        assert_eq!(event.output_tokens, Some(40));
+        assert_eq!(event.routing_method.as_deref(), Some("pattern"));
+        assert!(!EventFilter::Errors.matches(&event));
+        assert!(EventFilter::Errors.matches(&RequestEvent::completed(&record(529))));
+        assert!(!EventFilter::Errors.matches(&RequestEvent::new(Stage::Received, "abc-2")));
+    }
+}
@@ -0,0 +1,268 @@
+//! Past requests as the metrics log records them, for `croxy history` and
+//! `croxy show`. The TUI only reaches back as far as its retention; the
+//! log keeps everything its rotated files still hold.
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::MetricsLogConfig;
+use crate::metrics::Timings;
+use crate::metrics_log::rotated_path;
+
+/// One line of the metrics log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    /// Missing from lines written before request IDs were logged.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    pub model: String,
+    pub provider: String,
+    pub routing_method: Option<String>,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub error: Option<String>,
+    pub batch_size: Option<usize>,
+    #[serde(default)]
+    pub redactions: usize,
+    pub listener: Option<String>,
+    #[serde(default)]
+    pub client: Option<String>,
+    pub cutoff: Option<String>,
+    pub chaos: Option<String>,
+    #[serde(default)]
+    pub bench: Option<String>,
+    #[serde(default)]
+    pub client_aborted: Option<u64>,
+    #[serde(default)]
+    pub near_limit: bool,
+    #[serde(default)]
+    pub timings: Timings,
+}
+
+impl Entry {
+    /// What kind of failure the request was, if it failed.
+    pub fn error_category(&self) -> Option<&'static str> {
+        if self.chaos.is_some() {
+            return Some("chaos");
+        }
+        match (self.status, self.cutoff.as_deref()) {
+            (504, _) | (_, Some("request_timeout_ms")) => Some("timeout"),
+            (_, Some(_)) => Some("cutoff"),
+            (429, _) => Some("rate_limited"),
+            (529, _) => Some("overloaded"),
+            (400..=499, _) => Some("client"),
+            (500..=599, _) => Some("provider"),
+            _ => None,
+        }
+    }
+}
+
+/// The metrics log's files, oldest first: `.max_files` down to `.1`, then
+/// the current one.
+pub fn log_paths(config: &MetricsLogConfig) -> Vec<PathBuf> {
+    let base = Path::new(&config.path);
+    (1..=config.max_files)
+        .rev()
+        .map(|i| rotated_path(base, i))
+        .chain(std::iter::once(base.to_path_buf()))
+        .collect()
+}
+
+/// Every entry in the metrics log, oldest first. Missing files and lines
+/// that don't parse are skipped.
+pub fn read(config: &MetricsLogConfig) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for path in log_paths(config) {
+        let Ok(file) = std::fs::File::open(&path) else {
+            continue;
+        };
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            if let Ok(entry) = serde_json::from_str(&line) {
+                entries.push(entry);
+            }
+        }
+    }
+    entries
+}
+
+/// The entry for request `id`, the latest if it was logged more than once.
+pub fn find(config: &MetricsLogConfig, id: &str) -> Option<Entry> {
+    read(config)
+        .into_iter()
+        .rfind(|entry| entry.request_id.as_deref() == Some(id))
+}
+
+/// A status code to match, exactly (`404`) or by class (`4xx`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusFilter {
+    Exact(u16),
+    Class(u16),
+}
+
+impl StatusFilter {
+    pub fn matches(self, status: u16) -> bool {
+        match self {
+            StatusFilter::Exact(code) => status == code,
+            StatusFilter::Class(class) => status / 100 == class,
+        }
+    }
+}
+
+pub fn parse_status(value: &str) -> Result<StatusFilter, String> {
+    let invalid = || format!("invalid status '{value}' (expected e.g. 404 or 4xx)");
+    match value.to_ascii_lowercase().strip_suffix("xx") {
+        Some(class) => match class.parse() {
+            Ok(class @ 1..=5) => Ok(StatusFilter::Class(class)),
+            _ => Err(invalid()),
+        },
+        None => match value.parse() {
+            Ok(code @ 100..=599) => Ok(StatusFilter::Exact(code)),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+/// Parses an age like `90s`, `30m`, `24h`, or `7d`.
+pub fn parse_age(value: &str) -> Result<chrono::Duration, String> {
+    let invalid = || format!("invalid duration '{value}' (expected e.g. 30m, 24h, or 7d)");
+    let split = value.len().saturating_sub(1);
+    let (Some(amount), Some(unit)) = (value.get(..split), value.get(split..)) else {
+        return Err(invalid());
+    };
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(amount)),
+        "m" => Ok(chrono::Duration::minutes(amount)),
+        "h" => Ok(chrono::Duration::hours(amount)),
+        "d" => Ok(chrono::Duration::days(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Which entries `croxy history` lists.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Matched anywhere in the requested model.
+    pub model: Option<String>,
+    pub status: Option<StatusFilter>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.model
+            .as_ref()
+            .is_none_or(|model| entry.model.contains(model.as_str()))
+            && self
+                .status
+                .is_none_or(|status| status.matches(entry.status))
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn line(ts: &str, id: &str, model: &str, status: u16) -> String {
+        format!(
+            r#"{{"timestamp":"{ts}","request_id":"{id}","model":"{model}","provider":"anthropic","status":{status},"duration_ms":100,"input_tokens":50,"output_tokens":75,"error":null}}"#
+        )
+    }
+
+    #[test]
+    fn entries_are_read_oldest_first_across_rotations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.jsonl");
+        let config = MetricsLogConfig {
+            path: path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut rotated = std::fs::File::create(rotated_path(&path, 1)).unwrap();
+        writeln!(
+            rotated,
+            "{}",
+            line("2026-10-15T15:00:00Z", "1a-1", "opus", 200)
+        )
+        .unwrap();
+        let mut current = std::fs::File::create(&path).unwrap();
+        writeln!(current, "not json").unwrap();
+        writeln!(
+            current,
+            "{}",
+            line("2026-10-16T09:00:00Z", "1a-2", "haiku", 529)
+        )
+        .unwrap();
+
+        let entries = read(&config);
+        let ids: Vec<_> = entries.iter().map(|e| e.request_id.as_deref()).collect();
+        assert_eq!(ids, vec![Some("1a-1"), Some("1a-2")]);
+        assert_eq!(find(&config, "1a-2").unwrap().status, 529);
+        assert!(find(&config, "1a-3").is_none());
+    }
+
+    #[test]
+    fn failures_are_categorized() {
+        let mut entry: Entry =
+            serde_json::from_str(&line("2026-10-16T09:00:00Z", "1a-2", "opus", 200)).unwrap();
+        assert_eq!(entry.error_category(), None);
+        entry.status = 529;
+        assert_eq!(entry.error_category(), Some("overloaded"));
+        entry.status = 404;
+        assert_eq!(entry.error_category(), Some("client"));
+        entry.status = 200;
+        entry.cutoff = Some("max_stream_secs".to_string());
+        assert_eq!(entry.error_category(), Some("cutoff"));
+        entry.cutoff = Some("request_timeout_ms".to_string());
+        assert_eq!(entry.error_category(), Some("timeout"));
+    }
+
+    #[test]
+    fn filters_match_model_status_and_age() {
+        let entry: Entry = serde_json::from_str(&line(
+            "2026-10-16T09:00:00Z",
+            "1a-2",
+            "claude-haiku-4-5",
+            529,
+        ))
+        .unwrap();
+        assert!(Filter::default().matches(&entry));
+
+        let filter = Filter {
+            model: Some("haiku".to_string()),
+            status: Some(parse_status("5xx").unwrap()),
+            since: Some("2026-10-15T09:00:00Z".parse().unwrap()),
+        };
+        assert!(filter.matches(&entry));
+        let filter = Filter {
+            status: Some(parse_status("500").unwrap()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&entry));
+        let filter = Filter {
+            since: Some("2026-10-16T10:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&entry));
+    }
+
+    #[test]
+    fn statuses_and_ages_parse() {
+        assert_eq!(parse_status("4XX"), Ok(StatusFilter::Class(4)));
+        assert_eq!(parse_status("404"), Ok(StatusFilter::Exact(404)));
+        assert!(parse_status("7xx").is_err());
+        assert!(parse_status("abc").is_err());
+        assert_eq!(parse_age("24h"), Ok(chrono::Duration::hours(24)));
+        assert_eq!(parse_age("7d"), Ok(chrono::Duration::days(7)));
+        assert!(parse_age("h").is_err());
+        assert!(parse_age("24").is_err());
+        assert!(parse_age("").is_err());
+    }
+}
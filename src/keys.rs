@@ -0,0 +1,753 @@
+//! Virtual API keys. croxy mints keys that clients present instead of a
+//! real provider key; each can carry a budget, a rate limit, and a list of
+//! routes it may use. Requests made with one are sent on with the
+//! provider's own credentials.
+//!
+//! `keys.json` holds the keys and is written by `croxy key`. Only SHA-256
+//! hashes are stored. The daemon rereads it when it changes and keeps what
+//! each key has spent in `key-usage.json`.
+//!
+//! `[clients.NAME]` in the config gives the key named NAME daily and
+//! monthly token quotas, and pins of its own (see [`crate::tenants`]).
+//! Tokens used toward the quotas are kept in `token-usage.json`.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use http::{HeaderMap, StatusCode};
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ClientConfig;
+use crate::router::ResolvedRoute;
+
+pub const KEY_PREFIX: &str = "sk-croxy-";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualKey {
+    pub name: String,
+    /// Hex SHA-256 of the key.
+    pub hash: String,
+    /// The start of the key, to recognize it by.
+    pub hint: String,
+    pub created: DateTime<Utc>,
+    pub budget_usd: Option<f64>,
+    pub requests_per_minute: Option<u32>,
+    /// Route names or provider names the key may use; empty allows all.
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+impl VirtualKey {
+    pub fn allows(&self, route: &ResolvedRoute) -> bool {
+        self.routes.is_empty()
+            || self.routes.iter().any(|allowed| {
+                route.route_name.as_deref() == Some(allowed) || route.provider_name == *allowed
+            })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyFile {
+    #[serde(default)]
+    keys: Vec<VirtualKey>,
+}
+
+/// Path of the spend file kept next to `keys_path`.
+pub fn usage_path(keys_path: &Path) -> PathBuf {
+    keys_path.with_file_name("key-usage.json")
+}
+
+/// Path of the token count file kept next to `keys_path`.
+pub fn token_usage_path(keys_path: &Path) -> PathBuf {
+    keys_path.with_file_name("token-usage.json")
+}
+
+fn hash(key: &str) -> String {
+    digest(&SHA256, key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn read_keys(path: &Path) -> Result<Vec<VirtualKey>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str::<KeyFile>(&content)
+            .map(|file| file.keys)
+            .map_err(|e| format!("invalid {}: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("failed to read {}: {e}", path.display())),
+    }
+}
+
+/// Writes through a temporary file so readers never see half a file.
+fn write_json(path: &Path, value: &impl Serialize) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("failed to create {}: {e}", dir.display()))?;
+    }
+    let content = serde_json::to_string_pretty(value).expect("key data serializes");
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, content)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+pub fn read_usage(keys_path: &Path) -> HashMap<String, f64> {
+    std::fs::read_to_string(usage_path(keys_path))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn read_tokens(keys_path: &Path) -> HashMap<String, Tokens> {
+    std::fs::read_to_string(token_usage_path(keys_path))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Tokens a key has used on one UTC day and in its month.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Tokens {
+    day: NaiveDate,
+    today: u64,
+    this_month: u64,
+}
+
+impl Tokens {
+    fn new(day: NaiveDate) -> Self {
+        Self {
+            day,
+            today: 0,
+            this_month: 0,
+        }
+    }
+
+    /// Starts counting `day`, and its month if that's new too.
+    fn roll(&mut self, day: NaiveDate) {
+        if day == self.day {
+            return;
+        }
+        if (day.year(), day.month()) != (self.day.year(), self.day.month()) {
+            self.this_month = 0;
+        }
+        self.today = 0;
+        self.day = day;
+    }
+}
+
+/// A client's token use against its quotas, as shown in the Clients tab.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub client: String,
+    /// The UTC day the counts were last updated on.
+    pub day: NaiveDate,
+    pub daily_used: u64,
+    pub daily_limit: Option<u64>,
+    pub monthly_used: u64,
+    pub monthly_limit: Option<u64>,
+}
+
+impl QuotaUsage {
+    /// Tokens used on `today` and in its month. Counts from an earlier day
+    /// or month no longer apply.
+    pub fn current(&self, today: NaiveDate) -> (u64, u64) {
+        let mut tokens = Tokens {
+            day: self.day,
+            today: self.daily_used,
+            this_month: self.monthly_used,
+        };
+        tokens.roll(today);
+        (tokens.today, tokens.this_month)
+    }
+}
+
+/// When the quota for `period` that includes `day` starts over.
+fn quota_reset(period: &str, day: NaiveDate) -> DateTime<Utc> {
+    let next = match period {
+        "daily" => day.checked_add_days(Days::new(1)),
+        _ => day
+            .with_day(1)
+            .and_then(|first| first.checked_add_months(Months::new(1))),
+    };
+    next.unwrap_or(day)
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+}
+
+/// Parses a budget such as `5usd`, `$5`, or `5`.
+pub fn parse_budget(value: &str) -> Result<f64, String> {
+    let amount = value
+        .trim()
+        .trim_start_matches('$')
+        .trim_end_matches("usd")
+        .trim_end_matches("USD")
+        .trim();
+    match amount.parse::<f64>() {
+        Ok(usd) if usd.is_finite() && usd > 0.0 => Ok(usd),
+        _ => Err(format!(
+            "invalid budget '{value}': expected an amount in USD like 5usd"
+        )),
+    }
+}
+
+pub struct NewKey {
+    pub name: String,
+    pub budget_usd: Option<f64>,
+    pub requests_per_minute: Option<u32>,
+    pub routes: Vec<String>,
+}
+
+/// Adds a key to `path` and returns it. This is the only time the key
+/// itself is available.
+pub fn create(path: &Path, new: NewKey) -> Result<String, String> {
+    let mut keys = read_keys(path)?;
+    if keys.iter().any(|k| k.name == new.name) {
+        return Err(format!("a key named '{}' already exists", new.name));
+    }
+    let mut random = [0u8; 24];
+    SystemRandom::new()
+        .fill(&mut random)
+        .map_err(|_| "failed to generate key".to_string())?;
+    let secret: String = random.iter().map(|b| format!("{b:02x}")).collect();
+    let key = format!("{KEY_PREFIX}{secret}");
+    keys.push(VirtualKey {
+        name: new.name,
+        hash: hash(&key),
+        hint: format!("{}...", &key[..KEY_PREFIX.len() + 6]),
+        created: Utc::now(),
+        budget_usd: new.budget_usd,
+        requests_per_minute: new.requests_per_minute,
+        routes: new.routes,
+    });
+    write_json(path, &KeyFile { keys })?;
+    Ok(key)
+}
+
+pub fn revoke(path: &Path, name: &str) -> Result<(), String> {
+    let mut keys = read_keys(path)?;
+    let before = keys.len();
+    keys.retain(|k| k.name != name);
+    if keys.len() == before {
+        return Err(format!("no key named '{name}'"));
+    }
+    write_json(path, &KeyFile { keys })
+}
+
+pub fn list(path: &Path) -> Result<Vec<VirtualKey>, String> {
+    read_keys(path)
+}
+
+/// The virtual key a request presents, from `x-api-key` or a bearer token.
+pub fn presented(headers: &HeaderMap) -> Option<&str> {
+    let api_key = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+    let bearer = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    api_key
+        .into_iter()
+        .chain(bearer)
+        .find(|key| key.starts_with(KEY_PREFIX))
+}
+
+/// Why a virtual key was turned away.
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    Unknown,
+    OverBudget {
+        name: String,
+        budget_usd: f64,
+    },
+    RateLimited {
+        name: String,
+        per_minute: u32,
+    },
+    Route {
+        name: String,
+        route: String,
+    },
+    OverQuota {
+        name: String,
+        /// `daily` or `monthly`.
+        period: &'static str,
+        limit: u64,
+        resets: DateTime<Utc>,
+    },
+}
+
+impl Denied {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Denied::Unknown => StatusCode::UNAUTHORIZED,
+            Denied::OverBudget { .. } | Denied::Route { .. } => StatusCode::FORBIDDEN,
+            Denied::RateLimited { .. } | Denied::OverQuota { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// A short name for the kind of denial, for the audit log.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Denied::Unknown => "unknown_key",
+            Denied::OverBudget { .. } => "over_budget",
+            Denied::RateLimited { .. } => "rate_limited",
+            Denied::Route { .. } => "route",
+            Denied::OverQuota { .. } => "over_quota",
+        }
+    }
+}
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denied::Unknown => write!(f, "invalid croxy virtual key"),
+            Denied::OverBudget { name, budget_usd } => {
+                write!(f, "key '{name}' has spent its ${budget_usd:.2} budget")
+            }
+            Denied::RateLimited { name, per_minute } => {
+                write!(
+                    f,
+                    "key '{name}' is limited to {per_minute} requests per minute"
+                )
+            }
+            Denied::Route { name, route } => write!(f, "key '{name}' may not use {route}"),
+            Denied::OverQuota {
+                name,
+                period,
+                limit,
+                resets,
+            } => write!(
+                f,
+                "key '{name}' has used its {period} quota of {limit} tokens; it resets at {}",
+                resets.format("%Y-%m-%d %H:%M UTC")
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Loaded {
+    keys: Vec<VirtualKey>,
+    /// Modification time and length of the file the keys came from.
+    stamp: Option<(SystemTime, u64)>,
+    spent: HashMap<String, f64>,
+    recent: HashMap<String, VecDeque<Instant>>,
+    tokens: HashMap<String, Tokens>,
+}
+
+/// The daemon's view of the virtual keys.
+pub struct KeyStore {
+    path: Option<PathBuf>,
+    required: bool,
+    quotas: HashMap<String, ClientConfig>,
+    loaded: Mutex<Loaded>,
+}
+
+impl KeyStore {
+    /// No keys: requests are passed through with their own credentials.
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            required: false,
+            quotas: HashMap::new(),
+            loaded: Mutex::new(Loaded::default()),
+        }
+    }
+
+    /// Keys from `path`, which need not exist yet. With `required`, every
+    /// request must present one.
+    pub fn open(path: PathBuf, required: bool) -> Self {
+        let store = Self {
+            loaded: Mutex::new(Loaded {
+                spent: read_usage(&path),
+                tokens: read_tokens(&path),
+                ..Loaded::default()
+            }),
+            path: Some(path),
+            required,
+            quotas: HashMap::new(),
+        };
+        store.refresh(&mut store.loaded.lock().expect("keys lock poisoned"));
+        store
+    }
+
+    /// Token quotas by key name, from `[clients]`.
+    pub fn with_quotas(mut self, quotas: HashMap<String, ClientConfig>) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Rereads the key file if it changed since it was last read.
+    fn refresh(&self, loaded: &mut Loaded) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+        let stamp = std::fs::metadata(path)
+            .ok()
+            .and_then(|m| Some((m.modified().ok()?, m.len())));
+        if stamp == loaded.stamp {
+            return;
+        }
+        match read_keys(path) {
+            Ok(keys) => {
+                tracing::info!(keys = keys.len(), "loaded virtual keys");
+                loaded.keys = keys;
+                loaded.stamp = stamp;
+            }
+            Err(e) => tracing::warn!("keeping previous virtual keys: {e}"),
+        }
+    }
+
+    /// Names of the keys, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut loaded = self.loaded.lock().expect("keys lock poisoned");
+        self.refresh(&mut loaded);
+        let mut names: Vec<String> = loaded.keys.iter().map(|k| k.name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    /// The key a presented key is, without checking its limits or counting
+    /// it toward them.
+    pub fn identify(&self, presented: &str) -> Option<VirtualKey> {
+        let mut loaded = self.loaded.lock().expect("keys lock poisoned");
+        self.refresh(&mut loaded);
+        let hash = hash(presented);
+        loaded.keys.iter().find(|k| k.hash == hash).cloned()
+    }
+
+    /// Checks a presented key against its budget and rate limit, counting
+    /// the request toward the limit.
+    pub fn authorize(&self, presented: &str) -> Result<VirtualKey, Denied> {
+        let mut loaded = self.loaded.lock().expect("keys lock poisoned");
+        self.refresh(&mut loaded);
+        let hash = hash(presented);
+        let key = loaded
+            .keys
+            .iter()
+            .find(|k| k.hash == hash)
+            .cloned()
+            .ok_or(Denied::Unknown)?;
+
+        if let Some(budget_usd) = key.budget_usd
+            && loaded.spent.get(&key.name).copied().unwrap_or(0.0) >= budget_usd
+        {
+            return Err(Denied::OverBudget {
+                name: key.name,
+                budget_usd,
+            });
+        }
+        if let Some(quota) = self.quotas.get(&key.name) {
+            let today = Utc::now().date_naive();
+            let mut tokens = loaded
+                .tokens
+                .get(&key.name)
+                .copied()
+                .unwrap_or(Tokens::new(today));
+            tokens.roll(today);
+            for (period, limit, used) in [
+                ("daily", quota.daily_tokens, tokens.today),
+                ("monthly", quota.monthly_tokens, tokens.this_month),
+            ] {
+                if let Some(limit) = limit
+                    && used >= limit
+                {
+                    return Err(Denied::OverQuota {
+                        name: key.name,
+                        period,
+                        limit,
+                        resets: quota_reset(period, today),
+                    });
+                }
+            }
+        }
+        if let Some(per_minute) = key.requests_per_minute {
+            let now = Instant::now();
+            let recent = loaded.recent.entry(key.name.clone()).or_default();
+            while recent
+                .front()
+                .is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(60))
+            {
+                recent.pop_front();
+            }
+            if recent.len() >= per_minute as usize {
+                return Err(Denied::RateLimited {
+                    name: key.name,
+                    per_minute,
+                });
+            }
+            recent.push_back(now);
+        }
+        Ok(key)
+    }
+
+    /// Adds to what `name` has spent and saves the totals.
+    pub fn charge(&self, name: &str, usd: f64) {
+        if usd <= 0.0 {
+            return;
+        }
+        let mut loaded = self.loaded.lock().expect("keys lock poisoned");
+        *loaded.spent.entry(name.to_string()).or_default() += usd;
+        if let Some(ref path) = self.path
+            && let Err(e) = write_json(&usage_path(path), &loaded.spent)
+        {
+            tracing::warn!("failed to save key usage: {e}");
+        }
+    }
+
+    /// Counts `tokens` toward `name`'s quotas and saves the counts,
+    /// returning its usage if it has a quota.
+    pub fn use_tokens(&self, name: &str, tokens: u64) -> Option<QuotaUsage> {
+        let quota = self.quotas.get(name)?;
+        let today = Utc::now().date_naive();
+        let mut loaded = self.loaded.lock().expect("keys lock poisoned");
+        let used = loaded
+            .tokens
+            .entry(name.to_string())
+            .or_insert(Tokens::new(today));
+        used.roll(today);
+        used.today += tokens;
+        used.this_month += tokens;
+        let usage = quota_usage(name, quota, *used);
+        if let Some(ref path) = self.path
+            && let Err(e) = write_json(&token_usage_path(path), &loaded.tokens)
+        {
+            tracing::warn!("failed to save token usage: {e}");
+        }
+        Some(usage)
+    }
+
+    /// Token use of every key with a quota, by name.
+    pub fn quota_usage(&self) -> Vec<QuotaUsage> {
+        let today = Utc::now().date_naive();
+        let loaded = self.loaded.lock().expect("keys lock poisoned");
+        let mut usage: Vec<QuotaUsage> = self
+            .quotas
+            .iter()
+            .map(|(name, quota)| {
+                let mut used = loaded
+                    .tokens
+                    .get(name)
+                    .copied()
+                    .unwrap_or(Tokens::new(today));
+                used.roll(today);
+                quota_usage(name, quota, used)
+            })
+            .collect();
+        usage.sort_by(|a, b| a.client.cmp(&b.client));
+        usage
+    }
+
+    /// The name, spend, and budget in USD of every key with a budget.
+    pub fn budgets(&self) -> Vec<(String, f64, f64)> {
+        let mut loaded = self.loaded.lock().expect("keys lock poisoned");
+        self.refresh(&mut loaded);
+        loaded
+            .keys
+            .iter()
+            .filter_map(|key| {
+                let spent = loaded.spent.get(&key.name).copied().unwrap_or(0.0);
+                Some((key.name.clone(), spent, key.budget_usd?))
+            })
+            .collect()
+    }
+
+    pub fn spent(&self, name: &str) -> f64 {
+        self.loaded
+            .lock()
+            .expect("keys lock poisoned")
+            .spent
+            .get(name)
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+fn quota_usage(name: &str, quota: &ClientConfig, used: Tokens) -> QuotaUsage {
+    QuotaUsage {
+        client: name.to_string(),
+        day: used.day,
+        daily_used: used.today,
+        daily_limit: quota.daily_tokens,
+        monthly_used: used.this_month,
+        monthly_limit: quota.monthly_tokens,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_key(name: &str) -> NewKey {
+        NewKey {
+            name: name.to_string(),
+            budget_usd: None,
+            requests_per_minute: None,
+            routes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn budgets_parse_with_or_without_unit() {
+        assert_eq!(parse_budget("5usd").unwrap(), 5.0);
+        assert_eq!(parse_budget("$2.50").unwrap(), 2.5);
+        assert_eq!(parse_budget("10").unwrap(), 10.0);
+        assert!(parse_budget("five").is_err());
+        assert!(parse_budget("0usd").is_err());
+    }
+
+    #[test]
+    fn created_keys_are_stored_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let key = create(&path, new_key("ci")).unwrap();
+        assert!(key.starts_with(KEY_PREFIX));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains(&key));
+        assert!(content.contains(&hash(&key)));
+        assert_eq!(
+            create(&path, new_key("ci")).unwrap_err(),
+            "a key named 'ci' already exists"
+        );
+
+        revoke(&path, "ci").unwrap();
+        assert!(list(&path).unwrap().is_empty());
+        assert_eq!(revoke(&path, "ci").unwrap_err(), "no key named 'ci'");
+    }
+
+    #[test]
+    fn store_picks_up_new_keys_and_enforces_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let store = KeyStore::open(path.clone(), false);
+        assert_eq!(
+            store.authorize("sk-croxy-nope").unwrap_err(),
+            Denied::Unknown
+        );
+
+        let key = create(
+            &path,
+            NewKey {
+                budget_usd: Some(1.0),
+                ..new_key("ci")
+            },
+        )
+        .unwrap();
+        assert_eq!(store.authorize(&key).unwrap().name, "ci");
+
+        store.charge("ci", 0.6);
+        store.charge("ci", 0.6);
+        let denied = store.authorize(&key).unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert_eq!(denied.to_string(), "key 'ci' has spent its $1.00 budget");
+
+        // Spend survives a restart
+        let reopened = KeyStore::open(path, false);
+        assert!((reopened.spent("ci") - 1.2).abs() < 1e-9);
+        let budgets = reopened.budgets();
+        assert_eq!(budgets.len(), 1);
+        assert_eq!((budgets[0].0.as_str(), budgets[0].2), ("ci", 1.0));
+    }
+
+    #[test]
+    fn rate_limit_counts_requests_per_minute() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let key = create(
+            &path,
+            NewKey {
+                requests_per_minute: Some(2),
+                ..new_key("ci")
+            },
+        )
+        .unwrap();
+        let store = KeyStore::open(path, false);
+        assert!(store.authorize(&key).is_ok());
+        assert!(store.authorize(&key).is_ok());
+        assert_eq!(
+            store.authorize(&key).unwrap_err().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn quotas_refuse_keys_that_used_their_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        let key = create(&path, new_key("intern")).unwrap();
+        let quotas = HashMap::from([(
+            "intern".to_string(),
+            ClientConfig {
+                daily_tokens: Some(100),
+                monthly_tokens: Some(1000),
+                ..ClientConfig::default()
+            },
+        )]);
+        let store = KeyStore::open(path.clone(), false).with_quotas(quotas.clone());
+        assert!(store.use_tokens("other", 500).is_none());
+        let usage = store.use_tokens("intern", 60).unwrap();
+        assert_eq!((usage.daily_used, usage.daily_limit), (60, Some(100)));
+        assert!(store.authorize(&key).is_ok());
+
+        store.use_tokens("intern", 60);
+        let denied = store.authorize(&key).unwrap_err();
+        assert_eq!(denied.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(denied.reason(), "over_quota");
+        let tomorrow = Utc::now().date_naive().succ_opt().unwrap();
+        assert_eq!(
+            denied.to_string(),
+            format!(
+                "key 'intern' has used its daily quota of 100 tokens; it resets at {tomorrow} 00:00 UTC"
+            )
+        );
+
+        // Counts survive a restart
+        let reopened = KeyStore::open(path, false).with_quotas(quotas);
+        assert_eq!(reopened.quota_usage()[0].monthly_used, 120);
+    }
+
+    #[test]
+    fn token_counts_start_over_each_day_and_month() {
+        let day = |d: &str| d.parse::<NaiveDate>().unwrap();
+        let mut tokens = Tokens {
+            day: day("2026-01-30"),
+            today: 10,
+            this_month: 50,
+        };
+        tokens.roll(day("2026-01-31"));
+        assert_eq!((tokens.today, tokens.this_month), (0, 50));
+        tokens.roll(day("2026-02-01"));
+        assert_eq!((tokens.today, tokens.this_month), (0, 0));
+
+        assert_eq!(
+            quota_reset("monthly", day("2026-01-31")).to_rfc3339(),
+            "2026-02-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            quota_reset("daily", day("2026-12-31")).to_rfc3339(),
+            "2027-01-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn presented_key_comes_from_either_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "sk-ant-real".parse().unwrap());
+        assert_eq!(presented(&headers), None);
+        headers.insert(
+            http::header::AUTHORIZATION,
+            "Bearer sk-croxy-abc".parse().unwrap(),
+        );
+        assert_eq!(presented(&headers), Some("sk-croxy-abc"));
+    }
+}
@@ -1,11 +1,70 @@
 #![cfg_attr(not(test), warn(clippy::unwrap_used))]
 
+pub mod access_log;
+pub mod admin;
+pub mod alerts;
+pub mod archive;
 pub mod attach;
+pub mod audit;
 pub mod auto_router;
+pub mod auto_router_local;
+pub mod balance;
+pub mod batches;
+pub mod bench;
+pub mod body_sizes;
+pub mod caching;
+pub mod capabilities;
+pub mod capture;
+pub mod chaos;
 pub mod cli_config;
+pub mod clients;
+pub mod compare;
 pub mod config;
+pub mod context_guard;
+pub mod context_windows;
+pub mod control;
+pub mod crash;
+pub mod dedup;
+pub mod diff;
+pub mod error;
+pub mod events;
+pub mod google_auth;
+pub mod history;
+pub mod keys;
+pub mod listeners;
 pub mod metrics;
 pub mod metrics_log;
+pub mod middleware;
+pub mod model_check;
+pub mod notifications;
+pub mod peek;
+pub mod pricing;
+pub mod prometheus;
 pub mod proxy;
+pub mod ratelimits;
+pub mod redact;
+pub mod replay;
+pub mod response_headers;
 pub mod router;
+pub mod schema;
+pub mod script;
+pub mod scrub;
+pub mod secrets;
+pub mod server;
+pub mod service;
+pub mod session;
+pub mod setup;
+pub mod templates;
+pub mod tenants;
+pub mod tokenizer;
+pub mod tokens;
+pub mod tool_repair;
+pub mod tool_results;
+pub mod translate;
 pub mod tui;
+pub mod validate;
+pub mod vertex;
+pub mod warm;
+
+pub use error::CroxyError;
+pub use server::Server;
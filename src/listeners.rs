@@ -0,0 +1,432 @@
+//! Extra `[[server.listeners]]`: TCP addresses, HTTPS addresses, and unix
+//! sockets served alongside `[server]`'s own, each tagged so requests can
+//! be attributed to the listener they arrived on. Every listener speaks
+//! HTTP/1.1 and HTTP/2, so a client can multiplex its requests over one
+//! connection.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::mpsc;
+use tokio_native_tls::native_tls::{self, Identity};
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::config::ListenerConfig;
+use crate::metrics::MetricsStore;
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The listener a request arrived on, attached to it as an extension.
+#[derive(Debug, Clone, Default)]
+pub struct Ingress {
+    pub tag: Option<String>,
+    /// Requests must present a virtual key.
+    pub require_key: bool,
+}
+
+impl Ingress {
+    pub fn from_config(config: &ListenerConfig) -> Self {
+        Self {
+            tag: config.tag.clone(),
+            require_key: config.require_key,
+        }
+    }
+}
+
+/// Protocols offered to HTTPS clients, most preferred first.
+const ALPN: [&str; 2] = ["h2", "http/1.1"];
+
+/// Where a connection came from, attached to its requests so they can be
+/// attributed to a client. Unix socket peers have no address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub ip: Option<IpAddr>,
+    /// Unique to the connection, so the requests sharing it can be
+    /// counted together.
+    pub connection: u64,
+}
+
+impl Peer {
+    /// A newly accepted connection from `ip`.
+    pub fn new(ip: Option<IpAddr>) -> Self {
+        static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+        Self {
+            ip,
+            connection: NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Peer::new(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl Connected<IncomingStream<'_, KeepAlive>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, KeepAlive>) -> Self {
+        Peer::new(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl<L: Listener<Addr = SocketAddr>> Connected<IncomingStream<'_, Allowlisted<L>>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, Allowlisted<L>>) -> Self {
+        Peer::new(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Peer::new(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl Connected<IncomingStream<'_, tokio::net::UnixListener>> for Peer {
+    fn connect_info(_: IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+        Peer::new(None)
+    }
+}
+
+/// The requests in flight on one client connection. An HTTP/1.1
+/// connection carries one at a time; an HTTP/2 one may carry many.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStreams {
+    pub connection: u64,
+    /// Unset for unix socket clients.
+    pub peer: Option<IpAddr>,
+    /// `HTTP/1.1` or `HTTP/2`.
+    pub http: String,
+    pub streams: u64,
+}
+
+/// Checks one `[[server.listeners]]` entry.
+pub fn check(config: &ListenerConfig) -> Result<(), String> {
+    match (&config.address, &config.socket) {
+        (Some(address), None) => {
+            if address
+                .rsplit_once(':')
+                .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+            {
+                return Err(format!("address '{address}' must be host:port"));
+            }
+        }
+        (None, Some(_)) if config.tls_cert.is_some() || config.tls_key.is_some() => {
+            return Err("TLS needs an address, not a socket".to_string());
+        }
+        (None, Some(_)) => {}
+        _ => return Err("needs exactly one of address or socket".to_string()),
+    }
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            tls_acceptor(
+                &crate::secrets::expand_home(cert),
+                &crate::secrets::expand_home(key),
+            )?;
+        }
+        (None, None) => {}
+        _ => return Err("tls_cert and tls_key must be set together".to_string()),
+    }
+    Ok(())
+}
+
+/// Binds a unix socket only its owner can connect to. The socket is bound
+/// in a directory only the owner can enter and restricted there, then
+/// linked into place, so there is no moment another user could connect.
+/// Like a plain bind, fails if `path` exists.
+pub fn bind_unix(path: &Path) -> std::io::Result<UnixListener> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = parent.join(format!(
+        ".croxy-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("s");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::hard_link(&staged, path).map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => ErrorKind::AddrInUse.into(),
+            _ => e,
+        })?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+/// `server.allow_cidrs`: the networks TCP clients may connect from.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist(Vec<IpNet>);
+
+impl Allowlist {
+    /// Parses CIDRs, or single addresses.
+    pub fn parse(cidrs: &[String]) -> Result<Self, String> {
+        cidrs
+            .iter()
+            .map(|cidr| {
+                cidr.parse::<IpNet>()
+                    .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid CIDR '{cidr}'"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Loopback is always allowed, and everyone when the list is empty.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets see IPv4 clients as IPv4-mapped IPv6
+        let ip = ip.to_canonical();
+        self.0.is_empty() || ip.is_loopback() || self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Drops connections from outside an allowlist as they are accepted,
+/// before any bytes are read from them.
+pub struct Allowlisted<L> {
+    inner: L,
+    allow: Arc<Allowlist>,
+    metrics: Arc<MetricsStore>,
+    audit: Arc<AuditLog>,
+}
+
+impl<L> Allowlisted<L> {
+    pub fn new(
+        inner: L,
+        allow: Arc<Allowlist>,
+        metrics: Arc<MetricsStore>,
+        audit: Arc<AuditLog>,
+    ) -> Self {
+        Self {
+            inner,
+            allow,
+            metrics,
+            audit,
+        }
+    }
+}
+
+impl<L: Listener<Addr = SocketAddr>> Listener for Allowlisted<L> {
+    type Io = L::Io;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, SocketAddr) {
+        loop {
+            let (io, addr) = self.inner.accept().await;
+            if self.allow.allows(addr.ip()) {
+                return (io, addr);
+            }
+            self.metrics.count_rejected_connection();
+            tracing::warn!(peer = %addr, "rejected connection from outside server.allow_cidrs");
+            self.audit.record(AuditEvent::ConnectionRejected {
+                peer: addr.to_string(),
+            });
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Turns on TCP keep-alive for each accepted connection, so one whose
+/// client went away without closing it is noticed and dropped rather
+/// than held open forever.
+pub struct KeepAlive {
+    inner: TcpListener,
+    /// How long a connection sits idle before it's probed; `None` leaves
+    /// keep-alive off.
+    idle: Option<Duration>,
+}
+
+impl KeepAlive {
+    pub fn new(inner: TcpListener, idle: Option<Duration>) -> Self {
+        Self { inner, idle }
+    }
+}
+
+impl Listener for KeepAlive {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, SocketAddr) {
+        let (io, addr) = Listener::accept(&mut self.inner).await;
+        if let Some(idle) = self.idle {
+            let keepalive = TcpKeepalive::new().with_time(idle);
+            if let Err(e) = SockRef::from(&io).set_tcp_keepalive(&keepalive) {
+                tracing::debug!(peer = %addr, "failed to enable TCP keep-alive: {e}");
+            }
+        }
+        (io, addr)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Loads a PEM certificate chain and its PKCS#8 private key.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {e}", path.display()))
+    };
+    let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+        .map_err(|e| format!("invalid TLS certificate or key: {e}"))?;
+    native_tls::TlsAcceptor::builder(identity)
+        .accept_alpn(&ALPN)
+        .build()
+        .map(TlsAcceptor::from)
+        .map_err(|e| format!("failed to set up TLS: {e}"))
+}
+
+/// Serves HTTPS. Handshakes run off the accept loop, so a slow or broken
+/// client can't hold up the ones behind it.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+    accepting: tokio::task::JoinHandle<()>,
+}
+
+impl TlsListener {
+    pub fn new<L>(mut listener: L, acceptor: TlsAcceptor) -> std::io::Result<Self>
+    where
+        L: Listener<Io = TcpStream, Addr = SocketAddr>,
+    {
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(64);
+        let accepting = tokio::spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await;
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!(peer = %addr, "TLS handshake failed: {e}"),
+                        Err(_) => tracing::debug!(peer = %addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            connections,
+            local_addr,
+            accepting,
+        })
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        self.accepting.abort();
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept loop only ends when this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(address: Option<&str>, socket: Option<&str>) -> ListenerConfig {
+        ListenerConfig {
+            address: address.map(String::from),
+            socket: socket.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn listeners_need_one_place_to_listen() {
+        assert!(check(&listener(Some("0.0.0.0:3443"), None)).is_ok());
+        assert!(check(&listener(Some("[::1]:3443"), None)).is_ok());
+        assert!(check(&listener(None, Some("/tmp/croxy.sock"))).is_ok());
+        assert_eq!(
+            check(&listener(None, None)),
+            Err("needs exactly one of address or socket".to_string())
+        );
+        assert_eq!(
+            check(&listener(Some("0.0.0.0"), None)),
+            Err("address '0.0.0.0' must be host:port".to_string())
+        );
+
+        let mut tls = listener(Some("0.0.0.0:3443"), None);
+        tls.tls_cert = Some("cert.pem".to_string());
+        assert_eq!(
+            check(&tls),
+            Err("tls_cert and tls_key must be set together".to_string())
+        );
+        tls.tls_key = Some("/nonexistent/key.pem".to_string());
+        assert!(check(&tls).unwrap_err().starts_with("failed to read"));
+    }
+
+    #[tokio::test]
+    async fn unix_sockets_are_bound_owner_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("croxy.sock");
+        let _listener = bind_unix(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        // Nothing is left behind, and an existing socket isn't replaced
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        let err = bind_unix(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn allowlists_match_networks_and_always_loopback() {
+        let cidrs = ["10.0.0.0/8".to_string(), "192.168.1.20".to_string()];
+        let allow = Allowlist::parse(&cidrs).unwrap();
+        let allows = |ip: &str| allow.allows(ip.parse().unwrap());
+        assert!(allows("10.1.2.3"));
+        assert!(allows("192.168.1.20"));
+        assert!(allows("::ffff:10.1.2.3"));
+        assert!(allows("127.0.0.1"));
+        assert!(allows("::1"));
+        assert!(!allows("192.168.1.21"));
+        assert!(!allows("8.8.8.8"));
+
+        assert!(Allowlist::default().allows("8.8.8.8".parse().unwrap()));
+        assert_eq!(
+            Allowlist::parse(&["10.0.0.0/33".to_string()]).err(),
+            Some("invalid CIDR '10.0.0.0/33'".to_string())
+        );
+    }
+}
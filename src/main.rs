@@ -1,34 +1,52 @@
 use std::fs;
-use std::net::TcpStream;
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use axum::Router as AxumRouter;
-use axum::routing::any;
 use clap::{Parser, Subcommand};
 use figment::Figment;
-use figment::providers::{Env, Format, Toml};
+use figment::providers::{Env, Format, Serialized, Toml};
 use nix::sys::signal::{Signal, kill};
 use nix::unistd::Pid;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket, UnixListener};
+use tokio::sync::{Notify, watch};
 use tracing::info;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
+use croxy::access_log::AccessLog;
 use croxy::attach;
+use croxy::audit::{AuditEvent, AuditLog};
+use croxy::capture::CaptureStore;
 use croxy::cli_config;
-use croxy::config::Config;
+use croxy::compare::Comparer;
+use croxy::config::{ApiFormat, Config, LogFormat, LoggingConfig};
+use croxy::control;
+use croxy::crash::{CrashReporter, LogTail, TailWriter};
+use croxy::error::ExitStatus;
+use croxy::events::{EventFilter, RequestEvent};
+use croxy::listeners::{self, Allowlist, Allowlisted, Ingress, KeepAlive, Peer, TlsListener};
 use croxy::metrics::MetricsStore;
-use croxy::metrics_log::MetricsLogger;
-use croxy::proxy::{AppState, handle_request};
+use croxy::metrics_log::{MetricsLogger, open_app_log};
+use croxy::proxy::AppState;
 use croxy::router::Router;
-use croxy::tui::ExitMode;
+use croxy::scrub::{ScrubbedWriter, Scrubber};
+use croxy::session::SessionSummary;
+use croxy::templates::Template;
+use croxy::tui::{App, ExitMode};
+use croxy::{CroxyError, Server};
 
 #[derive(Parser)]
 #[command(
     name = "croxy",
-    about = "Observability proxy for the Anthropic API with provider routing"
+    about = "Observability proxy for the Anthropic API with provider routing",
+    after_help = ExitStatus::help()
 )]
 struct Cli {
     /// Path to config file
@@ -39,6 +57,34 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Application log format (overrides [logging] format)
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Named instance, with its own pid file, logs, and default port
+    #[arg(long, global = true, value_name = "NAME")]
+    instance: Option<String>,
+
+    /// Config profile to apply from [profiles.NAME] (or set CROXY_PROFILE)
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Print results as JSON on stdout
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Minutes of metrics to keep in memory (overrides [retention] minutes)
+    #[arg(long, global = true, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    retention: Option<u64>,
+
+    /// Set by `croxy start` on the spawned daemon so it logs to croxy.log
+    #[arg(long, hide = true)]
+    daemon: bool,
+
+    /// Set by `croxy start --takeover`: ask this pid to drain once bound
+    #[arg(long, hide = true, value_name = "PID")]
+    takeover_from: Option<i32>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -46,57 +92,399 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start proxy in background
-    Start,
+    Start {
+        /// Replace a running instance without dropping in-flight requests
+        #[arg(long)]
+        takeover: bool,
+    },
     /// Stop a detached instance
     Stop,
+    /// Show what the running instance has seen, with hints for tuning it
+    Status {
+        /// Show only the usage of the virtual key with this name
+        #[arg(long, value_name = "NAME")]
+        tenant: Option<String>,
+    },
+    /// Open the TUI for a running instance, locally or on another host
+    Attach {
+        /// Address of a remote croxy (e.g. 192.168.1.10:3100); its admin API
+        /// must be reachable
+        #[arg(long, value_name = "HOST:PORT")]
+        host: Option<String>,
+        /// Admin token for the remote instance (or set CROXY_ADMIN_TOKEN)
+        #[arg(long, value_name = "TOKEN", requires = "host")]
+        token: Option<String>,
+    },
+    /// Print request lifecycle events from the running instance, one JSON
+    /// object per line
+    Events {
+        /// Keep printing events as requests arrive, instead of printing the
+        /// completed requests croxy holds and exiting
+        #[arg(long)]
+        follow: bool,
+        /// Print only these events
+        #[arg(long, value_enum)]
+        filter: Option<EventFilter>,
+    },
+    /// Send models matching a pattern to a provider until unpinned or
+    /// croxy restarts
+    Pin {
+        /// Regex matched against the requested model, as in routes
+        pattern: String,
+        /// Provider to send matching requests to
+        provider: String,
+        /// Also save the pin to [[pins]] in the config file
+        #[arg(long)]
+        save: bool,
+    },
+    /// Remove a pin
+    Unpin {
+        pattern: String,
+        /// Also remove the pin from the config file
+        #[arg(long)]
+        save: bool,
+    },
     /// Print shell environment variables (for eval)
     Shellenv,
-    /// Create default config file
-    Init,
+    /// Create a starter config file
+    Init {
+        /// Starter config to generate
+        #[arg(long, value_enum, default_value_t = Template::default())]
+        template: Template,
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+        /// Print the config instead of writing it
+        #[arg(long)]
+        stdout: bool,
+    },
     /// Read or modify configuration
     Config {
         #[command(subcommand)]
         action: ConfigAction,
     },
+    /// Show how a request would be routed, optionally sending it through the daemon
+    TestRoute {
+        /// Model name as a client would send it
+        #[arg(long)]
+        model: String,
+        /// User message used for auto-routing and the sample request
+        #[arg(long, default_value = "hello")]
+        message: String,
+        /// `metadata.user_id` to send, for routes matching on it
+        #[arg(long)]
+        user_id: Option<String>,
+        /// Send a minimal request through the running daemon
+        #[arg(long)]
+        send: bool,
+    },
+    /// Send synthetic traffic through the running daemon and report how
+    /// each provider held up
+    Bench {
+        /// Model name as a client would send it
+        #[arg(long)]
+        model: String,
+        /// Requests in flight at once
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+        concurrency: u64,
+        /// Requests to send in all
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+        requests: u64,
+        /// File whose contents are sent as the user message, instead of a
+        /// short built-in prompt
+        #[arg(long, value_name = "FILE")]
+        prompt_file: Option<PathBuf>,
+        /// max_tokens for each request
+        #[arg(long, default_value_t = 256)]
+        max_tokens: u64,
+    },
+    /// Run a command with ANTHROPIC_BASE_URL pointed at croxy
+    Run {
+        /// Set ANTHROPIC_API_KEY for the child process
+        #[arg(long, value_name = "KEY")]
+        api_key: Option<String>,
+        /// Command and arguments to run
+        #[arg(trailing_var_arg = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+    /// Manage a systemd (Linux) or launchd (macOS) user service
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// Re-send a captured request and compare it with the original
+    Replay {
+        /// Request ID of the capture; lists recent captures when omitted
+        id: Option<String>,
+        /// Send to this provider instead of routing
+        #[arg(long)]
+        provider: Option<String>,
+        /// Replace the requested model
+        #[arg(long)]
+        model: Option<String>,
+    },
+    /// List past requests from the metrics log
+    History {
+        /// Only requests whose model contains this
+        #[arg(long)]
+        model: Option<String>,
+        /// Only this status, or class of status (e.g. 529 or 4xx)
+        #[arg(long, value_parser = croxy::history::parse_status)]
+        status: Option<croxy::history::StatusFilter>,
+        /// Only requests this recent (e.g. 30m, 24h, 7d)
+        #[arg(long, value_parser = croxy::history::parse_age, value_name = "AGE")]
+        since: Option<chrono::Duration>,
+        /// Most requests to list, newest kept
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Compare two windows of the metrics log per model or provider
+    Diff {
+        /// Length of each window (e.g. 30m, 1h, 1d)
+        #[arg(long, value_parser = croxy::history::parse_age, value_name = "AGE", default_value = "1h")]
+        window: chrono::Duration,
+        /// How long ago the later window ends; it ends now by default
+        #[arg(long, value_parser = croxy::history::parse_age, value_name = "AGE")]
+        ago: Option<chrono::Duration>,
+        /// How far before the later window the baseline is; it comes right
+        /// before it by default (e.g. 1d for the same hours yesterday)
+        #[arg(long, value_parser = croxy::history::parse_age, value_name = "AGE")]
+        baseline: Option<chrono::Duration>,
+        /// What to compare
+        #[arg(long, value_enum, default_value_t = croxy::diff::GroupBy::Model)]
+        by: croxy::diff::GroupBy,
+    },
+    /// Print everything the metrics log holds about one request
+    Show {
+        /// Request ID, as listed by `croxy history`
+        id: String,
+    },
+    /// Load the model behind a route now, so the next request doesn't wait
+    Warm {
+        /// Route name, or provider name for its default model
+        route: String,
+    },
+    /// Manage virtual API keys for clients of this instance
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyAction {
+    /// Create a key and print it (it is not shown again)
+    Create {
+        /// Name the key is listed and revoked by
+        #[arg(long)]
+        name: String,
+        /// Spend limit in USD (e.g. 5usd)
+        #[arg(long, value_parser = croxy::keys::parse_budget)]
+        budget: Option<f64>,
+        /// Requests allowed per minute
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        rpm: Option<u32>,
+        /// Comma-separated route or provider names the key may use
+        #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+        routes: Vec<String>,
+    },
+    /// List keys with their limits and spend
+    List,
+    /// Delete a key
+    Revoke { name: String },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Install and start the service for the current binary and config
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Show service status
+    Status,
 }
 
 #[derive(Subcommand)]
 enum ConfigAction {
-    /// Set a configuration value (dot-separated key)
+    /// Set a configuration value (dot-separated key; `routes.0.model` indexes
+    /// arrays, `routes[]` appends, and `{ ... }` values create tables)
     Set { key: String, value: String },
+    /// Append a [[routes]] entry
+    AddRoute {
+        /// Provider the route sends requests to
+        #[arg(long)]
+        provider: String,
+        /// Regex matched against the requested model
+        #[arg(long, required_unless_present = "description")]
+        pattern: Option<String>,
+        /// Model name to rewrite matching requests to
+        #[arg(long)]
+        model: Option<String>,
+        /// Route name, required for auto-routing
+        #[arg(long, requires = "description")]
+        name: Option<String>,
+        /// Description used by the auto-router
+        #[arg(long, requires = "name")]
+        description: Option<String>,
+    },
     /// Get a configuration value (dot-separated key)
     Get { key: String },
+    /// Remove a configuration value or table (dot-separated key)
+    Unset { key: String },
+    /// List all configuration values, including environment overrides
+    List,
+    /// Open the config file in $EDITOR and validate before saving
+    Edit,
     /// Print the config file path
     Path,
 }
 
+static INSTANCE: OnceLock<Option<String>> = OnceLock::new();
+
+fn instance() -> Option<&'static str> {
+    INSTANCE.get().and_then(|i| i.as_deref())
+}
+
+/// The `--profile` flag, if given.
+static PROFILE_ARG: OnceLock<Option<String>> = OnceLock::new();
+
+fn profile_arg() -> Option<&'static str> {
+    PROFILE_ARG.get().and_then(|p| p.as_deref())
+}
+
+/// The `--retention` flag, if given.
+static RETENTION_ARG: OnceLock<Option<u64>> = OnceLock::new();
+
+/// Config values set by command-line flags, which take precedence over the
+/// file and environment.
+fn cli_overrides() -> Figment {
+    match RETENTION_ARG.get().copied().flatten() {
+        Some(minutes) => Figment::new()
+            .merge(Serialized::default("retention.enabled", true))
+            .merge(Serialized::default("retention.minutes", minutes)),
+        None => Figment::new(),
+    }
+}
+
 fn config_dir() -> PathBuf {
     dirs::home_dir()
         .expect("could not determine home directory")
         .join(".config/croxy")
 }
 
-fn default_config_path() -> PathBuf {
-    config_dir().join("config.toml")
+/// Directory holding the config, pid file, and logs for an instance.
+fn instance_dir(name: Option<&str>) -> PathBuf {
+    match name {
+        Some(name) => config_dir().join("instances").join(name),
+        None => config_dir(),
+    }
+}
+
+fn state_dir() -> PathBuf {
+    instance_dir(instance())
 }
 
 fn pid_path() -> PathBuf {
-    config_dir().join("croxy.pid")
+    state_dir().join("croxy.pid")
 }
 
 fn log_path() -> PathBuf {
-    config_dir().join("croxy.log")
+    state_dir().join("croxy.log")
 }
 
-fn load_config(path: &PathBuf) -> Config {
+/// Socket attached TUIs read the daemon's metrics from.
+fn control_socket_path() -> PathBuf {
+    state_dir().join("control.sock")
+}
+
+/// Resolves the instance name from the CLI flag, falling back to
+/// `[instance] name` in the config file.
+fn resolve_instance(cli_instance: Option<String>, config_path: &PathBuf) -> Option<String> {
+    let name = cli_instance.or_else(|| {
+        Figment::new()
+            .merge(Toml::file(config_path))
+            .extract_inner::<String>("instance.name")
+            .ok()
+    })?;
+    if let Err(e) = croxy::config::validate_instance_name(&name) {
+        ExitStatus::Config.fail(e);
+    }
+    Some(name)
+}
+
+/// Defaults that differ per named instance, layered beneath the config file.
+fn instance_defaults() -> Figment {
+    let Some(name) = instance() else {
+        return Figment::new();
+    };
+    let metrics_path = state_dir().join("logs/metrics.jsonl");
+    let audit_path = state_dir().join("logs/audit.jsonl");
+    let crash_path = state_dir().join("crash");
     Figment::new()
-        .merge(Toml::file(path))
+        .merge(Serialized::default(
+            "server.port",
+            croxy::config::instance_port(name),
+        ))
+        .merge(Serialized::default(
+            "logging.metrics.path",
+            metrics_path.to_string_lossy().to_string(),
+        ))
+        .merge(Serialized::default(
+            "logging.audit.path",
+            audit_path.to_string_lossy().to_string(),
+        ))
+        .merge(Serialized::default(
+            "logging.crash.path",
+            crash_path.to_string_lossy().to_string(),
+        ))
+}
+
+fn load_config(path: &Path) -> Config {
+    let (config, warnings) = read_config(path).unwrap_or_else(|e| ExitStatus::Config.fail(e));
+    for warning in &warnings {
+        eprintln!("warning: {warning} in {}", path.display());
+    }
+    config
+}
+
+/// Loads and validates config, returning it with any validation warnings.
+/// Errors are formatted for display, one problem per line.
+fn read_config(path: &Path) -> Result<(Config, Vec<String>), String> {
+    let file =
+        croxy::config::config_figment(path).map_err(|e| format!("failed to load config: {e}"))?;
+    let env_profile = std::env::var("CROXY_PROFILE").ok();
+    let profile = croxy::config::select_profile(profile_arg(), env_profile.as_deref(), &file);
+    let merged = croxy::config::apply_profile(file.clone(), profile.as_deref())
+        .map_err(|e| format!("failed to load config: {e}"))?;
+    let merged = croxy::config::apply_route_defaults(merged);
+    let config: Config = instance_defaults()
+        .merge(merged)
         .merge(Env::prefixed("CROXY_").split("_"))
+        .merge(cli_overrides())
         .extract()
-        .unwrap_or_else(|e| {
-            eprintln!("failed to load config: {e}");
-            std::process::exit(1);
-        })
+        .map_err(|e| format!("failed to load config: {e}"))?;
+
+    // Without a config file there is nothing to validate yet; commands that
+    // need providers report that themselves.
+    if !path.exists() {
+        return Ok((config, Vec::new()));
+    }
+    let report = croxy::validate::validate(&file, &config);
+    if !report.is_ok() {
+        let mut message = String::new();
+        for warning in &report.warnings {
+            message.push_str(&format!("warning: {warning} in {}\n", path.display()));
+        }
+        message.push_str(&format!("invalid config {}:", path.display()));
+        for error in &report.errors {
+            message.push_str(&format!("\n  {error}"));
+        }
+        if config.strict && report.errors.iter().any(|e| e.starts_with("unknown key")) {
+            message.push_str("\nhint: set `strict = false` to only warn about unknown keys");
+        }
+        return Err(message);
+    }
+    Ok((config, report.warnings))
 }
 
 fn read_pid() -> Option<i32> {
@@ -113,6 +501,14 @@ fn remove_pid_file() {
     let _ = fs::remove_file(pid_path());
 }
 
+/// Removes the pid file only if it still names this process, so an instance
+/// that was taken over doesn't delete its successor's pid file.
+fn remove_own_pid_file() {
+    if read_pid() == i32::try_from(std::process::id()).ok() {
+        remove_pid_file();
+    }
+}
+
 fn write_pid_file() {
     let pid = std::process::id();
     fs::write(pid_path(), pid.to_string()).unwrap_or_else(|e| {
@@ -120,149 +516,825 @@ fn write_pid_file() {
     });
 }
 
-fn cmd_stop() {
-    match read_pid() {
+fn cmd_stop(json: bool) {
+    let stopped = match read_pid() {
         Some(pid) if pid_is_alive(pid) => {
             kill(Pid::from_raw(pid), Signal::SIGTERM).unwrap_or_else(|e| {
-                eprintln!("failed to send SIGTERM to {pid}: {e}");
-                std::process::exit(1);
+                ExitStatus::Failure.fail(format!("failed to send SIGTERM to {pid}: {e}"))
             });
             remove_pid_file();
-            eprintln!("stopped croxy (pid {pid})");
+            if !json {
+                eprintln!("stopped croxy (pid {pid})");
+            }
+            Some(pid)
         }
         Some(_) => {
             remove_pid_file();
-            eprintln!("croxy is not running (stale pid file removed)");
+            if !json {
+                eprintln!("croxy is not running (stale pid file removed)");
+            }
+            None
         }
         None => {
-            eprintln!("croxy is not running (no pid file)");
+            if !json {
+                eprintln!("croxy is not running (no pid file)");
+            }
+            None
+        }
+    };
+    if json {
+        print_json(serde_json::json!({"stopped": stopped.is_some(), "pid": stopped}));
+    }
+    if stopped.is_none() {
+        ExitStatus::NotRunning.exit();
+    }
+}
+
+/// Prints the running instance's status from its admin API: how close
+/// bodies come to `max_body_size`, and a larger limit when too many
+/// requests come near it, then each tenant's usage. Without the admin
+/// token, a virtual key in `ANTHROPIC_API_KEY` shows its own usage.
+async fn cmd_status(config_path: &Path, tenant: Option<&str>, json: bool) {
+    let config = load_config(config_path);
+    if !config.server.tcp {
+        ExitStatus::Config.fail("status requires the TCP listener ([server] tcp = true)");
+    }
+    let addr = config.server.client_addr();
+    let admin_token = croxy::secrets::resolve_admin_token(&config.admin)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(e));
+    let token = admin_token.or_else(|| {
+        std::env::var("ANTHROPIC_API_KEY")
+            .ok()
+            .filter(|key| key.starts_with(croxy::keys::KEY_PREFIX))
+    });
+    let status = control::remote_status(&addr, token.as_deref(), tenant)
+        .await
+        .unwrap_or_else(|e| ExitStatus::NotRunning.fail(e));
+    if json {
+        return print_json(serde_json::to_value(&status).unwrap_or_default());
+    }
+    match tenant {
+        Some(_) => println!("croxy {} on {addr}", status.version),
+        None => println!(
+            "croxy {} on {addr}, {} attached",
+            status.version, status.viewers
+        ),
+    }
+    if let Some(sizes) = status.body_sizes {
+        for line in sizes.lines() {
+            println!("{line}");
+        }
+        if let Some(hint) = sizes.hint {
+            println!("hint: {hint}");
+        }
+    }
+    for tenant in &status.tenants {
+        for line in tenant.lines() {
+            println!("{line}");
         }
     }
 }
 
-fn cmd_init() {
-    let dir = config_dir();
-    let path = dir.join("config.toml");
+/// Prints the result of a command run with `--json`.
+fn print_json(value: serde_json::Value) {
+    println!("{value:#}");
+}
 
-    if path.exists() {
-        eprintln!("config already exists: {}", path.display());
-        return;
+/// Where requests are captured and compared, and the audit log and
+/// scrubber for what croxy writes down.
+type Recorders = (
+    Option<Arc<CaptureStore>>,
+    Option<Arc<Comparer>>,
+    Arc<AuditLog>,
+    Arc<Scrubber>,
+);
+
+/// Proxy state for `config`, exiting if its provider settings can't be
+/// loaded.
+fn app_state(
+    config: &Config,
+    router: Router,
+    metrics: Arc<MetricsStore>,
+    keys: croxy::keys::KeyStore,
+    (captures, compare, audit, scrubber): Recorders,
+) -> AppState {
+    Server::builder(config)
+        .router(router)
+        .metrics(metrics)
+        .keys(keys)
+        .captures(captures)
+        .compare(compare)
+        .audit(audit)
+        .scrubber(scrubber)
+        .state()
+        .unwrap_or_else(|e| e.exit_status().fail(e))
+}
+
+/// Opens `[logging.audit]`; a log that can't be opened is a startup error,
+/// since running without the audit trail it asks for would be silent.
+fn open_audit_log(config: &Config) -> AuditLog {
+    AuditLog::from_config(&config.logging.audit).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!(
+            "failed to open audit log {}: {e}",
+            config.logging.audit.path
+        ));
+    })
+}
+
+fn capture_dir(config: &Config) -> PathBuf {
+    match config.capture.dir {
+        Some(ref dir) => croxy::secrets::expand_home(dir),
+        None => state_dir().join("captures"),
     }
+}
 
-    fs::create_dir_all(&dir).unwrap_or_else(|e| {
-        eprintln!("failed to create {}: {e}", dir.display());
-        std::process::exit(1);
+/// Lists the newest `limit` metrics log entries `filter` matches, oldest
+/// first.
+fn cmd_events(follow: bool, filter: Option<EventFilter>) {
+    let stream = UnixStream::connect(control_socket_path()).unwrap_or_else(|e| {
+        ExitStatus::NotRunning.fail(format!(
+            "failed to connect to {}: {e}",
+            control_socket_path().display()
+        ))
     });
+    let mut stdout = std::io::stdout().lock();
+    let mut print = |event: RequestEvent| {
+        if filter.is_some_and(|filter| !filter.matches(&event)) {
+            return Ok(());
+        }
+        let line = serde_json::to_string(&event).map_err(std::io::Error::other)?;
+        writeln!(stdout, "{line}")?;
+        stdout.flush()
+    };
+    let result = if follow {
+        control::follow_events(stream, print)
+    } else {
+        control::completed_events(std::io::BufReader::new(stream))
+            .and_then(|events| events.into_iter().try_for_each(&mut print))
+    };
+    match result {
+        // The reader went away, as `croxy events | head` does
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => ExitStatus::Failure.fail(format!("events: {e}")),
+        Ok(()) => {}
+    }
+}
 
-    let default_config = r#"[server]
-host = "127.0.0.1"
-port = 3100
-# max_body_size = 10485760  # 10 MiB
-
-[provider.anthropic]
-url = "https://api.anthropic.com"
-
-[provider.ollama]
-url = "http://localhost:11434"
-strip_auth = true
-api_key = "ollama"
-stub_count_tokens = true
-
-[[routes]]
-pattern = "opus"
-provider = "anthropic"
-
-[[routes]]
-pattern = "sonnet|haiku"
-provider = "ollama"
-model = "qwen2.5-coder:32b"
-
-[default]
-provider = "anthropic"
-
-# [auto_router]
-# enabled = true
-# url = "http://localhost:8080/v1/chat/completions"
-# model = "mlx-community/Arch-Router-1.5B-4bit"
-# timeout_ms = 5000
-
-# [retention]
-# enabled = true
-# minutes = 60
-
-# [logging.metrics]
-# enabled = true
-# path = "~/.config/croxy/logs/metrics.jsonl"
-# max_size_mb = 50
-# max_files = 5
-"#;
-
-    fs::write(&path, default_config).unwrap_or_else(|e| {
-        eprintln!("failed to write {}: {e}", path.display());
-        std::process::exit(1);
+/// Sends a pin or unpin to the running instance, then makes the same
+/// change to `[[pins]]` in `save_to`.
+fn cmd_pin(command: &control::Command, save_to: Option<&PathBuf>, json: bool) {
+    let stream = UnixStream::connect(control_socket_path()).unwrap_or_else(|e| {
+        ExitStatus::NotRunning.fail(format!(
+            "failed to connect to {}: {e}",
+            control_socket_path().display()
+        ))
     });
+    let reply = control::send_command(stream, command)
+        .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("{command}: {e}")));
+    if !reply.ok {
+        ExitStatus::Failure.fail(reply.message);
+    }
+    match (command, save_to) {
+        (control::Command::Pin { pattern, provider }, Some(path)) => {
+            cli_config::config_save_pin(path, pattern, Some(provider))
+        }
+        (control::Command::Unpin { pattern }, Some(path)) => {
+            cli_config::config_save_pin(path, pattern, None)
+        }
+        _ => {}
+    }
+    if json {
+        print_json(serde_json::json!({
+            "message": reply.message,
+            "pins": reply.routing.map(|routing| routing.pins).unwrap_or_default(),
+        }));
+    } else {
+        println!("{}", reply.message);
+    }
+}
+
+fn cmd_history(config_path: &Path, filter: &croxy::history::Filter, limit: usize, json: bool) {
+    let config = load_config(config_path);
+    let log = &config.logging.metrics;
+    let entries: Vec<_> = croxy::history::read(log)
+        .into_iter()
+        .filter(|entry| filter.matches(entry))
+        .collect();
+    let entries = &entries[entries.len().saturating_sub(limit)..];
+    if json {
+        print_json(serde_json::json!(entries));
+        return;
+    }
+    if entries.is_empty() {
+        eprintln!("no matching requests in {}", log.path);
+        if !log.enabled {
+            eprintln!("hint: set [logging.metrics] enabled = true and restart croxy");
+        }
+        return;
+    }
+    for entry in entries {
+        println!(
+            "{:<16} {}  {:<28} {:<12} {:<4} {:>8} {:>8} {:>8}",
+            entry.request_id.as_deref().unwrap_or("-"),
+            entry
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%m-%d %H:%M:%S"),
+            entry.model,
+            entry.provider,
+            entry.status,
+            format!("{}ms", entry.duration_ms),
+            entry.input_tokens,
+            entry.output_tokens,
+        );
+    }
+}
+
+/// Prints how each model's or provider's requests in `current` compare
+/// with those in `baseline`.
+fn cmd_diff(
+    config_path: &Path,
+    by: croxy::diff::GroupBy,
+    baseline: croxy::diff::Window,
+    current: croxy::diff::Window,
+    json: bool,
+) {
+    let config = load_config(config_path);
+    let log = &config.logging.metrics;
+    let groups = croxy::diff::diff(&croxy::history::read(log), by, baseline, current);
+    if json {
+        print_json(serde_json::json!({
+            "by": by.name(),
+            "baseline": baseline,
+            "current": current,
+            "groups": groups,
+        }));
+        return;
+    }
+    let span = |window: croxy::diff::Window| {
+        format!(
+            "{} to {}",
+            window
+                .from
+                .with_timezone(&chrono::Local)
+                .format("%m-%d %H:%M"),
+            window
+                .to
+                .with_timezone(&chrono::Local)
+                .format("%m-%d %H:%M")
+        )
+    };
+    println!("baseline {}, current {}", span(baseline), span(current));
+    if groups.is_empty() {
+        eprintln!("no requests in either window in {}", log.path);
+        if !log.enabled {
+            eprintln!("hint: set [logging.metrics] enabled = true and restart croxy");
+        }
+        return;
+    }
+    println!(
+        "{:<28} {:<12} {:<24} {:<24} {:<24} tokens/req",
+        by.name(),
+        "requests",
+        "p50",
+        "p95",
+        "errors"
+    );
+    for group in &groups {
+        let [requests, p50, p95, errors, tokens] = group.columns();
+        println!(
+            "{:<28} {requests:<12} {p50:<24} {p95:<24} {errors:<24} {tokens}",
+            group.name
+        );
+    }
+}
 
-    eprintln!("created {}", path.display());
+/// Prints request `id`'s metrics log entry, with its cost and capture.
+fn cmd_show(config_path: &Path, id: &str, json: bool) {
+    let config = load_config(config_path);
+    let Some(entry) = croxy::history::find(&config.logging.metrics, id) else {
+        eprintln!(
+            "error: no request '{id}' in {}",
+            config.logging.metrics.path
+        );
+        if !config.logging.metrics.enabled {
+            eprintln!("hint: set [logging.metrics] enabled = true and restart croxy");
+        }
+        ExitStatus::Failure.exit();
+    };
+    let cost = config
+        .providers
+        .get(&entry.provider)
+        .filter(|provider| croxy::pricing::is_billable_url(&provider.url))
+        .and_then(|_| {
+            croxy::pricing::estimate_cost_usd(&entry.model, entry.input_tokens, entry.output_tokens)
+        });
+    let capture = Some(capture_dir(&config).join(format!("{id}.json"))).filter(|p| p.exists());
+
+    if json {
+        let mut value = serde_json::to_value(&entry).expect("history entry serializes");
+        value["error_category"] = serde_json::json!(entry.error_category());
+        value["cost_usd"] = serde_json::json!(cost);
+        value["capture"] = serde_json::json!(capture);
+        print_json(value);
+        return;
+    }
+    let or_dash = |value: Option<&str>| value.unwrap_or("-").to_string();
+    let mut lines = vec![
+        ("request", id.to_string()),
+        (
+            "time",
+            entry
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S%.3f")
+                .to_string(),
+        ),
+        ("model", entry.model.clone()),
+        ("provider", entry.provider.clone()),
+        ("method", or_dash(entry.routing_method.as_deref())),
+        ("listener", or_dash(entry.listener.as_deref())),
+        ("client", or_dash(entry.client.as_deref())),
+        (
+            "status",
+            match entry.error_category() {
+                Some(category) => format!("{} ({category})", entry.status),
+                None => entry.status.to_string(),
+            },
+        ),
+        ("duration", format!("{}ms", entry.duration_ms)),
+        (
+            "timing",
+            or_dash(entry.timings.describe(entry.duration_ms).as_deref()),
+        ),
+        (
+            "tokens",
+            format!("{} in, {} out", entry.input_tokens, entry.output_tokens),
+        ),
+        (
+            "cost",
+            cost.map_or("-".to_string(), |usd| format!("${usd:.4}")),
+        ),
+    ];
+    if let Some(size) = entry.batch_size {
+        lines.push(("batch", format!("{size} requests")));
+    }
+    if entry.redactions > 0 {
+        lines.push(("redactions", entry.redactions.to_string()));
+    }
+    if let Some(ref cutoff) = entry.cutoff {
+        lines.push(("cutoff", cutoff.clone()));
+    }
+    if let Some(ref chaos) = entry.chaos {
+        lines.push(("chaos", chaos.clone()));
+    }
+    if let Some(bytes) = entry.client_aborted {
+        lines.push(("aborted", format!("client went away after {bytes} bytes")));
+    }
+    if let Some(ref error) = entry.error {
+        lines.push(("error", error.clone()));
+    }
+    lines.push((
+        "capture",
+        capture.map_or("-".to_string(), |p| p.display().to_string()),
+    ));
+    for (label, value) in lines {
+        println!("{:<12} {value}", format!("{label}:"));
+    }
 }
 
-fn cmd_shellenv(config_path: &PathBuf) {
+/// Replays a captured request through this config's routing, or lists
+/// recent captures when no ID is given.
+async fn cmd_replay(
+    config_path: &Path,
+    id: Option<&str>,
+    provider: Option<&str>,
+    model: Option<&str>,
+    json: bool,
+) {
     let config = load_config(config_path);
-    let host = match config.server.host.as_str() {
-        "0.0.0.0" => "127.0.0.1",
-        "::" => "::1",
-        other => other,
+    let dir = capture_dir(&config);
+    let Some(id) = id else {
+        let captures = croxy::capture::recent(&dir, 20);
+        if json {
+            let list: Vec<_> = captures
+                .iter()
+                .map(|capture| {
+                    let outcome = capture.outcome.as_ref();
+                    serde_json::json!({
+                        "id": capture.id,
+                        "timestamp": capture.timestamp,
+                        "model": capture.model(),
+                        "provider": outcome.map(|o| &o.provider),
+                        "status": outcome.map(|o| o.status),
+                    })
+                })
+                .collect();
+            print_json(serde_json::Value::Array(list));
+            return;
+        }
+        if captures.is_empty() {
+            eprintln!("no captures in {}", dir.display());
+            if !config.capture.enabled {
+                eprintln!("hint: set [capture] enabled = true and restart croxy");
+            }
+        }
+        for capture in captures {
+            let outcome = capture.outcome.as_ref();
+            println!(
+                "{:<16} {}  {:<28} {:<12} {}",
+                capture.id,
+                capture
+                    .timestamp
+                    .with_timezone(&chrono::Local)
+                    .format("%m-%d %H:%M:%S"),
+                capture.model(),
+                outcome.map_or("-", |o| o.provider.as_str()),
+                outcome.map_or("-".to_string(), |o| o.status.to_string()),
+            );
+        }
+        return;
     };
-    let addr = format!("{host}:{}", config.server.port);
+    let capture = croxy::capture::load(&dir, id).unwrap_or_else(|e| ExitStatus::Failure.fail(e));
 
-    if TcpStream::connect(&addr).is_ok() {
-        println!("export ANTHROPIC_BASE_URL=http://{addr}");
+    let router = Router::from_config(&config)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(format!("failed to build router: {e}")));
+    if let Some(provider) = provider
+        && let Err(e) = router.force_provider(Some(provider))
+    {
+        e.exit_status().fail(e);
+    }
+    // Replays are kept out of the daemon's logs and captures
+    let state = Server::builder(&config)
+        .router(router)
+        .captures(None)
+        .compare(None)
+        .audit(Arc::new(AuditLog::disabled()))
+        .access_log(Arc::new(AccessLog::disabled()))
+        .scrubber(Arc::new(Scrubber::default()))
+        .state()
+        .unwrap_or_else(|e| e.exit_status().fail(e));
+    let state = Arc::new(state);
+    let api_key = std::env::var("ANTHROPIC_API_KEY").ok();
+
+    if !json {
+        eprintln!(
+            "replaying {} {} {} ({})",
+            capture.id,
+            capture.method,
+            capture.path,
+            model.unwrap_or(capture.model())
+        );
+    }
+    match croxy::replay::replay(state, &capture, model, api_key.as_deref()).await {
+        Ok(outcome) if json => print_json(serde_json::json!({
+            "id": capture.id,
+            "original": capture.outcome,
+            "replay": outcome,
+        })),
+        Ok(outcome) => print!(
+            "{}",
+            croxy::replay::diff(capture.outcome.as_ref(), &outcome)
+        ),
+        Err(e) => {
+            ExitStatus::Provider.fail(e);
+        }
     }
 }
 
-fn detach(config_path: &PathBuf, verbose: bool) {
+/// Pings the provider behind a route or provider name to load its model,
+/// and reports how long that took.
+async fn cmd_warm(config_path: &Path, name: &str, json: bool) {
+    let config = load_config(config_path);
+    let router = Router::from_config(&config)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(format!("failed to build router: {e}")));
+    let Some(route) = router.named_route(name) else {
+        ExitStatus::Failure.fail(format!("no route or provider named '{name}'"));
+    };
+    let provider = &route.provider_name;
+    if !matches!(route.api_format, ApiFormat::Anthropic | ApiFormat::Ollama) {
+        ExitStatus::Failure.fail(format!(
+            "{provider} uses api_format \"{}\"; warm-up pings need \"anthropic\" or \"ollama\"",
+            route.api_format
+        ));
+    }
+    let Some(model) = route
+        .model_rewrite
+        .clone()
+        .or_else(|| croxy::warm::model_for(&config, provider))
+    else {
+        ExitStatus::Config.fail(format!(
+            "no model to warm for '{name}': set provider.{provider}.warm_model"
+        ));
+    };
+    let clients =
+        croxy::clients::provider_clients(&config).unwrap_or_else(|e| ExitStatus::Provider.fail(e));
+    let client = clients
+        .get(provider)
+        .cloned()
+        .unwrap_or_else(croxy::clients::default_client);
+    let interval = config.providers[provider]
+        .warm_interval_secs
+        .map(std::time::Duration::from_secs);
+
+    if !json {
+        eprintln!("warming {provider}/{model}");
+    }
+    let warmth =
+        croxy::warm::ping(&client, &route, &model, croxy::warm::keep_alive(interval)).await;
+    if let Some(e) = warmth.error {
+        ExitStatus::Provider.fail(format!("warm-up failed: {e}"));
+    }
+    if json {
+        print_json(serde_json::json!({
+            "provider": provider,
+            "model": model,
+            "was_loaded": warmth.was_loaded,
+            "duration_ms": warmth.duration_ms,
+        }));
+        return;
+    }
+    let elapsed = format!("{:.1}s", warmth.duration_ms as f64 / 1000.0);
+    match warmth.was_loaded {
+        Some(true) => println!("{provider}/{model} was already loaded ({elapsed})"),
+        Some(false) => println!("{provider}/{model} loaded in {elapsed}"),
+        None => println!("{provider}/{model} answered in {elapsed}"),
+    }
+}
+
+fn keys_path() -> PathBuf {
+    state_dir().join("keys.json")
+}
+
+fn cmd_key(config_path: &Path, action: KeyAction, json: bool) {
+    let path = keys_path();
+    let audit = || open_audit_log(&load_config(config_path));
+    let result = match action {
+        KeyAction::Create {
+            name,
+            budget,
+            rpm,
+            routes,
+        } => {
+            let audit = audit();
+            let event = AuditEvent::KeyCreated {
+                key: name.clone(),
+                budget_usd: budget,
+                requests_per_minute: rpm,
+                routes: routes.clone(),
+            };
+            croxy::keys::create(
+                &path,
+                croxy::keys::NewKey {
+                    name: name.clone(),
+                    budget_usd: budget,
+                    requests_per_minute: rpm,
+                    routes,
+                },
+            )
+            .map(|key| {
+                audit.record(event);
+                if json {
+                    print_json(serde_json::json!({"name": name, "key": key}));
+                    return;
+                }
+                println!("{key}");
+                eprintln!("store this key now; croxy keeps only its hash");
+            })
+        }
+        KeyAction::List => croxy::keys::list(&path).map(|keys| {
+            if json {
+                let usage = croxy::keys::read_usage(&path);
+                let list: Vec<_> = keys
+                    .iter()
+                    .map(|key| {
+                        serde_json::json!({
+                            "name": key.name,
+                            "hint": key.hint,
+                            "spent_usd": usage.get(&key.name).copied().unwrap_or(0.0),
+                            "budget_usd": key.budget_usd,
+                            "requests_per_minute": key.requests_per_minute,
+                            "routes": key.routes,
+                        })
+                    })
+                    .collect();
+                print_json(serde_json::Value::Array(list));
+                return;
+            }
+            if keys.is_empty() {
+                eprintln!("no keys (create one with `croxy key create --name NAME`)");
+                return;
+            }
+            let usage = croxy::keys::read_usage(&path);
+            for key in keys {
+                let spent = usage.get(&key.name).copied().unwrap_or(0.0);
+                let budget = key
+                    .budget_usd
+                    .map_or(String::new(), |b| format!(" / ${b:.2}"));
+                let rpm = key
+                    .requests_per_minute
+                    .map_or(String::new(), |n| format!("  {n}/min"));
+                let routes = if key.routes.is_empty() {
+                    String::new()
+                } else {
+                    format!("  routes: {}", key.routes.join(","))
+                };
+                println!(
+                    "{:<16} {}  ${spent:.2}{budget}{rpm}{routes}",
+                    key.name, key.hint
+                );
+            }
+        }),
+        KeyAction::Revoke { name } => {
+            let audit = audit();
+            croxy::keys::revoke(&path, &name).map(|()| {
+                audit.record(AuditEvent::KeyRevoked { key: name.clone() });
+                if json {
+                    print_json(serde_json::json!({ "revoked": name }));
+                } else {
+                    eprintln!("revoked key '{name}'");
+                }
+            })
+        }
+    };
+    if let Err(e) = result {
+        ExitStatus::Failure.fail(e);
+    }
+}
+
+fn cmd_init(config_path: &Path, template: Template, force: bool, stdout: bool, json: bool) {
+    let content = template.content();
+    let name =
+        clap::ValueEnum::to_possible_value(&template).map(|value| value.get_name().to_string());
+    if stdout {
+        if json {
+            print_json(serde_json::json!({"template": name, "content": content}));
+        } else {
+            print!("{content}");
+        }
+        return;
+    }
+
+    if config_path.exists() && !force {
+        if json {
+            print_json(serde_json::json!({"path": config_path, "created": false}));
+            return;
+        }
+        eprintln!("config already exists: {}", config_path.display());
+        eprintln!("hint: use --force to overwrite it or --stdout to print the template");
+        return;
+    }
+
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir).unwrap_or_else(|e| {
+            ExitStatus::Failure.fail(format!("failed to create {}: {e}", dir.display()))
+        });
+    }
+
+    fs::write(config_path, content).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", config_path.display()))
+    });
+
+    if json {
+        print_json(serde_json::json!({"path": config_path, "created": true, "template": name}));
+    } else {
+        eprintln!("created {}", config_path.display());
+    }
+}
+
+/// Runs the setup wizard when there is no config yet and someone is at
+/// the terminal to answer it.
+async fn first_run(config_path: &Path) {
+    use std::io::IsTerminal;
+    if config_path.exists() || !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return;
+    }
+    let models = croxy::setup::probe_ollama(croxy::setup::OLLAMA_URL).await;
+    let mut wizard = croxy::setup::Wizard::new(std::io::stdin().lock(), std::io::stderr());
+    let answers = wizard.answers(models.as_deref()).unwrap_or_else(|e| {
+        eprintln!();
+        ExitStatus::Failure.fail(e);
+    });
+
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir).unwrap_or_else(|e| {
+            ExitStatus::Failure.fail(format!("failed to create {}: {e}", dir.display()))
+        });
+    }
+    fs::write(config_path, croxy::setup::render(&answers)).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", config_path.display()))
+    });
+    eprintln!("\ncreated {}", config_path.display());
+
+    let config = load_config(config_path);
+    if wizard
+        .confirm("Print the line that points Claude Code at croxy?")
+        .unwrap_or(false)
+    {
+        println!("export ANTHROPIC_BASE_URL={}", config.server.base_url());
+        eprintln!(
+            "hint: add `eval \"$(croxy shellenv)\"` to your shell profile to set it whenever croxy runs"
+        );
+    }
+}
+
+/// Whether a croxy instance is accepting connections on the configured
+/// listener (TCP when enabled, otherwise the unix socket).
+fn is_accepting(config: &Config) -> bool {
+    if config.server.tcp {
+        TcpStream::connect(config.server.client_addr()).is_ok()
+    } else if let Some(ref socket) = config.server.socket {
+        UnixStream::connect(socket).is_ok()
+    } else {
+        false
+    }
+}
+
+fn cmd_shellenv(config_path: &Path, json: bool) {
+    let config = load_config(config_path);
+
+    if json {
+        let running = is_accepting(&config);
+        let env = if running {
+            serde_json::json!({"ANTHROPIC_BASE_URL": config.server.base_url()})
+        } else {
+            serde_json::json!({})
+        };
+        print_json(serde_json::json!({"running": running, "instance": instance(), "env": env}));
+        return;
+    }
+    if is_accepting(&config) {
+        if let Some(name) = instance() {
+            println!("# croxy instance: {name}");
+        }
+        println!("export ANTHROPIC_BASE_URL={}", config.server.base_url());
+    }
+}
+
+fn detach(
+    config_path: &PathBuf,
+    verbose: bool,
+    log_format: Option<LogFormat>,
+    takeover: bool,
+    json: bool,
+) {
+    let mut previous = None;
     if let Some(pid) = read_pid() {
         if pid_is_alive(pid) {
-            eprintln!("croxy is already running (pid {pid})");
-            std::process::exit(1);
+            if !takeover {
+                eprintln!("error: croxy is already running (pid {pid})");
+                eprintln!("hint: use `croxy start --takeover` to replace it");
+                ExitStatus::AlreadyRunning.exit();
+            }
+            previous = Some(pid);
+        } else {
+            remove_pid_file();
         }
-        remove_pid_file();
     }
 
     let config = load_config(config_path);
-    let host = match config.server.host.as_str() {
-        "0.0.0.0" => "127.0.0.1",
-        "::" => "::1",
-        other => other,
-    };
-    let probe_addr = format!("{host}:{}", config.server.port);
 
-    let dir = config_dir();
+    let dir = state_dir();
     fs::create_dir_all(&dir).unwrap_or_else(|e| {
-        eprintln!("failed to create {}: {e}", dir.display());
-        std::process::exit(1);
+        ExitStatus::Failure.fail(format!("failed to create {}: {e}", dir.display()))
     });
 
-    let log = fs::File::create(log_path()).unwrap_or_else(|e| {
-        eprintln!("failed to create log file: {e}");
-        std::process::exit(1);
-    });
+    // Append: the daemon rotates croxy.log itself; this handle only catches
+    // output that bypasses tracing (e.g. panics).
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())
+        .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("failed to open log file: {e}")));
     let log_err = log.try_clone().unwrap();
 
     let exe = std::env::current_exe().unwrap_or_else(|e| {
-        eprintln!("failed to determine executable path: {e}");
-        std::process::exit(1);
+        ExitStatus::Failure.fail(format!("failed to determine executable path: {e}"))
     });
 
-    let devnull = fs::File::open("/dev/null").unwrap_or_else(|e| {
-        eprintln!("failed to open /dev/null: {e}");
-        std::process::exit(1);
-    });
+    let devnull = fs::File::open("/dev/null")
+        .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("failed to open /dev/null: {e}")));
 
     let mut cmd = Command::new(exe);
-    cmd.arg("--config").arg(config_path);
+    cmd.arg("--daemon").arg("--config").arg(config_path);
+    if let Some(name) = instance() {
+        cmd.arg("--instance").arg(name);
+    }
+    if let Some(name) = profile_arg() {
+        cmd.arg("--profile").arg(name);
+    }
+    if let Some(minutes) = RETENTION_ARG.get().copied().flatten() {
+        cmd.arg("--retention").arg(minutes.to_string());
+    }
     if verbose {
         cmd.arg("--verbose");
     }
+    if log_format == Some(LogFormat::Json) {
+        cmd.arg("--log-format").arg("json");
+    }
+    if let Some(pid) = previous {
+        cmd.arg("--takeover-from").arg(pid.to_string());
+    }
     cmd.stdin(devnull);
 
     // Create new session so child survives terminal close
@@ -275,32 +1347,49 @@ fn detach(config_path: &PathBuf, verbose: bool) {
     }
 
     let mut child = cmd.stdout(log).stderr(log_err).spawn().unwrap_or_else(|e| {
-        eprintln!("failed to spawn detached process: {e}");
-        std::process::exit(1);
+        ExitStatus::Failure.fail(format!("failed to spawn detached process: {e}"))
     });
 
     let child_pid = child.id();
 
     // Detach: we don't want to wait on the child (it's the daemon).
-    // Reap it so we don't leave a zombie during the brief startup check.
+    // Reap it so we don't leave a zombie during the brief startup check,
+    // and keep its exit status in case it fails to start.
+    let (exited_tx, exited) = std::sync::mpsc::channel();
     std::thread::spawn(move || {
-        let _ = child.wait();
+        let _ = exited_tx.send(child.wait().ok().and_then(|status| status.code()));
     });
 
-    fs::write(pid_path(), child_pid.to_string()).unwrap_or_else(|e| {
-        eprintln!("failed to write pid file: {e}");
-        std::process::exit(1);
-    });
+    fs::write(pid_path(), child_pid.to_string())
+        .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("failed to write pid file: {e}")));
+
+    if let Some(old_pid) = previous {
+        return await_takeover(&config, (child_pid, &exited), old_pid, json);
+    }
+    let started = |accepting: bool| {
+        print_json(serde_json::json!({
+            "pid": child_pid,
+            "accepting": accepting,
+            "base_url": config.server.base_url(),
+            "log": log_path(),
+        }));
+    };
 
     // Poll until the daemon is accepting connections or the process dies
     let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
     loop {
         if !pid_is_alive(i32::try_from(child_pid).expect("invalid pid")) {
             remove_pid_file();
-            eprintln!("croxy failed to start, check {}", log_path().display());
-            std::process::exit(1);
+            eprintln!(
+                "error: croxy failed to start, check {}",
+                log_path().display()
+            );
+            std::process::exit(daemon_exit_code(&exited));
         }
-        if TcpStream::connect(&probe_addr).is_ok() {
+        if is_accepting(&config) {
+            if json {
+                return started(true);
+            }
             eprintln!(
                 "croxy started (pid {child_pid}), log: {}",
                 log_path().display()
@@ -308,6 +1397,9 @@ fn detach(config_path: &PathBuf, verbose: bool) {
             return;
         }
         if std::time::Instant::now() >= deadline {
+            if json {
+                return started(false);
+            }
             eprintln!(
                 "croxy started (pid {child_pid}) but not yet accepting connections, log: {}",
                 log_path().display()
@@ -318,28 +1410,413 @@ fn detach(config_path: &PathBuf, verbose: bool) {
     }
 }
 
-fn run_attached(config_path: &PathBuf) {
+/// The status a daemon that failed to start exited with, so `start` can
+/// exit with it too.
+fn daemon_exit_code(exited: &std::sync::mpsc::Receiver<Option<i32>>) -> i32 {
+    exited
+        .recv_timeout(std::time::Duration::from_secs(1))
+        .ok()
+        .flatten()
+        .unwrap_or(ExitStatus::Failure.code())
+}
+
+/// Waits for the new daemon to bind and the old one to finish draining.
+fn await_takeover(
+    config: &Config,
+    (child_pid, exited): (u32, &std::sync::mpsc::Receiver<Option<i32>>),
+    old_pid: i32,
+    json: bool,
+) {
+    let child = i32::try_from(child_pid).expect("invalid pid");
+    let deadline = std::time::Instant::now()
+        + std::time::Duration::from_secs(config.server.drain_timeout_secs + 5);
+    let took_over = |drained: bool| {
+        print_json(serde_json::json!({
+            "pid": child_pid,
+            "previous_pid": old_pid,
+            "drained": drained,
+            "base_url": config.server.base_url(),
+            "log": log_path(),
+        }));
+    };
+    if !json {
+        eprintln!("taking over from pid {old_pid}, waiting for it to drain...");
+    }
+    loop {
+        if !pid_is_alive(child) {
+            let _ = fs::write(pid_path(), old_pid.to_string());
+            eprintln!(
+                "error: croxy failed to start, pid {old_pid} left running, check {}",
+                log_path().display()
+            );
+            std::process::exit(daemon_exit_code(exited));
+        }
+        if !pid_is_alive(old_pid) {
+            if json {
+                return took_over(true);
+            }
+            eprintln!(
+                "croxy took over (pid {child_pid}), log: {}",
+                log_path().display()
+            );
+            return;
+        }
+        if std::time::Instant::now() >= deadline {
+            if json {
+                return took_over(false);
+            }
+            eprintln!("croxy started (pid {child_pid}); pid {old_pid} is still draining");
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+/// Prints how `model` resolved, one field per line.
+fn print_route(router: &Router, model: &str, route: &croxy::router::ResolvedRoute) {
+    println!("model:       {model}");
+    println!("method:      {}", route.routing_method);
+    println!(
+        "provider:    {} ({})",
+        route.provider_name, route.provider_url
+    );
+    if let Some(ref group) = route.group {
+        let members = router.group_members(group).unwrap_or_default();
+        println!("group:       {group} ({})", members.join(", "));
+    }
+    println!(
+        "rewrite:     {}",
+        route.model_rewrite.as_deref().unwrap_or("-")
+    );
+    println!("strip_auth:  {}", route.strip_auth);
+    println!(
+        "api_key:     {}",
+        if route.api_key.is_set() { "set" } else { "-" }
+    );
+    println!("stub_counts: {}", route.stub_count_tokens);
+    println!("api_format:  {}", route.api_format);
+}
+
+async fn cmd_test_route(
+    config_path: &Path,
+    model: &str,
+    message: &str,
+    user_id: Option<&str>,
+    send: bool,
+    json: bool,
+) {
     let config = load_config(config_path);
+    let router = Router::from_config(&config)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(format!("failed to build router: {e}")));
+    let client = croxy::clients::default_client();
+
+    let messages = vec![serde_json::json!({"role": "user", "content": message})];
+    let mut body = serde_json::json!({
+        "model": model,
+        "max_tokens": 16,
+        "messages": messages,
+    });
+    if let Some(user_id) = user_id {
+        body["metadata"] = serde_json::json!({"user_id": user_id});
+    }
+    let route = router
+        .resolve(
+            model,
+            croxy::router::Endpoint::Messages,
+            croxy::router::RequestFields::of(Some(&body)),
+            Some(&messages),
+            &client,
+        )
+        .await;
+
+    let mut result = serde_json::json!({
+        "model": model,
+        "method": route.routing_method.to_string(),
+        "provider": route.provider_name,
+        "provider_url": route.provider_url,
+        "group": route.group,
+        "group_members": route.group.as_deref().and_then(|g| router.group_members(g)),
+        "rewrite": route.model_rewrite,
+        "strip_auth": route.strip_auth,
+        "api_key": route.api_key.is_set(),
+        "stub_count_tokens": route.stub_count_tokens,
+        "api_format": route.api_format.to_string(),
+    });
+    if json && !send {
+        return print_json(result);
+    }
+    if !json {
+        print_route(&router, model, &route);
+    }
+    if !send {
+        return;
+    }
+
+    if !config.server.tcp {
+        ExitStatus::Config.fail("--send requires the TCP listener ([server] tcp = true)");
+    }
+    let addr = config.server.client_addr();
+    if TcpStream::connect(&addr).is_err() {
+        ExitStatus::NotRunning.fail(format!(
+            "croxy is not accepting connections on {addr}, start it first"
+        ));
+    }
+
+    let mut request = client
+        .post(format!("http://{addr}/v1/messages"))
+        .header("anthropic-version", "2023-06-01")
+        .json(&body);
+    if let Ok(key) = std::env::var("ANTHROPIC_API_KEY") {
+        request = request.header("x-api-key", key);
+    }
+
+    let start = std::time::Instant::now();
+    match request.send().await {
+        Ok(response) => {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            let latency_ms = start.elapsed().as_millis() as u64;
+            let preview: String = body.chars().take(200).collect();
+            if json {
+                result["response"] = serde_json::json!({
+                    "status": status.as_u16(),
+                    "latency_ms": latency_ms,
+                    "error": (!status.is_success()).then_some(&preview),
+                });
+                print_json(result);
+            } else {
+                println!();
+                println!("status:      {status}");
+                println!("latency:     {latency_ms}ms");
+                if !status.is_success() {
+                    println!("error:       {preview}");
+                }
+            }
+            if !status.is_success() {
+                ExitStatus::Provider.exit();
+            }
+        }
+        Err(e) => {
+            ExitStatus::Provider.fail(format!("request failed: {e}"));
+        }
+    }
+}
 
-    if !config.logging.metrics.enabled {
-        eprintln!("cannot attach: [logging.metrics] enabled = true required in config");
-        std::process::exit(1);
+/// Drives `options` through the running daemon and prints latency,
+/// time to first token, and throughput for each provider that served it.
+async fn cmd_bench(config_path: &Path, options: croxy::bench::Options, json: bool) {
+    let config = load_config(config_path);
+    if !config.server.tcp {
+        ExitStatus::Config.fail("croxy bench requires the TCP listener ([server] tcp = true)");
+    }
+    let addr = config.server.client_addr();
+    if TcpStream::connect(&addr).is_err() {
+        ExitStatus::NotRunning.fail(format!(
+            "croxy is not accepting connections on {addr}, start it first"
+        ));
+    }
+    let client = croxy::clients::default_client();
+    let api_key = std::env::var("ANTHROPIC_API_KEY").ok();
+    let run_id = croxy::bench::run_id();
+
+    if !json {
+        eprintln!(
+            "{run_id}: {} requests for {}, {} at a time",
+            options.requests, options.model, options.concurrency
+        );
     }
+    let start = std::time::Instant::now();
+    let samples = croxy::bench::run(
+        &client,
+        &format!("http://{addr}/v1/messages"),
+        api_key.as_deref(),
+        &run_id,
+        &options,
+    )
+    .await;
+    let elapsed = start.elapsed();
+    let reports = croxy::bench::report(&samples, elapsed);
+    let failed = samples.iter().filter(|s| s.error.is_some()).count();
+    if json {
+        print_json(serde_json::json!({
+            "run": run_id,
+            "model": options.model,
+            "concurrency": options.concurrency,
+            "requests": samples.len(),
+            "errors": failed,
+            "duration_ms": elapsed.as_millis() as u64,
+            "providers": reports,
+        }));
+    } else {
+        let ms = |ms: u64| croxy::tui::views::format_duration(std::time::Duration::from_millis(ms));
+        let ttft = |p: Option<u64>| p.map_or("-".to_string(), ms);
+        println!(
+            "{:<16} {:<10} {:<8} {:<10} {:<10} {:<10} {:<10} {:<10} {:<8} tok/s",
+            "provider", "requests", "errors", "p50", "p95", "p99", "ttft p50", "ttft p95", "req/s"
+        );
+        for r in &reports {
+            println!(
+                "{:<16} {:<10} {:<8} {:<10} {:<10} {:<10} {:<10} {:<10} {:<8.2} {:.1}",
+                r.provider,
+                r.requests,
+                r.errors,
+                ms(r.p50_ms),
+                ms(r.p95_ms),
+                ms(r.p99_ms),
+                ttft(r.ttft_p50_ms),
+                ttft(r.ttft_p95_ms),
+                r.requests_per_sec,
+                r.tokens_per_sec,
+            );
+        }
+        println!();
+        println!(
+            "{} requests in {:.1}s, {failed} failed",
+            samples.len(),
+            elapsed.as_secs_f64()
+        );
+        if let Some(error) = samples.iter().find_map(|s| s.error.as_deref()) {
+            println!("first error: {error}");
+        }
+    }
+    if failed == samples.len() {
+        ExitStatus::Provider.exit();
+    }
+}
 
-    let retention = retention_duration(&config);
-    let metrics = Arc::new(MetricsStore::new(retention));
+fn cmd_run(config_path: &PathBuf, verbose: bool, api_key: Option<String>, command: &[String]) {
+    let config = load_config(config_path);
 
-    attach::load_history(&config.logging.metrics, &metrics);
+    let running = read_pid().is_some_and(pid_is_alive) || is_accepting(&config);
+    if !running {
+        detach(config_path, verbose, None, false, false);
+    }
+
+    let session_start = chrono::Utc::now();
 
-    let log_path = PathBuf::from(&config.logging.metrics.path);
+    let mut child = Command::new(&command[0]);
+    child
+        .args(&command[1..])
+        .env("ANTHROPIC_BASE_URL", config.server.base_url());
+    if let Some(ref key) = api_key {
+        child.env("ANTHROPIC_API_KEY", key);
+    }
+
+    // Let the child own Ctrl-C while we wait so the summary still prints.
+    // SAFETY: only async-signal-safe calls (signal) run in pre_exec.
+    unsafe {
+        let _ = nix::sys::signal::signal(Signal::SIGINT, nix::sys::signal::SigHandler::SigIgn);
+        child.pre_exec(|| {
+            nix::sys::signal::signal(Signal::SIGINT, nix::sys::signal::SigHandler::SigDfl)
+                .map_err(std::io::Error::other)?;
+            Ok(())
+        });
+    }
+
+    let status = child.status().unwrap_or_else(|e| {
+        eprintln!("failed to run {}: {e}", command[0]);
+        std::process::exit(127);
+    });
+
+    print_session_summary(session_start, api_key.as_deref());
+
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Prints totals for the requests the daemon served since `session_start`,
+/// only counting those made with `api_key` when it is a virtual key.
+fn print_session_summary(session_start: chrono::DateTime<chrono::Utc>, api_key: Option<&str>) {
+    // Give in-flight stream finalizations a moment to reach the store.
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    let snapshot = UnixStream::connect(control_socket_path())
+        .and_then(|stream| control::snapshot_records(std::io::BufReader::new(stream)));
+    let (records, billable) = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("croxy: no session summary: {e}");
+            return;
+        }
+    };
+    let client = api_key.and_then(|key| {
+        croxy::keys::KeyStore::open(keys_path(), false)
+            .identify(key)
+            .map(|key| key.name)
+    });
+    let records: Vec<_> = records
+        .into_iter()
+        .filter(|r| r.wallclock >= session_start && r.chaos.is_none() && r.bench.is_none())
+        .filter(|r| client.is_none() || r.client == client)
+        .collect();
+    eprintln!("{}", SessionSummary::from_records(&records, &billable));
+}
+
+fn attached_store(config: &Config) -> Arc<MetricsStore> {
+    let metrics = Arc::new(MetricsStore::new(config.retention.duration()));
+    apply_display_window(&metrics, config);
+    metrics
+}
+
+fn run_attached(config_path: &Path) {
+    let config = load_config(config_path);
+    let metrics = attached_store(&config);
     let stop = Arc::new(AtomicBool::new(false));
 
-    let tail_store = metrics.clone();
-    let tail_stop = stop.clone();
-    let _tail_handle = std::thread::spawn(move || {
-        attach::tail_log(&log_path, tail_store, tail_stop);
+    let mut commands = None;
+    match UnixStream::connect(control_socket_path()) {
+        Ok(stream) => {
+            commands = Some(
+                control::attach_local(stream, metrics.clone())
+                    .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("cannot attach: {e}"))),
+            );
+        }
+        // Daemons predating the control socket: rebuild from the metrics log.
+        Err(_) if config.logging.metrics.enabled => {
+            attach::load_history(&config.logging.metrics, &metrics);
+            let log_path = PathBuf::from(&config.logging.metrics.path);
+            let tail_store = metrics.clone();
+            let tail_stop = stop.clone();
+            let _tail_handle = std::thread::spawn(move || {
+                attach::tail_log(&log_path, tail_store, tail_stop);
+            });
+        }
+        Err(e) => {
+            ExitStatus::NotRunning.fail(format!(
+                "cannot attach: failed to connect to {}: {e}",
+                control_socket_path().display()
+            ));
+        }
+    }
+
+    run_attached_tui(metrics, commands, &config, stop);
+}
+
+/// Attaches to a daemon on another machine through its admin API.
+async fn run_attached_remote(config_path: &Path, host: &str, token: Option<String>) {
+    let config = load_config(config_path);
+    let metrics = attached_store(&config);
+
+    let response = control::connect_remote(host, token.as_deref())
+        .await
+        .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("cannot attach: {e}")));
+    let follow_store = metrics.clone();
+    tokio::spawn(async move {
+        control::follow_remote(response, &follow_store).await;
     });
+    let commands = control::remote_commands(host, token);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    tokio::task::spawn_blocking(move || run_attached_tui(metrics, Some(commands), &config, stop))
+        .await
+        .unwrap();
+}
 
+fn run_attached_tui(
+    metrics: Arc<MetricsStore>,
+    commands: Option<control::CommandChannel>,
+    config: &Config,
+    stop: Arc<AtomicBool>,
+) {
     let evict_metrics = metrics.clone();
     let evict_stop = stop.clone();
     let _evict_handle = std::thread::spawn(move || {
@@ -349,52 +1826,61 @@ fn run_attached(config_path: &PathBuf) {
         }
     });
 
-    croxy::tui::run(metrics, true).unwrap_or_else(|e| {
-        eprintln!("TUI error: {e}");
-        std::process::exit(1);
-    });
+    let mut app = App::new(metrics, true);
+    app.commands = commands;
+    configure_tui(&mut app, config);
+    croxy::tui::run(app).unwrap_or_else(|e| ExitStatus::Failure.fail(format!("TUI error: {e}")));
 
     stop.store(true, Ordering::Relaxed);
     // Don't join -- the evict thread sleeps 60s and we don't want to block exit.
     // The process is exiting anyway; these threads will be cleaned up.
 }
 
-fn init_tracing(use_tui: bool, verbose: bool) {
+fn init_tracing(
+    to_file: bool,
+    verbose: bool,
+    config: &LoggingConfig,
+    format: LogFormat,
+    scrubber: Arc<Scrubber>,
+    tail: Arc<LogTail>,
+) {
     let default_filter = if verbose { "croxy=debug" } else { "croxy=info" };
-    let env_filter = || {
-        tracing_subscriber::EnvFilter::try_from_default_env()
-            .unwrap_or_else(|_| default_filter.parse().unwrap())
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| default_filter.parse().unwrap());
+
+    let writer = if to_file {
+        let log_file = open_app_log(log_path(), &config.app)
+            .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("failed to open log file: {e}")));
+        BoxMakeWriter::new(ScrubbedWriter::new(
+            TailWriter::new(std::sync::Mutex::new(log_file), tail),
+            scrubber,
+        ))
+    } else {
+        BoxMakeWriter::new(ScrubbedWriter::new(
+            TailWriter::new(std::io::stdout, tail),
+            scrubber,
+        ))
     };
 
-    if use_tui {
-        let log_dir = config_dir();
-        let _ = fs::create_dir_all(&log_dir);
-        let log_file = fs::File::create(log_dir.join("croxy.log")).unwrap_or_else(|e| {
-            eprintln!("failed to create log file: {e}");
-            std::process::exit(1);
-        });
-        tracing_subscriber::fmt()
-            .with_env_filter(env_filter())
-            .with_writer(std::sync::Mutex::new(log_file))
-            .with_ansi(false)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_env_filter(env_filter())
-            .init();
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_writer(writer)
+        .with_ansi(!to_file);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).init(),
     }
 }
 
-fn retention_duration(config: &Config) -> std::time::Duration {
-    if config.retention.enabled {
-        std::time::Duration::from_secs(config.retention.minutes.saturating_mul(60))
-    } else {
-        std::time::Duration::from_secs(365 * 24 * 60 * 60)
+fn apply_display_window(metrics: &MetricsStore, config: &Config) {
+    if let Some(minutes) = config.retention.display_minutes {
+        metrics.set_window(std::time::Duration::from_secs(minutes.saturating_mul(60)));
     }
 }
 
 fn create_metrics(config: &Config, retention: std::time::Duration) -> Arc<MetricsStore> {
-    Arc::new(if config.logging.metrics.enabled {
+    let metrics = Arc::new(if config.logging.metrics.enabled {
         match MetricsLogger::new(&config.logging.metrics) {
             Ok(logger) => {
                 info!(path = %config.logging.metrics.path, "metrics logging enabled");
@@ -407,28 +1893,24 @@ fn create_metrics(config: &Config, retention: std::time::Duration) -> Arc<Metric
         }
     } else {
         MetricsStore::new(retention)
-    })
+    });
+    apply_display_window(&metrics, config);
+    metrics
 }
 
-fn spawn_eviction_task(metrics: &Arc<MetricsStore>) {
-    let evict_metrics = metrics.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            evict_metrics.evict_expired();
-        }
-    });
+/// Applies `[tui]` and the alert thresholds to `app`; the config was
+/// checked on load.
+fn configure_tui(app: &mut App, config: &Config) {
+    app.configure(&config.tui)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(e));
+    app.alert_thresholds = croxy::alerts::Thresholds::from_config(config);
 }
 
-async fn run_tui(metrics: Arc<MetricsStore>) -> ExitMode {
-    tokio::task::spawn_blocking(move || croxy::tui::run(metrics, false))
+async fn run_tui(app: App) -> ExitMode {
+    tokio::task::spawn_blocking(move || croxy::tui::run(app))
         .await
         .unwrap()
-        .unwrap_or_else(|e| {
-            eprintln!("TUI error: {e}");
-            std::process::exit(1);
-        })
+        .unwrap_or_else(|e| ExitStatus::Failure.fail(format!("TUI error: {e}")))
 }
 
 async fn await_shutdown_signal() {
@@ -445,62 +1927,539 @@ async fn await_shutdown_signal() {
     }
 }
 
-async fn run_foreground(listener: TcpListener, app: AxumRouter, metrics: Arc<MetricsStore>) {
-    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+/// Waits for SIGINT/SIGTERM or a drain command from an attached viewer.
+async fn await_stop(drain_requested: &Notify) {
+    tokio::select! {
+        _ = await_shutdown_signal() => {}
+        _ = drain_requested.notified() => info!("drain requested by an attached viewer"),
+    }
+}
 
-    tokio::spawn(async move {
-        axum::serve(listener, app)
-            .with_graceful_shutdown(async {
-                let _ = shutdown_rx.await;
-            })
-            .await
-            .unwrap_or_else(|e| tracing::error!("server error: {e}"));
+enum Bound {
+    Tcp(Allowlisted<KeepAlive>),
+    Tls(TlsListener),
+    Unix(UnixListener),
+}
+
+struct Listeners {
+    /// Each with the ingress its requests are tagged with; `[server]`'s own
+    /// listeners get the untagged default.
+    bound: Vec<(Bound, Ingress)>,
+    /// Socket files we created and their inodes, so shutdown only removes
+    /// our own.
+    sockets: Vec<(PathBuf, u64)>,
+    /// On takeover, sockets bound beside the predecessor's, each with the
+    /// path it moves to once the predecessor agrees to drain.
+    staged: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Listeners {
+    fn listen_unix(&mut self, path: &str, takeover: bool) -> UnixListener {
+        // On takeover the predecessor's socket stays in place until it agrees
+        // to drain, so this one is bound beside it and moved over it then
+        let bind_at = if takeover {
+            PathBuf::from(format!("{path}.{}", std::process::id()))
+        } else {
+            PathBuf::from(path)
+        };
+        // A leftover socket file from an unclean exit blocks bind
+        if fs::metadata(&bind_at).is_ok() && (takeover || UnixStream::connect(&bind_at).is_err()) {
+            let _ = fs::remove_file(&bind_at);
+        }
+        let listener = listeners::bind_unix(&bind_at)
+            .unwrap_or_else(|e| ExitStatus::Bind.fail(format!("failed to bind {path}: {e}")));
+        if let Ok(meta) = fs::metadata(&bind_at) {
+            self.sockets.push((PathBuf::from(path), meta.ino()));
+        }
+        if takeover {
+            self.staged.push((bind_at, PathBuf::from(path)));
+        }
+        info!(socket = %path, "croxy listening");
+        listener
+    }
+
+    /// Moves sockets bound on takeover over the predecessor's, which keeps
+    /// serving the connections it accepted through the unlinked file.
+    fn publish(&mut self) {
+        for (staged, path) in self.staged.drain(..) {
+            if let Err(e) = fs::rename(&staged, &path) {
+                let _ = fs::remove_file(&staged);
+                ExitStatus::Bind.fail(format!("failed to bind {}: {e}", path.display()));
+            }
+        }
+    }
+
+    /// Removes sockets bound on a takeover that didn't go ahead, leaving
+    /// the predecessor's in place.
+    fn abandon(&mut self) {
+        for (staged, _) in self.staged.drain(..) {
+            let _ = fs::remove_file(staged);
+        }
+    }
+}
+
+/// Connects to the control socket of the instance being taken over, which
+/// is how it is told to drain once this one is bound.
+fn connect_predecessor(old_pid: i32) -> UnixStream {
+    let path = control_socket_path();
+    UnixStream::connect(&path).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!(
+            "can't take over from pid {old_pid}: failed to connect to {}: {e}; \
+             stop it with `croxy stop` and start again",
+            path.display()
+        ))
+    })
+}
+
+/// Asks the instance being taken over to stop accepting and drain, now
+/// that this one is bound to the same addresses, then moves this one's
+/// unix sockets into place. Exits without serving when it doesn't agree,
+/// leaving it running where it was.
+fn hand_over(old_pid: i32, stream: UnixStream, listeners: &mut Listeners) {
+    let command = control::Command::Drain;
+    let refused = match control::send_command(stream, &command) {
+        Ok(reply) if reply.ok => None,
+        Ok(reply) => Some(reply.message),
+        Err(e) => Some(format!("{command}: {e}")),
+    };
+    if let Some(reason) = refused {
+        listeners.abandon();
+        ExitStatus::Failure.fail(format!("can't take over from pid {old_pid}: {reason}"));
+    }
+    listeners.publish();
+    info!(
+        pid = old_pid,
+        "bound listeners, previous instance is draining"
+    );
+}
+
+/// Binds with SO_REUSEPORT so a successor started with `--takeover` can bind
+/// the same port while this instance drains.
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Binds a proxy listener on `addr`, exiting when that fails or another
+/// instance is already serving there.
+async fn listen_tcp(addr: &str, takeover: bool) -> TcpListener {
+    let fail = |e: &dyn std::fmt::Display| -> ! {
+        ExitStatus::Bind.fail(format!("failed to bind {addr}: {e}"));
+    };
+    let sock_addr = match tokio::net::lookup_host(addr).await.map(|mut a| a.next()) {
+        Ok(Some(sock_addr)) => sock_addr,
+        Ok(None) => fail(&"address did not resolve"),
+        Err(e) => fail(&e),
+    };
+    // SO_REUSEPORT would otherwise let two instances silently share a port
+    let mut probe = sock_addr;
+    if probe.ip().is_unspecified() {
+        probe.set_ip(match probe {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    if !takeover
+        && TcpStream::connect_timeout(&probe, std::time::Duration::from_millis(200)).is_ok()
+    {
+        fail(&"address already in use");
+    }
+    let listener = bind_tcp(sock_addr).unwrap_or_else(|e| {
+        if takeover && e.kind() == std::io::ErrorKind::AddrInUse {
+            fail(
+                &"the running instance's listener can't be shared (it wasn't bound with \
+                   SO_REUSEPORT); stop it with `croxy stop` and start again",
+            );
+        }
+        fail(&e)
     });
+    info!(addr = %addr, "croxy listening");
+    listener
+}
+
+async fn bind_listeners(
+    config: &Config,
+    takeover: bool,
+    metrics: &Arc<MetricsStore>,
+    audit: &Arc<AuditLog>,
+) -> Listeners {
+    let allow = Allowlist::parse(&config.server.allow_cidrs)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(format!("server.allow_cidrs: {e}")));
+    let allow = Arc::new(allow);
+    let keepalive = config.server.keepalive();
+    let allowlisted = |listener| {
+        let listener = KeepAlive::new(listener, keepalive);
+        Allowlisted::new(listener, allow.clone(), metrics.clone(), audit.clone())
+    };
+
+    let mut listeners = Listeners {
+        bound: Vec::new(),
+        sockets: Vec::new(),
+        staged: Vec::new(),
+    };
+    if config.server.tcp {
+        let addr = format!("{}:{}", config.server.host, config.server.port);
+        let listener = allowlisted(listen_tcp(&addr, takeover).await);
+        listeners
+            .bound
+            .push((Bound::Tcp(listener), Ingress::default()));
+    }
+    if let Some(ref path) = config.server.socket {
+        let listener = listeners.listen_unix(path, takeover);
+        listeners
+            .bound
+            .push((Bound::Unix(listener), Ingress::default()));
+    }
+
+    for extra in &config.server.listeners {
+        let bound = match (&extra.address, &extra.socket) {
+            (Some(addr), _) => {
+                let listener = allowlisted(listen_tcp(addr, takeover).await);
+                match (&extra.tls_cert, &extra.tls_key) {
+                    (Some(cert), Some(key)) => {
+                        let tls = listeners::tls_acceptor(
+                            &croxy::secrets::expand_home(cert),
+                            &croxy::secrets::expand_home(key),
+                        )
+                        .and_then(|acceptor| {
+                            TlsListener::new(listener, acceptor).map_err(|e| e.to_string())
+                        })
+                        .unwrap_or_else(|e| {
+                            ExitStatus::Bind.fail(format!("failed to serve TLS on {addr}: {e}"))
+                        });
+                        Bound::Tls(tls)
+                    }
+                    _ => Bound::Tcp(listener),
+                }
+            }
+            (None, Some(path)) => Bound::Unix(listeners.listen_unix(path, takeover)),
+            (None, None) => continue,
+        };
+        listeners.bound.push((bound, Ingress::from_config(extra)));
+    }
+
+    if listeners.bound.is_empty() {
+        ExitStatus::Config.fail(
+            "no listeners configured: set [server] socket, enable tcp, or add [[server.listeners]]",
+        );
+    }
+    listeners
+}
+
+fn spawn_servers(
+    listeners: Listeners,
+    app: AxumRouter,
+    shutdown: watch::Receiver<bool>,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    listeners
+        .bound
+        .into_iter()
+        .map(|(bound, ingress)| {
+            let app = app.clone().layer(axum::Extension(ingress));
+            let shutdown = shutdown.clone();
+            match bound {
+                Bound::Tcp(listener) => tokio::spawn(serve(listener, app, shutdown)),
+                Bound::Tls(listener) => tokio::spawn(serve(listener, app, shutdown)),
+                Bound::Unix(listener) => tokio::spawn(serve(listener, app, shutdown)),
+            }
+        })
+        .collect()
+}
 
-    spawn_eviction_task(&metrics);
+async fn serve<L>(listener: L, app: AxumRouter, mut shutdown: watch::Receiver<bool>)
+where
+    L: axum::serve::Listener,
+    L::Addr: std::fmt::Debug,
+    Peer: for<'a> axum::extract::connect_info::Connected<axum::serve::IncomingStream<'a, L>>,
+{
+    axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
+        .await
+        .unwrap_or_else(|e| tracing::error!("server error: {e}"));
+}
+
+fn remove_socket_files(sockets: &[(PathBuf, u64)]) {
+    for (path, ino) in sockets {
+        if fs::metadata(path).ok().map(|m| m.ino()) == Some(*ino) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
 
-    match run_tui(metrics).await {
+/// Serves the control socket for attached viewers, returning its inode.
+/// Called after the proxy listeners are bound, so a second instance that
+/// fails to bind never replaces a running daemon's socket.
+fn serve_control(
+    metrics: &Arc<MetricsStore>,
+    viewers: &control::Viewers,
+    controller: &Arc<control::Controller>,
+) -> Option<u64> {
+    let path = control_socket_path();
+    match control::bind(&path) {
+        Ok(listener) => {
+            tokio::spawn(control::serve(
+                listener,
+                metrics.clone(),
+                viewers.clone(),
+                Some(controller.clone()),
+            ));
+            fs::metadata(&path).ok().map(|m| m.ino())
+        }
+        Err(e) => {
+            tracing::warn!("failed to bind control socket {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+fn remove_control_socket(ino: Option<u64>) {
+    let path = control_socket_path();
+    if ino.is_some() && fs::metadata(&path).ok().map(|m| m.ino()) == ino {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Waits for servers to finish in-flight requests, up to the drain timeout.
+async fn drain(handles: Vec<tokio::task::JoinHandle<()>>, timeout: std::time::Duration) {
+    let all = futures::future::join_all(handles);
+    if tokio::time::timeout(timeout, all).await.is_err() {
+        tracing::warn!("drain timeout reached, dropping remaining connections");
+    }
+}
+
+async fn run_foreground(
+    listeners: Listeners,
+    app: AxumRouter,
+    tui: App,
+    (shutdown_tx, shutdown_rx): (watch::Sender<bool>, watch::Receiver<bool>),
+    drain_requested: Arc<Notify>,
+    drain_timeout: std::time::Duration,
+) {
+    let handles = spawn_servers(listeners, app, shutdown_rx);
+
+    croxy::server::spawn_eviction(&tui.metrics);
+
+    match run_tui(tui).await {
         ExitMode::Quit => {
-            let _ = shutdown_tx.send(());
+            let _ = shutdown_tx.send(true);
             tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
         ExitMode::Detach => {
             write_pid_file();
             eprintln!("detached (pid {})", std::process::id());
-            await_shutdown_signal().await;
-            let _ = shutdown_tx.send(());
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            remove_pid_file();
+            await_stop(&drain_requested).await;
+            info!("shutting down, draining in-flight requests");
+            let _ = shutdown_tx.send(true);
+            drain(handles, drain_timeout).await;
+            remove_own_pid_file();
         }
     }
 }
 
-async fn run_headless(listener: TcpListener, app: AxumRouter) {
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c().await.ok();
-            info!("shutting down");
-        })
-        .await
-        .unwrap();
+async fn run_headless(
+    listeners: Listeners,
+    app: AxumRouter,
+    (shutdown_tx, shutdown_rx): (watch::Sender<bool>, watch::Receiver<bool>),
+    drain_requested: Arc<Notify>,
+    drain_timeout: std::time::Duration,
+) {
+    let handles = spawn_servers(listeners, app, shutdown_rx);
+
+    await_stop(&drain_requested).await;
+    info!("shutting down, draining in-flight requests");
+    let _ = shutdown_tx.send(true);
+
+    drain(handles, drain_timeout).await;
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let config_path = cli.config.unwrap_or_else(default_config_path);
+    let config_path = cli
+        .config
+        .unwrap_or_else(|| instance_dir(cli.instance.as_deref()).join("config.toml"));
+    let _ = INSTANCE.set(resolve_instance(cli.instance, &config_path));
+    let _ = PROFILE_ARG.set(cli.profile);
+    let _ = RETENTION_ARG.set(cli.retention);
 
     match cli.command {
-        Some(Commands::Start) => return detach(&config_path, cli.verbose),
-        Some(Commands::Stop) => return cmd_stop(),
-        Some(Commands::Init) => return cmd_init(),
-        Some(Commands::Shellenv) => return cmd_shellenv(&config_path),
+        Some(Commands::Start { takeover }) => {
+            first_run(&config_path).await;
+            return detach(
+                &config_path,
+                cli.verbose,
+                cli.log_format,
+                takeover,
+                cli.json,
+            );
+        }
+        Some(Commands::Stop) => return cmd_stop(cli.json),
+        Some(Commands::Status { tenant }) => {
+            return cmd_status(&config_path, tenant.as_deref(), cli.json).await;
+        }
+        Some(Commands::Attach { host, token }) => {
+            return match host {
+                Some(host) => {
+                    let token = token.or_else(|| std::env::var("CROXY_ADMIN_TOKEN").ok());
+                    run_attached_remote(&config_path, &host, token).await
+                }
+                None => {
+                    if !read_pid().is_some_and(pid_is_alive) {
+                        ExitStatus::NotRunning.fail("croxy is not running");
+                    }
+                    run_attached(&config_path)
+                }
+            };
+        }
+        Some(Commands::Events { follow, filter }) => return cmd_events(follow, filter),
+        Some(Commands::Pin {
+            pattern,
+            provider,
+            save,
+        }) => {
+            let command = control::Command::Pin { pattern, provider };
+            return cmd_pin(&command, save.then_some(&config_path), cli.json);
+        }
+        Some(Commands::Unpin { pattern, save }) => {
+            let command = control::Command::Unpin { pattern };
+            return cmd_pin(&command, save.then_some(&config_path), cli.json);
+        }
+        Some(Commands::Init {
+            template,
+            force,
+            stdout,
+        }) => return cmd_init(&config_path, template, force, stdout, cli.json),
+        Some(Commands::Shellenv) => return cmd_shellenv(&config_path, cli.json),
+        Some(Commands::TestRoute {
+            model,
+            message,
+            user_id,
+            send,
+        }) => {
+            return cmd_test_route(
+                &config_path,
+                &model,
+                &message,
+                user_id.as_deref(),
+                send,
+                cli.json,
+            )
+            .await;
+        }
+        Some(Commands::Bench {
+            model,
+            concurrency,
+            requests,
+            prompt_file,
+            max_tokens,
+        }) => {
+            let prompt = match prompt_file {
+                Some(path) => std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                    ExitStatus::Failure.fail(format!("failed to read {}: {e}", path.display()))
+                }),
+                None => "Write a short paragraph about the sea.".to_string(),
+            };
+            let options = croxy::bench::Options {
+                model,
+                concurrency: concurrency as usize,
+                requests: requests as usize,
+                prompt,
+                max_tokens,
+            };
+            return cmd_bench(&config_path, options, cli.json).await;
+        }
+        Some(Commands::Run { api_key, command }) => {
+            return cmd_run(&config_path, cli.verbose, api_key, &command);
+        }
+        Some(Commands::Service { action }) => {
+            return match action {
+                ServiceAction::Install => {
+                    croxy::service::install(&config_path, &log_path(), instance())
+                }
+                ServiceAction::Uninstall => croxy::service::uninstall(instance()),
+                ServiceAction::Status => croxy::service::status(instance()),
+            };
+        }
+        Some(Commands::Key { action }) => return cmd_key(&config_path, action, cli.json),
+        Some(Commands::Warm { route }) => return cmd_warm(&config_path, &route, cli.json).await,
+        Some(Commands::History {
+            model,
+            status,
+            since,
+            limit,
+        }) => {
+            let filter = croxy::history::Filter {
+                model,
+                status,
+                since: since.map(|age| chrono::Utc::now() - age),
+            };
+            return cmd_history(&config_path, &filter, limit, cli.json);
+        }
+        Some(Commands::Diff {
+            window,
+            ago,
+            baseline,
+            by,
+        }) => {
+            let current = croxy::diff::Window::ending(ago.unwrap_or_default(), window);
+            let baseline = current.shifted(baseline.unwrap_or(window));
+            return cmd_diff(&config_path, by, baseline, current, cli.json);
+        }
+        Some(Commands::Show { id }) => return cmd_show(&config_path, &id, cli.json),
+        Some(Commands::Replay {
+            id,
+            provider,
+            model,
+        }) => {
+            return cmd_replay(
+                &config_path,
+                id.as_deref(),
+                provider.as_deref(),
+                model.as_deref(),
+                cli.json,
+            )
+            .await;
+        }
         Some(Commands::Config { action }) => {
             return match action {
                 ConfigAction::Set { key, value } => {
-                    cli_config::config_set(&config_path, &key, &value)
+                    cli_config::config_set(&config_path, &key, &value, cli.json)
+                }
+                ConfigAction::AddRoute {
+                    provider,
+                    pattern,
+                    model,
+                    name,
+                    description,
+                } => cli_config::config_add_route(
+                    &config_path,
+                    &cli_config::NewRoute {
+                        provider,
+                        pattern,
+                        model,
+                        name,
+                        description,
+                    },
+                    cli.json,
+                ),
+                ConfigAction::Get { key } => cli_config::config_get(&config_path, &key, cli.json),
+                ConfigAction::Unset { key } => {
+                    cli_config::config_unset(&config_path, &key, cli.json)
+                }
+                ConfigAction::List => cli_config::config_list(&config_path, cli.json),
+                ConfigAction::Edit => cli_config::config_edit(&config_path),
+                ConfigAction::Path if cli.json => {
+                    print_json(serde_json::json!({ "path": config_path }))
                 }
-                ConfigAction::Get { key } => cli_config::config_get(&config_path, &key),
                 ConfigAction::Path => println!("{}", config_path.display()),
             };
         }
@@ -517,43 +2476,130 @@ async fn main() {
         return run_attached(&config_path);
     }
 
-    init_tracing(use_tui, cli.verbose);
-
+    first_run(&config_path).await;
     let config = load_config(&config_path);
-    let router = Router::from_config(&config).unwrap_or_else(|e| {
-        eprintln!("failed to build router: {e}");
-        std::process::exit(1);
-    });
 
-    let retention = retention_duration(&config);
+    let scrubber = Arc::new(
+        Scrubber::new(&config.logging.redact_patterns)
+            .unwrap_or_else(|e| ExitStatus::Config.fail(format!("logging.redact_patterns: {e}"))),
+    );
+    let log_tail = Arc::new(LogTail::new(config.logging.crash.lines));
+    init_tracing(
+        use_tui || cli.daemon,
+        cli.verbose,
+        &config.logging,
+        cli.log_format.unwrap_or(config.logging.format),
+        scrubber.clone(),
+        log_tail.clone(),
+    );
+    let router = Router::from_config(&config)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(format!("failed to build router: {e}")));
+
+    let retention = config.retention.duration();
     let metrics = create_metrics(&config, retention);
+    if config.logging.crash.enabled {
+        CrashReporter::new(&config.logging.crash.path, metrics.clone(), log_tail).install();
+    }
 
-    let state = Arc::new(AppState {
-        router,
-        client: reqwest::Client::builder()
-            .no_proxy()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .expect("failed to build HTTP client"),
-        metrics: metrics.clone(),
-        max_body_size: config.server.max_body_size,
+    let captures = config.capture.enabled.then(|| {
+        let store = CaptureStore::open(capture_dir(&config), config.capture.max_requests);
+        Arc::new(store.unwrap_or_else(|e| ExitStatus::Failure.fail(e)))
     });
-
-    let app = AxumRouter::new()
-        .fallback(any(handle_request))
-        .with_state(state);
-
-    let addr = format!("{}:{}", config.server.host, config.server.port);
-    let listener = TcpListener::bind(&addr).await.unwrap_or_else(|e| {
-        eprintln!("failed to bind {addr}: {e}");
-        std::process::exit(1);
+    let compare =
+        Comparer::from_config(&config.compare, Some(state_dir().join("comparisons.jsonl")))
+            .unwrap_or_else(|e| ExitStatus::Config.fail(e))
+            .map(Arc::new);
+    let keys = croxy::keys::KeyStore::open(keys_path(), config.keys.required);
+    let audit = Arc::new(open_audit_log(&config));
+    let state = Arc::new(app_state(
+        &config,
+        router,
+        metrics.clone(),
+        keys,
+        (captures, compare, audit.clone(), scrubber.clone()),
+    ));
+
+    let shutdown = watch::channel(false);
+    let viewers = control::Viewers::default();
+    let drain_requested = Arc::new(Notify::new());
+    let reload_path = config_path.clone();
+    let reload_scrubber = scrubber.clone();
+    let controller = Arc::new(control::Controller::new(
+        state.clone(),
+        Box::new(move || {
+            let (config, _) = read_config(&reload_path).map_err(CroxyError::Config)?;
+            let router = Router::from_config(&config)?;
+            reload_scrubber.set_secrets(croxy::server::provider_secrets(&config, &router));
+            Ok(router)
+        }),
+        drain_requested.clone(),
+    ));
+
+    let admin_token = croxy::secrets::resolve_admin_token(&config.admin)
+        .unwrap_or_else(|e| ExitStatus::Config.fail(e));
+    let admin = croxy::admin::serves(&config, admin_token.as_deref()).then(|| {
+        Arc::new(croxy::admin::AdminState {
+            metrics: metrics.clone(),
+            token: admin_token,
+            shutdown: shutdown.1.clone(),
+            viewers: viewers.clone(),
+            controller: Some(controller.clone()),
+            audit: audit.clone(),
+            keys: state.keys.clone(),
+        })
     });
+    let warming = state.clone();
+    let budgets = state.keys.clone();
+    let app = croxy::server::app(state, admin);
+
+    // Reach the predecessor before binding anything, so a takeover that
+    // can't hand over leaves it serving untouched.
+    let predecessor = cli.takeover_from.map(|pid| (pid, connect_predecessor(pid)));
+    let mut listeners = bind_listeners(&config, predecessor.is_some(), &metrics, &audit).await;
+    if let Some((old_pid, stream)) = predecessor {
+        hand_over(old_pid, stream, &mut listeners);
+    }
+    let sockets = listeners.sockets.clone();
+    let control_ino = serve_control(&metrics, &viewers, &controller);
+    let warm = croxy::warm::targets(&config);
+    if !warm.is_empty() {
+        tokio::spawn(croxy::warm::run(warming.clone(), warm));
+    }
+    let model_checks = croxy::model_check::targets(&config);
+    if !model_checks.is_empty() {
+        tokio::spawn(croxy::model_check::run(warming.clone(), model_checks));
+    }
+    tokio::spawn(croxy::tokens::run(warming.clone()));
+    if config.auto_router.enabled
+        && let Some(ref local) = config.auto_router.local
+    {
+        let supervisor = croxy::auto_router_local::Supervisor::new(local, &config.auto_router.url)
+            .unwrap_or_else(|e| ExitStatus::Config.fail(format!("auto_router.local: {e}")));
+        tokio::spawn(supervisor.run(shutdown.1.clone()));
+    }
+    if let Some(rules) = croxy::notifications::Rules::from_config(&config) {
+        tokio::spawn(croxy::notifications::run(warming, Some(rules)));
+    }
 
-    info!(addr = %addr, "croxy listening");
-
+    let drain_timeout = std::time::Duration::from_secs(config.server.drain_timeout_secs);
     if use_tui {
-        run_foreground(listener, app, metrics).await;
+        let mut tui = App::new(metrics, false);
+        tui.viewers = Some(viewers);
+        tui.budgets = Some(budgets);
+        configure_tui(&mut tui, &config);
+        run_foreground(
+            listeners,
+            app,
+            tui,
+            shutdown,
+            drain_requested,
+            drain_timeout,
+        )
+        .await;
     } else {
-        run_headless(listener, app).await;
+        run_headless(listeners, app, shutdown, drain_requested, drain_timeout).await;
     }
+
+    remove_socket_files(&sockets);
+    remove_control_socket(control_ino);
 }
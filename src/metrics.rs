@@ -1,17 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
 
+use crate::balance::Health;
+use crate::body_sizes::{BodySizeReport, BodySizes, Direction};
+use crate::compare::Comparison;
+use crate::events::RequestEvent;
+use crate::keys::QuotaUsage;
+use crate::listeners::ConnectionStreams;
 use crate::metrics_log::MetricsLogger;
+use crate::prometheus::Latency;
+use crate::ratelimits::RateLimit;
+use crate::session::SessionSummary;
+use crate::tokens::AuthStatus;
+use crate::warm::Warmth;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RoutingMethod {
     Pattern,
     Auto,
     Default,
+    /// Chosen by the `[script]` hook.
+    Script,
 }
 
 impl std::fmt::Display for RoutingMethod {
@@ -20,6 +36,20 @@ impl std::fmt::Display for RoutingMethod {
             RoutingMethod::Pattern => write!(f, "pattern"),
             RoutingMethod::Auto => write!(f, "auto"),
             RoutingMethod::Default => write!(f, "default"),
+            RoutingMethod::Script => write!(f, "script"),
+        }
+    }
+}
+
+impl RoutingMethod {
+    /// Parses the name written by `Display`, treating anything unknown as
+    /// `Default`.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "pattern" => RoutingMethod::Pattern,
+            "auto" => RoutingMethod::Auto,
+            "script" => RoutingMethod::Script,
+            _ => RoutingMethod::Default,
         }
     }
 }
@@ -27,6 +57,9 @@ impl std::fmt::Display for RoutingMethod {
 #[derive(Debug, Clone)]
 pub struct RequestRecord {
     pub id: u64,
+    /// The ID the request was served under, as in captures and the access
+    /// log.
+    pub request_id: Option<String>,
     pub timestamp: Instant,
     pub wallclock: DateTime<Utc>,
     pub model: String,
@@ -37,24 +70,287 @@ pub struct RequestRecord {
     pub input_tokens: u64,
     pub output_tokens: u64,
     pub error_body: Option<String>,
+    /// Number of requests, for a Message Batches API submission.
+    pub batch_size: Option<usize>,
+    /// Matches replaced by `[[redact]]` rules before forwarding.
+    pub redactions: usize,
+    /// Tag of the `[[server.listeners]]` entry the request arrived on.
+    pub listener: Option<String>,
+    /// Who sent the request: its virtual key's name, else its
+    /// `server.client_header`, else the address it came from.
+    pub client: Option<String>,
+    /// The route limit the response was cut off at, if any.
+    pub cutoff: Option<String>,
+    /// Faults `[chaos]` injected into the request. Such records are kept
+    /// out of the TUI's statistics.
+    pub chaos: Option<String>,
+    /// The `croxy bench` run that sent the request. Such records are also
+    /// kept out of the TUI's statistics.
+    pub bench: Option<String>,
+    /// Bytes sent before the client went away mid-response, if it did.
+    pub client_aborted: Option<u64>,
+    /// Whether the request came near its model's context window.
+    pub near_limit: bool,
+    pub timings: Timings,
+}
+
+impl Default for RequestRecord {
+    /// A record stamped now with nothing else known, as for a request that
+    /// hasn't been answered yet.
+    fn default() -> Self {
+        Self {
+            id: 0,
+            request_id: None,
+            timestamp: Instant::now(),
+            wallclock: Utc::now(),
+            model: String::new(),
+            provider: String::new(),
+            routing_method: RoutingMethod::Default,
+            status: 0,
+            duration: Duration::ZERO,
+            input_tokens: 0,
+            output_tokens: 0,
+            error_body: None,
+            batch_size: None,
+            redactions: 0,
+            listener: None,
+            client: None,
+            cutoff: None,
+            chaos: None,
+            bench: None,
+            client_aborted: None,
+            near_limit: false,
+            timings: Timings::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl RequestRecord {
+    /// A request for tests to override the fields they look at: a 200 from
+    /// Anthropic's `claude-opus-4-6` after 500ms, 100 tokens in and 200 out.
+    pub(crate) fn sample() -> Self {
+        Self {
+            model: "claude-opus-4-6".to_string(),
+            provider: "anthropic".to_string(),
+            routing_method: RoutingMethod::Pattern,
+            status: 200,
+            duration: Duration::from_millis(500),
+            input_tokens: 100,
+            output_tokens: 200,
+            ..Self::default()
+        }
+    }
+}
+
+/// Where a request's time went, phase by phase, in milliseconds. Phases
+/// the request didn't go through are `None`; what the phases don't
+/// account for of its duration was spent in croxy between them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timings {
+    /// Reading the request body from the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_read_ms: Option<u64>,
+    /// Choosing a route: the classifier, `[script]`, and any wait for a
+    /// rate limit to reset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_ms: Option<u64>,
+    /// The auto-router's classifier, part of routing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classifier_ms: Option<u64>,
+    /// Opening a connection to the provider, when no idle one could be
+    /// reused. Part of the time to first byte.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    /// From sending the request to the provider until its response
+    /// headers arrived, including retries after a 429.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttfb_ms: Option<u64>,
+    /// From the response headers until the last byte reached the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_ms: Option<u64>,
+}
+
+impl Timings {
+    /// The phases as one line, with the classifier and connect inside the
+    /// phases they are part of and the rest of `duration_ms` put down to
+    /// croxy. `None` for a request with no phases recorded.
+    pub fn describe(&self, duration_ms: u64) -> Option<String> {
+        let ms = |ms: u64| crate::tui::views::format_duration(Duration::from_millis(ms));
+        let within = |outer: Option<u64>, name: &str, inner: Option<u64>| {
+            outer.map(|outer| match inner {
+                Some(inner) => format!("{} ({name} {})", ms(outer), ms(inner)),
+                None => ms(outer),
+            })
+        };
+        let phases: Vec<String> = [
+            ("body", self.body_read_ms.map(ms)),
+            (
+                "routing",
+                within(self.routing_ms, "classifier", self.classifier_ms),
+            ),
+            (
+                "first byte",
+                within(self.ttfb_ms, "connect", self.connect_ms),
+            ),
+            ("stream", self.stream_ms.map(ms)),
+        ]
+        .into_iter()
+        .filter_map(|(name, phase)| Some(format!("{name} {}", phase?)))
+        .collect();
+        if phases.is_empty() {
+            return None;
+        }
+        let accounted: u64 = [
+            self.body_read_ms,
+            self.routing_ms,
+            self.ttfb_ms,
+            self.stream_ms,
+        ]
+        .into_iter()
+        .flatten()
+        .sum();
+        Some(format!(
+            "{}, croxy {}",
+            phases.join(", "),
+            ms(duration_ms.saturating_sub(accounted))
+        ))
+    }
+}
+
+/// A change to the store's records, as sent to [`MetricsStore::subscribe`].
+#[derive(Debug, Clone)]
+pub enum MetricsEvent {
+    /// A request was recorded; streamed ones are finalized later.
+    Recorded(RequestRecord),
+    /// A streamed request finished, with its final tokens and duration.
+    Finalized(RequestRecord),
+    /// A record was replaced under its existing ID, as when an attached
+    /// viewer mirrors a daemon's store.
+    Updated(RequestRecord),
 }
 
 pub struct MetricsStore {
     records: RwLock<Vec<RequestRecord>>,
     id_index: RwLock<HashMap<u64, usize>>,
-    window: Duration,
+    /// How long records are kept before eviction.
+    retention: RwLock<Duration>,
+    /// How far back `snapshot` (and so the TUI) looks; at most `retention`.
+    window: RwLock<Duration>,
     logger: Option<Mutex<MetricsLogger>>,
     next_id: AtomicU64,
+    /// Bumped on every insert or update so viewers can wait for changes
+    /// instead of polling.
+    version: watch::Sender<u64>,
+    events: broadcast::Sender<MetricsEvent>,
+    /// Request lifecycle events, for `croxy events`.
+    lifecycle: broadcast::Sender<RequestEvent>,
+    tool_results_truncated: AtomicU64,
+    /// Malformed tool calls fixed for `repair_tool_calls` routes.
+    tool_calls_repaired: AtomicU64,
+    /// Auto-router classifications answered without asking the classifier.
+    classifications_skipped: AtomicU64,
+    /// Requests answered from, or turned away for, an identical one in
+    /// flight.
+    requests_deduplicated: AtomicU64,
+    /// Streams that outran their client, and how long they waited on it.
+    streams_stalled: AtomicU64,
+    stream_stall_ms: AtomicU64,
+    /// TCP connections turned away by `server.allow_cidrs`.
+    connections_rejected: AtomicU64,
+    /// Responses whose client went away before the end, by model.
+    client_aborts: RwLock<BTreeMap<String, u64>>,
+    /// Client connections with requests in flight, by connection.
+    connections: RwLock<HashMap<u64, ConnectionStreams>>,
+    /// Recent dual-send comparisons, newest last, and how many there have
+    /// been in all.
+    comparisons: RwLock<(VecDeque<Comparison>, u64)>,
+    /// The latest rate limits each provider reported.
+    rate_limits: RwLock<HashMap<String, RateLimit>>,
+    /// The latest warm-up ping to each provider.
+    warmth: RwLock<HashMap<String, Warmth>>,
+    /// Each provider's recent latency and error rate, for picking among
+    /// group members.
+    health: RwLock<HashMap<String, Health>>,
+    /// Token use of each client with a quota.
+    quotas: RwLock<HashMap<String, QuotaUsage>>,
+    /// Providers billed at Anthropic list prices, for estimating cost.
+    billable: RwLock<HashSet<String>>,
+    /// The latest token fetch of each provider with a token source.
+    auth: RwLock<HashMap<String, AuthStatus>>,
+    /// Counts for `[telemetry.prometheus]`, when it is enabled.
+    latency: RwLock<Option<Latency>>,
+    /// Sizes of bodies against `server.max_body_size`, once it is set.
+    body_sizes: RwLock<Option<BodySizes>>,
 }
 
+/// What one client has sent, as grouped by [`MetricsStore::by_client`].
+#[derive(Debug, PartialEq)]
+pub struct ClientActivity {
+    /// `-` for requests no client could be attributed to.
+    pub client: String,
+    pub totals: SessionSummary,
+    pub last_seen: Instant,
+}
+
+/// A request counted by [`MetricsStore::open_stream`].
+pub struct StreamSlot {
+    metrics: Arc<MetricsStore>,
+    connection: u64,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut connections = self
+            .metrics
+            .connections
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(streams) = connections.get_mut(&self.connection) {
+            streams.streams -= 1;
+            if streams.streams == 0 {
+                connections.remove(&self.connection);
+            }
+        }
+    }
+}
+
+/// How many comparisons are kept for the TUI.
+const MAX_COMPARISONS: usize = 1000;
+
+/// Events a subscriber can fall behind by before it misses some.
+const EVENT_CAPACITY: usize = 1024;
+
 impl MetricsStore {
     pub fn new(window: Duration) -> Self {
         Self {
             records: RwLock::new(Vec::new()),
             id_index: RwLock::new(HashMap::new()),
-            window,
+            retention: RwLock::new(window),
+            window: RwLock::new(window),
             logger: None,
             next_id: AtomicU64::new(1),
+            version: watch::Sender::new(0),
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            lifecycle: broadcast::Sender::new(EVENT_CAPACITY),
+            tool_results_truncated: AtomicU64::new(0),
+            tool_calls_repaired: AtomicU64::new(0),
+            classifications_skipped: AtomicU64::new(0),
+            requests_deduplicated: AtomicU64::new(0),
+            streams_stalled: AtomicU64::new(0),
+            stream_stall_ms: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            client_aborts: RwLock::new(BTreeMap::new()),
+            connections: RwLock::new(HashMap::new()),
+            comparisons: RwLock::new((VecDeque::new(), 0)),
+            rate_limits: RwLock::new(HashMap::new()),
+            warmth: RwLock::new(HashMap::new()),
+            health: RwLock::new(HashMap::new()),
+            quotas: RwLock::new(HashMap::new()),
+            billable: RwLock::new(HashSet::new()),
+            auth: RwLock::new(HashMap::new()),
+            latency: RwLock::new(None),
+            body_sizes: RwLock::new(None),
         }
     }
 
@@ -62,15 +358,356 @@ impl MetricsStore {
         Self {
             records: RwLock::new(Vec::new()),
             id_index: RwLock::new(HashMap::new()),
-            window,
+            retention: RwLock::new(window),
+            window: RwLock::new(window),
             logger: Some(Mutex::new(logger)),
             next_id: AtomicU64::new(1),
+            version: watch::Sender::new(0),
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            lifecycle: broadcast::Sender::new(EVENT_CAPACITY),
+            tool_results_truncated: AtomicU64::new(0),
+            tool_calls_repaired: AtomicU64::new(0),
+            classifications_skipped: AtomicU64::new(0),
+            requests_deduplicated: AtomicU64::new(0),
+            streams_stalled: AtomicU64::new(0),
+            stream_stall_ms: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            client_aborts: RwLock::new(BTreeMap::new()),
+            connections: RwLock::new(HashMap::new()),
+            comparisons: RwLock::new((VecDeque::new(), 0)),
+            rate_limits: RwLock::new(HashMap::new()),
+            warmth: RwLock::new(HashMap::new()),
+            health: RwLock::new(HashMap::new()),
+            quotas: RwLock::new(HashMap::new()),
+            billable: RwLock::new(HashSet::new()),
+            auth: RwLock::new(HashMap::new()),
+            latency: RwLock::new(None),
+            body_sizes: RwLock::new(None),
+        }
+    }
+
+    pub fn count_truncated_tool_results(&self, count: usize) {
+        self.tool_results_truncated
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Tool results cut down since startup.
+    pub fn tool_results_truncated(&self) -> u64 {
+        self.tool_results_truncated.load(Ordering::Relaxed)
+    }
+
+    pub fn count_repaired_tool_calls(&self, count: usize) {
+        self.tool_calls_repaired
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Tool calls repaired since startup.
+    pub fn tool_calls_repaired(&self) -> u64 {
+        self.tool_calls_repaired.load(Ordering::Relaxed)
+    }
+
+    pub fn count_skipped_classification(&self) {
+        self.classifications_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Auto-router classifications skipped since startup.
+    pub fn classifications_skipped(&self) -> u64 {
+        self.classifications_skipped.load(Ordering::Relaxed)
+    }
+
+    pub fn count_deduplicated(&self) {
+        self.requests_deduplicated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Duplicate requests attached or rejected since startup.
+    pub fn requests_deduplicated(&self) -> u64 {
+        self.requests_deduplicated.load(Ordering::Relaxed)
+    }
+
+    /// Counts a stream whose buffer filled up, waiting `stalled` in all
+    /// for its client to catch up.
+    pub fn count_stalled_stream(&self, stalled: Duration) {
+        self.streams_stalled.fetch_add(1, Ordering::Relaxed);
+        self.stream_stall_ms
+            .fetch_add(stalled.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_rejected_connection(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Connections from outside `server.allow_cidrs` since startup.
+    pub fn connections_rejected(&self) -> u64 {
+        self.connections_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request in flight on `connection` until the returned slot
+    /// is dropped.
+    pub fn open_stream(
+        self: &Arc<Self>,
+        connection: u64,
+        peer: Option<IpAddr>,
+        http: &str,
+    ) -> StreamSlot {
+        let mut connections = self.connections.write().expect("connections lock poisoned");
+        connections
+            .entry(connection)
+            .or_insert_with(|| ConnectionStreams {
+                connection,
+                peer,
+                http: http.to_string(),
+                streams: 0,
+            })
+            .streams += 1;
+        StreamSlot {
+            metrics: self.clone(),
+            connection,
+        }
+    }
+
+    /// Every connection with requests in flight, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionStreams> {
+        let connections = self.connections.read().expect("connections lock poisoned");
+        let mut connections: Vec<ConnectionStreams> = connections.values().cloned().collect();
+        connections.sort_by_key(|c| c.connection);
+        connections
+    }
+
+    /// Streams stalled on a slow client since startup, and the total time
+    /// they spent waiting.
+    pub fn stalled_streams(&self) -> (u64, Duration) {
+        (
+            self.streams_stalled.load(Ordering::Relaxed),
+            Duration::from_millis(self.stream_stall_ms.load(Ordering::Relaxed)),
+        )
+    }
+
+    pub fn record_comparison(&self, comparison: Comparison) {
+        {
+            let mut guard = self.comparisons.write().expect("comparisons lock poisoned");
+            let (comparisons, total) = &mut *guard;
+            comparisons.push_back(comparison);
+            if comparisons.len() > MAX_COMPARISONS {
+                comparisons.pop_front();
+            }
+            *total += 1;
+        }
+        self.bump();
+    }
+
+    /// Recent comparisons, oldest first.
+    pub fn comparisons(&self) -> Vec<Comparison> {
+        let guard = self.comparisons.read().expect("comparisons lock poisoned");
+        guard.0.iter().cloned().collect()
+    }
+
+    /// Comparisons recorded since `seen` of them had been, newest last, and
+    /// the new total.
+    pub fn comparisons_since(&self, seen: u64) -> (Vec<Comparison>, u64) {
+        let guard = self.comparisons.read().expect("comparisons lock poisoned");
+        let (comparisons, total) = &*guard;
+        let new = (total - seen.min(*total)).min(comparisons.len() as u64) as usize;
+        let recent = comparisons.iter().skip(comparisons.len() - new).cloned();
+        (recent.collect(), *total)
+    }
+
+    /// Keeps `limit` as its provider's latest.
+    pub fn record_rate_limit(&self, limit: RateLimit) {
+        self.rate_limits
+            .write()
+            .expect("rate limits lock poisoned")
+            .insert(limit.provider.clone(), limit);
+        self.bump();
+    }
+
+    pub fn rate_limit(&self, provider: &str) -> Option<RateLimit> {
+        let limits = self.rate_limits.read().expect("rate limits lock poisoned");
+        limits.get(provider).cloned()
+    }
+
+    /// Every provider's latest rate limits, by provider name.
+    pub fn rate_limits(&self) -> Vec<RateLimit> {
+        let limits = self.rate_limits.read().expect("rate limits lock poisoned");
+        let mut limits: Vec<RateLimit> = limits.values().cloned().collect();
+        limits.sort_by(|a, b| a.provider.cmp(&b.provider));
+        limits
+    }
+
+    /// Keeps `warmth` as its provider's latest warm-up ping.
+    pub fn record_warmth(&self, warmth: Warmth) {
+        self.warmth
+            .write()
+            .expect("warmth lock poisoned")
+            .insert(warmth.provider.clone(), warmth);
+        self.bump();
+    }
+
+    /// Every provider's latest warm-up ping, by provider name.
+    pub fn warmth(&self) -> Vec<Warmth> {
+        let warmth = self.warmth.read().expect("warmth lock poisoned");
+        let mut warmth: Vec<Warmth> = warmth.values().cloned().collect();
+        warmth.sort_by(|a, b| a.provider.cmp(&b.provider));
+        warmth
+    }
+
+    /// Keeps `health` as its provider's latest, as attached viewers do.
+    pub fn record_auth(&self, status: AuthStatus) {
+        self.auth
+            .write()
+            .expect("auth lock poisoned")
+            .insert(status.provider.clone(), status);
+        self.bump();
+    }
+
+    /// The latest token fetch of every provider with a token source, by
+    /// provider name.
+    pub fn auth(&self) -> Vec<AuthStatus> {
+        let auth = self.auth.read().expect("auth lock poisoned");
+        let mut auth: Vec<AuthStatus> = auth.values().cloned().collect();
+        auth.sort_by(|a, b| a.provider.cmp(&b.provider));
+        auth
+    }
+
+    pub fn record_health(&self, health: Health) {
+        self.health
+            .write()
+            .expect("health lock poisoned")
+            .insert(health.provider.clone(), health);
+    }
+
+    /// How each provider has performed lately, sorted by provider.
+    pub fn health(&self) -> Vec<Health> {
+        let health = self.health.read().expect("health lock poisoned");
+        let mut health: Vec<Health> = health.values().cloned().collect();
+        health.sort_by(|a, b| a.provider.cmp(&b.provider));
+        health
+    }
+
+    /// Keeps `usage` as its client's latest.
+    pub fn record_quota(&self, usage: QuotaUsage) {
+        self.quotas
+            .write()
+            .expect("quotas lock poisoned")
+            .insert(usage.client.clone(), usage);
+        self.bump();
+    }
+
+    /// Token use of every client with a quota, by client name.
+    pub fn quotas(&self) -> Vec<QuotaUsage> {
+        let quotas = self.quotas.read().expect("quotas lock poisoned");
+        let mut quotas: Vec<QuotaUsage> = quotas.values().cloned().collect();
+        quotas.sort_by(|a, b| a.client.cmp(&b.client));
+        quotas
+    }
+
+    pub fn set_billable(&self, providers: HashSet<String>) {
+        *self.billable.write().expect("billable lock poisoned") = providers;
+        self.bump();
+    }
+
+    /// Providers whose requests are priced, by name.
+    pub fn billable(&self) -> HashSet<String> {
+        self.billable
+            .read()
+            .expect("billable lock poisoned")
+            .clone()
+    }
+
+    /// Starts counting requests for Prometheus into buckets bounded by
+    /// `bounds` (in milliseconds), or stops with `None`. Counts are kept
+    /// while the bounds stay the same.
+    pub fn set_latency_buckets(&self, bounds: Option<Vec<u64>>) {
+        let mut latency = self.latency.write().expect("latency lock poisoned");
+        if latency.as_ref().map(Latency::bounds) != bounds.as_deref() {
+            *latency = bounds.map(Latency::new);
+        }
+    }
+
+    /// Request counts and latencies in the Prometheus text format, or
+    /// `None` when `[telemetry.prometheus]` is off.
+    pub fn prometheus(&self) -> Option<String> {
+        self.latency
+            .read()
+            .expect("latency lock poisoned")
+            .as_ref()
+            .map(Latency::render)
+    }
+
+    /// Starts counting body sizes against `limit`, warning once
+    /// `warn_percent` of requests come near it. Counts are kept while the
+    /// settings stay the same.
+    pub fn set_body_limit(&self, limit: usize, warn_percent: f64) {
+        let mut sizes = self.body_sizes.write().expect("body sizes lock poisoned");
+        let unchanged = sizes
+            .as_ref()
+            .is_some_and(|s| s.limit() == limit as u64 && s.warn_percent() == warn_percent);
+        if !unchanged {
+            *sizes = Some(BodySizes::new(limit, warn_percent));
+        }
+    }
+
+    /// Counts a body of `bytes`, under `route` once the request has been
+    /// routed, logging a warning when requests crowd the limit.
+    pub fn record_body_size(&self, direction: Direction, route: Option<&str>, bytes: usize) {
+        let warning = self
+            .body_sizes
+            .write()
+            .expect("body sizes lock poisoned")
+            .as_mut()
+            .and_then(|sizes| sizes.observe(direction, route, bytes));
+        if let Some(warning) = warning {
+            tracing::warn!("{warning}; consider raising server.max_body_size");
+        }
+    }
+
+    /// Body sizes since startup, once the limit is set.
+    pub fn body_sizes(&self) -> Option<BodySizeReport> {
+        self.body_sizes
+            .read()
+            .expect("body sizes lock poisoned")
+            .as_ref()
+            .map(BodySizes::report)
+    }
+
+    pub fn provider_health(&self, provider: &str) -> Option<Health> {
+        self.health
+            .read()
+            .expect("health lock poisoned")
+            .get(provider)
+            .cloned()
+    }
+
+    /// Folds a finished request into its provider's health and latency
+    /// counts. Faults `[chaos]` injected say nothing about the provider,
+    /// and a response its client abandoned says nothing of its latency.
+    fn observe(&self, record: &RequestRecord) {
+        if record.chaos.is_some() || record.provider.is_empty() {
+            return;
+        }
+        if record.client_aborted.is_none() {
+            self.health
+                .write()
+                .expect("health lock poisoned")
+                .entry(record.provider.clone())
+                .or_insert_with(|| Health::new(&record.provider))
+                .observe(record.status, record.duration);
+        }
+        if let Some(latency) = self
+            .latency
+            .write()
+            .expect("latency lock poisoned")
+            .as_mut()
+        {
+            latency.observe(record);
         }
     }
 
     pub fn record(&self, mut record: RequestRecord) {
         record.id = self.next_id.fetch_add(1, Ordering::Relaxed);
         self.log_record(&record);
+        self.observe(&record);
+        self.publish(MetricsEvent::Recorded, &record);
+        self.complete(&record);
         let mut records = self.records.write().expect("metrics lock poisoned");
         let idx = records.len();
         let id = record.id;
@@ -79,12 +716,15 @@ impl MetricsStore {
             .write()
             .expect("index lock poisoned")
             .insert(id, idx);
+        drop(records);
+        self.bump();
     }
 
     /// Record a pending entry and return its stable ID for later finalization.
     pub fn record_pending(&self, mut record: RequestRecord) -> u64 {
         let id = self.next_id.fetch_add(1, Ordering::Relaxed);
         record.id = id;
+        self.publish(MetricsEvent::Recorded, &record);
         let mut records = self.records.write().expect("metrics lock poisoned");
         let idx = records.len();
         records.push(record);
@@ -92,9 +732,46 @@ impl MetricsStore {
             .write()
             .expect("index lock poisoned")
             .insert(id, idx);
+        drop(records);
+        self.bump();
         id
     }
 
+    /// Notes that the client of a recorded response went away after
+    /// `bytes` of it, ahead of `finalize_stream`, and counts the abort
+    /// toward its model.
+    pub fn mark_aborted(&self, id: u64, bytes: u64) {
+        let mut records = self.records.write().expect("metrics lock poisoned");
+        let index = self.id_index.read().expect("index lock poisoned");
+        if let Some(record) = index.get(&id).and_then(|&idx| records.get_mut(idx)) {
+            record.client_aborted = Some(bytes);
+            *self
+                .client_aborts
+                .write()
+                .expect("aborts lock poisoned")
+                .entry(record.model.clone())
+                .or_default() += 1;
+        }
+    }
+
+    /// Responses abandoned by their client since startup, by model.
+    pub fn client_aborts(&self) -> BTreeMap<String, u64> {
+        self.client_aborts
+            .read()
+            .expect("aborts lock poisoned")
+            .clone()
+    }
+
+    /// Notes that a recorded response was cut off at `limit`, ahead of
+    /// `finalize_stream`.
+    pub fn mark_cutoff(&self, id: u64, limit: &str) {
+        let mut records = self.records.write().expect("metrics lock poisoned");
+        let index = self.id_index.read().expect("index lock poisoned");
+        if let Some(record) = index.get(&id).and_then(|&idx| records.get_mut(idx)) {
+            record.cutoff = Some(limit.to_string());
+        }
+    }
+
     /// Update output_tokens and duration for a previously recorded entry by ID.
     pub fn finalize_stream(&self, id: u64, output_tokens: u64, duration: Duration) {
         let completed = {
@@ -103,6 +780,9 @@ impl MetricsStore {
             if let Some(&idx) = index.get(&id) {
                 if let Some(record) = records.get_mut(idx) {
                     record.output_tokens = output_tokens;
+                    // Recorded when the response headers arrived
+                    record.timings.stream_ms =
+                        Some(duration.saturating_sub(record.duration).as_millis() as u64);
                     record.duration = duration;
                     Some(record.clone())
                 } else {
@@ -114,11 +794,82 @@ impl MetricsStore {
         };
         if let Some(record) = completed {
             self.log_record(&record);
+            self.observe(&record);
+            self.publish(MetricsEvent::Finalized, &record);
+            self.complete(&record);
+            self.bump();
+        }
+    }
+
+    /// Inserts a record under its existing ID, replacing any record with
+    /// the same ID. Used by attached viewers mirroring a daemon's store.
+    pub fn upsert(&self, record: RequestRecord) {
+        let mut records = self.records.write().expect("metrics lock poisoned");
+        let mut index = self.id_index.write().expect("index lock poisoned");
+        match index.get(&record.id) {
+            Some(&idx) => {
+                self.publish(MetricsEvent::Updated, &record);
+                records[idx] = record;
+            }
+            None => {
+                self.publish(MetricsEvent::Recorded, &record);
+                index.insert(record.id, records.len());
+                records.push(record);
+            }
         }
+        drop(index);
+        drop(records);
+        self.bump();
+    }
+
+    /// A receiver that is marked changed whenever a record is added or
+    /// updated.
+    pub fn changes(&self) -> watch::Receiver<u64> {
+        self.version.subscribe()
+    }
+
+    /// Every record added, finalized, or replaced from now on. A receiver
+    /// that falls more than 1024 events behind gets `Lagged` and should
+    /// catch up from `snapshot`.
+    pub fn subscribe(&self) -> broadcast::Receiver<MetricsEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends an event built from `record`, cloning it only when someone
+    /// is listening.
+    fn publish(&self, event: fn(RequestRecord) -> MetricsEvent, record: &RequestRecord) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event(record.clone()));
+        }
+    }
+
+    /// Every request lifecycle event from now on, with the same lag as
+    /// `subscribe`.
+    pub fn lifecycle(&self) -> broadcast::Receiver<RequestEvent> {
+        self.lifecycle.subscribe()
+    }
+
+    /// Sends the event `build` makes, building it only when someone is
+    /// listening.
+    pub fn emit(&self, build: impl FnOnce() -> RequestEvent) {
+        if self.lifecycle.receiver_count() > 0 {
+            let _ = self.lifecycle.send(build());
+        }
+    }
+
+    /// Announces that the request behind a final record has completed.
+    fn complete(&self, record: &RequestRecord) {
+        if record.request_id.is_some() {
+            self.emit(|| RequestEvent::completed(record));
+        }
+    }
+
+    fn bump(&self) {
+        self.version.send_modify(|v| *v = v.wrapping_add(1));
     }
 
     pub fn snapshot(&self) -> Vec<RequestRecord> {
-        let cutoff = Instant::now() - self.window;
+        let cutoff = Instant::now() - self.window();
         self.records
             .read()
             .expect("metrics lock poisoned")
@@ -128,16 +879,44 @@ impl MetricsStore {
             .collect()
     }
 
+    /// Every record still held, regardless of the display window.
+    pub fn retained(&self) -> Vec<RequestRecord> {
+        self.records.read().expect("metrics lock poisoned").clone()
+    }
+
+    /// The display window used by `snapshot`.
     pub fn window(&self) -> Duration {
-        self.window
+        *self.window.read().expect("window lock poisoned")
     }
 
     pub fn window_minutes(&self) -> u64 {
-        self.window.as_secs() / 60
+        self.window().as_secs() / 60
+    }
+
+    pub fn retention(&self) -> Duration {
+        *self.retention.read().expect("retention lock poisoned")
+    }
+
+    /// Changes how long records are kept, shrinking the display window if
+    /// it would now exceed retention. Takes effect at the next eviction.
+    pub fn set_retention(&self, retention: Duration) {
+        *self.retention.write().expect("retention lock poisoned") = retention;
+        {
+            let mut window = self.window.write().expect("window lock poisoned");
+            *window = (*window).min(retention);
+        }
+        self.bump();
+    }
+
+    /// Changes the display window, capped at the retention period.
+    pub fn set_window(&self, window: Duration) {
+        let retention = self.retention();
+        *self.window.write().expect("window lock poisoned") = window.min(retention);
+        self.bump();
     }
 
     pub fn evict_expired(&self) {
-        let cutoff = Instant::now() - self.window;
+        let cutoff = Instant::now() - self.retention();
         let mut records = self.records.write().expect("metrics lock poisoned");
         records.retain(|r| r.timestamp >= cutoff);
 
@@ -155,6 +934,7 @@ impl MetricsStore {
         };
         let entry = serde_json::json!({
             "timestamp": record.wallclock.to_rfc3339(),
+            "request_id": &record.request_id,
             "model": &record.model,
             "provider": &record.provider,
             "routing_method": record.routing_method.to_string(),
@@ -163,6 +943,16 @@ impl MetricsStore {
             "input_tokens": record.input_tokens,
             "output_tokens": record.output_tokens,
             "error": &record.error_body,
+            "batch_size": record.batch_size,
+            "redactions": record.redactions,
+            "listener": &record.listener,
+            "client": &record.client,
+            "cutoff": &record.cutoff,
+            "chaos": &record.chaos,
+            "bench": &record.bench,
+            "client_aborted": record.client_aborted,
+            "near_limit": record.near_limit,
+            "timings": record.timings,
         });
         if let Ok(line) = serde_json::to_string(&entry)
             && let Ok(mut l) = logger.lock()
@@ -172,6 +962,17 @@ impl MetricsStore {
         }
     }
 
+    /// `records` without those `[chaos]` injected faults into or `croxy
+    /// bench` sent, which would otherwise skew latency, error, and token
+    /// statistics.
+    pub fn without_synthetic(records: &[RequestRecord]) -> Vec<RequestRecord> {
+        records
+            .iter()
+            .filter(|r| r.chaos.is_none() && r.bench.is_none())
+            .cloned()
+            .collect()
+    }
+
     pub fn group_by<F, K>(records: &[RequestRecord], key_fn: F) -> HashMap<K, Vec<&RequestRecord>>
     where
         F: Fn(&RequestRecord) -> K,
@@ -184,6 +985,31 @@ impl MetricsStore {
         groups
     }
 
+    /// Totals for each client in `records`, by client name, pricing
+    /// requests to `billable` providers.
+    pub fn by_client(records: &[RequestRecord], billable: &HashSet<String>) -> Vec<ClientActivity> {
+        let groups = Self::group_by(records, |r| {
+            r.client.clone().unwrap_or_else(|| "-".to_string())
+        });
+        let mut clients: Vec<ClientActivity> = groups
+            .into_iter()
+            .map(|(client, records)| {
+                let records: Vec<RequestRecord> = records.into_iter().cloned().collect();
+                ClientActivity {
+                    client,
+                    totals: SessionSummary::from_records(&records, billable),
+                    last_seen: records
+                        .iter()
+                        .map(|r| r.timestamp)
+                        .max()
+                        .unwrap_or_else(Instant::now),
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client.cmp(&b.client));
+        clients
+    }
+
     pub fn duration_percentile(durations: &[Duration], p: u8) -> Duration {
         if durations.is_empty() {
             return Duration::ZERO;
@@ -227,26 +1053,50 @@ impl MetricsStore {
     pub fn requests_per_minute(records: &[RequestRecord], num_buckets: usize) -> Vec<u64> {
         Self::per_minute_buckets(records, num_buckets, |_| 1)
     }
+
+    /// Finished requests per minute by status class: successes (2xx and
+    /// 3xx), client errors (4xx), and server errors (5xx). Requests still
+    /// in flight are left out.
+    pub fn status_classes_per_minute(
+        records: &[RequestRecord],
+        num_buckets: usize,
+    ) -> [Vec<u64>; 3] {
+        let class = |range: std::ops::Range<u16>| {
+            Self::per_minute_buckets(records, num_buckets, |r| {
+                u64::from(range.contains(&r.status))
+            })
+        };
+        [class(100..400), class(400..500), class(500..600)]
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::Stage;
 
-    fn sample_record() -> RequestRecord {
-        RequestRecord {
-            id: 0,
-            timestamp: Instant::now(),
-            wallclock: Utc::now(),
-            model: "claude-opus-4-6".to_string(),
-            provider: "anthropic".to_string(),
-            routing_method: RoutingMethod::Default,
-            status: 200,
-            duration: Duration::from_millis(500),
-            input_tokens: 100,
-            output_tokens: 200,
-            error_body: None,
-        }
+    #[test]
+    fn streams_are_counted_per_connection_until_their_slots_drop() {
+        let store = Arc::new(MetricsStore::new(Duration::from_secs(60)));
+        let peer = Some(IpAddr::from([10, 0, 0, 7]));
+        let first = store.open_stream(1, peer, "HTTP/2");
+        let second = store.open_stream(1, peer, "HTTP/2");
+        let other = store.open_stream(2, None, "HTTP/1.1");
+        let connections = store.connections();
+        assert_eq!(
+            connections
+                .iter()
+                .map(|c| (c.connection, c.streams, c.http.as_str()))
+                .collect::<Vec<_>>(),
+            [(1, 2, "HTTP/2"), (2, 1, "HTTP/1.1")]
+        );
+        assert_eq!(connections[0].peer, peer);
+
+        drop(first);
+        drop(other);
+        assert_eq!(store.connections()[0].streams, 1);
+        drop(second);
+        assert!(store.connections().is_empty());
     }
 
     #[test]
@@ -255,10 +1105,35 @@ mod tests {
         assert_eq!(store.window(), Duration::from_secs(3600));
     }
 
+    #[test]
+    fn window_is_capped_by_retention() {
+        let store = MetricsStore::new(Duration::from_secs(3600));
+        store.set_window(Duration::from_secs(600));
+        assert_eq!(store.window(), Duration::from_secs(600));
+        assert_eq!(store.retention(), Duration::from_secs(3600));
+
+        store.set_window(Duration::from_secs(7200));
+        assert_eq!(store.window(), Duration::from_secs(3600));
+
+        store.set_retention(Duration::from_secs(300));
+        assert_eq!(store.window(), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn snapshot_uses_window_and_eviction_uses_retention() {
+        let store = MetricsStore::new(Duration::from_secs(60));
+        store.set_window(Duration::from_millis(50));
+        store.record(RequestRecord::sample());
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(store.snapshot().is_empty());
+        store.evict_expired();
+        assert_eq!(store.records.read().unwrap().len(), 1);
+    }
+
     #[test]
     fn records_and_retrieves() {
         let store = MetricsStore::new(Duration::from_secs(60));
-        store.record(sample_record());
+        store.record(RequestRecord::sample());
         let snap = store.snapshot();
         assert_eq!(snap.len(), 1);
         assert_eq!(snap[0].model, "claude-opus-4-6");
@@ -267,10 +1142,10 @@ mod tests {
     #[test]
     fn snapshot_excludes_expired() {
         let store = MetricsStore::new(Duration::from_millis(50));
-        let mut old = sample_record();
+        let mut old = RequestRecord::sample();
         old.timestamp = Instant::now() - Duration::from_millis(100);
         store.record(old);
-        store.record(sample_record());
+        store.record(RequestRecord::sample());
         let snap = store.snapshot();
         assert_eq!(snap.len(), 1);
     }
@@ -278,10 +1153,10 @@ mod tests {
     #[test]
     fn evict_removes_old_records() {
         let store = MetricsStore::new(Duration::from_millis(50));
-        let mut old = sample_record();
+        let mut old = RequestRecord::sample();
         old.timestamp = Instant::now() - Duration::from_millis(100);
         store.record(old);
-        store.record(sample_record());
+        store.record(RequestRecord::sample());
         store.evict_expired();
         assert_eq!(store.records.read().unwrap().len(), 1);
     }
@@ -289,7 +1164,7 @@ mod tests {
     #[test]
     fn snapshot_returns_owned_data() {
         let store = MetricsStore::new(Duration::from_secs(60));
-        store.record(sample_record());
+        store.record(RequestRecord::sample());
         let snap = store.snapshot();
         drop(snap);
         assert_eq!(store.snapshot().len(), 1);
@@ -299,9 +1174,9 @@ mod tests {
     fn group_by_model() {
         let store = MetricsStore::new(Duration::from_secs(60));
         for _ in 0..3 {
-            store.record(sample_record());
+            store.record(RequestRecord::sample());
         }
-        let mut sonnet = sample_record();
+        let mut sonnet = RequestRecord::sample();
         sonnet.model = "claude-sonnet-4-5-20250929".to_string();
         store.record(sonnet);
 
@@ -312,11 +1187,38 @@ mod tests {
         assert_eq!(groups["claude-sonnet-4-5-20250929"].len(), 1);
     }
 
+    #[test]
+    fn by_client_totals_each_client() {
+        let intern = |status, provider: &str| RequestRecord {
+            client: Some("intern".to_string()),
+            status,
+            provider: provider.to_string(),
+            ..RequestRecord::sample()
+        };
+        let records = vec![
+            intern(200, "anthropic"),
+            intern(429, "anthropic"),
+            intern(200, "ollama"),
+            RequestRecord::sample(),
+        ];
+        let billable = HashSet::from(["anthropic".to_string()]);
+        let clients = MetricsStore::by_client(&records, &billable);
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].client, "-");
+        assert_eq!(clients[1].client, "intern");
+        let totals = &clients[1].totals;
+        assert_eq!((totals.requests, totals.errors), (3, 1));
+        assert_eq!(totals.input_tokens, 300);
+        // Only the successful request to Anthropic is priced
+        let one = crate::pricing::estimate_cost_usd("claude-opus-4-6", 100, 200).unwrap();
+        assert!((totals.cost_usd - one).abs() < 1e-9);
+    }
+
     #[test]
     fn status_counts_all_codes() {
         let store = MetricsStore::new(Duration::from_secs(60));
         for status in [200, 200, 429, 429, 429, 500] {
-            let mut r = sample_record();
+            let mut r = RequestRecord::sample();
             r.status = status;
             store.record(r);
         }
@@ -332,7 +1234,7 @@ mod tests {
     fn tokens_per_minute_buckets() {
         let store = MetricsStore::new(Duration::from_secs(300));
         for _ in 0..3 {
-            let mut r = sample_record();
+            let mut r = RequestRecord::sample();
             r.input_tokens = 100;
             r.output_tokens = 50;
             store.record(r);
@@ -348,7 +1250,7 @@ mod tests {
     fn requests_per_minute_buckets() {
         let store = MetricsStore::new(Duration::from_secs(300));
         for _ in 0..5 {
-            store.record(sample_record());
+            store.record(RequestRecord::sample());
         }
         let snap = store.snapshot();
         let buckets = MetricsStore::requests_per_minute(&snap, 5);
@@ -356,11 +1258,26 @@ mod tests {
         assert_eq!(*buckets.last().unwrap(), 5);
     }
 
+    #[test]
+    fn status_classes_per_minute_buckets() {
+        let store = MetricsStore::new(Duration::from_secs(300));
+        for status in [200, 200, 304, 429, 400, 502, 0] {
+            let mut r = RequestRecord::sample();
+            r.status = status;
+            store.record(r);
+        }
+        let snap = store.snapshot();
+        let [ok, client, server] = MetricsStore::status_classes_per_minute(&snap, 5);
+        assert_eq!(ok, [0, 0, 0, 0, 3]);
+        assert_eq!(client, [0, 0, 0, 0, 2]);
+        assert_eq!(server, [0, 0, 0, 0, 1]);
+    }
+
     #[test]
     fn record_pending_returns_unique_ids() {
         let store = MetricsStore::new(Duration::from_secs(60));
-        let id0 = store.record_pending(sample_record());
-        let id1 = store.record_pending(sample_record());
+        let id0 = store.record_pending(RequestRecord::sample());
+        let id1 = store.record_pending(RequestRecord::sample());
         assert_ne!(id0, id1);
         assert!(id0 > 0);
         assert!(id1 > 0);
@@ -369,7 +1286,7 @@ mod tests {
     #[test]
     fn finalize_stream_updates_record_by_id() {
         let store = MetricsStore::new(Duration::from_secs(60));
-        let mut rec = sample_record();
+        let mut rec = RequestRecord::sample();
         rec.output_tokens = 0;
         rec.duration = Duration::ZERO;
         let id = store.record_pending(rec);
@@ -385,7 +1302,7 @@ mod tests {
     #[test]
     fn finalize_stream_ignores_unknown_id() {
         let store = MetricsStore::new(Duration::from_secs(60));
-        store.record(sample_record());
+        store.record(RequestRecord::sample());
         // Should not panic
         store.finalize_stream(999_999, 100, Duration::from_secs(1));
         assert_eq!(store.snapshot().len(), 1);
@@ -395,12 +1312,12 @@ mod tests {
     fn finalize_stable_after_eviction() {
         let store = MetricsStore::new(Duration::from_millis(50));
         // Insert an old record that will be evicted
-        let mut old = sample_record();
+        let mut old = RequestRecord::sample();
         old.timestamp = Instant::now() - Duration::from_millis(100);
         store.record(old);
 
         // Insert the pending record
-        let mut rec = sample_record();
+        let mut rec = RequestRecord::sample();
         rec.output_tokens = 0;
         let id = store.record_pending(rec);
 
@@ -430,7 +1347,7 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         let store = store_with_logger(dir.path());
 
-        store.record(sample_record());
+        store.record(RequestRecord::sample());
 
         let content = std::fs::read_to_string(dir.path().join("metrics.jsonl")).unwrap();
         let entry: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
@@ -444,7 +1361,7 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         let store = store_with_logger(dir.path());
 
-        let mut rec = sample_record();
+        let mut rec = RequestRecord::sample();
         rec.output_tokens = 0;
         rec.duration = Duration::ZERO;
         let id = store.record_pending(rec);
@@ -461,11 +1378,42 @@ mod tests {
         assert_eq!(entry["duration_ms"], 3000);
     }
 
+    #[test]
+    fn finalized_streams_log_their_timings() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store_with_logger(dir.path());
+
+        let mut rec = RequestRecord::sample();
+        rec.duration = Duration::from_millis(900);
+        rec.timings = Timings {
+            body_read_ms: Some(2),
+            routing_ms: Some(40),
+            classifier_ms: Some(35),
+            connect_ms: Some(120),
+            ttfb_ms: Some(850),
+            stream_ms: None,
+        };
+        let id = store.record_pending(rec);
+        store.finalize_stream(id, 500, Duration::from_millis(3900));
+
+        let content = std::fs::read_to_string(dir.path().join("metrics.jsonl")).unwrap();
+        let entry: crate::history::Entry = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(entry.timings.stream_ms, Some(3000));
+        assert_eq!(
+            entry.timings.describe(entry.duration_ms).as_deref(),
+            Some(
+                "body 2ms, routing 40ms (classifier 35ms), first byte 850ms (connect 120ms), \
+                 stream 3.00s, croxy 8ms"
+            )
+        );
+        assert_eq!(Timings::default().describe(100), None);
+    }
+
     #[test]
     fn percentile_duration() {
         let store = MetricsStore::new(Duration::from_secs(60));
         for ms in [100, 200, 300, 400, 500, 600, 700, 800, 900, 1000] {
-            let mut r = sample_record();
+            let mut r = RequestRecord::sample();
             r.duration = Duration::from_millis(ms);
             store.record(r);
         }
@@ -498,4 +1446,90 @@ mod tests {
             Duration::from_millis(42)
         );
     }
+
+    #[test]
+    fn upsert_replaces_by_id() {
+        let store = MetricsStore::new(Duration::from_secs(60));
+        let mut record = RequestRecord::sample();
+        record.id = 7;
+        store.upsert(record.clone());
+        record.output_tokens = 999;
+        store.upsert(record);
+        let snap = store.snapshot();
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap[0].id, 7);
+        assert_eq!(snap[0].output_tokens, 999);
+    }
+
+    #[test]
+    fn changes_fire_on_record_and_finalize() {
+        let store = MetricsStore::new(Duration::from_secs(60));
+        let mut changes = store.changes();
+        assert!(!changes.has_changed().unwrap());
+
+        let id = store.record_pending(RequestRecord::sample());
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        store.finalize_stream(id, 10, Duration::from_millis(5));
+        assert!(changes.has_changed().unwrap());
+    }
+
+    #[test]
+    fn subscribers_receive_record_and_finalize_events() {
+        let store = MetricsStore::new(Duration::from_secs(60));
+        // Nothing is sent before anyone subscribes
+        store.record(RequestRecord::sample());
+        let mut events = store.subscribe();
+
+        let id = store.record_pending(RequestRecord::sample());
+        store.finalize_stream(id, 10, Duration::from_millis(5));
+        let mut mirrored = RequestRecord::sample();
+        mirrored.id = id;
+        store.upsert(mirrored);
+
+        match events.try_recv().unwrap() {
+            MetricsEvent::Recorded(record) => {
+                assert_eq!(record.id, id);
+                assert_eq!(record.output_tokens, 200);
+            }
+            other => panic!("expected Recorded, got {other:?}"),
+        }
+        match events.try_recv().unwrap() {
+            MetricsEvent::Finalized(record) => {
+                assert_eq!(record.id, id);
+                assert_eq!(record.output_tokens, 10);
+            }
+            other => panic!("expected Finalized, got {other:?}"),
+        }
+        assert!(matches!(events.try_recv(), Ok(MetricsEvent::Updated(_))));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn requests_complete_when_recorded_or_finalized() {
+        let store = MetricsStore::new(Duration::from_secs(60));
+        let mut lifecycle = store.lifecycle();
+        let request = |id: &str| RequestRecord {
+            request_id: Some(id.to_string()),
+            ..RequestRecord::sample()
+        };
+
+        store.record(request("a-1"));
+        let id = store.record_pending(request("a-2"));
+        store.finalize_stream(id, 10, Duration::from_millis(5));
+        // Records without a request behind them, and mirrored ones, aren't
+        store.record(RequestRecord::sample());
+        store.upsert(request("a-3"));
+
+        let first = lifecycle.try_recv().unwrap();
+        assert_eq!(
+            (first.stage, first.request_id.as_str()),
+            (Stage::Completed, "a-1")
+        );
+        let second = lifecycle.try_recv().unwrap();
+        assert_eq!(second.request_id, "a-2");
+        assert_eq!(second.output_tokens, Some(10));
+        assert!(lifecycle.try_recv().is_err());
+    }
 }
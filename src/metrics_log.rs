@@ -1,27 +1,43 @@
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use crate::config::MetricsLogConfig;
+use crate::config::{AppLogConfig, MetricsLogConfig};
 
-pub struct MetricsLogger {
+/// Append-only file that rotates to `<name>.1`, `<name>.2`, ... once it
+/// exceeds a size limit or, optionally, an age limit.
+pub struct RotatingFile {
     path: PathBuf,
     max_size: u64,
     max_files: u32,
+    max_age: Option<Duration>,
+    opened_at: SystemTime,
     writer: BufWriter<File>,
 }
 
-impl MetricsLogger {
-    pub fn new(config: &MetricsLogConfig) -> io::Result<Self> {
-        let path = PathBuf::from(&config.path);
+impl RotatingFile {
+    pub fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_files: u32,
+        max_age: Option<Duration>,
+    ) -> io::Result<Self> {
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
         let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Age counts from when the file was first written, not from this open.
+        let opened_at = file
+            .metadata()
+            .and_then(|m| m.created())
+            .unwrap_or_else(|_| SystemTime::now());
         Ok(Self {
             path,
-            max_size: config.max_size_mb * 1024 * 1024,
-            max_files: config.max_files,
+            max_size,
+            max_files,
+            max_age,
+            opened_at,
             writer: BufWriter::new(file),
         })
     }
@@ -34,7 +50,10 @@ impl MetricsLogger {
 
     fn maybe_rotate(&mut self) -> io::Result<()> {
         let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
-        if size < self.max_size {
+        let expired = self
+            .max_age
+            .is_some_and(|age| self.opened_at.elapsed().unwrap_or_default() >= age);
+        if size < self.max_size && !expired {
             return Ok(());
         }
         self.rotate()
@@ -66,11 +85,61 @@ impl MetricsLogger {
             .append(true)
             .open(&self.path)?;
         self.writer = BufWriter::new(file);
+        self.opened_at = SystemTime::now();
 
         Ok(())
     }
 }
 
+/// Lets the file back a tracing writer; rotation is checked after each
+/// complete line so events are never split across files.
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        if buf[..n].ends_with(b"\n") {
+            self.writer.flush()?;
+            self.maybe_rotate()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct MetricsLogger {
+    file: RotatingFile,
+}
+
+impl MetricsLogger {
+    pub fn new(config: &MetricsLogConfig) -> io::Result<Self> {
+        let file = RotatingFile::open(
+            PathBuf::from(&config.path),
+            config.max_size_mb * 1024 * 1024,
+            config.max_files,
+            None,
+        )?;
+        Ok(Self { file })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_line(line)
+    }
+}
+
+/// Opens the application log (`croxy.log`) with the `[logging.app]` limits.
+pub fn open_app_log(path: PathBuf, config: &AppLogConfig) -> io::Result<RotatingFile> {
+    RotatingFile::open(
+        path,
+        config.max_size_mb * 1024 * 1024,
+        config.max_files,
+        config
+            .max_age_hours
+            .map(|h| Duration::from_secs(h.saturating_mul(3600))),
+    )
+}
+
 pub(crate) fn rotated_path(base: &Path, index: u32) -> PathBuf {
     let name = base.file_name().unwrap_or_default().to_string_lossy();
     base.with_file_name(format!("{name}.{index}"))
@@ -183,4 +252,45 @@ mod tests {
         assert_eq!(lines[0], "existing");
         assert_eq!(lines[1], "new");
     }
+
+    #[test]
+    fn rotates_when_age_exceeded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("croxy.log");
+        let mut file = RotatingFile::open(path.clone(), u64::MAX, 2, Some(Duration::ZERO)).unwrap();
+
+        file.write_line("old").unwrap();
+        assert!(dir.path().join("croxy.log.1").exists());
+        let rotated = fs::read_to_string(dir.path().join("croxy.log.1")).unwrap();
+        assert_eq!(rotated, "old\n");
+    }
+
+    #[test]
+    fn write_impl_rotates_on_line_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("croxy.log");
+        let mut file = RotatingFile::open(path.clone(), 8, 3, None).unwrap();
+
+        file.write_all(b"partial ").unwrap();
+        assert!(!dir.path().join("croxy.log.1").exists());
+        file.write_all(b"line\n").unwrap();
+        assert!(dir.path().join("croxy.log.1").exists());
+        let rotated = fs::read_to_string(dir.path().join("croxy.log.1")).unwrap();
+        assert_eq!(rotated, "partial line\n");
+    }
+
+    #[test]
+    fn app_log_uses_configured_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AppLogConfig {
+            max_size_mb: 0,
+            max_files: 1,
+            max_age_hours: None,
+        };
+        let mut file = open_app_log(dir.path().join("croxy.log"), &config).unwrap();
+        file.write_line("a").unwrap();
+        file.write_line("b").unwrap();
+        assert!(dir.path().join("croxy.log.1").exists());
+        assert!(!dir.path().join("croxy.log.2").exists());
+    }
 }
@@ -0,0 +1,305 @@
+//! The `[[middleware]]` pipeline: transforms applied, in config order, to
+//! each request's headers and body after it is routed and before it is
+//! forwarded. Each stage can be limited to some providers.
+
+use http::HeaderMap;
+use http::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+
+use crate::config::{MiddlewareConfig, MiddlewareKind, PromptPosition};
+use crate::redact::Redaction;
+
+enum Transform {
+    Redact(Redaction),
+    SetHeader(HeaderName, HeaderValue),
+    RemoveHeader(HeaderName),
+    MaxTokens(u64),
+    SystemPrompt(String, PromptPosition),
+}
+
+impl Transform {
+    fn from_config(config: &MiddlewareConfig) -> Result<Self, String> {
+        let required = |value: Option<&String>, field: &str| {
+            value
+                .cloned()
+                .ok_or_else(|| format!("{} needs {field}", kind_name(config.kind)))
+        };
+        let header_name = |name: String| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name '{name}'"))
+        };
+        Ok(match config.kind {
+            MiddlewareKind::Redact => Transform::Redact(Redaction::new(
+                config.pattern.as_deref(),
+                &config.words,
+                &config.replacement,
+            )?),
+            MiddlewareKind::SetHeader => {
+                let name = header_name(required(config.name.as_ref(), "name")?)?;
+                let value = required(config.value.as_ref(), "value")?;
+                let value = HeaderValue::from_str(&value)
+                    .map_err(|_| format!("invalid value for header '{name}'"))?;
+                Transform::SetHeader(name, value)
+            }
+            MiddlewareKind::RemoveHeader => {
+                Transform::RemoveHeader(header_name(required(config.name.as_ref(), "name")?)?)
+            }
+            MiddlewareKind::MaxTokens => match config.max {
+                Some(max) if max > 0 => Transform::MaxTokens(max),
+                _ => return Err("max_tokens needs max greater than 0".to_string()),
+            },
+            MiddlewareKind::SystemPrompt => {
+                Transform::SystemPrompt(required(config.text.as_ref(), "text")?, config.position)
+            }
+        })
+    }
+}
+
+fn kind_name(kind: MiddlewareKind) -> &'static str {
+    match kind {
+        MiddlewareKind::Redact => "redact",
+        MiddlewareKind::SetHeader => "set_header",
+        MiddlewareKind::RemoveHeader => "remove_header",
+        MiddlewareKind::MaxTokens => "max_tokens",
+        MiddlewareKind::SystemPrompt => "system_prompt",
+    }
+}
+
+struct Stage {
+    transform: Transform,
+    providers: Vec<String>,
+}
+
+/// What the pipeline did to a request.
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    /// Matches replaced by `redact` stages.
+    pub redactions: usize,
+    pub body_changed: bool,
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn from_config(stages: &[MiddlewareConfig]) -> Result<Self, String> {
+        let stages = stages
+            .iter()
+            .enumerate()
+            .map(|(i, config)| {
+                Ok(Stage {
+                    transform: Transform::from_config(config)
+                        .map_err(|e| format!("middleware.{i}: {e}"))?,
+                    providers: config.providers.clone(),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { stages })
+    }
+
+    /// Runs the stages for `provider` over a request's outgoing headers
+    /// and JSON body.
+    pub fn apply(
+        &self,
+        provider: &str,
+        headers: &mut HeaderMap,
+        mut body: Option<&mut Value>,
+    ) -> Applied {
+        let mut applied = Applied::default();
+        for stage in self.stages_for(provider) {
+            match (&stage.transform, body.as_deref_mut()) {
+                (Transform::SetHeader(name, value), _) => {
+                    headers.insert(name.clone(), value.clone());
+                }
+                (Transform::RemoveHeader(name), _) => {
+                    headers.remove(name);
+                }
+                (Transform::Redact(redaction), Some(body)) => {
+                    let count = redaction.apply(body);
+                    applied.redactions += count;
+                    applied.body_changed |= count > 0;
+                }
+                (Transform::MaxTokens(max), Some(body))
+                    if body["messages"].is_array()
+                        && body["max_tokens"].as_u64().is_some_and(|n| n > *max) =>
+                {
+                    body["max_tokens"] = Value::from(*max);
+                    applied.body_changed = true;
+                }
+                (Transform::SystemPrompt(text, position), Some(body))
+                    if body["messages"].is_array() =>
+                {
+                    add_system_prompt(body, text, *position);
+                    applied.body_changed = true;
+                }
+                _ => {}
+            }
+        }
+        applied
+    }
+
+    /// Whether any stage for `provider` looks at the request body.
+    pub fn reads_body(&self, provider: &str) -> bool {
+        self.stages_for(provider).any(|stage| {
+            !matches!(
+                stage.transform,
+                Transform::SetHeader(..) | Transform::RemoveHeader(_)
+            )
+        })
+    }
+
+    fn stages_for<'a>(&'a self, provider: &'a str) -> impl Iterator<Item = &'a Stage> + 'a {
+        self.stages
+            .iter()
+            .filter(move |s| s.providers.is_empty() || s.providers.iter().any(|p| p == provider))
+    }
+}
+
+/// Checks one `[[middleware]]` entry, as `Pipeline::from_config` would.
+pub fn check(config: &MiddlewareConfig) -> Result<(), String> {
+    Transform::from_config(config).map(|_| ())
+}
+
+fn add_system_prompt(body: &mut Value, text: &str, position: PromptPosition) {
+    let system = &mut body["system"];
+    match system {
+        Value::String(existing) => {
+            *existing = match position {
+                PromptPosition::Prepend => format!("{text}\n\n{existing}"),
+                PromptPosition::Append => format!("{existing}\n\n{text}"),
+            };
+        }
+        Value::Array(blocks) => {
+            let block = serde_json::json!({"type": "text", "text": text});
+            match position {
+                PromptPosition::Prepend => blocks.insert(0, block),
+                PromptPosition::Append => blocks.push(block),
+            }
+        }
+        _ => *system = Value::String(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+    use serde_json::json;
+
+    fn pipeline(toml: &str) -> Pipeline {
+        #[derive(serde::Deserialize)]
+        struct Stages {
+            middleware: Vec<MiddlewareConfig>,
+        }
+        let stages: Stages = Figment::from(Toml::string(toml)).extract().unwrap();
+        Pipeline::from_config(&stages.middleware).unwrap()
+    }
+
+    #[test]
+    fn stages_run_in_order_for_their_providers() {
+        let pipeline = pipeline(
+            r#"
+            [[middleware]]
+            type = "set_header"
+            name = "X-Team"
+            value = "platform"
+            [[middleware]]
+            type = "remove_header"
+            name = "anthropic-beta"
+            providers = ["ollama"]
+            [[middleware]]
+            type = "max_tokens"
+            max = 4096
+            providers = ["ollama"]
+            [[middleware]]
+            type = "redact"
+            words = ["corp.internal"]
+            "#,
+        );
+        let request = || {
+            let mut headers = HeaderMap::new();
+            headers.insert("anthropic-beta", "tools".parse().unwrap());
+            let body = json!({
+                "max_tokens": 32000,
+                "messages": [{"role": "user", "content": "ping corp.internal"}]
+            });
+            (headers, body)
+        };
+
+        let (mut headers, mut body) = request();
+        let applied = pipeline.apply("ollama", &mut headers, Some(&mut body));
+        assert_eq!(
+            applied,
+            Applied {
+                redactions: 1,
+                body_changed: true
+            }
+        );
+        assert_eq!(headers["x-team"], "platform");
+        assert!(!headers.contains_key("anthropic-beta"));
+        assert_eq!(body["max_tokens"], 4096);
+
+        let (mut headers, mut body) = request();
+        pipeline.apply("anthropic", &mut headers, Some(&mut body));
+        assert!(headers.contains_key("anthropic-beta"));
+        assert_eq!(body["max_tokens"], 32000);
+        assert_eq!(body["messages"][0]["content"], "ping [REDACTED]");
+    }
+
+    #[test]
+    fn system_prompt_is_added_to_any_form() {
+        let append = pipeline("[[middleware]]\ntype = \"system_prompt\"\ntext = \"Be brief.\"\n");
+        let prepend = pipeline(
+            "[[middleware]]\ntype = \"system_prompt\"\ntext = \"Be brief.\"\nposition = \"prepend\"\n",
+        );
+        let mut headers = HeaderMap::new();
+
+        let mut body = json!({"messages": []});
+        append.apply("anthropic", &mut headers, Some(&mut body));
+        assert_eq!(body["system"], "Be brief.");
+
+        let mut body = json!({"messages": [], "system": "You code."});
+        prepend.apply("anthropic", &mut headers, Some(&mut body));
+        assert_eq!(body["system"], "Be brief.\n\nYou code.");
+
+        let mut body = json!({"messages": [], "system": [{"type": "text", "text": "You code."}]});
+        append.apply("anthropic", &mut headers, Some(&mut body));
+        assert_eq!(body["system"][1]["text"], "Be brief.");
+
+        // Not a Messages request
+        let mut body = json!({"requests": []});
+        assert!(
+            !append
+                .apply("anthropic", &mut headers, Some(&mut body))
+                .body_changed
+        );
+    }
+
+    #[test]
+    fn stages_need_their_params() {
+        let check = |toml: &str| {
+            #[derive(serde::Deserialize)]
+            struct Stage {
+                middleware: MiddlewareConfig,
+            }
+            let stage: Stage = Figment::from(Toml::string(toml)).extract().unwrap();
+            super::check(&stage.middleware)
+        };
+        assert_eq!(
+            check("[middleware]\ntype = \"set_header\"\nname = \"x-a\"\n"),
+            Err("set_header needs value".to_string())
+        );
+        assert_eq!(
+            check("[middleware]\ntype = \"remove_header\"\nname = \"bad name\"\n"),
+            Err("invalid header name 'bad name'".to_string())
+        );
+        assert_eq!(
+            check("[middleware]\ntype = \"max_tokens\"\nmax = 0\n"),
+            Err("max_tokens needs max greater than 0".to_string())
+        );
+        assert!(check("[middleware]\ntype = \"redact\"\n").is_err());
+    }
+}
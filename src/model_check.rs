@@ -0,0 +1,269 @@
+//! Checks for models a route rewrites to that its Ollama server hasn't
+//! pulled. Ollama answers a request for one with a 404 the client shows as
+//! an inscrutable error, so providers with `missing_models` have their
+//! model list checked at startup and on an interval, and croxy warns, asks
+//! for the model to be pulled, or switches the route off until it is.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+
+use crate::config::{Config, MissingModels};
+use crate::proxy::AppState;
+use crate::router::Router;
+
+/// How often models are checked without `model_check_secs`.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Listing models should be quick; pulling one can take many minutes.
+const LIST_TIMEOUT: Duration = Duration::from_secs(5);
+const PULL_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// A provider whose models are checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub provider: String,
+    pub url: String,
+    pub action: MissingModels,
+    pub interval: Duration,
+}
+
+/// Providers with `missing_models`.
+pub fn targets(config: &Config) -> Vec<Target> {
+    let mut targets: Vec<Target> = config
+        .providers
+        .iter()
+        .filter_map(|(name, provider)| {
+            Some(Target {
+                provider: name.clone(),
+                url: provider.url.clone(),
+                action: provider.missing_models?,
+                interval: provider
+                    .model_check_secs
+                    .map_or(DEFAULT_INTERVAL, Duration::from_secs),
+            })
+        })
+        .collect();
+    targets.sort_by(|a, b| a.provider.cmp(&b.provider));
+    targets
+}
+
+/// The models the Ollama server at `url` has pulled.
+pub async fn pulled_models(client: &reqwest::Client, url: &str) -> Result<Vec<String>, String> {
+    let response = client
+        .get(format!("{}/api/tags", url.trim_end_matches('/')))
+        .timeout(LIST_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("failed to list models: {e}"))?;
+    let tags: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("failed to list models: {e}"))?;
+    Ok(tags["models"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|model| model["name"].as_str().map(String::from))
+        .collect())
+}
+
+/// Whether `model` is among `pulled`, which name untagged models
+/// `:latest`.
+pub fn is_pulled(pulled: &[String], model: &str) -> bool {
+    let tagged = format!("{model}:latest");
+    pulled.iter().any(|name| *name == model || *name == tagged)
+}
+
+/// What one target's checks have done so far.
+pub struct Checker {
+    target: Target,
+    /// Routes switched off for a missing model, and the router they were
+    /// switched off in; a config reload starts them over.
+    disabled: HashSet<usize>,
+    router: Option<Arc<Router>>,
+    /// Models already warned about, so each missing one is logged once.
+    warned: HashSet<String>,
+}
+
+impl Checker {
+    pub fn new(target: Target) -> Self {
+        Self {
+            target,
+            disabled: HashSet::new(),
+            router: None,
+            warned: HashSet::new(),
+        }
+    }
+
+    /// Checks the models routes rewrite to against those the provider has
+    /// pulled, and deals with any that are missing.
+    pub async fn check(&mut self, state: &AppState) {
+        let provider = &self.target.provider;
+        let client = state.client_for(provider);
+        let pulled = match pulled_models(client, &self.target.url).await {
+            Ok(pulled) => pulled,
+            Err(e) => {
+                tracing::warn!(provider = %provider, "{e}");
+                return;
+            }
+        };
+        let router = state.router();
+        if !self
+            .router
+            .as_ref()
+            .is_some_and(|checked| Arc::ptr_eq(checked, &router))
+        {
+            self.disabled.clear();
+            self.router = Some(router.clone());
+        }
+
+        for route in router.routes() {
+            let Some(model) = route.model.filter(|_| route.provider == *provider) else {
+                continue;
+            };
+            if is_pulled(&pulled, &model) {
+                self.warned.remove(&model);
+                if self.disabled.remove(&route.index) {
+                    tracing::info!(
+                        provider = %provider,
+                        model = %model,
+                        route = %route.pattern,
+                        "model is pulled now, switching its route back on"
+                    );
+                    let _ = router.set_route_enabled(route.index, true);
+                }
+                continue;
+            }
+            match self.target.action {
+                MissingModels::Warn => {
+                    if self.warned.insert(model.clone()) {
+                        tracing::warn!(
+                            provider = %provider,
+                            model = %model,
+                            route = %route.pattern,
+                            "route rewrites to a model the provider hasn't pulled"
+                        );
+                    }
+                }
+                MissingModels::Pull => {
+                    tracing::warn!(provider = %provider, model = %model, "pulling missing model");
+                    match pull(client, &self.target.url, &model).await {
+                        Ok(()) => {
+                            tracing::info!(provider = %provider, model = %model, "pulled model")
+                        }
+                        Err(e) => tracing::warn!(
+                            provider = %provider,
+                            model = %model,
+                            "failed to pull model: {e}"
+                        ),
+                    }
+                }
+                MissingModels::Fallback => {
+                    if route.enabled && self.disabled.insert(route.index) {
+                        tracing::warn!(
+                            provider = %provider,
+                            model = %model,
+                            route = %route.pattern,
+                            "model isn't pulled, switching its route off until it is"
+                        );
+                        let _ = router.set_route_enabled(route.index, false);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Asks the Ollama server at `url` to pull `model`, waiting until it has.
+async fn pull(client: &reqwest::Client, url: &str, model: &str) -> Result<(), String> {
+    let response = client
+        .post(format!("{}/api/pull", url.trim_end_matches('/')))
+        .json(&json!({"model": model, "stream": false}))
+        .timeout(PULL_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body: Value = response.json().await.unwrap_or_default();
+    Err(body["error"]
+        .as_str()
+        .map_or_else(|| status.to_string(), str::to_string))
+}
+
+/// Checks every target on its interval, starting now, until dropped.
+pub async fn run(state: Arc<AppState>, targets: Vec<Target>) {
+    futures::future::join_all(targets.into_iter().map(|target| {
+        let state = state.clone();
+        async move {
+            let mut interval = tokio::time::interval(target.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut checker = Checker::new(target);
+            loop {
+                interval.tick().await;
+                checker.check(&state).await;
+            }
+        }
+    }))
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    #[test]
+    fn untagged_models_match_latest() {
+        let pulled = ["llama3:latest".to_string(), "qwen3:8b".to_string()];
+        assert!(is_pulled(&pulled, "llama3"));
+        assert!(is_pulled(&pulled, "llama3:latest"));
+        assert!(is_pulled(&pulled, "qwen3:8b"));
+        assert!(!is_pulled(&pulled, "qwen3"));
+        assert!(!is_pulled(&pulled, "qwen3-coder:30b"));
+    }
+
+    #[test]
+    fn providers_with_missing_models_are_targets() {
+        let config: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [provider.anthropic]
+                url = "https://api.anthropic.com"
+                [provider.ollama]
+                url = "http://localhost:11434"
+                missing_models = "fallback"
+                [provider.gpu]
+                url = "http://gpu:11434"
+                missing_models = "pull"
+                model_check_secs = 60
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            targets(&config),
+            [
+                Target {
+                    provider: "gpu".to_string(),
+                    url: "http://gpu:11434".to_string(),
+                    action: MissingModels::Pull,
+                    interval: Duration::from_secs(60),
+                },
+                Target {
+                    provider: "ollama".to_string(),
+                    url: "http://localhost:11434".to_string(),
+                    action: MissingModels::Fallback,
+                    interval: DEFAULT_INTERVAL,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! `[notifications]`: runs a command, such as `notify-send` or
+//! `osascript`, when a provider goes down or comes back, a virtual key
+//! nears its budget, or a long request finishes. Each finished request is
+//! checked against the rules as it lands in the metrics store.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::alerts;
+use crate::config::{Config, NotifyOn};
+use crate::keys::KeyStore;
+use crate::metrics::{MetricsEvent, MetricsStore, RequestRecord};
+use crate::proxy::AppState;
+use crate::tui::views::format_duration;
+
+/// How long a notification command may run before it is abandoned.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What to notify about, and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rules {
+    pub command: Vec<String>,
+    pub on: Vec<NotifyOn>,
+    pub budget_threshold: f64,
+    pub long_request: Duration,
+    pub down_error_rate: f64,
+}
+
+impl Rules {
+    /// The rules `[notifications]` sets, or `None` when it has no command.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let notifications = &config.notifications;
+        if notifications.command.is_empty() {
+            return None;
+        }
+        Some(Self {
+            command: notifications.command.clone(),
+            on: notifications.on.clone(),
+            budget_threshold: notifications.budget_threshold,
+            long_request: Duration::from_secs(notifications.long_request_secs),
+            down_error_rate: notifications.down_error_rate,
+        })
+    }
+
+    fn notifies(&self, on: NotifyOn) -> bool {
+        self.on.contains(&on)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub on: NotifyOn,
+    pub message: String,
+}
+
+/// Checks finished requests against the rules, remembering what it has
+/// already notified about so each change is reported once.
+pub struct Watcher {
+    rules: Rules,
+    /// Providers reported down and not yet recovered.
+    down: HashSet<String>,
+    /// Keys reported past the budget threshold.
+    over_budget: HashSet<String>,
+}
+
+impl Watcher {
+    /// Keys already past the threshold when croxy starts aren't reported.
+    pub fn new(rules: Rules, keys: &KeyStore) -> Self {
+        let over_budget = alerts::over_budget(keys, rules.budget_threshold)
+            .iter()
+            .map(|condition| condition.subject().to_string())
+            .collect();
+        Self {
+            rules,
+            down: HashSet::new(),
+            over_budget,
+        }
+    }
+
+    /// What is worth notifying about now that `record` has finished: the
+    /// request itself if it was long, and the [`alerts`] conditions on
+    /// providers and budgets that started or ended since the last one.
+    pub fn observe(
+        &mut self,
+        record: &RequestRecord,
+        metrics: &MetricsStore,
+        keys: &KeyStore,
+    ) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        let mut notify = |on, message| notifications.push(Notification { on, message });
+
+        if self.rules.notifies(NotifyOn::LongRequest) && record.duration >= self.rules.long_request
+        {
+            notify(
+                NotifyOn::LongRequest,
+                format!(
+                    "{} request to {} finished after {}",
+                    record.model,
+                    record.provider,
+                    format_duration(record.duration)
+                ),
+            );
+        }
+
+        if self.rules.notifies(NotifyOn::ProviderDown) {
+            for condition in alerts::down(metrics, self.rules.down_error_rate) {
+                if self.down.insert(condition.subject().to_string()) {
+                    notify(NotifyOn::ProviderDown, condition.to_string());
+                }
+            }
+            // Recovery waits for the error rate to halve, so a provider
+            // hovering at the threshold doesn't flap
+            let failing: HashSet<String> = alerts::down(metrics, self.rules.down_error_rate / 2.0)
+                .iter()
+                .map(|condition| condition.subject().to_string())
+                .collect();
+            let mut recovered: Vec<String> = self
+                .down
+                .iter()
+                .filter(|provider| !failing.contains(*provider))
+                .cloned()
+                .collect();
+            recovered.sort();
+            for provider in recovered {
+                self.down.remove(&provider);
+                notify(NotifyOn::ProviderDown, format!("{provider} has recovered"));
+            }
+        }
+
+        if self.rules.notifies(NotifyOn::Budget) {
+            let conditions = alerts::over_budget(keys, self.rules.budget_threshold);
+            self.over_budget
+                .retain(|key| conditions.iter().any(|c| c.subject() == key));
+            for condition in conditions {
+                if self.over_budget.insert(condition.subject().to_string()) {
+                    notify(NotifyOn::Budget, condition.to_string());
+                }
+            }
+        }
+        notifications
+    }
+}
+
+/// Runs the notification command for `notification`, passing its message
+/// as the last argument and its kind as `CROXY_NOTIFICATION`.
+async fn send(command: &[String], notification: &Notification) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    let child = tokio::process::Command::new(program)
+        .args(args)
+        .arg(&notification.message)
+        .env("CROXY_NOTIFICATION", notification.on.name())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let result = match child {
+        Ok(mut child) => match tokio::time::timeout(COMMAND_TIMEOUT, child.wait()).await {
+            Ok(Ok(status)) if status.success() => Ok(()),
+            Ok(Ok(status)) => Err(format!("exited with {status}")),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        },
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        tracing::warn!(command = %program, "notification command failed: {e}");
+    }
+}
+
+/// Checks every request as it finishes and sends what the rules call for,
+/// until dropped.
+pub async fn run(state: Arc<AppState>, rules: Option<Rules>) {
+    let Some(rules) = rules else {
+        return;
+    };
+    let command = rules.command.clone();
+    let mut watcher = Watcher::new(rules, &state.keys);
+    let mut events = state.metrics.subscribe();
+    loop {
+        let record = match events.recv().await {
+            Ok(MetricsEvent::Finalized(record)) => record,
+            // Streams are recorded when they start and finalized later
+            Ok(MetricsEvent::Recorded(record)) if !record.duration.is_zero() => record,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::debug!(missed, "notifications fell behind");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        for notification in watcher.observe(&record, &state.metrics, &state.keys) {
+            tracing::info!(
+                on = notification.on.name(),
+                "notifying: {}",
+                notification.message
+            );
+            let command = command.clone();
+            tokio::spawn(async move { send(&command, &notification).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn rules() -> Rules {
+        Rules {
+            command: vec!["true".to_string()],
+            on: vec![
+                NotifyOn::ProviderDown,
+                NotifyOn::Budget,
+                NotifyOn::LongRequest,
+            ],
+            budget_threshold: 0.8,
+            long_request: Duration::from_secs(60),
+            down_error_rate: 0.5,
+        }
+    }
+
+    fn record(status: u16, duration: Duration) -> RequestRecord {
+        RequestRecord {
+            status,
+            duration,
+            ..RequestRecord::sample()
+        }
+    }
+
+    /// Records `record` and returns what the watcher makes of it.
+    fn finish(watcher: &mut Watcher, metrics: &MetricsStore, record: RequestRecord) -> Vec<String> {
+        metrics.record(record.clone());
+        watcher
+            .observe(&record, metrics, &KeyStore::disabled())
+            .into_iter()
+            .map(|n| n.message)
+            .collect()
+    }
+
+    #[test]
+    fn providers_are_reported_down_once_and_again_on_recovery() {
+        let metrics = MetricsStore::new(Duration::from_secs(600));
+        let mut watcher = Watcher::new(rules(), &KeyStore::disabled());
+        let quick = Duration::from_millis(200);
+
+        assert!(finish(&mut watcher, &metrics, record(503, quick)).is_empty());
+        assert!(finish(&mut watcher, &metrics, record(503, quick)).is_empty());
+        assert_eq!(
+            finish(&mut watcher, &metrics, record(503, quick)),
+            ["anthropic is down: 100% of recent requests failed"]
+        );
+        assert!(finish(&mut watcher, &metrics, record(503, quick)).is_empty());
+
+        let mut recovered = Vec::new();
+        for _ in 0..10 {
+            recovered.extend(finish(&mut watcher, &metrics, record(200, quick)));
+        }
+        assert_eq!(recovered, ["anthropic has recovered"]);
+    }
+
+    #[test]
+    fn long_requests_are_reported_when_they_finish() {
+        let metrics = MetricsStore::new(Duration::from_secs(600));
+        let mut watcher = Watcher::new(
+            Rules {
+                on: vec![NotifyOn::LongRequest],
+                ..rules()
+            },
+            &KeyStore::disabled(),
+        );
+        assert!(finish(&mut watcher, &metrics, record(200, Duration::from_secs(59))).is_empty());
+        assert_eq!(
+            finish(&mut watcher, &metrics, record(200, Duration::from_secs(90))),
+            ["claude-opus-4-6 request to anthropic finished after 1m30.0s"]
+        );
+    }
+
+    #[test]
+    fn keys_are_reported_as_they_cross_the_budget_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        for name in ["ci", "old"] {
+            crate::keys::create(
+                &path,
+                crate::keys::NewKey {
+                    name: name.to_string(),
+                    budget_usd: Some(10.0),
+                    requests_per_minute: None,
+                    routes: Vec::new(),
+                },
+            )
+            .unwrap();
+        }
+        let keys = KeyStore::open(path, false);
+        keys.charge("old", 9.0);
+        let metrics = MetricsStore::new(Duration::from_secs(600));
+        let mut watcher = Watcher::new(rules(), &keys);
+        let mut observe = || {
+            watcher
+                .observe(&record(200, Duration::from_secs(1)), &metrics, &keys)
+                .into_iter()
+                .map(|n| n.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(observe().is_empty());
+        keys.charge("ci", 8.5);
+        assert_eq!(observe(), ["key 'ci' has spent $8.50 of its $10.00 budget"]);
+        assert!(observe().is_empty());
+    }
+
+    #[test]
+    fn rules_need_a_command() {
+        let config =
+            |toml: &str| -> Config { Figment::new().merge(Toml::string(toml)).extract().unwrap() };
+        assert_eq!(Rules::from_config(&config("")), None);
+        let rules = Rules::from_config(&config(
+            "[notifications]\ncommand = [\"notify-send\", \"croxy\"]\non = [\"budget\"]\n",
+        ))
+        .unwrap();
+        assert_eq!(rules.on, [NotifyOn::Budget]);
+        assert_eq!(rules.long_request, Duration::from_secs(120));
+    }
+}
@@ -0,0 +1,150 @@
+//! Reading the model out of a request body without parsing the rest of it.
+//! Most requests are routed on their model alone, and building a
+//! `serde_json::Value` for a multi-megabyte conversation just to read one
+//! field is wasted work. The body is still checked to be valid JSON.
+
+use std::fmt;
+
+use serde::Deserialize;
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+
+/// The parts of a request body needed to route it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Peek {
+    /// Empty when the body has no string `model`.
+    pub model: String,
+    /// Whether the body has a `messages` array.
+    pub messages: bool,
+    /// The body's `max_tokens`, when it is a whole number.
+    pub max_tokens: Option<u64>,
+}
+
+/// Reads `body`'s model, skipping over everything else. Fails on invalid
+/// JSON, and on bodies whose shape it doesn't expect, such as a top-level
+/// array; those are left to a full parse.
+pub fn peek(body: &[u8]) -> Result<Peek, serde_json::Error> {
+    serde_json::from_slice(body)
+}
+
+impl<'de> Deserialize<'de> for Peek {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(PeekVisitor)
+    }
+}
+
+struct PeekVisitor;
+
+impl<'de> Visitor<'de> for PeekVisitor {
+    type Value = Peek;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Peek, A::Error> {
+        let mut peek = Peek::default();
+        // Later duplicates win, as they do in a full parse
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "model" => {
+                    peek.model = match map.next_value::<serde_json::Value>()? {
+                        serde_json::Value::String(model) => model,
+                        _ => String::new(),
+                    }
+                }
+                "messages" => peek.messages = map.next_value::<IsArray>()?.0,
+                "max_tokens" => {
+                    peek.max_tokens = map.next_value::<serde_json::Value>()?.as_u64();
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(peek)
+    }
+}
+
+/// Whether a value is an array, found without keeping its elements.
+struct IsArray(bool);
+
+impl<'de> Deserialize<'de> for IsArray {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(IsArrayVisitor)
+    }
+}
+
+struct IsArrayVisitor;
+
+impl<'de> Visitor<'de> for IsArrayVisitor {
+    type Value = IsArray;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<IsArray, A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(IsArray(true))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<IsArray, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(IsArray(false))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<IsArray, E> {
+        Ok(IsArray(false))
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<IsArray, E> {
+        Ok(IsArray(false))
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<IsArray, E> {
+        Ok(IsArray(false))
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<IsArray, E> {
+        Ok(IsArray(false))
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<IsArray, E> {
+        Ok(IsArray(false))
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<IsArray, E> {
+        Ok(IsArray(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_model_max_tokens_and_whether_there_are_messages() {
+        let body = br#"{"max_tokens": 10, "messages": [{"role": "user", "content": [{"type": "text", "text": "hi"}]}], "model": "claude-opus-4-6", "stream": true}"#;
+        assert_eq!(
+            peek(body).unwrap(),
+            Peek {
+                model: "claude-opus-4-6".to_string(),
+                messages: true,
+                max_tokens: Some(10),
+            }
+        );
+        assert_eq!(
+            peek(br#"{"model": 4, "messages": "hi", "model": null, "max_tokens": -1}"#).unwrap(),
+            Peek::default()
+        );
+        assert_eq!(peek(br#"{"model": "a", "model": "b"}"#).unwrap().model, "b");
+    }
+
+    #[test]
+    fn rejects_what_a_full_parse_would_reject() {
+        assert!(peek(br#"{"model": "a", "messages": [}"#).is_err());
+        assert!(peek(br#"{"model": "a"} trailing"#).is_err());
+        // Valid, but left to a full parse
+        assert!(peek(br#"["model", "a"]"#).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+/// Published per-million-token prices (input, output) in USD for Anthropic
+/// model families. Matched by substring against the model name.
+const MODEL_PRICES: &[(&str, f64, f64)] = &[
+    ("opus", 15.0, 75.0),
+    ("sonnet", 3.0, 15.0),
+    ("haiku", 1.0, 5.0),
+];
+
+/// Estimated cost in USD for a request, or `None` when the model family is
+/// unknown.
+pub fn estimate_cost_usd(model: &str, input_tokens: u64, output_tokens: u64) -> Option<f64> {
+    let model = model.to_lowercase();
+    MODEL_PRICES
+        .iter()
+        .find(|(family, _, _)| model.contains(family))
+        .map(|(_, input_price, output_price)| {
+            (input_tokens as f64 * input_price + output_tokens as f64 * output_price) / 1_000_000.0
+        })
+}
+
+/// Whether requests to this provider URL are billed at Anthropic list prices.
+pub fn is_billable_url(url: &str) -> bool {
+    url.contains("api.anthropic.com")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_families_are_priced() {
+        let cost = estimate_cost_usd("claude-sonnet-4-5-20250929", 1_000_000, 1_000_000).unwrap();
+        assert!((cost - 18.0).abs() < f64::EPSILON);
+        assert!(estimate_cost_usd("claude-opus-4-6", 1000, 0).is_some());
+        assert!(estimate_cost_usd("claude-haiku-4-5", 0, 1000).is_some());
+    }
+
+    #[test]
+    fn unknown_model_is_unpriced() {
+        assert_eq!(estimate_cost_usd("qwen3-coder:30b", 1000, 1000), None);
+    }
+
+    #[test]
+    fn billable_url_matches_anthropic_api() {
+        assert!(is_billable_url("https://api.anthropic.com"));
+        assert!(!is_billable_url("http://localhost:11434"));
+    }
+}
@@ -0,0 +1,192 @@
+//! `[telemetry.prometheus]`: request counts and a latency histogram for
+//! each provider, served in the Prometheus text format at
+//! `GET /_croxy/metrics`. Unlike the metrics store, which forgets requests
+//! past the retention window, these count every request since croxy
+//! started, as Prometheus expects.
+//!
+//! The histogram's buckets are configurable because one set doesn't fit
+//! every deployment: a cloud API answers in a second or two, while a local
+//! model can take anywhere from 100ms to five minutes.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::metrics::RequestRecord;
+
+/// Upper bounds, in milliseconds, of the default latency buckets, from a
+/// quick cloud API call to a long local generation.
+pub const DEFAULT_LATENCY_BUCKETS_MS: &[u64] = &[
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 120_000, 300_000,
+];
+
+/// One provider's finished requests.
+#[derive(Debug, Clone, Default)]
+struct Series {
+    /// Requests no slower than each bucket's bound; the last counts all.
+    buckets: Vec<u64>,
+    sum: Duration,
+    count: u64,
+    /// Requests by response status.
+    statuses: BTreeMap<u16, u64>,
+}
+
+/// Latencies of finished requests by provider, counted into buckets.
+#[derive(Debug, Clone)]
+pub struct Latency {
+    bounds: Vec<u64>,
+    providers: BTreeMap<String, Series>,
+    /// Responses abandoned by their client, by model.
+    aborts: BTreeMap<String, u64>,
+}
+
+impl Latency {
+    /// `bounds` are the buckets' upper bounds in milliseconds, ascending;
+    /// a `+Inf` bucket is added after them.
+    pub fn new(bounds: Vec<u64>) -> Self {
+        Self {
+            bounds,
+            providers: BTreeMap::new(),
+            aborts: BTreeMap::new(),
+        }
+    }
+
+    pub fn bounds(&self) -> &[u64] {
+        &self.bounds
+    }
+
+    pub fn observe(&mut self, record: &RequestRecord) {
+        let series = self
+            .providers
+            .entry(record.provider.clone())
+            .or_insert_with(|| Series {
+                buckets: vec![0; self.bounds.len() + 1],
+                ..Series::default()
+            });
+        let ms = record.duration.as_millis();
+        let first = self
+            .bounds
+            .iter()
+            .position(|&bound| ms <= u128::from(bound))
+            .unwrap_or(self.bounds.len());
+        for bucket in &mut series.buckets[first..] {
+            *bucket += 1;
+        }
+        series.sum += record.duration;
+        series.count += 1;
+        *series.statuses.entry(record.status).or_default() += 1;
+        if record.client_aborted.is_some() {
+            *self.aborts.entry(record.model.clone()).or_default() += 1;
+        }
+    }
+
+    /// Everything counted so far, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP croxy_requests_total Requests finished, by provider and status.\n");
+        out.push_str("# TYPE croxy_requests_total counter\n");
+        for (provider, series) in &self.providers {
+            let provider = escape(provider);
+            for (status, count) in &series.statuses {
+                let _ = writeln!(
+                    out,
+                    "croxy_requests_total{{provider=\"{provider}\",status=\"{status}\"}} {count}"
+                );
+            }
+        }
+        out.push_str(
+            "# HELP croxy_request_duration_seconds Time from a request arriving to its response finishing.\n",
+        );
+        out.push_str("# TYPE croxy_request_duration_seconds histogram\n");
+        for (provider, series) in &self.providers {
+            let provider = escape(provider);
+            let bounds = self
+                .bounds
+                .iter()
+                .map(|&ms| (ms as f64 / 1000.0).to_string())
+                .chain(["+Inf".to_string()]);
+            for (le, count) in bounds.zip(&series.buckets) {
+                let _ = writeln!(
+                    out,
+                    "croxy_request_duration_seconds_bucket{{provider=\"{provider}\",le=\"{le}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "croxy_request_duration_seconds_sum{{provider=\"{provider}\"}} {}",
+                series.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "croxy_request_duration_seconds_count{{provider=\"{provider}\"}} {}",
+                series.count
+            );
+        }
+        out.push_str(
+            "# HELP croxy_client_aborts_total Responses the client went away from before the end, by model.\n",
+        );
+        out.push_str("# TYPE croxy_client_aborts_total counter\n");
+        for (model, count) in &self.aborts {
+            let _ = writeln!(
+                out,
+                "croxy_client_aborts_total{{model=\"{}\"}} {count}",
+                escape(model)
+            );
+        }
+        out
+    }
+}
+
+/// `value` escaped for a label.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(provider: &str, status: u16, ms: u64) -> RequestRecord {
+        RequestRecord {
+            model: "qwen3-coder:30b".to_string(),
+            provider: provider.to_string(),
+            status,
+            duration: Duration::from_millis(ms),
+            ..RequestRecord::sample()
+        }
+    }
+
+    #[test]
+    fn requests_are_counted_into_the_configured_buckets() {
+        let mut latency = Latency::new(vec![100, 1_000, 60_000]);
+        latency.observe(&record("ollama", 200, 100));
+        latency.observe(&record("ollama", 200, 4_500));
+        latency.observe(&record("ollama", 500, 90_000));
+        let mut aborted = record("anthropic", 200, 800);
+        aborted.client_aborted = Some(512);
+        latency.observe(&aborted);
+
+        let text = latency.render();
+        for line in [
+            r#"croxy_requests_total{provider="anthropic",status="200"} 1"#,
+            r#"croxy_requests_total{provider="ollama",status="200"} 2"#,
+            r#"croxy_requests_total{provider="ollama",status="500"} 1"#,
+            r#"croxy_request_duration_seconds_bucket{provider="ollama",le="0.1"} 1"#,
+            r#"croxy_request_duration_seconds_bucket{provider="ollama",le="1"} 1"#,
+            r#"croxy_request_duration_seconds_bucket{provider="ollama",le="60"} 2"#,
+            r#"croxy_request_duration_seconds_bucket{provider="ollama",le="+Inf"} 3"#,
+            r#"croxy_request_duration_seconds_sum{provider="ollama"} 94.6"#,
+            r#"croxy_request_duration_seconds_count{provider="ollama"} 3"#,
+            r#"croxy_request_duration_seconds_bucket{provider="anthropic",le="1"} 1"#,
+            r#"croxy_client_aborts_total{model="qwen3-coder:30b"} 1"#,
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line} in:\n{text}"
+            );
+        }
+    }
+}
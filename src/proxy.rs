@@ -1,28 +1,178 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 
 use axum::{
-    body::Body,
-    extract::{Request, State},
-    http::{HeaderMap, HeaderValue, StatusCode},
+    body::{Body, HttpBody},
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, Version},
     response::Response,
 };
-use futures::TryStreamExt;
-use tokio::sync::oneshot;
-use tracing::{debug, error, info};
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt};
+use http_body::{Frame, SizeHint};
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
-use crate::metrics::{MetricsStore, RequestRecord};
-use crate::router::{ResolvedRoute, Router};
+use crate::access_log::{AccessEntry, AccessLog};
+use crate::archive::{self, Archive};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::batches::{self, BatchCall, BatchOwners};
+use crate::body_sizes::Direction;
+use crate::caching;
+use crate::capabilities::Capability;
+use crate::capture::{Capture, CaptureStore};
+use crate::chaos::Chaos;
+use crate::clients;
+use crate::compare::{Comparer, Comparison, ResponseSummary, Side};
+use crate::config::{
+    ApiFormat, CacheControl, ContextGuard, Dedup, ProviderConfig, ToolResultsConfig,
+};
+use crate::context_guard;
+use crate::context_windows::Fit;
+use crate::dedup::{self, InFlight, Joined, Leader};
+use crate::error::CroxyError;
+use crate::events::{RequestEvent, Stage};
+use crate::keys::{self, KeyStore};
+use crate::listeners::{Ingress, Peer};
+use crate::metrics::{MetricsStore, RequestRecord, RoutingMethod, StreamSlot, Timings};
+use crate::middleware::{Applied, Pipeline};
+use crate::peek::{self, Peek};
+use crate::pricing;
+use crate::ratelimits::{self, RateLimit};
+use crate::response_headers;
+use crate::router::{Endpoint, RequestFields, ResolvedRoute, ResponseLimits, Router};
+use crate::schema;
+use crate::script::{self, Decision, Script, ScriptRequest};
+use crate::scrub::Scrubber;
+use crate::tokenizer::{self, Tokenizer};
+use crate::tokens::{AuthStatus, ProviderToken};
+use crate::tool_repair::{self, StreamRepair};
+use crate::tool_results;
+use crate::translate::{self, Translation};
+use crate::vertex::{self, VertexProvider};
 
 pub struct AppState {
-    pub router: Router,
+    /// Replaced wholesale when config is reloaded at runtime.
+    pub router: RwLock<Arc<Router>>,
     pub client: reqwest::Client,
+    /// Clients for providers with their own proxy or TLS settings.
+    pub provider_clients: HashMap<String, reqwest::Client>,
+    /// Project, region, and credentials of `api_format = "vertex"` providers.
+    pub vertex: HashMap<String, Arc<VertexProvider>>,
+    /// Bearer token sources of providers with `token_command` or `oauth`.
+    pub tokens: HashMap<String, Arc<ProviderToken>>,
+    /// Tokenizers of providers with `tokenizer`, for output token counts
+    /// they don't report.
+    pub tokenizers: HashMap<String, Arc<Tokenizer>>,
+    /// Providers that Message Batches were created on.
+    pub batches: BatchOwners,
+    /// Requests on `dedup` routes that duplicates can attach to.
+    pub in_flight: Arc<InFlight>,
     pub metrics: Arc<MetricsStore>,
     pub max_body_size: usize,
+    /// Bytes of a streamed response read ahead of the client.
+    pub stream_buffer_size: usize,
+    /// Idle time after which event streams get a heartbeat comment.
+    pub sse_heartbeat: Option<Duration>,
+    /// Longest a request may take, end to end.
+    pub request_timeout: Option<Duration>,
+    /// Header naming the client of a request without a virtual key.
+    pub client_header: Option<String>,
+    /// Check Messages request bodies before forwarding them.
+    pub validate_requests: bool,
+    pub tool_results: ToolResultsConfig,
+    pub response_headers: response_headers::Policy,
+    /// Virtual keys clients may present instead of provider credentials.
+    pub keys: Arc<KeyStore>,
+    /// Where requests are saved for `croxy replay`, when capture is on.
+    pub captures: Option<Arc<CaptureStore>>,
+    /// Where responses of routes with `archive = true` are saved.
+    pub archive: Option<Arc<Archive>>,
+    /// Sends requests matching a `[[compare]]` rule to a second provider.
+    pub compare: Option<Arc<Comparer>>,
+    /// `[[middleware]]` stages run on requests before forwarding.
+    pub middleware: Pipeline,
+    /// The `[script]` routing hook.
+    pub script: Option<Script>,
+    /// Rejected credentials and operator commands are recorded here.
+    pub audit: Arc<AuditLog>,
+    /// One line per request, for ops tooling, when `[logging.access]` is on.
+    pub access_log: Arc<AccessLog>,
+    /// Removes secrets from error bodies and captures before they're kept.
+    pub scrubber: Arc<Scrubber>,
+    /// `[chaos]` fault injection, when enabled.
+    pub chaos: Option<Chaos>,
+}
+
+impl AppState {
+    pub fn router(&self) -> Arc<Router> {
+        self.router.read().expect("router lock poisoned").clone()
+    }
+
+    pub fn replace_router(&self, router: Router) {
+        *self.router.write().expect("router lock poisoned") = Arc::new(router);
+    }
+
+    pub fn client_for(&self, provider_name: &str) -> &reqwest::Client {
+        self.provider_clients
+            .get(provider_name)
+            .unwrap_or(&self.client)
+    }
+}
+
+/// Who sent a request without a virtual key: the value of the
+/// `client_header` it carries, or else the address it came from.
+fn client_of(parts: &http::request::Parts, client_header: Option<&str>) -> Option<String> {
+    let named = client_header
+        .and_then(|name| parts.headers.get(name))
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+    named.or_else(|| {
+        let ConnectInfo(peer) = parts.extensions.get::<ConnectInfo<Peer>>()?;
+        peer.ip.map(|ip| ip.to_string())
+    })
+}
+
+/// A response body that keeps its request counted against its connection
+/// until the last of it has been sent, or the client has gone.
+struct Slotted {
+    inner: Body,
+    _slot: StreamSlot,
+}
+
+impl HttpBody for Slotted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn http_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_10 => "HTTP/1.0",
+        _ => "HTTP/1.1",
+    }
 }
 
 /// Fires a oneshot signal when dropped, used to detect stream completion.
@@ -47,7 +197,7 @@ fn stub_count_tokens_response() -> Response {
     response
 }
 
-fn is_hop_by_hop(name: &http::header::HeaderName) -> bool {
+pub(crate) fn is_hop_by_hop(name: &http::header::HeaderName) -> bool {
     matches!(
         name.as_str(),
         "connection"
@@ -63,9 +213,23 @@ fn is_hop_by_hop(name: &http::header::HeaderName) -> bool {
 fn build_forwarding_headers(
     original_headers: &HeaderMap,
     route: &ResolvedRoute,
+    api_key: Option<&str>,
+    defaults: &HashMap<String, String>,
     body_len: usize,
 ) -> HeaderMap {
     let mut headers = HeaderMap::new();
+    // The client's own headers replace these
+    for (name, value) in defaults {
+        match (
+            http::header::HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => tracing::warn!(header = %name, "invalid default header, skipping"),
+        }
+    }
     for (key, value) in original_headers {
         if key == http::header::HOST || is_hop_by_hop(key) {
             continue;
@@ -76,7 +240,7 @@ fn build_forwarding_headers(
         headers.insert(key.clone(), value.clone());
     }
 
-    if let Some(ref api_key) = route.api_key {
+    if let Some(api_key) = api_key {
         if let Ok(value) = HeaderValue::from_str(api_key) {
             headers.insert(http::header::HeaderName::from_static("x-api-key"), value);
         } else {
@@ -98,22 +262,46 @@ fn build_forwarding_headers(
     headers
 }
 
-fn rewrite_model_in_body(
+/// What this request needs that `route`'s provider doesn't offer, if
+/// anything.
+fn lacking_capability(
+    router: &Router,
+    route: &ResolvedRoute,
+    path: &str,
     body_json: &mut Option<serde_json::Value>,
-    body_bytes: Bytes,
-    new_model: &str,
-) -> Result<Bytes, (StatusCode, String)> {
-    if let Some(json) = body_json {
-        json["model"] = serde_json::Value::String(new_model.to_string());
-        serde_json::to_vec(json).map(Bytes::from).map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("failed to serialize body: {e}"),
-            )
-        })
-    } else {
-        Ok(body_bytes)
-    }
+    body_bytes: &Bytes,
+) -> Result<Option<Capability>, CroxyError> {
+    let Some(provider) = router.provider(&route.provider_name) else {
+        return Ok(None);
+    };
+    // The body is only looked at when it could matter
+    let streams = !provider.capabilities.streaming
+        && parsed(body_json, body_bytes)?.is_some_and(|body| body["stream"] == true);
+    Ok(Capability::needed(path, streams)
+        .filter(|&capability| !provides(provider, route, capability)))
+}
+
+/// Whether `route` can serve requests needing `capability`. Token counts
+/// that are stubbed never reach the provider.
+fn provides(provider: &ProviderConfig, route: &ResolvedRoute, capability: Capability) -> bool {
+    provider.capabilities.supports(capability)
+        || (capability == Capability::CountTokens
+            && (route.stub_count_tokens || Translation::for_format(route.api_format).is_some()))
+}
+
+/// `default_headers` of the provider `route` goes to.
+fn default_headers(state: &AppState, route: &ResolvedRoute) -> HashMap<String, String> {
+    state
+        .router()
+        .provider(&route.provider_name)
+        .map(|p| p.default_headers.clone())
+        .unwrap_or_default()
+}
+
+fn serialize_body(json: &serde_json::Value) -> Result<Bytes, CroxyError> {
+    serde_json::to_vec(json)
+        .map(Bytes::from)
+        .map_err(|e| CroxyError::Internal(format!("failed to serialize body: {e}")))
 }
 
 fn parse_token_header(headers: &reqwest::header::HeaderMap, name: &str) -> Option<u64> {
@@ -127,7 +315,7 @@ fn log_outgoing_headers(headers: &HeaderMap) {
     for (key, value) in headers {
         if matches!(
             key.as_str(),
-            "x-api-key" | "authorization" | "proxy-authorization" | "cookie"
+            "x-api-key" | "api-key" | "authorization" | "proxy-authorization" | "cookie"
         ) {
             debug!(header = %key, value = "[REDACTED]", "outgoing header");
         } else {
@@ -136,20 +324,348 @@ fn log_outgoing_headers(headers: &HeaderMap) {
     }
 }
 
+/// Longest error kept with a request record.
+const MAX_ERROR_SUMMARY: usize = 1024;
+
+/// Longest a request is held for a provider's rate limit to reset.
+const MAX_RATELIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a 429 that didn't say, and the least
+/// wait between retries whatever it said.
+const DEFAULT_RETRY_WAIT: Duration = Duration::from_secs(1);
+const MIN_RETRY_WAIT: Duration = Duration::from_millis(250);
+
+/// Heartbeat interval for streams held while a 429 is retried, when
+/// `server.sse_heartbeat_secs` isn't set.
+const HOLD_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// The error for a provider that couldn't be reached at all.
+fn unreachable(e: reqwest::Error) -> CroxyError {
+    error!(error = %e, "provider request failed");
+    CroxyError::Upstream(format!("provider unreachable: {e}"))
+}
+
+/// Announces that the provider has started answering `record`'s request.
+fn first_byte(state: &AppState, record: &RequestRecord) {
+    state.metrics.emit(|| RequestEvent {
+        provider: Some(record.provider.clone()),
+        status: Some(record.status),
+        elapsed_ms: Some(record.timestamp.elapsed().as_millis() as u64),
+        ..RequestEvent::new(
+            Stage::FirstByte,
+            record.request_id.as_deref().unwrap_or_default(),
+        )
+    });
+}
+
+/// Checks a request of `body_len` bytes for `model` on the route's
+/// provider against the model's limits, logging one that won't fit.
+/// Returns whether it comes near the context window.
+fn near_context_limit(
+    router: &Router,
+    route: &ResolvedRoute,
+    model: &str,
+    body_len: usize,
+    max_tokens: Option<u64>,
+) -> bool {
+    let Some(limits) = router.model_limits(&route.provider_name, model) else {
+        return false;
+    };
+    let estimated_tokens = (body_len / 4) as u64;
+    if let Some(max_tokens) = max_tokens
+        && let Some(max_output_tokens) = limits.max_output_tokens
+        && max_tokens > max_output_tokens
+    {
+        warn!(
+            model = %model,
+            max_tokens,
+            max_output_tokens,
+            "request asks for more output than the model gives"
+        );
+    }
+    match limits.fit(estimated_tokens, max_tokens) {
+        Fit::Fits => false,
+        Fit::Near => true,
+        Fit::Over => {
+            warn!(
+                model = %model,
+                provider = %route.provider_name,
+                estimated_tokens,
+                max_tokens,
+                context_window = limits.context_window,
+                "request is likely too long for the model's context window"
+            );
+            true
+        }
+    }
+}
+
+fn note_rate_limits(state: &AppState, provider: &str, response: &reqwest::Response) {
+    if let Some(limit) = RateLimit::from_headers(provider, response.headers()) {
+        state.metrics.record_rate_limit(limit);
+    }
+}
+
+type Resend = Box<
+    dyn Fn() -> futures::future::BoxFuture<'static, reqwest::Result<reqwest::Response>>
+        + Send
+        + Sync,
+>;
+
+/// Sends a request again while its provider answers 429, waiting as long
+/// as the provider asks each time, for up to `max_wait` in all.
+struct Retry {
+    max_wait: Duration,
+    send: Resend,
+}
+
+impl Retry {
+    async fn run(
+        &self,
+        state: &AppState,
+        route: &ResolvedRoute,
+        mut response: reqwest::Response,
+    ) -> Result<reqwest::Response, CroxyError> {
+        let deadline = Instant::now() + self.max_wait;
+        while response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let wait = ratelimits::retry_wait(response.headers())
+                .unwrap_or(DEFAULT_RETRY_WAIT)
+                .max(MIN_RETRY_WAIT);
+            if Instant::now() + wait > deadline {
+                warn!(
+                    provider = %route.provider_name,
+                    wait_ms = wait.as_millis() as u64,
+                    "rate limited for longer than retry_429_max_wait_secs"
+                );
+                break;
+            }
+            info!(
+                provider = %route.provider_name,
+                wait_ms = wait.as_millis() as u64,
+                "rate limited, retrying"
+            );
+            tokio::time::sleep(wait).await;
+            response = (self.send)().await.map_err(unreachable)?;
+            note_rate_limits(state, &route.provider_name, &response);
+        }
+        Ok(response)
+    }
+}
+
+/// Whether a request body asks for a streamed response.
+fn wants_stream(body: &[u8]) -> bool {
+    #[derive(serde::Deserialize)]
+    struct Streamed {
+        #[serde(default)]
+        stream: bool,
+    }
+    serde_json::from_slice::<Streamed>(body).is_ok_and(|b| b.stream)
+}
+
+/// Starts an event stream before its response is ready, with an SSE
+/// comment every `heartbeat` until `response` resolves. A successful
+/// response's body follows. Anything else becomes an `error` event, as a
+/// success status has already been sent.
+fn hold_stream<F>(response: F, heartbeat: Duration) -> Response
+where
+    F: Future<Output = Result<Response, CroxyError>> + Send + 'static,
+{
+    let body = futures::stream::once(async move {
+        let (status, error) = match response.await {
+            Ok(response) if response.status().is_success() => {
+                let body = response.into_body().into_data_stream();
+                return body.map_err(std::io::Error::other).left_stream();
+            }
+            Ok(response) => {
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap_or_default();
+                let error = serde_json::from_slice(&body).unwrap_or_else(|_| {
+                    translate::error_json(status.as_u16(), &String::from_utf8_lossy(&body))
+                });
+                (status, error)
+            }
+            Err(e) => {
+                let status = e.status();
+                (
+                    status,
+                    translate::error_json(status.as_u16(), &e.to_string()),
+                )
+            }
+        };
+        debug!(status = %status, "held stream ended in an error");
+        let event = Bytes::from(format!("event: error\ndata: {error}\n\n"));
+        futures::stream::once(async move { Ok(event) }).right_stream()
+    })
+    .flatten();
+
+    let mut response = Response::new(Body::from_stream(with_heartbeat(body, heartbeat)));
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        HeaderValue::from_static("no-cache"),
+    );
+    response
+}
+
+/// Records a provider's response to a forwarded request and relays it to
+/// the client. `record` is completed from what the response reports.
+async fn relay_response(
+    state: &AppState,
+    mut upstream_response: reqwest::Response,
+    route: &ResolvedRoute,
+    mut record: RequestRecord,
+    completion: Completion,
+) -> Response {
+    let status = StatusCode::from_u16(upstream_response.status().as_u16())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    info!(status = %status, url = %upstream_response.url(), "provider responded");
+
+    record.status = status.as_u16();
+    record.duration = record.timestamp.elapsed();
+    first_byte(state, &record);
+    if let Some(tokens) = parse_token_header(upstream_response.headers(), "x-usage-input-tokens") {
+        record.input_tokens = tokens;
+    }
+    record.output_tokens =
+        parse_token_header(upstream_response.headers(), "x-usage-output-tokens").unwrap_or(0);
+
+    let mut response_headers = state.response_headers.filter(upstream_response.headers());
+
+    if status.as_u16() >= 400 {
+        return handle_error_response(
+            &mut upstream_response,
+            state,
+            status,
+            response_headers,
+            record,
+            completion,
+        )
+        .await;
+    }
+
+    // Batch creation answers with the batch's ID, which later calls use
+    if record.batch_size.is_some() {
+        let bytes = read_capped_body(&mut upstream_response, state.max_body_size).await;
+        if let Some(id) = serde_json::from_slice::<serde_json::Value>(&bytes)
+            .ok()
+            .and_then(|b| b["id"].as_str().map(String::from))
+        {
+            info!(batch = %id, provider = %route.provider_name, "batch created");
+            state.batches.remember(&id, &route.provider_name);
+        }
+        record.duration = record.timestamp.elapsed();
+        completion.run(&record, &bytes);
+        state.metrics.record(record);
+        let mut response = Response::new(Body::from(bytes));
+        *response.status_mut() = status;
+        *response.headers_mut() = response_headers;
+        return response;
+    }
+
+    let body = upstream_response.bytes_stream();
+    let body = if route.repair_tool_calls {
+        // Repairs change the body's length
+        response_headers.remove(http::header::CONTENT_LENGTH);
+        let events = is_event_stream(&response_headers);
+        futures::future::Either::Left(repair_tool_calls(body, events, state.metrics.clone()))
+    } else {
+        futures::future::Either::Right(body)
+    };
+    stream_response(
+        body,
+        (status, response_headers),
+        Arc::new(AtomicU64::new(record.output_tokens)),
+        state,
+        route.limits,
+        record,
+        completion,
+    )
+}
+
+/// Repairs the tool calls in a Messages response on its way to the
+/// client. Event streams are repaired as each tool call completes; other
+/// responses are read in full first.
+fn repair_tool_calls<S>(
+    body: S,
+    events: bool,
+    metrics: Arc<MetricsStore>,
+) -> impl Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+{
+    if events {
+        let mut repair = StreamRepair::default();
+        return body
+            .map_ok(move |chunk| {
+                let before = repair.repaired();
+                let chunk = repair.push(&chunk);
+                count_repairs(&metrics, repair.repaired() - before);
+                chunk
+            })
+            .left_stream();
+    }
+    futures::stream::once(async move {
+        let body: Vec<Bytes> = body.try_collect().await?;
+        let body = body.concat();
+        let Ok(mut message) = serde_json::from_slice::<serde_json::Value>(&body) else {
+            return Ok(Bytes::from(body));
+        };
+        let repaired = tool_repair::repair_message(&mut message);
+        if repaired == 0 {
+            return Ok(Bytes::from(body));
+        }
+        count_repairs(&metrics, repaired);
+        Ok(Bytes::from(message.to_string()))
+    })
+    .right_stream()
+}
+
+fn count_repairs(metrics: &MetricsStore, repaired: usize) {
+    if repaired > 0 {
+        info!(repaired, "repaired malformed tool call input");
+        metrics.count_repaired_tool_calls(repaired);
+    }
+}
+
+/// What an error response said, scrubbed of secrets, for its record.
+fn error_summary(status: StatusCode, body: &[u8], scrubber: &Scrubber) -> String {
+    let text = String::from_utf8_lossy(body);
+    if text.trim().is_empty() {
+        return format!("HTTP {status} ({} bytes)", body.len());
+    }
+    // Scrubbed before it is cut, so no partial secret survives at the end
+    let mut summary = format!("HTTP {status}: {}", scrubber.scrub(text.trim()));
+    if summary.len() > MAX_ERROR_SUMMARY {
+        let mut end = MAX_ERROR_SUMMARY;
+        while !summary.is_char_boundary(end) {
+            end -= 1;
+        }
+        summary.truncate(end);
+    }
+    summary
+}
+
 async fn handle_error_response(
     upstream_response: &mut reqwest::Response,
-    max_body_size: usize,
+    state: &AppState,
     status: StatusCode,
     response_headers: HeaderMap,
     record: RequestRecord,
-    metrics: &MetricsStore,
+    completion: Completion,
 ) -> Response {
-    let error_bytes = read_capped_body(upstream_response, max_body_size).await;
+    let error_bytes = read_capped_body(upstream_response, state.max_body_size).await;
     let error_len = error_bytes.len();
 
     let mut record = record;
-    record.error_body = Some(format!("HTTP {status} ({error_len} bytes)"));
-    metrics.record(record);
+    record.error_body = Some(error_summary(status, &error_bytes, &state.scrubber));
+    completion.run(&record, &error_bytes);
+    state.metrics.record(record);
 
     let mut headers = response_headers;
     headers.insert(
@@ -163,6 +679,12 @@ async fn handle_error_response(
     response
 }
 
+/// What a route's body sizes are counted under: its name, or its
+/// provider's for routes without one.
+fn size_label(route: &ResolvedRoute) -> &str {
+    route.route_name.as_deref().unwrap_or(&route.provider_name)
+}
+
 async fn read_capped_body(response: &mut reqwest::Response, max_size: usize) -> Vec<u8> {
     let mut buf = Vec::with_capacity(4096);
     while let Ok(Some(chunk)) = response.chunk().await {
@@ -175,41 +697,180 @@ async fn read_capped_body(response: &mut reqwest::Response, max_size: usize) ->
     buf
 }
 
-fn stream_response(
-    upstream_response: reqwest::Response,
-    status: StatusCode,
-    response_headers: HeaderMap,
-    record_id: u64,
-    header_output_tokens: u64,
-    start: Instant,
-    metrics: Arc<MetricsStore>,
-) -> Response {
+type Hook = Box<dyn FnOnce(&RequestRecord, &[u8]) + Send>;
+
+/// Work done once a request's metrics record is final, such as charging a
+/// virtual key or saving a capture. Hooks get the response body when one
+/// of them asked for it with `keep_body`, and an empty slice otherwise.
+#[derive(Default)]
+struct Completion {
+    hooks: Vec<Hook>,
+    keep_body: bool,
+}
+
+impl Completion {
+    fn add(&mut self, hook: impl FnOnce(&RequestRecord, &[u8]) + Send + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    fn run(self, record: &RequestRecord, body: &[u8]) {
+        for hook in self.hooks {
+            hook(record, body);
+        }
+    }
+}
+
+/// Charges a request made with virtual key `key`, if the provider bills
+/// for it. Only Anthropic's API is priced, so requests to translated
+/// providers are free.
+fn key_charge(
+    state: &AppState,
+    key: &str,
+    route: &ResolvedRoute,
+    model: &str,
+) -> Option<impl FnOnce(&RequestRecord, &[u8]) + Send + 'static> {
+    if !pricing::is_billable_url(&route.provider_url) {
+        return None;
+    }
+    let keys = state.keys.clone();
+    let key = key.to_string();
+    let model = route.model_rewrite.as_deref().unwrap_or(model).to_string();
+    Some(move |record: &RequestRecord, _: &[u8]| {
+        if record.status >= 400 {
+            return;
+        }
+        if let Some(usd) =
+            pricing::estimate_cost_usd(&model, record.input_tokens, record.output_tokens)
+        {
+            keys.charge(&key, usd);
+        }
+    })
+}
+
+/// Counts the tokens of a request made with virtual key `key` toward its
+/// quotas, whichever provider served it.
+fn quota_count(state: &AppState, key: &str) -> impl FnOnce(&RequestRecord, &[u8]) + Send + 'static {
+    let keys = state.keys.clone();
+    let metrics = state.metrics.clone();
+    let key = key.to_string();
+    move |record: &RequestRecord, _: &[u8]| {
+        if record.status >= 400 {
+            return;
+        }
+        let tokens = record.input_tokens + record.output_tokens;
+        if let Some(usage) = keys.use_tokens(&key, tokens) {
+            metrics.record_quota(usage);
+        }
+    }
+}
+
+/// Streams `body` to the client, within the route's `limits`, and
+/// finalizes the metrics record and `completion` when it ends. Output
+/// tokens come from `reported_output_tokens` once the provider has
+/// reported them, otherwise they are estimated from the bytes sent. A
+/// client that goes away before the end has its record marked
+/// `client_aborted`, and the provider stops being read.
+fn stream_response<S>(
+    body: S,
+    (status, response_headers): (StatusCode, HeaderMap),
+    reported_output_tokens: Arc<AtomicU64>,
+    state: &AppState,
+    limits: ResponseLimits,
+    record: RequestRecord,
+    completion: Completion,
+) -> Response
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+{
     let byte_counter = Arc::new(AtomicU64::new(0));
     let counter = byte_counter.clone();
+    let kept = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let tokenizer = state.tokenizers.get(&record.provider).cloned();
+    let keep_body = completion.keep_body;
+    let keep = (keep_body || tokenizer.is_some()).then(|| (kept.clone(), state.max_body_size));
 
     let (done_tx, done_rx) = oneshot::channel();
     let guard = StreamGuard(Some(done_tx));
+    // Set once the body has been sent in full, or has failed
+    let ended = Arc::new(AtomicBool::new(false));
+    let content_length = response_headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
 
-    let stream = upstream_response
-        .bytes_stream()
-        .map_ok(move |chunk| {
-            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
-            let _hold = &guard;
-            chunk
-        })
-        .map_err(std::io::Error::other);
+    let is_event_stream = is_event_stream(&response_headers);
+    let cutoff = Arc::new(std::sync::OnceLock::new());
+    let body = read_ahead(body, state.stream_buffer_size, state.metrics.clone());
+    let stream = with_limits(
+        body,
+        limits,
+        (record.timestamp, is_event_stream),
+        cutoff.clone(),
+    )
+    .map_ok(move |chunk| {
+        counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        if let Some((ref kept, limit)) = keep {
+            let mut kept = kept.lock().expect("response copy lock poisoned");
+            let room = limit.saturating_sub(kept.len());
+            kept.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        let _hold = &guard;
+        chunk
+    })
+    .inspect_err({
+        let ended = ended.clone();
+        move |_| ended.store(true, Ordering::Relaxed)
+    })
+    .chain(futures::stream::poll_fn({
+        let ended = ended.clone();
+        move |_| {
+            ended.store(true, Ordering::Relaxed);
+            Poll::Ready(None)
+        }
+    }));
 
-    let body = Body::from_stream(stream);
+    let body = match state.sse_heartbeat {
+        Some(idle) if is_event_stream => Body::from_stream(with_heartbeat(stream, idle)),
+        _ => Body::from_stream(stream),
+    };
 
+    let metrics = state.metrics.clone();
+    let start = record.timestamp;
+    let mut record = record;
+    let record_id = metrics.record_pending(record.clone());
     tokio::spawn(async move {
         let _ = done_rx.await;
         let total_bytes = byte_counter.load(Ordering::Relaxed);
-        let estimated = if header_output_tokens > 0 {
-            header_output_tokens
-        } else {
-            total_bytes / 4
+        let reported = reported_output_tokens.load(Ordering::Relaxed);
+        let kept = kept.lock().expect("response copy lock poisoned");
+        // A body of known length may be let go once it has all been sent
+        let delivered = content_length.is_some_and(|length| total_bytes >= length);
+        if !ended.load(Ordering::Relaxed) && !delivered {
+            info!(
+                model = %record.model,
+                provider = %record.provider,
+                bytes = total_bytes,
+                "client disconnected mid-response"
+            );
+            metrics.mark_aborted(record_id, total_bytes);
+            record.client_aborted = Some(total_bytes);
+        }
+        let estimated = match tokenizer {
+            _ if reported > 0 => reported,
+            // Only a whole response can be counted
+            Some(tokenizer) if kept.len() as u64 == total_bytes => {
+                tokenizer.count(&tokenizer::generated_text(&kept))
+            }
+            _ => total_bytes / 4,
         };
+        if let Some(limit) = cutoff.get() {
+            metrics.mark_cutoff(record_id, limit);
+            record.cutoff = Some(limit.to_string());
+        }
         metrics.finalize_stream(record_id, estimated, start.elapsed());
+        record.output_tokens = estimated;
+        record.duration = start.elapsed();
+        completion.run(&record, if keep_body { &kept } else { &[] });
     });
 
     let mut response = Response::new(body);
@@ -218,63 +879,752 @@ fn stream_response(
     response
 }
 
-fn filter_response_headers(upstream_headers: &reqwest::header::HeaderMap) -> HeaderMap {
-    let mut headers = HeaderMap::new();
-    for (key, value) in upstream_headers {
-        if is_hop_by_hop(key) || key == http::header::CONTENT_ENCODING {
-            continue;
-        }
-        headers.insert(key.clone(), value.clone());
-    }
+/// Whether a request body is JSON: it says so, or says nothing.
+fn is_json_body(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| v.contains("json"))
+}
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
     headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"))
+}
+
+/// Reads `body` into a buffer of up to `buffer_size` bytes that the client
+/// drains. Chunks are passed through as they arrive, without copying. When
+/// the client falls a full buffer behind, reading stops until it catches
+/// up, so the provider is slowed to the client's pace, and the stream is
+/// counted as stalled. Reading ends as soon as the client goes away, even
+/// mid-wait for the provider, so the provider's connection is dropped and
+/// it stops generating.
+fn read_ahead<S>(
+    body: S,
+    buffer_size: usize,
+    metrics: Arc<MetricsStore>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + Send + 'static,
+{
+    let buffer_size = buffer_size.clamp(1, u32::MAX as usize);
+    let room = Arc::new(Semaphore::new(buffer_size));
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut body = std::pin::pin!(body);
+        let mut stalled = Duration::ZERO;
+        loop {
+            let chunk = tokio::select! {
+                chunk = body.next() => chunk,
+                () = tx.closed() => break,
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let item = match chunk {
+                Ok(chunk) => {
+                    let needed = chunk.len().min(buffer_size) as u32;
+                    let permit = match room.clone().try_acquire_many_owned(needed) {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            let waiting = Instant::now();
+                            let permit = tokio::select! {
+                                permit = room.clone().acquire_many_owned(needed) => permit,
+                                () = tx.closed() => break,
+                            };
+                            let Ok(permit) = permit else {
+                                break;
+                            };
+                            stalled += waiting.elapsed();
+                            permit
+                        }
+                    };
+                    Ok((chunk, permit))
+                }
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            if tx.send(item).is_err() {
+                break;
+            }
+        }
+        if !stalled.is_zero() {
+            debug!(
+                stalled_ms = stalled.as_millis() as u64,
+                "stream waited on a slow client"
+            );
+            metrics.count_stalled_stream(stalled);
+        }
+    });
+    // Each chunk's share of the buffer is given back as the client takes it.
+    futures::stream::unfold(rx, |mut rx| async move {
+        let item = rx.recv().await?;
+        Some((item.map(|(chunk, _permit)| chunk), rx))
+    })
+}
+
+/// Ends `stream` once it passes `limits`, measuring time from `start`.
+/// Reading from the provider stops with it. Event streams end with an
+/// `error` event so the client sees why; other responses are aborted, as
+/// a body cut short would otherwise look complete. The limit hit is left
+/// in `cutoff`. Past `limits.drop_after`, the stream fails without any
+/// explanation, as a dropped connection would.
+fn with_limits<S>(
+    stream: S,
+    limits: ResponseLimits,
+    (start, events): (Instant, bool),
+    cutoff: Arc<std::sync::OnceLock<&'static str>>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+{
+    let deadline = [
+        limits.max_duration.map(|max| (max, "max_stream_secs")),
+        limits
+            .request_timeout
+            .map(|max| (max, "request_timeout_ms")),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|&(max, _)| max);
+    let state = (Some(Box::pin(stream)), 0u64, true, false);
+    futures::stream::unfold(state, move |(stream, sent, between_events, dropped)| {
+        let cutoff = cutoff.clone();
+        async move {
+            let Some(mut stream) = stream else {
+                let error = std::io::Error::other("connection dropped by [chaos]");
+                return dropped.then_some((Err(error), (None, sent, true, false)));
+            };
+            let next = match deadline {
+                Some((max, _)) => {
+                    let deadline = tokio::time::Instant::from_std(start + max);
+                    tokio::time::timeout_at(deadline, stream.next()).await
+                }
+                None => Ok(stream.next().await),
+            };
+            let (limit, message) = match next {
+                Ok(Some(Ok(chunk))) => {
+                    if let Some(drop_after) = limits.drop_after
+                        && sent + chunk.len() as u64 > drop_after
+                    {
+                        warn!(
+                            bytes = drop_after,
+                            "[chaos] dropping the response mid-stream"
+                        );
+                        let partial = chunk.slice(..(drop_after - sent.min(drop_after)) as usize);
+                        return Some((Ok(partial), (None, drop_after, true, true)));
+                    }
+                    let sent = sent + chunk.len() as u64;
+                    match limits.max_bytes {
+                        Some(max) if sent > max => (
+                            "max_response_bytes",
+                            format!("response cut off after {max} bytes (max_response_bytes)"),
+                        ),
+                        _ => {
+                            let between_events = if chunk.is_empty() {
+                                between_events
+                            } else {
+                                ends_event(&chunk)
+                            };
+                            return Some((Ok(chunk), (Some(stream), sent, between_events, false)));
+                        }
+                    }
+                }
+                Ok(Some(Err(e))) => {
+                    return Some((Err(e), (Some(stream), sent, between_events, false)));
+                }
+                Ok(None) => return None,
+                Err(_) => match deadline {
+                    Some((max, "request_timeout_ms")) => (
+                        "request_timeout_ms",
+                        format!(
+                            "response cut off after {}ms (request_timeout_ms)",
+                            max.as_millis()
+                        ),
+                    ),
+                    _ => (
+                        "max_stream_secs",
+                        format!(
+                            "response cut off after {}s (max_stream_secs)",
+                            limits.max_duration.unwrap_or_default().as_secs()
+                        ),
+                    ),
+                },
+            };
+            drop(stream);
+            let _ = cutoff.set(limit);
+            warn!("{message}");
+            let item = if events {
+                // Close off any event the provider was partway through
+                let separator = if between_events { "" } else { "\n\n" };
+                let status = if limit == "request_timeout_ms" {
+                    504
+                } else {
+                    500
+                };
+                let error = translate::error_json(status, &message);
+                Ok(Bytes::from(format!(
+                    "{separator}event: error\ndata: {error}\n\n"
+                )))
+            } else {
+                Err(std::io::Error::other(message))
+            };
+            Some((item, (None, sent, true, false)))
+        }
+    })
+}
+
+/// Sends an SSE comment whenever `stream` has been idle for `idle`, so
+/// clients with read timeouts keep waiting on a provider that's thinking.
+/// Comments only go between events, never inside one cut off mid-chunk.
+fn with_heartbeat<S>(
+    stream: S,
+    idle: Duration,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static,
+{
+    const PING: &[u8] = b": ping\n\n";
+    let stream = Box::pin(stream);
+    futures::stream::unfold(
+        (stream, true),
+        move |(mut stream, between_events)| async move {
+            loop {
+                match tokio::time::timeout(idle, stream.next()).await {
+                    Ok(Some(item)) => {
+                        let between_events = match item {
+                            Ok(ref chunk) if !chunk.is_empty() => ends_event(chunk),
+                            _ => between_events,
+                        };
+                        return Some((item, (stream, between_events)));
+                    }
+                    Ok(None) => return None,
+                    Err(_) if between_events => {
+                        return Some((Ok(Bytes::from_static(PING)), (stream, true)));
+                    }
+                    Err(_) => {}
+                }
+            }
+        },
+    )
+}
+
+/// Whether an SSE chunk ends at the blank line closing an event.
+fn ends_event(chunk: &[u8]) -> bool {
+    chunk.ends_with(b"\n\n") || chunk.ends_with(b"\r\n\r\n")
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Records a request that ran past `timeout` before its response started,
+/// and returns the error it is answered with. Streams that run past it are
+/// cut off by `with_limits` instead.
+fn timed_out(
+    state: &AppState,
+    access: &AccessEntry,
+    (start, wallclock): (Instant, DateTime<Utc>),
+    timeout: Duration,
+) -> CroxyError {
+    let message = format!(
+        "request timed out after {}ms (request_timeout_ms)",
+        timeout.as_millis()
+    );
+    warn!(request_id = %access.request_id, "{message}");
+    state.metrics.record(RequestRecord {
+        request_id: Some(access.request_id.clone()),
+        timestamp: start,
+        wallclock,
+        model: access.model.clone().unwrap_or_default(),
+        provider: access.provider.clone().unwrap_or_default(),
+        status: StatusCode::GATEWAY_TIMEOUT.as_u16(),
+        duration: start.elapsed(),
+        error_body: Some(message.clone()),
+        listener: access.listener.clone(),
+        client: access.client.clone(),
+        cutoff: Some("request_timeout_ms".to_string()),
+        bench: access.bench.clone(),
+        ..RequestRecord::default()
+    });
+    CroxyError::Timeout(message)
+}
+
+/// Process-unique request ID, prefixed with the process start time so IDs
+/// from different daemon runs don't collide in shipped logs.
+fn next_request_id() -> String {
+    static EPOCH: std::sync::LazyLock<i64> = std::sync::LazyLock::new(|| Utc::now().timestamp());
+    let n = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{n}", *EPOCH)
 }
 
 pub async fn handle_request(
     State(state): State<Arc<AppState>>,
     request: Request,
-) -> Result<Response, (StatusCode, String)> {
+) -> Result<Response, CroxyError> {
+    let request_id = next_request_id();
+    let slot = request
+        .extensions()
+        .get::<ConnectInfo<Peer>>()
+        .map(|ConnectInfo(peer)| {
+            let http = http_version(request.version());
+            state.metrics.open_stream(peer.connection, peer.ip, http)
+        });
+    let span = info_span!("request", request_id = %request_id);
+    let (start, wallclock) = (Instant::now(), Utc::now());
+    let mut access = AccessEntry::new(&request_id, &request);
+    let proxied = proxy_request(state.clone(), request, &request_id, &mut access).instrument(span);
+    let result = match state.request_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, proxied).await {
+            Ok(result) => result,
+            Err(_) => Err(timed_out(&state, &access, (start, wallclock), timeout)),
+        },
+        None => proxied.await,
+    };
+    match result {
+        Ok(mut response) => {
+            if access.bench.is_some()
+                && let Some(provider) = access
+                    .provider
+                    .as_deref()
+                    .and_then(|p| HeaderValue::from_str(p).ok())
+            {
+                response
+                    .headers_mut()
+                    .insert(crate::bench::PROVIDER_HEADER, provider);
+            }
+            let response = state.access_log.finish(access, response);
+            Ok(match slot {
+                Some(slot) => response.map(|inner| Body::new(Slotted { inner, _slot: slot })),
+                None => response,
+            })
+        }
+        Err(e) => {
+            // Timeouts are recorded, and so complete, like any response
+            if !matches!(e, CroxyError::Timeout(_)) {
+                state.metrics.emit(|| RequestEvent {
+                    client: access.client.clone(),
+                    model: access.model.clone(),
+                    provider: access.provider.clone(),
+                    status: Some(e.status().as_u16()),
+                    elapsed_ms: Some(start.elapsed().as_millis() as u64),
+                    error: Some(e.to_string()),
+                    ..RequestEvent::new(Stage::Completed, &request_id)
+                });
+            }
+            access.status = e.status().as_u16();
+            access.bytes = e.to_string().len() as u64;
+            state.access_log.record(access);
+            Err(e)
+        }
+    }
+}
+
+async fn proxy_request(
+    state: Arc<AppState>,
+    request: Request,
+    request_id: &str,
+    access: &mut AccessEntry,
+) -> Result<Response, CroxyError> {
     let start = Instant::now();
     let wallclock = Utc::now();
-    let (parts, body) = request.into_parts();
+    let (mut parts, body) = request.into_parts();
+    let ingress = parts
+        .extensions
+        .get::<Ingress>()
+        .cloned()
+        .unwrap_or_default();
+    access.client = client_of(&parts, state.client_header.as_deref());
+    // The run's tag is croxy's own, not the provider's business
+    parts.headers.remove(crate::bench::HEADER);
     let method = parts.method.clone();
     let path = parts
         .uri
         .path_and_query()
         .map(|pq| pq.as_str().to_string())
         .unwrap_or_else(|| parts.uri.path().to_string());
+    state.metrics.emit(|| RequestEvent {
+        method: Some(method.to_string()),
+        path: Some(path.clone()),
+        client: access.client.clone(),
+        ..RequestEvent::new(Stage::Received, request_id)
+    });
 
-    let body_bytes = axum::body::to_bytes(body, state.max_body_size)
-        .await
-        .map_err(|e| (StatusCode::BAD_REQUEST, format!("failed to read body: {e}")))?;
+    let key = match keys::presented(&parts.headers) {
+        Some(presented) => match state.keys.authorize(presented) {
+            Ok(key) => {
+                access.key = Some(key.name.clone());
+                access.client = Some(key.name.clone());
+                Some(key)
+            }
+            Err(denied) => {
+                warn!(path = %path, "{denied}");
+                state
+                    .audit
+                    .record(AuditEvent::denied(&denied, &path, ingress.tag.as_deref()));
+                return Ok(denied_response(&denied));
+            }
+        },
+        None if state.keys.required() || ingress.require_key => {
+            state.audit.record(AuditEvent::AuthFailed {
+                path: path.clone(),
+                listener: ingress.tag.clone(),
+                reason: "no virtual key presented".to_string(),
+            });
+            return Ok(json_response(
+                StatusCode::UNAUTHORIZED,
+                &translate::error_json(401, "a croxy virtual key is required"),
+            ));
+        }
+        None => None,
+    };
 
-    let body_len = body_bytes.len();
+    // Oversized bodies are read in full when their tool results can be cut
+    let read_limit = match state.tool_results.max_size {
+        Some(_) => state.max_body_size.max(state.tool_results.max_request_size),
+        None => state.max_body_size,
+    };
+    let mut body_bytes = match axum::body::to_bytes(body, read_limit).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            // Bodies cut off at the limit are counted at the size they claimed
+            if let Some(declared) = parts
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+                .filter(|&declared| declared > read_limit)
+            {
+                state
+                    .metrics
+                    .record_body_size(Direction::Request, None, declared);
+            }
+            return Err(CroxyError::Request(format!("failed to read body: {e}")));
+        }
+    };
+    let mut timings = Timings {
+        body_read_ms: Some(start.elapsed().as_millis() as u64),
+        ..Timings::default()
+    };
 
-    let (mut body_json, model) = if !body_bytes.is_empty() {
-        let json: serde_json::Value = serde_json::from_slice(&body_bytes)
-            .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid JSON body: {e}")))?;
-        let model = json
-            .get("model")
-            .and_then(|m| m.as_str())
-            .unwrap_or("")
-            .to_string();
-        (Some(json), model)
+    // Most requests are routed on their model alone; the body is parsed
+    // only once something needs to look inside it.
+    let mut body_json = None;
+    let json_body = is_json_body(&parts.headers);
+    let (mut model, has_messages, max_tokens) = if !body_bytes.is_empty() {
+        let peeked = match peek::peek(&body_bytes) {
+            Ok(peeked) => peeked,
+            // Uploads and the like are passed through unread
+            Err(_) if !json_body => Peek::default(),
+            Err(_) => match parsed(&mut body_json, &body_bytes)? {
+                Some(json) => Peek {
+                    model: json["model"].as_str().unwrap_or("").to_string(),
+                    messages: json["messages"].is_array(),
+                    max_tokens: json["max_tokens"].as_u64(),
+                },
+                None => Peek::default(),
+            },
+        };
+        // A body under the limit can't hold a tool result over it
+        if let Some(max_size) = state.tool_results.max_size
+            && json_body
+            && body_bytes.len() > max_size
+            && let Some(json) = parsed(&mut body_json, &body_bytes)?
+        {
+            let truncated = tool_results::truncate(json, max_size);
+            if truncated > 0 {
+                let original_len = body_bytes.len();
+                body_bytes = serialize_body(json)?;
+                state.metrics.count_truncated_tool_results(truncated);
+                warn!(
+                    truncated,
+                    original_bytes = original_len,
+                    bytes = body_bytes.len(),
+                    "truncated oversized tool results"
+                );
+            }
+        }
+        if body_bytes.len() > state.max_body_size {
+            state
+                .metrics
+                .record_body_size(Direction::Request, None, body_bytes.len());
+            return Err(CroxyError::Request(format!(
+                "request body is {} bytes, over max_body_size ({})",
+                body_bytes.len(),
+                state.max_body_size
+            )));
+        }
+        (peeked.model, peeked.messages, peeked.max_tokens)
     } else {
-        (None, String::new())
+        (String::new(), false, None)
+    };
+    if state.validate_requests && method == http::Method::POST && schema::applies(parts.uri.path())
+    {
+        let body = parsed(&mut body_json, &body_bytes)?;
+        if let Err(problem) = schema::check(
+            parts.uri.path(),
+            body.map_or(&serde_json::Value::Null, |b| b),
+        ) {
+            warn!(path = %path, "rejected malformed request: {problem}");
+            return Ok(json_response(
+                StatusCode::BAD_REQUEST,
+                &translate::error_json(400, &problem),
+            ));
+        }
+    }
+    let body_len = body_bytes.len();
+    let mut completion = Completion::default();
+    if let Some(ref store) = state.captures {
+        let capture = store.start(Capture::new(
+            request_id,
+            &method,
+            &path,
+            &parts.headers,
+            parsed(&mut body_json, &body_bytes)?
+                .cloned()
+                .map(|mut body| {
+                    state.scrubber.scrub_value(&mut body);
+                    body
+                }),
+        ));
+        completion.add(move |record, _| capture.finish(record));
+    }
+
+    let router = state.router();
+    let routing = Instant::now();
+    let mut batch_size = None;
+    let mut route = match BatchCall::of(&method, parts.uri.path()) {
+        Some(BatchCall::Create) => {
+            let body = parsed(&mut body_json, &body_bytes)?
+                .ok_or_else(|| CroxyError::Request("missing request body".to_string()))?;
+            let batch = match batches::route_batch(&router, body) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    return Ok(json_response(
+                        StatusCode::BAD_REQUEST,
+                        &translate::error_json(400, &e),
+                    ));
+                }
+            };
+            body_bytes = serialize_body(body)?;
+            model = batch.models;
+            batch_size = Some(batch.size);
+            batch.route
+        }
+        // Batches created before a restart go to the usual route
+        Some(BatchCall::Existing(id)) => state
+            .batches
+            .owner(id)
+            .and_then(|provider| router.provider_route(&provider))
+            .unwrap_or_else(|| router.resolve_pattern(&model, Endpoint::Other)),
+        None => {
+            let endpoint = Endpoint::of(parts.uri.path());
+            // A body that isn't JSON, like a file upload, has no fields
+            if router.matches_fields() && json_body {
+                parsed(&mut body_json, &body_bytes)?;
+            }
+            let fields = RequestFields::of(body_json.as_ref());
+            let client_route = key
+                .as_ref()
+                .and_then(|key| router.resolve_client(&key.name, &model, endpoint, fields));
+            match client_route {
+                Some(route) => route,
+                None => {
+                    if has_messages && router.classifies(&model) {
+                        parsed(&mut body_json, &body_bytes)?;
+                    }
+                    let fields = RequestFields::of(body_json.as_ref());
+                    let messages = body_json
+                        .as_ref()
+                        .and_then(|j| j.get("messages"))
+                        .and_then(|m| m.as_array())
+                        .map(|v| v.as_slice());
+                    let classifies = router.classifies(&model);
+                    let skipped = classifies && router.skips_classification(endpoint, messages);
+                    if skipped {
+                        state.metrics.count_skipped_classification();
+                    }
+                    let classifying = Instant::now();
+                    let route = router
+                        .resolve(&model, endpoint, fields, messages, &state.client)
+                        .await;
+                    if classifies && !skipped {
+                        timings.classifier_ms = Some(classifying.elapsed().as_millis() as u64);
+                    }
+                    route
+                }
+            }
+        }
     };
+    router.balance(&mut route, &state.metrics);
 
-    let messages = body_json
-        .as_ref()
-        .and_then(|j| j.get("messages"))
-        .and_then(|m| m.as_array())
-        .map(|v| v.as_slice());
+    if let Some(ref script) = state.script
+        && BatchCall::of(&method, parts.uri.path()).is_none()
+    {
+        parsed(&mut body_json, &body_bytes)?;
+        let decision = script
+            .decide(&ScriptRequest {
+                method: method.as_str(),
+                path: &path,
+                model: &model,
+                provider: &route.provider_name,
+                route: route.route_name.as_deref(),
+                key: key.as_ref().map(|k| k.name.as_str()),
+                headers: script::visible_headers(&parts.headers),
+                body: body_json.as_ref(),
+            })
+            .await;
+        match decision {
+            Ok(decision) => apply_decision(
+                &router,
+                decision,
+                &mut route,
+                &mut body_json,
+                &mut body_bytes,
+            )?,
+            // A broken script shouldn't take the proxy down with it
+            Err(e) => error!(model = %model, "{e}"),
+        }
+    }
 
-    let route = state.router.resolve(&model, messages, &state.client).await;
+    if let Some(provider) = router.provider(&route.provider_name)
+        && let Some(reserve) = provider.ratelimit_reserve
+        && let Some(limit) = state.metrics.rate_limit(&route.provider_name)
+        && limit.nearly_exhausted(reserve)
+    {
+        let fallback = provider.ratelimit_fallback.as_deref().and_then(|name| {
+            let route = router.provider_route(name);
+            if route.is_none() {
+                warn!(provider = %name, "rate limit fallback has no route");
+            }
+            route
+        });
+        match fallback {
+            Some(fallback) => {
+                info!(
+                    provider = %route.provider_name,
+                    fallback = %fallback.provider_name,
+                    "rate limit nearly exhausted, rerouting"
+                );
+                route = fallback;
+            }
+            None => {
+                let wait = limit.time_to_reset(reserve).min(MAX_RATELIMIT_WAIT);
+                info!(
+                    provider = %route.provider_name,
+                    wait_ms = wait.as_millis() as u64,
+                    "rate limit nearly exhausted, waiting for it to reset"
+                );
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    if let Some(capability) = lacking_capability(
+        &router,
+        &route,
+        parts.uri.path(),
+        &mut body_json,
+        &body_bytes,
+    )? {
+        let fallback = router
+            .provider(&route.provider_name)
+            .and_then(|provider| provider.capability_fallback.as_deref())
+            .and_then(|name| router.provider_route(name))
+            .filter(|fallback| {
+                router
+                    .provider(&fallback.provider_name)
+                    .is_some_and(|provider| provides(provider, fallback, capability))
+            });
+        match fallback {
+            Some(fallback) => {
+                info!(
+                    provider = %route.provider_name,
+                    fallback = %fallback.provider_name,
+                    capability = capability.name(),
+                    "provider lacks a capability, rerouting"
+                );
+                route = fallback;
+            }
+            None => {
+                let message = format!(
+                    "provider '{}' does not support {}",
+                    route.provider_name,
+                    capability.name()
+                );
+                warn!(path = %path, "{message}");
+                return Ok(json_response(
+                    StatusCode::NOT_IMPLEMENTED,
+                    &translate::error_json(501, &message),
+                ));
+            }
+        }
+    }
+
+    access.model = (!model.is_empty()).then(|| model.clone());
+    access.provider = Some(route.provider_name.clone());
+    route.limits.request_timeout = state.request_timeout;
+    state
+        .metrics
+        .record_body_size(Direction::Request, Some(size_label(&route)), body_len);
 
-    if parts.uri.path().contains("/count_tokens") && route.stub_count_tokens {
+    // A virtual key stands in for the provider's own credentials
+    if let Some(ref key) = key {
+        if !key.allows(&route) {
+            let denied = keys::Denied::Route {
+                name: key.name.clone(),
+                route: route
+                    .route_name
+                    .clone()
+                    .unwrap_or_else(|| format!("provider '{}'", route.provider_name)),
+            };
+            warn!(model = %model, "{denied}");
+            state
+                .audit
+                .record(AuditEvent::denied(&denied, &path, ingress.tag.as_deref()));
+            return Ok(denied_response(&denied));
+        }
+        route.strip_auth = true;
+        if batch_size.is_none() {
+            if let Some(charge) = key_charge(&state, &key.name, &route, &model) {
+                completion.add(charge);
+            }
+            completion.add(quota_count(&state, &key.name));
+        }
+    }
+
+    // Token counting is an Anthropic endpoint with no equivalent in the
+    // translated APIs.
+    if parts.uri.path().contains("/count_tokens")
+        && (route.stub_count_tokens || Translation::for_format(route.api_format).is_some())
+    {
         debug!(path = %path, "returning stub count_tokens response");
         return Ok(stub_count_tokens_response());
     }
 
+    let mut leader = None;
+    if let Some(mode) = route.dedup {
+        let key = dedup::key(&route.provider_name, &path, &parts.headers, &body_bytes);
+        match state.in_flight.join(key) {
+            Joined::First(first) => leader = Some(first),
+            Joined::Duplicate(_) if mode == Dedup::Reject => {
+                info!(model = %model, "rejecting a duplicate of a request in flight");
+                state.metrics.count_deduplicated();
+                return Ok(json_response(
+                    StatusCode::CONFLICT,
+                    &translate::error_json(409, "an identical request is already in flight"),
+                ));
+            }
+            // Should the first request fail before it has a response,
+            // this one is sent on its own
+            Joined::Duplicate(duplicate) => {
+                if let Some(response) = duplicate.response().await {
+                    info!(model = %model, "attached to an identical request in flight");
+                    state.metrics.count_deduplicated();
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
     info!(
         model = %model,
         provider = %route.provider_url,
@@ -283,15 +1633,188 @@ pub async fn handle_request(
         estimated_tokens = body_len / 4,
         "routing request"
     );
+    timings.routing_ms = Some(routing.elapsed().as_millis() as u64);
+    state.metrics.emit(|| RequestEvent {
+        client: access.client.clone(),
+        model: Some(model.clone()),
+        provider: Some(route.provider_name.clone()),
+        route: route.route_name.clone(),
+        routing_method: Some(route.routing_method.to_string()),
+        elapsed_ms: Some(start.elapsed().as_millis() as u64),
+        ..RequestEvent::new(Stage::Routed, request_id)
+    });
+
+    let chaos = match state.chaos {
+        Some(ref chaos) => {
+            let fault = chaos.roll(&route);
+            if let Some(latency) = fault.latency {
+                debug!(
+                    latency_ms = latency.as_millis() as u64,
+                    "[chaos] delaying request"
+                );
+                tokio::time::sleep(latency).await;
+            }
+            route.limits.drop_after = fault.truncate_after;
+            if let Some(status) = fault.error {
+                let record = RequestRecord {
+                    request_id: Some(request_id.to_string()),
+                    timestamp: start,
+                    wallclock,
+                    model: model.clone(),
+                    provider: route.provider_name.clone(),
+                    routing_method: route.routing_method,
+                    status: status.as_u16(),
+                    duration: start.elapsed(),
+                    batch_size,
+                    listener: ingress.tag,
+                    client: access.client.clone(),
+                    chaos: fault.label(),
+                    bench: access.bench.clone(),
+                    timings,
+                    ..RequestRecord::default()
+                };
+                return Ok(injected_error(&state, status, record, completion));
+            }
+            fault.label()
+        }
+        None => None,
+    };
+
+    if method == http::Method::POST
+        && parts.uri.path() == "/v1/messages"
+        && let Some(ref comparer) = state.compare
+        && let Some(rule) = comparer.select(&model)
+        && let Some(body) = parsed(&mut body_json, &body_bytes)?
+    {
+        match router.provider_route(&rule.provider) {
+            Some(mut candidate) => {
+                candidate.model_rewrite = rule.model.clone();
+                candidate.strip_auth |= key.is_some();
+                debug!(provider = %candidate.provider_name, "sending comparison request");
+                let pending = tokio::spawn(send_candidate(
+                    state.clone(),
+                    candidate,
+                    parts.headers.clone(),
+                    body.clone(),
+                    model.clone(),
+                ));
+                completion.keep_body = true;
+                completion.add(compare_hook(
+                    &state,
+                    comparer.clone(),
+                    request_id,
+                    &model,
+                    &route,
+                    pending,
+                ));
+            }
+            None => warn!(provider = %rule.provider, "compare provider not found"),
+        }
+    }
 
-    let final_body = if let Some(ref new_model) = route.model_rewrite {
-        rewrite_model_in_body(&mut body_json, body_bytes, new_model)?
+    if state.middleware.reads_body(&route.provider_name) {
+        parsed(&mut body_json, &body_bytes)?;
+    }
+    let applied = run_middleware(
+        &state,
+        &route.provider_name,
+        &mut parts.headers,
+        body_json.as_mut(),
+    );
+    let redactions = applied.redactions;
+    let mut body_changed = applied.body_changed;
+
+    if parts.uri.path() == "/v1/messages"
+        && !route.sampling.is_empty()
+        && let Some(json) = parsed(&mut body_json, &body_bytes)?
+    {
+        route.sampling.apply(json);
+        debug!(sampling = ?route.sampling, "overrode sampling parameters");
+        body_changed = true;
+    }
+
+    if parts.uri.path() == "/v1/messages"
+        && let Some(guard) = route.context_guard
+        && let Some(limits) = router.model_limits(
+            &route.provider_name,
+            route.model_rewrite.as_deref().unwrap_or(&model),
+        )
+        && let Some(json) = parsed(&mut body_json, &body_bytes)?
+    {
+        body_changed |=
+            guard_context(&state, &parts.headers, guard, limits.context_window, json).await;
+    }
+
+    if let Some(ref archive) = state.archive
+        && route.archive
+        && parts.uri.path() == "/v1/messages"
+    {
+        let pending = archive::Pending {
+            model: route.model_rewrite.clone().unwrap_or_else(|| model.clone()),
+            route: route.route_name.clone(),
+            request: parsed(&mut body_json, &body_bytes)?.cloned(),
+        };
+        let archive = archive.clone();
+        completion.keep_body = true;
+        completion.add(move |record, body| archive.finish(pending, record, body));
+    }
+
+    if let Some(translation) = Translation::for_format(route.api_format)
+        && parts.uri.path() == "/v1/messages"
+    {
+        parsed(&mut body_json, &body_bytes)?;
+        let response = forward_translated(
+            &state,
+            &parts.headers,
+            &route,
+            translation,
+            body_json,
+            &model,
+            (
+                request_id, start, wallclock, redactions, access, chaos, timings, completion,
+            ),
+        )
+        .await?;
+        return Ok(shared(leader, response));
+    }
+
+    if parts.uri.path().starts_with("/v1/messages")
+        && let Some(provider) = router.provider(&route.provider_name)
+        && provider.cache_control != CacheControl::Passthrough
+        && let Some(json) = parsed(&mut body_json, &body_bytes)?
+        && caching::apply(provider.cache_control, json)
+    {
+        debug!(mode = ?provider.cache_control, "adjusted cache_control breakpoints");
+        body_changed = true;
+    }
+
+    let (url, final_body, bearer) = if route.api_format == ApiFormat::Vertex {
+        parsed(&mut body_json, &body_bytes)?;
+        let (url, body, token) =
+            vertex_request(&state, &route, parts.uri.path(), body_json).await?;
+        (url, body, Some(token))
     } else {
-        body_bytes
+        if let Some(ref new_model) = route.model_rewrite
+            && let Some(json) = parsed(&mut body_json, &body_bytes)?
+        {
+            json["model"] = serde_json::Value::String(new_model.clone());
+            body_changed = true;
+        }
+        let final_body = match body_json {
+            Some(ref json) if body_changed => serialize_body(json)?,
+            _ => body_bytes,
+        };
+        let url = format!("{}{}", route.provider_url.trim_end_matches('/'), path);
+        (url, final_body, provider_token(&state, &route).await?)
     };
 
-    let url = format!("{}{}", route.provider_url.trim_end_matches('/'), path);
-    let headers = build_forwarding_headers(&parts.headers, &route, final_body.len());
+    let defaults = default_headers(&state, &route);
+    let api_key = provider_api_key(&state, &route).await?;
+    let mut headers =
+        build_forwarding_headers(&parts.headers, &route, api_key, &defaults, final_body.len());
+    if let Some(token) = bearer {
+        use_bearer(&mut headers, &token)?;
+    }
 
     debug!(url = %url, "forwarding to provider");
     log_outgoing_headers(&headers);
@@ -299,68 +1822,687 @@ pub async fn handle_request(
         debug!(body_bytes = final_body.len(), "outgoing body");
     }
 
-    let mut upstream_response = state
-        .client
-        .request(method, &url)
-        .headers(headers)
-        .body(final_body)
-        .send()
+    let near_limit = parts.uri.path() == "/v1/messages"
+        && near_context_limit(
+            &router,
+            &route,
+            route.model_rewrite.as_deref().unwrap_or(&model),
+            final_body.len(),
+            max_tokens,
+        );
+    let mut record = RequestRecord {
+        request_id: Some(request_id.to_string()),
+        timestamp: start,
+        wallclock,
+        model: model.clone(),
+        provider: route.provider_name.clone(),
+        routing_method: route.routing_method,
+        input_tokens: (body_len / 4) as u64,
+        batch_size,
+        redactions,
+        listener: ingress.tag,
+        client: access.client.clone(),
+        chaos,
+        bench: access.bench.clone(),
+        near_limit,
+        timings,
+        ..RequestRecord::default()
+    };
+    let sent_body = final_body.clone();
+    let client = state.client_for(&route.provider_name).clone();
+    let send = move || {
+        client
+            .request(method.clone(), &url)
+            .headers(headers.clone())
+            .body(final_body.clone())
+            .send()
+    };
+    let sent = Instant::now();
+    let (sent_response, connect) = clients::timing_connect(send()).await;
+    let mut upstream_response = sent_response.map_err(unreachable)?;
+    record.timings.connect_ms = connect.map(|connect| connect.as_millis() as u64);
+    note_rate_limits(&state, &route.provider_name, &upstream_response);
+
+    if upstream_response.status() == StatusCode::TOO_MANY_REQUESTS
+        && let Some(max_wait) = router
+            .provider(&route.provider_name)
+            .and_then(|p| p.retry_429_max_wait_secs)
+            .map(Duration::from_secs)
+    {
+        let retry = Retry {
+            max_wait,
+            send: Box::new(move || send().boxed()),
+        };
+        // Streaming clients get their response started now, so heartbeats
+        // can keep the connection open while the request waits.
+        if wants_stream(&sent_body) {
+            let heartbeat = state.sse_heartbeat.unwrap_or(HOLD_HEARTBEAT);
+            let held = async move {
+                let upstream_response = retry.run(&state, &route, upstream_response).await?;
+                record.timings.ttfb_ms = Some(sent.elapsed().as_millis() as u64);
+                Ok(relay_response(&state, upstream_response, &route, record, completion).await)
+            };
+            return Ok(shared(
+                leader,
+                hold_stream(held.in_current_span(), heartbeat),
+            ));
+        }
+        upstream_response = retry.run(&state, &route, upstream_response).await?;
+    }
+    record.timings.ttfb_ms = Some(sent.elapsed().as_millis() as u64);
+
+    let response = relay_response(&state, upstream_response, &route, record, completion).await;
+    Ok(shared(leader, response))
+}
+
+/// Passes `response` on, to duplicates of the request as well if it
+/// leads any.
+fn shared(leader: Option<Leader>, response: Response) -> Response {
+    match leader {
+        Some(leader) => leader.share(response),
+        None => response,
+    }
+}
+
+/// Rewrites a request for Claude on Vertex AI, returning its URL, body, and
+/// the access token that replaces the client's credentials.
+async fn vertex_request(
+    state: &AppState,
+    route: &ResolvedRoute,
+    path: &str,
+    body_json: Option<serde_json::Value>,
+) -> Result<(String, Bytes, String), CroxyError> {
+    let vertex = state.vertex.get(&route.provider_name).ok_or_else(|| {
+        CroxyError::Internal(format!(
+            "provider '{}' is not set up for vertex",
+            route.provider_name
+        ))
+    })?;
+    let mut body =
+        body_json.ok_or_else(|| CroxyError::Request("missing request body".to_string()))?;
+    if let Some(ref new_model) = route.model_rewrite {
+        body["model"] = serde_json::Value::String(new_model.clone());
+    }
+    let model = body["model"].as_str().unwrap_or_default().to_string();
+    let streaming = body["stream"].as_bool().unwrap_or(false);
+    let url = vertex
+        .url(&route.provider_url, path, &model, streaming)
+        .ok_or_else(|| CroxyError::Routing(format!("{path} is not available on Vertex AI")))?;
+    vertex::prepare_body(&mut body, path);
+    let body = serde_json::to_vec(&body)
+        .map(Bytes::from)
+        .map_err(|e| CroxyError::Internal(format!("failed to serialize body: {e}")))?;
+
+    let token = vertex.token(state.client_for(&route.provider_name)).await;
+    // Recorded as it changes, for the providers view
+    let error = token.as_ref().err().cloned();
+    let last = state
+        .metrics
+        .auth()
+        .into_iter()
+        .find(|status| status.provider == route.provider_name);
+    if last.is_none_or(|status| status.error != error) {
+        state.metrics.record_auth(AuthStatus {
+            provider: route.provider_name.clone(),
+            checked: chrono::Utc::now(),
+            expires: None,
+            error,
+        });
+    }
+    let token = token.map_err(|e| {
+        error!(provider = %route.provider_name, error = %e, "vertex authentication failed");
+        CroxyError::Upstream(e)
+    })?;
+    Ok((url, body, token))
+}
+
+/// The bearer token for `route`'s provider, when it has `token_command` or
+/// `oauth`.
+async fn provider_token(
+    state: &AppState,
+    route: &ResolvedRoute,
+) -> Result<Option<String>, CroxyError> {
+    let Some(tokens) = state.tokens.get(&route.provider_name) else {
+        return Ok(None);
+    };
+    tokens
+        .token(state.client_for(&route.provider_name), &state.metrics)
         .await
+        .map(Some)
         .map_err(|e| {
-            error!(url = %url, error = %e, "provider request failed");
-            (
-                StatusCode::BAD_GATEWAY,
-                format!("provider unreachable: {e}"),
+            error!(provider = %route.provider_name, error = %e, "provider authentication failed");
+            CroxyError::Upstream(e)
+        })
+}
+
+/// The API key `route` sends to its provider, read from its file or the
+/// keychain if no request has needed it yet. A key read now is scrubbed
+/// from logs from then on.
+async fn provider_api_key<'a>(
+    state: &AppState,
+    route: &'a ResolvedRoute,
+) -> Result<Option<&'a str>, CroxyError> {
+    let key = route.api_key.load().await.map_err(|e| {
+        error!(provider = %route.provider_name, error = %e, "failed to read provider API key");
+        CroxyError::Upstream(e)
+    })?;
+    if let Some(key) = key {
+        state.scrubber.add_secret(key);
+    }
+    Ok(key)
+}
+
+/// Applies what the `[script]` hook decided for a request.
+fn apply_decision(
+    router: &Router,
+    decision: Decision,
+    route: &mut ResolvedRoute,
+    body_json: &mut Option<serde_json::Value>,
+    body_bytes: &mut Bytes,
+) -> Result<(), CroxyError> {
+    if let Some(provider) = decision.provider {
+        match router.provider_route(&provider) {
+            Some(chosen) => {
+                debug!(provider = %provider, "script chose provider");
+                *route = chosen;
+                route.routing_method = RoutingMethod::Script;
+            }
+            None => warn!(provider = %provider, "script chose an unknown provider"),
+        }
+    }
+    if let Some(model) = decision.model {
+        route.model_rewrite = Some(model);
+        route.routing_method = RoutingMethod::Script;
+    }
+    if let Some(body) = decision.body {
+        *body_bytes = serialize_body(&body)?;
+        *body_json = Some(body);
+    }
+    Ok(())
+}
+
+/// The request body as JSON, parsed on first use. `None` for an empty body.
+fn parsed<'a>(
+    json: &'a mut Option<serde_json::Value>,
+    bytes: &[u8],
+) -> Result<Option<&'a mut serde_json::Value>, CroxyError> {
+    if json.is_none() && !bytes.is_empty() {
+        let value = serde_json::from_slice(bytes)
+            .map_err(|e| CroxyError::Request(format!("invalid JSON body: {e}")))?;
+        *json = Some(value);
+    }
+    Ok(json.as_mut())
+}
+
+/// Runs the `[[middleware]]` stages for `provider` over a request.
+fn run_middleware(
+    state: &AppState,
+    provider: &str,
+    headers: &mut HeaderMap,
+    body: Option<&mut serde_json::Value>,
+) -> Applied {
+    let applied = state.middleware.apply(provider, headers, body);
+    if applied.redactions > 0 {
+        info!(provider = %provider, redactions = applied.redactions, "redacted request body");
+    }
+    applied
+}
+
+/// Replaces the client's credentials with an access token.
+fn use_bearer(headers: &mut HeaderMap, token: &str) -> Result<(), CroxyError> {
+    headers.remove("x-api-key");
+    let value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| {
+        CroxyError::Upstream("access token contains invalid header characters".to_string())
+    })?;
+    headers.insert(http::header::AUTHORIZATION, value);
+    Ok(())
+}
+
+/// Drops, or summarizes, the oldest messages of a Messages request that
+/// won't fit in `window` tokens. Returns whether the body changed.
+async fn guard_context(
+    state: &AppState,
+    headers: &HeaderMap,
+    guard: ContextGuard,
+    window: u64,
+    body: &mut serde_json::Value,
+) -> bool {
+    let summarizer = match guard {
+        ContextGuard::Trim => None,
+        ContextGuard::Summarize => state.router().summary_route(),
+    };
+    let reserve = if summarizer.is_some() {
+        context_guard::SUMMARY_TOKENS
+    } else {
+        0
+    };
+    let Some(cut) = context_guard::cut(body, window, reserve) else {
+        return false;
+    };
+    let dropped = context_guard::trim(body, cut);
+    if let Some(route) = summarizer {
+        let model = route.model_rewrite.clone().unwrap_or_default();
+        let request = context_guard::summary_request(&model, &dropped);
+        match candidate_response(state, &route, headers, request, &model).await {
+            Ok((status, summary)) if status < 400 && !summary.text.is_empty() => {
+                context_guard::prepend_summary(body, &summary.text);
+                info!(
+                    messages = dropped.len(),
+                    window, "summarized oldest messages to fit the context window"
+                );
+                return true;
+            }
+            Ok((status, _)) => {
+                warn!(status, "context summary failed, trimming instead");
+            }
+            Err(e) => warn!("context summary failed, trimming instead: {e}"),
+        }
+    }
+    info!(
+        messages = dropped.len(),
+        window, "trimmed oldest messages to fit the context window"
+    );
+    true
+}
+
+/// Sends the comparison copy of a Messages request to `route`, unstreamed,
+/// and reports how it went.
+async fn send_candidate(
+    state: Arc<AppState>,
+    route: ResolvedRoute,
+    mut headers: HeaderMap,
+    mut body: serde_json::Value,
+    model: String,
+) -> Side {
+    let start = Instant::now();
+    body["stream"] = serde_json::Value::Bool(false);
+    run_middleware(&state, &route.provider_name, &mut headers, Some(&mut body));
+    let result = candidate_response(&state, &route, &headers, body, &model).await;
+    let mut side = Side {
+        provider: route.provider_name.clone(),
+        model: route.model_rewrite.clone().unwrap_or(model),
+        duration_ms: start.elapsed().as_millis() as u64,
+        ..Side::default()
+    };
+    match result {
+        Ok((status, summary)) => {
+            side.status = status;
+            side.input_tokens = summary.input_tokens;
+            side.output_tokens = summary.output_tokens;
+            side.stop_reason = summary.stop_reason;
+            side.text = summary.text;
+        }
+        Err(e) => {
+            warn!(provider = %route.provider_name, "comparison request failed: {e}");
+            side.status = e.status().as_u16();
+        }
+    }
+    side
+}
+
+async fn candidate_response(
+    state: &AppState,
+    route: &ResolvedRoute,
+    original_headers: &HeaderMap,
+    mut body: serde_json::Value,
+    model: &str,
+) -> Result<(u16, ResponseSummary), CroxyError> {
+    let translation = Translation::for_format(route.api_format);
+    let request = match translation {
+        Some(translation) => {
+            let bearer = provider_token(state, route).await?;
+            let (request, ..) = translated_request(
+                state,
+                original_headers,
+                route,
+                translation,
+                &body,
+                model,
+                bearer.as_deref(),
             )
-        })?;
+            .await?;
+            request
+        }
+        None => {
+            let (url, body, bearer) = if route.api_format == ApiFormat::Vertex {
+                let (url, body, token) =
+                    vertex_request(state, route, "/v1/messages", Some(body)).await?;
+                (url, body, Some(token))
+            } else {
+                if let Some(ref new_model) = route.model_rewrite {
+                    body["model"] = serde_json::Value::String(new_model.clone());
+                }
+                let url = format!("{}/v1/messages", route.provider_url.trim_end_matches('/'));
+                (
+                    url,
+                    serialize_body(&body)?,
+                    provider_token(state, route).await?,
+                )
+            };
+            let defaults = default_headers(state, route);
+            let api_key = provider_api_key(state, route).await?;
+            let mut headers =
+                build_forwarding_headers(original_headers, route, api_key, &defaults, body.len());
+            if let Some(token) = bearer {
+                use_bearer(&mut headers, &token)?;
+            }
+            state
+                .client_for(&route.provider_name)
+                .post(&url)
+                .headers(headers)
+                .body(body)
+        }
+    };
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| CroxyError::Upstream(format!("provider unreachable: {}", e.without_url())))?;
+    let status = response.status().as_u16();
+    let bytes = read_capped_body(&mut response, state.max_body_size).await;
+    if status >= 400 {
+        return Ok((status, ResponseSummary::default()));
+    }
+    let summary = match translation {
+        Some(translation) => {
+            let response: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| {
+                CroxyError::Upstream(format!("invalid response from provider: {e}"))
+            })?;
+            ResponseSummary::from_message(&translation.response(&response, model))
+        }
+        None => ResponseSummary::read(&bytes),
+    };
+    Ok((status, summary))
+}
 
-    let status = StatusCode::from_u16(upstream_response.status().as_u16())
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+/// Records the comparison once both the primary response, given to the
+/// hook, and the candidate's are in.
+fn compare_hook(
+    state: &AppState,
+    comparer: Arc<Comparer>,
+    request_id: &str,
+    model: &str,
+    route: &ResolvedRoute,
+    pending: tokio::task::JoinHandle<Side>,
+) -> impl FnOnce(&RequestRecord, &[u8]) + Send + 'static {
+    let metrics = state.metrics.clone();
+    let request_id = request_id.to_string();
+    let model = model.to_string();
+    let primary_model = route.model_rewrite.clone().unwrap_or_else(|| model.clone());
+    move |record: &RequestRecord, body: &[u8]| {
+        let summary = ResponseSummary::read(body);
+        let reported = |summary: u64, record: u64| if summary > 0 { summary } else { record };
+        let primary = Side {
+            provider: record.provider.clone(),
+            model: primary_model,
+            status: record.status,
+            duration_ms: record.duration.as_millis() as u64,
+            input_tokens: reported(summary.input_tokens, record.input_tokens),
+            output_tokens: reported(summary.output_tokens, record.output_tokens),
+            stop_reason: summary.stop_reason,
+            text: summary.text,
+        };
+        tokio::spawn(async move {
+            let candidate = pending.await.unwrap_or_default();
+            comparer.record(
+                Comparison::new(&request_id, &model, primary, candidate),
+                &metrics,
+            );
+        });
+    }
+}
 
-    info!(status = %status, url = %url, "provider responded");
+/// Answers with an error `[chaos]` injected in place of the provider's
+/// response, shaped like one a provider would send.
+fn injected_error(
+    state: &AppState,
+    status: StatusCode,
+    mut record: RequestRecord,
+    completion: Completion,
+) -> Response {
+    let message = format!("croxy chaos: injected HTTP {}", status.as_u16());
+    warn!(status = %status, "[chaos] {message}");
+    record.error_body = Some(message.clone());
+    completion.run(&record, &[]);
+    state.metrics.record(record);
+    let mut response = json_response(status, &translate::error_json(status.as_u16(), &message));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, HeaderValue::from_static("1"));
+    }
+    response
+}
 
-    let input_tokens = parse_token_header(upstream_response.headers(), "x-usage-input-tokens")
-        .unwrap_or((body_len / 4) as u64);
-    let output_tokens =
-        parse_token_header(upstream_response.headers(), "x-usage-output-tokens").unwrap_or(0);
+fn json_response(status: StatusCode, body: &serde_json::Value) -> Response {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// The error a client gets for a virtual key the store turned away. Over
+/// quota, it also says which quota and when it resets.
+fn denied_response(denied: &keys::Denied) -> Response {
+    let mut body = translate::error_json(denied.status().as_u16(), &denied.to_string());
+    let keys::Denied::OverQuota {
+        name,
+        period,
+        limit,
+        resets,
+    } = denied
+    else {
+        return json_response(denied.status(), &body);
+    };
+    body["quota"] = serde_json::json!({
+        "client": name,
+        "period": period,
+        "limit_tokens": limit,
+        "resets_at": resets,
+    });
+    let mut response = json_response(denied.status(), &body);
+    let wait = (*resets - chrono::Utc::now()).num_seconds().max(1);
+    if let Ok(value) = HeaderValue::from_str(&wait.to_string()) {
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, value);
+    }
+    response
+}
+
+/// Builds the request a provider with its own API gets for a Messages
+/// request, returning it with its URL and body size. `bearer` is the
+/// provider's token from [`provider_token`].
+async fn translated_request(
+    state: &AppState,
+    original_headers: &HeaderMap,
+    route: &ResolvedRoute,
+    translation: Translation,
+    body: &serde_json::Value,
+    model: &str,
+    bearer: Option<&str>,
+) -> Result<(reqwest::RequestBuilder, String, usize), CroxyError> {
+    let upstream_model = route.model_rewrite.as_deref().unwrap_or(model);
+    let (api_version, defaults) = state
+        .router()
+        .provider(&route.provider_name)
+        .map(|p| (p.api_version.clone(), p.default_headers.clone()))
+        .unwrap_or_default();
+    let api_key = provider_api_key(state, route).await?;
+    let upstream = translation.request(
+        &route.provider_url,
+        api_key,
+        api_version.as_deref(),
+        body,
+        upstream_model,
+    );
+    let upstream_body = serde_json::to_vec(&upstream.body)
+        .map(Bytes::from)
+        .map_err(|e| CroxyError::Internal(format!("failed to serialize body: {e}")))?;
+    let upstream_len = upstream_body.len();
+
+    let mut headers =
+        build_forwarding_headers(original_headers, route, api_key, &defaults, upstream_len);
+    if !translation.forwards_auth() {
+        headers.remove(http::header::AUTHORIZATION);
+        headers.remove("x-api-key");
+    }
+    for (name, value) in &upstream.headers {
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| CroxyError::Internal(format!("invalid {name} header value")))?;
+        headers.insert(*name, value);
+    }
+    if let Some(token) = bearer {
+        use_bearer(&mut headers, token)?;
+    }
+    debug!(url = %upstream.url, format = %route.api_format, "forwarding translated request");
+    log_outgoing_headers(&headers);
+
+    let request = state
+        .client_for(&route.provider_name)
+        .post(&upstream.url)
+        .query(&upstream.query)
+        .headers(headers)
+        .body(upstream_body);
+    Ok((request, upstream.url, upstream_len))
+}
+
+/// Forwards a Messages request to a provider with its own API, translating
+/// the request, the response, and streamed events.
+async fn forward_translated(
+    state: &Arc<AppState>,
+    original_headers: &HeaderMap,
+    route: &ResolvedRoute,
+    translation: Translation,
+    body_json: Option<serde_json::Value>,
+    model: &str,
+    (request_id, start, wallclock, redactions, access, chaos, mut timings, completion): (
+        &str,
+        Instant,
+        chrono::DateTime<Utc>,
+        usize,
+        &AccessEntry,
+        Option<String>,
+        Timings,
+        Completion,
+    ),
+) -> Result<Response, CroxyError> {
+    let body = body_json.ok_or_else(|| CroxyError::Request("missing request body".to_string()))?;
+    let streaming = body["stream"].as_bool().unwrap_or(false);
+    let bearer = provider_token(state, route).await?;
+    let (request, url, upstream_len) = translated_request(
+        state,
+        original_headers,
+        route,
+        translation,
+        &body,
+        model,
+        bearer.as_deref(),
+    )
+    .await?;
+    let estimated_input_tokens = (upstream_len / 4) as u64;
+    let near_limit = near_context_limit(
+        &state.router(),
+        route,
+        route.model_rewrite.as_deref().unwrap_or(model),
+        upstream_len,
+        body["max_tokens"].as_u64(),
+    );
 
-    let response_headers = filter_response_headers(upstream_response.headers());
+    // The error's URL would include any key in the query
+    let sent = Instant::now();
+    let (sent_response, connect) = clients::timing_connect(request.send()).await;
+    let mut upstream_response = sent_response.map_err(|e| unreachable(e.without_url()))?;
+    timings.connect_ms = connect.map(|connect| connect.as_millis() as u64);
+    timings.ttfb_ms = Some(sent.elapsed().as_millis() as u64);
 
-    let base_record = RequestRecord {
-        id: 0,
+    let status = StatusCode::from_u16(upstream_response.status().as_u16())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    info!(status = %status, url = %url, "provider responded");
+    note_rate_limits(state, &route.provider_name, &upstream_response);
+
+    let mut record = RequestRecord {
+        request_id: Some(request_id.to_string()),
         timestamp: start,
         wallclock,
-        model: model.clone(),
+        model: model.to_string(),
         provider: route.provider_name.clone(),
         routing_method: route.routing_method,
         status: status.as_u16(),
         duration: start.elapsed(),
-        input_tokens,
-        output_tokens,
-        error_body: None,
+        input_tokens: estimated_input_tokens,
+        redactions,
+        listener: access.listener.clone(),
+        client: access.client.clone(),
+        chaos,
+        bench: access.bench.clone(),
+        near_limit,
+        timings,
+        ..RequestRecord::default()
     };
+    first_byte(state, &record);
 
     if status.as_u16() >= 400 {
-        return Ok(handle_error_response(
-            &mut upstream_response,
-            state.max_body_size,
+        let error_bytes = read_capped_body(&mut upstream_response, state.max_body_size).await;
+        record.error_body = Some(error_summary(status, &error_bytes, &state.scrubber));
+        completion.run(&record, &error_bytes);
+        state.metrics.record(record);
+        return Ok(json_response(
             status,
-            response_headers,
-            base_record,
-            &state.metrics,
-        )
-        .await);
+            &translation.error(status.as_u16(), &error_bytes),
+        ));
     }
 
-    let record_id = state.metrics.record_pending(base_record);
+    if !streaming {
+        let bytes = read_capped_body(&mut upstream_response, state.max_body_size).await;
+        state
+            .metrics
+            .record_body_size(Direction::Response, Some(size_label(route)), bytes.len());
+        let response: serde_json::Value = serde_json::from_slice(&bytes)
+            .map_err(|e| CroxyError::Upstream(format!("invalid response from provider: {e}")))?;
+        let mut message = translation.response(&response, model);
+        if route.repair_tool_calls {
+            count_repairs(&state.metrics, tool_repair::repair_message(&mut message));
+        }
+        record.input_tokens = message["usage"]["input_tokens"]
+            .as_u64()
+            .unwrap_or(record.input_tokens);
+        record.output_tokens = message["usage"]["output_tokens"].as_u64().unwrap_or(0);
+        record.duration = start.elapsed();
+        completion.run(&record, message.to_string().as_bytes());
+        state.metrics.record(record);
+        return Ok(json_response(status, &message));
+    }
+
+    let output_tokens = Arc::new(AtomicU64::new(0));
+    let mut translator = translation.stream(model, output_tokens.clone());
+    let events = upstream_response
+        .bytes_stream()
+        .map_ok(move |chunk| translator.push(&chunk));
+    let events = if route.repair_tool_calls {
+        futures::future::Either::Left(repair_tool_calls(events, true, state.metrics.clone()))
+    } else {
+        futures::future::Either::Right(events)
+    };
 
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        HeaderValue::from_static("no-cache"),
+    );
     Ok(stream_response(
-        upstream_response,
-        status,
-        response_headers,
-        record_id,
+        events,
+        (status, headers),
         output_tokens,
-        start,
-        state.metrics.clone(),
+        state,
+        route.limits,
+        record,
+        completion,
     ))
 }
@@ -0,0 +1,290 @@
+//! Provider rate limits, as reported in response headers: Anthropic's
+//! `anthropic-ratelimit-*` and the `x-ratelimit-*` family used by OpenAI
+//! and compatible APIs. The latest report per provider is kept so the TUI
+//! can show what's left and routing can back off before a 429.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+/// One limited resource: requests, or tokens of some kind.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    pub limit: Option<u64>,
+    pub remaining: u64,
+    /// When the quota refills.
+    pub reset: Option<DateTime<Utc>>,
+}
+
+impl Quota {
+    /// The fraction left, if the limit is known.
+    pub fn fraction_remaining(&self) -> Option<f64> {
+        self.limit
+            .filter(|&limit| limit > 0)
+            .map(|limit| self.remaining as f64 / limit as f64)
+    }
+}
+
+/// The latest rate limits a provider reported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub provider: String,
+    pub updated: DateTime<Utc>,
+    #[serde(default)]
+    pub requests: Option<Quota>,
+    #[serde(default)]
+    pub tokens: Option<Quota>,
+    #[serde(default)]
+    pub input_tokens: Option<Quota>,
+    #[serde(default)]
+    pub output_tokens: Option<Quota>,
+}
+
+impl RateLimit {
+    /// Parses the rate limit headers of a response from `provider`, or
+    /// `None` when it sent none.
+    pub fn from_headers(provider: &str, headers: &HeaderMap) -> Option<Self> {
+        let now = Utc::now();
+        // Anthropic's take precedence; both name quotas the same way
+        let quota = |name: &str| {
+            let get = |name: String| headers.get(name).and_then(|v| v.to_str().ok());
+            let (limit, remaining, reset) =
+                match get(format!("anthropic-ratelimit-{name}-remaining")) {
+                    Some(remaining) => (
+                        get(format!("anthropic-ratelimit-{name}-limit")),
+                        remaining,
+                        get(format!("anthropic-ratelimit-{name}-reset")),
+                    ),
+                    None => (
+                        get(format!("x-ratelimit-limit-{name}")),
+                        get(format!("x-ratelimit-remaining-{name}"))?,
+                        get(format!("x-ratelimit-reset-{name}")),
+                    ),
+                };
+            Some(Quota {
+                limit: limit.and_then(|v| v.trim().parse().ok()),
+                remaining: remaining.trim().parse().ok()?,
+                reset: reset.and_then(|v| parse_reset(v, now)),
+            })
+        };
+        let limit = Self {
+            provider: provider.to_string(),
+            updated: now,
+            requests: quota("requests"),
+            tokens: quota("tokens"),
+            input_tokens: quota("input-tokens"),
+            output_tokens: quota("output-tokens"),
+        };
+        let reported = limit.quotas().next().is_some();
+        reported.then_some(limit)
+    }
+
+    fn quotas(&self) -> impl Iterator<Item = &Quota> {
+        [
+            &self.requests,
+            &self.tokens,
+            &self.input_tokens,
+            &self.output_tokens,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Quotas with less than `reserve` of their limit left that haven't
+    /// reset yet.
+    fn exhausted(&self, reserve: f64, now: DateTime<Utc>) -> impl Iterator<Item = &Quota> {
+        self.quotas().filter(move |quota| {
+            quota.reset.is_none_or(|reset| reset > now)
+                && quota.fraction_remaining().is_some_and(|f| f < reserve)
+        })
+    }
+
+    /// Whether any quota is down to its last `reserve` fraction.
+    pub fn nearly_exhausted(&self, reserve: f64) -> bool {
+        self.exhausted(reserve, Utc::now()).next().is_some()
+    }
+
+    /// How long until every quota under `reserve` has reset. Unknown reset
+    /// times count as none.
+    pub fn time_to_reset(&self, reserve: f64) -> Duration {
+        let now = Utc::now();
+        self.exhausted(reserve, now)
+            .filter_map(|quota| quota.reset)
+            .max()
+            .and_then(|reset| (reset - now).to_std().ok())
+            .unwrap_or_default()
+    }
+}
+
+/// How long a 429 response asks the client to wait: its `retry-after`,
+/// in seconds or as a date, or else until its exhausted quotas reset.
+pub fn retry_wait(headers: &HeaderMap) -> Option<Duration> {
+    let now = Utc::now();
+    let until = |at: DateTime<Utc>| (at - now).to_std().ok().or(Some(Duration::ZERO));
+    if let Some(value) = headers
+        .get(http::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+    {
+        if let Ok(secs) = value.trim().parse::<f64>() {
+            return Duration::try_from_secs_f64(secs).ok();
+        }
+        if let Ok(at) = DateTime::parse_from_rfc2822(value.trim()) {
+            return until(at.with_timezone(&Utc));
+        }
+    }
+    let limit = RateLimit::from_headers("", headers)?;
+    limit
+        .quotas()
+        .filter(|quota| quota.remaining == 0)
+        .filter_map(|quota| quota.reset)
+        .max()
+        .and_then(until)
+}
+
+/// Reset times come as RFC 3339 timestamps (Anthropic), durations such as
+/// `6m0s` or `20ms` (OpenAI), or plain seconds.
+fn parse_reset(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Some(at.with_timezone(&Utc));
+    }
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(now + Duration::try_from_secs_f64(secs).ok()?);
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|&end| end > 0)?;
+        let amount: f64 = rest[..digits].parse().ok()?;
+        let unit_end = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .map_or(rest.len(), |end| digits + end);
+        let unit = match &rest[digits..unit_end] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += Duration::try_from_secs_f64(amount * unit).ok()?;
+        rest = &rest[unit_end..];
+    }
+    Some(now + total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (http::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_anthropic_headers() {
+        let limit = RateLimit::from_headers(
+            "anthropic",
+            &headers(&[
+                ("anthropic-ratelimit-requests-limit", "50"),
+                ("anthropic-ratelimit-requests-remaining", "49"),
+                ("anthropic-ratelimit-requests-reset", "2099-01-01T00:00:30Z"),
+                ("anthropic-ratelimit-input-tokens-limit", "40000"),
+                ("anthropic-ratelimit-input-tokens-remaining", "1000"),
+            ]),
+        )
+        .unwrap();
+        let requests = limit.requests.as_ref().unwrap();
+        assert_eq!((requests.limit, requests.remaining), (Some(50), 49));
+        assert_eq!(
+            requests.reset.unwrap().to_rfc3339(),
+            "2099-01-01T00:00:30+00:00"
+        );
+        assert_eq!(limit.input_tokens.as_ref().unwrap().remaining, 1000);
+        assert_eq!(limit.tokens, None);
+
+        // Input tokens are down to 2.5%
+        assert!(limit.nearly_exhausted(0.1));
+        assert!(!limit.nearly_exhausted(0.02));
+    }
+
+    #[test]
+    fn parses_openai_headers_and_durations() {
+        let limit = RateLimit::from_headers(
+            "openai",
+            &headers(&[
+                ("x-ratelimit-limit-requests", "500"),
+                ("x-ratelimit-remaining-requests", "5"),
+                ("x-ratelimit-reset-requests", "1m30s"),
+                ("x-ratelimit-remaining-tokens", "123"),
+            ]),
+        )
+        .unwrap();
+        let requests = limit.requests.as_ref().unwrap();
+        assert_eq!((requests.limit, requests.remaining), (Some(500), 5));
+        let wait = limit.time_to_reset(0.05);
+        assert!(wait > Duration::from_secs(88) && wait <= Duration::from_secs(90));
+        // Without a limit, a quota can't be judged
+        assert_eq!(limit.tokens.as_ref().unwrap().fraction_remaining(), None);
+
+        assert_eq!(RateLimit::from_headers("a", &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn retry_waits_follow_retry_after_then_resets() {
+        let wait = |pairs| retry_wait(&headers(pairs));
+        assert_eq!(wait(&[("retry-after", "7")]), Some(Duration::from_secs(7)));
+        assert_eq!(
+            wait(&[("retry-after", "Thu, 01 Jan 1970 00:00:00 GMT")]),
+            Some(Duration::ZERO)
+        );
+        let reset = wait(&[
+            ("anthropic-ratelimit-tokens-remaining", "0"),
+            ("anthropic-ratelimit-tokens-reset", "2099-01-01T00:00:00Z"),
+            ("anthropic-ratelimit-requests-remaining", "10"),
+        ]);
+        assert!(reset.unwrap() > Duration::from_secs(86400));
+        assert_eq!(
+            wait(&[("anthropic-ratelimit-requests-remaining", "10")]),
+            None
+        );
+        assert_eq!(wait(&[]), None);
+    }
+
+    #[test]
+    fn reset_times_in_several_formats() {
+        let now = Utc::now();
+        let after = |value| parse_reset(value, now).map(|at| (at - now).num_milliseconds());
+        assert_eq!(after("6m0s"), Some(360_000));
+        assert_eq!(after("20ms"), Some(20));
+        assert_eq!(after("1.5s"), Some(1500));
+        assert_eq!(after("2"), Some(2000));
+        assert_eq!(after("soon"), None);
+        assert_eq!(after("5x"), None);
+    }
+
+    #[test]
+    fn quotas_past_their_reset_are_not_exhausted() {
+        let quota = Quota {
+            limit: Some(10),
+            remaining: 0,
+            reset: Some(Utc::now() - chrono::Duration::seconds(1)),
+        };
+        let limit = RateLimit {
+            provider: "a".to_string(),
+            updated: Utc::now(),
+            requests: Some(quota),
+            tokens: None,
+            input_tokens: None,
+            output_tokens: None,
+        };
+        assert!(!limit.nearly_exhausted(0.5));
+        assert_eq!(limit.time_to_reset(0.5), Duration::ZERO);
+    }
+}
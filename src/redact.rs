@@ -0,0 +1,105 @@
+//! Redaction of outbound request bodies, for the `redact` middleware.
+//! Text such as internal hostnames or API keys is replaced before a request
+//! reaches a provider, so it never leaves the machine for a cloud backend.
+
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+/// Replaces text matching a `redact` middleware's pattern or words.
+pub struct Redaction {
+    regex: Regex,
+    replacement: String,
+}
+
+impl Redaction {
+    /// Matches `pattern`, or else `words` as literals ignoring case.
+    pub fn new(pattern: Option<&str>, words: &[String], replacement: &str) -> Result<Self, String> {
+        let regex = match (pattern, words.is_empty()) {
+            (Some(pattern), true) => Regex::new(pattern)
+                .map_err(|e| format!("invalid redact pattern '{pattern}': {e}"))?,
+            (None, false) => {
+                let words: Vec<String> = words.iter().map(|w| regex::escape(w)).collect();
+                RegexBuilder::new(&words.join("|"))
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("invalid redact words: {e}"))?
+            }
+            _ => return Err("redact needs either pattern or words".to_string()),
+        };
+        Ok(Self {
+            regex,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// Replaces matches in every string of `body` except the model name.
+    /// Returns how many were replaced.
+    pub fn apply(&self, body: &mut Value) -> usize {
+        let model = body.get_mut("model").map(Value::take);
+        let count = self.redact_value(body);
+        if let Some(model) = model {
+            body["model"] = model;
+        }
+        count
+    }
+
+    fn redact_value(&self, value: &mut Value) -> usize {
+        match value {
+            Value::String(text) => {
+                let matches = self.regex.find_iter(text).count();
+                if matches > 0 {
+                    *text = self
+                        .regex
+                        .replace_all(text, self.replacement.as_str())
+                        .into_owned();
+                }
+                matches
+            }
+            Value::Array(items) => items.iter_mut().map(|v| self.redact_value(v)).sum(),
+            Value::Object(fields) => fields.values_mut().map(|v| self.redact_value(v)).sum(),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn redacts_nested_strings_but_not_the_model() {
+        let keys = Redaction::new(Some(r"sk-[A-Za-z0-9]{8,}"), &[], "[REDACTED]").unwrap();
+        let hosts = Redaction::new(None, &words(&["build.corp.internal"]), "[host]").unwrap();
+        let mut body = json!({
+            "model": "sk-model-name-123",
+            "system": "deploy to BUILD.corp.internal",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "key sk-abcdef123456 and sk-ghijkl789012"}
+            ]}]
+        });
+
+        assert_eq!(keys.apply(&mut body), 2);
+        assert_eq!(hosts.apply(&mut body), 1);
+        assert_eq!(body["model"], "sk-model-name-123");
+        assert_eq!(body["system"], "deploy to [host]");
+        assert_eq!(
+            body["messages"][0]["content"][0]["text"],
+            "key [REDACTED] and [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn needs_a_pattern_or_words() {
+        assert!(Redaction::new(None, &[], "x").is_err());
+        assert!(Redaction::new(Some("a"), &words(&["b"]), "x").is_err());
+        assert!(Redaction::new(Some("("), &[], "x").is_err());
+        let mut body = json!("A.B(C");
+        let redaction = Redaction::new(None, &words(&["a.b(c"]), "x").unwrap();
+        assert_eq!(redaction.apply(&mut body), 1);
+    }
+}
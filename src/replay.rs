@@ -0,0 +1,154 @@
+//! `croxy replay`: sends a captured request through the proxy again and
+//! compares how it went with the original.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use serde_json::Value;
+
+use crate::capture::{Capture, Outcome};
+use crate::compare::ResponseSummary;
+use crate::proxy::{AppState, handle_request};
+
+/// Replays `capture` through `state`'s router, with the model replaced by
+/// `model` if given. `api_key` stands in for the client's credentials,
+/// which are not captured.
+pub async fn replay(
+    state: Arc<AppState>,
+    capture: &Capture,
+    model: Option<&str>,
+    api_key: Option<&str>,
+) -> Result<Outcome, String> {
+    let mut body = capture.body.clone();
+    if let (Some(model), Some(body)) = (model, body.as_mut()) {
+        body["model"] = Value::String(model.to_string());
+    }
+    let mut request = Request::builder()
+        .method(capture.method.as_str())
+        .uri(&capture.path);
+    for (name, value) in &capture.headers {
+        request = request.header(name, value);
+    }
+    if let Some(api_key) = api_key {
+        request = request.header("x-api-key", api_key);
+    }
+    let body = match body {
+        Some(ref json) => {
+            request = request.header(http::header::CONTENT_TYPE, "application/json");
+            Body::from(json.to_string())
+        }
+        None => Body::empty(),
+    };
+    let request = request
+        .body(body)
+        .map_err(|e| format!("invalid capture {}: {e}", capture.id))?;
+
+    let start = Instant::now();
+    let response = match handle_request(State(state.clone()), request).await {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(format!("replay failed: {} {e}", e.status()));
+        }
+    };
+    let status = response.status().as_u16();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| format!("replay failed reading the response: {e}"))?;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let summary = ResponseSummary::read(&bytes);
+    let provider = state
+        .metrics
+        .retained()
+        .last()
+        .map(|r| r.provider.clone())
+        .unwrap_or_default();
+    Ok(Outcome {
+        provider,
+        status,
+        duration_ms,
+        input_tokens: summary.input_tokens,
+        output_tokens: summary.output_tokens,
+    })
+}
+
+/// A side-by-side comparison of the original and the replay.
+pub fn diff(original: Option<&Outcome>, replay: &Outcome) -> String {
+    let column = |f: &dyn Fn(&Outcome) -> String| original.map_or("-".to_string(), f);
+    let change = |before: Option<u64>, after: u64| match before {
+        Some(before) if before > 0 => {
+            let percent = (after as f64 - before as f64) / before as f64 * 100.0;
+            format!(" ({percent:+.0}%)")
+        }
+        _ => String::new(),
+    };
+    let rows = [
+        (
+            "provider",
+            column(&|o| o.provider.clone()),
+            replay.provider.clone(),
+        ),
+        (
+            "status",
+            column(&|o| o.status.to_string()),
+            replay.status.to_string(),
+        ),
+        (
+            "latency",
+            column(&|o| format!("{}ms", o.duration_ms)),
+            format!(
+                "{}ms{}",
+                replay.duration_ms,
+                change(original.map(|o| o.duration_ms), replay.duration_ms)
+            ),
+        ),
+        (
+            "input tokens",
+            column(&|o| o.input_tokens.to_string()),
+            replay.input_tokens.to_string(),
+        ),
+        (
+            "output tokens",
+            column(&|o| o.output_tokens.to_string()),
+            format!(
+                "{}{}",
+                replay.output_tokens,
+                change(original.map(|o| o.output_tokens), replay.output_tokens)
+            ),
+        ),
+    ];
+    let mut out = format!("{:<15}{:<16}{}\n", "", "original", "replay");
+    for (label, before, after) in rows {
+        out.push_str(&format!("{label:<15}{before:<16}{after}\n"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(provider: &str, duration_ms: u64, output_tokens: u64) -> Outcome {
+        Outcome {
+            provider: provider.to_string(),
+            status: 200,
+            duration_ms,
+            input_tokens: 50,
+            output_tokens,
+        }
+    }
+
+    #[test]
+    fn diff_shows_relative_change() {
+        let table = diff(
+            Some(&outcome("anthropic", 1000, 40)),
+            &outcome("ollama", 2500, 30),
+        );
+        assert!(table.contains("provider       anthropic       ollama"));
+        assert!(table.contains("latency        1000ms          2500ms (+150%)"));
+        assert!(table.contains("output tokens  40              30 (-25%)"));
+        let table = diff(None, &outcome("ollama", 2500, 30));
+        assert!(table.contains("status         -               200"));
+    }
+}
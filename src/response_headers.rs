@@ -0,0 +1,153 @@
+//! `[response_headers]`: which of a provider's response headers reach the
+//! client, and headers croxy adds to every response. Names are matched
+//! without regard to case, and a trailing `*` matches any name starting
+//! with what comes before it, as in `anthropic-ratelimit-*`.
+//!
+//! Hop-by-hop headers and `content-encoding` are never forwarded, as croxy
+//! relays the body decoded, and `content-type` and `content-length` always
+//! are, as clients can't read a body without them. Headers croxy sets
+//! itself, such as those in `add`, are never filtered.
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::config::ResponseHeadersConfig;
+use crate::proxy::is_hop_by_hop;
+
+/// Headers kept whatever the policy says.
+const ALWAYS_KEPT: [HeaderName; 2] = [http::header::CONTENT_TYPE, http::header::CONTENT_LENGTH];
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    add: HeaderMap,
+}
+
+impl Policy {
+    pub fn from_config(config: &ResponseHeadersConfig) -> Result<Self, String> {
+        let mut add = HeaderMap::new();
+        for (name, value) in &config.add {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("add: invalid header name '{name}'"))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("add: invalid value for '{name}'"))?;
+            add.insert(name, value);
+        }
+        let lowercase = |names: &[String]| names.iter().map(|n| n.to_ascii_lowercase()).collect();
+        Ok(Self {
+            allow: lowercase(&config.allow),
+            deny: lowercase(&config.deny),
+            add,
+        })
+    }
+
+    /// Whether a provider's response header called `name` is forwarded.
+    pub fn forwards(&self, name: &HeaderName) -> bool {
+        if is_hop_by_hop(name) || name == http::header::CONTENT_ENCODING {
+            return false;
+        }
+        if ALWAYS_KEPT.contains(name) {
+            return true;
+        }
+        let name = name.as_str();
+        (self.allow.is_empty() || self.allow.iter().any(|p| matches(p, name)))
+            && !self.deny.iter().any(|p| matches(p, name))
+    }
+
+    /// The headers of a provider's response that are forwarded.
+    pub fn filter(&self, upstream: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in upstream {
+            if self.forwards(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        headers
+    }
+
+    /// Sets the `add` headers on a response.
+    pub fn add_to(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.add {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Whether a lowercase header `name` matches `pattern`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn upstream() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("content-type", "text/event-stream"),
+            ("content-encoding", "gzip"),
+            ("connection", "keep-alive"),
+            ("request-id", "req_1"),
+            ("cf-ray", "8a1b"),
+            ("anthropic-ratelimit-requests-remaining", "49"),
+            ("server", "cloudflare"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn names(headers: &HeaderMap) -> Vec<&str> {
+        let mut names: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn without_a_policy_all_but_hop_by_hop_and_encoding_are_forwarded() {
+        let policy = Policy::from_config(&ResponseHeadersConfig::default()).unwrap();
+        assert_eq!(
+            names(&policy.filter(&upstream())),
+            [
+                "anthropic-ratelimit-requests-remaining",
+                "cf-ray",
+                "content-type",
+                "request-id",
+                "server"
+            ]
+        );
+    }
+
+    #[test]
+    fn deny_outranks_allow_and_the_body_headers_are_kept() {
+        let policy = Policy::from_config(&ResponseHeadersConfig {
+            allow: vec!["Request-Id".to_string(), "anthropic-*".to_string()],
+            deny: vec![
+                "anthropic-ratelimit-*".to_string(),
+                "content-type".to_string(),
+            ],
+            add: HashMap::from([("x-croxy-gateway".to_string(), "team".to_string())]),
+        })
+        .unwrap();
+        let mut headers = policy.filter(&upstream());
+        assert_eq!(names(&headers), ["content-type", "request-id"]);
+
+        policy.add_to(&mut headers);
+        assert_eq!(headers["x-croxy-gateway"], "team");
+
+        let invalid = ResponseHeadersConfig {
+            add: HashMap::from([("bad header".to_string(), "x".to_string())]),
+            ..ResponseHeadersConfig::default()
+        };
+        assert_eq!(
+            Policy::from_config(&invalid).unwrap_err(),
+            "add: invalid header name 'bad header'"
+        );
+    }
+}
@@ -1,19 +1,198 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use regex::Regex;
+use ring::rand::SystemRandom;
+use serde::{Deserialize, Serialize};
 use tracing::warn;
 
-use crate::config::{AutoRouterConfig, Config};
-use crate::metrics::RoutingMethod;
+use crate::balance;
+use crate::config::{
+    ApiFormat, AutoRouterConfig, Config, ContextGuard, Dedup, ProviderConfig, RouteConfig,
+};
+use crate::context_windows::{self, Limits};
+use crate::error::CroxyError;
+use crate::metrics::{MetricsStore, RoutingMethod};
+use crate::secrets::ApiKey;
 
+#[derive(Clone)]
 pub struct ResolvedRoute {
     pub provider_name: String,
     pub provider_url: String,
     pub model_rewrite: Option<String>,
     pub strip_auth: bool,
-    pub api_key: Option<String>,
+    pub api_key: ApiKey,
     pub stub_count_tokens: bool,
+    pub api_format: ApiFormat,
     pub routing_method: RoutingMethod,
+    /// `name` of the route that matched, if it has one.
+    pub route_name: Option<String>,
+    /// Group the route sends to, until [`Router::balance`] picks one of
+    /// its members. The provider fields are its first member's until then.
+    pub group: Option<String>,
+    pub limits: ResponseLimits,
+    pub sampling: Sampling,
+    pub context_guard: Option<ContextGuard>,
+    /// What happens to a request identical to one still in flight.
+    pub dedup: Option<Dedup>,
+    /// Whether responses are saved to the archive.
+    pub archive: bool,
+    /// Whether malformed tool calls in responses are repaired.
+    pub repair_tool_calls: bool,
+}
+
+/// How much a route's responses may return, and for how long, before
+/// croxy cuts them off.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResponseLimits {
+    pub max_bytes: Option<u64>,
+    /// Counted from when the request arrived.
+    pub max_duration: Option<Duration>,
+    /// Bytes after which the connection is dropped without warning, set by
+    /// `[chaos]` to mimic a provider failing mid-stream.
+    pub drop_after: Option<u64>,
+    /// `server.request_timeout_ms`, also counted from when the request
+    /// arrived.
+    pub request_timeout: Option<Duration>,
+}
+
+impl ResponseLimits {
+    fn new(max_bytes: Option<u64>, max_secs: Option<u64>) -> Self {
+        Self {
+            max_bytes,
+            max_duration: max_secs.map(Duration::from_secs),
+            drop_after: None,
+            request_timeout: None,
+        }
+    }
+}
+
+/// Sampling parameters a route sets on every request, whatever the client
+/// asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u64>,
+    pub max_tokens: Option<u64>,
+}
+
+impl Sampling {
+    fn new(route: &RouteConfig) -> Self {
+        Self {
+            temperature: route.temperature,
+            top_p: route.top_p,
+            top_k: route.top_k,
+            max_tokens: route.max_tokens,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets each overridden parameter on a Messages request body.
+    pub fn apply(&self, body: &mut serde_json::Value) {
+        let Some(body) = body.as_object_mut() else {
+            return;
+        };
+        let overrides = [
+            ("temperature", self.temperature.map(serde_json::Value::from)),
+            ("top_p", self.top_p.map(serde_json::Value::from)),
+            ("top_k", self.top_k.map(serde_json::Value::from)),
+            ("max_tokens", self.max_tokens.map(serde_json::Value::from)),
+        ];
+        for (name, value) in overrides {
+            if let Some(value) = value {
+                body.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+/// The kind of API call a request is, going by its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Messages,
+    CountTokens,
+    Models,
+    Other,
+}
+
+impl Endpoint {
+    pub fn of(path: &str) -> Self {
+        match path {
+            "/v1/messages" | "/v1/complete" => Endpoint::Messages,
+            "/v1/messages/count_tokens" => Endpoint::CountTokens,
+            _ if path == "/v1/models" || path.starts_with("/v1/models/") => Endpoint::Models,
+            _ => Endpoint::Other,
+        }
+    }
+
+    /// The name of the endpoint's `[default.*]` table.
+    pub fn name(self) -> &'static str {
+        match self {
+            Endpoint::Messages => "messages",
+            Endpoint::CountTokens => "count_tokens",
+            Endpoint::Models => "models",
+            Endpoint::Other => "other",
+        }
+    }
+
+    /// Whether the call has a model generate a response, and so is worth
+    /// asking the auto-router about.
+    pub fn generates(self) -> bool {
+        self == Endpoint::Messages
+    }
+}
+
+/// What routes can match on besides the model, read from the request
+/// body. Requests without a body match only routes that ask for none.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestFields<'a> {
+    /// `metadata.user_id`, which Claude clients set per user or session.
+    pub user_id: Option<&'a str>,
+}
+
+impl<'a> RequestFields<'a> {
+    pub fn of(body: Option<&'a serde_json::Value>) -> Self {
+        Self {
+            user_id: body
+                .and_then(|body| body.pointer("/metadata/user_id"))
+                .and_then(serde_json::Value::as_str),
+        }
+    }
+}
+
+/// Conversations whose auto-router classification is remembered.
+const MAX_CLASSIFICATIONS: usize = 4096;
+
+/// A pattern route as shown to attached viewers, indexed by its position
+/// among the pattern routes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteInfo {
+    pub index: usize,
+    pub pattern: String,
+    pub provider: String,
+    pub model: Option<String>,
+    pub enabled: bool,
+}
+
+/// A pin as shown to attached viewers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PinInfo {
+    pub pattern: String,
+    pub provider: String,
+    /// Whether the pin came from `[[pins]]` rather than a command.
+    pub saved: bool,
+}
+
+struct Pin {
+    pattern: Regex,
+    route: ResolvedRoute,
+    saved: bool,
 }
 
 pub struct RouteCandidate {
@@ -23,22 +202,41 @@ pub struct RouteCandidate {
 
 struct CompiledRoute {
     pattern: Regex,
+    /// Matched against [`RequestFields::user_id`] when set.
+    user_id: Option<Regex>,
+    name: Option<String>,
+    group: Option<String>,
     provider_name: String,
     provider_url: String,
     model_rewrite: Option<String>,
     strip_auth: bool,
-    api_key: Option<String>,
+    api_key: ApiKey,
     stub_count_tokens: bool,
+    api_format: ApiFormat,
+    limits: ResponseLimits,
+    sampling: Sampling,
+    context_guard: Option<ContextGuard>,
+    dedup: Option<Dedup>,
+    archive: bool,
+    repair_tool_calls: bool,
 }
 
 struct AutoRouteEntry {
     name: String,
+    group: Option<String>,
     provider_name: String,
     provider_url: String,
     model_rewrite: Option<String>,
     strip_auth: bool,
-    api_key: Option<String>,
+    api_key: ApiKey,
     stub_count_tokens: bool,
+    api_format: ApiFormat,
+    limits: ResponseLimits,
+    sampling: Sampling,
+    context_guard: Option<ContextGuard>,
+    dedup: Option<Dedup>,
+    archive: bool,
+    repair_tool_calls: bool,
 }
 
 pub struct Router {
@@ -47,82 +245,214 @@ pub struct Router {
     auto_candidates: Vec<RouteCandidate>,
     auto_router_config: Option<AutoRouterConfig>,
     default: ResolvedRoute,
+    /// Defaults for particular endpoints, in place of `default`.
+    endpoint_defaults: HashMap<Endpoint, ResolvedRoute>,
+    providers: HashMap<String, ProviderConfig>,
+    /// Pattern routes switched off at runtime. Overrides live only as long
+    /// as this router, so a config reload clears them.
+    disabled: RwLock<HashSet<usize>>,
+    /// Provider all traffic is sent to, set at runtime.
+    forced: RwLock<Option<ResolvedRoute>>,
+    /// Model patterns sent to a provider whatever the routes say, checked
+    /// in order before `forced`.
+    pins: RwLock<Vec<Pin>>,
+    /// `[[clients.NAME.pins]]` by client name, checked before `pins`.
+    client_pins: HashMap<String, Vec<Pin>>,
+    /// Provider and model that summarize for `context_guard = "summarize"`.
+    summary: Option<(String, String)>,
+    /// The auto route each recent conversation was classified to, with
+    /// when, keyed by [`conversation_key`].
+    classifications: Mutex<HashMap<u64, (String, Instant)>>,
+    /// Members of the groups routes send to.
+    groups: HashMap<String, Vec<String>>,
+    /// API keys of group members, which need not have routes of their own.
+    member_keys: HashMap<String, ApiKey>,
+    /// Context windows and output limits by model.
+    model_limits: context_windows::Registry,
+    random: SystemRandom,
 }
 
 impl Router {
-    pub fn from_config(config: &Config) -> Result<Self, String> {
-        let default_provider = config
-            .providers
-            .get(&config.default.provider)
-            .ok_or_else(|| {
-                format!(
-                    "default provider '{}' not found in providers",
-                    config.default.provider
-                )
-            })?;
+    pub fn from_config(config: &Config) -> Result<Self, CroxyError> {
+        // Secrets are only read for providers that are actually routed to,
+        // and each at most once.
+        let mut keys = HashMap::new();
 
-        let default = ResolvedRoute {
-            provider_name: config.default.provider.clone(),
-            provider_url: default_provider.url.clone(),
-            model_rewrite: None,
-            strip_auth: default_provider.strip_auth,
-            api_key: default_provider.api_key.clone(),
-            stub_count_tokens: default_provider.stub_count_tokens,
-            routing_method: RoutingMethod::Default,
+        let limits = ResponseLimits::new(
+            config.default.max_response_bytes,
+            config.default.max_stream_secs,
+        );
+        let mut default_to = |name: &str, provider: &str| {
+            let (provider, provider_config) =
+                config.providers.get_key_value(provider).ok_or_else(|| {
+                    CroxyError::Config(format!(
+                        "{name} provider '{provider}' not found in providers"
+                    ))
+                })?;
+            Ok::<_, CroxyError>(ResolvedRoute {
+                provider_name: provider.clone(),
+                provider_url: provider_config.url.clone(),
+                model_rewrite: None,
+                strip_auth: provider_config.strip_auth,
+                api_key: cached_api_key(&mut keys, provider, provider_config)
+                    .map_err(CroxyError::Config)?,
+                stub_count_tokens: provider_config.stub_count_tokens,
+                api_format: provider_config.api_format,
+                routing_method: RoutingMethod::Default,
+                route_name: None,
+                group: None,
+                limits,
+                sampling: Sampling::default(),
+                context_guard: None,
+                dedup: None,
+                archive: false,
+                repair_tool_calls: false,
+            })
         };
+        let mut default = default_to("default", &config.default.provider)?;
+        default.model_rewrite = config.default.model.clone();
+        if let Some(strip_auth) = config.default.strip_auth {
+            default.strip_auth = strip_auth;
+        }
+        if let Some(ref api_key) = config.default.api_key {
+            default.api_key = ApiKey::given(api_key);
+        }
+        let mut endpoint_defaults = HashMap::new();
+        for (endpoint, endpoint_default) in [
+            (Endpoint::Messages, &config.default.messages),
+            (Endpoint::CountTokens, &config.default.count_tokens),
+            (Endpoint::Models, &config.default.models),
+            (Endpoint::Other, &config.default.other),
+        ] {
+            if let Some(endpoint_default) = endpoint_default {
+                let route = default_to(
+                    &format!("default.{}", endpoint.name()),
+                    &endpoint_default.provider,
+                )?;
+                endpoint_defaults.insert(endpoint, route);
+            }
+        }
 
         let mut routes = Vec::new();
         let mut auto_routes = Vec::new();
         let mut auto_candidates = Vec::new();
         let mut seen_names = HashSet::new();
+        let mut groups = HashMap::new();
+        let mut member_keys = HashMap::new();
 
         for route in &config.routes {
             if route.pattern.is_none() && route.description.is_none() {
-                return Err(format!(
+                return Err(CroxyError::Config(format!(
                     "route for provider '{}' has neither pattern nor description",
                     route.provider
-                ));
+                )));
+            }
+
+            // Auto routes are picked by the classifier, not by matching
+            if route.user_id.is_some() && route.pattern.is_none() {
+                return Err(CroxyError::Config(format!(
+                    "route for provider '{}' has user_id but no pattern",
+                    route.provider
+                )));
             }
 
             if route.description.is_some() && route.name.is_none() {
-                return Err(format!(
+                return Err(CroxyError::Config(format!(
                     "route for provider '{}' has description but no name",
                     route.provider
-                ));
+                )));
             }
 
-            let provider = config.providers.get(&route.provider).ok_or_else(|| {
-                format!("route provider '{}' not found in providers", route.provider)
+            // A group's routes start out at its first member
+            let group = config.groups.get(&route.provider);
+            let provider_name = match group {
+                Some(group) => group.members.first().ok_or_else(|| {
+                    CroxyError::Config(format!("group '{}' has no members", route.provider))
+                })?,
+                None => &route.provider,
+            };
+            for member in group.into_iter().flat_map(|g| &g.members) {
+                let provider = config.providers.get(member).ok_or_else(|| {
+                    CroxyError::Config(format!(
+                        "group '{}' member '{member}' not found in providers",
+                        route.provider
+                    ))
+                })?;
+                let key =
+                    cached_api_key(&mut keys, member, provider).map_err(CroxyError::Config)?;
+                member_keys.insert(member.clone(), key);
+            }
+            if let Some(group) = group {
+                groups.insert(route.provider.clone(), group.members.clone());
+            }
+            let group = group.map(|_| route.provider.clone());
+            let provider = config.providers.get(provider_name).ok_or_else(|| {
+                CroxyError::Config(format!(
+                    "route provider '{}' not found in providers",
+                    route.provider
+                ))
             })?;
+            let api_key =
+                cached_api_key(&mut keys, provider_name, provider).map_err(CroxyError::Config)?;
+            let limits = ResponseLimits::new(route.max_response_bytes, route.max_stream_secs);
+            let sampling = Sampling::new(route);
 
             if let Some(ref pattern_str) = route.pattern {
-                let pattern = Regex::new(pattern_str)
-                    .map_err(|e| format!("invalid regex '{}': {}", pattern_str, e))?;
+                let pattern = Regex::new(pattern_str).map_err(|e| {
+                    CroxyError::Config(format!("invalid regex '{}': {}", pattern_str, e))
+                })?;
+                let user_id = route
+                    .user_id
+                    .as_deref()
+                    .map(|user_id| {
+                        Regex::new(user_id).map_err(|e| {
+                            CroxyError::Config(format!("invalid user_id regex '{user_id}': {e}"))
+                        })
+                    })
+                    .transpose()?;
 
                 routes.push(CompiledRoute {
                     pattern,
-                    provider_name: route.provider.clone(),
+                    user_id,
+                    name: route.name.clone(),
+                    group: group.clone(),
+                    provider_name: provider_name.clone(),
                     provider_url: provider.url.clone(),
                     model_rewrite: route.model.clone(),
                     strip_auth: provider.strip_auth,
-                    api_key: provider.api_key.clone(),
+                    api_key: api_key.clone(),
                     stub_count_tokens: provider.stub_count_tokens,
+                    api_format: provider.api_format,
+                    limits,
+                    sampling,
+                    context_guard: route.context_guard,
+                    dedup: route.dedup,
+                    archive: route.archive,
+                    repair_tool_calls: route.repair_tool_calls,
                 });
             }
 
             if let (Some(name), Some(description)) = (&route.name, &route.description) {
                 if !seen_names.insert(name.clone()) {
-                    return Err(format!("duplicate route name '{name}'"));
+                    return Err(CroxyError::Config(format!("duplicate route name '{name}'")));
                 }
 
                 auto_routes.push(AutoRouteEntry {
                     name: name.clone(),
-                    provider_name: route.provider.clone(),
+                    group,
+                    provider_name: provider_name.clone(),
                     provider_url: provider.url.clone(),
                     model_rewrite: route.model.clone(),
                     strip_auth: provider.strip_auth,
-                    api_key: provider.api_key.clone(),
+                    api_key: api_key.clone(),
                     stub_count_tokens: provider.stub_count_tokens,
+                    api_format: provider.api_format,
+                    limits,
+                    sampling,
+                    context_guard: route.context_guard,
+                    dedup: route.dedup,
+                    archive: route.archive,
+                    repair_tool_calls: route.repair_tool_calls,
                 });
 
                 auto_candidates.push(RouteCandidate {
@@ -134,7 +464,9 @@ impl Router {
 
         let auto_router_config = if config.auto_router.enabled {
             if config.auto_router.url.is_empty() {
-                return Err("auto_router.enabled is true but url is empty".to_string());
+                return Err(CroxyError::Config(
+                    "auto_router.enabled is true but url is empty".to_string(),
+                ));
             }
             if auto_candidates.is_empty() {
                 warn!("auto_router is enabled but no routes have descriptions");
@@ -144,80 +476,629 @@ impl Router {
                 url: config.auto_router.url.clone(),
                 model: config.auto_router.model.clone(),
                 timeout_ms: config.auto_router.timeout_ms,
+                local: None,
             })
         } else {
             None
         };
 
-        Ok(Router {
+        let mut router = Router {
             routes,
             auto_routes,
             auto_candidates,
             auto_router_config,
             default,
-        })
+            endpoint_defaults,
+            providers: config.providers.clone(),
+            disabled: RwLock::new(HashSet::new()),
+            forced: RwLock::new(None),
+            pins: RwLock::new(Vec::new()),
+            client_pins: HashMap::new(),
+            summary: config
+                .context_guard
+                .summary_provider
+                .clone()
+                .zip(config.context_guard.summary_model.clone()),
+            classifications: Mutex::new(HashMap::new()),
+            groups,
+            member_keys,
+            model_limits: context_windows::Registry::from_config(config),
+            random: SystemRandom::new(),
+        };
+        for pin in &config.pins {
+            router
+                .add_pin(&pin.pattern, &pin.provider, true)
+                .map_err(|e| CroxyError::Config(format!("pins: {e}")))?;
+        }
+        for (client, client_config) in &config.clients {
+            let pins = client_config
+                .pins
+                .iter()
+                .map(|pin| router.compile_pin(&pin.pattern, &pin.provider, true))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| CroxyError::Config(format!("clients.{client}.pins: {e}")))?;
+            router.client_pins.insert(client.clone(), pins);
+        }
+        Ok(router)
     }
 
     pub async fn resolve(
         &self,
         model: &str,
+        endpoint: Endpoint,
+        fields: RequestFields<'_>,
         messages: Option<&[serde_json::Value]>,
         client: &reqwest::Client,
     ) -> ResolvedRoute {
-        if model == "auto" {
+        if model == "auto" && !self.overridden(model) {
             if let Some(ref config) = self.auto_router_config
                 && let Some(messages) = messages
                 && !self.auto_candidates.is_empty()
-                && let Some(name) =
+            {
+                let key = conversation_key(messages);
+                if self.skips_classification(endpoint, Some(messages)) {
+                    let previous = key.and_then(|key| self.classification(key));
+                    return previous
+                        .and_then(|name| self.auto_route(&name))
+                        .unwrap_or_else(|| self.make_default(endpoint));
+                }
+                if let Some(name) =
                     crate::auto_router::classify(client, config, &self.auto_candidates, messages)
                         .await
-                && let Some(entry) = self.auto_routes.iter().find(|r| r.name == name)
-            {
-                return ResolvedRoute {
-                    provider_name: entry.provider_name.clone(),
-                    provider_url: entry.provider_url.clone(),
-                    model_rewrite: entry.model_rewrite.clone(),
-                    strip_auth: entry.strip_auth,
-                    api_key: entry.api_key.clone(),
-                    stub_count_tokens: entry.stub_count_tokens,
-                    routing_method: RoutingMethod::Auto,
-                };
+                    && let Some(route) = self.auto_route(&name)
+                {
+                    if let Some(key) = key {
+                        self.remember_classification(key, name);
+                    }
+                    return route;
+                }
             }
-            return self.make_default();
+            return self.make_default(endpoint);
         }
 
-        self.resolve_pattern(model)
-    }
-
-    pub fn resolve_pattern(&self, model: &str) -> ResolvedRoute {
-        for route in &self.routes {
-            if route.pattern.is_match(model) {
-                return ResolvedRoute {
-                    provider_name: route.provider_name.clone(),
-                    provider_url: route.provider_url.clone(),
-                    model_rewrite: route.model_rewrite.clone(),
-                    strip_auth: route.strip_auth,
-                    api_key: route.api_key.clone(),
-                    stub_count_tokens: route.stub_count_tokens,
-                    routing_method: RoutingMethod::Pattern,
-                };
+        self.resolve_fields(model, endpoint, fields)
+    }
+
+    /// Whether some route matches on [`RequestFields`], and so needs the
+    /// request's body.
+    pub fn matches_fields(&self) -> bool {
+        self.routes.iter().any(|route| route.user_id.is_some())
+    }
+
+    /// Whether `resolve` would ask the auto-router about `model`, and so
+    /// needs the request's messages.
+    pub fn classifies(&self, model: &str) -> bool {
+        model == "auto"
+            && !self.overridden(model)
+            && self.auto_router_config.is_some()
+            && !self.auto_candidates.is_empty()
+    }
+
+    /// Whether `resolve` would answer a request to `endpoint` without
+    /// asking the auto-router: calls that don't generate anything, and
+    /// turns that only hand back tool results in a conversation that was
+    /// already classified, which reuse the earlier answer.
+    pub fn skips_classification(
+        &self,
+        endpoint: Endpoint,
+        messages: Option<&[serde_json::Value]>,
+    ) -> bool {
+        if !endpoint.generates() {
+            return true;
+        }
+        let Some(messages) = messages else {
+            return false;
+        };
+        messages.last().is_some_and(is_tool_results)
+            && conversation_key(messages).is_some_and(|key| self.classification(key).is_some())
+    }
+
+    fn classification(&self, key: u64) -> Option<String> {
+        let classifications = self
+            .classifications
+            .lock()
+            .expect("classifications lock poisoned");
+        classifications.get(&key).map(|(name, _)| name.clone())
+    }
+
+    fn remember_classification(&self, key: u64, name: String) {
+        let mut classifications = self
+            .classifications
+            .lock()
+            .expect("classifications lock poisoned");
+        if classifications.len() >= MAX_CLASSIFICATIONS && !classifications.contains_key(&key) {
+            let oldest = classifications
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                classifications.remove(&oldest);
             }
         }
+        classifications.insert(key, (name, Instant::now()));
+    }
 
-        self.make_default()
+    /// Whether a pin or a forced provider decides where `model` goes.
+    fn overridden(&self, model: &str) -> bool {
+        self.forced_provider().is_some()
+            || self
+                .pins
+                .read()
+                .expect("routes lock poisoned")
+                .iter()
+                .any(|pin| pin.pattern.is_match(model))
     }
 
-    fn make_default(&self) -> ResolvedRoute {
-        ResolvedRoute {
-            provider_name: self.default.provider_name.clone(),
-            provider_url: self.default.provider_url.clone(),
-            model_rewrite: self.default.model_rewrite.clone(),
-            strip_auth: self.default.strip_auth,
-            api_key: self.default.api_key.clone(),
-            stub_count_tokens: self.default.stub_count_tokens,
+    /// Where a request for `model` goes going by its model alone, as for a
+    /// request without a body.
+    pub fn resolve_pattern(&self, model: &str, endpoint: Endpoint) -> ResolvedRoute {
+        self.resolve_fields(model, endpoint, RequestFields::default())
+    }
+
+    pub fn resolve_fields(
+        &self,
+        model: &str,
+        endpoint: Endpoint,
+        fields: RequestFields<'_>,
+    ) -> ResolvedRoute {
+        let pins = self.pins.read().expect("routes lock poisoned");
+        let forced = self.forced.read().expect("routes lock poisoned");
+        // A pin for the model outranks a provider forced for everything
+        let forced = pins
+            .iter()
+            .find(|pin| pin.pattern.is_match(model))
+            .map(|pin| &pin.route)
+            .or(forced.as_ref());
+        self.resolve_to(model, endpoint, fields, forced)
+    }
+
+    /// Where `client`'s request for `model` goes when one of the client's
+    /// own pins matches it. These outrank every other pin, so the client's
+    /// requests skip classification too.
+    pub fn resolve_client(
+        &self,
+        client: &str,
+        model: &str,
+        endpoint: Endpoint,
+        fields: RequestFields<'_>,
+    ) -> Option<ResolvedRoute> {
+        let pin = self
+            .client_pins
+            .get(client)?
+            .iter()
+            .find(|pin| pin.pattern.is_match(model))?;
+        Some(self.resolve_to(model, endpoint, fields, Some(&pin.route)))
+    }
+
+    /// The first enabled route for `model` whose conditions `fields` meet,
+    /// keeping to `forced`'s provider when one is given.
+    fn resolve_to(
+        &self,
+        model: &str,
+        endpoint: Endpoint,
+        fields: RequestFields<'_>,
+        forced: Option<&ResolvedRoute>,
+    ) -> ResolvedRoute {
+        let disabled = self.disabled.read().expect("routes lock poisoned");
+        for (index, route) in self.routes.iter().enumerate() {
+            if disabled.contains(&index) || !route.pattern.is_match(model) {
+                continue;
+            }
+            if let Some(ref user_id) = route.user_id
+                && !fields.user_id.is_some_and(|id| user_id.is_match(id))
+            {
+                continue;
+            }
+            // While forced, only routes to the forced provider apply, so
+            // their model rewrites still take effect.
+            if let Some(forced) = forced
+                && !self.reaches(
+                    route.group.as_deref(),
+                    &route.provider_name,
+                    &forced.provider_name,
+                )
+            {
+                continue;
+            }
+            let mut resolved = ResolvedRoute {
+                provider_name: route.provider_name.clone(),
+                provider_url: route.provider_url.clone(),
+                model_rewrite: route.model_rewrite.clone(),
+                strip_auth: route.strip_auth,
+                api_key: route.api_key.clone(),
+                stub_count_tokens: route.stub_count_tokens,
+                api_format: route.api_format,
+                routing_method: RoutingMethod::Pattern,
+                route_name: route.name.clone(),
+                group: route.group.clone(),
+                limits: route.limits,
+                sampling: route.sampling,
+                context_guard: route.context_guard,
+                dedup: route.dedup,
+                archive: route.archive,
+                repair_tool_calls: route.repair_tool_calls,
+            };
+            if let Some(forced) = forced
+                && resolved.group.is_some()
+            {
+                self.point_at(&mut resolved, &forced.provider_name);
+            }
+            return resolved;
+        }
+
+        match forced {
+            Some(forced) => forced.clone(),
+            None => self.make_default(endpoint),
+        }
+    }
+
+    /// Points a route to a group at the member that has been doing best
+    /// lately, by `metrics`. Other routes are left alone.
+    pub fn balance(&self, route: &mut ResolvedRoute, metrics: &MetricsStore) {
+        let Some(members) = route.group.as_ref().and_then(|g| self.groups.get(g)) else {
+            return;
+        };
+        if let Some(member) =
+            balance::pick(members, |name| metrics.provider_health(name), &self.random)
+        {
+            let member = member.to_string();
+            self.point_at(route, &member);
+        }
+    }
+
+    /// Members of the group called `name`.
+    pub fn group_members(&self, name: &str) -> Option<&[String]> {
+        self.groups.get(name).map(Vec::as_slice)
+    }
+
+    /// Whether a route to `group`, or else to `provider`, can send to
+    /// `target`.
+    fn reaches(&self, group: Option<&str>, provider: &str, target: &str) -> bool {
+        match group.and_then(|g| self.groups.get(g)) {
+            Some(members) => members.iter().any(|m| m == target),
+            None => provider == target,
+        }
+    }
+
+    /// Sends `route` to `member` of its group.
+    fn point_at(&self, route: &mut ResolvedRoute, member: &str) {
+        let Some(provider) = self.providers.get(member) else {
+            return;
+        };
+        route.provider_name = member.to_string();
+        route.provider_url = provider.url.clone();
+        route.strip_auth = provider.strip_auth;
+        route.api_key = self.member_keys.get(member).cloned().unwrap_or_default();
+        route.stub_count_tokens = provider.stub_count_tokens;
+        route.api_format = provider.api_format;
+        route.group = None;
+    }
+
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let disabled = self.disabled.read().expect("routes lock poisoned");
+        self.routes
+            .iter()
+            .enumerate()
+            .map(|(index, route)| RouteInfo {
+                index,
+                pattern: route.pattern.as_str().to_string(),
+                provider: route.group.as_ref().unwrap_or(&route.provider_name).clone(),
+                model: route.model_rewrite.clone(),
+                enabled: !disabled.contains(&index),
+            })
+            .collect()
+    }
+
+    pub fn provider_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.providers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The provider API keys this router sends, for scrubbing from logs.
+    /// Keys not read from their file or the keychain yet are left out.
+    pub fn api_keys(&self) -> Vec<String> {
+        let forced = self.forced.read().expect("routes lock poisoned");
+        let pins = self.pins.read().expect("routes lock poisoned");
+        std::iter::once(&self.default.api_key)
+            .chain(self.endpoint_defaults.values().map(|route| &route.api_key))
+            .chain(forced.iter().map(|route| &route.api_key))
+            .chain(pins.iter().map(|pin| &pin.route.api_key))
+            .chain(
+                self.client_pins
+                    .values()
+                    .flatten()
+                    .map(|pin| &pin.route.api_key),
+            )
+            .chain(self.routes.iter().map(|route| &route.api_key))
+            .chain(self.auto_routes.iter().map(|route| &route.api_key))
+            .chain(self.member_keys.values())
+            .filter_map(|key| key.cached().map(String::from))
+            .collect()
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&ProviderConfig> {
+        self.providers.get(name)
+    }
+
+    /// The limits of `model` as served by `provider`, whose
+    /// `context_window` outranks the built-in one.
+    pub fn model_limits(&self, provider: &str, model: &str) -> Option<Limits> {
+        self.model_limits.limits(
+            model,
+            self.provider(provider).and_then(|p| p.context_window),
+        )
+    }
+
+    pub fn forced_provider(&self) -> Option<String> {
+        self.forced
+            .read()
+            .expect("routes lock poisoned")
+            .as_ref()
+            .map(|r| r.provider_name.clone())
+    }
+
+    pub fn set_route_enabled(&self, index: usize, enabled: bool) -> Result<(), CroxyError> {
+        if index >= self.routes.len() {
+            return Err(CroxyError::Routing(format!(
+                "route {index} out of range ({} pattern routes)",
+                self.routes.len()
+            )));
+        }
+        let mut disabled = self.disabled.write().expect("routes lock poisoned");
+        if enabled {
+            disabled.remove(&index);
+        } else {
+            disabled.insert(index);
+        }
+        Ok(())
+    }
+
+    /// Sends all traffic to `name`, or restores normal routing with `None`.
+    pub fn force_provider(&self, name: Option<&str>) -> Result<(), CroxyError> {
+        let route = name.map(|name| self.override_route(name)).transpose()?;
+        *self.forced.write().expect("routes lock poisoned") = route;
+        Ok(())
+    }
+
+    /// The route a forced provider or pin sends to when none of the
+    /// provider's own routes match.
+    fn override_route(&self, name: &str) -> Result<ResolvedRoute, CroxyError> {
+        let provider = self.providers.get(name).ok_or_else(|| {
+            CroxyError::Routing(format!("provider '{name}' not found in providers"))
+        })?;
+        Ok(ResolvedRoute {
+            provider_name: name.to_string(),
+            provider_url: provider.url.clone(),
+            model_rewrite: None,
+            strip_auth: provider.strip_auth,
+            api_key: ApiKey::new(name, provider).map_err(CroxyError::Config)?,
+            stub_count_tokens: provider.stub_count_tokens,
+            api_format: provider.api_format,
             routing_method: RoutingMethod::Default,
+            route_name: None,
+            group: None,
+            limits: ResponseLimits::default(),
+            sampling: Sampling::default(),
+            context_guard: None,
+            dedup: None,
+            archive: false,
+            repair_tool_calls: false,
+        })
+    }
+
+    fn compile_pin(&self, pattern: &str, name: &str, saved: bool) -> Result<Pin, CroxyError> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| CroxyError::Routing(format!("invalid regex '{pattern}': {e}")))?;
+        Ok(Pin {
+            pattern: regex,
+            route: self.override_route(name)?,
+            saved,
+        })
+    }
+
+    /// Sends models matching `pattern` to `name` ahead of the routes and
+    /// any forced provider, replacing an earlier pin of the same pattern.
+    pub fn pin(&self, pattern: &str, name: &str) -> Result<(), CroxyError> {
+        self.add_pin(pattern, name, false)
+    }
+
+    fn add_pin(&self, pattern: &str, name: &str, saved: bool) -> Result<(), CroxyError> {
+        let pin = self.compile_pin(pattern, name, saved)?;
+        let mut pins = self.pins.write().expect("routes lock poisoned");
+        match pins.iter_mut().find(|p| p.pattern.as_str() == pattern) {
+            Some(existing) => *existing = pin,
+            None => pins.push(pin),
         }
+        Ok(())
+    }
+
+    /// Removes the pin of `pattern`, whether set by a command or saved.
+    pub fn unpin(&self, pattern: &str) -> Result<(), CroxyError> {
+        let mut pins = self.pins.write().expect("routes lock poisoned");
+        let before = pins.len();
+        pins.retain(|pin| pin.pattern.as_str() != pattern);
+        if pins.len() == before {
+            return Err(CroxyError::Routing(format!("'{pattern}' is not pinned")));
+        }
+        Ok(())
+    }
+
+    pub fn pins(&self) -> Vec<PinInfo> {
+        self.pins
+            .read()
+            .expect("routes lock poisoned")
+            .iter()
+            .map(|pin| PinInfo {
+                pattern: pin.pattern.as_str().to_string(),
+                provider: pin.route.provider_name.clone(),
+                saved: pin.saved,
+            })
+            .collect()
     }
+
+    /// Carries the pins set by commands on `previous` over to this router,
+    /// so a reload keeps them. Pins to providers that are gone are
+    /// dropped.
+    pub fn keep_pins(&self, previous: &Router) {
+        for pin in previous.pins().into_iter().filter(|pin| !pin.saved) {
+            if let Err(e) = self.pin(&pin.pattern, &pin.provider) {
+                warn!(pattern = %pin.pattern, "pin dropped on reload: {e}");
+            }
+        }
+    }
+
+    /// A route to `name` without any model rewrite, built from a route or
+    /// the default that already uses it. Used for requests tied to a
+    /// provider rather than a model, like looking up a message batch.
+    pub fn provider_route(&self, name: &str) -> Option<ResolvedRoute> {
+        if self.default.provider_name == name {
+            return Some(ResolvedRoute {
+                model_rewrite: None,
+                ..self.default.clone()
+            });
+        }
+        if let Some(ref forced) = *self.forced.read().expect("routes lock poisoned")
+            && forced.provider_name == name
+        {
+            return Some(forced.clone());
+        }
+        let Some(route) = self.routes.iter().find(|r| r.provider_name == name) else {
+            if let Some(route) = self
+                .endpoint_defaults
+                .values()
+                .find(|route| route.provider_name == name)
+            {
+                return Some(route.clone());
+            }
+            // Group members after the first have no routes of their own
+            self.member_keys.get(name)?;
+            let mut route = self.default.clone();
+            route.model_rewrite = None;
+            route.limits = ResponseLimits::default();
+            self.point_at(&mut route, name);
+            return Some(route);
+        };
+        Some(ResolvedRoute {
+            provider_name: route.provider_name.clone(),
+            provider_url: route.provider_url.clone(),
+            model_rewrite: None,
+            strip_auth: route.strip_auth,
+            api_key: route.api_key.clone(),
+            stub_count_tokens: route.stub_count_tokens,
+            api_format: route.api_format,
+            routing_method: RoutingMethod::Default,
+            route_name: None,
+            group: None,
+            limits: route.limits,
+            sampling: Sampling::default(),
+            context_guard: None,
+            dedup: None,
+            archive: false,
+            repair_tool_calls: false,
+        })
+    }
+
+    /// A route to the model that summarizes messages a context guard
+    /// trims, if one is configured.
+    pub fn summary_route(&self) -> Option<ResolvedRoute> {
+        let (provider, model) = self.summary.as_ref()?;
+        let mut route = self.provider_route(provider)?;
+        route.model_rewrite = Some(model.clone());
+        Some(route)
+    }
+
+    /// The route or auto route called `name`, or else a route to the
+    /// provider called `name`, for commands that take either.
+    pub fn named_route(&self, name: &str) -> Option<ResolvedRoute> {
+        if let Some(route) = self.routes.iter().find(|r| r.name.as_deref() == Some(name)) {
+            return Some(ResolvedRoute {
+                provider_name: route.provider_name.clone(),
+                provider_url: route.provider_url.clone(),
+                model_rewrite: route.model_rewrite.clone(),
+                strip_auth: route.strip_auth,
+                api_key: route.api_key.clone(),
+                stub_count_tokens: route.stub_count_tokens,
+                api_format: route.api_format,
+                routing_method: RoutingMethod::Pattern,
+                route_name: route.name.clone(),
+                group: route.group.clone(),
+                limits: route.limits,
+                sampling: route.sampling,
+                context_guard: route.context_guard,
+                dedup: route.dedup,
+                archive: route.archive,
+                repair_tool_calls: route.repair_tool_calls,
+            });
+        }
+        self.auto_route(name).or_else(|| self.provider_route(name))
+    }
+
+    fn auto_route(&self, name: &str) -> Option<ResolvedRoute> {
+        let entry = self.auto_routes.iter().find(|r| r.name == name)?;
+        Some(ResolvedRoute {
+            provider_name: entry.provider_name.clone(),
+            provider_url: entry.provider_url.clone(),
+            model_rewrite: entry.model_rewrite.clone(),
+            strip_auth: entry.strip_auth,
+            api_key: entry.api_key.clone(),
+            stub_count_tokens: entry.stub_count_tokens,
+            api_format: entry.api_format,
+            routing_method: RoutingMethod::Auto,
+            route_name: Some(entry.name.clone()),
+            group: entry.group.clone(),
+            limits: entry.limits,
+            sampling: entry.sampling,
+            context_guard: entry.context_guard,
+            dedup: entry.dedup,
+            archive: entry.archive,
+            repair_tool_calls: entry.repair_tool_calls,
+        })
+    }
+
+    /// The default route for requests to `endpoint`.
+    fn make_default(&self, endpoint: Endpoint) -> ResolvedRoute {
+        self.endpoint_defaults
+            .get(&endpoint)
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+fn cached_api_key<'a>(
+    cache: &mut HashMap<&'a str, ApiKey>,
+    name: &'a str,
+    provider: &ProviderConfig,
+) -> Result<ApiKey, String> {
+    if let Some(key) = cache.get(name) {
+        return Ok(key.clone());
+    }
+    let key = ApiKey::new(name, provider)?;
+    cache.insert(name, key.clone());
+    Ok(key)
+}
+
+/// Identifies a conversation by its opening message, which stays the same
+/// as the conversation grows.
+fn conversation_key(messages: &[serde_json::Value]) -> Option<u64> {
+    let first = messages.first()?;
+    let mut hasher = DefaultHasher::new();
+    first.to_string().hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Whether `message` is a user turn made only of tool results.
+fn is_tool_results(message: &serde_json::Value) -> bool {
+    message.get("role").and_then(|r| r.as_str()) == Some("user")
+        && message
+            .get("content")
+            .and_then(|c| c.as_array())
+            .is_some_and(|blocks| {
+                !blocks.is_empty()
+                    && blocks
+                        .iter()
+                        .all(|b| b.get("type").and_then(|t| t.as_str()) == Some("tool_result"))
+            })
 }
 
 #[cfg(test)]
@@ -257,7 +1138,7 @@ mod tests {
     fn resolve_production(model: &str) -> ResolvedRoute {
         Router::from_config(&production_config())
             .unwrap()
-            .resolve_pattern(model)
+            .resolve_pattern(model, Endpoint::Messages)
     }
 
     #[test]
@@ -266,7 +1147,7 @@ mod tests {
         assert_eq!(route.provider_url, "https://api.anthropic.com");
         assert_eq!(route.model_rewrite, None);
         assert!(!route.strip_auth);
-        assert_eq!(route.api_key, None);
+        assert!(!route.api_key.is_set());
         assert!(!route.stub_count_tokens);
     }
 
@@ -276,10 +1157,359 @@ mod tests {
         assert_eq!(route.provider_url, "http://localhost:11434");
         assert_eq!(route.model_rewrite.as_deref(), Some("qwen3-coder:30b"));
         assert!(route.strip_auth);
-        assert_eq!(route.api_key.as_deref(), Some("ollama"));
+        assert_eq!(route.api_key.get().unwrap(), Some("ollama"));
         assert!(route.stub_count_tokens);
     }
 
+    #[test]
+    fn disabled_route_falls_through() {
+        let router = Router::from_config(&production_config()).unwrap();
+        router.set_route_enabled(1, false).unwrap();
+        let route = router.resolve_pattern("claude-sonnet-4-5", Endpoint::Messages);
+        assert_eq!(route.provider_name, "anthropic");
+        assert!(!router.routes()[1].enabled);
+
+        router.set_route_enabled(1, true).unwrap();
+        assert_eq!(
+            router
+                .resolve_pattern("claude-sonnet-4-5", Endpoint::Messages)
+                .provider_name,
+            "ollama"
+        );
+        assert!(matches!(
+            router.set_route_enabled(5, false),
+            Err(CroxyError::Routing(_))
+        ));
+    }
+
+    #[test]
+    fn forced_provider_keeps_its_own_routes() {
+        let router = Router::from_config(&production_config()).unwrap();
+        router.force_provider(Some("ollama")).unwrap();
+        assert_eq!(router.forced_provider().as_deref(), Some("ollama"));
+
+        // opus normally goes to anthropic; forced, it goes to ollama as-is
+        let route = router.resolve_pattern("claude-opus-4-6", Endpoint::Messages);
+        assert_eq!(route.provider_name, "ollama");
+        assert_eq!(route.model_rewrite, None);
+        // ollama's own sonnet route still rewrites the model
+        let route = router.resolve_pattern("claude-sonnet-4-5", Endpoint::Messages);
+        assert_eq!(route.model_rewrite.as_deref(), Some("qwen3-coder:30b"));
+
+        router.force_provider(None).unwrap();
+        assert_eq!(
+            router
+                .resolve_pattern("claude-opus-4-6", Endpoint::Messages)
+                .provider_name,
+            "anthropic"
+        );
+        assert!(matches!(
+            router.force_provider(Some("missing")),
+            Err(CroxyError::Routing(_))
+        ));
+    }
+
+    #[test]
+    fn pins_outrank_routes_and_the_forced_provider() {
+        let router = Router::from_config(&production_config()).unwrap();
+        let provider = |model| {
+            let route = router.resolve_pattern(model, Endpoint::Messages);
+            (route.provider_name, route.model_rewrite)
+        };
+        router.pin("opus", "ollama").unwrap();
+        router.pin("sonnet", "anthropic").unwrap();
+        assert_eq!(provider("claude-opus-4-6"), ("ollama".to_string(), None));
+        assert_eq!(
+            provider("claude-sonnet-4-5"),
+            ("anthropic".to_string(), None)
+        );
+        // Pinned to its own route's provider, haiku keeps the rewrite
+        router.pin("haiku", "ollama").unwrap();
+        assert_eq!(
+            provider("claude-haiku-4-5"),
+            ("ollama".to_string(), Some("qwen3-coder:30b".to_string()))
+        );
+
+        router.force_provider(Some("ollama")).unwrap();
+        assert_eq!(provider("claude-sonnet-4-5").0, "anthropic");
+        router.unpin("sonnet").unwrap();
+        assert_eq!(provider("claude-sonnet-4-5").0, "ollama");
+        assert!(matches!(
+            router.unpin("sonnet"),
+            Err(CroxyError::Routing(_))
+        ));
+        assert!(matches!(
+            router.pin("opus", "missing"),
+            Err(CroxyError::Routing(_))
+        ));
+        assert!(matches!(
+            router.pin("(", "ollama"),
+            Err(CroxyError::Routing(_))
+        ));
+    }
+
+    #[test]
+    fn saved_pins_load_and_reloads_keep_the_others() {
+        let mut cfg = production_config();
+        cfg.pins = vec![crate::config::PinConfig {
+            pattern: "opus".to_string(),
+            provider: "ollama".to_string(),
+        }];
+        let router = Router::from_config(&cfg).unwrap();
+        router.pin("sonnet", "anthropic").unwrap();
+        assert_eq!(
+            router
+                .resolve_pattern("claude-opus-4-6", Endpoint::Messages)
+                .provider_name,
+            "ollama"
+        );
+
+        // The saved pin comes from the config the reload reads, not the old
+        // router
+        let reloaded = Router::from_config(&production_config()).unwrap();
+        reloaded.keep_pins(&router);
+        assert_eq!(
+            reloaded.pins(),
+            [PinInfo {
+                pattern: "sonnet".to_string(),
+                provider: "anthropic".to_string(),
+                saved: false,
+            }]
+        );
+
+        cfg.pins[0].provider = "missing".to_string();
+        assert!(matches!(
+            Router::from_config(&cfg),
+            Err(CroxyError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn client_pins_apply_only_to_their_client() {
+        let mut cfg = production_config();
+        cfg.clients.insert(
+            "alice".to_string(),
+            crate::config::ClientConfig {
+                pins: vec![crate::config::PinConfig {
+                    pattern: "sonnet".to_string(),
+                    provider: "ollama".to_string(),
+                }],
+                ..Default::default()
+            },
+        );
+        let router = Router::from_config(&cfg).unwrap();
+        router.pin("sonnet", "anthropic").unwrap();
+        let alice = router
+            .resolve_client(
+                "alice",
+                "claude-sonnet-4-5",
+                Endpoint::Messages,
+                RequestFields::default(),
+            )
+            .unwrap();
+        assert_eq!(alice.provider_name, "ollama");
+        assert!(
+            router
+                .resolve_client(
+                    "alice",
+                    "claude-opus-4-6",
+                    Endpoint::Messages,
+                    RequestFields::default()
+                )
+                .is_none()
+        );
+        assert!(
+            router
+                .resolve_client(
+                    "bob",
+                    "claude-sonnet-4-5",
+                    Endpoint::Messages,
+                    RequestFields::default()
+                )
+                .is_none()
+        );
+
+        cfg.clients.get_mut("alice").unwrap().pins[0].provider = "missing".to_string();
+        assert!(matches!(
+            Router::from_config(&cfg),
+            Err(CroxyError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn user_id_routes_match_only_their_users() {
+        let router = Router::from_config(&config(
+            r#"
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            [provider.ollama]
+            url = "http://localhost:11434"
+            [[routes]]
+            pattern = "sonnet"
+            user_id = "^user_experiments"
+            provider = "ollama"
+            [[routes]]
+            pattern = "sonnet"
+            provider = "anthropic"
+            "#,
+        ))
+        .unwrap();
+        assert!(router.matches_fields());
+
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "metadata": {"user_id": "user_experiments_session_1"},
+        });
+        let provider = |fields| {
+            router
+                .resolve_fields("claude-sonnet-4-5", Endpoint::Messages, fields)
+                .provider_name
+        };
+        assert_eq!(provider(RequestFields::of(Some(&body))), "ollama");
+        let other = serde_json::json!({"metadata": {"user_id": "user_main"}});
+        assert_eq!(provider(RequestFields::of(Some(&other))), "anthropic");
+        assert_eq!(provider(RequestFields::default()), "anthropic");
+
+        let unmatched = config(
+            r#"
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            [[routes]]
+            name = "coding"
+            description = "Code"
+            user_id = "^user_experiments"
+            provider = "anthropic"
+            "#,
+        );
+        assert!(matches!(
+            Router::from_config(&unmatched),
+            Err(CroxyError::Config(e)) if e.contains("user_id but no pattern")
+        ));
+    }
+
+    #[test]
+    fn sampling_overrides_replace_the_clients() {
+        let mut cfg = production_config();
+        cfg.routes[1].temperature = Some(0.2);
+        cfg.routes[1].max_tokens = Some(4096);
+        let router = Router::from_config(&cfg).unwrap();
+
+        let route = router.resolve_pattern("claude-sonnet-4-5", Endpoint::Messages);
+        let mut body = serde_json::json!({"model": "x", "temperature": 1.0, "top_p": 0.9});
+        route.sampling.apply(&mut body);
+        assert_eq!(
+            body,
+            serde_json::json!({"model": "x", "temperature": 0.2, "top_p": 0.9, "max_tokens": 4096})
+        );
+        assert!(
+            router
+                .resolve_pattern("claude-opus-4-6", Endpoint::Messages)
+                .sampling
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn group_routes_go_to_the_healthier_member() {
+        let router = Router::from_config(&config(
+            r#"
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            [provider.mini]
+            url = "http://mini:11434"
+            [provider.studio]
+            url = "http://studio:11434"
+            strip_auth = true
+            [group.local]
+            members = ["mini", "studio"]
+            [[routes]]
+            pattern = "sonnet"
+            provider = "local"
+            model = "qwen3-coder:30b"
+            "#,
+        ))
+        .unwrap();
+        assert_eq!(router.routes()[0].provider, "local");
+
+        let metrics = MetricsStore::new(Duration::from_secs(60));
+        for (provider, ms) in [("mini", 2000), ("studio", 300)] {
+            let mut health = balance::Health::new(provider);
+            health.observe(200, Duration::from_millis(ms));
+            metrics.record_health(health);
+        }
+        let mut route = router.resolve_pattern("claude-sonnet-4-5", Endpoint::Messages);
+        assert_eq!(route.group.as_deref(), Some("local"));
+        router.balance(&mut route, &metrics);
+        assert_eq!(route.provider_name, "studio");
+        assert_eq!(route.provider_url, "http://studio:11434");
+        assert!(route.strip_auth);
+        assert_eq!(route.group, None);
+        assert_eq!(route.model_rewrite.as_deref(), Some("qwen3-coder:30b"));
+
+        // Forcing a member sends the group's routes to it alone
+        router.force_provider(Some("mini")).unwrap();
+        let route = router.resolve_pattern("claude-sonnet-4-5", Endpoint::Messages);
+        assert_eq!(route.provider_name, "mini");
+        assert_eq!(route.group, None);
+        assert_eq!(
+            router.provider_route("studio").unwrap().provider_name,
+            "studio"
+        );
+    }
+
+    #[test]
+    fn named_routes_fall_back_to_providers() {
+        let mut cfg = production_config();
+        cfg.routes[1].name = Some("local".to_string());
+        let router = Router::from_config(&cfg).unwrap();
+
+        let route = router.named_route("local").unwrap();
+        assert_eq!(route.provider_name, "ollama");
+        assert_eq!(route.model_rewrite.as_deref(), Some("qwen3-coder:30b"));
+        let route = router.named_route("anthropic").unwrap();
+        assert_eq!(route.route_name, None);
+        assert!(router.named_route("missing").is_none());
+    }
+
+    #[test]
+    fn api_key_file_is_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("key");
+        std::fs::write(&key_path, "sk-from-file\n").unwrap();
+        let cfg = config(&format!(
+            r#"
+            [provider.local]
+            url = "http://localhost:11434"
+            api_key_file = "{}"
+            [default]
+            provider = "local"
+            "#,
+            key_path.display()
+        ));
+        let route = Router::from_config(&cfg)
+            .unwrap()
+            .resolve_pattern("any", Endpoint::Messages);
+        assert_eq!(route.api_key.get().unwrap(), Some("sk-from-file"));
+    }
+
+    #[test]
+    fn unreadable_secrets_fail_when_first_used() {
+        let cfg = config(
+            r#"
+            [provider.local]
+            url = "http://localhost:11434"
+            api_key_file = "/nonexistent/croxy-key"
+            [default]
+            provider = "local"
+            "#,
+        );
+        let route = Router::from_config(&cfg)
+            .unwrap()
+            .resolve_pattern("any", Endpoint::Messages);
+        let err = route.api_key.get().expect_err("should fail");
+        assert!(err.contains("provider 'local'"), "{err}");
+    }
+
     #[test]
     fn haiku_routes_to_ollama_with_rewrite() {
         let route = resolve_production("claude-haiku-4-5-20251001");
@@ -294,12 +1524,65 @@ mod tests {
         assert_eq!(route.model_rewrite, None);
     }
 
+    #[test]
+    fn default_can_rewrite_unmatched_models() {
+        let mut cfg = production_config();
+        cfg.default.provider = "ollama".to_string();
+        cfg.default.model = Some("qwen3:8b".to_string());
+        cfg.default.strip_auth = Some(false);
+        cfg.default.api_key = Some("local-key".to_string());
+        let router = Router::from_config(&cfg).unwrap();
+
+        let route = router.resolve_pattern("gpt-4o", Endpoint::Messages);
+        assert_eq!(route.provider_name, "ollama");
+        assert_eq!(route.model_rewrite.as_deref(), Some("qwen3:8b"));
+        assert!(!route.strip_auth);
+        assert_eq!(route.api_key.get().unwrap(), Some("local-key"));
+        // Matched models keep their route's rewrite
+        let route = router.resolve_pattern("claude-opus-4-6", Endpoint::Messages);
+        assert_eq!(route.model_rewrite, None);
+        // Requests tied to the provider rather than a model aren't rewritten
+        assert_eq!(router.provider_route("ollama").unwrap().model_rewrite, None);
+    }
+
     #[test]
     fn empty_model_falls_back_to_default() {
         let route = resolve_production("");
         assert_eq!(route.provider_url, "https://api.anthropic.com");
     }
 
+    #[test]
+    fn unmatched_requests_use_their_endpoint_default() {
+        let cfg = config(
+            r#"
+            [server]
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            [provider.local]
+            url = "http://localhost:4000"
+            [provider.catalog]
+            url = "http://catalog"
+            [[routes]]
+            pattern = "opus"
+            provider = "anthropic"
+            [default]
+            provider = "local"
+            [default.models]
+            provider = "catalog"
+            "#,
+        );
+        let router = Router::from_config(&cfg).unwrap();
+        let provider = |model, endpoint| router.resolve_pattern(model, endpoint).provider_name;
+        assert_eq!(provider("", Endpoint::Models), "catalog");
+        assert_eq!(provider("sonnet", Endpoint::Messages), "local");
+        assert_eq!(provider("sonnet", Endpoint::CountTokens), "local");
+        assert_eq!(provider("opus", Endpoint::Messages), "anthropic");
+        assert_eq!(
+            router.provider_route("catalog").unwrap().provider_url,
+            "http://catalog"
+        );
+    }
+
     #[test]
     fn first_matching_route_wins() {
         let cfg = config(
@@ -320,7 +1603,7 @@ mod tests {
             "#,
         );
         let router = Router::from_config(&cfg).unwrap();
-        let route = router.resolve_pattern("opus");
+        let route = router.resolve_pattern("opus", Endpoint::Messages);
         assert_eq!(route.provider_url, "http://a");
     }
 
@@ -338,7 +1621,10 @@ mod tests {
             provider = "a"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(err.contains("invalid regex"), "got: {err}");
     }
 
@@ -356,7 +1642,10 @@ mod tests {
             provider = "a"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(err.contains("not found"), "got: {err}");
     }
 
@@ -383,7 +1672,10 @@ mod tests {
             provider = "nonexistent"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(err.contains("not found"), "got: {err}");
     }
 
@@ -400,7 +1692,10 @@ mod tests {
             provider = "a"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(err.contains("description but no name"), "got: {err}");
     }
 
@@ -416,7 +1711,10 @@ mod tests {
             provider = "a"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(
             err.contains("neither pattern nor description"),
             "got: {err}"
@@ -443,7 +1741,10 @@ mod tests {
             provider = "a"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(err.contains("duplicate route name"), "got: {err}");
     }
 
@@ -463,7 +1764,10 @@ mod tests {
             provider = "a"
             "#,
         );
-        let err = Router::from_config(&cfg).err().expect("should fail");
+        let err = Router::from_config(&cfg)
+            .err()
+            .expect("should fail")
+            .to_string();
         assert!(err.contains("url is empty"), "got: {err}");
     }
 
@@ -513,4 +1817,28 @@ mod tests {
         assert_eq!(router.routes.len(), 0);
         assert_eq!(router.auto_candidates.len(), 1);
     }
+
+    #[test]
+    fn endpoints_are_classified_by_path() {
+        assert_eq!(Endpoint::of("/v1/messages"), Endpoint::Messages);
+        assert_eq!(
+            Endpoint::of("/v1/messages/count_tokens"),
+            Endpoint::CountTokens
+        );
+        assert_eq!(Endpoint::of("/v1/models/claude-opus"), Endpoint::Models);
+        assert_eq!(Endpoint::of("/v1/messages/batches"), Endpoint::Other);
+        assert!(Endpoint::Messages.generates());
+        assert!(!Endpoint::CountTokens.generates());
+
+        let text = serde_json::json!({"role": "user", "content": "hi"});
+        let results = serde_json::json!({"role": "user", "content": [
+            {"type": "tool_result", "tool_use_id": "t1", "content": "ok"}
+        ]});
+        assert!(!is_tool_results(&text));
+        assert!(is_tool_results(&results));
+        assert_eq!(
+            conversation_key(&[text.clone(), results.clone()]),
+            conversation_key(&[text])
+        );
+    }
 }
@@ -0,0 +1,202 @@
+//! `server.validate_requests`: a check of inbound Messages bodies for the
+//! mistakes that otherwise come back as a provider's confusing error, or
+//! none at all from a lenient local server. Only the shape is checked: a
+//! field the API doesn't know is left for the provider to judge.
+
+use serde_json::Value;
+
+use crate::router::Endpoint;
+
+const ROLES: [&str; 2] = ["user", "assistant"];
+
+/// A top-level field, what it should hold, and whether a value does.
+type TypeCheck = (&'static str, &'static str, fn(&Value) -> bool);
+
+/// The calls whose bodies are checked: Messages and token counting.
+fn endpoint(path: &str) -> Option<Endpoint> {
+    match path {
+        "/v1/messages" => Some(Endpoint::Messages),
+        "/v1/messages/count_tokens" => Some(Endpoint::CountTokens),
+        _ => None,
+    }
+}
+
+/// Whether bodies sent to `path` are checked.
+pub fn applies(path: &str) -> bool {
+    endpoint(path).is_some()
+}
+
+/// The first problem with a request body sent to `path`, as the field
+/// it is in and what is wrong with it, e.g. `messages.2.role: ...`.
+pub fn check(path: &str, body: &Value) -> Result<(), String> {
+    let Some(endpoint) = endpoint(path) else {
+        return Ok(());
+    };
+    let Some(body) = body.as_object() else {
+        return Err("body: expected an object".to_string());
+    };
+    match body.get("model") {
+        Some(Value::String(model)) if !model.is_empty() => {}
+        Some(Value::String(_)) => return Err("model: must not be empty".to_string()),
+        Some(_) => return Err("model: expected a string".to_string()),
+        None => return Err("model: field required".to_string()),
+    }
+    if endpoint == Endpoint::Messages {
+        match body.get("max_tokens") {
+            Some(max_tokens) if max_tokens.as_u64().is_some_and(|n| n > 0) => {}
+            Some(_) => return Err("max_tokens: expected a positive integer".to_string()),
+            None => return Err("max_tokens: field required".to_string()),
+        }
+    }
+    let messages = match body.get("messages") {
+        Some(Value::Array(messages)) => messages,
+        Some(_) => return Err("messages: expected an array".to_string()),
+        None => return Err("messages: field required".to_string()),
+    };
+    for (i, message) in messages.iter().enumerate() {
+        check_message(message).map_err(|e| format!("messages.{i}{e}"))?;
+    }
+    if let Some(system) = body.get("system") {
+        check_content(system).map_err(|e| format!("system{e}"))?;
+    }
+    let types: [TypeCheck; 6] = [
+        ("stream", "a boolean", Value::is_boolean),
+        ("temperature", "a number", Value::is_number),
+        ("top_p", "a number", Value::is_number),
+        ("top_k", "a non-negative integer", Value::is_u64),
+        ("stop_sequences", "an array of strings", |v| {
+            v.as_array()
+                .is_some_and(|stops| stops.iter().all(Value::is_string))
+        }),
+        ("tools", "an array", Value::is_array),
+    ];
+    for (field, expected, valid) in types {
+        if body.get(field).is_some_and(|value| !valid(value)) {
+            return Err(format!("{field}: expected {expected}"));
+        }
+    }
+    for (i, tool) in body
+        .get("tools")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        if !tool.is_object() {
+            return Err(format!("tools.{i}: expected an object"));
+        }
+        if tool["name"].as_str().is_none_or(str::is_empty) {
+            return Err(format!("tools.{i}.name: field required"));
+        }
+    }
+    Ok(())
+}
+
+/// A message's problem, given from the message's own path on, e.g.
+/// `.role: ...`.
+fn check_message(message: &Value) -> Result<(), String> {
+    let Some(message) = message.as_object() else {
+        return Err(": expected an object".to_string());
+    };
+    match message.get("role") {
+        Some(Value::String(role)) if ROLES.contains(&role.as_str()) => {}
+        Some(role) => {
+            return Err(format!(
+                ".role: expected \"user\" or \"assistant\", got {role}"
+            ));
+        }
+        None => return Err(".role: field required".to_string()),
+    }
+    match message.get("content") {
+        Some(content) => check_content(content).map_err(|e| format!(".content{e}")),
+        None => Err(".content: field required".to_string()),
+    }
+}
+
+/// Content is a string or an array of blocks, each with a `type`.
+/// Problems are given from the content's own path on, e.g. `.1.type: ...`.
+fn check_content(content: &Value) -> Result<(), String> {
+    let blocks = match content {
+        Value::String(_) => return Ok(()),
+        Value::Array(blocks) => blocks,
+        _ => return Err(": expected a string or an array of content blocks".to_string()),
+    };
+    for (i, block) in blocks.iter().enumerate() {
+        if !block.is_object() {
+            return Err(format!(".{i}: expected an object"));
+        }
+        if !block["type"].is_string() {
+            return Err(format!(".{i}.type: field required"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn problem(body: Value) -> String {
+        check("/v1/messages", &body).unwrap_err()
+    }
+
+    #[test]
+    fn problems_name_the_field_they_are_in() {
+        let valid = json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 1024,
+            "system": [{"type": "text", "text": "Be brief."}],
+            "messages": [
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": [{"type": "text", "text": "hello"}]},
+            ],
+            "tools": [{"name": "read", "input_schema": {}}],
+            "stream": true,
+        });
+        assert_eq!(check("/v1/messages", &valid), Ok(()));
+
+        let with = |field: &str, value: Value| {
+            let mut body = valid.clone();
+            body[field] = value;
+            problem(body)
+        };
+        assert_eq!(with("model", json!("")), "model: must not be empty");
+        assert_eq!(with("messages", json!({})), "messages: expected an array");
+        assert_eq!(
+            with("messages", json!([{"role": "system", "content": "x"}])),
+            "messages.0.role: expected \"user\" or \"assistant\", got \"system\""
+        );
+        assert_eq!(
+            with(
+                "messages",
+                json!([{"role": "user", "content": "x"}, {"role": "user", "content": [{"text": "y"}]}])
+            ),
+            "messages.1.content.0.type: field required"
+        );
+        assert_eq!(
+            with("messages", json!(["hi"])),
+            "messages.0: expected an object"
+        );
+        assert_eq!(
+            with("system", json!(3)),
+            "system: expected a string or an array of content blocks"
+        );
+        assert_eq!(with("stream", json!("yes")), "stream: expected a boolean");
+        assert_eq!(with("tools", json!([{}])), "tools.0.name: field required");
+        assert_eq!(
+            with("max_tokens", json!(0)),
+            "max_tokens: expected a positive integer"
+        );
+        assert_eq!(problem(json!([])), "body: expected an object");
+    }
+
+    #[test]
+    fn token_counts_need_no_max_tokens_and_other_calls_are_not_checked() {
+        let count = json!({"model": "claude-sonnet-4", "messages": []});
+        assert_eq!(check("/v1/messages/count_tokens", &count), Ok(()));
+        assert_eq!(problem(count), "max_tokens: field required");
+        assert_eq!(check("/v1/messages/batches", &json!({})), Ok(()));
+    }
+}
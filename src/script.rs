@@ -0,0 +1,274 @@
+//! The `[script]` hook: a Lua function that sees each request after it is
+//! routed and can send it elsewhere, change the model, or rewrite the
+//! body. It is for policy the config can't express, and needs croxy built
+//! with `--features scripting`.
+//!
+//! The script defines `on_request(request)` and returns `nil` to leave the
+//! request alone, or a table with any of `provider`, `model`, and `body`.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "scripting")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "scripting")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::ScriptConfig;
+use crate::scrub::CREDENTIAL_HEADERS;
+
+/// Lua states the script is loaded into, so this many requests can run it
+/// at once. Each has its own globals.
+#[cfg(feature = "scripting")]
+const STATES: usize = 4;
+
+/// What `on_request` is called with.
+#[derive(Debug, Serialize)]
+pub struct ScriptRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub model: &'a str,
+    /// The provider and route the config chose.
+    pub provider: &'a str,
+    pub route: Option<&'a str>,
+    /// Name of the virtual key the client presented.
+    pub key: Option<&'a str>,
+    /// Request headers, without credentials.
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<&'a Value>,
+}
+
+/// The request's headers a script may see.
+pub fn visible_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| !CREDENTIAL_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// What `on_request` returned.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct Decision {
+    /// Provider to send the request to instead.
+    pub provider: Option<String>,
+    /// Model to ask the provider for.
+    pub model: Option<String>,
+    /// Replacement request body.
+    pub body: Option<Value>,
+}
+
+pub struct Script {
+    #[cfg(feature = "scripting")]
+    states: Arc<States>,
+}
+
+#[cfg(feature = "scripting")]
+struct States {
+    lua: Vec<Mutex<mlua::Lua>>,
+    /// Where the next call starts looking for a free state.
+    next: AtomicUsize,
+    timeout: Duration,
+}
+
+impl Script {
+    /// `None` when no script is configured.
+    pub fn from_config(config: &ScriptConfig) -> Result<Option<Self>, String> {
+        let Some(ref path) = config.path else {
+            return Ok(None);
+        };
+        let path = crate::secrets::expand_home(path);
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        Self::load(
+            &source,
+            &path.display().to_string(),
+            Duration::from_millis(config.timeout_ms),
+        )
+        .map(Some)
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn load(_source: &str, _name: &str, _timeout: Duration) -> Result<Self, String> {
+        Err("this croxy was built without scripting; rebuild with --features scripting".to_string())
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    pub async fn decide(&self, _request: &ScriptRequest<'_>) -> Result<Decision, String> {
+        Ok(Decision::default())
+    }
+
+    /// Runs `source` in Lua states without the `io` and `os` libraries.
+    #[cfg(feature = "scripting")]
+    fn load(source: &str, name: &str, timeout: Duration) -> Result<Self, String> {
+        use mlua::{Function, Lua, LuaOptions, StdLib};
+
+        let lua = (0..STATES)
+            .map(|_| {
+                let lua = Lua::new_with(
+                    StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+                    LuaOptions::default(),
+                )
+                .map_err(|e| format!("failed to start Lua: {e}"))?;
+                lua.load(source)
+                    .set_name(name)
+                    .exec()
+                    .map_err(|e| format!("{name}: {e}"))?;
+                lua.globals()
+                    .get::<_, Function>("on_request")
+                    .map_err(|_| format!("{name} does not define on_request"))?;
+                Ok(Mutex::new(lua))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            states: Arc::new(States {
+                lua,
+                next: AtomicUsize::new(0),
+                timeout,
+            }),
+        })
+    }
+
+    /// Calls `on_request` on the blocking pool, as a script may run for up
+    /// to `timeout_ms`.
+    #[cfg(feature = "scripting")]
+    pub async fn decide(&self, request: &ScriptRequest<'_>) -> Result<Decision, String> {
+        let request = serde_json::to_value(request)
+            .map_err(|e| format!("failed to pass the request to the script: {e}"))?;
+        let states = self.states.clone();
+        tokio::task::spawn_blocking(move || states.decide(&request))
+            .await
+            .map_err(|e| format!("on_request failed: {e}"))?
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl States {
+    /// A free state, or when all are busy, the next one once it is.
+    fn lock(&self) -> std::sync::MutexGuard<'_, mlua::Lua> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = self.lua.len();
+        (0..n)
+            .find_map(|i| self.lua[(start + i) % n].try_lock().ok())
+            .unwrap_or_else(|| self.lua[start % n].lock().expect("script lock poisoned"))
+    }
+
+    fn decide(&self, request: &Value) -> Result<Decision, String> {
+        use mlua::{Function, HookTriggers, LuaSerdeExt};
+
+        let lua = self.lock();
+        let deadline = std::time::Instant::now() + self.timeout;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(1000),
+            move |_, _| {
+                if std::time::Instant::now() > deadline {
+                    return Err(mlua::Error::RuntimeError("script timed out".to_string()));
+                }
+                Ok(())
+            },
+        );
+        let on_request: Function = lua
+            .globals()
+            .get("on_request")
+            .map_err(|e| format!("on_request is gone: {e}"))?;
+        let request = lua
+            .to_value(request)
+            .map_err(|e| format!("failed to pass the request to the script: {e}"))?;
+        let result = on_request.call::<_, mlua::Value>(request);
+        lua.remove_hook();
+        let result = result.map_err(|e| format!("on_request failed: {e}"))?;
+        if result.is_nil() {
+            return Ok(Decision::default());
+        }
+        lua.from_value(result)
+            .map_err(|e| format!("on_request returned an invalid decision: {e}"))
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(body: &Value) -> ScriptRequest<'_> {
+        ScriptRequest {
+            method: "POST",
+            path: "/v1/messages",
+            model: "claude-opus-4-6",
+            provider: "anthropic",
+            route: Some("opus"),
+            key: Some("ci"),
+            headers: BTreeMap::new(),
+            body: Some(body),
+        }
+    }
+
+    #[tokio::test]
+    async fn scripts_can_reroute_and_rewrite_the_body() {
+        let script = Script::load(
+            r#"
+            function on_request(request)
+                if request.key ~= "ci" then
+                    return nil
+                end
+                local body = request.body
+                body.max_tokens = math.min(body.max_tokens, 1024)
+                return { provider = "ollama", model = "qwen3:8b", body = body }
+            end
+            "#,
+            "policy.lua",
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        let body = json!({"model": "claude-opus-4-6", "max_tokens": 8192, "messages": []});
+        let decision = script.decide(&request(&body)).await.unwrap();
+        assert_eq!(decision.provider.as_deref(), Some("ollama"));
+        assert_eq!(decision.model.as_deref(), Some("qwen3:8b"));
+        assert_eq!(
+            decision.body,
+            Some(json!({"model": "claude-opus-4-6", "max_tokens": 1024, "messages": []}))
+        );
+    }
+
+    #[tokio::test]
+    async fn runaway_scripts_are_stopped() {
+        let script = Script::load(
+            "function on_request(request) while true do end end",
+            "loop.lua",
+            Duration::from_millis(10),
+        )
+        .unwrap();
+        let err = script.decide(&request(&json!({}))).await.unwrap_err();
+        assert!(err.contains("script timed out"), "{err}");
+    }
+
+    #[test]
+    fn scripts_have_no_os_access() {
+        assert!(Script::load("os.execute('true')", "os.lua", Duration::from_millis(10)).is_err());
+        assert_eq!(
+            Script::load("x = 1", "empty.lua", Duration::from_millis(10)).err(),
+            Some("empty.lua does not define on_request".to_string())
+        );
+    }
+
+    #[test]
+    fn scripts_see_no_credentials() {
+        let mut headers = HeaderMap::new();
+        for name in [
+            "x-goog-api-key",
+            "proxy-authorization",
+            "cookie",
+            "authorization",
+        ] {
+            headers.insert(name, "secret".parse().unwrap());
+        }
+        headers.insert("anthropic-version", "2023-06-01".parse().unwrap());
+        assert_eq!(
+            visible_headers(&headers),
+            BTreeMap::from([("anthropic-version".to_string(), "2023-06-01".to_string())])
+        );
+    }
+}
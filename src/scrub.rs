@@ -0,0 +1,279 @@
+//! Scrubbing secrets out of what croxy writes down: the application log,
+//! the error kept with each request record, and capture files. Values of
+//! credential headers, well-known key formats, the providers' own API
+//! keys, and any `logging.redact_patterns` are replaced with `[REDACTED]`,
+//! so a provider echoing a key back in an error can't leak it to disk.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::{Arc, RwLock};
+
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+use tracing_subscriber::fmt::MakeWriter;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Provider keys shorter than this are left alone; placeholder keys such
+/// as `ollama` would otherwise be scrubbed from every line that names them.
+const MIN_SECRET_LEN: usize = 12;
+
+/// Headers that carry credentials, in lowercase.
+pub const CREDENTIAL_HEADERS: &[&str] = &[
+    "x-api-key",
+    "api-key",
+    "x-goog-api-key",
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Patterns and their replacements, always applied after the credential
+/// headers'.
+const BUILTIN: &[(&str, &str)] = &[
+    // Gemini takes its key in the query string
+    (
+        r#"(?i)([?&](?:key|api_key|access_token)=)[^&\s"'#)\\]+"#,
+        "$1[REDACTED]",
+    ),
+    (
+        r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]{8,}",
+        "Bearer [REDACTED]",
+    ),
+    (r"\bsk-(?:ant|croxy|proj)-[A-Za-z0-9_-]{8,}", REDACTED),
+    (r"\bAIza[0-9A-Za-z_-]{30,}", REDACTED),
+];
+
+/// `secrets` long enough to scrub, and a regex matching any of them.
+fn literal_regex<I: IntoIterator<Item = String>>(secrets: I) -> (Vec<String>, Option<Regex>) {
+    let mut secrets: Vec<String> = secrets
+        .into_iter()
+        .filter(|s| s.len() >= MIN_SECRET_LEN)
+        .collect();
+    // Longest first, so a key that contains another is replaced whole
+    secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    secrets.dedup();
+    let regex = (!secrets.is_empty()).then(|| {
+        let escaped: Vec<String> = secrets.iter().map(|s| regex::escape(s)).collect();
+        RegexBuilder::new(&escaped.join("|"))
+            .size_limit(1 << 24)
+            .build()
+            .expect("escaped literals are a valid regex")
+    });
+    (secrets, regex)
+}
+
+pub struct Scrubber {
+    patterns: Vec<(Regex, &'static str)>,
+    /// The providers' API keys, and a regex matching them literally.
+    /// Replaced on reload.
+    secrets: RwLock<(Vec<String>, Option<Regex>)>,
+}
+
+impl Default for Scrubber {
+    fn default() -> Self {
+        Self::new(&[]).expect("built-in scrub patterns are valid")
+    }
+}
+
+impl Scrubber {
+    /// The built-in patterns plus `patterns` from `logging.redact_patterns`.
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        // `x-api-key: ...`, `"authorization":"Bearer ..."`, `cookie=...`
+        let headers = format!(
+            r#"(?i)\b({})(["']?\s*[:=]\s*["']?)(?:(?:bearer|basic)\s+)?[^\s"',;&\\]+"#,
+            CREDENTIAL_HEADERS.join("|")
+        );
+        let builtin = std::iter::once((headers.as_str(), "$1$2[REDACTED]"))
+            .chain(BUILTIN.iter().copied())
+            .map(|(pattern, replacement)| {
+                Regex::new(pattern)
+                    .map(|regex| (regex, replacement))
+                    .map_err(|e| e.to_string())
+            });
+        let configured = patterns.iter().map(|pattern| {
+            Regex::new(pattern)
+                .map(|regex| (regex, REDACTED))
+                .map_err(|e| format!("invalid redact pattern '{pattern}': {e}"))
+        });
+        Ok(Self {
+            patterns: builtin.chain(configured).collect::<Result<_, _>>()?,
+            secrets: RwLock::new((Vec::new(), None)),
+        })
+    }
+
+    /// Sets the literal secrets to scrub, replacing any set before.
+    pub fn set_secrets<I: IntoIterator<Item = String>>(&self, secrets: I) {
+        *self.secrets.write().expect("scrubber lock poisoned") = literal_regex(secrets);
+    }
+
+    /// Adds a secret to scrub, such as a provider key read after startup.
+    pub fn add_secret(&self, secret: &str) {
+        let known = |secrets: &[String]| secrets.iter().any(|s| s == secret);
+        if secret.len() < MIN_SECRET_LEN
+            || known(&self.secrets.read().expect("scrubber lock poisoned").0)
+        {
+            return;
+        }
+        let mut guard = self.secrets.write().expect("scrubber lock poisoned");
+        if !known(&guard.0) {
+            let secrets = guard.0.iter().cloned().chain([secret.to_string()]);
+            *guard = literal_regex(secrets);
+        }
+    }
+
+    /// `text` with every secret replaced, borrowed when there were none.
+    pub fn scrub<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if let Some(ref secrets) = self.secrets.read().expect("scrubber lock poisoned").1
+            && let Cow::Owned(scrubbed) = secrets.replace_all(&text, REDACTED)
+        {
+            text = Cow::Owned(scrubbed);
+        }
+        for (regex, replacement) in &self.patterns {
+            if let Cow::Owned(scrubbed) = regex.replace_all(&text, *replacement) {
+                text = Cow::Owned(scrubbed);
+            }
+        }
+        text
+    }
+
+    /// Scrubs every string in `value`, keys included.
+    pub fn scrub_value(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => {
+                if let Cow::Owned(scrubbed) = self.scrub(text) {
+                    *text = scrubbed;
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.scrub_value(v)),
+            Value::Object(fields) => {
+                let scrubbed = std::mem::take(fields)
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        self.scrub_value(&mut value);
+                        (self.scrub(&key).into_owned(), value)
+                    })
+                    .collect();
+                *fields = scrubbed;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A tracing writer that scrubs each event before it is written.
+pub struct ScrubbedWriter<M> {
+    inner: M,
+    scrubber: Arc<Scrubber>,
+}
+
+impl<M> ScrubbedWriter<M> {
+    pub fn new(inner: M, scrubber: Arc<Scrubber>) -> Self {
+        Self { inner, scrubber }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for ScrubbedWriter<M> {
+    type Writer = Scrubbing<'a, M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Scrubbing {
+            writer: self.inner.make_writer(),
+            scrubber: &self.scrubber,
+        }
+    }
+}
+
+/// Formatted events arrive in a single write, so each is scrubbed whole.
+pub struct Scrubbing<'a, W> {
+    writer: W,
+    scrubber: &'a Scrubber,
+}
+
+impl<W: Write> Write for Scrubbing<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.writer
+            .write_all(self.scrubber.scrub(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn scrubs_headers_known_key_formats_and_query_keys() {
+        let scrubber = Scrubber::default();
+        assert_eq!(
+            scrubber.scrub("x-api-key: abc123def456 sent"),
+            "x-api-key: [REDACTED] sent"
+        );
+        assert_eq!(
+            scrubber.scrub(r#"{"Authorization":"Bearer tok.en-value","model":"m"}"#),
+            r#"{"Authorization":"[REDACTED]","model":"m"}"#
+        );
+        assert_eq!(
+            scrubber
+                .scrub("error sending request for url (https://g.example/v1?alt=sse&key=AIzaSyQ)"),
+            "error sending request for url (https://g.example/v1?alt=sse&key=[REDACTED])"
+        );
+        assert_eq!(
+            scrubber.scrub("invalid key sk-ant-api03-abcdefghijkl"),
+            "invalid key [REDACTED]"
+        );
+        assert!(matches!(
+            scrubber.scrub("provider responded status=200"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn scrubs_provider_keys_and_configured_patterns() {
+        let scrubber = Scrubber::new(&[r"corp-[0-9]{4}".to_string()]).unwrap();
+        scrubber.set_secrets(["my-azure-key-0123456789".to_string(), "ollama".to_string()]);
+        assert_eq!(
+            scrubber.scrub("401: key my-azure-key-0123456789 rejected for corp-1234 via ollama"),
+            "401: key [REDACTED] rejected for [REDACTED] via ollama"
+        );
+
+        let mut body = json!({"messages": [{"content": "use my-azure-key-0123456789"}]});
+        scrubber.scrub_value(&mut body);
+        assert_eq!(body["messages"][0]["content"], "use [REDACTED]");
+
+        scrubber.add_secret("key-read-later-0123");
+        assert_eq!(
+            scrubber.scrub("my-azure-key-0123456789 key-read-later-0123"),
+            "[REDACTED] [REDACTED]"
+        );
+
+        scrubber.set_secrets([]);
+        assert_eq!(
+            scrubber.scrub("my-azure-key-0123456789"),
+            "my-azure-key-0123456789"
+        );
+        assert!(Scrubber::new(&["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn writer_scrubs_each_event() {
+        let scrubber = Arc::new(Scrubber::default());
+        let writer = ScrubbedWriter::new(std::sync::Mutex::new(Vec::new()), scrubber);
+        writer
+            .make_writer()
+            .write_all(b"WARN upstream said: x-api-key=sk-ant-xyz12345678\n")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(writer.inner.into_inner().unwrap()).unwrap(),
+            "WARN upstream said: x-api-key=[REDACTED]\n"
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
+
+use crate::config::{AdminConfig, ProviderConfig};
+
+/// A provider's API key. One in `api_key_file` or the keychain is read
+/// when a request first needs it rather than when the config loads, so a
+/// provider that is never used is never read. Clones share what was read.
+#[derive(Clone, Default)]
+pub struct ApiKey(Option<Arc<KeySource>>);
+
+struct KeySource {
+    provider: String,
+    place: Place,
+    key: OnceLock<String>,
+}
+
+enum Place {
+    Given,
+    File(String),
+    Keychain(String),
+}
+
+impl ApiKey {
+    /// Where `provider` keeps its API key, from `api_key`, `api_key_file`,
+    /// or `api_key_keychain`. At most one source may be set, counting the
+    /// token sources in [`crate::tokens`]. Nothing is read yet.
+    pub fn new(name: &str, provider: &ProviderConfig) -> Result<Self, String> {
+        let sources = [
+            provider.api_key.is_some(),
+            provider.api_key_file.is_some(),
+            provider.api_key_keychain.is_some(),
+            provider.token_command.is_some(),
+            provider.oauth.is_some(),
+        ];
+        if sources.iter().filter(|set| **set).count() > 1 {
+            return Err(format!(
+                "provider '{name}' sets more than one of api_key, api_key_file, api_key_keychain, \
+                 token_command, oauth"
+            ));
+        }
+
+        let key = OnceLock::new();
+        let place = if let Some(ref given) = provider.api_key {
+            let _ = key.set(given.clone());
+            Place::Given
+        } else if let Some(ref path) = provider.api_key_file {
+            Place::File(path.clone())
+        } else if let Some(ref entry) = provider.api_key_keychain {
+            Place::Keychain(entry.clone())
+        } else {
+            return Ok(Self::default());
+        };
+        Ok(Self(Some(Arc::new(KeySource {
+            provider: name.to_string(),
+            place,
+            key,
+        }))))
+    }
+
+    /// A key given as is, such as `[default] api_key`.
+    pub fn given(key: &str) -> Self {
+        Self(Some(Arc::new(KeySource {
+            provider: String::new(),
+            place: Place::Given,
+            key: OnceLock::from(key.to_string()),
+        })))
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// The key, read from its file or the keychain if it hasn't been yet,
+    /// blocking while it is read. A read that fails is tried again next
+    /// time. Errors name the provider and the source but never include the
+    /// secret itself.
+    pub fn get(&self) -> Result<Option<&str>, String> {
+        let Some(ref source) = self.0 else {
+            return Ok(None);
+        };
+        if let Some(key) = source.key.get() {
+            return Ok(Some(key));
+        }
+        let read = match source.place {
+            Place::Given => return Ok(None),
+            Place::File(ref path) => read_secret_file("api_key_file", path),
+            Place::Keychain(ref entry) => read_keychain(entry),
+        }
+        .map_err(|e| format!("provider '{}': {e}", source.provider))?;
+        Ok(Some(source.key.get_or_init(|| read)))
+    }
+
+    /// Like [`ApiKey::get`], but reads on the blocking pool, since a
+    /// keychain lookup can wait on the user to allow it.
+    pub async fn load(&self) -> Result<Option<&str>, String> {
+        let Some(ref source) = self.0 else {
+            return Ok(None);
+        };
+        if let Some(key) = source.key.get() {
+            return Ok(Some(key));
+        }
+        let key = self.clone();
+        tokio::task::spawn_blocking(move || key.get().map(|_| ()))
+            .await
+            .map_err(|e| format!("provider '{}': {e}", source.provider))??;
+        Ok(self.cached())
+    }
+
+    /// The key if it has been read, without reading it.
+    pub fn cached(&self) -> Option<&str> {
+        self.0
+            .as_ref()
+            .and_then(|source| source.key.get())
+            .map(String::as_str)
+    }
+}
+
+/// Resolves the admin token from `token`, `token_file`, or
+/// `token_keychain`, at most one of which may be set.
+pub fn resolve_admin_token(admin: &AdminConfig) -> Result<Option<String>, String> {
+    let sources = [
+        admin.token.is_some(),
+        admin.token_file.is_some(),
+        admin.token_keychain.is_some(),
+    ];
+    if sources.iter().filter(|set| **set).count() > 1 {
+        return Err("admin sets more than one of token, token_file, token_keychain".to_string());
+    }
+    if let Some(ref path) = admin.token_file {
+        return read_secret_file("admin.token_file", path).map(Some);
+    }
+    if let Some(ref entry) = admin.token_keychain {
+        return read_keychain(entry).map(Some);
+    }
+    Ok(admin.token.clone())
+}
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir()
+            .map(|h| h.join(rest))
+            .unwrap_or_else(|| PathBuf::from(path)),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Reads a secret from a file, trimming surrounding whitespace so files
+/// written with a trailing newline work. `field` names the setting in
+/// errors.
+pub fn read_secret_file(field: &str, path: &str) -> Result<String, String> {
+    let full = expand_home(path);
+    let content = fs::read_to_string(&full)
+        .map_err(|e| format!("failed to read {field} {}: {e}", full.display()))?;
+    let secret = content.trim();
+    if secret.is_empty() {
+        return Err(format!("{field} {} is empty", full.display()));
+    }
+    Ok(secret.to_string())
+}
+
+/// Splits a `service/account` keychain reference.
+fn parse_keychain_entry(entry: &str) -> Result<(&str, &str), String> {
+    match entry.split_once('/') {
+        Some((service, account)) if !service.is_empty() && !account.is_empty() => {
+            Ok((service, account))
+        }
+        _ => Err(format!(
+            "invalid keychain entry '{entry}', expected \"service/account\""
+        )),
+    }
+}
+
+/// Looks up a generic password in the OS keychain: `security` on macOS,
+/// `secret-tool` (libsecret) elsewhere.
+pub fn read_keychain(entry: &str) -> Result<String, String> {
+    let (service, account) = parse_keychain_entry(entry)?;
+
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("security");
+        cmd.args(["find-generic-password", "-s", service, "-a", account, "-w"]);
+        cmd
+    } else {
+        let mut cmd = Command::new("secret-tool");
+        cmd.args(["lookup", "service", service, "account", account]);
+        cmd
+    };
+
+    let output = cmd
+        .output()
+        .map_err(|e| format!("failed to query keychain for '{entry}': {e}"))?;
+    if !output.status.success() {
+        return Err(format!("keychain entry '{entry}' not found"));
+    }
+    let secret = String::from_utf8(output.stdout)
+        .map_err(|_| format!("keychain entry '{entry}' is not valid UTF-8"))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(format!("keychain entry '{entry}' is empty"));
+    }
+    Ok(secret.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(
+        api_key: Option<&str>,
+        file: Option<&str>,
+        keychain: Option<&str>,
+    ) -> ProviderConfig {
+        ProviderConfig {
+            url: "http://localhost".to_string(),
+            strip_auth: false,
+            api_key: api_key.map(String::from),
+            api_key_file: file.map(String::from),
+            api_key_keychain: keychain.map(String::from),
+            token_command: None,
+            token_ttl_secs: None,
+            oauth: None,
+            stub_count_tokens: false,
+            proxy_url: None,
+            ca_cert: None,
+            insecure_skip_verify: false,
+            http2: false,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_secs: None,
+            keepalive_secs: None,
+            api_format: Default::default(),
+            project: None,
+            region: None,
+            credentials_file: None,
+            api_version: None,
+            cache_control: Default::default(),
+            ratelimit_reserve: None,
+            ratelimit_fallback: None,
+            retry_429_max_wait_secs: None,
+            warm_interval_secs: None,
+            warm_model: None,
+            context_window: None,
+            default_headers: Default::default(),
+            capabilities: Default::default(),
+            capability_fallback: None,
+            missing_models: None,
+            model_check_secs: None,
+            tokenizer: None,
+        }
+    }
+
+    fn resolve_api_key(name: &str, provider: &ProviderConfig) -> Result<Option<String>, String> {
+        let key = ApiKey::new(name, provider)?;
+        key.get().map(|key| key.map(String::from))
+    }
+
+    #[test]
+    fn inline_key_is_returned() {
+        let key = resolve_api_key("a", &provider(Some("sk-1"), None, None)).unwrap();
+        assert_eq!(key.as_deref(), Some("sk-1"));
+    }
+
+    #[test]
+    fn no_source_is_none() {
+        assert_eq!(
+            resolve_api_key("a", &provider(None, None, None)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn file_key_is_trimmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        fs::write(&path, "sk-file\n").unwrap();
+        let key =
+            resolve_api_key("a", &provider(None, Some(path.to_str().unwrap()), None)).unwrap();
+        assert_eq!(key.as_deref(), Some("sk-file"));
+    }
+
+    #[tokio::test]
+    async fn file_keys_are_read_on_first_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        let key = ApiKey::new("a", &provider(None, Some(path.to_str().unwrap()), None)).unwrap();
+        assert!(key.load().await.is_err());
+
+        fs::write(&path, "sk-later\n").unwrap();
+        let shared = key.clone();
+        assert_eq!(key.load().await.unwrap(), Some("sk-later"));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(shared.cached(), Some("sk-later"));
+        assert_eq!(shared.get().unwrap(), Some("sk-later"));
+    }
+
+    #[test]
+    fn empty_file_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        fs::write(&path, "\n").unwrap();
+        let err = resolve_api_key("a", &provider(None, Some(path.to_str().unwrap()), None))
+            .expect_err("should fail");
+        assert!(err.contains("is empty"), "{err}");
+    }
+
+    #[test]
+    fn missing_file_names_provider() {
+        let err = resolve_api_key("ollama", &provider(None, Some("/nonexistent/key"), None))
+            .expect_err("should fail");
+        assert!(err.contains("provider 'ollama'"), "{err}");
+    }
+
+    #[test]
+    fn multiple_sources_are_rejected() {
+        let err = resolve_api_key(
+            "a",
+            &provider(Some("sk-secret-value"), Some("/tmp/k"), None),
+        )
+        .expect_err("should fail");
+        assert!(err.contains("more than one"), "{err}");
+        assert!(
+            !err.contains("sk-secret-value"),
+            "error must not leak the secret: {err}"
+        );
+    }
+
+    #[test]
+    fn admin_token_comes_from_one_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        fs::write(&path, "s3cret\n").unwrap();
+        let from_file = AdminConfig {
+            token_file: Some(path.display().to_string()),
+            ..AdminConfig::default()
+        };
+        assert_eq!(
+            resolve_admin_token(&from_file).unwrap().as_deref(),
+            Some("s3cret")
+        );
+        let both = AdminConfig {
+            token: Some("s3cret".to_string()),
+            ..from_file
+        };
+        assert!(
+            resolve_admin_token(&both)
+                .unwrap_err()
+                .contains("more than one")
+        );
+    }
+
+    #[test]
+    fn keychain_entry_must_have_account() {
+        assert!(parse_keychain_entry("croxy/anthropic").is_ok());
+        assert!(parse_keychain_entry("croxy").is_err());
+        assert!(parse_keychain_entry("/anthropic").is_err());
+    }
+
+    #[test]
+    fn expand_home_only_touches_tilde_prefix() {
+        assert_eq!(expand_home("/etc/key"), PathBuf::from("/etc/key"));
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~/.secrets/x"), home.join(".secrets/x"));
+    }
+}
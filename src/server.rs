@@ -0,0 +1,460 @@
+//! Running croxy inside another binary. [`Server::builder`] wires a config
+//! into proxy state and an axum app the way the `croxy` binary does, and
+//! [`Server::serve`] runs it until its [`ServerHandle`] shuts it down.
+//!
+//! ```no_run
+//! # async fn run(config: croxy::config::Config) -> Result<(), croxy::CroxyError> {
+//! let server = croxy::Server::builder(&config).build()?;
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:3100").await?;
+//! let handle = server.serve(listener)?;
+//! // ...
+//! handle.shutdown().await
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::routing::any;
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, watch};
+use tokio::task::JoinHandle;
+
+use crate::access_log::AccessLog;
+use crate::admin::{self, AdminState};
+use crate::archive::Archive;
+use crate::audit::AuditLog;
+use crate::capture::CaptureStore;
+use crate::chaos::Chaos;
+use crate::clients;
+use crate::compare::Comparer;
+use crate::config::Config;
+use crate::control::{Controller, ReloadFn, Viewers};
+use crate::error::CroxyError;
+use crate::keys::KeyStore;
+use crate::listeners::{KeepAlive, Peer};
+use crate::metrics::MetricsStore;
+use crate::middleware::Pipeline;
+use crate::model_check;
+use crate::notifications::{self, Rules};
+use crate::proxy::{AppState, handle_request};
+use crate::response_headers;
+use crate::router::Router;
+use crate::script::Script;
+use crate::scrub::Scrubber;
+use crate::secrets;
+use crate::tokenizer;
+use crate::tokens;
+use crate::warm;
+
+/// How often expired requests are dropped from metrics.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Builds a [`Server`], or just its [`AppState`], from a config. Anything
+/// not set is built from the config.
+pub struct ServerBuilder<'a> {
+    config: &'a Config,
+    router: Option<Router>,
+    metrics: Option<Arc<MetricsStore>>,
+    keys: KeyStore,
+    captures: Option<Option<Arc<CaptureStore>>>,
+    compare: Option<Option<Arc<Comparer>>>,
+    audit: Option<Arc<AuditLog>>,
+    access_log: Option<Arc<AccessLog>>,
+    scrubber: Option<Arc<Scrubber>>,
+    reload: Option<ReloadFn>,
+}
+
+impl<'a> ServerBuilder<'a> {
+    fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            router: None,
+            metrics: None,
+            keys: KeyStore::disabled(),
+            captures: None,
+            compare: None,
+            audit: None,
+            access_log: None,
+            scrubber: None,
+            reload: None,
+        }
+    }
+
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<MetricsStore>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Client keys; without them every client is let through.
+    pub fn keys(mut self, keys: KeyStore) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Where requests are captured, `None` for nowhere. By default
+    /// `[capture]` decides, and needs a `dir` when it's enabled.
+    pub fn captures(mut self, captures: Option<Arc<CaptureStore>>) -> Self {
+        self.captures = Some(captures);
+        self
+    }
+
+    /// Shadow comparisons, `None` for none. By default `[[compare]]`
+    /// decides, with results only logged.
+    pub fn compare(mut self, compare: Option<Arc<Comparer>>) -> Self {
+        self.compare = Some(compare);
+        self
+    }
+
+    pub fn audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Scrubs secrets from what croxy writes down. It is taught the
+    /// provider keys either way.
+    pub fn scrubber(mut self, scrubber: Arc<Scrubber>) -> Self {
+        self.scrubber = Some(scrubber);
+        self
+    }
+
+    /// Rebuilds the router when a viewer asks for a reload. Without it,
+    /// reloads fail.
+    pub fn reload(mut self, reload: ReloadFn) -> Self {
+        self.reload = Some(reload);
+        self
+    }
+
+    /// Proxy state alone, for binaries that serve it themselves.
+    pub fn state(&mut self) -> Result<AppState, CroxyError> {
+        let config = self.config;
+        let router = match self.router.take() {
+            Some(router) => router,
+            None => Router::from_config(config)?,
+        };
+        let scrubber = match self.scrubber.take() {
+            Some(scrubber) => scrubber,
+            None => Arc::new(
+                Scrubber::new(&config.logging.redact_patterns)
+                    .map_err(|e| CroxyError::Config(format!("logging.redact_patterns: {e}")))?,
+            ),
+        };
+        scrubber.set_secrets(provider_secrets(config, &router));
+        let captures = match self.captures.take() {
+            Some(captures) => captures,
+            None if config.capture.enabled => {
+                let dir = config.capture.dir.as_deref().ok_or_else(|| {
+                    CroxyError::Config("capture.dir must be set to capture requests".to_string())
+                })?;
+                let dir = crate::secrets::expand_home(dir);
+                Some(Arc::new(
+                    CaptureStore::open(dir, config.capture.max_requests)
+                        .map_err(CroxyError::Config)?,
+                ))
+            }
+            None => None,
+        };
+        let archive = config
+            .archive
+            .enabled
+            .then(|| Archive::open(&config.archive, scrubber.clone()).map(Arc::new))
+            .transpose()
+            .map_err(CroxyError::Config)?;
+        let compare = match self.compare.take() {
+            Some(compare) => compare,
+            None => Comparer::from_config(&config.compare, None)
+                .map_err(CroxyError::Config)?
+                .map(Arc::new),
+        };
+        let audit = match self.audit.take() {
+            Some(audit) => audit,
+            None => Arc::new(AuditLog::from_config(&config.logging.audit).map_err(|e| {
+                CroxyError::Config(format!(
+                    "failed to open audit log {}: {e}",
+                    config.logging.audit.path
+                ))
+            })?),
+        };
+        let access_log = match self.access_log.take() {
+            Some(access_log) => access_log,
+            None => Arc::new(
+                AccessLog::from_config(&config.logging.access, scrubber.clone()).map_err(|e| {
+                    CroxyError::Config(format!(
+                        "failed to open access log {}: {e}",
+                        config.logging.access.path
+                    ))
+                })?,
+            ),
+        };
+        let metrics = self
+            .metrics
+            .take()
+            .unwrap_or_else(|| Arc::new(MetricsStore::new(config.retention.duration())));
+
+        let keys = std::mem::replace(&mut self.keys, KeyStore::disabled())
+            .with_quotas(config.clients.clone());
+        for usage in keys.quota_usage() {
+            metrics.record_quota(usage);
+        }
+        metrics.set_billable(
+            config
+                .providers
+                .iter()
+                .filter(|(_, p)| crate::pricing::is_billable_url(&p.url))
+                .map(|(name, _)| name.clone())
+                .collect(),
+        );
+
+        let prometheus = &config.telemetry.prometheus;
+        metrics.set_latency_buckets(
+            prometheus
+                .enabled
+                .then(|| prometheus.latency_buckets_ms.clone()),
+        );
+
+        metrics.set_body_limit(
+            config.server.max_body_size,
+            config.server.body_size_warn_percent,
+        );
+
+        let chaos = Chaos::from_config(&config.chaos).map_err(CroxyError::Config)?;
+        if chaos.is_some() {
+            tracing::warn!("[chaos] is enabled: faults will be injected into requests");
+        }
+
+        Ok(AppState {
+            router: std::sync::RwLock::new(Arc::new(router)),
+            client: clients::default_client(),
+            provider_clients: clients::provider_clients(config).map_err(CroxyError::Config)?,
+            vertex: crate::vertex::providers(config).map_err(CroxyError::Config)?,
+            tokens: tokens::providers(config).map_err(CroxyError::Config)?,
+            tokenizers: tokenizer::providers(config).map_err(CroxyError::Config)?,
+            batches: Default::default(),
+            in_flight: Default::default(),
+            metrics,
+            max_body_size: config.server.max_body_size,
+            stream_buffer_size: config.server.stream_buffer_size,
+            sse_heartbeat: config.server.sse_heartbeat_secs.map(Duration::from_secs),
+            request_timeout: config.server.request_timeout_ms.map(Duration::from_millis),
+            client_header: config.server.client_header.clone(),
+            validate_requests: config.server.validate_requests,
+            tool_results: config.tool_results.clone(),
+            response_headers: response_headers::Policy::from_config(&config.response_headers)
+                .map_err(|e| CroxyError::Config(format!("response_headers.{e}")))?,
+            keys: Arc::new(keys),
+            captures,
+            archive,
+            compare,
+            middleware: Pipeline::from_config(&config.middleware).map_err(CroxyError::Config)?,
+            script: Script::from_config(&config.script).map_err(CroxyError::Config)?,
+            audit,
+            access_log,
+            scrubber,
+            chaos,
+        })
+    }
+
+    pub fn build(mut self) -> Result<Server, CroxyError> {
+        let state = Arc::new(self.state()?);
+        let (shutdown, _) = watch::channel(false);
+        let drain = Arc::new(Notify::new());
+        let token = secrets::resolve_admin_token(&self.config.admin).map_err(CroxyError::Config)?;
+        let admin = admin::serves(self.config, token.as_deref()).then(|| {
+            let reload = self.reload.take().unwrap_or_else(|| {
+                Box::new(|| Err(CroxyError::Config("reload is not supported".to_string())))
+            });
+            Arc::new(AdminState {
+                metrics: state.metrics.clone(),
+                token,
+                shutdown: shutdown.subscribe(),
+                viewers: Viewers::default(),
+                controller: Some(Arc::new(Controller::new(
+                    state.clone(),
+                    reload,
+                    drain.clone(),
+                ))),
+                audit: state.audit.clone(),
+                keys: state.keys.clone(),
+            })
+        });
+        Ok(Server {
+            state,
+            admin,
+            warm: warm::targets(self.config),
+            model_checks: model_check::targets(self.config),
+            notifications: Rules::from_config(self.config),
+            drain_timeout: Duration::from_secs(self.config.server.drain_timeout_secs),
+            keepalive: self.config.server.keepalive(),
+            shutdown,
+            drain,
+        })
+    }
+}
+
+/// The proxy, ready to serve: its state, and the admin API when
+/// `[admin]` is enabled.
+pub struct Server {
+    state: Arc<AppState>,
+    admin: Option<Arc<AdminState>>,
+    warm: Vec<warm::Target>,
+    model_checks: Vec<model_check::Target>,
+    notifications: Option<Rules>,
+    drain_timeout: Duration,
+    keepalive: Option<Duration>,
+    shutdown: watch::Sender<bool>,
+    drain: Arc<Notify>,
+}
+
+impl Server {
+    pub fn builder(config: &Config) -> ServerBuilder<'_> {
+        ServerBuilder::new(config)
+    }
+
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// The axum app, to serve or nest yourself. Warm-up pings, model
+    /// checks, token renewal, notifications, and metrics eviction are left
+    /// to the caller.
+    pub fn into_app(self) -> axum::Router {
+        app(self.state, self.admin)
+    }
+
+    /// Serves on `listener` in the background, along with warm-up pings,
+    /// model checks, token renewal, notifications, and metrics eviction,
+    /// until the handle shuts it down or a viewer asks croxy to stop.
+    pub fn serve(self, listener: TcpListener) -> Result<ServerHandle, CroxyError> {
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(self.shutdown);
+        let mut stop = shutdown.subscribe();
+        let stopping = shutdown.clone();
+        let drain = self.drain;
+        let metrics = self.state.metrics.clone();
+        let background = [
+            spawn_eviction(&metrics),
+            tokio::spawn(warm::run(self.state.clone(), self.warm)),
+            tokio::spawn(model_check::run(self.state.clone(), self.model_checks)),
+            tokio::spawn(tokens::run(self.state.clone())),
+            tokio::spawn(notifications::run(self.state.clone(), self.notifications)),
+        ];
+        let app = app(self.state, self.admin);
+        let listener = KeepAlive::new(listener, self.keepalive);
+        let task = tokio::spawn(async move {
+            let result = axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+                .with_graceful_shutdown(async move {
+                    tokio::select! {
+                        _ = stop.wait_for(|stop| *stop) => {}
+                        () = drain.notified() => {}
+                    }
+                    // Ends admin streams too
+                    stopping.send_replace(true);
+                })
+                .await;
+            for task in background {
+                task.abort();
+            }
+            result.map_err(CroxyError::Io)
+        });
+        Ok(ServerHandle {
+            addr,
+            shutdown,
+            drain_timeout: self.drain_timeout,
+            task,
+        })
+    }
+}
+
+/// A running [`Server`].
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Arc<watch::Sender<bool>>,
+    drain_timeout: Duration,
+    task: JoinHandle<Result<(), CroxyError>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting connections and waits up to
+    /// `server.drain_timeout_secs` for in-flight requests to finish.
+    pub async fn shutdown(mut self) -> Result<(), CroxyError> {
+        self.shutdown.send_replace(true);
+        match tokio::time::timeout(self.drain_timeout, &mut self.task).await {
+            Ok(result) => result.map_err(|e| CroxyError::Internal(e.to_string()))?,
+            Err(_) => {
+                tracing::warn!("drain timeout reached, dropping remaining connections");
+                self.task.abort();
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits for the server to stop on its own, as when a viewer stops it.
+    pub async fn wait(self) -> Result<(), CroxyError> {
+        self.task
+            .await
+            .map_err(|e| CroxyError::Internal(e.to_string()))?
+    }
+}
+
+/// The proxy's axum app: the admin API under [`admin::PREFIX`] when given,
+/// and every other path proxied.
+pub fn app(state: Arc<AppState>, admin: Option<Arc<AdminState>>) -> axum::Router {
+    let mut app = axum::Router::new();
+    if let Some(admin) = admin {
+        app = app.nest_service(admin::PREFIX, admin::router(admin));
+    }
+    app.fallback(any(handle_request))
+        .layer(axum::middleware::map_response_with_state(
+            state.clone(),
+            add_response_headers,
+        ))
+        .with_state(state)
+}
+
+/// Sets `[response_headers] add` on everything croxy answers, its own
+/// errors and the admin API included.
+async fn add_response_headers(
+    State(state): State<Arc<AppState>>,
+    mut response: axum::response::Response,
+) -> axum::response::Response {
+    state.response_headers.add_to(response.headers_mut());
+    response
+}
+
+/// Every provider API key the config holds or the router resolved.
+pub fn provider_secrets(config: &Config, router: &Router) -> Vec<String> {
+    config
+        .providers
+        .values()
+        .filter_map(|provider| provider.api_key.clone())
+        .chain(router.api_keys())
+        .collect()
+}
+
+/// Drops expired requests from `metrics` every minute until aborted.
+pub fn spawn_eviction(metrics: &Arc<MetricsStore>) -> JoinHandle<()> {
+    let metrics = metrics.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            metrics.evict_expired();
+        }
+    })
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::ExitStatus;
+
+const SYSTEMD_UNIT: &str = "croxy.service";
+const LAUNCHD_LABEL: &str = "com.panbanda.croxy";
+
+fn unit_name(instance: Option<&str>) -> String {
+    match instance {
+        Some(name) => format!("croxy-{name}.service"),
+        None => SYSTEMD_UNIT.to_string(),
+    }
+}
+
+fn launchd_label(instance: Option<&str>) -> String {
+    match instance {
+        Some(name) => format!("{LAUNCHD_LABEL}.{name}"),
+        None => LAUNCHD_LABEL.to_string(),
+    }
+}
+
+pub fn systemd_unit(exe: &Path, config_path: &Path, instance: Option<&str>) -> String {
+    let instance_arg = instance
+        .map(|name| format!(" --instance {name}"))
+        .unwrap_or_default();
+    format!(
+        "[Unit]
+Description=croxy - observability proxy for the Anthropic API
+After=network-online.target
+
+[Service]
+ExecStart=\"{exe}\" --config \"{config}\"{instance_arg}
+Restart=on-failure
+RestartSec=2
+
+[Install]
+WantedBy=default.target
+",
+        exe = exe.display(),
+        config = config_path.display(),
+    )
+}
+
+pub fn launchd_plist(
+    exe: &Path,
+    config_path: &Path,
+    log_path: &Path,
+    instance: Option<&str>,
+) -> String {
+    let label = launchd_label(instance);
+    let instance_args = instance
+        .map(|name| format!("\n    <string>--instance</string>\n    <string>{name}</string>"))
+        .unwrap_or_default();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>{exe}</string>
+    <string>--config</string>
+    <string>{config}</string>{instance_args}
+  </array>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <true/>
+  <key>StandardOutPath</key>
+  <string>{log}</string>
+  <key>StandardErrorPath</key>
+  <string>{log}</string>
+</dict>
+</plist>
+"#,
+        exe = xml_escape(&exe.display().to_string()),
+        config = xml_escape(&config_path.display().to_string()),
+        log = xml_escape(&log_path.display().to_string()),
+    )
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn home() -> PathBuf {
+    dirs::home_dir().expect("could not determine home directory")
+}
+
+/// Path where the service definition for this platform is installed.
+pub fn service_path(instance: Option<&str>) -> PathBuf {
+    if cfg!(target_os = "macos") {
+        home()
+            .join("Library/LaunchAgents")
+            .join(format!("{}.plist", launchd_label(instance)))
+    } else {
+        home()
+            .join(".config/systemd/user")
+            .join(unit_name(instance))
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> bool {
+    match Command::new(program).args(args).status() {
+        Ok(status) => status.success(),
+        Err(e) => {
+            eprintln!("failed to run {program}: {e}");
+            false
+        }
+    }
+}
+
+pub fn install(config_path: &Path, log_path: &Path, instance: Option<&str>) {
+    let exe = std::env::current_exe().unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to determine executable path: {e}"))
+    });
+    let config_path = fs::canonicalize(config_path).unwrap_or_else(|_| config_path.to_path_buf());
+
+    let path = service_path(instance);
+    let unit = unit_name(instance);
+    let contents = if cfg!(target_os = "macos") {
+        launchd_plist(&exe, &config_path, log_path, instance)
+    } else {
+        systemd_unit(&exe, &config_path, instance)
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap_or_else(|e| {
+            ExitStatus::Failure.fail(format!("failed to create {}: {e}", parent.display()))
+        });
+    }
+    fs::write(&path, contents).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to write {}: {e}", path.display()))
+    });
+    eprintln!("wrote {}", path.display());
+
+    let started = if cfg!(target_os = "macos") {
+        let path = path.to_string_lossy();
+        run("launchctl", &["load", "-w", &path])
+    } else {
+        run("systemctl", &["--user", "daemon-reload"])
+            && run("systemctl", &["--user", "enable", "--now", &unit])
+    };
+    if !started {
+        ExitStatus::Failure.fail("service file installed but could not be started");
+    }
+    eprintln!("croxy service installed and started");
+}
+
+pub fn uninstall(instance: Option<&str>) {
+    let path = service_path(instance);
+    if !path.exists() {
+        eprintln!("croxy service is not installed");
+        return;
+    }
+
+    if cfg!(target_os = "macos") {
+        let path = path.to_string_lossy();
+        run("launchctl", &["unload", "-w", &path]);
+    } else {
+        run(
+            "systemctl",
+            &["--user", "disable", "--now", &unit_name(instance)],
+        );
+    }
+
+    fs::remove_file(&path).unwrap_or_else(|e| {
+        ExitStatus::Failure.fail(format!("failed to remove {}: {e}", path.display()))
+    });
+    if !cfg!(target_os = "macos") {
+        run("systemctl", &["--user", "daemon-reload"]);
+    }
+    eprintln!("removed {}", path.display());
+}
+
+pub fn status(instance: Option<&str>) {
+    let path = service_path(instance);
+    if !path.exists() {
+        ExitStatus::NotRunning.fail("croxy service is not installed");
+    }
+    eprintln!("service file: {}", path.display());
+
+    let ok = if cfg!(target_os = "macos") {
+        run("launchctl", &["list", LAUNCHD_LABEL])
+    } else {
+        run(
+            "systemctl",
+            &["--user", "status", "--no-pager", SYSTEMD_UNIT],
+        )
+    };
+    if !ok {
+        ExitStatus::NotRunning.exit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn systemd_unit_runs_binary_with_config() {
+        let unit = systemd_unit(
+            Path::new("/usr/local/bin/croxy"),
+            Path::new("/home/u/.config/croxy/config.toml"),
+            None,
+        );
+        assert!(unit.contains(
+            "ExecStart=\"/usr/local/bin/croxy\" --config \"/home/u/.config/croxy/config.toml\""
+        ));
+        assert!(unit.contains("WantedBy=default.target"));
+    }
+
+    #[test]
+    fn launchd_plist_escapes_paths() {
+        let plist = launchd_plist(
+            Path::new("/opt/a&b/croxy"),
+            Path::new("/c.toml"),
+            Path::new("/croxy.log"),
+            None,
+        );
+        assert!(plist.contains("<string>/opt/a&amp;b/croxy</string>"));
+        assert!(plist.contains(LAUNCHD_LABEL));
+        assert!(plist.contains("<string>/croxy.log</string>"));
+    }
+
+    #[test]
+    fn named_instance_gets_its_own_service() {
+        let unit = systemd_unit(Path::new("/bin/croxy"), Path::new("/c.toml"), Some("work"));
+        assert!(unit.ends_with("WantedBy=default.target\n"));
+        assert!(unit.contains("--config \"/c.toml\" --instance work"));
+        assert_eq!(unit_name(Some("work")), "croxy-work.service");
+        assert_eq!(launchd_label(Some("work")), "com.panbanda.croxy.work");
+
+        let plist = launchd_plist(
+            Path::new("/bin/croxy"),
+            Path::new("/c.toml"),
+            Path::new("/l"),
+            Some("work"),
+        );
+        assert!(plist.contains("<string>--instance</string>"));
+        assert!(plist.contains("<string>com.panbanda.croxy.work</string>"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::metrics::RequestRecord;
+use crate::pricing::estimate_cost_usd;
+
+/// Totals for the requests made during a `croxy run` session.
+#[derive(Debug, Default, PartialEq)]
+pub struct SessionSummary {
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost_usd: f64,
+}
+
+impl SessionSummary {
+    /// Summarizes `records`, only counting cost for providers in `billable`.
+    /// Failed requests aren't charged for, so they add no cost.
+    pub fn from_records(records: &[RequestRecord], billable: &HashSet<String>) -> Self {
+        let mut summary = Self::default();
+        for record in records {
+            summary.requests += 1;
+            if record.status >= 400 {
+                summary.errors += 1;
+            }
+            summary.input_tokens += record.input_tokens;
+            summary.output_tokens += record.output_tokens;
+            if record.status < 400 && billable.contains(&record.provider) {
+                summary.cost_usd +=
+                    estimate_cost_usd(&record.model, record.input_tokens, record.output_tokens)
+                        .unwrap_or(0.0);
+            }
+        }
+        summary
+    }
+}
+
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "croxy session: {} requests ({} errors), {} in / {} out tokens, est. cost ${:.4}",
+            self.requests, self.errors, self.input_tokens, self.output_tokens, self.cost_usd
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(model: &str, provider: &str, status: u16) -> RequestRecord {
+        RequestRecord {
+            model: model.to_string(),
+            provider: provider.to_string(),
+            status,
+            input_tokens: 1_000_000,
+            output_tokens: 0,
+            ..RequestRecord::sample()
+        }
+    }
+
+    #[test]
+    fn summarizes_counts_and_the_cost_of_billable_successes() {
+        let records = vec![
+            record("claude-sonnet-4", "anthropic", 200),
+            record("claude-sonnet-4", "ollama", 200),
+            record("claude-opus-4", "anthropic", 500),
+        ];
+        let billable = HashSet::from(["anthropic".to_string()]);
+        let summary = SessionSummary::from_records(&records, &billable);
+        assert_eq!(summary.requests, 3);
+        assert_eq!(summary.errors, 1);
+        assert_eq!(summary.input_tokens, 3_000_000);
+        assert!((summary.cost_usd - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_session_displays_zeroes() {
+        let summary = SessionSummary::from_records(&[], &HashSet::new());
+        assert_eq!(
+            summary.to_string(),
+            "croxy session: 0 requests (0 errors), 0 in / 0 out tokens, est. cost $0.0000"
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! The first-run setup wizard, offered when croxy starts on a terminal
+//! without a config file. It asks which providers to use, offers the
+//! models a local Ollama already has, and writes a config much like the
+//! `croxy init` templates.
+
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+use crate::config::ApiFormat;
+
+pub const ANTHROPIC_URL: &str = "https://api.anthropic.com";
+pub const OLLAMA_URL: &str = "http://localhost:11434";
+
+/// What the wizard was told.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Answers {
+    pub anthropic: bool,
+    /// The Ollama model Sonnet and Haiku go to, or every tier when Ollama
+    /// is the only provider.
+    pub ollama: Option<String>,
+    pub custom: Option<CustomProvider>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomProvider {
+    pub name: String,
+    pub url: String,
+    pub api_format: ApiFormat,
+}
+
+/// The models a local Ollama has pulled, or `None` when nothing answers
+/// at `url`.
+pub async fn probe_ollama(url: &str) -> Option<Vec<String>> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(2))
+        .build()
+        .ok()?;
+    crate::model_check::pulled_models(&client, url).await.ok()
+}
+
+/// Asks its questions on `output` and reads the answers from `input`.
+pub struct Wizard<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Runs the questions. `ollama_models` is what `probe_ollama` found.
+    pub fn answers(&mut self, ollama_models: Option<&[String]>) -> io::Result<Answers> {
+        writeln!(
+            self.output,
+            "\ncroxy {} -- first-run setup\n",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        match ollama_models {
+            Some(models) if !models.is_empty() => writeln!(
+                self.output,
+                "Found Ollama at {OLLAMA_URL} with {} model(s).",
+                models.len()
+            )?,
+            Some(_) => writeln!(
+                self.output,
+                "Found Ollama at {OLLAMA_URL}, but no models are pulled yet."
+            )?,
+            None => writeln!(self.output, "Ollama is not running at {OLLAMA_URL}.")?,
+        }
+        writeln!(
+            self.output,
+            "\nWhich providers should croxy send requests to?\n  1) Anthropic\n  2) Ollama (local models)\n  3) Another provider"
+        )?;
+        let default = if ollama_models.is_some_and(|m| !m.is_empty()) {
+            "1,2"
+        } else {
+            "1"
+        };
+        let picks = loop {
+            let answer = self.ask("Providers, comma-separated", default)?;
+            match parse_picks(&answer) {
+                Some(picks) => break picks,
+                None => writeln!(self.output, "Pick one or more of 1, 2, and 3.")?,
+            }
+        };
+
+        let mut answers = Answers {
+            anthropic: picks.contains(&1),
+            ..Answers::default()
+        };
+        if picks.contains(&2) {
+            answers.ollama = Some(self.ollama_model(ollama_models.unwrap_or_default())?);
+        }
+        if picks.contains(&3) {
+            answers.custom = Some(self.custom_provider()?);
+        }
+        Ok(answers)
+    }
+
+    /// Asks whether to do something, defaulting to yes.
+    pub fn confirm(&mut self, question: &str) -> io::Result<bool> {
+        let answer = self.ask(question, "Y/n")?;
+        Ok(!answer.eq_ignore_ascii_case("n") && !answer.eq_ignore_ascii_case("no"))
+    }
+
+    fn ollama_model(&mut self, models: &[String]) -> io::Result<String> {
+        if !models.is_empty() {
+            writeln!(self.output, "\nOllama models:")?;
+            for (i, model) in models.iter().enumerate() {
+                writeln!(self.output, "  {}) {model}", i + 1)?;
+            }
+        }
+        let default = models.first().map_or("qwen3-coder:30b", String::as_str);
+        let answer = self.ask("Ollama model to route to", default)?;
+        Ok(answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| models.get(n.wrapping_sub(1)))
+            .cloned()
+            .unwrap_or(answer))
+    }
+
+    fn custom_provider(&mut self) -> io::Result<CustomProvider> {
+        let name = loop {
+            let name = self.ask("\nName for the provider", "custom")?;
+            if is_provider_name(&name) {
+                break name;
+            }
+            writeln!(
+                self.output,
+                "Use letters, digits, '-' and '_', other than anthropic and ollama."
+            )?;
+        };
+        let url = loop {
+            let url = self.ask("Its base URL", "")?;
+            if url.starts_with("http://") || url.starts_with("https://") {
+                break url;
+            }
+            writeln!(self.output, "The URL must start with http:// or https://.")?;
+        };
+        let api_format = loop {
+            let answer = self.ask(
+                "API it speaks (anthropic, ollama, gemini, azure)",
+                "anthropic",
+            )?;
+            match answer.to_ascii_lowercase().as_str() {
+                "anthropic" => break ApiFormat::Anthropic,
+                "ollama" => break ApiFormat::Ollama,
+                "gemini" => break ApiFormat::Gemini,
+                "azure" => break ApiFormat::Azure,
+                _ => writeln!(self.output, "Unknown API '{answer}'.")?,
+            }
+        };
+        Ok(CustomProvider {
+            name,
+            url,
+            api_format,
+        })
+    }
+
+    /// Prints `question`, then returns the trimmed answer, or `default`
+    /// when it is empty.
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+        if default.is_empty() {
+            write!(self.output, "{question}: ")?;
+        } else {
+            write!(self.output, "{question} [{default}]: ")?;
+        }
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "setup cancelled",
+            ));
+        }
+        let answer = line.trim();
+        Ok(if answer.is_empty() { default } else { answer }.to_string())
+    }
+}
+
+/// The provider numbers in `answer`, or `None` if any is unknown or there
+/// are none.
+fn parse_picks(answer: &str) -> Option<Vec<u8>> {
+    let picks = answer
+        .split([',', ' '])
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().ok().filter(|n| (1..=3).contains(n)))
+        .collect::<Option<Vec<u8>>>()?;
+    (!picks.is_empty()).then_some(picks)
+}
+
+fn is_provider_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "anthropic"
+        && name != "ollama"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The config for `answers`. Anthropic is the default provider when
+/// chosen, then the custom provider, then Ollama.
+pub fn render(answers: &Answers) -> String {
+    let mut out = String::from(
+        "# croxy config: written by the setup wizard\n#\n# `croxy init --template` has other starting points.\n",
+    );
+    if answers.anthropic {
+        out.push_str(&format!(
+            "\n[provider.anthropic]\nurl = \"{ANTHROPIC_URL}\"\n"
+        ));
+    }
+    if answers.ollama.is_some() {
+        out.push_str(&format!(
+            "\n[provider.ollama]\nurl = \"{OLLAMA_URL}\"\n\
+             # Ollama ignores the Anthropic key, so don't send it anywhere\n\
+             strip_auth = true\napi_key = \"ollama\"\nstub_count_tokens = true\n"
+        ));
+    }
+    if let Some(custom) = &answers.custom {
+        out.push_str(&format!(
+            "\n[provider.{}]\nurl = {}\n",
+            custom.name,
+            toml_string(&custom.url)
+        ));
+        if custom.api_format != ApiFormat::Anthropic {
+            out.push_str(&format!("api_format = \"{}\"\n", custom.api_format));
+        }
+        out.push_str("# api_key_file = \"~/.config/croxy/provider.key\"\n");
+    }
+
+    if let Some(model) = &answers.ollama {
+        let pattern = if answers.anthropic || answers.custom.is_some() {
+            "sonnet|haiku"
+        } else {
+            "opus|sonnet|haiku"
+        };
+        out.push_str(&format!(
+            "\n[[routes]]\npattern = \"{pattern}\"\nprovider = \"ollama\"\nmodel = {}\n",
+            toml_string(model)
+        ));
+    }
+
+    let default = if answers.anthropic {
+        "anthropic"
+    } else if let Some(custom) = &answers.custom {
+        &custom.name
+    } else {
+        "ollama"
+    };
+    out.push_str(&format!("\n[default]\nprovider = \"{default}\"\n"));
+    out
+}
+
+fn toml_string(s: &str) -> String {
+    serde_json::Value::String(s.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli_config::validate_config_str;
+    use std::path::Path;
+
+    fn run(input: &str, models: Option<&[String]>) -> Answers {
+        Wizard::new(input.as_bytes(), Vec::new())
+            .answers(models)
+            .unwrap()
+    }
+
+    #[test]
+    fn defaults_follow_what_the_probe_found() {
+        let models = vec!["qwen3-coder:30b".to_string(), "qwen3:8b".to_string()];
+        assert_eq!(
+            run("\n\n", Some(&models)),
+            Answers {
+                anthropic: true,
+                ollama: Some("qwen3-coder:30b".to_string()),
+                custom: None,
+            }
+        );
+        assert_eq!(
+            run("\n", None),
+            Answers {
+                anthropic: true,
+                ..Answers::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reasks_until_answers_are_usable() {
+        let models = vec!["llama3".to_string(), "qwen3:8b".to_string()];
+        let answers = run(
+            "4\n2,3\n2\nollama\nmy gw\ngw\nftp://x\nhttps://gw.example\nsoap\nazure\n",
+            Some(&models),
+        );
+        assert_eq!(
+            answers,
+            Answers {
+                anthropic: false,
+                ollama: Some("qwen3:8b".to_string()),
+                custom: Some(CustomProvider {
+                    name: "gw".to_string(),
+                    url: "https://gw.example".to_string(),
+                    api_format: ApiFormat::Azure,
+                }),
+            }
+        );
+        assert!(
+            Wizard::new("".as_bytes(), Vec::new())
+                .answers(None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rendered_configs_are_valid() {
+        let custom = CustomProvider {
+            name: "gw".to_string(),
+            url: "https://gw.example".to_string(),
+            api_format: ApiFormat::Anthropic,
+        };
+        for answers in [
+            Answers {
+                anthropic: true,
+                ..Answers::default()
+            },
+            Answers {
+                ollama: Some("qwen3:8b".to_string()),
+                ..Answers::default()
+            },
+            Answers {
+                anthropic: true,
+                ollama: Some("qwen3:8b".to_string()),
+                custom: Some(custom.clone()),
+            },
+            Answers {
+                custom: Some(custom.clone()),
+                ..Answers::default()
+            },
+        ] {
+            let content = render(&answers);
+            validate_config_str(&content, Path::new("/"))
+                .unwrap_or_else(|e| panic!("{answers:?}: {e}\n{content}"));
+        }
+
+        let content = render(&Answers {
+            ollama: Some("qwen3:8b".to_string()),
+            custom: Some(custom),
+            ..Answers::default()
+        });
+        assert!(content.contains("pattern = \"sonnet|haiku\""));
+        assert!(content.ends_with("[default]\nprovider = \"gw\"\n"));
+    }
+}
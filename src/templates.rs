@@ -0,0 +1,64 @@
+/// Starter configs offered by `croxy init --template`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Template {
+    /// Opus to Anthropic, Sonnet and Haiku to local Ollama
+    #[default]
+    CostSaver,
+    /// Everything to local Ollama models
+    ClaudeCodeLocal,
+    /// Everything through a shared team gateway
+    TeamGateway,
+    /// Plain passthrough to Anthropic
+    Minimal,
+}
+
+impl Template {
+    pub const ALL: [Template; 4] = [
+        Template::CostSaver,
+        Template::ClaudeCodeLocal,
+        Template::TeamGateway,
+        Template::Minimal,
+    ];
+
+    pub fn content(self) -> &'static str {
+        match self {
+            Template::CostSaver => include_str!("templates/cost-saver.toml"),
+            Template::ClaudeCodeLocal => include_str!("templates/claude-code-local.toml"),
+            Template::TeamGateway => include_str!("templates/team-gateway.toml"),
+            Template::Minimal => include_str!("templates/minimal.toml"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::validate::{check_config, unknown_keys};
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    #[test]
+    fn templates_are_valid_configs() {
+        for template in Template::ALL {
+            let file = Figment::from(Toml::string(template.content()));
+            let config: Config = file
+                .extract()
+                .unwrap_or_else(|e| panic!("{template:?}: {e}"));
+            let unknown = unknown_keys::<Config>(file.extract().unwrap());
+            assert!(unknown.is_empty(), "{template:?}: {unknown:?}");
+            let errors = check_config(&config);
+            assert!(errors.is_empty(), "{template:?}: {errors:?}");
+        }
+    }
+
+    #[test]
+    fn templates_start_with_a_description() {
+        for template in Template::ALL {
+            assert!(
+                template.content().starts_with("# croxy config: "),
+                "{template:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Per-tenant usage for `croxy status`. A tenant is a virtual key: its
+//! requests are recorded under the key's name, `[clients.NAME]` gives it
+//! quotas and pins of its own, and the key itself carries its budget. A
+//! key holder asking for status with their own key sees only this.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::keys::QuotaUsage;
+use crate::metrics::RequestRecord;
+use crate::tui::views::format_tokens;
+
+/// One tenant's requests among the records croxy holds, with its spend
+/// and quotas.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TenantUsage {
+    pub name: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// What the key has spent in all, not just in the records held.
+    pub spent_usd: f64,
+    pub budget_usd: Option<f64>,
+    #[serde(default)]
+    pub quota: Option<QuotaUsage>,
+    /// The same counts by model, busiest first.
+    #[serde(default)]
+    pub models: Vec<ModelUsage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelUsage {
+    pub model: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl ModelUsage {
+    fn add(&mut self, record: &RequestRecord) {
+        self.requests += 1;
+        if record.status >= 400 {
+            self.errors += 1;
+        }
+        self.input_tokens += record.input_tokens;
+        self.output_tokens += record.output_tokens;
+    }
+}
+
+impl TenantUsage {
+    /// Counts the requests in `records` made with the key called `name`.
+    pub fn from_records(name: &str, records: &[RequestRecord]) -> Self {
+        let mut total = ModelUsage::default();
+        let mut models: BTreeMap<&str, ModelUsage> = BTreeMap::new();
+        for record in records.iter().filter(|r| r.client.as_deref() == Some(name)) {
+            total.add(record);
+            models
+                .entry(&record.model)
+                .or_insert_with(|| ModelUsage {
+                    model: record.model.clone(),
+                    ..ModelUsage::default()
+                })
+                .add(record);
+        }
+        let mut models: Vec<ModelUsage> = models.into_values().collect();
+        models.sort_by_key(|m| std::cmp::Reverse(m.requests));
+        Self {
+            name: name.to_string(),
+            requests: total.requests,
+            errors: total.errors,
+            input_tokens: total.input_tokens,
+            output_tokens: total.output_tokens,
+            models,
+            ..Self::default()
+        }
+    }
+
+    /// The usage as `croxy status` prints it.
+    pub fn lines(&self) -> Vec<String> {
+        let spend = match self.budget_usd {
+            Some(budget) => format!("${:.2} of ${budget:.2} spent", self.spent_usd),
+            None => format!("${:.2} spent", self.spent_usd),
+        };
+        let mut lines = vec![format!(
+            "tenant {}: {} requests ({} errors), {} in / {} out tokens, {spend}",
+            self.name,
+            self.requests,
+            self.errors,
+            format_tokens(self.input_tokens),
+            format_tokens(self.output_tokens),
+        )];
+        if let Some(ref quota) = self.quota {
+            let (today, month) = quota.current(Utc::now().date_naive());
+            let limit = |limit: Option<u64>| limit.map_or("unlimited".to_string(), format_tokens);
+            lines.push(format!(
+                "  quota: {} of {} today, {} of {} this month",
+                format_tokens(today),
+                limit(quota.daily_limit),
+                format_tokens(month),
+                limit(quota.monthly_limit),
+            ));
+        }
+        for model in &self.models {
+            lines.push(format!(
+                "  {}: {} requests ({} errors), {} in / {} out tokens",
+                model.model,
+                model.requests,
+                model.errors,
+                format_tokens(model.input_tokens),
+                format_tokens(model.output_tokens),
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(client: &str, model: &str, status: u16) -> RequestRecord {
+        RequestRecord {
+            model: model.to_string(),
+            status,
+            output_tokens: 10,
+            client: Some(client.to_string()),
+            ..RequestRecord::sample()
+        }
+    }
+
+    #[test]
+    fn usage_counts_only_the_tenants_requests() {
+        let records = vec![
+            record("alice", "claude-sonnet-4", 200),
+            record("alice", "claude-sonnet-4", 500),
+            record("alice", "claude-haiku-4", 200),
+            record("bob", "claude-sonnet-4", 200),
+        ];
+        let usage = TenantUsage::from_records("alice", &records);
+        assert_eq!((usage.requests, usage.errors), (3, 1));
+        assert_eq!((usage.input_tokens, usage.output_tokens), (300, 30));
+        assert_eq!(usage.models.len(), 2);
+        assert_eq!(usage.models[0].model, "claude-sonnet-4");
+        assert_eq!((usage.models[0].requests, usage.models[0].errors), (2, 1));
+
+        let nobody = TenantUsage::from_records("carol", &records);
+        assert_eq!(nobody.requests, 0);
+        assert!(nobody.models.is_empty());
+    }
+}
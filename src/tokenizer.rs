@@ -0,0 +1,188 @@
+//! Output token counts from a model's own tokenizer. Local servers often
+//! leave usage out of their responses, and croxy then estimates a token
+//! for every four bytes it relays, event framing included. A provider
+//! with `tokenizer` set to a Hugging Face `tokenizer.json` instead has the
+//! text its responses generated counted with it. Needs croxy built with
+//! `--features tokenizer`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::config::Config;
+
+pub struct Tokenizer {
+    #[cfg(feature = "tokenizer")]
+    inner: tokenizers::Tokenizer,
+}
+
+impl Tokenizer {
+    /// Loads a `tokenizer.json` (`~/` is expanded).
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let path = crate::secrets::expand_home(path);
+        if !path.is_file() {
+            return Err(format!("{} does not exist", path.display()));
+        }
+        Self::load(&path)
+    }
+
+    #[cfg(not(feature = "tokenizer"))]
+    fn load(_path: &std::path::Path) -> Result<Self, String> {
+        Err(
+            "this croxy was built without tokenizers; rebuild with --features tokenizer"
+                .to_string(),
+        )
+    }
+
+    #[cfg(not(feature = "tokenizer"))]
+    pub fn count(&self, _text: &str) -> u64 {
+        0
+    }
+
+    #[cfg(feature = "tokenizer")]
+    fn load(path: &std::path::Path) -> Result<Self, String> {
+        tokenizers::Tokenizer::from_file(path)
+            .map(|inner| Self { inner })
+            .map_err(|e| format!("invalid tokenizer {}: {e}", path.display()))
+    }
+
+    /// Tokens in `text`, without the special tokens a prompt would get.
+    #[cfg(feature = "tokenizer")]
+    pub fn count(&self, text: &str) -> u64 {
+        match self.inner.encode(text, false) {
+            Ok(encoding) => encoding.len() as u64,
+            Err(e) => {
+                tracing::warn!("failed to count tokens: {e}");
+                0
+            }
+        }
+    }
+}
+
+/// The tokenizer of each provider with `tokenizer` set.
+pub fn providers(config: &Config) -> Result<HashMap<String, Arc<Tokenizer>>, String> {
+    let mut tokenizers = HashMap::new();
+    for (name, provider) in &config.providers {
+        if let Some(ref path) = provider.tokenizer {
+            let tokenizer =
+                Tokenizer::from_file(path).map_err(|e| format!("provider '{name}': {e}"))?;
+            tokenizers.insert(name.clone(), Arc::new(tokenizer));
+        }
+    }
+    Ok(tokenizers)
+}
+
+/// What a Messages response, streamed or not, generated: its text,
+/// thinking, and tool call input, one block after another.
+pub fn generated_text(body: &[u8]) -> String {
+    if let Ok(message) = serde_json::from_slice::<Value>(body) {
+        let blocks = message["content"].as_array().into_iter().flatten();
+        return blocks
+            .filter_map(|block| match block["type"].as_str() {
+                Some("text") => block["text"].as_str().map(str::to_string),
+                Some("thinking") => block["thinking"].as_str().map(str::to_string),
+                Some("tool_use") => Some(block["input"].to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+    let mut text = String::new();
+    let events = String::from_utf8_lossy(body);
+    for data in events.lines().filter_map(|l| l.strip_prefix("data:")) {
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+            continue;
+        };
+        let delta = &event["delta"];
+        match event["type"].as_str() {
+            Some("content_block_start") if !text.is_empty() => text.push('\n'),
+            Some("content_block_delta") => {
+                let piece = match delta["type"].as_str() {
+                    Some("text_delta") => delta["text"].as_str(),
+                    Some("thinking_delta") => delta["thinking"].as_str(),
+                    Some("input_json_delta") => delta["partial_json"].as_str(),
+                    _ => None,
+                };
+                text.push_str(piece.unwrap_or_default());
+            }
+            _ => {}
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_text_is_read_from_streams_and_messages() {
+        let stream = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":5}}}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"Let me \"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"look.\"}}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":1}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"path\\\":\"}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\\\"a.rs\\\"}\"}}\n\n",
+        );
+        assert_eq!(
+            generated_text(stream.as_bytes()),
+            "Let me look.\n{\"path\":\"a.rs\"}"
+        );
+
+        let message = serde_json::json!({
+            "content": [
+                {"type": "thinking", "thinking": "Hmm."},
+                {"type": "text", "text": "Done."},
+            ],
+        });
+        assert_eq!(
+            generated_text(message.to_string().as_bytes()),
+            "Hmm.\nDone."
+        );
+    }
+
+    #[cfg(not(feature = "tokenizer"))]
+    #[test]
+    fn tokenizers_need_the_feature() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let err = Tokenizer::from_file(file.path().to_str().unwrap())
+            .err()
+            .unwrap();
+        assert!(err.contains("--features tokenizer"), "{err}");
+    }
+
+    #[cfg(feature = "tokenizer")]
+    #[test]
+    fn tokens_are_counted_with_the_tokenizer() {
+        let vocab = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0, "hello": 1, "world": 2, ".": 3},
+                "unk_token": "[UNK]",
+            },
+        });
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), vocab.to_string()).unwrap();
+        let tokenizer = Tokenizer::from_file(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(tokenizer.count("hello world."), 3);
+        assert_eq!(tokenizer.count("hello there world"), 3);
+    }
+}
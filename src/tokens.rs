@@ -0,0 +1,359 @@
+//! Short-lived bearer tokens for providers behind OAuth, such as a
+//! corporate gateway: printed by `token_command`, or fetched with the
+//! client credentials grant of `[provider.NAME.oauth]`. A token is fetched
+//! when first needed and renewed in the background shortly before it
+//! expires; if renewal fails, the old token is used while it lasts.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::{Config, OAuthConfig, ProviderConfig};
+use crate::metrics::MetricsStore;
+use crate::proxy::AppState;
+use crate::secrets::read_secret_file;
+
+/// Tokens are renewed this long before they expire.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long a `token_command` token is used without `token_ttl_secs`.
+const DEFAULT_COMMAND_TTL: Duration = Duration::from_secs(300);
+
+/// How long an OAuth token is used when the response doesn't say.
+const DEFAULT_OAUTH_TTL: Duration = Duration::from_secs(3600);
+
+/// How long `token_command` may run.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often tokens are checked for renewal.
+const RENEW_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The outcome of a provider's latest token fetch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthStatus {
+    pub provider: String,
+    pub checked: DateTime<Utc>,
+    /// When the token in use expires, when known.
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+enum Source {
+    Command {
+        command: Vec<String>,
+        ttl: Duration,
+    },
+    ClientCredentials {
+        token_url: String,
+        client_id: String,
+        client_secret: String,
+        scope: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Hands out one provider's bearer tokens.
+pub struct ProviderToken {
+    provider: String,
+    source: Source,
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+impl ProviderToken {
+    /// The token source `provider` sets, if any.
+    pub fn from_config(name: &str, provider: &ProviderConfig) -> Result<Option<Self>, String> {
+        let source = match (&provider.token_command, &provider.oauth) {
+            (Some(command), None) => {
+                if command.is_empty() {
+                    return Err(format!("provider '{name}': token_command is empty"));
+                }
+                Source::Command {
+                    command: command.clone(),
+                    ttl: provider
+                        .token_ttl_secs
+                        .map_or(DEFAULT_COMMAND_TTL, Duration::from_secs),
+                }
+            }
+            (None, Some(oauth)) => {
+                client_credentials(oauth).map_err(|e| format!("provider '{name}': {e}"))?
+            }
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "provider '{name}' sets both token_command and oauth"
+                ));
+            }
+        };
+        Ok(Some(Self {
+            provider: name.to_string(),
+            source,
+            cached: Mutex::new(None),
+        }))
+    }
+
+    /// A token valid for at least [`EXPIRY_MARGIN`] where one can be had,
+    /// fetching a new one if need be. Each fetch is recorded in `metrics`.
+    pub async fn token(
+        &self,
+        client: &reqwest::Client,
+        metrics: &MetricsStore,
+    ) -> Result<String, String> {
+        // Held across the fetch so concurrent requests wait for one
+        let mut cached = self.cached.lock().await;
+        let now = Instant::now();
+        if let Some((ref token, expires)) = *cached
+            && now + EXPIRY_MARGIN < expires
+        {
+            return Ok(token.clone());
+        }
+        let fetched = self.fetch(client).await;
+        let status = |expires: Option<Instant>, error| AuthStatus {
+            provider: self.provider.clone(),
+            checked: Utc::now(),
+            expires: expires.map(wallclock),
+            error,
+        };
+        match fetched {
+            Ok((token, ttl)) => {
+                let expires = now + ttl;
+                tracing::debug!(provider = %self.provider, ttl_secs = ttl.as_secs(), "fetched token");
+                metrics.record_auth(status(Some(expires), None));
+                *cached = Some((token.clone(), expires));
+                Ok(token)
+            }
+            Err(e) => {
+                tracing::warn!(provider = %self.provider, "token renewal failed: {e}");
+                let still_valid = cached.as_ref().filter(|(_, expires)| now < *expires);
+                metrics.record_auth(status(still_valid.map(|(_, at)| *at), Some(e.clone())));
+                match still_valid {
+                    Some((token, _)) => Ok(token.clone()),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn fetch(&self, client: &reqwest::Client) -> Result<(String, Duration), String> {
+        match self.source {
+            Source::Command { ref command, ttl } => {
+                run_command(command).await.map(|token| (token, ttl))
+            }
+            Source::ClientCredentials {
+                ref token_url,
+                ref client_id,
+                ref client_secret,
+                ref scope,
+            } => {
+                let mut form = vec![
+                    ("grant_type", "client_credentials"),
+                    ("client_id", client_id.as_str()),
+                    ("client_secret", client_secret.as_str()),
+                ];
+                if let Some(scope) = scope {
+                    form.push(("scope", scope));
+                }
+                let response = client
+                    .post(token_url)
+                    .form(&form)
+                    .send()
+                    .await
+                    .map_err(|e| format!("failed to fetch OAuth token: {}", e.without_url()))?;
+                let status = response.status();
+                if !status.is_success() {
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(format!(
+                        "failed to fetch OAuth token: HTTP {status}: {body}"
+                    ));
+                }
+                let response: TokenResponse = response
+                    .json()
+                    .await
+                    .map_err(|e| format!("invalid OAuth token response: {e}"))?;
+                let ttl = response
+                    .expires_in
+                    .map_or(DEFAULT_OAUTH_TTL, Duration::from_secs);
+                Ok((response.access_token, ttl))
+            }
+        }
+    }
+}
+
+/// The wall clock time of `at`.
+fn wallclock(at: Instant) -> DateTime<Utc> {
+    let left = at.saturating_duration_since(Instant::now());
+    Utc::now() + chrono::Duration::from_std(left).unwrap_or_default()
+}
+
+fn client_credentials(oauth: &OAuthConfig) -> Result<Source, String> {
+    let client_secret = match (&oauth.client_secret, &oauth.client_secret_file) {
+        (Some(secret), None) => secret.clone(),
+        (None, Some(path)) => read_secret_file("oauth.client_secret_file", path)?,
+        (None, None) => return Err("oauth needs client_secret or client_secret_file".to_string()),
+        (Some(_), Some(_)) => {
+            return Err("oauth sets both client_secret and client_secret_file".to_string());
+        }
+    };
+    Ok(Source::ClientCredentials {
+        token_url: oauth.token_url.clone(),
+        client_id: oauth.client_id.clone(),
+        client_secret,
+        scope: oauth.scope.clone(),
+    })
+}
+
+/// Runs `command` and returns what it printed, trimmed.
+async fn run_command(command: &[String]) -> Result<String, String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err("token_command is empty".to_string());
+    };
+    let child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+    let output = match tokio::time::timeout(COMMAND_TIMEOUT, child).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("failed to run token_command {program}: {e}")),
+        Err(_) => return Err(format!("token_command {program} timed out")),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "token_command {program} exited with {}: {}",
+            output.status,
+            stderr.trim()
+        ));
+    }
+    let token = String::from_utf8(output.stdout)
+        .map_err(|_| format!("token_command {program} printed invalid UTF-8"))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("token_command {program} printed nothing"));
+    }
+    Ok(token.to_string())
+}
+
+/// Sets up every provider with a token source.
+pub fn providers(config: &Config) -> Result<HashMap<String, Arc<ProviderToken>>, String> {
+    let mut tokens = HashMap::new();
+    for (name, provider) in &config.providers {
+        if let Some(token) = ProviderToken::from_config(name, provider)? {
+            tokens.insert(name.clone(), Arc::new(token));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Renews each provider's token shortly before it expires, so requests
+/// don't wait on a fetch and failures show before one needs the token.
+/// Runs until dropped.
+pub async fn run(state: Arc<AppState>) {
+    if state.tokens.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(RENEW_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        for (name, token) in &state.tokens {
+            // Failures are recorded and logged by the fetch
+            let _ = token.token(state.client_for(name), &state.metrics).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn config(toml: &str) -> Config {
+        Figment::new().merge(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[tokio::test]
+    async fn command_tokens_are_reused_until_they_near_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let config = config(&format!(
+            r#"
+            [provider.gateway]
+            url = "https://llm.corp.internal"
+            token_command = ["sh", "-c", "echo x >> {0}; echo token-$(wc -l < {0})"]
+            token_ttl_secs = 3600
+            "#,
+            counter.display()
+        ));
+        let tokens = providers(&config).unwrap();
+        let metrics = MetricsStore::new(Duration::from_secs(60));
+        let client = reqwest::Client::new();
+        let token = &tokens["gateway"];
+
+        assert_eq!(token.token(&client, &metrics).await.unwrap(), "token-1");
+        assert_eq!(token.token(&client, &metrics).await.unwrap(), "token-1");
+        let status = &metrics.auth()[0];
+        assert_eq!(status.provider, "gateway");
+        assert_eq!(status.error, None);
+        assert!(status.expires.unwrap() > Utc::now() + chrono::Duration::minutes(59));
+
+        // Within the margin of expiry, the next request fetches a new one
+        *token.cached.lock().await = Some(("token-1".to_string(), Instant::now() + EXPIRY_MARGIN));
+        assert_eq!(token.token(&client, &metrics).await.unwrap(), "token-2");
+    }
+
+    #[tokio::test]
+    async fn failed_renewals_keep_the_old_token_while_it_lasts() {
+        let config = config(
+            r#"
+            [provider.gateway]
+            url = "https://llm.corp.internal"
+            token_command = ["false"]
+            "#,
+        );
+        let tokens = providers(&config).unwrap();
+        let metrics = MetricsStore::new(Duration::from_secs(60));
+        let client = reqwest::Client::new();
+        let token = &tokens["gateway"];
+
+        let err = token.token(&client, &metrics).await.unwrap_err();
+        assert!(err.contains("token_command false exited"), "{err}");
+        assert!(metrics.auth()[0].error.is_some());
+
+        *token.cached.lock().await =
+            Some(("old".to_string(), Instant::now() + Duration::from_secs(30)));
+        assert_eq!(token.token(&client, &metrics).await.unwrap(), "old");
+        let status = &metrics.auth()[0];
+        assert!(status.error.is_some());
+        assert!(status.expires.is_some());
+    }
+
+    #[test]
+    fn oauth_needs_one_client_secret() {
+        let err = |oauth: &str| {
+            let config = config(&format!(
+                "[provider.gateway]\nurl = \"https://llm.corp.internal\"\n\
+                 [provider.gateway.oauth]\ntoken_url = \"https://login.corp.internal/token\"\n\
+                 client_id = \"croxy\"\n{oauth}"
+            ));
+            providers(&config).err()
+        };
+        assert_eq!(err("client_secret = \"s3cret\"\n"), None);
+        assert_eq!(
+            err("").as_deref(),
+            Some("provider 'gateway': oauth needs client_secret or client_secret_file")
+        );
+    }
+}
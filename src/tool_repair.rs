@@ -0,0 +1,348 @@
+//! `repair_tool_calls`: fixes the malformed tool call JSON weak local
+//! models produce. A tool call whose input doesn't parse fails in the
+//! client, so on routes that ask for it each `tool_use` block is checked
+//! once it is complete and repaired where the mistake is a common one:
+//! trailing commas, unescaped quotes or control characters in strings,
+//! code fences, and missing closing brackets.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+
+use crate::translate::{LineBuffer, sse_event};
+
+/// What checking a tool call's input found.
+#[derive(Debug, PartialEq)]
+pub enum Checked {
+    Valid,
+    Repaired(Value),
+    /// Malformed beyond what can be fixed; left as it was.
+    Broken,
+}
+
+/// Checks `input`, the text of a tool call's arguments, which must be a
+/// JSON object.
+pub fn check(input: &str) -> Checked {
+    if serde_json::from_str::<Value>(input).is_ok_and(|v| v.is_object()) {
+        return Checked::Valid;
+    }
+    match serde_json::from_str::<Value>(&repair(input)) {
+        Ok(value) if value.is_object() => Checked::Repaired(value),
+        _ => Checked::Broken,
+    }
+}
+
+/// `input` with the usual mistakes fixed, for a parse to try again.
+fn repair(input: &str) -> String {
+    let input = strip_fence(input.trim());
+    let chars: Vec<char> = input.chars().collect();
+    let mut out = String::with_capacity(input.len() + 8);
+    let mut open = Vec::new();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if in_string {
+            match c {
+                '\\' => match chars.get(i + 1) {
+                    Some(&next @ ('"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't' | 'u')) => {
+                        out.push('\\');
+                        out.push(next);
+                        i += 1;
+                    }
+                    _ => out.push_str("\\\\"),
+                },
+                // A quote only ends the string where JSON could go on
+                // after it; any other is part of the text
+                '"' if closes_string(&chars[i + 1..]) => {
+                    out.push('"');
+                    in_string = false;
+                }
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        } else {
+            match c {
+                '"' => {
+                    out.push('"');
+                    in_string = true;
+                }
+                '{' => {
+                    out.push(c);
+                    open.push('}');
+                }
+                '[' => {
+                    out.push(c);
+                    open.push(']');
+                }
+                '}' | ']' => {
+                    out.push(c);
+                    open.pop();
+                }
+                ',' if matches!(next_significant(&chars[i + 1..]), Some('}' | ']') | None) => {}
+                c => out.push(c),
+            }
+        }
+        i += 1;
+    }
+    if in_string {
+        out.push('"');
+    }
+    while let Some(close) = open.pop() {
+        out.push(close);
+    }
+    out
+}
+
+/// `input` without the Markdown code fence some models wrap JSON in.
+fn strip_fence(input: &str) -> &str {
+    let Some(rest) = input.strip_prefix("```") else {
+        return input;
+    };
+    let rest = rest.strip_prefix("json").unwrap_or(rest);
+    rest.strip_suffix("```").unwrap_or(rest).trim()
+}
+
+fn next_significant(chars: &[char]) -> Option<char> {
+    chars.iter().copied().find(|c| !c.is_whitespace())
+}
+
+/// Whether a quote followed by `rest` can end a string.
+fn closes_string(rest: &[char]) -> bool {
+    match next_significant(rest) {
+        None | Some(':' | '}' | ']') => true,
+        Some(',') => {
+            let after = rest
+                .iter()
+                .position(|&c| c == ',')
+                .map_or(rest, |i| &rest[i + 1..]);
+            matches!(next_significant(after), None | Some('"' | '}' | ']'))
+        }
+        _ => false,
+    }
+}
+
+/// Repairs the tool calls in a complete Messages response, returning how
+/// many needed it. Inputs sent as a string instead of an object are
+/// parsed.
+pub fn repair_message(message: &mut Value) -> usize {
+    let mut repaired = 0;
+    let Some(content) = message["content"].as_array_mut() else {
+        return 0;
+    };
+    for block in content.iter_mut().filter(|b| b["type"] == "tool_use") {
+        let fixed = match &block["input"] {
+            Value::Object(_) => continue,
+            Value::String(text) => match check(text) {
+                Checked::Valid => serde_json::from_str(text).ok(),
+                Checked::Repaired(value) => Some(value),
+                Checked::Broken => None,
+            },
+            Value::Null => Some(json!({})),
+            _ => None,
+        };
+        match fixed {
+            Some(input) => {
+                block["input"] = input;
+                repaired += 1;
+            }
+            None => tracing::warn!(tool = %block["name"], "tool call input is beyond repair"),
+        }
+    }
+    repaired
+}
+
+/// Repairs the tool calls in a streamed Messages response. A tool call's
+/// input deltas are held back until its block closes, then sent as one,
+/// repaired if need be; every other event passes through as it came.
+#[derive(Default)]
+pub struct StreamRepair {
+    lines: LineBuffer,
+    /// Lines of the event being read.
+    event: Vec<u8>,
+    /// Input received so far for each open tool call, by block index.
+    inputs: HashMap<u64, String>,
+    repaired: usize,
+}
+
+impl StreamRepair {
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        let mut out = Vec::with_capacity(chunk.len());
+        for line in self.lines.push(chunk) {
+            let blank = line.iter().all(|b| b.is_ascii_whitespace());
+            self.event.extend_from_slice(&line);
+            if blank {
+                let event = std::mem::take(&mut self.event);
+                self.relay(event, &mut out);
+            }
+        }
+        Bytes::from(out)
+    }
+
+    /// Tool calls repaired so far.
+    pub fn repaired(&self) -> usize {
+        self.repaired
+    }
+
+    fn relay(&mut self, event: Vec<u8>, out: &mut Vec<u8>) {
+        let data = String::from_utf8_lossy(&event)
+            .lines()
+            .find_map(|line| line.strip_prefix("data:").map(|d| d.trim().to_string()));
+        let Some(data) = data.and_then(|d| serde_json::from_str::<Value>(&d).ok()) else {
+            out.extend_from_slice(&event);
+            return;
+        };
+        let index = data["index"].as_u64();
+        match (data["type"].as_str(), index) {
+            (Some("content_block_start"), Some(index))
+                if data["content_block"]["type"] == "tool_use" =>
+            {
+                self.inputs.insert(index, String::new());
+            }
+            (Some("content_block_delta"), Some(index))
+                if data["delta"]["type"] == "input_json_delta" =>
+            {
+                if let Some(input) = self.inputs.get_mut(&index) {
+                    input.push_str(data["delta"]["partial_json"].as_str().unwrap_or_default());
+                    return;
+                }
+            }
+            (Some("content_block_stop"), Some(index)) => {
+                if let Some(input) = self.inputs.remove(&index) {
+                    let input = match check(&input) {
+                        _ if input.trim().is_empty() => input,
+                        Checked::Valid => input,
+                        Checked::Repaired(value) => {
+                            self.repaired += 1;
+                            value.to_string()
+                        }
+                        Checked::Broken => {
+                            tracing::warn!(index, "tool call input is beyond repair");
+                            input
+                        }
+                    };
+                    if !input.is_empty() {
+                        let delta = json!({
+                            "type": "content_block_delta",
+                            "index": index,
+                            "delta": {"type": "input_json_delta", "partial_json": input},
+                        });
+                        out.extend_from_slice(sse_event("content_block_delta", &delta).as_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+        out.extend_from_slice(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translate::parse_events;
+
+    fn repaired(input: &str) -> Value {
+        match check(input) {
+            Checked::Repaired(value) => value,
+            other => panic!("{input} was {other:?}"),
+        }
+    }
+
+    #[test]
+    fn common_mistakes_are_repaired() {
+        assert_eq!(check(r#"{"path": "a.rs"}"#), Checked::Valid);
+        assert_eq!(
+            repaired(r#"{"path": "a.rs", "lines": [1, 2,],}"#),
+            json!({"path": "a.rs", "lines": [1, 2]})
+        );
+        assert_eq!(
+            repaired(r#"{"command": "echo "hi" > out.txt", "timeout": 5}"#),
+            json!({"command": "echo \"hi\" > out.txt", "timeout": 5})
+        );
+        assert_eq!(
+            repaired("{\"content\": \"line one\nline\ttwo\"}"),
+            json!({"content": "line one\nline\ttwo"})
+        );
+        assert_eq!(
+            repaired(r#"{"pattern": "\d+"}"#),
+            json!({"pattern": "\\d+"})
+        );
+        assert_eq!(
+            repaired("```json\n{\"path\": \"a.rs\"}\n```"),
+            json!({"path": "a.rs"})
+        );
+        assert_eq!(
+            repaired(r#"{"edits": [{"old": "a", "new": "b"#),
+            json!({"edits": [{"old": "a", "new": "b"}]})
+        );
+        assert_eq!(check("not json at all"), Checked::Broken);
+        assert_eq!(check(r#"["a", "b"]"#), Checked::Broken);
+    }
+
+    #[test]
+    fn string_and_missing_inputs_become_objects() {
+        let mut message = json!({"content": [
+            {"type": "text", "text": "Reading it"},
+            {"type": "tool_use", "name": "read", "input": {"path": "a.rs"}},
+            {"type": "tool_use", "name": "read", "input": "{\"path\": \"b.rs\",}"},
+            {"type": "tool_use", "name": "ls", "input": null},
+            {"type": "tool_use", "name": "bad", "input": "oops"},
+        ]});
+        assert_eq!(repair_message(&mut message), 2);
+        assert_eq!(message["content"][2]["input"], json!({"path": "b.rs"}));
+        assert_eq!(message["content"][3]["input"], json!({}));
+        assert_eq!(message["content"][4]["input"], "oops");
+    }
+
+    #[test]
+    fn streamed_tool_input_is_held_until_its_block_closes() {
+        let events = [
+            sse_event("message_start", &json!({"type": "message_start"})),
+            sse_event(
+                "content_block_start",
+                &json!({"type": "content_block_start", "index": 0, "content_block": {"type": "tool_use", "name": "read"}}),
+            ),
+            sse_event(
+                "content_block_delta",
+                &json!({"type": "content_block_delta", "index": 0, "delta": {"type": "input_json_delta", "partial_json": "{\"path\": "}}),
+            ),
+            sse_event(
+                "content_block_delta",
+                &json!({"type": "content_block_delta", "index": 0, "delta": {"type": "input_json_delta", "partial_json": "\"a.rs\",}"}}),
+            ),
+            sse_event(
+                "content_block_stop",
+                &json!({"type": "content_block_stop", "index": 0}),
+            ),
+            sse_event("message_stop", &json!({"type": "message_stop"})),
+        ]
+        .concat();
+
+        let mut repair = StreamRepair::default();
+        // Split mid-event, as the network would
+        let (first, second) = events.as_bytes().split_at(150);
+        let mut out = repair.push(first).to_vec();
+        out.extend_from_slice(&repair.push(second));
+
+        let events = parse_events(&out);
+        let names: Vec<&str> = events.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_stop",
+            ]
+        );
+        assert_eq!(events[2].1["delta"]["partial_json"], r#"{"path":"a.rs"}"#);
+        assert_eq!(repair.repaired(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Cuts oversized `tool_result` content down to size. A runaway command
+//! output or file read would otherwise push the request past
+//! `max_body_size` and fail the whole turn.
+
+use serde_json::Value;
+
+/// Cuts the text of every `tool_result` block longer than `max_size`
+/// bytes, keeping its start and end around a marker. Returns how many
+/// blocks were cut.
+pub fn truncate(body: &mut Value, max_size: usize) -> usize {
+    let mut truncated = 0;
+    let Some(messages) = body.get_mut("messages").and_then(Value::as_array_mut) else {
+        return 0;
+    };
+    for message in messages {
+        let Some(blocks) = message.get_mut("content").and_then(Value::as_array_mut) else {
+            continue;
+        };
+        for block in blocks.iter_mut().filter(|b| b["type"] == "tool_result") {
+            let Some(content) = block.get_mut("content") else {
+                continue;
+            };
+            let mut cut = false;
+            match content {
+                Value::String(text) => cut = cut_text(text, max_size),
+                Value::Array(parts) => {
+                    for part in parts.iter_mut().filter(|p| p["type"] == "text") {
+                        if let Some(Value::String(text)) = part.get_mut("text") {
+                            cut |= cut_text(text, max_size);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if cut {
+                truncated += 1;
+            }
+        }
+    }
+    truncated
+}
+
+/// Keeps the first three quarters and the last quarter of `max_size`
+/// bytes: the start of output usually says what it is, the end how it
+/// finished.
+fn cut_text(text: &mut String, max_size: usize) -> bool {
+    if text.len() <= max_size {
+        return false;
+    }
+    let head_end = floor_char_boundary(text, max_size * 3 / 4);
+    let tail_start = ceil_char_boundary(text, text.len() - max_size / 4);
+    let removed = tail_start - head_end;
+    *text = format!(
+        "{}\n\n[... croxy truncated {removed} bytes of this tool result ...]\n\n{}",
+        &text[..head_end],
+        &text[tail_start..]
+    );
+    true
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn cuts_long_results_keeping_head_and_tail() {
+        let output = format!("{}{}{}", "a".repeat(600), "b".repeat(1000), "c".repeat(400));
+        let mut body = json!({"messages": [
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "t1", "content": output},
+                {"type": "tool_result", "tool_use_id": "t2", "content": "short"}
+            ]}
+        ]});
+        assert_eq!(truncate(&mut body, 800), 1);
+        let text = body["messages"][0]["content"][0]["content"]
+            .as_str()
+            .unwrap();
+        assert!(text.starts_with(&"a".repeat(600)));
+        assert!(text.ends_with(&"c".repeat(200)));
+        assert!(text.contains("[... croxy truncated 1200 bytes of this tool result ...]"));
+        assert_eq!(body["messages"][0]["content"][1]["content"], "short");
+    }
+
+    #[test]
+    fn cuts_text_parts_and_respects_char_boundaries() {
+        let mut body = json!({"messages": [
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "t1", "content": [
+                    {"type": "text", "text": "é".repeat(100)},
+                    {"type": "image", "source": {"type": "base64", "data": "x".repeat(500)}}
+                ]}
+            ]},
+            {"role": "user", "content": "plain text is never cut".repeat(10)}
+        ]});
+        assert_eq!(truncate(&mut body, 51), 1);
+        let text = body["messages"][0]["content"][0]["content"][0]["text"]
+            .as_str()
+            .unwrap();
+        assert!(text.starts_with(&"é".repeat(19)));
+        assert_eq!(
+            body["messages"][0]["content"][0]["content"][1]["source"]["data"]
+                .as_str()
+                .unwrap()
+                .len(),
+            500
+        );
+    }
+}
@@ -0,0 +1,531 @@
+//! Azure OpenAI chat completions. The deployment name goes in the path, the
+//! API version in the query, and the key in an `api-key` header. Streams
+//! are server-sent events of `chat.completion.chunk` deltas.
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+
+use super::{EventWriter, LineBuffer, StopReason, Upstream, message_id, text_of, tool_use_id};
+
+/// Used when a provider doesn't set `api_version`.
+pub const DEFAULT_API_VERSION: &str = "2024-10-21";
+
+pub fn request(
+    base_url: &str,
+    api_key: Option<&str>,
+    api_version: Option<&str>,
+    body: &Value,
+    deployment: &str,
+) -> Upstream {
+    Upstream {
+        url: format!("{base_url}/openai/deployments/{deployment}/chat/completions"),
+        query: vec![(
+            "api-version",
+            api_version.unwrap_or(DEFAULT_API_VERSION).to_string(),
+        )],
+        headers: api_key
+            .map(|key| ("api-key", key.to_string()))
+            .into_iter()
+            .collect(),
+        body: chat_request(body),
+    }
+}
+
+/// Translates a Messages request body into a chat completions body.
+pub fn chat_request(body: &Value) -> Value {
+    let mut messages = Vec::new();
+    if let Some(system) = body.get("system") {
+        let text = text_of(system);
+        if !text.is_empty() {
+            messages.push(json!({"role": "system", "content": text}));
+        }
+    }
+    for message in body["messages"].as_array().into_iter().flatten() {
+        match (&message["role"], &message["content"]) {
+            (_, Value::String(text)) => {
+                messages.push(json!({"role": message["role"], "content": text}));
+            }
+            (role, Value::Array(blocks)) if role == "assistant" => {
+                messages.push(assistant_message(blocks));
+            }
+            (_, Value::Array(blocks)) => user_messages(blocks, &mut messages),
+            _ => {}
+        }
+    }
+
+    let mut request = json!({"messages": messages});
+
+    if let Some(tools) = body["tools"].as_array() {
+        let functions: Vec<Value> = tools
+            .iter()
+            .filter(|t| t.get("input_schema").is_some())
+            .map(|t| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": t["name"],
+                        "description": t["description"],
+                        "parameters": t["input_schema"],
+                    }
+                })
+            })
+            .collect();
+        if !functions.is_empty() {
+            request["tools"] = Value::Array(functions);
+        }
+    }
+    let tool_choice = match body["tool_choice"]["type"].as_str() {
+        Some("auto") => Some(json!("auto")),
+        Some("any") => Some(json!("required")),
+        Some("none") => Some(json!("none")),
+        Some("tool") => Some(json!({
+            "type": "function",
+            "function": {"name": body["tool_choice"]["name"]},
+        })),
+        _ => None,
+    };
+    if let Some(tool_choice) = tool_choice {
+        request["tool_choice"] = tool_choice;
+    }
+
+    for (from, to) in [
+        ("max_tokens", "max_tokens"),
+        ("temperature", "temperature"),
+        ("top_p", "top_p"),
+        ("stop_sequences", "stop"),
+    ] {
+        if let Some(value) = body.get(from) {
+            request[to] = value.clone();
+        }
+    }
+    if body["stream"] == true {
+        request["stream"] = json!(true);
+        // Without this, streamed responses carry no token counts
+        request["stream_options"] = json!({"include_usage": true});
+    }
+
+    request
+}
+
+/// One assistant message, with its tool calls alongside its text.
+fn assistant_message(blocks: &[Value]) -> Value {
+    let text = text_of(&Value::Array(blocks.to_vec()));
+    let tool_calls: Vec<Value> = blocks
+        .iter()
+        .filter(|b| b["type"] == "tool_use")
+        .map(|b| {
+            json!({
+                "id": b["id"],
+                "type": "function",
+                "function": {"name": b["name"], "arguments": b["input"].to_string()},
+            })
+        })
+        .collect();
+    let mut message = json!({
+        "role": "assistant",
+        "content": if text.is_empty() { Value::Null } else { json!(text) },
+    });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    message
+}
+
+/// Tool results become `tool` messages, which must directly follow the
+/// assistant's calls; the rest of the content follows as a user message.
+fn user_messages(blocks: &[Value], messages: &mut Vec<Value>) {
+    let mut parts = Vec::new();
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("tool_result") => messages.push(json!({
+                "role": "tool",
+                "tool_call_id": block["tool_use_id"],
+                "content": text_of(&block["content"]),
+            })),
+            Some("text") => parts.extend(
+                block["text"]
+                    .as_str()
+                    .map(|t| json!({"type": "text", "text": t})),
+            ),
+            Some("image") => {
+                let source = &block["source"];
+                let url = match source["type"].as_str() {
+                    Some("base64") => Some(format!(
+                        "data:{};base64,{}",
+                        source["media_type"].as_str().unwrap_or("image/png"),
+                        source["data"].as_str().unwrap_or("")
+                    )),
+                    Some("url") => source["url"].as_str().map(String::from),
+                    _ => None,
+                };
+                parts
+                    .extend(url.map(|url| json!({"type": "image_url", "image_url": {"url": url}})));
+            }
+            _ => {}
+        }
+    }
+    if !parts.is_empty() {
+        messages.push(json!({"role": "user", "content": parts}));
+    }
+}
+
+fn usage(response: &Value) -> (u64, u64) {
+    let usage = &response["usage"];
+    (
+        usage["prompt_tokens"].as_u64().unwrap_or(0),
+        usage["completion_tokens"].as_u64().unwrap_or(0),
+    )
+}
+
+/// Tool arguments arrive as a JSON string; a malformed one becomes an empty
+/// input rather than failing the whole response.
+fn arguments(call: &Value) -> Value {
+    call["function"]["arguments"]
+        .as_str()
+        .and_then(|args| serde_json::from_str(args).ok())
+        .unwrap_or_else(|| json!({}))
+}
+
+/// Why `choice` finished. OpenAI-compatible servers like vLLM name the
+/// stop sequence reached in `stop_reason`.
+fn stop_reason(choice: &Value) -> StopReason {
+    let reason = StopReason::from_finish(choice["finish_reason"].as_str().unwrap_or_default());
+    match choice["stop_reason"].as_str() {
+        Some(sequence) if reason == StopReason::EndTurn => {
+            StopReason::StopSequence(sequence.to_string())
+        }
+        _ => reason,
+    }
+}
+
+/// Translates a complete chat completion into a Messages response,
+/// reported under the model the client asked for.
+pub fn messages_response(response: &Value, model: &str) -> Value {
+    let choice = &response["choices"][0];
+    let mut content = Vec::new();
+    if let Some(text) = choice["message"]["content"]
+        .as_str()
+        .filter(|t| !t.is_empty())
+    {
+        content.push(json!({"type": "text", "text": text}));
+    }
+    let calls = choice["message"]["tool_calls"].as_array();
+    for call in calls.into_iter().flatten() {
+        content.push(json!({
+            "type": "tool_use",
+            "id": call["id"].as_str().map_or_else(tool_use_id, String::from),
+            "name": call["function"]["name"],
+            "input": arguments(call),
+        }));
+    }
+
+    let (input_tokens, output_tokens) = usage(response);
+    let stop = stop_reason(choice).given_tools(calls.is_some_and(|c| !c.is_empty()));
+    json!({
+        "id": message_id(),
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": stop.name(),
+        "stop_sequence": stop.sequence(),
+        "usage": {"input_tokens": input_tokens, "output_tokens": output_tokens},
+    })
+}
+
+/// Translates a streamed chat completion. Tool call arguments arrive in
+/// fragments and are passed through as they come; usage arrives in a final
+/// chunk after the finish reason.
+pub struct CompletionStream {
+    lines: LineBuffer,
+    writer: EventWriter,
+    stop: StopReason,
+    finished: bool,
+}
+
+impl CompletionStream {
+    pub fn new(writer: EventWriter) -> Self {
+        Self {
+            lines: LineBuffer::default(),
+            writer,
+            stop: StopReason::default(),
+            finished: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        for line in self.lines.push(chunk) {
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                self.finish(0, 0);
+            } else if let Ok(value) = serde_json::from_str::<Value>(data) {
+                self.translate(&value);
+            }
+        }
+        self.writer.take()
+    }
+
+    fn translate(&mut self, chunk: &Value) {
+        if let Some(error) = chunk["error"]["message"].as_str() {
+            self.writer.error(error);
+            return;
+        }
+        // Azure sends content filter results in chunks with no choices
+        if let Some(choice) = chunk["choices"].get(0) {
+            let delta = &choice["delta"];
+            if let Some(text) = delta["content"].as_str().filter(|t| !t.is_empty()) {
+                self.writer.text(text);
+            }
+            for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                if let Some(id) = call["id"].as_str() {
+                    self.writer
+                        .start_tool_use(Some(id), &call["function"]["name"]);
+                }
+                if let Some(args) = call["function"]["arguments"]
+                    .as_str()
+                    .filter(|a| !a.is_empty())
+                {
+                    self.writer.tool_input(args);
+                }
+            }
+            if choice["finish_reason"].is_string() {
+                self.stop = stop_reason(choice);
+            }
+        }
+        if chunk["usage"].is_object() {
+            let (input_tokens, output_tokens) = usage(chunk);
+            self.finish(input_tokens, output_tokens);
+        }
+    }
+
+    fn finish(&mut self, input_tokens: u64, output_tokens: u64) {
+        if !self.finished {
+            self.finished = true;
+            self.writer
+                .finish(std::mem::take(&mut self.stop), input_tokens, output_tokens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translate::parse_events;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn request_uses_deployment_version_and_key_header() {
+        let upstream = request(
+            "https://acme.openai.azure.com",
+            Some("azure-key"),
+            None,
+            &json!({"messages": []}),
+            "gpt-4o-mini",
+        );
+        assert_eq!(
+            upstream.url,
+            "https://acme.openai.azure.com/openai/deployments/gpt-4o-mini/chat/completions"
+        );
+        assert_eq!(
+            upstream.query,
+            vec![("api-version", DEFAULT_API_VERSION.to_string())]
+        );
+        assert_eq!(upstream.headers, vec![("api-key", "azure-key".to_string())]);
+
+        let upstream = request(
+            "http://a",
+            None,
+            Some("2025-01-01-preview"),
+            &json!({}),
+            "d",
+        );
+        assert_eq!(upstream.query[0].1, "2025-01-01-preview");
+        assert!(upstream.headers.is_empty());
+    }
+
+    #[test]
+    fn request_maps_messages_and_params() {
+        let body = json!({
+            "system": [{"type": "text", "text": "be brief"}],
+            "max_tokens": 256,
+            "stop_sequences": ["END"],
+            "stream": true,
+            "messages": [
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": [{"type": "text", "text": "hello"}]},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "what is this?"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "AAAA"}}
+                ]}
+            ]
+        });
+        let request = chat_request(&body);
+        assert_eq!(
+            request["messages"],
+            json!([
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "hello"},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "what is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,AAAA"}}
+                ]}
+            ])
+        );
+        assert_eq!(request["max_tokens"], 256);
+        assert_eq!(request["stop"], json!(["END"]));
+        assert_eq!(request["stream_options"]["include_usage"], true);
+    }
+
+    #[test]
+    fn request_maps_tools_calls_and_results() {
+        let body = json!({
+            "tools": [{
+                "name": "read_file",
+                "description": "Read a file",
+                "input_schema": {"type": "object", "properties": {"path": {"type": "string"}}}
+            }],
+            "tool_choice": {"type": "tool", "name": "read_file"},
+            "messages": [
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"path": "a.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "fn main() {}"},
+                    {"type": "text", "text": "now fix it"}
+                ]}
+            ]
+        });
+        let request = chat_request(&body);
+        assert_eq!(
+            request["tools"][0]["function"]["parameters"]["properties"]["path"]["type"],
+            "string"
+        );
+        assert_eq!(
+            request["tool_choice"],
+            json!({"type": "function", "function": {"name": "read_file"}})
+        );
+        assert_eq!(
+            request["messages"],
+            json!([
+                {"role": "assistant", "content": null, "tool_calls": [{
+                    "id": "toolu_1",
+                    "type": "function",
+                    "function": {"name": "read_file", "arguments": "{\"path\":\"a.rs\"}"}
+                }]},
+                {"role": "tool", "tool_call_id": "toolu_1", "content": "fn main() {}"},
+                {"role": "user", "content": [{"type": "text", "text": "now fix it"}]}
+            ])
+        );
+    }
+
+    #[test]
+    fn response_maps_content_calls_and_usage() {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": "reading it",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "read_file", "arguments": "{\"path\":\"a.rs\"}"}
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 30, "completion_tokens": 8, "total_tokens": 38}
+        });
+        let message = messages_response(&response, "claude-haiku-4-5");
+        assert_eq!(message["model"], "claude-haiku-4-5");
+        assert_eq!(message["content"][0]["text"], "reading it");
+        assert_eq!(
+            message["content"][1],
+            json!({"type": "tool_use", "id": "call_1", "name": "read_file", "input": {"path": "a.rs"}})
+        );
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(
+            message["usage"],
+            json!({"input_tokens": 30, "output_tokens": 8})
+        );
+
+        let truncated =
+            json!({"choices": [{"message": {"content": "abc"}, "finish_reason": "length"}]});
+        assert_eq!(
+            messages_response(&truncated, "m")["stop_reason"],
+            "max_tokens"
+        );
+
+        let filtered =
+            json!({"choices": [{"message": {"content": null}, "finish_reason": "content_filter"}]});
+        assert_eq!(messages_response(&filtered, "m")["stop_reason"], "refusal");
+
+        // vLLM names the stop sequence it stopped at
+        let stopped = json!({"choices": [{
+            "message": {"content": "abc"},
+            "finish_reason": "stop",
+            "stop_reason": "END",
+        }]});
+        let message = messages_response(&stopped, "m");
+        assert_eq!(message["stop_reason"], "stop_sequence");
+        assert_eq!(message["stop_sequence"], "END");
+    }
+
+    #[test]
+    fn stream_translates_text_and_tool_call_fragments() {
+        let tokens = Arc::new(AtomicU64::new(0));
+        let mut stream = CompletionStream::new(EventWriter::new("m", tokens.clone()));
+        let sse = concat!(
+            "data: {\"choices\":[],\"prompt_filter_results\":[]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Let me look\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",",
+            "\"type\":\"function\",\"function\":{\"name\":\"read_file\",\"arguments\":\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"path\\\":\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"a.rs\\\"}\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":9}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let (a, b) = sse.split_at(100);
+        let mut out = stream.push(a.as_bytes()).to_vec();
+        out.extend_from_slice(&stream.push(b.as_bytes()));
+
+        let events = parse_events(&out);
+        assert_eq!(events[1].1["content_block"]["type"], "text");
+        assert_eq!(
+            events[4].1["content_block"],
+            json!({"type": "tool_use", "id": "call_1", "name": "read_file", "input": {}})
+        );
+        let input: String = events
+            .iter()
+            .filter(|(_, e)| e["delta"]["type"] == "input_json_delta")
+            .map(|(_, e)| e["delta"]["partial_json"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(input, r#"{"path":"a.rs"}"#);
+        let deltas: Vec<&Value> = events
+            .iter()
+            .filter(|(name, _)| name == "message_delta")
+            .map(|(_, e)| e)
+            .collect();
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0]["delta"]["stop_reason"], "tool_use");
+        assert_eq!(deltas[0]["usage"]["input_tokens"], 12);
+        assert_eq!(tokens.load(Ordering::Relaxed), 9);
+    }
+
+    #[test]
+    fn stream_finishes_on_done_without_usage() {
+        let mut stream = CompletionStream::new(EventWriter::new("m", Arc::new(AtomicU64::new(0))));
+        let out = stream.push(
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"hi\"},\"finish_reason\":\"length\"}]}\n\ndata: [DONE]\n\n",
+        );
+        let events = parse_events(&out);
+        let (name, delta) = &events[events.len() - 2];
+        assert_eq!(name, "message_delta");
+        assert_eq!(delta["delta"]["stop_reason"], "max_tokens");
+    }
+}
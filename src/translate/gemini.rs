@@ -0,0 +1,495 @@
+//! Google's Generative Language API (`generateContent`), which takes the API
+//! key in the query string and streams server-sent events of whole
+//! response chunks.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde_json::{Map, Value, json};
+
+use super::{EventWriter, LineBuffer, StopReason, Upstream, message_id, text_of, tool_use_id};
+
+/// Schema fields Gemini accepts in function parameters. Anything else,
+/// like `$schema` or `additionalProperties`, is rejected by the API.
+const SCHEMA_FIELDS: &[&str] = &[
+    "type",
+    "title",
+    "description",
+    "nullable",
+    "enum",
+    "default",
+    "example",
+    "required",
+    "minItems",
+    "maxItems",
+    "minProperties",
+    "maxProperties",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+    "propertyOrdering",
+];
+
+pub fn request(base_url: &str, api_key: Option<&str>, body: &Value, model: &str) -> Upstream {
+    let streaming = body["stream"] == true;
+    let method = if streaming {
+        "streamGenerateContent"
+    } else {
+        "generateContent"
+    };
+    let mut query = Vec::new();
+    if streaming {
+        query.push(("alt", "sse".to_string()));
+    }
+    if let Some(key) = api_key {
+        query.push(("key", key.to_string()));
+    }
+    Upstream {
+        url: format!("{base_url}/v1beta/models/{model}:{method}"),
+        query,
+        headers: Vec::new(),
+        body: generate_request(body),
+    }
+}
+
+/// Translates a Messages request body into a `generateContent` body.
+pub fn generate_request(body: &Value) -> Value {
+    // Tool results only carry the call's ID; Gemini wants the function name.
+    let mut tool_names = HashMap::new();
+    let mut contents = Vec::new();
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let role = if message["role"] == "assistant" {
+            "model"
+        } else {
+            "user"
+        };
+        let parts = match message["content"] {
+            Value::String(ref text) => vec![json!({"text": text})],
+            Value::Array(ref blocks) => parts(blocks, &mut tool_names),
+            _ => Vec::new(),
+        };
+        if !parts.is_empty() {
+            contents.push(json!({"role": role, "parts": parts}));
+        }
+    }
+
+    let mut request = json!({"contents": contents});
+
+    if let Some(system) = body.get("system") {
+        let text = text_of(system);
+        if !text.is_empty() {
+            request["systemInstruction"] = json!({"parts": [{"text": text}]});
+        }
+    }
+
+    if let Some(tools) = body["tools"].as_array() {
+        let declarations: Vec<Value> = tools
+            .iter()
+            .filter(|t| t.get("input_schema").is_some())
+            .map(|t| {
+                json!({
+                    "name": t["name"],
+                    "description": t["description"],
+                    "parameters": schema(&t["input_schema"]),
+                })
+            })
+            .collect();
+        if !declarations.is_empty() {
+            request["tools"] = json!([{"functionDeclarations": declarations}]);
+        }
+    }
+
+    let mut config = Map::new();
+    for (from, to) in [
+        ("max_tokens", "maxOutputTokens"),
+        ("temperature", "temperature"),
+        ("top_p", "topP"),
+        ("top_k", "topK"),
+        ("stop_sequences", "stopSequences"),
+    ] {
+        if let Some(value) = body.get(from) {
+            config.insert(to.to_string(), value.clone());
+        }
+    }
+    if body["thinking"]["type"] == "enabled" {
+        let mut thinking = json!({"includeThoughts": true});
+        if let Some(budget) = body["thinking"].get("budget_tokens") {
+            thinking["thinkingBudget"] = budget.clone();
+        }
+        config.insert("thinkingConfig".to_string(), thinking);
+    }
+    if !config.is_empty() {
+        request["generationConfig"] = Value::Object(config);
+    }
+
+    request
+}
+
+fn parts(blocks: &[Value], tool_names: &mut HashMap<String, String>) -> Vec<Value> {
+    let mut parts = Vec::new();
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => parts.extend(block["text"].as_str().map(|t| json!({"text": t}))),
+            Some("image") if block["source"]["type"] == "base64" => parts.push(json!({
+                "inlineData": {
+                    "mimeType": block["source"]["media_type"],
+                    "data": block["source"]["data"],
+                }
+            })),
+            Some("tool_use") => {
+                if let (Some(id), Some(name)) = (block["id"].as_str(), block["name"].as_str()) {
+                    tool_names.insert(id.to_string(), name.to_string());
+                }
+                parts.push(json!({
+                    "functionCall": {"name": block["name"], "args": block["input"]}
+                }));
+            }
+            Some("tool_result") => {
+                let name = block["tool_use_id"]
+                    .as_str()
+                    .and_then(|id| tool_names.get(id))
+                    .cloned()
+                    .unwrap_or_default();
+                parts.push(json!({
+                    "functionResponse": {
+                        "name": name,
+                        "response": {"content": text_of(&block["content"])},
+                    }
+                }));
+            }
+            _ => {}
+        }
+    }
+    parts
+}
+
+/// Reduces a JSON Schema to the subset Gemini accepts.
+fn schema(value: &Value) -> Value {
+    let Value::Object(fields) = value else {
+        return value.clone();
+    };
+    let mut out = Map::new();
+    for (key, value) in fields {
+        let kept = match key.as_str() {
+            "properties" => match value {
+                Value::Object(properties) => Value::Object(
+                    properties
+                        .iter()
+                        .map(|(name, property)| (name.clone(), schema(property)))
+                        .collect(),
+                ),
+                _ => continue,
+            },
+            "items" => schema(value),
+            "anyOf" => match value {
+                Value::Array(options) => options.iter().map(schema).collect(),
+                _ => continue,
+            },
+            // Only these string formats are supported
+            "format" if value == "enum" || value == "date-time" => value.clone(),
+            key if SCHEMA_FIELDS.contains(&key) => value.clone(),
+            _ => continue,
+        };
+        out.insert(key.clone(), kept);
+    }
+    Value::Object(out)
+}
+
+fn function_call_input(call: &Value) -> Value {
+    match call["args"] {
+        Value::Null => json!({}),
+        ref args => args.clone(),
+    }
+}
+
+fn usage(response: &Value) -> (u64, u64) {
+    let usage = &response["usageMetadata"];
+    let count = |field: &str| usage[field].as_u64().unwrap_or(0);
+    (
+        count("promptTokenCount"),
+        count("candidatesTokenCount") + count("thoughtsTokenCount"),
+    )
+}
+
+/// Translates a complete `generateContent` response into a Messages
+/// response, reported under the model the client asked for.
+pub fn messages_response(response: &Value, model: &str) -> Value {
+    let candidate = &response["candidates"][0];
+    let mut content = Vec::new();
+    let mut used_tools = false;
+    for part in candidate["content"]["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        if let Some(call) = part.get("functionCall") {
+            used_tools = true;
+            content.push(json!({
+                "type": "tool_use",
+                "id": call["id"].as_str().map_or_else(tool_use_id, String::from),
+                "name": call["name"],
+                "input": function_call_input(call),
+            }));
+        } else if let Some(text) = part["text"].as_str() {
+            if part["thought"] == true {
+                content.push(json!({"type": "thinking", "thinking": text, "signature": ""}));
+            } else {
+                content.push(json!({"type": "text", "text": text}));
+            }
+        }
+    }
+
+    let (input_tokens, output_tokens) = usage(response);
+    json!({
+        "id": message_id(),
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": stop_reason(response).given_tools(used_tools).name(),
+        "stop_sequence": null,
+        "usage": {"input_tokens": input_tokens, "output_tokens": output_tokens},
+    })
+}
+
+/// Why the response ended. A prompt Gemini blocked has no candidates, only
+/// the block reason.
+fn stop_reason(response: &Value) -> StopReason {
+    if response["promptFeedback"]["blockReason"].is_string() {
+        return StopReason::Refusal;
+    }
+    StopReason::from_finish(
+        response["candidates"][0]["finishReason"]
+            .as_str()
+            .unwrap_or_default(),
+    )
+}
+
+/// Translates a `streamGenerateContent?alt=sse` response, where each event
+/// carries a partial response in the same shape as a complete one.
+pub struct ContentStream {
+    lines: LineBuffer,
+    writer: EventWriter,
+}
+
+impl ContentStream {
+    pub fn new(writer: EventWriter) -> Self {
+        Self {
+            lines: LineBuffer::default(),
+            writer,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        for line in self.lines.push(chunk) {
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            if let Ok(value) = serde_json::from_str::<Value>(data.trim()) {
+                self.translate(&value);
+            }
+        }
+        self.writer.take()
+    }
+
+    fn translate(&mut self, chunk: &Value) {
+        if let Some(error) = chunk["error"]["message"].as_str() {
+            self.writer.error(error);
+            return;
+        }
+        let candidate = &chunk["candidates"][0];
+        for part in candidate["content"]["parts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            if let Some(call) = part.get("functionCall") {
+                self.writer
+                    .tool_use(&call["name"], &function_call_input(call));
+            } else if let Some(text) = part["text"].as_str().filter(|t| !t.is_empty()) {
+                if part["thought"] == true {
+                    self.writer.thinking(text);
+                } else {
+                    self.writer.text(text);
+                }
+            }
+        }
+        if candidate["finishReason"].is_string() {
+            let (input_tokens, output_tokens) = usage(chunk);
+            self.writer
+                .finish(stop_reason(chunk), input_tokens, output_tokens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translate::parse_events;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn request_puts_key_in_query_and_picks_method() {
+        let body = json!({"stream": true, "messages": []});
+        let upstream = request(
+            "https://generativelanguage.googleapis.com",
+            Some("AIza-test"),
+            &body,
+            "gemini-2.5-pro",
+        );
+        assert_eq!(
+            upstream.url,
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-pro:streamGenerateContent"
+        );
+        assert_eq!(
+            upstream.query,
+            vec![("alt", "sse".to_string()), ("key", "AIza-test".to_string())]
+        );
+
+        let upstream = request("http://g", None, &json!({"messages": []}), "m");
+        assert!(upstream.url.ends_with(":generateContent"));
+        assert!(upstream.query.is_empty());
+    }
+
+    #[test]
+    fn request_maps_contents_system_and_config() {
+        let body = json!({
+            "system": "be brief",
+            "max_tokens": 256,
+            "top_p": 0.9,
+            "thinking": {"type": "enabled", "budget_tokens": 1024},
+            "messages": [
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": [{"type": "text", "text": "hello"}]}
+            ]
+        });
+        let request = generate_request(&body);
+        assert_eq!(
+            request["contents"],
+            json!([
+                {"role": "user", "parts": [{"text": "hi"}]},
+                {"role": "model", "parts": [{"text": "hello"}]}
+            ])
+        );
+        assert_eq!(request["systemInstruction"]["parts"][0]["text"], "be brief");
+        assert_eq!(
+            request["generationConfig"],
+            json!({
+                "maxOutputTokens": 256,
+                "topP": 0.9,
+                "thinkingConfig": {"includeThoughts": true, "thinkingBudget": 1024}
+            })
+        );
+    }
+
+    #[test]
+    fn request_maps_tools_calls_and_results() {
+        let body = json!({
+            "tools": [{
+                "name": "read_file",
+                "description": "Read a file",
+                "input_schema": {
+                    "$schema": "http://json-schema.org/draft-07/schema#",
+                    "type": "object",
+                    "additionalProperties": false,
+                    "properties": {
+                        "path": {"type": "string", "format": "uri"},
+                        "lines": {"type": "array", "items": {"type": "integer", "additionalProperties": false}}
+                    },
+                    "required": ["path"]
+                }
+            }],
+            "messages": [
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"path": "a.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": [{"type": "text", "text": "fn main() {}"}]}
+                ]}
+            ]
+        });
+        let request = generate_request(&body);
+        assert_eq!(
+            request["tools"][0]["functionDeclarations"][0]["parameters"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": {"type": "string"},
+                    "lines": {"type": "array", "items": {"type": "integer"}}
+                },
+                "required": ["path"]
+            })
+        );
+        assert_eq!(
+            request["contents"][0]["parts"][0],
+            json!({"functionCall": {"name": "read_file", "args": {"path": "a.rs"}}})
+        );
+        assert_eq!(
+            request["contents"][1]["parts"][0],
+            json!({"functionResponse": {"name": "read_file", "response": {"content": "fn main() {}"}}})
+        );
+    }
+
+    #[test]
+    fn response_maps_parts_and_usage() {
+        let response = json!({
+            "candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"text": "pondering", "thought": true},
+                    {"text": "reading it"},
+                    {"functionCall": {"name": "read_file", "args": {"path": "a.rs"}}}
+                ]},
+                "finishReason": "STOP"
+            }],
+            "usageMetadata": {"promptTokenCount": 30, "candidatesTokenCount": 8, "thoughtsTokenCount": 4}
+        });
+        let message = messages_response(&response, "claude-sonnet-4-5");
+        assert_eq!(message["model"], "claude-sonnet-4-5");
+        assert_eq!(message["content"][0]["type"], "thinking");
+        assert_eq!(message["content"][1]["text"], "reading it");
+        assert_eq!(message["content"][2]["name"], "read_file");
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(
+            message["usage"],
+            json!({"input_tokens": 30, "output_tokens": 12})
+        );
+
+        let unsafe_output = json!({"candidates": [{"finishReason": "SAFETY"}]});
+        assert_eq!(
+            messages_response(&unsafe_output, "m")["stop_reason"],
+            "refusal"
+        );
+        let blocked = json!({"promptFeedback": {"blockReason": "PROHIBITED_CONTENT"}});
+        assert_eq!(messages_response(&blocked, "m")["stop_reason"], "refusal");
+    }
+
+    #[test]
+    fn stream_translates_sse_chunks() {
+        let tokens = Arc::new(AtomicU64::new(0));
+        let mut stream = ContentStream::new(EventWriter::new("m", tokens.clone()));
+        let sse = concat!(
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"Hel\"}]}}]}\r\n\r\n",
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"lo\"}]},\"finishReason\":\"MAX_TOKENS\"}],",
+            "\"usageMetadata\":{\"promptTokenCount\":5,\"candidatesTokenCount\":2}}\r\n\r\n",
+        );
+        let (a, b) = sse.split_at(30);
+        let mut out = stream.push(a.as_bytes()).to_vec();
+        out.extend_from_slice(&stream.push(b.as_bytes()));
+
+        let events = parse_events(&out);
+        let text: String = events
+            .iter()
+            .filter(|(name, _)| name == "content_block_delta")
+            .map(|(_, e)| e["delta"]["text"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(text, "Hello");
+        let (name, delta) = &events[events.len() - 2];
+        assert_eq!(name, "message_delta");
+        assert_eq!(delta["delta"]["stop_reason"], "max_tokens");
+        assert_eq!(tokens.load(Ordering::Relaxed), 2);
+    }
+}
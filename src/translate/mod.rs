@@ -0,0 +1,527 @@
+//! Translation between the Anthropic Messages API that clients speak and
+//! the native APIs of providers with a non-Anthropic `api_format`.
+
+pub mod azure;
+pub mod gemini;
+pub mod ollama;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+
+use crate::config::ApiFormat;
+
+/// A request translated for the provider. `url` is safe to log; anything
+/// secret goes in `query` or `headers`.
+pub struct Upstream {
+    pub url: String,
+    pub query: Vec<(&'static str, String)>,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Value,
+}
+
+/// The translation for a provider's `api_format`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Translation {
+    Ollama,
+    Gemini,
+    Azure,
+}
+
+impl Translation {
+    /// `None` for providers that speak the Messages API themselves.
+    pub fn for_format(format: ApiFormat) -> Option<Self> {
+        match format {
+            ApiFormat::Anthropic | ApiFormat::Vertex => None,
+            ApiFormat::Ollama => Some(Translation::Ollama),
+            ApiFormat::Gemini => Some(Translation::Gemini),
+            ApiFormat::Azure => Some(Translation::Azure),
+        }
+    }
+
+    /// Whether the client's Anthropic credentials may be forwarded. They
+    /// never go to a provider that takes its own key another way.
+    pub fn forwards_auth(self) -> bool {
+        match self {
+            Translation::Ollama => true,
+            Translation::Gemini | Translation::Azure => false,
+        }
+    }
+
+    /// Translates a Messages request for `model` on the provider at
+    /// `base_url`. `api_version` only applies to Azure.
+    pub fn request(
+        self,
+        base_url: &str,
+        api_key: Option<&str>,
+        api_version: Option<&str>,
+        body: &Value,
+        model: &str,
+    ) -> Upstream {
+        let base_url = base_url.trim_end_matches('/');
+        match self {
+            Translation::Ollama => Upstream {
+                url: format!("{base_url}{}", ollama::CHAT_PATH),
+                query: Vec::new(),
+                headers: Vec::new(),
+                body: ollama::chat_request(body, model),
+            },
+            Translation::Gemini => gemini::request(base_url, api_key, body, model),
+            Translation::Azure => azure::request(base_url, api_key, api_version, body, model),
+        }
+    }
+
+    /// Translates a complete response into a Messages response, reported
+    /// under the model the client asked for.
+    pub fn response(self, response: &Value, model: &str) -> Value {
+        match self {
+            Translation::Ollama => ollama::messages_response(response, model),
+            Translation::Gemini => gemini::messages_response(response, model),
+            Translation::Azure => azure::messages_response(response, model),
+        }
+    }
+
+    /// Translates an error body into an Anthropic one.
+    pub fn error(self, status: u16, body: &[u8]) -> Value {
+        let parsed = serde_json::from_slice::<Value>(body).ok();
+        let message = parsed
+            .as_ref()
+            .and_then(|v| match self {
+                Translation::Ollama => v["error"].as_str(),
+                Translation::Gemini | Translation::Azure => v["error"]["message"].as_str(),
+            })
+            .map(String::from)
+            .unwrap_or_else(|| String::from_utf8_lossy(body).into_owned());
+        error_json(status, &message)
+    }
+
+    /// A translator for a streamed response. `output_tokens` is set once
+    /// the provider reports usage.
+    pub fn stream(self, model: &str, output_tokens: Arc<AtomicU64>) -> EventStream {
+        let writer = EventWriter::new(model, output_tokens);
+        match self {
+            Translation::Ollama => EventStream::Ollama(ollama::ChatStream::new(writer)),
+            Translation::Gemini => EventStream::Gemini(gemini::ContentStream::new(writer)),
+            Translation::Azure => EventStream::Azure(azure::CompletionStream::new(writer)),
+        }
+    }
+}
+
+/// Turns a provider's streamed response into Messages server-sent events.
+pub enum EventStream {
+    Ollama(ollama::ChatStream),
+    Gemini(gemini::ContentStream),
+    Azure(azure::CompletionStream),
+}
+
+impl EventStream {
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        match self {
+            EventStream::Ollama(stream) => stream.push(chunk),
+            EventStream::Gemini(stream) => stream.push(chunk),
+            EventStream::Azure(stream) => stream.push(chunk),
+        }
+    }
+}
+
+/// An ID for a translated message, in the shape clients expect.
+pub fn message_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    format!("msg_croxy{:016x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// An ID for a translated tool call, for providers that don't assign one.
+pub fn tool_use_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    format!("toolu_croxy{:016x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Formats one server-sent event.
+pub fn sse_event(name: &str, data: &Value) -> String {
+    format!("event: {name}\ndata: {data}\n\n")
+}
+
+/// An Anthropic error body for `message`.
+pub fn error_json(status: u16, message: &str) -> Value {
+    let kind = match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_error",
+        _ => "api_error",
+    };
+    json!({"type": "error", "error": {"type": kind, "message": message}})
+}
+
+/// Joins the text of a string or an array of content blocks.
+pub fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter(|b| b["type"] == "text")
+            .filter_map(|b| b["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Why a provider stopped generating, as the Messages API's
+/// `stop_reason` puts it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum StopReason {
+    #[default]
+    EndTurn,
+    MaxTokens,
+    /// One of the request's `stop_sequences`, when the provider says which.
+    StopSequence(String),
+    ToolUse,
+    /// The provider's content filter withheld the response.
+    Refusal,
+}
+
+impl StopReason {
+    /// The stop reason for a provider's finish reason, in any of the
+    /// vocabularies the translated APIs use: OpenAI's `finish_reason`,
+    /// Gemini's `finishReason`, and Ollama's `done_reason`. Reasons with
+    /// no Anthropic counterpart, like Ollama's `unload`, end the turn.
+    pub fn from_finish(reason: &str) -> Self {
+        match reason.to_ascii_lowercase().as_str() {
+            "length" | "max_tokens" => StopReason::MaxTokens,
+            "tool_calls" | "function_call" | "tool_use" => StopReason::ToolUse,
+            "content_filter" | "safety" | "recitation" | "blocklist" | "prohibited_content"
+            | "spii" | "image_safety" | "refusal" => StopReason::Refusal,
+            _ => StopReason::EndTurn,
+        }
+    }
+
+    /// Settles the reason against whether the response called tools,
+    /// which providers don't report consistently. A response cut off or
+    /// withheld says so even if it started a tool call.
+    pub fn given_tools(self, used_tools: bool) -> Self {
+        match self {
+            StopReason::EndTurn | StopReason::StopSequence(_) if used_tools => StopReason::ToolUse,
+            StopReason::ToolUse if !used_tools => StopReason::EndTurn,
+            other => other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StopReason::EndTurn => "end_turn",
+            StopReason::MaxTokens => "max_tokens",
+            StopReason::StopSequence(_) => "stop_sequence",
+            StopReason::ToolUse => "tool_use",
+            StopReason::Refusal => "refusal",
+        }
+    }
+
+    /// The stop sequence reached, for `stop_sequence`.
+    pub fn sequence(&self) -> Option<&str> {
+        match self {
+            StopReason::StopSequence(sequence) => Some(sequence),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a byte stream into lines, holding a partial line until the rest
+/// arrives.
+#[derive(Default)]
+pub struct LineBuffer(Vec<u8>);
+
+impl LineBuffer {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.0.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(end) = self.0.iter().position(|&b| b == b'\n') {
+            lines.push(self.0.drain(..=end).collect());
+        }
+        lines
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    Text,
+    Thinking,
+    ToolUse,
+}
+
+/// Writes the Messages event sequence: `message_start`, content blocks
+/// opened and closed as the kind of content changes, then `message_delta`
+/// and `message_stop`.
+pub struct EventWriter {
+    model: String,
+    out: String,
+    started: bool,
+    open: Option<Block>,
+    index: usize,
+    used_tools: bool,
+    output_tokens: Arc<AtomicU64>,
+}
+
+impl EventWriter {
+    pub fn new(model: &str, output_tokens: Arc<AtomicU64>) -> Self {
+        Self {
+            model: model.to_string(),
+            out: String::new(),
+            started: false,
+            open: None,
+            index: 0,
+            used_tools: false,
+            output_tokens,
+        }
+    }
+
+    /// Events written since the last call.
+    pub fn take(&mut self) -> Bytes {
+        Bytes::from(std::mem::take(&mut self.out))
+    }
+
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        self.out.push_str(&sse_event(
+            "message_start",
+            &json!({
+                "type": "message_start",
+                "message": {
+                    "id": message_id(),
+                    "type": "message",
+                    "role": "assistant",
+                    "model": self.model,
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": {"input_tokens": 0, "output_tokens": 0},
+                },
+            }),
+        ));
+    }
+
+    pub fn text(&mut self, text: &str) {
+        self.open_block(Block::Text, || json!({"type": "text", "text": ""}));
+        self.delta(json!({"type": "text_delta", "text": text}));
+    }
+
+    pub fn thinking(&mut self, thinking: &str) {
+        self.open_block(
+            Block::Thinking,
+            || json!({"type": "thinking", "thinking": ""}),
+        );
+        self.delta(json!({"type": "thinking_delta", "thinking": thinking}));
+    }
+
+    /// A complete tool call, sent as a block of its own.
+    pub fn tool_use(&mut self, name: &Value, input: &Value) {
+        self.start_tool_use(None, name);
+        self.tool_input(&input.to_string());
+        self.close_block();
+    }
+
+    /// Opens a tool call whose input arrives in pieces through `tool_input`.
+    pub fn start_tool_use(&mut self, id: Option<&str>, name: &Value) {
+        self.close_block();
+        let id = id.map_or_else(tool_use_id, String::from);
+        self.open_block(
+            Block::ToolUse,
+            || json!({"type": "tool_use", "id": id, "name": name, "input": {}}),
+        );
+        self.used_tools = true;
+    }
+
+    /// More of the open tool call's input, as a fragment of JSON.
+    pub fn tool_input(&mut self, partial_json: &str) {
+        if self.open == Some(Block::ToolUse) {
+            self.delta(json!({"type": "input_json_delta", "partial_json": partial_json}));
+        }
+    }
+
+    pub fn error(&mut self, message: &str) {
+        self.start();
+        self.out
+            .push_str(&sse_event("error", &error_json(500, message)));
+    }
+
+    pub fn finish(&mut self, stop: StopReason, input_tokens: u64, output_tokens: u64) {
+        self.start();
+        self.close_block();
+        self.output_tokens.store(output_tokens, Ordering::Relaxed);
+        let stop = stop.given_tools(self.used_tools);
+        self.out.push_str(&sse_event(
+            "message_delta",
+            &json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": stop.name(),
+                    "stop_sequence": stop.sequence(),
+                },
+                "usage": {"input_tokens": input_tokens, "output_tokens": output_tokens},
+            }),
+        ));
+        self.out
+            .push_str(&sse_event("message_stop", &json!({"type": "message_stop"})));
+    }
+
+    /// Opens a block of `block`'s kind unless one is already open.
+    fn open_block(&mut self, block: Block, content_block: impl FnOnce() -> Value) {
+        self.start();
+        if self.open == Some(block) {
+            return;
+        }
+        self.close_block();
+        self.out.push_str(&sse_event(
+            "content_block_start",
+            &json!({
+                "type": "content_block_start",
+                "index": self.index,
+                "content_block": content_block(),
+            }),
+        ));
+        self.open = Some(block);
+    }
+
+    fn close_block(&mut self) {
+        if self.open.take().is_some() {
+            self.stop();
+        }
+    }
+
+    fn delta(&mut self, delta: Value) {
+        self.out.push_str(&sse_event(
+            "content_block_delta",
+            &json!({"type": "content_block_delta", "index": self.index, "delta": delta}),
+        ));
+    }
+
+    fn stop(&mut self) {
+        self.out.push_str(&sse_event(
+            "content_block_stop",
+            &json!({"type": "content_block_stop", "index": self.index}),
+        ));
+        self.index += 1;
+    }
+}
+
+/// Parses translated SSE output into (event, data) pairs.
+#[cfg(test)]
+pub(crate) fn parse_events(sse: &[u8]) -> Vec<(String, Value)> {
+    std::str::from_utf8(sse)
+        .unwrap()
+        .split("\n\n")
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            let (name, data) = e.split_once('\n').unwrap();
+            (
+                name.trim_start_matches("event: ").to_string(),
+                serde_json::from_str(data.trim_start_matches("data: ")).unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_of_joins_text_blocks() {
+        let content = json!([
+            {"type": "text", "text": "one"},
+            {"type": "image", "source": {}},
+            {"type": "text", "text": "two"}
+        ]);
+        assert_eq!(text_of(&content), "one\ntwo");
+        assert_eq!(text_of(&json!("plain")), "plain");
+    }
+
+    #[test]
+    fn error_types_follow_status() {
+        assert_eq!(
+            error_json(404, "no such model")["error"]["type"],
+            "not_found_error"
+        );
+        assert_eq!(error_json(500, "boom")["error"]["type"], "api_error");
+    }
+
+    #[test]
+    fn errors_are_read_per_format() {
+        let ollama = Translation::Ollama.error(404, br#"{"error":"model 'x' not found"}"#);
+        assert_eq!(ollama["error"]["message"], "model 'x' not found");
+        let gemini = Translation::Gemini.error(
+            400,
+            br#"{"error":{"code":400,"message":"API key not valid","status":"INVALID_ARGUMENT"}}"#,
+        );
+        assert_eq!(gemini["error"]["type"], "invalid_request_error");
+        assert_eq!(gemini["error"]["message"], "API key not valid");
+        let raw = Translation::Gemini.error(502, b"bad gateway");
+        assert_eq!(raw["error"]["message"], "bad gateway");
+    }
+
+    #[test]
+    fn line_buffer_holds_partial_lines() {
+        let mut lines = LineBuffer::default();
+        assert!(lines.push(b"one\ntw").len() == 1);
+        assert_eq!(lines.push(b"o\n"), vec![b"two\n".to_vec()]);
+    }
+
+    #[test]
+    fn writer_switches_blocks_by_kind() {
+        let mut writer = EventWriter::new("m", Arc::new(AtomicU64::new(0)));
+        writer.thinking("hmm");
+        writer.text("a");
+        writer.text("b");
+        writer.finish(StopReason::MaxTokens, 3, 4);
+        let events = parse_events(&writer.take());
+        let names: Vec<&str> = events.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert_eq!(events[4].1["index"], 1);
+        assert_eq!(events[8].1["delta"]["stop_reason"], "max_tokens");
+    }
+
+    #[test]
+    fn finish_reasons_map_to_stop_reasons() {
+        for (reason, stop) in [
+            ("stop", "end_turn"),
+            ("STOP", "end_turn"),
+            ("unload", "end_turn"),
+            ("length", "max_tokens"),
+            ("MAX_TOKENS", "max_tokens"),
+            ("tool_calls", "tool_use"),
+            ("content_filter", "refusal"),
+            ("SAFETY", "refusal"),
+        ] {
+            assert_eq!(StopReason::from_finish(reason).name(), stop, "{reason}");
+        }
+        assert_eq!(StopReason::EndTurn.given_tools(true), StopReason::ToolUse);
+        assert_eq!(StopReason::ToolUse.given_tools(false), StopReason::EndTurn);
+        assert_eq!(
+            StopReason::MaxTokens.given_tools(true),
+            StopReason::MaxTokens
+        );
+        let sequence = StopReason::StopSequence("END".to_string());
+        assert_eq!(sequence.sequence(), Some("END"));
+        assert_eq!(sequence.given_tools(false).name(), "stop_sequence");
+    }
+}
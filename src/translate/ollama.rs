@@ -0,0 +1,409 @@
+//! Ollama's native `/api/chat`, which streams newline-delimited JSON rather
+//! than server-sent events.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde_json::{Map, Value, json};
+
+use super::{EventWriter, LineBuffer, StopReason, message_id, text_of, tool_use_id};
+
+/// Path of the chat endpoint, relative to the provider URL.
+pub const CHAT_PATH: &str = "/api/chat";
+
+/// Translates a Messages request body into an `/api/chat` body for `model`.
+pub fn chat_request(body: &Value, model: &str) -> Value {
+    let mut messages = Vec::new();
+    if let Some(system) = body.get("system") {
+        let text = text_of(system);
+        if !text.is_empty() {
+            messages.push(json!({"role": "system", "content": text}));
+        }
+    }
+
+    // Tool results only carry the call's ID; Ollama wants the tool's name.
+    let mut tool_names = HashMap::new();
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let role = message["role"].as_str().unwrap_or("user");
+        match message["content"] {
+            Value::String(ref text) => messages.push(json!({"role": role, "content": text})),
+            Value::Array(ref blocks) => push_blocks(&mut messages, role, blocks, &mut tool_names),
+            _ => {}
+        }
+    }
+
+    let mut request = json!({
+        "model": model,
+        "messages": messages,
+        "stream": body["stream"].as_bool().unwrap_or(false),
+    });
+
+    if let Some(tools) = body["tools"].as_array() {
+        let tools: Vec<Value> = tools
+            .iter()
+            .filter(|t| t.get("input_schema").is_some())
+            .map(|t| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": t["name"],
+                        "description": t["description"],
+                        "parameters": t["input_schema"],
+                    }
+                })
+            })
+            .collect();
+        if !tools.is_empty() {
+            request["tools"] = Value::Array(tools);
+        }
+    }
+
+    let mut options = Map::new();
+    for (from, to) in [
+        ("max_tokens", "num_predict"),
+        ("temperature", "temperature"),
+        ("top_p", "top_p"),
+        ("top_k", "top_k"),
+        ("stop_sequences", "stop"),
+    ] {
+        if let Some(value) = body.get(from) {
+            options.insert(to.to_string(), value.clone());
+        }
+    }
+    if !options.is_empty() {
+        request["options"] = Value::Object(options);
+    }
+
+    if body["thinking"]["type"] == "enabled" {
+        request["think"] = Value::Bool(true);
+    }
+
+    request
+}
+
+fn push_blocks(
+    messages: &mut Vec<Value>,
+    role: &str,
+    blocks: &[Value],
+    tool_names: &mut HashMap<String, String>,
+) {
+    let mut text = Vec::new();
+    let mut thinking = Vec::new();
+    let mut images = Vec::new();
+    let mut tool_calls = Vec::new();
+
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => text.extend(block["text"].as_str()),
+            Some("thinking") => thinking.extend(block["thinking"].as_str()),
+            Some("image") => images.extend(block["source"]["data"].as_str()),
+            Some("tool_use") => {
+                if let (Some(id), Some(name)) = (block["id"].as_str(), block["name"].as_str()) {
+                    tool_names.insert(id.to_string(), name.to_string());
+                }
+                tool_calls.push(json!({
+                    "function": {"name": block["name"], "arguments": block["input"]}
+                }));
+            }
+            Some("tool_result") => {
+                let mut result = json!({"role": "tool", "content": text_of(&block["content"])});
+                if let Some(name) = block["tool_use_id"]
+                    .as_str()
+                    .and_then(|id| tool_names.get(id))
+                {
+                    result["tool_name"] = Value::String(name.clone());
+                }
+                messages.push(result);
+            }
+            _ => {}
+        }
+    }
+
+    if text.is_empty() && thinking.is_empty() && images.is_empty() && tool_calls.is_empty() {
+        return;
+    }
+    let mut message = json!({"role": role, "content": text.join("\n")});
+    if !thinking.is_empty() {
+        message["thinking"] = Value::String(thinking.join("\n"));
+    }
+    if !images.is_empty() {
+        message["images"] = json!(images);
+    }
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    messages.push(message);
+}
+
+/// Ollama returns arguments as an object, but some models produce a JSON
+/// string instead.
+fn tool_arguments(arguments: &Value) -> Value {
+    match arguments {
+        Value::String(s) => serde_json::from_str(s).unwrap_or_else(|_| json!({})),
+        Value::Null => json!({}),
+        other => other.clone(),
+    }
+}
+
+/// Why the response ended. Ollama doesn't say when it stopped at a stop
+/// sequence, so those end the turn.
+fn stop_reason(chat: &Value) -> StopReason {
+    StopReason::from_finish(chat["done_reason"].as_str().unwrap_or_default())
+}
+
+/// Translates a complete `/api/chat` response into a Messages response,
+/// reported under the model the client asked for.
+pub fn messages_response(chat: &Value, model: &str) -> Value {
+    let message = &chat["message"];
+    let mut content = Vec::new();
+    if let Some(thinking) = message["thinking"].as_str().filter(|t| !t.is_empty()) {
+        content.push(json!({"type": "thinking", "thinking": thinking, "signature": ""}));
+    }
+    if let Some(text) = message["content"].as_str().filter(|t| !t.is_empty()) {
+        content.push(json!({"type": "text", "text": text}));
+    }
+    let calls = message["tool_calls"].as_array().map_or(&[][..], |c| c);
+    for call in calls {
+        content.push(json!({
+            "type": "tool_use",
+            "id": tool_use_id(),
+            "name": call["function"]["name"],
+            "input": tool_arguments(&call["function"]["arguments"]),
+        }));
+    }
+
+    json!({
+        "id": message_id(),
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": stop_reason(chat).given_tools(!calls.is_empty()).name(),
+        "stop_sequence": null,
+        "usage": {
+            "input_tokens": chat["prompt_eval_count"].as_u64().unwrap_or(0),
+            "output_tokens": chat["eval_count"].as_u64().unwrap_or(0),
+        },
+    })
+}
+
+/// Translates a streamed `/api/chat` response, one JSON object per line.
+pub struct ChatStream {
+    lines: LineBuffer,
+    writer: EventWriter,
+}
+
+impl ChatStream {
+    pub fn new(writer: EventWriter) -> Self {
+        Self {
+            lines: LineBuffer::default(),
+            writer,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Bytes {
+        for line in self.lines.push(chunk) {
+            if let Ok(value) = serde_json::from_slice::<Value>(&line) {
+                self.translate(&value);
+            }
+        }
+        self.writer.take()
+    }
+
+    fn translate(&mut self, chunk: &Value) {
+        if let Some(error) = chunk["error"].as_str() {
+            self.writer.error(error);
+            return;
+        }
+        let message = &chunk["message"];
+        if let Some(thinking) = message["thinking"].as_str().filter(|t| !t.is_empty()) {
+            self.writer.thinking(thinking);
+        }
+        if let Some(text) = message["content"].as_str().filter(|t| !t.is_empty()) {
+            self.writer.text(text);
+        }
+        for call in message["tool_calls"].as_array().into_iter().flatten() {
+            self.writer.tool_use(
+                &call["function"]["name"],
+                &tool_arguments(&call["function"]["arguments"]),
+            );
+        }
+        if chunk["done"] == true {
+            self.writer.finish(
+                stop_reason(chunk),
+                chunk["prompt_eval_count"].as_u64().unwrap_or(0),
+                chunk["eval_count"].as_u64().unwrap_or(0),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::translate::parse_events as events;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn request_maps_system_messages_and_options() {
+        let body = json!({
+            "model": "claude-sonnet-4-5",
+            "system": [{"type": "text", "text": "be brief"}],
+            "max_tokens": 512,
+            "temperature": 0.2,
+            "stop_sequences": ["END"],
+            "stream": true,
+            "messages": [
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": [{"type": "text", "text": "hello"}]}
+            ]
+        });
+        let chat = chat_request(&body, "qwen3-coder:30b");
+        assert_eq!(chat["model"], "qwen3-coder:30b");
+        assert_eq!(chat["stream"], true);
+        assert_eq!(
+            chat["messages"],
+            json!([
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "hello"}
+            ])
+        );
+        assert_eq!(
+            chat["options"],
+            json!({"num_predict": 512, "temperature": 0.2, "stop": ["END"]})
+        );
+    }
+
+    #[test]
+    fn request_maps_tools_and_results() {
+        let body = json!({
+            "tools": [{
+                "name": "read_file",
+                "description": "Read a file",
+                "input_schema": {"type": "object"}
+            }],
+            "messages": [
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "read_file", "input": {"path": "a.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "fn main() {}"},
+                    {"type": "text", "text": "explain"}
+                ]}
+            ]
+        });
+        let chat = chat_request(&body, "llama3");
+        assert_eq!(chat["stream"], false);
+        assert_eq!(chat["tools"][0]["function"]["name"], "read_file");
+        assert_eq!(
+            chat["tools"][0]["function"]["parameters"],
+            json!({"type": "object"})
+        );
+        let messages = chat["messages"].as_array().unwrap();
+        assert_eq!(
+            messages[0]["tool_calls"][0]["function"],
+            json!({"name": "read_file", "arguments": {"path": "a.rs"}})
+        );
+        assert_eq!(
+            messages[1],
+            json!({"role": "tool", "content": "fn main() {}", "tool_name": "read_file"})
+        );
+        assert_eq!(messages[2], json!({"role": "user", "content": "explain"}));
+    }
+
+    #[test]
+    fn response_maps_text_tools_and_usage() {
+        let chat = json!({
+            "message": {
+                "role": "assistant",
+                "content": "let me look",
+                "tool_calls": [{"function": {"name": "read_file", "arguments": {"path": "a.rs"}}}]
+            },
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 40,
+            "eval_count": 12
+        });
+        let message = messages_response(&chat, "claude-sonnet-4-5");
+        assert_eq!(message["model"], "claude-sonnet-4-5");
+        assert_eq!(message["stop_reason"], "tool_use");
+        assert_eq!(message["content"][0]["text"], "let me look");
+        assert_eq!(message["content"][1]["type"], "tool_use");
+        assert_eq!(message["content"][1]["input"], json!({"path": "a.rs"}));
+        assert_eq!(
+            message["usage"],
+            json!({"input_tokens": 40, "output_tokens": 12})
+        );
+    }
+
+    #[test]
+    fn length_limit_is_max_tokens() {
+        let chat = json!({"message": {"content": "cut"}, "done_reason": "length"});
+        assert_eq!(messages_response(&chat, "m")["stop_reason"], "max_tokens");
+    }
+
+    #[test]
+    fn stream_emits_message_events() {
+        let tokens = Arc::new(AtomicU64::new(0));
+        let mut stream = ChatStream::new(EventWriter::new("claude-sonnet-4-5", tokens.clone()));
+        let ndjson = concat!(
+            r#"{"message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            "\n",
+            r#"{"message":{"role":"assistant","content":"lo"},"done":false}"#,
+            "\n",
+            r#"{"message":{"role":"assistant","content":""},"done":true,"done_reason":"stop","eval_count":7}"#,
+            "\n",
+        );
+        // Split mid-line to exercise buffering.
+        let (a, b) = ndjson.split_at(20);
+        let mut out = stream.push(a.as_bytes()).to_vec();
+        out.extend_from_slice(&stream.push(b.as_bytes()));
+
+        let events = events(&out);
+        let names: Vec<&str> = events.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        assert_eq!(events[0].1["message"]["model"], "claude-sonnet-4-5");
+        assert_eq!(events[3].1["delta"]["text"], "lo");
+        assert_eq!(events[5].1["delta"]["stop_reason"], "end_turn");
+        assert_eq!(events[5].1["usage"]["output_tokens"], 7);
+        assert_eq!(tokens.load(Ordering::Relaxed), 7);
+    }
+
+    #[test]
+    fn stream_tool_calls_get_their_own_blocks() {
+        let mut stream = ChatStream::new(EventWriter::new("m", Arc::new(AtomicU64::new(0))));
+        let out = stream.push(
+            concat!(
+                r#"{"message":{"thinking":"hmm"},"done":false}"#,
+                "\n",
+                r#"{"message":{"content":"","tool_calls":[{"function":{"name":"ls","arguments":{"dir":"."}}}]},"done":false}"#,
+                "\n",
+                r#"{"message":{"content":""},"done":true,"done_reason":"stop"}"#,
+                "\n",
+            )
+            .as_bytes(),
+        );
+        let events = events(&out);
+        assert_eq!(events[1].1["content_block"]["type"], "thinking");
+        assert_eq!(events[2].1["delta"]["thinking"], "hmm");
+        assert_eq!(events[3].0, "content_block_stop");
+        assert_eq!(events[4].1["index"], 1);
+        assert_eq!(events[4].1["content_block"]["name"], "ls");
+        assert_eq!(events[5].1["delta"]["partial_json"], r#"{"dir":"."}"#);
+        assert_eq!(events[7].1["delta"]["stop_reason"], "tool_use");
+    }
+}
@@ -0,0 +1,464 @@
+//! Which keys do what in the TUI. Each action has default keys, which
+//! `[tui.keys]` replaces action by action; a binding is one key or a
+//! sequence of two, such as `g g`. Ctrl-C always quits, and `y` always
+//! confirms a daemon command.
+
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::config::{KeyBinding, TuiKeys};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Quit,
+    Detach,
+    Overview,
+    Models,
+    Providers,
+    Errors,
+    Compare,
+    Clients,
+    NextTab,
+    PrevTab,
+    ScrollDown,
+    ScrollUp,
+    ScrollTop,
+    ScrollBottom,
+    Routing,
+    ForceProvider,
+    Reload,
+    Drain,
+    Alerts,
+    ToggleRoute,
+    Pin,
+}
+
+impl Action {
+    pub const ALL: [Action; 21] = [
+        Action::Quit,
+        Action::Detach,
+        Action::Overview,
+        Action::Models,
+        Action::Providers,
+        Action::Errors,
+        Action::Compare,
+        Action::Clients,
+        Action::NextTab,
+        Action::PrevTab,
+        Action::ScrollDown,
+        Action::ScrollUp,
+        Action::ScrollTop,
+        Action::ScrollBottom,
+        Action::Routing,
+        Action::ForceProvider,
+        Action::Reload,
+        Action::Drain,
+        Action::Alerts,
+        Action::ToggleRoute,
+        Action::Pin,
+    ];
+
+    /// The action's key under `[tui.keys]`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::Detach => "detach",
+            Action::Overview => "overview",
+            Action::Models => "models",
+            Action::Providers => "providers",
+            Action::Errors => "errors",
+            Action::Compare => "compare",
+            Action::Clients => "clients",
+            Action::NextTab => "next_tab",
+            Action::PrevTab => "prev_tab",
+            Action::ScrollDown => "scroll_down",
+            Action::ScrollUp => "scroll_up",
+            Action::ScrollTop => "scroll_top",
+            Action::ScrollBottom => "scroll_bottom",
+            Action::Routing => "routing",
+            Action::ForceProvider => "force_provider",
+            Action::Reload => "reload",
+            Action::Drain => "drain",
+            Action::Alerts => "alerts",
+            Action::ToggleRoute => "toggle_route",
+            Action::Pin => "pin",
+        }
+    }
+
+    fn default_keys(self) -> &'static [&'static str] {
+        match self {
+            Action::Quit => &["q"],
+            Action::Detach => &["d"],
+            Action::Overview => &["1"],
+            Action::Models => &["2"],
+            Action::Providers => &["3"],
+            Action::Errors => &["4"],
+            Action::Compare => &["5"],
+            Action::Clients => &["6"],
+            Action::NextTab => &["tab", "right", "l"],
+            Action::PrevTab => &["left", "h"],
+            Action::ScrollDown => &["j", "down"],
+            Action::ScrollUp => &["k", "up"],
+            Action::ScrollTop => &["g g", "home"],
+            Action::ScrollBottom => &["G", "end"],
+            Action::Routing => &["o"],
+            Action::ForceProvider => &["p"],
+            Action::Reload => &["r"],
+            Action::Drain => &["X"],
+            Action::Alerts => &["a"],
+            Action::ToggleRoute => &["enter", "space"],
+            Action::Pin => &["P"],
+        }
+    }
+
+    /// Whether the action applies in the routing panel. Keys bound to
+    /// none of these fall through to the tabs while it is open.
+    pub fn in_panel(self) -> bool {
+        matches!(
+            self,
+            Action::Routing
+                | Action::ScrollDown
+                | Action::ScrollUp
+                | Action::ScrollTop
+                | Action::ScrollBottom
+                | Action::ToggleRoute
+                | Action::Pin
+        )
+    }
+
+    /// Whether the action applies only in the routing panel.
+    fn panel_only(self) -> bool {
+        matches!(self, Action::ToggleRoute | Action::Pin)
+    }
+
+    /// Whether the two can both apply to the same key press. Panel
+    /// actions come first in the panel, so they can share keys with
+    /// actions that apply only outside it.
+    fn overlaps(self, other: Action) -> bool {
+        (!self.panel_only() && !other.panel_only()) || (self.in_panel() && other.in_panel())
+    }
+}
+
+/// A key as the keymap matches it: Shift is part of the character, so
+/// only Ctrl is told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    code: KeyCode,
+    ctrl: bool,
+}
+
+impl Key {
+    fn of(event: &KeyEvent) -> Self {
+        Self {
+            code: event.code,
+            ctrl: event.modifiers.contains(KeyModifiers::CONTROL),
+        }
+    }
+
+    /// Parses `"q"`, `"X"`, `"down"`, `"f5"`, or `"ctrl-d"`.
+    fn parse(text: &str) -> Result<Self, String> {
+        let (ctrl, name) = match text.get(..5) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("ctrl-") && text.len() > 5 => {
+                (true, &text[5..])
+            }
+            _ => (false, text),
+        };
+        let mut chars = name.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match name.to_ascii_lowercase().as_str() {
+                "space" => KeyCode::Char(' '),
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "enter" => KeyCode::Enter,
+                "esc" => KeyCode::Esc,
+                "backspace" => KeyCode::Backspace,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                f => match f.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => return Err(format!("unknown key '{text}'")),
+                },
+            },
+        };
+        if ctrl && code == KeyCode::Char('c') {
+            return Err("ctrl-c always quits and can't be rebound".to_string());
+        }
+        Ok(Self { code, ctrl })
+    }
+
+    /// The key as the footer shows it.
+    fn label(self) -> String {
+        let name = match self.code {
+            KeyCode::Char(' ') => "space".to_string(),
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::F(n) => format!("f{n}"),
+            KeyCode::BackTab => "backtab".to_string(),
+            KeyCode::PageUp => "pageup".to_string(),
+            KeyCode::PageDown => "pagedown".to_string(),
+            other => other.to_string().to_lowercase(),
+        };
+        if self.ctrl {
+            format!("ctrl-{name}")
+        } else {
+            name
+        }
+    }
+}
+
+/// Parses one key, or a sequence of two separated by a space.
+fn parse_sequence(text: &str) -> Result<Vec<Key>, String> {
+    if text.chars().count() == 1 {
+        return Ok(vec![Key::parse(text)?]);
+    }
+    let keys: Vec<Key> = text
+        .split_whitespace()
+        .map(Key::parse)
+        .collect::<Result<_, _>>()?;
+    match keys.len() {
+        1 | 2 => Ok(keys),
+        _ => Err(format!("'{text}' is not one key or a sequence of two")),
+    }
+}
+
+/// A sequence as the footer shows it: `gg`, or `ctrl-w j`.
+fn sequence_label(keys: &[Key]) -> String {
+    let labels: Vec<String> = keys.iter().map(|key| key.label()).collect();
+    if labels.iter().all(|label| label.chars().count() == 1) {
+        labels.concat()
+    } else {
+        labels.join(" ")
+    }
+}
+
+/// Key sequences bound to each action.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    keys: HashMap<Action, Vec<Vec<Key>>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let keys = Action::ALL
+            .into_iter()
+            .map(|action| {
+                let keys = action
+                    .default_keys()
+                    .iter()
+                    .map(|k| parse_sequence(k).expect("default keys parse"));
+                (action, keys.collect())
+            })
+            .collect();
+        Self { keys }
+    }
+}
+
+impl Keymap {
+    /// The defaults with `[tui.keys]` applied. A key bound to two actions
+    /// that can both apply to the same key press is an error, as one
+    /// would shadow the other; so is a key that starts another action's
+    /// sequence.
+    pub fn from_config(config: &TuiKeys) -> Result<Self, String> {
+        let mut keymap = Self::default();
+        for (action, binding) in configured(config) {
+            let Some(binding) = binding else {
+                continue;
+            };
+            if binding.keys().is_empty() {
+                return Err(format!("tui.keys.{} has no keys", action.name()));
+            }
+            let keys = binding
+                .keys()
+                .iter()
+                .map(|k| parse_sequence(k).map_err(|e| format!("tui.keys.{}: {e}", action.name())))
+                .collect::<Result<_, _>>()?;
+            keymap.keys.insert(action, keys);
+        }
+        for (i, first) in Action::ALL.into_iter().enumerate() {
+            for second in Action::ALL.into_iter().skip(i + 1) {
+                if !first.overlaps(second) {
+                    continue;
+                }
+                for a in &keymap.keys[&first] {
+                    for b in &keymap.keys[&second] {
+                        if a == b {
+                            return Err(format!(
+                                "tui.keys: '{}' is bound to both {} and {}",
+                                sequence_label(a),
+                                first.name(),
+                                second.name()
+                            ));
+                        }
+                        let ((short, shorter), (long, longer)) = if a.len() < b.len() {
+                            ((a, first), (b, second))
+                        } else {
+                            ((b, second), (a, first))
+                        };
+                        if long.starts_with(short) {
+                            return Err(format!(
+                                "tui.keys: '{}' of {} starts '{}' of {}",
+                                sequence_label(short),
+                                shorter.name(),
+                                sequence_label(long),
+                                longer.name()
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// The action the key presses in `events` are bound to, if any. With
+    /// the routing panel open, its actions come first.
+    pub fn action(&self, events: &[KeyEvent], panel: bool) -> Option<Action> {
+        let keys: Vec<Key> = events.iter().map(Key::of).collect();
+        let bound = |action: &Action| self.keys[action].contains(&keys);
+        let mut actions = Action::ALL.into_iter();
+        panel
+            .then(|| actions.clone().filter(|a| a.in_panel()).find(bound))
+            .flatten()
+            .or_else(|| actions.find(|a| !a.panel_only() && bound(a)))
+    }
+
+    /// Whether `event` is the first of a sequence, to wait for the next
+    /// key press before acting on it.
+    pub fn starts_sequence(&self, event: &KeyEvent) -> bool {
+        let key = Key::of(event);
+        self.keys
+            .values()
+            .flatten()
+            .any(|keys| keys.len() > 1 && keys[0] == key)
+    }
+
+    /// The first key bound to `action`, for hints.
+    pub fn label(&self, action: Action) -> String {
+        self.keys[&action]
+            .first()
+            .map_or_else(String::new, |keys| sequence_label(keys))
+    }
+}
+
+fn configured(config: &TuiKeys) -> [(Action, Option<&KeyBinding>); 21] {
+    [
+        (Action::Quit, config.quit.as_ref()),
+        (Action::Detach, config.detach.as_ref()),
+        (Action::Overview, config.overview.as_ref()),
+        (Action::Models, config.models.as_ref()),
+        (Action::Providers, config.providers.as_ref()),
+        (Action::Errors, config.errors.as_ref()),
+        (Action::Compare, config.compare.as_ref()),
+        (Action::Clients, config.clients.as_ref()),
+        (Action::NextTab, config.next_tab.as_ref()),
+        (Action::PrevTab, config.prev_tab.as_ref()),
+        (Action::ScrollDown, config.scroll_down.as_ref()),
+        (Action::ScrollUp, config.scroll_up.as_ref()),
+        (Action::ScrollTop, config.scroll_top.as_ref()),
+        (Action::ScrollBottom, config.scroll_bottom.as_ref()),
+        (Action::Routing, config.routing.as_ref()),
+        (Action::ForceProvider, config.force_provider.as_ref()),
+        (Action::Reload, config.reload.as_ref()),
+        (Action::Drain, config.drain.as_ref()),
+        (Action::Alerts, config.alerts.as_ref()),
+        (Action::ToggleRoute, config.toggle_route.as_ref()),
+        (Action::Pin, config.pin.as_ref()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn keymap(toml: &str) -> Result<Keymap, String> {
+        let config: crate::config::Config =
+            Figment::new().merge(Toml::string(toml)).extract().unwrap();
+        Keymap::from_config(&config.tui.keys)
+    }
+
+    fn press(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn configured_keys_replace_an_actions_defaults() {
+        let keys = keymap("[tui.keys]\ndetach = \"ctrl-d\"\nscroll_down = [\"n\", \"pagedown\"]\n")
+            .unwrap();
+        assert_eq!(keys.action(&[press(KeyCode::Char('d'))], false), None);
+        assert_eq!(
+            keys.action(
+                &[KeyEvent::new(KeyCode::Char('d'), KeyModifiers::CONTROL)],
+                false
+            ),
+            Some(Action::Detach)
+        );
+        assert_eq!(
+            keys.action(&[press(KeyCode::PageDown)], true),
+            Some(Action::ScrollDown)
+        );
+        assert_eq!(keys.action(&[press(KeyCode::Char('j'))], false), None);
+        // Actions left out keep their defaults
+        assert_eq!(
+            keys.action(&[press(KeyCode::Char('q'))], false),
+            Some(Action::Quit)
+        );
+        assert_eq!(keys.label(Action::Detach), "ctrl-d");
+        assert_eq!(keys.label(Action::ToggleRoute), "enter");
+    }
+
+    #[test]
+    fn keys_bound_twice_are_rejected() {
+        assert_eq!(
+            keymap("[tui.keys]\nquit = \"d\"\n").unwrap_err(),
+            "tui.keys: 'd' is bound to both quit and detach"
+        );
+        // Panel keys only shadow each other within the panel
+        assert!(keymap("[tui.keys]\npin = \"r\"\n").is_ok());
+        assert_eq!(
+            keymap("[tui.keys]\npin = \"k\"\n").unwrap_err(),
+            "tui.keys: 'k' is bound to both scroll_up and pin"
+        );
+        assert_eq!(
+            keymap("[tui.keys]\nreload = \"f13\"\n").unwrap_err(),
+            "tui.keys.reload: unknown key 'f13'"
+        );
+        assert_eq!(
+            keymap("[tui.keys]\nquit = \"ctrl-c\"\n").unwrap_err(),
+            "tui.keys.quit: ctrl-c always quits and can't be rebound"
+        );
+    }
+
+    #[test]
+    fn sequences_wait_for_their_second_key() {
+        let keys = Keymap::default();
+        let g = press(KeyCode::Char('g'));
+        assert!(keys.starts_sequence(&g));
+        assert_eq!(keys.action(&[g], false), None);
+        assert_eq!(keys.action(&[g, g], false), Some(Action::ScrollTop));
+        assert_eq!(keys.label(Action::ScrollTop), "gg");
+
+        assert_eq!(
+            keymap("[tui.keys]\nreload = \"g\"\n").unwrap_err(),
+            "tui.keys: 'g' of reload starts 'gg' of scroll_top"
+        );
+        assert_eq!(
+            keymap("[tui.keys]\nscroll_top = \"ctrl-w k\"\n")
+                .unwrap()
+                .label(Action::ScrollTop),
+            "ctrl-w k"
+        );
+        assert_eq!(
+            keymap("[tui.keys]\nscroll_top = \"g g g\"\n").unwrap_err(),
+            "tui.keys.scroll_top: 'g g g' is not one key or a sequence of two"
+        );
+    }
+}
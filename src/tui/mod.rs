@@ -1,14 +1,22 @@
+pub mod keys;
 pub mod views;
 
+use std::collections::HashSet;
 use std::io;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
 use ratatui::prelude::*;
 use ratatui::widgets::{Block, Borders, Paragraph, Tabs};
+use tokio::sync::watch;
 
+use crate::alerts::{Alert, Thresholds};
+use crate::config::{LiveLogConfig, TuiConfig};
+use crate::control::{Command, CommandChannel, RoutingState, Viewers};
+use crate::keys::KeyStore;
 use crate::metrics::MetricsStore;
+use keys::{Action, Keymap};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Tab {
@@ -16,11 +24,24 @@ pub enum Tab {
     Models,
     Providers,
     Errors,
+    Compare,
+    Clients,
 }
 
 impl Tab {
-    fn titles() -> Vec<&'static str> {
-        vec!["Overview [1]", "Models [2]", "Providers [3]", "Errors [4]"]
+    /// Each tab's title, with the key that shows it.
+    fn titles(keys: &Keymap) -> Vec<String> {
+        [
+            ("Overview", Action::Overview),
+            ("Models", Action::Models),
+            ("Providers", Action::Providers),
+            ("Errors", Action::Errors),
+            ("Compare", Action::Compare),
+            ("Clients", Action::Clients),
+        ]
+        .into_iter()
+        .map(|(title, action)| format!("{title} [{}]", keys.label(action)))
+        .collect()
     }
 
     fn index(self) -> usize {
@@ -29,6 +50,8 @@ impl Tab {
             Tab::Models => 1,
             Tab::Providers => 2,
             Tab::Errors => 3,
+            Tab::Compare => 4,
+            Tab::Clients => 5,
         }
     }
 }
@@ -42,9 +65,35 @@ pub enum ExitMode {
 pub struct App {
     pub metrics: Arc<MetricsStore>,
     pub active_tab: Tab,
+    /// Rows scrolled past in the active tab's table; drawing clamps it to
+    /// where the last row is at the bottom.
     pub scroll_offset: usize,
     pub exit_mode: Option<ExitMode>,
     pub attached: bool,
+    /// Viewers attached to this instance, shown when running as the daemon.
+    pub viewers: Option<Viewers>,
+    /// Daemon commands, available when attached.
+    pub commands: Option<CommandChannel>,
+    /// Routing state last reported by the daemon.
+    pub routing: Option<RoutingState>,
+    /// Command waiting for y/n confirmation.
+    pub confirm: Option<Command>,
+    /// Outcome of the last command, shown in the footer.
+    pub notice: Option<(bool, String)>,
+    pub routing_panel: bool,
+    pub route_cursor: usize,
+    /// What each key does, from `[tui.keys]`.
+    pub keys: Keymap,
+    pub live_log: LiveLogConfig,
+    pub alert_thresholds: Thresholds,
+    /// Virtual keys whose budgets alert, when running as the daemon.
+    pub budgets: Option<Arc<KeyStore>>,
+    alerts: Vec<Alert>,
+    /// Alerts seen in the alert list, until their cause clears.
+    acknowledged: HashSet<String>,
+    pub alert_panel: bool,
+    /// The first key of a sequence, such as `gg`, waiting for the second.
+    pending_key: Option<event::KeyEvent>,
 }
 
 impl App {
@@ -55,87 +104,297 @@ impl App {
             scroll_offset: 0,
             exit_mode: None,
             attached,
+            viewers: None,
+            commands: None,
+            routing: None,
+            confirm: None,
+            notice: None,
+            routing_panel: false,
+            route_cursor: 0,
+            keys: Keymap::default(),
+            live_log: LiveLogConfig::default(),
+            alert_thresholds: Thresholds::default(),
+            budgets: None,
+            alerts: Vec::new(),
+            acknowledged: HashSet::new(),
+            alert_panel: false,
+            pending_key: None,
         }
     }
 
+    /// Applies `[tui]`.
+    pub fn configure(&mut self, config: &TuiConfig) -> Result<(), String> {
+        self.keys = Keymap::from_config(&config.keys)?;
+        self.live_log = config.live_log.clone();
+        Ok(())
+    }
+
+    /// Works out which alerts apply now. Acknowledgements of alerts that
+    /// have cleared are forgotten, so they show again if they come back.
+    pub fn refresh_alerts(&mut self) {
+        self.alerts = crate::alerts::active(
+            &self.metrics,
+            self.budgets.as_deref(),
+            &self.alert_thresholds,
+        );
+        let active: HashSet<&str> = self.alerts.iter().map(|a| a.id.as_str()).collect();
+        self.acknowledged.retain(|id| active.contains(id.as_str()));
+    }
+
+    /// Opens the alert list, acknowledging what's in it.
+    fn show_alerts(&mut self) {
+        self.alert_panel = true;
+        self.acknowledged
+            .extend(self.alerts.iter().map(|a| a.id.clone()));
+    }
+
+    /// Applies replies that arrived since the last frame.
+    pub fn poll_replies(&mut self) {
+        let Some(ref commands) = self.commands else {
+            return;
+        };
+        while let Some(reply) = commands.try_recv() {
+            if let Some(routing) = reply.routing {
+                self.route_cursor = self
+                    .route_cursor
+                    .min(routing.routes.len().saturating_sub(1));
+                self.routing = Some(routing);
+            }
+            if !reply.message.is_empty() {
+                self.notice = Some((reply.ok, reply.message));
+            }
+        }
+    }
+
+    fn send(&mut self, command: Command) {
+        if let Some(ref commands) = self.commands {
+            commands.send(command);
+        }
+    }
+
+    /// The provider `p` switches to next: each provider in turn, then back
+    /// to normal routing.
+    fn next_forced(&self) -> Option<Command> {
+        let routing = self.routing.as_ref()?;
+        let next = match routing.forced {
+            None => routing.providers.first().cloned(),
+            Some(ref current) => routing
+                .providers
+                .iter()
+                .skip_while(|p| *p != current)
+                .nth(1)
+                .cloned(),
+        };
+        Some(Command::ForceProvider { provider: next })
+    }
+
+    /// The pin `P` sets for the selected route's pattern next: each
+    /// provider in turn, then unpinned.
+    fn next_pin(&self) -> Option<Command> {
+        let routing = self.routing.as_ref()?;
+        let pattern = routing.routes.get(self.route_cursor)?.pattern.clone();
+        let current = routing.pins.iter().find(|pin| pin.pattern == pattern);
+        let next = match current {
+            None => routing.providers.first().cloned(),
+            Some(pin) => routing
+                .providers
+                .iter()
+                .skip_while(|p| **p != pin.provider)
+                .nth(1)
+                .cloned(),
+        };
+        Some(match next {
+            Some(provider) => Command::Pin { pattern, provider },
+            None => Command::Unpin { pattern },
+        })
+    }
+
+    /// Handles keys for daemon commands, returning whether the keys were
+    /// used.
+    fn handle_command_key(&mut self, keys: &[event::KeyEvent]) -> bool {
+        let Some(action) = self.keys.action(keys, self.routing_panel) else {
+            let esc = keys.len() == 1 && keys[0].code == KeyCode::Esc;
+            return esc && std::mem::take(&mut self.routing_panel);
+        };
+        if self.routing_panel && action.in_panel() {
+            let routes = self.routing.as_ref().map_or(0, |r| r.routes.len());
+            match action {
+                Action::Routing => self.routing_panel = false,
+                Action::ScrollDown => {
+                    self.route_cursor = (self.route_cursor + 1).min(routes.saturating_sub(1));
+                }
+                Action::ScrollUp => self.route_cursor = self.route_cursor.saturating_sub(1),
+                Action::ScrollTop => self.route_cursor = 0,
+                Action::ScrollBottom => self.route_cursor = routes.saturating_sub(1),
+                Action::ToggleRoute => {
+                    if let Some(route) = self
+                        .routing
+                        .as_ref()
+                        .and_then(|r| r.routes.get(self.route_cursor))
+                    {
+                        self.confirm = Some(Command::SetRouteEnabled {
+                            index: route.index,
+                            enabled: !route.enabled,
+                        });
+                    }
+                }
+                Action::Pin => {
+                    if let Some(command) = self.next_pin() {
+                        self.confirm = Some(command);
+                    }
+                }
+                _ => {}
+            }
+            return true;
+        }
+        match action {
+            Action::Routing => {
+                self.routing_panel = true;
+                self.send(Command::Status);
+            }
+            Action::ForceProvider => match self.next_forced() {
+                Some(command) => self.confirm = Some(command),
+                None => self.send(Command::Status),
+            },
+            Action::Reload => self.confirm = Some(Command::Reload),
+            Action::Drain => self.confirm = Some(Command::Drain),
+            _ => return false,
+        }
+        true
+    }
+
+    fn show_tab(&mut self, tab: Tab) {
+        self.active_tab = tab;
+        self.scroll_offset = 0;
+    }
+
     pub fn handle_key(&mut self, key: event::KeyEvent) {
         if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
             self.exit_mode = Some(ExitMode::Quit);
             return;
         }
-        match key.code {
-            KeyCode::Char('q') => self.exit_mode = Some(ExitMode::Quit),
-            KeyCode::Char('d') if !self.attached => {
-                self.exit_mode = Some(ExitMode::Detach);
-            }
-            KeyCode::Char('1') => {
-                self.active_tab = Tab::Overview;
-                self.scroll_offset = 0;
-            }
-            KeyCode::Char('2') => {
-                self.active_tab = Tab::Models;
-                self.scroll_offset = 0;
+        if let Some(command) = self.confirm.take() {
+            if key.code == KeyCode::Char('y') {
+                self.send(command);
+            } else {
+                self.notice = Some((false, "cancelled".to_string()));
             }
-            KeyCode::Char('3') => {
-                self.active_tab = Tab::Providers;
-                self.scroll_offset = 0;
-            }
-            KeyCode::Char('4') => {
-                self.active_tab = Tab::Errors;
-                self.scroll_offset = 0;
+            return;
+        }
+        // The key after the first of a sequence completes it, or else
+        // counts on its own
+        let keys = match self.pending_key.take() {
+            Some(first)
+                if self
+                    .keys
+                    .action(&[first, key], self.routing_panel)
+                    .is_some() =>
+            {
+                vec![first, key]
             }
-            KeyCode::Tab | KeyCode::Right | KeyCode::Char('l') => {
-                self.active_tab = match self.active_tab {
-                    Tab::Overview => Tab::Models,
-                    Tab::Models => Tab::Providers,
-                    Tab::Providers => Tab::Errors,
-                    Tab::Errors => Tab::Overview,
-                };
-                self.scroll_offset = 0;
+            _ if self.keys.starts_sequence(&key) => {
+                self.pending_key = Some(key);
+                return;
             }
-            KeyCode::Left | KeyCode::Char('h') => {
-                self.active_tab = match self.active_tab {
-                    Tab::Overview => Tab::Errors,
-                    Tab::Models => Tab::Overview,
-                    Tab::Providers => Tab::Models,
-                    Tab::Errors => Tab::Providers,
-                };
-                self.scroll_offset = 0;
+            _ => vec![key],
+        };
+        if self.alert_panel
+            && (key.code == KeyCode::Esc || self.keys.action(&keys, false) == Some(Action::Alerts))
+        {
+            self.alert_panel = false;
+            return;
+        }
+        if self.commands.is_some() && self.handle_command_key(&keys) {
+            return;
+        }
+        let Some(action) = self.keys.action(&keys, false) else {
+            return;
+        };
+        match action {
+            Action::Quit => self.exit_mode = Some(ExitMode::Quit),
+            Action::Detach if !self.attached => {
+                self.exit_mode = Some(ExitMode::Detach);
             }
-            KeyCode::Char('j') | KeyCode::Down => {
+            Action::Overview => self.show_tab(Tab::Overview),
+            Action::Models => self.show_tab(Tab::Models),
+            Action::Providers => self.show_tab(Tab::Providers),
+            Action::Errors => self.show_tab(Tab::Errors),
+            Action::Compare => self.show_tab(Tab::Compare),
+            Action::Clients => self.show_tab(Tab::Clients),
+            Action::NextTab => self.show_tab(match self.active_tab {
+                Tab::Overview => Tab::Models,
+                Tab::Models => Tab::Providers,
+                Tab::Providers => Tab::Errors,
+                Tab::Errors => Tab::Compare,
+                Tab::Compare => Tab::Clients,
+                Tab::Clients => Tab::Overview,
+            }),
+            Action::PrevTab => self.show_tab(match self.active_tab {
+                Tab::Overview => Tab::Clients,
+                Tab::Models => Tab::Overview,
+                Tab::Providers => Tab::Models,
+                Tab::Errors => Tab::Providers,
+                Tab::Compare => Tab::Errors,
+                Tab::Clients => Tab::Compare,
+            }),
+            Action::ScrollDown => {
                 self.scroll_offset = self.scroll_offset.saturating_add(1);
             }
-            KeyCode::Char('k') | KeyCode::Up => {
+            Action::ScrollUp => {
                 self.scroll_offset = self.scroll_offset.saturating_sub(1);
             }
+            Action::ScrollTop => self.scroll_offset = 0,
+            // Clamped to the last row when drawn
+            Action::ScrollBottom => self.scroll_offset = usize::MAX,
+            Action::Alerts => self.show_alerts(),
             _ => {}
         }
     }
 
-    pub fn draw(&self, frame: &mut Frame) {
-        let title = if self.attached {
-            " croxy (attached) "
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let viewers = self.viewers.as_ref().map_or(0, Viewers::count);
+        let forced = self.routing.as_ref().and_then(|r| r.forced.as_deref());
+        let title = if let Some(provider) = forced {
+            format!(" croxy (attached) · forced to {provider} ")
+        } else if self.attached {
+            " croxy (attached) ".to_string()
+        } else if viewers > 0 {
+            format!(
+                " croxy · {viewers} viewer{} attached ",
+                if viewers == 1 { "" } else { "s" }
+            )
         } else {
-            " croxy "
+            " croxy ".to_string()
         };
 
-        let hint = if self.attached {
-            " q:quit "
-        } else {
-            " q:quit  d:detach "
-        };
+        let mut actions = vec![(Action::Quit, "quit")];
+        if self.commands.is_some() {
+            actions.extend([
+                (Action::Routing, "routing"),
+                (Action::ForceProvider, "force provider"),
+                (Action::Reload, "reload"),
+                (Action::Drain, "drain"),
+            ]);
+        } else if !self.attached {
+            actions.push((Action::Detach, "detach"));
+        }
+        let hint: String = actions
+            .into_iter()
+            .map(|(action, name)| format!(" {}:{name} ", self.keys.label(action)))
+            .collect();
 
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
                 Constraint::Length(3),
+                Constraint::Length(1),
                 Constraint::Min(0),
                 Constraint::Length(1),
             ])
             .split(frame.area());
 
         let tabs = Tabs::new(
-            Tab::titles()
+            Tab::titles(&self.keys)
                 .into_iter()
                 .map(Line::from)
                 .collect::<Vec<_>>(),
@@ -149,11 +408,24 @@ impl App {
         );
         frame.render_widget(tabs, chunks[0]);
 
-        let content_area = chunks[1];
-        match self.active_tab {
-            Tab::Overview => {
-                views::overview::draw(frame, content_area, &self.metrics, self.scroll_offset)
-            }
+        let alerts_key = self.keys.label(Action::Alerts);
+        views::alerts::draw_banner(
+            frame,
+            chunks[1],
+            &self.alerts,
+            &self.acknowledged,
+            &alerts_key,
+        );
+
+        let content_area = chunks[2];
+        let max_scroll = match self.active_tab {
+            Tab::Overview => views::overview::draw(
+                frame,
+                content_area,
+                &self.metrics,
+                self.scroll_offset,
+                &self.live_log,
+            ),
             Tab::Models => {
                 views::models::draw(frame, content_area, &self.metrics, self.scroll_offset)
             }
@@ -163,17 +435,48 @@ impl App {
             Tab::Errors => {
                 views::errors::draw(frame, content_area, &self.metrics, self.scroll_offset)
             }
+            Tab::Compare => {
+                views::compare::draw(frame, content_area, &self.metrics, self.scroll_offset)
+            }
+            Tab::Clients => {
+                views::clients::draw(frame, content_area, &self.metrics, self.scroll_offset)
+            }
+        };
+        self.scroll_offset = self.scroll_offset.min(max_scroll);
+
+        if self.routing_panel {
+            views::routing::draw(
+                frame,
+                content_area,
+                self.routing.as_ref(),
+                self.route_cursor,
+            );
+        }
+        if self.alert_panel {
+            views::alerts::draw_panel(frame, content_area, &self.alerts, &alerts_key);
         }
 
-        let footer = Paragraph::new(Line::from(vec![Span::styled(
-            hint,
-            Style::default().fg(Color::DarkGray),
-        )]));
-        frame.render_widget(footer, chunks[2]);
+        let mut spans = Vec::new();
+        if let Some(ref command) = self.confirm {
+            spans.push(Span::styled(
+                format!(" {command}? [y/N] "),
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+            ));
+        } else if let Some((ok, ref message)) = self.notice {
+            let color = if ok { Color::Green } else { Color::Red };
+            spans.push(Span::styled(
+                format!(" {message} "),
+                Style::default().fg(color),
+            ));
+        }
+        spans.push(Span::styled(hint, Style::default().fg(Color::DarkGray)));
+        frame.render_widget(Paragraph::new(Line::from(spans)), chunks[3]);
     }
 }
 
-pub fn run(metrics: Arc<MetricsStore>, attached: bool) -> io::Result<ExitMode> {
+pub fn run(mut app: App) -> io::Result<ExitMode> {
     let mut terminal = ratatui::init();
 
     let default_hook = std::panic::take_hook();
@@ -182,13 +485,16 @@ pub fn run(metrics: Arc<MetricsStore>, attached: bool) -> io::Result<ExitMode> {
         default_hook(info);
     }));
 
-    let mut app = App::new(metrics, attached);
+    let mut changes = app.metrics.changes();
+    app.send(Command::Status);
 
     let result = (|| -> io::Result<ExitMode> {
         loop {
+            app.poll_replies();
+            app.refresh_alerts();
             terminal.draw(|frame| app.draw(frame))?;
 
-            if event::poll(Duration::from_millis(250))? {
+            if wait_for_input(&mut changes)? {
                 match event::read()? {
                     Event::Key(key) if key.kind == KeyEventKind::Press => {
                         app.handle_key(key);
@@ -210,6 +516,24 @@ pub fn run(metrics: Arc<MetricsStore>, attached: bool) -> io::Result<ExitMode> {
     result
 }
 
+/// Waits up to the refresh interval for terminal input, returning early
+/// without input when metrics change so new requests show immediately.
+fn wait_for_input(changes: &mut watch::Receiver<u64>) -> io::Result<bool> {
+    const REFRESH: Duration = Duration::from_millis(250);
+    const SLICE: Duration = Duration::from_millis(25);
+    let deadline = Instant::now() + REFRESH;
+    while Instant::now() < deadline {
+        if event::poll(SLICE)? {
+            return Ok(true);
+        }
+        if changes.has_changed().unwrap_or(false) {
+            changes.mark_unchanged();
+            return Ok(false);
+        }
+    }
+    Ok(false)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -275,6 +599,8 @@ mod tests {
             ('2', Tab::Models),
             ('3', Tab::Providers),
             ('4', Tab::Errors),
+            ('5', Tab::Compare),
+            ('6', Tab::Clients),
             ('1', Tab::Overview),
         ] {
             app.handle_key(key(KeyCode::Char(ch)));
@@ -299,7 +625,14 @@ mod tests {
     fn tab_cycles_through_tabs() {
         assert_tab_cycle(
             KeyCode::Tab,
-            &[Tab::Models, Tab::Providers, Tab::Errors, Tab::Overview],
+            &[
+                Tab::Models,
+                Tab::Providers,
+                Tab::Errors,
+                Tab::Compare,
+                Tab::Clients,
+                Tab::Overview,
+            ],
         );
     }
 
@@ -329,7 +662,14 @@ mod tests {
     fn right_arrow_cycles_forward() {
         assert_tab_cycle(
             KeyCode::Right,
-            &[Tab::Models, Tab::Providers, Tab::Errors, Tab::Overview],
+            &[
+                Tab::Models,
+                Tab::Providers,
+                Tab::Errors,
+                Tab::Compare,
+                Tab::Clients,
+                Tab::Overview,
+            ],
         );
     }
 
@@ -337,7 +677,14 @@ mod tests {
     fn left_arrow_cycles_backward() {
         assert_tab_cycle(
             KeyCode::Left,
-            &[Tab::Errors, Tab::Providers, Tab::Models, Tab::Overview],
+            &[
+                Tab::Clients,
+                Tab::Compare,
+                Tab::Errors,
+                Tab::Providers,
+                Tab::Models,
+                Tab::Overview,
+            ],
         );
     }
 
@@ -370,6 +717,32 @@ mod tests {
         assert!(app.exit_mode.is_none());
     }
 
+    #[test]
+    fn configured_keys_replace_the_defaults() {
+        let config: crate::config::Config = figment::Figment::new()
+            .merge(
+                <figment::providers::Toml as figment::providers::Format>::string(
+                    "[tui.keys]\ndetach = \"ctrl-d\"\nscroll_down = \"n\"\n",
+                ),
+            )
+            .extract()
+            .unwrap();
+        let mut app = make_app();
+        app.configure(&config.tui).unwrap();
+
+        app.handle_key(key(KeyCode::Char('n')));
+        assert_eq!(app.scroll_offset, 1);
+        app.handle_key(key(KeyCode::Char('j')));
+        assert_eq!(app.scroll_offset, 1);
+        app.handle_key(key(KeyCode::Char('d')));
+        assert!(app.exit_mode.is_none());
+        app.handle_key(event::KeyEvent::new(
+            KeyCode::Char('d'),
+            KeyModifiers::CONTROL,
+        ));
+        assert_eq!(app.exit_mode, Some(ExitMode::Detach));
+    }
+
     #[test]
     fn footer_shows_detach_in_foreground() {
         let app = make_app();
@@ -381,4 +754,204 @@ mod tests {
         let app = make_attached_app();
         assert!(app.attached);
     }
+
+    /// An attached app whose commands land in the returned receiver, with
+    /// routing state for two providers and one route already received.
+    fn make_commanding_app() -> (App, std::sync::mpsc::Receiver<Command>) {
+        let (sent_tx, sent_rx) = std::sync::mpsc::channel();
+        let (_reply_tx, reply_rx) = std::sync::mpsc::channel();
+        let mut app = make_attached_app();
+        app.commands = Some(CommandChannel::new(
+            Box::new(move |command| sent_tx.send(command).unwrap()),
+            reply_rx,
+        ));
+        app.routing = Some(RoutingState {
+            forced: None,
+            providers: vec!["anthropic".to_string(), "ollama".to_string()],
+            routes: vec![crate::router::RouteInfo {
+                index: 0,
+                pattern: "haiku".to_string(),
+                provider: "ollama".to_string(),
+                model: None,
+                enabled: true,
+            }],
+            pins: Vec::new(),
+        });
+        (app, sent_rx)
+    }
+
+    #[test]
+    fn commands_wait_for_confirmation() {
+        let (mut app, sent) = make_commanding_app();
+        app.handle_key(key(KeyCode::Char('r')));
+        assert_eq!(app.confirm, Some(Command::Reload));
+        assert!(sent.try_recv().is_err());
+        app.handle_key(key(KeyCode::Char('y')));
+        assert_eq!(sent.try_recv().unwrap(), Command::Reload);
+        assert!(app.confirm.is_none());
+    }
+
+    #[test]
+    fn other_key_cancels_command() {
+        let (mut app, sent) = make_commanding_app();
+        app.handle_key(key(KeyCode::Char('X')));
+        app.handle_key(key(KeyCode::Char('n')));
+        assert!(sent.try_recv().is_err());
+        assert!(app.confirm.is_none());
+        assert_eq!(app.notice, Some((false, "cancelled".to_string())));
+    }
+
+    #[test]
+    fn p_cycles_forced_provider() {
+        let (mut app, _sent) = make_commanding_app();
+        app.handle_key(key(KeyCode::Char('p')));
+        assert_eq!(
+            app.confirm.take(),
+            Some(Command::ForceProvider {
+                provider: Some("anthropic".to_string())
+            })
+        );
+        app.routing.as_mut().unwrap().forced = Some("ollama".to_string());
+        app.handle_key(key(KeyCode::Char('p')));
+        assert_eq!(app.confirm, Some(Command::ForceProvider { provider: None }));
+    }
+
+    #[test]
+    fn routing_panel_toggles_selected_route() {
+        let (mut app, sent) = make_commanding_app();
+        app.handle_key(key(KeyCode::Char('o')));
+        assert!(app.routing_panel);
+        assert_eq!(sent.try_recv().unwrap(), Command::Status);
+        app.handle_key(key(KeyCode::Enter));
+        app.handle_key(key(KeyCode::Char('y')));
+        assert_eq!(
+            sent.try_recv().unwrap(),
+            Command::SetRouteEnabled {
+                index: 0,
+                enabled: false
+            }
+        );
+    }
+
+    #[test]
+    fn shift_p_cycles_the_selected_routes_pin() {
+        let (mut app, sent) = make_commanding_app();
+        app.handle_key(key(KeyCode::Char('o')));
+        assert_eq!(sent.try_recv().unwrap(), Command::Status);
+        app.handle_key(key(KeyCode::Char('P')));
+        assert_eq!(
+            app.confirm.take(),
+            Some(Command::Pin {
+                pattern: "haiku".to_string(),
+                provider: "anthropic".to_string(),
+            })
+        );
+        app.routing.as_mut().unwrap().pins = vec![crate::router::PinInfo {
+            pattern: "haiku".to_string(),
+            provider: "ollama".to_string(),
+            saved: false,
+        }];
+        app.handle_key(key(KeyCode::Char('P')));
+        app.handle_key(key(KeyCode::Char('y')));
+        assert_eq!(
+            sent.try_recv().unwrap(),
+            Command::Unpin {
+                pattern: "haiku".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn command_keys_ignored_without_channel() {
+        let mut app = make_attached_app();
+        app.handle_key(key(KeyCode::Char('r')));
+        assert!(app.confirm.is_none());
+    }
+
+    #[test]
+    fn alerts_stay_acknowledged_until_they_clear() {
+        let mut app = make_app();
+        let report = |remaining| crate::ratelimits::RateLimit {
+            provider: "anthropic".to_string(),
+            updated: chrono::Utc::now(),
+            requests: Some(crate::ratelimits::Quota {
+                limit: Some(100),
+                remaining,
+                reset: None,
+            }),
+            tokens: None,
+            input_tokens: None,
+            output_tokens: None,
+        };
+        let unread = |app: &App| {
+            app.alerts
+                .iter()
+                .filter(|a| !app.acknowledged.contains(&a.id))
+                .count()
+        };
+
+        app.metrics.record_rate_limit(report(5));
+        app.refresh_alerts();
+        assert_eq!(unread(&app), 1);
+
+        app.handle_key(key(KeyCode::Char('a')));
+        assert!(app.alert_panel);
+        assert_eq!(unread(&app), 0);
+        app.handle_key(key(KeyCode::Esc));
+        assert!(!app.alert_panel);
+        app.refresh_alerts();
+        assert_eq!(unread(&app), 0);
+
+        // Once it clears, the same alert is new again
+        app.metrics.record_rate_limit(report(50));
+        app.refresh_alerts();
+        assert!(app.alerts.is_empty());
+        app.metrics.record_rate_limit(report(5));
+        app.refresh_alerts();
+        assert_eq!(unread(&app), 1);
+    }
+
+    #[test]
+    fn scrolling_stops_at_the_last_row() {
+        let mut app = make_app();
+        for i in 0..20 {
+            app.metrics.record_rate_limit(crate::ratelimits::RateLimit {
+                provider: format!("provider-{i:02}"),
+                updated: chrono::Utc::now(),
+                requests: None,
+                tokens: Some(crate::ratelimits::Quota {
+                    limit: Some(100),
+                    remaining: 100,
+                    reset: None,
+                }),
+                input_tokens: None,
+                output_tokens: None,
+            });
+        }
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(100, 20)).unwrap();
+        let mut draw = |app: &mut App| {
+            terminal.draw(|frame| app.draw(frame)).unwrap();
+        };
+        app.handle_key(key(KeyCode::Char('3')));
+
+        // 15 rows of content leave 12 for the table's rows
+        app.handle_key(key(KeyCode::Char('G')));
+        draw(&mut app);
+        assert_eq!(app.scroll_offset, 8);
+        app.handle_key(key(KeyCode::Char('j')));
+        draw(&mut app);
+        assert_eq!(app.scroll_offset, 8);
+        app.handle_key(key(KeyCode::Char('k')));
+        assert_eq!(app.scroll_offset, 7);
+
+        app.handle_key(key(KeyCode::Char('g')));
+        assert_eq!(app.scroll_offset, 7);
+        app.handle_key(key(KeyCode::Char('g')));
+        assert_eq!(app.scroll_offset, 0);
+
+        // A lone g is dropped when the next key doesn't complete gg
+        app.handle_key(key(KeyCode::Char('g')));
+        app.handle_key(key(KeyCode::Char('j')));
+        assert_eq!(app.scroll_offset, 1);
+    }
 }
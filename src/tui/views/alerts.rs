@@ -0,0 +1,105 @@
+use std::collections::HashSet;
+
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Clear, Paragraph};
+
+use crate::alerts::{Alert, Severity};
+
+fn severity_style(severity: Severity) -> Style {
+    match severity {
+        Severity::Critical => Style::default()
+            .fg(Color::White)
+            .bg(Color::Red)
+            .add_modifier(Modifier::BOLD),
+        Severity::Warning => Style::default().fg(Color::Black).bg(Color::Yellow),
+    }
+}
+
+fn severity_label(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Critical => "CRITICAL",
+        Severity::Warning => "WARNING",
+    }
+}
+
+/// Draws the alert line: the most severe alert not yet acknowledged and
+/// how many more there are, or how many are acknowledged.
+pub fn draw_banner(
+    frame: &mut Frame,
+    area: Rect,
+    alerts: &[Alert],
+    acknowledged: &HashSet<String>,
+    key: &str,
+) {
+    let dim = Style::default().fg(Color::DarkGray);
+    let unread: Vec<&Alert> = alerts
+        .iter()
+        .filter(|a| !acknowledged.contains(&a.id))
+        .collect();
+    let line = match unread.first() {
+        None if alerts.is_empty() => Line::from(Span::styled(" no alerts", dim)),
+        None => Line::from(Span::styled(
+            format!(
+                " {} acknowledged alert{}  {key}:alerts",
+                alerts.len(),
+                if alerts.len() == 1 { "" } else { "s" }
+            ),
+            dim,
+        )),
+        Some(alert) => {
+            let style = severity_style(alert.severity);
+            let mut spans = vec![Span::styled(
+                format!(" {} {} ", severity_label(alert.severity), alert.message),
+                style,
+            )];
+            if unread.len() > 1 {
+                spans.push(Span::raw(format!(" +{} more", unread.len() - 1)));
+            }
+            spans.push(Span::styled(format!("  {key}:alerts"), dim));
+            Line::from(spans)
+        }
+    };
+    frame.render_widget(Paragraph::new(line), area);
+}
+
+/// Draws the list of active alerts over the current tab.
+pub fn draw_panel(frame: &mut Frame, area: Rect, alerts: &[Alert], key: &str) {
+    let mut lines: Vec<Line> = alerts
+        .iter()
+        .map(|alert| {
+            Line::from(vec![
+                Span::styled(
+                    format!(" {:<8} ", severity_label(alert.severity)),
+                    severity_style(alert.severity),
+                ),
+                Span::raw(format!(" {}", alert.message)),
+            ])
+        })
+        .collect();
+    if lines.is_empty() {
+        lines.push(Line::from(Span::styled(
+            "no active alerts",
+            Style::default().fg(Color::DarkGray),
+        )));
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        format!("{key}:close  esc:close"),
+        Style::default().fg(Color::DarkGray),
+    )));
+
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let width = (area.width * 3 / 4).max(40).min(area.width);
+    let panel = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+
+    frame.render_widget(Clear, panel);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Alerts ")),
+        panel,
+    );
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::Utc;
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Cell, Row, Table};
+
+use super::{format_time_ago, format_tokens};
+use crate::keys::QuotaUsage;
+use crate::metrics::MetricsStore;
+
+/// Width of a usage bar, in cells.
+const BAR_WIDTH: usize = 20;
+
+pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) -> usize {
+    let quotas = metrics.quotas();
+    let area = if quotas.is_empty() {
+        area
+    } else {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(0),
+                Constraint::Length(quotas.len() as u16 + 3),
+            ])
+            .split(area);
+        frame.render_widget(quota_table(&quotas), chunks[1]);
+        chunks[0]
+    };
+
+    let snap = MetricsStore::without_synthetic(&metrics.snapshot());
+    let clients = MetricsStore::by_client(&snap, &metrics.billable());
+    let max_scroll = super::max_scroll(area, clients.len());
+    let scroll = scroll.min(max_scroll);
+
+    let header = Row::new(vec!["Client", "Reqs", "In", "Out", "Cost", "Err%", "Last"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let now = Instant::now();
+    let rows: Vec<Row> = clients
+        .iter()
+        .skip(scroll)
+        .map(|activity| {
+            let totals = &activity.totals;
+            let error_rate = totals.errors as f64 / totals.requests.max(1) as f64 * 100.0;
+            let error_style = if totals.errors > 0 {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            Row::new(vec![
+                Cell::from(activity.client.clone()).style(Style::default().fg(Color::White)),
+                Cell::from(format_tokens(totals.requests)),
+                Cell::from(format_tokens(totals.input_tokens))
+                    .style(Style::default().fg(Color::Cyan)),
+                Cell::from(format_tokens(totals.output_tokens))
+                    .style(Style::default().fg(Color::Green)),
+                Cell::from(format!("${:.2}", totals.cost_usd)),
+                Cell::from(format!("{error_rate:.1}%")).style(error_style),
+                Cell::from(format_time_ago(now.duration_since(activity.last_seen))),
+            ])
+        })
+        .collect();
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Min(20),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Length(7),
+            Constraint::Length(9),
+        ],
+    )
+    .header(header)
+    .block(Block::default().borders(Borders::ALL).title(" Clients "));
+
+    frame.render_widget(table, area);
+    super::render_scrollbar(frame, area, clients.len(), scroll);
+    max_scroll
+}
+
+/// Each client's token use against its quotas.
+fn quota_table(quotas: &[QuotaUsage]) -> Table<'static> {
+    let header = Row::new(vec!["Client", "Today", "This month"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let today = Utc::now().date_naive();
+    let rows: Vec<Row> = quotas
+        .iter()
+        .map(|usage| {
+            let (daily, monthly) = usage.current(today);
+            Row::new(vec![
+                Cell::from(usage.client.clone()).style(Style::default().fg(Color::White)),
+                usage_cell(daily, usage.daily_limit),
+                usage_cell(monthly, usage.monthly_limit),
+            ])
+        })
+        .collect();
+
+    Table::new(
+        rows,
+        [
+            Constraint::Min(20),
+            Constraint::Length(BAR_WIDTH as u16 + 20),
+            Constraint::Length(BAR_WIDTH as u16 + 20),
+        ],
+    )
+    .header(header)
+    .block(Block::default().borders(Borders::ALL).title(" Quotas "))
+}
+
+/// Tokens `used` of `limit` as a bar, colored by how close to the limit
+/// they are.
+fn usage_cell(used: u64, limit: Option<u64>) -> Cell<'static> {
+    let Some(limit) = limit else {
+        return Cell::from(format!("{} (no quota)", format_tokens(used)))
+            .style(Style::default().fg(Color::DarkGray));
+    };
+    let fraction = used as f64 / limit as f64;
+    let color = if fraction >= 1.0 {
+        Color::Red
+    } else if fraction >= 0.8 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    Cell::from(format!(
+        "{} {}/{}",
+        bar(fraction),
+        format_tokens(used),
+        format_tokens(limit)
+    ))
+    .style(Style::default().fg(color))
+}
+
+fn bar(fraction: f64) -> String {
+    let filled = ((fraction.clamp(0.0, 1.0) * BAR_WIDTH as f64).round() as usize).min(BAR_WIDTH);
+    format!("{}{}", "█".repeat(filled), "░".repeat(BAR_WIDTH - filled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_fill_to_the_fraction_used() {
+        assert_eq!(bar(0.0), "░".repeat(BAR_WIDTH));
+        assert_eq!(bar(0.5), format!("{}{}", "█".repeat(10), "░".repeat(10)));
+        assert_eq!(bar(3.0), "█".repeat(BAR_WIDTH));
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Cell, Row, Table};
+
+use super::{format_duration, format_tokens};
+use crate::compare;
+use crate::metrics::MetricsStore;
+
+pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) -> usize {
+    let pairs = compare::summarize(&metrics.comparisons());
+    let max_scroll = super::max_scroll(area, pairs.len());
+    let scroll = scroll.min(max_scroll);
+
+    let header = Row::new(vec![
+        "Primary",
+        "Candidate",
+        "Reqs",
+        "Errs",
+        "P50",
+        "Cand P50",
+        "Out",
+        "Cand Out",
+        "Similarity",
+    ])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let rows: Vec<Row> = pairs
+        .iter()
+        .skip(scroll)
+        .map(|pair| {
+            let error_style = if pair.candidate_errors > 0 {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            let similarity = pair
+                .similarity
+                .map_or("-".to_string(), |s| format!("{:.0}%", s * 100.0));
+            Row::new(vec![
+                Cell::from(pair.primary.as_str()).style(Style::default().fg(Color::White)),
+                Cell::from(pair.candidate.as_str()).style(Style::default().fg(Color::White)),
+                Cell::from(format_tokens(pair.count as u64)),
+                Cell::from(format_tokens(pair.candidate_errors as u64)).style(error_style),
+                Cell::from(format_duration(pair.primary_p50)),
+                Cell::from(format_duration(pair.candidate_p50)),
+                Cell::from(format_tokens(pair.primary_output_tokens))
+                    .style(Style::default().fg(Color::Green)),
+                Cell::from(format_tokens(pair.candidate_output_tokens))
+                    .style(Style::default().fg(Color::Green)),
+                Cell::from(similarity).style(Style::default().fg(Color::Cyan)),
+            ])
+        })
+        .collect();
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Min(12),
+            Constraint::Min(12),
+            Constraint::Length(8),
+            Constraint::Length(6),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Length(11),
+        ],
+    )
+    .header(header)
+    .block(Block::default().borders(Borders::ALL).title(" Compare "));
+
+    frame.render_widget(table, area);
+    super::render_scrollbar(frame, area, pairs.len(), scroll);
+    max_scroll
+}
@@ -4,14 +4,49 @@ use ratatui::prelude::*;
 use ratatui::widgets::{Block, Borders, Cell, Row, Table};
 
 use super::format_time_ago;
-use crate::metrics::MetricsStore;
+use super::overview::{line_dataset, status_color, time_chart, to_points};
+use crate::metrics::{MetricsStore, RequestRecord};
 
-pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) {
+/// 2xx, 4xx, and 5xx responses per minute, so bursts of errors show when
+/// they happened rather than only as totals.
+fn draw_status_timeline(frame: &mut Frame, area: Rect, snap: &[RequestRecord], num_buckets: usize) {
+    let classes = MetricsStore::status_classes_per_minute(snap, num_buckets);
+    let ceil = classes
+        .iter()
+        .flatten()
+        .max()
+        .unwrap_or(&1)
+        .max(&10)
+        .div_ceil(5)
+        * 5;
+    let points: Vec<_> = classes.iter().map(|class| to_points(class)).collect();
+    let datasets = points
+        .iter()
+        .zip([200, 400, 500])
+        .map(|(points, status)| line_dataset(points, status_color(status)))
+        .collect();
+    let [ok, client, server] = classes.map(|class| class.iter().sum::<u64>());
+    let title = format!(" Status/min (2xx: {ok}, 4xx: {client}, 5xx: {server}) ");
+    frame.render_widget(time_chart(datasets, num_buckets, title, ceil), area);
+}
+
+pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) -> usize {
     let snap = metrics.snapshot();
+    let num_buckets = metrics.window_minutes().max(1) as usize;
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(10), Constraint::Min(0)])
+        .split(area);
+    draw_status_timeline(frame, chunks[0], &snap, num_buckets);
+    let area = chunks[1];
 
     let now = std::time::Instant::now();
     let mut errors: Vec<_> = snap.iter().filter(|r| r.status >= 400).collect();
-    errors.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    errors.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
+    let count = errors.len();
+    let max_scroll = super::max_scroll(area, count);
+    let scroll = scroll.min(max_scroll);
 
     let header = Row::new(vec!["Age", "Model", "Provider", "Status", "Error"])
         .style(Style::default().add_modifier(Modifier::BOLD));
@@ -39,7 +74,6 @@ pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll:
         })
         .collect();
 
-    let count = errors.len();
     let table = Table::new(
         rows,
         [
@@ -59,4 +93,5 @@ pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll:
 
     frame.render_widget(table, area);
     super::render_scrollbar(frame, area, count, scroll);
+    max_scroll
 }
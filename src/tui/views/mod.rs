@@ -1,10 +1,14 @@
 use ratatui::prelude::*;
 use ratatui::widgets::{Scrollbar, ScrollbarOrientation, ScrollbarState};
 
+pub mod alerts;
+pub mod clients;
+pub mod compare;
 pub mod errors;
 pub mod models;
 pub mod overview;
 pub mod providers;
+pub mod routing;
 
 /// Formats a token count for display: raw below 1K, "1.0K" style up to ~1M,
 /// "1.5M" style above.
@@ -55,11 +59,23 @@ pub fn format_duration(dur: std::time::Duration) -> String {
     }
 }
 
+/// Table rows that fit in `area`, after the border (top + bottom) and
+/// header row.
+fn visible_rows(area: Rect) -> usize {
+    area.height.saturating_sub(3) as usize
+}
+
+/// The furthest a table of `total_rows` in `area` scrolls: to where its
+/// last row is at the bottom. Views clamp to it and return it, so the
+/// scroll keys stop there.
+pub fn max_scroll(area: Rect, total_rows: usize) -> usize {
+    total_rows.saturating_sub(visible_rows(area))
+}
+
 /// Renders a subtle vertical scrollbar when `total_rows` exceeds the visible
-/// area. Accounts for border (top + bottom) and header row = 3 lines of
-/// overhead.
+/// area.
 pub fn render_scrollbar(frame: &mut Frame, area: Rect, total_rows: usize, scroll: usize) {
-    let visible_rows = area.height.saturating_sub(3) as usize;
+    let visible_rows = visible_rows(area);
     if total_rows > visible_rows {
         let mut state =
             ScrollbarState::new(total_rows.saturating_sub(visible_rows)).position(scroll);
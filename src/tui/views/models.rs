@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use ratatui::prelude::*;
@@ -12,7 +13,7 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
     let groups = MetricsStore::group_by(snap, |r| r.model.clone());
 
     let header = Row::new(vec![
-        "", "Model", "Reqs", "In", "Out", "Avg/Req", "P50", "P95", "Errs",
+        "", "Model", "Reqs", "In", "Out", "Avg/Req", "P50", "P95", "Errs", "Near",
     ])
     .style(Style::default().add_modifier(Modifier::BOLD));
 
@@ -32,7 +33,14 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
             let p50 = MetricsStore::duration_percentile(&durations, 50);
             let p95 = MetricsStore::duration_percentile(&durations, 95);
             let errors: u64 = records.iter().filter(|r| r.status >= 400).count() as u64;
+            // Requests that came near the model's context window
+            let near: u64 = records.iter().filter(|r| r.near_limit).count() as u64;
             let routing_method = if records
+                .iter()
+                .any(|r| r.routing_method == RoutingMethod::Script)
+            {
+                RoutingMethod::Script
+            } else if records
                 .iter()
                 .any(|r| r.routing_method == RoutingMethod::Auto)
             {
@@ -50,6 +58,7 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
                 RoutingMethod::Pattern => ("PTN", Style::default().fg(Color::Cyan)),
                 RoutingMethod::Auto => ("AUT", Style::default().fg(Color::Yellow)),
                 RoutingMethod::Default => ("DEF", Style::default().fg(Color::DarkGray)),
+                RoutingMethod::Script => ("SCR", Style::default().fg(Color::Magenta)),
             };
 
             let error_style = if errors > 0 {
@@ -58,6 +67,12 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
                 Style::default().fg(Color::DarkGray)
             };
 
+            let near_style = if near > 0 {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+
             Row::new(vec![
                 Cell::from(indicator).style(indicator_style),
                 Cell::from(model.clone()).style(Style::default().fg(Color::White)),
@@ -69,6 +84,7 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
                 Cell::from(format_duration(p50)),
                 Cell::from(format_duration(p95)),
                 Cell::from(format_tokens(errors)).style(error_style),
+                Cell::from(format_tokens(near)).style(near_style),
             ])
         })
         .collect();
@@ -85,6 +101,7 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
             Constraint::Length(8),
             Constraint::Length(8),
             Constraint::Length(8),
+            Constraint::Length(8),
         ],
     )
     .header(header)
@@ -93,9 +110,13 @@ pub fn model_table(snap: &[RequestRecord], title: String, skip: usize) -> (Table
     (table, total)
 }
 
-pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) {
-    let snap = metrics.snapshot();
+pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) -> usize {
+    let snap = MetricsStore::without_synthetic(&metrics.snapshot());
+    let models: HashSet<&str> = snap.iter().map(|r| r.model.as_str()).collect();
+    let max_scroll = super::max_scroll(area, models.len());
+    let scroll = scroll.min(max_scroll);
     let (table, total) = model_table(&snap, " Models ".to_string(), scroll);
     frame.render_widget(table, area);
     super::render_scrollbar(frame, area, total, scroll);
+    max_scroll
 }
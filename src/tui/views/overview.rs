@@ -7,6 +7,7 @@ use ratatui::widgets::{
 };
 
 use super::{format_duration, format_time_ago, format_tokens};
+use crate::config::{LiveLogColumn, LiveLogConfig};
 use crate::metrics::{MetricsStore, RoutingMethod};
 
 fn time_axis_labels(num_buckets: usize) -> Vec<String> {
@@ -35,12 +36,25 @@ fn build_time_chart<'a>(
     color: Color,
     ceil: u64,
 ) -> Chart<'a> {
-    let dataset = Dataset::default()
+    time_chart(vec![line_dataset(points, color)], num_buckets, title, ceil)
+}
+
+pub(super) fn line_dataset(points: &[(f64, f64)], color: Color) -> Dataset<'_> {
+    Dataset::default()
         .marker(Marker::Braille)
         .graph_type(GraphType::Line)
         .style(Style::default().fg(color))
-        .data(points);
-    Chart::new(vec![dataset])
+        .data(points)
+}
+
+/// A chart of per-minute values over the last `num_buckets` minutes.
+pub(super) fn time_chart(
+    datasets: Vec<Dataset<'_>>,
+    num_buckets: usize,
+    title: String,
+    ceil: u64,
+) -> Chart<'_> {
+    Chart::new(datasets)
         .block(Block::default().borders(Borders::ALL).title(title))
         .x_axis(
             Axis::default()
@@ -54,7 +68,7 @@ fn build_time_chart<'a>(
         )
 }
 
-fn to_points(data: &[u64]) -> Vec<(f64, f64)> {
+pub(super) fn to_points(data: &[u64]) -> Vec<(f64, f64)> {
     data.iter()
         .enumerate()
         .map(|(i, &v)| (i as f64, v as f64))
@@ -149,7 +163,7 @@ fn status_label(code: u16) -> Option<&'static str> {
     }
 }
 
-fn status_color(code: u16) -> Color {
+pub(super) fn status_color(code: u16) -> Color {
     if code < 300 {
         Color::Green
     } else if code < 500 {
@@ -220,17 +234,83 @@ fn duration_style(
     }
 }
 
+/// `model` as the Live Log shows it, shortened as `[tui.live_log]` asks.
+pub fn short_model<'a>(model: &'a str, config: &LiveLogConfig) -> &'a str {
+    let mut name = config
+        .strip_prefixes
+        .iter()
+        .find_map(|prefix| model.strip_prefix(prefix.as_str()))
+        .filter(|rest| !rest.is_empty())
+        .unwrap_or(model);
+    if config.strip_dates {
+        name = strip_date(name);
+    }
+    name
+}
+
+/// `name` without a trailing release date: `-20250514`, `@20250514`, or
+/// `-2024-08-06`.
+fn strip_date(name: &str) -> &str {
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    let bytes = name.as_bytes();
+    if let Some(split) = name.len().checked_sub(9)
+        && matches!(bytes[split], b'-' | b'@')
+        && digits(&name[split + 1..])
+        && split > 0
+    {
+        return &name[..split];
+    }
+    if let Some(split) = name.len().checked_sub(11)
+        && bytes[split] == b'-'
+        && split > 0
+    {
+        let date = &name[split + 1..];
+        let parts: Vec<&str> = date.split('-').collect();
+        if parts.iter().map(|p| p.len()).eq([4, 2, 2]) && parts.iter().all(|p| digits(p)) {
+            return &name[..split];
+        }
+    }
+    name
+}
+
+fn column_header(column: LiveLogColumn) -> &'static str {
+    match column {
+        LiveLogColumn::Age => "Age",
+        LiveLogColumn::Model => "Model",
+        LiveLogColumn::Provider => "Provider",
+        LiveLogColumn::Route => "Route",
+        LiveLogColumn::Status => "Status",
+        LiveLogColumn::Duration => "Duration",
+        LiveLogColumn::Tokens => "In/Out",
+        LiveLogColumn::Client => "Client",
+        LiveLogColumn::Id => "ID",
+    }
+}
+
+fn column_width(column: LiveLogColumn) -> Constraint {
+    match column {
+        LiveLogColumn::Age => Constraint::Length(8),
+        LiveLogColumn::Model => Constraint::Min(20),
+        LiveLogColumn::Provider => Constraint::Length(12),
+        LiveLogColumn::Route => Constraint::Length(5),
+        LiveLogColumn::Status => Constraint::Length(6),
+        LiveLogColumn::Duration => Constraint::Length(10),
+        LiveLogColumn::Tokens => Constraint::Length(12),
+        LiveLogColumn::Client => Constraint::Length(14),
+        LiveLogColumn::Id => Constraint::Length(12),
+    }
+}
+
 fn draw_live_log(
     frame: &mut Frame,
     area: Rect,
     snap: &[crate::metrics::RequestRecord],
     scroll: usize,
-) {
-    let header = Row::new(vec![
-        "Age", "Model", "Provider", "Route", "Status", "Duration", "In/Out",
-    ])
-    .style(Style::default().add_modifier(Modifier::BOLD))
-    .bottom_margin(0);
+    config: &LiveLogConfig,
+) -> usize {
+    let header = Row::new(config.columns.iter().map(|&c| column_header(c)))
+        .style(Style::default().add_modifier(Modifier::BOLD))
+        .bottom_margin(0);
 
     let now = std::time::Instant::now();
     let durations: Vec<std::time::Duration> = snap.iter().map(|r| r.duration).collect();
@@ -239,35 +319,51 @@ fn draw_live_log(
     let p99 = MetricsStore::duration_percentile(&durations, 99);
 
     let mut sorted: Vec<_> = snap.iter().collect();
-    sorted.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    sorted.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
 
     let total_rows = sorted.len();
+    let max_scroll = super::max_scroll(area, total_rows);
+    let scroll = scroll.min(max_scroll);
 
     let rows: Vec<Row> = sorted
         .iter()
         .skip(scroll)
         .take(50)
         .map(|r| {
-            let status_style = if r.status >= 400 {
-                Style::default().fg(Color::Red)
-            } else {
-                Style::default().fg(Color::Green)
-            };
-            let age = now.duration_since(r.timestamp);
-            let (route_label, route_style) = match r.routing_method {
-                RoutingMethod::Pattern => ("PTN", Style::default().fg(Color::Cyan)),
-                RoutingMethod::Auto => ("AUT", Style::default().fg(Color::Yellow)),
-                RoutingMethod::Default => ("DEF", Style::default().fg(Color::DarkGray)),
-            };
-            Row::new(vec![
-                Cell::from(format_time_ago(age)).style(Style::default().fg(Color::DarkGray)),
-                Cell::from(r.model.as_str()),
-                Cell::from(r.provider.as_str()).style(Style::default().fg(Color::DarkGray)),
-                Cell::from(route_label).style(route_style),
-                Cell::from(r.status.to_string()).style(status_style),
-                Cell::from(format_duration(r.duration))
+            let cell = |column| match column {
+                LiveLogColumn::Age => Cell::from(format_time_ago(now.duration_since(r.timestamp)))
+                    .style(Style::default().fg(Color::DarkGray)),
+                LiveLogColumn::Model => Cell::from(short_model(&r.model, config)),
+                LiveLogColumn::Provider => {
+                    Cell::from(r.provider.as_str()).style(Style::default().fg(Color::DarkGray))
+                }
+                LiveLogColumn::Route => {
+                    let (label, style) = match r.routing_method {
+                        RoutingMethod::Pattern => ("PTN", Style::default().fg(Color::Cyan)),
+                        RoutingMethod::Auto => ("AUT", Style::default().fg(Color::Yellow)),
+                        RoutingMethod::Default => ("DEF", Style::default().fg(Color::DarkGray)),
+                        RoutingMethod::Script => ("SCR", Style::default().fg(Color::Magenta)),
+                    };
+                    Cell::from(label).style(style)
+                }
+                LiveLogColumn::Status => {
+                    // Faults injected by [chaos] are marked, so they aren't
+                    // mistaken for the provider's
+                    let (status, style) = match r.chaos {
+                        Some(_) => (
+                            format!("{}*", r.status),
+                            Style::default().fg(Color::Magenta),
+                        ),
+                        None if r.status >= 400 => {
+                            (r.status.to_string(), Style::default().fg(Color::Red))
+                        }
+                        None => (r.status.to_string(), Style::default().fg(Color::Green)),
+                    };
+                    Cell::from(status).style(style)
+                }
+                LiveLogColumn::Duration => Cell::from(format_duration(r.duration))
                     .style(duration_style(r.duration, p50, p95, p99)),
-                Cell::from(Line::from(vec![
+                LiveLogColumn::Tokens => Cell::from(Line::from(vec![
                     Span::styled(
                         format_tokens(r.input_tokens),
                         Style::default().fg(Color::Cyan),
@@ -278,30 +374,31 @@ fn draw_live_log(
                         Style::default().fg(Color::Green),
                     ),
                 ])),
-            ])
+                LiveLogColumn::Client => Cell::from(r.client.as_deref().unwrap_or("-"))
+                    .style(Style::default().fg(Color::DarkGray)),
+                LiveLogColumn::Id => Cell::from(r.request_id.as_deref().unwrap_or("-"))
+                    .style(Style::default().fg(Color::DarkGray)),
+            };
+            Row::new(config.columns.iter().map(|&c| cell(c)))
         })
         .collect();
 
-    let table = Table::new(
-        rows,
-        [
-            Constraint::Length(8),
-            Constraint::Min(20),
-            Constraint::Length(12),
-            Constraint::Length(5),
-            Constraint::Length(6),
-            Constraint::Length(10),
-            Constraint::Length(12),
-        ],
-    )
-    .header(header)
-    .block(Block::default().borders(Borders::ALL).title(" Live Log "));
+    let table = Table::new(rows, config.columns.iter().map(|&c| column_width(c)))
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(" Live Log "));
 
     frame.render_widget(table, area);
     super::render_scrollbar(frame, area, total_rows, scroll);
+    max_scroll
 }
 
-pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) {
+pub fn draw(
+    frame: &mut Frame,
+    area: Rect,
+    metrics: &Arc<MetricsStore>,
+    scroll: usize,
+    live_log: &LiveLogConfig,
+) -> usize {
     let snap = metrics.snapshot();
     let num_buckets = metrics.window_minutes().max(1) as usize;
 
@@ -315,8 +412,47 @@ pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll:
         ])
         .split(area);
 
-    draw_charts_row(frame, chunks[0], &snap, num_buckets);
-    draw_stats_row(frame, chunks[1], &snap);
-    draw_token_usage(frame, chunks[2], &snap);
-    draw_live_log(frame, chunks[3], &snap, scroll);
+    // The live log shows everything; the statistics leave out [chaos]
+    let real = MetricsStore::without_synthetic(&snap);
+    draw_charts_row(frame, chunks[0], &real, num_buckets);
+    draw_stats_row(frame, chunks[1], &real);
+    draw_token_usage(frame, chunks[2], &real);
+    draw_live_log(frame, chunks[3], &snap, scroll, live_log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_names_are_shortened_as_configured() {
+        let config = LiveLogConfig {
+            strip_prefixes: vec!["claude-".to_string(), "anthropic/".to_string()],
+            strip_dates: true,
+            ..LiveLogConfig::default()
+        };
+        assert_eq!(
+            short_model("claude-sonnet-4-5-20250929", &config),
+            "sonnet-4-5"
+        );
+        assert_eq!(
+            short_model("anthropic/claude-opus-4-6", &config),
+            "claude-opus-4-6"
+        );
+        assert_eq!(
+            short_model("claude-3-5-haiku@20241022", &config),
+            "3-5-haiku"
+        );
+        assert_eq!(short_model("gpt-4o-2024-08-06", &config), "gpt-4o");
+        assert_eq!(short_model("qwen3-coder:30b", &config), "qwen3-coder:30b");
+        // A name that is nothing but a prefix or a date is left whole
+        assert_eq!(short_model("claude-", &config), "claude-");
+        assert_eq!(strip_date("20250514"), "20250514");
+
+        let unchanged = LiveLogConfig::default();
+        assert_eq!(
+            short_model("claude-sonnet-4-5-20250929", &unchanged),
+            "claude-sonnet-4-5-20250929"
+        );
+    }
 }
@@ -1,28 +1,84 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use ratatui::prelude::*;
 use ratatui::widgets::{Block, Borders, Cell, Row, Table};
 
 use super::{format_duration, format_tokens};
+use crate::balance::Health;
 use crate::metrics::MetricsStore;
+use crate::ratelimits::{Quota, RateLimit};
+use crate::tokens::AuthStatus;
+use crate::warm::Warmth;
 
-pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) {
-    let snap = metrics.snapshot();
+pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll: usize) -> usize {
+    let snap = MetricsStore::without_synthetic(&metrics.snapshot());
     let groups = MetricsStore::group_by(&snap, |r| r.provider.clone());
+    let rate_limits: HashMap<String, RateLimit> = metrics
+        .rate_limits()
+        .into_iter()
+        .map(|limit| (limit.provider.clone(), limit))
+        .collect();
+    let warmth: HashMap<String, Warmth> = metrics
+        .warmth()
+        .into_iter()
+        .map(|warmth| (warmth.provider.clone(), warmth))
+        .collect();
+    let health: HashMap<String, Health> = metrics
+        .health()
+        .into_iter()
+        .map(|health| (health.provider.clone(), health))
+        .collect();
+    let auth: HashMap<String, AuthStatus> = metrics
+        .auth()
+        .into_iter()
+        .map(|status| (status.provider.clone(), status))
+        .collect();
 
     let header = Row::new(vec![
-        "Provider", "Reqs", "In", "Out", "Avg/Req", "P50", "P95", "Errs",
+        "Provider",
+        "Reqs",
+        "In",
+        "Out",
+        "Avg/Req",
+        "P50",
+        "P95",
+        "Errs",
+        "EWMA",
+        "Err%",
+        "Reqs Left",
+        "Toks Left",
+        "Model",
+        "Auth",
     ])
     .style(Style::default().add_modifier(Modifier::BOLD));
 
-    let mut names: Vec<&String> = groups.keys().collect();
+    // Providers that reported rate limits, are kept warm, have a health
+    // history, or fetch tokens are listed even when idle
+    let mut names: Vec<&String> = groups
+        .keys()
+        .chain(rate_limits.keys())
+        .chain(warmth.keys())
+        .chain(health.keys())
+        .chain(auth.keys())
+        .collect();
     names.sort();
+    names.dedup();
+    let max_scroll = super::max_scroll(area, names.len());
+    let scroll = scroll.min(max_scroll);
 
     let rows: Vec<Row> = names
         .iter()
         .skip(scroll)
         .map(|name| {
-            let records = &groups[*name];
+            let records = groups.get(*name).map_or(&[][..], Vec::as_slice);
+            let limit = rate_limits.get(*name);
+            let tokens = limit.and_then(|l| {
+                l.tokens
+                    .as_ref()
+                    .or(l.input_tokens.as_ref())
+                    .or(l.output_tokens.as_ref())
+            });
             let count = records.len() as u64;
             let input: u64 = records.iter().map(|r| r.input_tokens).sum();
             let output: u64 = records.iter().map(|r| r.output_tokens).sum();
@@ -45,6 +101,12 @@ pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll:
                 Cell::from(format_duration(p50)),
                 Cell::from(format_duration(p95)),
                 Cell::from(format_tokens(errors)).style(error_style),
+                latency_cell(health.get(*name)),
+                error_rate_cell(health.get(*name)),
+                quota_cell(limit.and_then(|l| l.requests.as_ref())),
+                quota_cell(tokens),
+                warmth_cell(warmth.get(*name)),
+                auth_cell(auth.get(*name)),
             ])
         })
         .collect();
@@ -60,6 +122,12 @@ pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll:
             Constraint::Length(8),
             Constraint::Length(8),
             Constraint::Length(8),
+            Constraint::Length(8),
+            Constraint::Length(6),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(6),
+            Constraint::Length(6),
         ],
     )
     .header(header)
@@ -67,4 +135,66 @@ pub fn draw(frame: &mut Frame, area: Rect, metrics: &Arc<MetricsStore>, scroll:
 
     frame.render_widget(table, area);
     super::render_scrollbar(frame, area, names.len(), scroll);
+    max_scroll
+}
+
+/// What's left of a rate limit, colored by how close it is to running out.
+fn quota_cell(quota: Option<&Quota>) -> Cell<'static> {
+    let Some(quota) = quota else {
+        return Cell::from("-").style(Style::default().fg(Color::DarkGray));
+    };
+    let color = match quota.fraction_remaining() {
+        Some(f) if f < 0.1 => Color::Red,
+        Some(f) if f < 0.25 => Color::Yellow,
+        _ => Color::White,
+    };
+    Cell::from(format_tokens(quota.remaining)).style(Style::default().fg(color))
+}
+
+/// Moving average latency, which group members are picked by.
+fn latency_cell(health: Option<&Health>) -> Cell<'static> {
+    match health {
+        Some(health) if health.latency_ms > 0.0 => Cell::from(format_duration(
+            std::time::Duration::from_secs_f64(health.latency_ms / 1000.0),
+        )),
+        _ => Cell::from("-").style(Style::default().fg(Color::DarkGray)),
+    }
+}
+
+/// Moving average error rate, which group members are picked by.
+fn error_rate_cell(health: Option<&Health>) -> Cell<'static> {
+    let Some(health) = health else {
+        return Cell::from("-").style(Style::default().fg(Color::DarkGray));
+    };
+    let color = match health.error_rate {
+        r if r >= 0.25 => Color::Red,
+        r if r >= 0.05 => Color::Yellow,
+        _ => Color::White,
+    };
+    Cell::from(format!("{:.0}%", health.error_rate * 100.0)).style(Style::default().fg(color))
+}
+
+/// Whether the provider's model is loaded, going by its last warm-up ping.
+fn warmth_cell(warmth: Option<&Warmth>) -> Cell<'static> {
+    let (text, color) = match warmth {
+        None => ("-", Color::DarkGray),
+        Some(warmth) if warmth.error.is_some() => ("error", Color::Red),
+        Some(warmth) if warmth.is_warm(chrono::Utc::now()) => ("warm", Color::Green),
+        Some(_) => ("cold", Color::Yellow),
+    };
+    Cell::from(text).style(Style::default().fg(color))
+}
+
+/// Whether the provider's bearer token is good, going by its last fetch.
+/// A failed renewal is `stale` while the old token lasts.
+fn auth_cell(status: Option<&AuthStatus>) -> Cell<'static> {
+    let (text, color) = match status {
+        None => ("-", Color::DarkGray),
+        Some(status) if status.error.is_none() => ("ok", Color::Green),
+        Some(status) if status.expires.is_some_and(|at| at > chrono::Utc::now()) => {
+            ("stale", Color::Yellow)
+        }
+        Some(_) => ("error", Color::Red),
+    };
+    Cell::from(text).style(Style::default().fg(color))
 }
@@ -0,0 +1,91 @@
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Clear, Paragraph};
+
+use crate::control::RoutingState;
+
+/// Draws the routing panel over the current tab: forced provider, pins,
+/// pattern routes with their enabled state, and the selected route.
+pub fn draw(frame: &mut Frame, area: Rect, routing: Option<&RoutingState>, cursor: usize) {
+    let mut lines = Vec::new();
+    match routing {
+        None => lines.push(Line::from("waiting for the daemon...")),
+        Some(state) => {
+            lines.push(match state.forced {
+                Some(ref provider) => Line::from(vec![
+                    Span::raw("Traffic: "),
+                    Span::styled(
+                        format!("forced to {provider}"),
+                        Style::default()
+                            .fg(Color::Yellow)
+                            .add_modifier(Modifier::BOLD),
+                    ),
+                ]),
+                None => Line::from("Traffic: normal routing"),
+            });
+            for pin in &state.pins {
+                lines.push(Line::from(vec![
+                    Span::raw("Pinned:  "),
+                    Span::styled(
+                        format!("{} -> {}", pin.pattern, pin.provider),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                    Span::styled(
+                        if pin.saved { " (saved)" } else { "" },
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ]));
+            }
+            lines.push(Line::from(""));
+            if state.routes.is_empty() {
+                lines.push(Line::from(Span::styled(
+                    "no pattern routes",
+                    Style::default().fg(Color::DarkGray),
+                )));
+            }
+            for route in &state.routes {
+                let marker = if route.index == cursor { "> " } else { "  " };
+                let (flag, flag_style) = if route.enabled {
+                    ("on ", Style::default().fg(Color::Green))
+                } else {
+                    ("off", Style::default().fg(Color::Red))
+                };
+                let target = match route.model {
+                    Some(ref model) => format!("{} ({model})", route.provider),
+                    None => route.provider.clone(),
+                };
+                let mut line = Line::from(vec![
+                    Span::raw(marker),
+                    Span::styled(flag, flag_style),
+                    Span::raw(format!(
+                        " {:>2}  {} -> {target}",
+                        route.index, route.pattern
+                    )),
+                ]);
+                if route.index == cursor {
+                    line = line.style(Style::default().add_modifier(Modifier::BOLD));
+                }
+                lines.push(line);
+            }
+        }
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        "enter:toggle route  P:pin route  p:force provider  esc:close",
+        Style::default().fg(Color::DarkGray),
+    )));
+
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let width = (area.width * 3 / 4).max(40).min(area.width);
+    let panel = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+
+    frame.render_widget(Clear, panel);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Routing ")),
+        panel,
+    );
+}
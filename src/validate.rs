@@ -0,0 +1,1277 @@
+use std::cell::RefCell;
+use std::path::Path;
+
+use figment::Figment;
+use nix::unistd::{AccessFlags, access};
+use regex::Regex;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde_json::Value;
+
+use crate::config::{ApiFormat, Config, ContextGuard, MissingModels};
+use crate::context_windows;
+use crate::middleware;
+use crate::response_headers;
+use crate::script::Script;
+use crate::tokenizer::Tokenizer;
+
+/// Problems found in a config. Everything is collected so a single run
+/// reports every mistake instead of stopping at the first.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Validates the extracted `config` and the raw `file` figment it came from.
+/// Unknown keys are errors when `strict` is on (the default) and warnings
+/// otherwise.
+pub fn validate(file: &Figment, config: &Config) -> Report {
+    let mut report = Report::default();
+
+    match file.extract::<Value>() {
+        Ok(value) => {
+            for key in unknown_keys::<Config>(value) {
+                let msg = format!("unknown key '{key}'");
+                if config.strict {
+                    report.errors.push(msg);
+                } else {
+                    report.warnings.push(msg);
+                }
+            }
+        }
+        Err(e) => report.errors.push(e.to_string()),
+    }
+
+    report.errors.extend(check_config(config));
+    let admin = &config.admin;
+    let tokenless = [&admin.token, &admin.token_file, &admin.token_keychain]
+        .iter()
+        .all(|source| source.is_none());
+    if admin.enabled
+        && tokenless
+        && let Some(address) = crate::admin::exposed_address(&config.server)
+    {
+        report.warnings.push(format!(
+            "admin: no token is set and {address} is reachable from other machines, \
+             so the admin API won't be served"
+        ));
+    }
+    report
+}
+
+/// Semantic checks that serde can't express.
+pub fn check_config(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if config.server.port == 0 {
+        errors.push("server.port must be between 1 and 65535".to_string());
+    }
+    if config.server.max_body_size == 0 {
+        errors.push("server.max_body_size must be greater than 0".to_string());
+    }
+    if !(config.server.body_size_warn_percent > 0.0
+        && config.server.body_size_warn_percent <= 100.0)
+    {
+        errors.push("server.body_size_warn_percent must be between 0 and 100".to_string());
+    }
+    if config.server.stream_buffer_size == 0 {
+        errors.push("server.stream_buffer_size must be greater than 0".to_string());
+    }
+    if config.server.sse_heartbeat_secs == Some(0) {
+        errors.push("server.sse_heartbeat_secs must be greater than 0".to_string());
+    }
+    if config.server.request_timeout_ms == Some(0) {
+        errors.push("server.request_timeout_ms must be greater than 0".to_string());
+    }
+    if let Err(e) = crate::listeners::Allowlist::parse(&config.server.allow_cidrs) {
+        errors.push(format!("server.allow_cidrs: {e}"));
+    }
+    if let Some(ref header) = config.server.client_header
+        && http::HeaderName::from_bytes(header.as_bytes()).is_err()
+    {
+        errors.push(format!(
+            "server.client_header: '{header}' is not a valid header name"
+        ));
+    }
+    for (i, listener) in config.server.listeners.iter().enumerate() {
+        if let Err(e) = crate::listeners::check(listener) {
+            errors.push(format!("server.listeners.{i}: {e}"));
+        }
+    }
+    if let Err(e) = crate::tui::keys::Keymap::from_config(&config.tui.keys) {
+        errors.push(e);
+    }
+    if config.tui.live_log.columns.is_empty() {
+        errors.push("tui.live_log.columns must not be empty".to_string());
+    }
+    if let Err(e) = crate::scrub::Scrubber::new(&config.logging.redact_patterns) {
+        errors.push(format!("logging.redact_patterns: {e}"));
+    }
+    if config.logging.crash.lines == 0 {
+        errors.push("logging.crash.lines must be greater than 0".to_string());
+    }
+    if config.tool_results.max_size == Some(0) {
+        errors.push("tool_results.max_size must be greater than 0".to_string());
+    }
+    if let Err(e) = response_headers::Policy::from_config(&config.response_headers) {
+        errors.push(format!("response_headers.{e}"));
+    }
+    if config.capture.max_requests == 0 {
+        errors.push("capture.max_requests must be greater than 0".to_string());
+    }
+    if config.archive.enabled && config.archive.path.is_none() {
+        errors.push("archive.path must be set to archive responses".to_string());
+    }
+    if config.archive.max_bytes == 0 {
+        errors.push("archive.max_bytes must be greater than 0".to_string());
+    }
+    let notifications = &config.notifications;
+    for (field, share) in [
+        ("budget_threshold", notifications.budget_threshold),
+        ("down_error_rate", notifications.down_error_rate),
+    ] {
+        if !(share > 0.0 && share <= 1.0) {
+            errors.push(format!(
+                "notifications.{field} must be greater than 0 and at most 1"
+            ));
+        }
+    }
+    if notifications.long_request_secs == 0 {
+        errors.push("notifications.long_request_secs must be greater than 0".to_string());
+    }
+    let buckets = &config.telemetry.prometheus.latency_buckets_ms;
+    if buckets.is_empty() || buckets[0] == 0 || buckets.windows(2).any(|w| w[0] >= w[1]) {
+        errors.push(
+            "telemetry.prometheus.latency_buckets_ms must be ascending and greater than 0"
+                .to_string(),
+        );
+    }
+    let mut models: Vec<_> = config.models.iter().collect();
+    models.sort_by_key(|(name, _)| *name);
+    for (name, limits) in models {
+        for (field, tokens) in [
+            ("context_window", limits.context_window),
+            ("max_output_tokens", limits.max_output_tokens),
+        ] {
+            if tokens == Some(0) {
+                errors.push(format!("models.{name}.{field} must be greater than 0"));
+            }
+        }
+    }
+    let mut clients: Vec<_> = config.clients.iter().collect();
+    clients.sort_by_key(|(name, _)| *name);
+    for (name, client) in clients {
+        for (field, quota) in [
+            ("daily_tokens", client.daily_tokens),
+            ("monthly_tokens", client.monthly_tokens),
+        ] {
+            if quota == Some(0) {
+                errors.push(format!("clients.{name}.{field} must be greater than 0"));
+            }
+        }
+    }
+
+    let mut names: Vec<&String> = config.providers.keys().collect();
+    names.sort();
+    for name in names {
+        let provider = &config.providers[name];
+        if let Err(e) = check_url(&provider.url) {
+            errors.push(format!("provider.{name}.url: {e}"));
+        }
+        if let Some(ref url) = provider.proxy_url
+            && let Err(e) = check_url(url)
+        {
+            errors.push(format!("provider.{name}.proxy_url: {e}"));
+        }
+        let has_token = provider.token_command.is_some() || provider.oauth.is_some();
+        if matches!(provider.api_format, ApiFormat::Gemini | ApiFormat::Azure)
+            && provider.api_key.is_none()
+            && provider.api_key_file.is_none()
+            && provider.api_key_keychain.is_none()
+            && !has_token
+        {
+            errors.push(format!(
+                "provider.{name}: api_format \"{}\" requires an API key",
+                provider.api_format
+            ));
+        }
+        if let Some(ref oauth) = provider.oauth
+            && let Err(e) = check_url(&oauth.token_url)
+        {
+            errors.push(format!("provider.{name}.oauth.token_url: {e}"));
+        }
+        if provider.token_ttl_secs == Some(0) {
+            errors.push(format!(
+                "provider.{name}.token_ttl_secs must be greater than 0"
+            ));
+        }
+        if provider.api_format == ApiFormat::Vertex && has_token {
+            errors.push(format!(
+                "provider.{name}: vertex providers authenticate with credentials_file, not token_command or oauth"
+            ));
+        }
+        if provider.api_format == ApiFormat::Vertex {
+            if provider.project.is_none() {
+                errors.push(format!("provider.{name}.project is required for vertex"));
+            }
+            if provider.region.is_none() {
+                errors.push(format!("provider.{name}.region is required for vertex"));
+            }
+        }
+        if let Some(reserve) = provider.ratelimit_reserve
+            && !(reserve > 0.0 && reserve < 1.0)
+        {
+            errors.push(format!(
+                "provider.{name}.ratelimit_reserve must be between 0 and 1"
+            ));
+        }
+        if provider.retry_429_max_wait_secs == Some(0) {
+            errors.push(format!(
+                "provider.{name}.retry_429_max_wait_secs must be greater than 0"
+            ));
+        }
+        if let Some(ref fallback) = provider.ratelimit_fallback {
+            if provider.ratelimit_reserve.is_none() {
+                errors.push(format!(
+                    "provider.{name}.ratelimit_fallback needs ratelimit_reserve"
+                ));
+            }
+            if fallback == name || !config.providers.contains_key(fallback) {
+                errors.push(format!(
+                    "provider.{name}.ratelimit_fallback: '{fallback}' is not another provider"
+                ));
+            } else if !config.routes_to(fallback) {
+                errors.push(format!(
+                    "provider.{name}.ratelimit_fallback: '{fallback}' is not used by the default or any route"
+                ));
+            }
+        }
+        if let Some(ref path) = provider.tokenizer
+            && let Err(e) = Tokenizer::from_file(path)
+        {
+            errors.push(format!("provider.{name}.tokenizer: {e}"));
+        }
+        if provider.model_check_secs == Some(0) {
+            errors.push(format!(
+                "provider.{name}.model_check_secs must be greater than 0"
+            ));
+        }
+        if provider.missing_models == Some(MissingModels::Fallback)
+            && config.default.provider == *name
+        {
+            errors.push(format!(
+                "provider.{name}.missing_models: \"fallback\" needs a default provider other than '{name}'"
+            ));
+        }
+        if let Some(ref fallback) = provider.capability_fallback {
+            if fallback == name || !config.providers.contains_key(fallback) {
+                errors.push(format!(
+                    "provider.{name}.capability_fallback: '{fallback}' is not another provider"
+                ));
+            } else if !config.routes_to(fallback) {
+                errors.push(format!(
+                    "provider.{name}.capability_fallback: '{fallback}' is not used by the default or any route"
+                ));
+            }
+        }
+        if provider.context_window == Some(0) {
+            errors.push(format!(
+                "provider.{name}.context_window must be greater than 0"
+            ));
+        }
+        let mut headers: Vec<_> = provider.default_headers.iter().collect();
+        headers.sort();
+        for (header, value) in headers {
+            match http::HeaderName::from_bytes(header.as_bytes()) {
+                Err(_) => errors.push(format!(
+                    "provider.{name}.default_headers: '{header}' is not a valid header name"
+                )),
+                Ok(h) if h == http::header::HOST || h == http::header::CONTENT_LENGTH => errors
+                    .push(format!(
+                        "provider.{name}.default_headers: croxy sets '{header}' itself"
+                    )),
+                Ok(_) if http::HeaderValue::from_str(value).is_err() => errors.push(format!(
+                    "provider.{name}.default_headers: '{header}' has an invalid value"
+                )),
+                Ok(_) => {}
+            }
+        }
+        if let Some(interval) = provider.warm_interval_secs {
+            if interval == 0 {
+                errors.push(format!(
+                    "provider.{name}.warm_interval_secs must be greater than 0"
+                ));
+            }
+            if !matches!(
+                provider.api_format,
+                ApiFormat::Anthropic | ApiFormat::Ollama
+            ) {
+                errors.push(format!(
+                    "provider.{name}.warm_interval_secs: warm-up pings need api_format \"anthropic\" or \"ollama\""
+                ));
+            }
+            if !config.routes_to(name) {
+                errors.push(format!(
+                    "provider.{name}.warm_interval_secs: '{name}' is not used by the default or any route"
+                ));
+            } else if crate::warm::model_for(config, name).is_none() {
+                errors.push(format!(
+                    "provider.{name}.warm_interval_secs needs warm_model or a route with a model"
+                ));
+            }
+        }
+    }
+
+    if !config.providers.contains_key(&config.default.provider) {
+        errors.push(format!(
+            "default.provider: '{}' not found in providers",
+            config.default.provider
+        ));
+    }
+    for (endpoint, default) in config.default.endpoints() {
+        if !config.providers.contains_key(&default.provider) {
+            errors.push(format!(
+                "default.{endpoint}.provider: '{}' not found in providers",
+                default.provider
+            ));
+        }
+    }
+
+    let limits = std::iter::once((
+        "default".to_string(),
+        config.default.max_response_bytes,
+        config.default.max_stream_secs,
+    ))
+    .chain(config.routes.iter().enumerate().map(|(i, route)| {
+        (
+            format!("routes.{i}"),
+            route.max_response_bytes,
+            route.max_stream_secs,
+        )
+    }));
+    for (at, max_bytes, max_secs) in limits {
+        if max_bytes == Some(0) {
+            errors.push(format!("{at}.max_response_bytes must be greater than 0"));
+        }
+        if max_secs == Some(0) {
+            errors.push(format!("{at}.max_stream_secs must be greater than 0"));
+        }
+    }
+
+    let mut groups: Vec<_> = config.groups.iter().collect();
+    groups.sort_by_key(|&(name, _)| name);
+    for (name, group) in groups {
+        if config.providers.contains_key(name) {
+            errors.push(format!("group.{name}: '{name}' is also a provider"));
+        }
+        if group.members.is_empty() {
+            errors.push(format!("group.{name}.members must not be empty"));
+        }
+        for member in &group.members {
+            if !config.providers.contains_key(member) {
+                errors.push(format!(
+                    "group.{name}.members: '{member}' not found in providers"
+                ));
+            }
+        }
+    }
+
+    let model_limits = context_windows::Registry::from_config(config);
+    for (i, route) in config.routes.iter().enumerate() {
+        // A route naming a group reaches each of its members
+        let members = match config.groups.get(&route.provider) {
+            Some(group) => group.members.clone(),
+            None => vec![route.provider.clone()],
+        };
+        if !config.providers.contains_key(&route.provider)
+            && !config.groups.contains_key(&route.provider)
+        {
+            errors.push(format!(
+                "routes.{i}.provider: '{}' not found in providers or groups",
+                route.provider
+            ));
+        }
+        if let Some(ref pattern) = route.pattern
+            && let Err(e) = Regex::new(pattern)
+        {
+            errors.push(format!("routes.{i}.pattern: invalid regex: {e}"));
+        }
+        if let Some(ref user_id) = route.user_id
+            && let Err(e) = Regex::new(user_id)
+        {
+            errors.push(format!("routes.{i}.user_id: invalid regex: {e}"));
+        }
+        if route.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+            errors.push(format!("routes.{i}.temperature must be between 0 and 2"));
+        }
+        if route.top_p.is_some_and(|p| !(p > 0.0 && p <= 1.0)) {
+            errors.push(format!(
+                "routes.{i}.top_p must be greater than 0 and at most 1"
+            ));
+        }
+        if route.top_k == Some(0) {
+            errors.push(format!("routes.{i}.top_k must be greater than 0"));
+        }
+        if route.max_tokens == Some(0) {
+            errors.push(format!("routes.{i}.max_tokens must be greater than 0"));
+        }
+        if let Some(guard) = route.context_guard {
+            // Without a rewrite the client's model is looked up per request
+            if let Some(ref model) = route.model
+                && model_limits.limits(model, None).is_none()
+            {
+                for member in &members {
+                    if config
+                        .providers
+                        .get(member)
+                        .is_some_and(|p| p.context_window.is_none())
+                    {
+                        errors.push(format!(
+                            "routes.{i}.context_guard needs provider.{member}.context_window or a [models] context_window for '{model}'"
+                        ));
+                    }
+                }
+            }
+            if guard == ContextGuard::Summarize && config.context_guard.summary_model.is_none() {
+                errors.push(format!(
+                    "routes.{i}.context_guard = \"summarize\" needs context_guard.summary_provider and summary_model"
+                ));
+            }
+        }
+        if route.archive && !config.archive.enabled {
+            errors.push(format!("routes.{i}.archive needs [archive] enabled"));
+        }
+    }
+
+    let summary = &config.context_guard;
+    match (&summary.summary_provider, &summary.summary_model) {
+        (Some(provider), Some(_)) => {
+            if !config.providers.contains_key(provider) {
+                errors.push(format!(
+                    "context_guard.summary_provider: '{provider}' not found in providers"
+                ));
+            } else if !config.routes_to(provider) {
+                errors.push(format!(
+                    "context_guard.summary_provider: '{provider}' is not used by the default or any route"
+                ));
+            }
+        }
+        (Some(_), None) => {
+            errors.push("context_guard.summary_provider needs summary_model".to_string())
+        }
+        (None, Some(_)) => {
+            errors.push("context_guard.summary_model needs summary_provider".to_string())
+        }
+        (None, None) => {}
+    }
+
+    for (i, rule) in config.compare.iter().enumerate() {
+        if !config.providers.contains_key(&rule.provider) {
+            errors.push(format!(
+                "compare.{i}.provider: '{}' not found in providers",
+                rule.provider
+            ));
+        } else if !config.routes_to(&rule.provider) {
+            errors.push(format!(
+                "compare.{i}.provider: '{}' is not used by the default or any route",
+                rule.provider
+            ));
+        }
+        if let Err(e) = Regex::new(&rule.pattern) {
+            errors.push(format!("compare.{i}.pattern: invalid regex: {e}"));
+        }
+        if !(rule.sample > 0.0 && rule.sample <= 1.0) {
+            errors.push(format!(
+                "compare.{i}.sample must be greater than 0 and at most 1"
+            ));
+        }
+    }
+
+    for (i, stage) in config.middleware.iter().enumerate() {
+        if let Err(e) = middleware::check(stage) {
+            errors.push(format!("middleware.{i}: {e}"));
+        }
+        for provider in &stage.providers {
+            if !config.providers.contains_key(provider) {
+                errors.push(format!(
+                    "middleware.{i}.providers: '{provider}' not found in providers"
+                ));
+            }
+        }
+    }
+
+    if let Err(e) = Script::from_config(&config.script) {
+        errors.push(format!("script.path: {e}"));
+    }
+    if config.script.timeout_ms == 0 {
+        errors.push("script.timeout_ms must be greater than 0".to_string());
+    }
+
+    for (i, rule) in config.chaos.rules.iter().enumerate() {
+        if let Err(e) = crate::chaos::check(rule) {
+            errors.push(format!("chaos.rules.{i}: {e}"));
+        }
+        for name in &rule.routes {
+            let is_route = config
+                .routes
+                .iter()
+                .any(|route| route.name.as_deref() == Some(name));
+            if !is_route && !config.providers.contains_key(name) {
+                errors.push(format!(
+                    "chaos.rules.{i}.routes: '{name}' is neither a route name nor a provider"
+                ));
+            }
+        }
+    }
+
+    if config.auto_router.enabled
+        && let Err(e) = check_url(&config.auto_router.url)
+    {
+        errors.push(format!("auto_router.url: {e}"));
+    }
+    if let Some(ref local) = config.auto_router.local {
+        if !config.auto_router.enabled {
+            errors.push("auto_router.local is set but auto_router.enabled is false".to_string());
+        }
+        if local.command.trim().is_empty() {
+            errors.push("auto_router.local.command must not be empty".to_string());
+        }
+        if let Some(ref url) = local.health_url
+            && let Err(e) = check_url(url)
+        {
+            errors.push(format!("auto_router.local.health_url: {e}"));
+        }
+    }
+
+    let admin_sources = [
+        &config.admin.token,
+        &config.admin.token_file,
+        &config.admin.token_keychain,
+    ];
+    if admin_sources
+        .iter()
+        .filter(|source| source.is_some())
+        .count()
+        > 1
+    {
+        errors.push("admin sets more than one of token, token_file, token_keychain".to_string());
+    }
+
+    if config.retention.enabled && config.retention.minutes == 0 {
+        errors.push("retention.minutes must be greater than 0".to_string());
+    }
+    if config.retention.display_minutes == Some(0) {
+        errors.push("retention.display_minutes must be greater than 0".to_string());
+    }
+
+    let mut profile_names: Vec<&String> = config.profiles.keys().collect();
+    profile_names.sort();
+    for name in profile_names {
+        let profile = &config.profiles[name];
+        if !profile.profiles.is_empty() {
+            errors.push(format!("profiles.{name}: profiles cannot be nested"));
+        }
+        if !profile.include.is_empty() {
+            errors.push(format!(
+                "profiles.{name}.include: includes are only allowed at the top level"
+            ));
+        }
+    }
+
+    if config.logging.metrics.enabled
+        && let Err(e) = check_writable(Path::new(&config.logging.metrics.path))
+    {
+        errors.push(format!("logging.metrics.path: {e}"));
+    }
+
+    errors
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid URL '{url}': {e}"))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!(
+            "unsupported scheme '{scheme}' in '{url}', expected http or https"
+        )),
+    }
+}
+
+/// Checks that a file could be created or appended to at `path`: the file
+/// itself if it exists, otherwise its nearest existing ancestor directory.
+fn check_writable(path: &Path) -> Result<(), String> {
+    let mut target = path;
+    while !target.exists() {
+        match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => target = parent,
+            _ => return Ok(()),
+        }
+    }
+    if target != path && !target.is_dir() {
+        return Err(format!("{} is not a directory", target.display()));
+    }
+    access(target, AccessFlags::W_OK).map_err(|_| format!("{} is not writable", target.display()))
+}
+
+/// Returns dotted paths of keys in `value` that `T` doesn't declare, found by
+/// feeding `value` through a deserializer that compares each object against
+/// the field list serde derives for the struct it is read into.
+pub fn unknown_keys<T: DeserializeOwned>(value: Value) -> Vec<String> {
+    let unknown = RefCell::new(Vec::new());
+    // Type errors are reported by the real extraction; only keys matter here.
+    let _ = T::deserialize(Probe {
+        value,
+        path: String::new(),
+        unknown: &unknown,
+    });
+    let mut keys = unknown.into_inner();
+    keys.sort();
+    keys
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+struct Probe<'a> {
+    value: Value,
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'de> de::Deserializer<'de> for Probe<'_> {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Object(map) => visitor.visit_map(ProbeMap {
+                iter: map.into_iter(),
+                pending: None,
+                path: self.path,
+                unknown: self.unknown,
+            }),
+            Value::Array(items) => visitor.visit_seq(ProbeSeq {
+                iter: items.into_iter().enumerate(),
+                path: self.path,
+                unknown: self.unknown,
+            }),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if let Value::Object(ref map) = self.value {
+            let mut unknown = self.unknown.borrow_mut();
+            for key in map.keys() {
+                if !fields.contains(&key.as_str()) {
+                    unknown.push(join(&self.path, key));
+                }
+            }
+        }
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map identifier
+    }
+}
+
+struct ProbeMap<'a> {
+    iter: serde_json::map::IntoIter,
+    pending: Option<(String, Value)>,
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'de> MapAccess<'de> for ProbeMap<'_> {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, value)) = self.iter.next() else {
+            return Ok(None);
+        };
+        let deserializer: de::value::StringDeserializer<Self::Error> =
+            key.clone().into_deserializer();
+        self.pending = Some((key, value));
+        seed.deserialize(deserializer).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, value) = self
+            .pending
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before key"))?;
+        seed.deserialize(Probe {
+            value,
+            path: join(&self.path, &key),
+            unknown: self.unknown,
+        })
+    }
+}
+
+struct ProbeSeq<'a> {
+    iter: std::iter::Enumerate<std::vec::IntoIter<Value>>,
+    path: String,
+    unknown: &'a RefCell<Vec<String>>,
+}
+
+impl<'de> SeqAccess<'de> for ProbeSeq<'_> {
+    type Error = serde_json::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        let Some((i, value)) = self.iter.next() else {
+            return Ok(None);
+        };
+        seed.deserialize(Probe {
+            value,
+            path: join(&self.path, &i.to_string()),
+            unknown: self.unknown,
+        })
+        .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::providers::{Format, Toml};
+
+    fn report(toml: &str) -> Report {
+        let file = Figment::from(Toml::string(toml));
+        let config: Config = file.extract().unwrap();
+        validate(&file, &config)
+    }
+
+    const BASE: &str = r#"
+        [provider.anthropic]
+        url = "https://api.anthropic.com"
+    "#;
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        let r = report(BASE);
+        assert!(r.is_ok(), "{:?}", r.errors);
+        assert!(r.warnings.is_empty());
+    }
+
+    #[test]
+    fn missing_model_fallback_needs_somewhere_else_to_go() {
+        let r = report(
+            "[provider.ollama]\nurl = \"http://localhost:11434\"\n\
+             missing_models = \"fallback\"\nmodel_check_secs = 0\n\
+             [default]\nprovider = \"ollama\"\n",
+        );
+        assert_eq!(
+            r.errors,
+            [
+                "provider.ollama.model_check_secs must be greater than 0",
+                "provider.ollama.missing_models: \"fallback\" needs a default provider other than 'ollama'",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_tokenless_admin_api_on_a_public_address_is_flagged() {
+        let r = report(&format!("{BASE}\n[server]\nhost = \"0.0.0.0\"\n"));
+        assert!(r.is_ok(), "{:?}", r.errors);
+        assert_eq!(
+            r.warnings,
+            [
+                "admin: no token is set and 0.0.0.0:3100 is reachable from other machines, \
+              so the admin API won't be served"
+            ]
+        );
+        let r = report(&format!(
+            "{BASE}\n[server]\nhost = \"0.0.0.0\"\n[admin]\ntoken = \"a\"\ntoken_file = \"b\"\n"
+        ));
+        assert!(r.warnings.is_empty());
+        assert_eq!(
+            r.errors,
+            ["admin sets more than one of token, token_file, token_keychain"]
+        );
+        assert!(report(BASE).warnings.is_empty());
+    }
+
+    #[test]
+    fn local_auto_router_needs_a_command_and_the_auto_router() {
+        let r = report(&format!(
+            "{BASE}\n[auto_router.local]\ncommand = \"\"\nhealth_url = \"localhost:8081\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            [
+                "auto_router.local is set but auto_router.enabled is false",
+                "auto_router.local.command must not be empty",
+                "auto_router.local.health_url: unsupported scheme 'localhost' in 'localhost:8081', expected http or https",
+            ]
+        );
+    }
+
+    #[test]
+    fn capability_fallbacks_must_be_routed_providers() {
+        let r = report(&format!(
+            "{BASE}\n[provider.anthropic.capabilities]\nbatches = false\n\
+             [provider.ollama]\nurl = \"http://localhost:11434\"\ncapability_fallback = \"ollama\"\n\
+             [provider.gw]\nurl = \"https://gw.example\"\ncapability_fallback = \"anthropic\"\n\
+             [provider.vllm]\nurl = \"http://localhost:8000\"\ncapability_fallback = \"gw\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            [
+                "provider.ollama.capability_fallback: 'ollama' is not another provider",
+                "provider.vllm.capability_fallback: 'gw' is not used by the default or any route",
+            ]
+        );
+    }
+
+    #[test]
+    fn default_headers_must_be_sendable() {
+        let r = report(&format!(
+            r#"{BASE}
+            [provider.anthropic.default_headers]
+            anthropic-version = "2023-06-01"
+            "bad header" = "x"
+            host = "api.example"
+            x-note = "line\nbreak"
+            "#
+        ));
+        assert_eq!(
+            r.errors,
+            [
+                "provider.anthropic.default_headers: 'bad header' is not a valid header name",
+                "provider.anthropic.default_headers: croxy sets 'host' itself",
+                "provider.anthropic.default_headers: 'x-note' has an invalid value",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_paths() {
+        let r = report(
+            r#"
+            typo = 1
+            [server]
+            prot = 3100
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            strip_authh = true
+            [route_defaults]
+            pattern = "sonnet"
+            [[routes]]
+            pattern = "opus"
+            provider = "anthropic"
+            modle = "x"
+            "#,
+        );
+        assert_eq!(
+            r.errors,
+            vec![
+                "unknown key 'provider.anthropic.strip_authh'",
+                "unknown key 'route_defaults.pattern'",
+                "unknown key 'routes.0.modle'",
+                "unknown key 'server.prot'",
+                "unknown key 'typo'",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_warnings_when_not_strict() {
+        let r = report(&format!("strict = false\nextra = true\n{BASE}"));
+        assert!(r.is_ok(), "{:?}", r.errors);
+        assert_eq!(r.warnings, vec!["unknown key 'extra'"]);
+    }
+
+    #[test]
+    fn unknown_keys_inside_profiles_are_reported() {
+        let r = report(&format!(
+            "{BASE}\n[profiles.work.provider.anthropic]\nurl = \"https://gw.corp\"\nstrip_authh = true\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec!["unknown key 'profiles.work.provider.anthropic.strip_authh'"]
+        );
+    }
+
+    #[test]
+    fn semantic_errors_are_all_reported() {
+        let r = report(
+            r#"
+            [server]
+            port = 0
+            [provider.anthropic]
+            url = "api.anthropic.com"
+            [provider.corp]
+            url = "ftp://corp"
+            [[routes]]
+            pattern = "("
+            provider = "missing"
+            [default]
+            provider = "nope"
+            [default.models]
+            provider = "gone"
+            "#,
+        );
+        assert_eq!(r.errors.len(), 7, "{:#?}", r.errors);
+        assert!(r.errors[0].starts_with("server.port"));
+        assert!(r.errors[1].starts_with("provider.anthropic.url"));
+        assert!(r.errors[2].contains("unsupported scheme 'ftp'"));
+        assert!(r.errors[3].starts_with("default.provider"));
+        assert!(r.errors[4].starts_with("default.models.provider"));
+        assert!(r.errors[5].starts_with("routes.0.provider"));
+        assert!(r.errors[6].starts_with("routes.0.pattern"));
+    }
+
+    #[test]
+    fn archived_routes_need_the_archive() {
+        let r = report(&format!(
+            r#"{BASE}
+            [[routes]]
+            pattern = "opus"
+            provider = "anthropic"
+            archive = true
+            "#
+        ));
+        assert_eq!(r.errors, vec!["routes.0.archive needs [archive] enabled"]);
+
+        let r = report(&format!(
+            "{BASE}\n[archive]\nenabled = true\nmax_bytes = 0\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "archive.path must be set to archive responses",
+                "archive.max_bytes must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn notification_thresholds_must_be_in_range() {
+        let r = report(&format!(
+            r#"{BASE}
+            [notifications]
+            command = ["notify-send", "croxy"]
+            on = ["budget", "long_request"]
+            budget_threshold = 1.5
+            down_error_rate = 0
+            long_request_secs = 0
+            "#
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "notifications.budget_threshold must be greater than 0 and at most 1",
+                "notifications.down_error_rate must be greater than 0 and at most 1",
+                "notifications.long_request_secs must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn latency_buckets_must_ascend() {
+        let buckets = |list: &str| {
+            report(&format!(
+                "{BASE}\n[telemetry.prometheus]\nlatency_buckets_ms = {list}\n"
+            ))
+            .errors
+        };
+        assert!(buckets("[100, 1000, 300000]").is_empty());
+        for bad in ["[]", "[0, 100]", "[1000, 100]", "[100, 100]"] {
+            assert_eq!(
+                buckets(bad),
+                vec![
+                    "telemetry.prometheus.latency_buckets_ms must be ascending and greater than 0"
+                ],
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn model_limits_must_be_positive() {
+        let r = report(&format!(
+            r#"{BASE}
+            [models.qwen3-coder]
+            context_window = 0
+            [models.llama3]
+            context_window = 8192
+            max_output_tokens = 0
+            "#
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "models.llama3.max_output_tokens must be greater than 0",
+                "models.qwen3-coder.context_window must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn crash_bundles_keep_some_log_lines() {
+        let r = report(&format!("{BASE}\n[logging.crash]\nlines = 0\n"));
+        assert_eq!(r.errors, vec!["logging.crash.lines must be greater than 0"]);
+    }
+
+    #[test]
+    fn client_header_must_be_a_header_name() {
+        let r = report(&format!("{BASE}\n[server]\nclient_header = \"x client\"\n"));
+        assert_eq!(
+            r.errors,
+            vec!["server.client_header: 'x client' is not a valid header name"]
+        );
+    }
+
+    #[test]
+    fn client_quotas_must_allow_some_tokens() {
+        let r = report(&format!(
+            "{BASE}\n[clients.intern]\ndaily_tokens = 0\nmonthly_tokens = 5000000\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec!["clients.intern.daily_tokens must be greater than 0"]
+        );
+    }
+
+    #[test]
+    fn groups_need_existing_members_and_their_own_name() {
+        let r = report(&format!(
+            r#"{BASE}
+            [group.anthropic]
+            members = []
+            [group.local]
+            members = ["mini"]
+            [[routes]]
+            pattern = "sonnet"
+            provider = "local"
+            "#
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "group.anthropic: 'anthropic' is also a provider",
+                "group.anthropic.members must not be empty",
+                "group.local.members: 'mini' not found in providers",
+            ]
+        );
+    }
+
+    #[test]
+    fn unwritable_metrics_path_is_reported() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let r = report(&format!(
+            "{BASE}\n[logging.metrics]\nenabled = true\npath = \"{}/metrics.jsonl\"\n",
+            file.path().display()
+        ));
+        assert!(
+            r.errors
+                .iter()
+                .any(|e| e.starts_with("logging.metrics.path")),
+            "{:?}",
+            r.errors
+        );
+    }
+
+    #[test]
+    fn creatable_metrics_path_is_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("new/sub/metrics.jsonl");
+        let r = report(&format!(
+            "{BASE}\n[logging.metrics]\nenabled = true\npath = \"{}\"\n",
+            path.display()
+        ));
+        assert!(r.is_ok(), "{:?}", r.errors);
+    }
+
+    #[test]
+    fn gemini_without_key_is_reported() {
+        let r = report(&format!(
+            "{BASE}\n[provider.gemini]\nurl = \"https://generativelanguage.googleapis.com\"\napi_format = \"gemini\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec!["provider.gemini: api_format \"gemini\" requires an API key"]
+        );
+    }
+
+    #[test]
+    fn azure_without_key_is_reported() {
+        let r = report(&format!(
+            "{BASE}\n[provider.azure]\nurl = \"https://acme.openai.azure.com\"\napi_format = \"azure\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec!["provider.azure: api_format \"azure\" requires an API key"]
+        );
+    }
+
+    #[test]
+    fn token_sources_stand_in_for_api_keys() {
+        let r = report(&format!(
+            "{BASE}\n[provider.azure]\nurl = \"https://acme.openai.azure.com\"\napi_format = \"azure\"\n\
+             [provider.azure.oauth]\ntoken_url = \"https://login.microsoftonline.com/t/oauth2/v2.0/token\"\n\
+             client_id = \"croxy\"\nclient_secret = \"s3cret\"\n\
+             [provider.vertex]\nurl = \"https://us-east5-aiplatform.googleapis.com\"\napi_format = \"vertex\"\n\
+             project = \"p\"\nregion = \"us-east5\"\ntoken_command = [\"gcloud\", \"auth\", \"print-access-token\"]\n\
+             [provider.gateway]\nurl = \"https://llm.corp.internal\"\ntoken_command = [\"corp-token\"]\ntoken_ttl_secs = 0\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "provider.gateway.token_ttl_secs must be greater than 0",
+                "provider.vertex: vertex providers authenticate with credentials_file, not token_command or oauth",
+            ]
+        );
+    }
+
+    #[test]
+    fn vertex_requires_project_and_region() {
+        let r = report(&format!(
+            "{BASE}\n[provider.vertex]\nurl = \"https://us-east5-aiplatform.googleapis.com\"\napi_format = \"vertex\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "provider.vertex.project is required for vertex",
+                "provider.vertex.region is required for vertex"
+            ]
+        );
+    }
+
+    #[test]
+    fn middleware_stages_are_checked() {
+        let r = report(&format!(
+            "{BASE}\n[[middleware]]\ntype = \"redact\"\nwords = [\"corp\"]\nproviders = [\"gemini\"]\n\
+             [[middleware]]\ntype = \"redact\"\npattern = \"x\"\nwords = [\"y\"]\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "middleware.0.providers: 'gemini' not found in providers",
+                "middleware.1: redact needs either pattern or words",
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_rules_are_checked() {
+        let r = report(&format!(
+            "{BASE}\n[provider.ollama]\nurl = \"http://localhost:11434\"\n\
+             [[compare]]\npattern = \"(\"\nprovider = \"ollama\"\nsample = 0.0\n"
+        ));
+        assert_eq!(r.errors.len(), 3, "{:#?}", r.errors);
+        assert!(r.errors[0].contains("is not used by the default or any route"));
+        assert!(r.errors[1].starts_with("compare.0.pattern"));
+        assert!(r.errors[2].starts_with("compare.0.sample"));
+    }
+
+    #[test]
+    fn warm_up_pings_are_checked() {
+        let r = report(&format!(
+            "{BASE}\n[provider.ollama]\nurl = \"http://localhost:11434\"\napi_format = \"ollama\"\nwarm_interval_secs = 0\n\
+             [provider.gemini]\nurl = \"https://generativelanguage.googleapis.com\"\napi_format = \"gemini\"\napi_key = \"k\"\nwarm_interval_secs = 60\n\
+             [[routes]]\npattern = \"haiku\"\nprovider = \"ollama\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "provider.gemini.warm_interval_secs: warm-up pings need api_format \"anthropic\" or \"ollama\"",
+                "provider.gemini.warm_interval_secs: 'gemini' is not used by the default or any route",
+                "provider.ollama.warm_interval_secs must be greater than 0",
+                "provider.ollama.warm_interval_secs needs warm_model or a route with a model",
+            ]
+        );
+    }
+
+    #[test]
+    fn sampling_overrides_are_checked() {
+        let r = report(&format!(
+            "{BASE}\n[[routes]]\npattern = \"haiku\"\nprovider = \"anthropic\"\n\
+             temperature = 2.5\ntop_p = 0.0\ntop_k = 0\nmax_tokens = 0\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "routes.0.temperature must be between 0 and 2",
+                "routes.0.top_p must be greater than 0 and at most 1",
+                "routes.0.top_k must be greater than 0",
+                "routes.0.max_tokens must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn context_guards_are_checked() {
+        let r = report(&format!(
+            "{BASE}\n[provider.ollama]\nurl = \"http://localhost:11434\"\n\
+             [[routes]]\npattern = \"haiku\"\nprovider = \"ollama\"\nmodel = \"my-finetune\"\ncontext_guard = \"summarize\"\n\
+             [[routes]]\npattern = \"sonnet\"\nprovider = \"ollama\"\nmodel = \"qwen3-coder:30b\"\ncontext_guard = \"trim\"\n\
+             [[routes]]\npattern = \"opus\"\nprovider = \"ollama\"\ncontext_guard = \"trim\"\n\
+             [context_guard]\nsummary_provider = \"anthropic\"\n"
+        ));
+        assert_eq!(
+            r.errors,
+            vec![
+                "routes.0.context_guard needs provider.ollama.context_window or a [models] context_window for 'my-finetune'",
+                "routes.0.context_guard = \"summarize\" needs context_guard.summary_provider and summary_model",
+                "context_guard.summary_provider needs summary_model",
+            ]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Claude on Google Vertex AI. The Messages API is unchanged apart from
+//! where the model goes: it moves from the body into the URL, and the body
+//! names the Vertex API version instead.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::config::{ApiFormat, Config, ProviderConfig};
+use crate::google_auth::TokenSource;
+
+pub const ANTHROPIC_VERSION: &str = "vertex-2023-10-16";
+
+/// A Vertex provider's project, region, and credentials.
+pub struct VertexProvider {
+    project: String,
+    region: String,
+    tokens: TokenSource,
+}
+
+impl VertexProvider {
+    pub fn from_config(name: &str, provider: &ProviderConfig) -> Result<Self, String> {
+        let setting = |value: &Option<String>, field: &str| {
+            value
+                .clone()
+                .ok_or_else(|| format!("provider '{name}': {field} is required for vertex"))
+        };
+        Ok(Self {
+            project: setting(&provider.project, "project")?,
+            region: setting(&provider.region, "region")?,
+            tokens: TokenSource::from_config(provider.credentials_file.as_deref())
+                .map_err(|e| format!("provider '{name}': {e}"))?,
+        })
+    }
+
+    /// The Vertex URL for a Messages API `path` on `model`, or `None` for
+    /// endpoints Vertex doesn't serve.
+    pub fn url(&self, base_url: &str, path: &str, model: &str, streaming: bool) -> Option<String> {
+        let action = match path {
+            "/v1/messages" if streaming => format!("{}:streamRawPredict", model_id(model)),
+            "/v1/messages" => format!("{}:rawPredict", model_id(model)),
+            "/v1/messages/count_tokens" => "count-tokens:rawPredict".to_string(),
+            _ => return None,
+        };
+        Some(format!(
+            "{}/v1/projects/{}/locations/{}/publishers/anthropic/models/{action}",
+            base_url.trim_end_matches('/'),
+            self.project,
+            self.region
+        ))
+    }
+
+    pub async fn token(&self, client: &reqwest::Client) -> Result<String, String> {
+        self.tokens.token(client).await
+    }
+}
+
+/// Vertex names model versions with `@`: `claude-sonnet-4-5-20250929`
+/// becomes `claude-sonnet-4-5@20250929`.
+pub fn model_id(model: &str) -> String {
+    if model.contains('@') {
+        return model.to_string();
+    }
+    match model.rsplit_once('-') {
+        Some((name, date)) if date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{name}@{date}")
+        }
+        _ => model.to_string(),
+    }
+}
+
+/// Adjusts a request body for Vertex. Token counting still takes the model
+/// in the body; messages take it in the URL.
+pub fn prepare_body(body: &mut Value, path: &str) {
+    let Some(fields) = body.as_object_mut() else {
+        return;
+    };
+    if path == "/v1/messages/count_tokens" {
+        if let Some(Value::String(model)) = fields.get_mut("model") {
+            *model = model_id(model);
+        }
+    } else {
+        fields.remove("model");
+        fields.insert(
+            "anthropic_version".to_string(),
+            Value::String(ANTHROPIC_VERSION.to_string()),
+        );
+    }
+}
+
+/// Sets up every Vertex provider, reading their credentials.
+pub fn providers(config: &Config) -> Result<HashMap<String, Arc<VertexProvider>>, String> {
+    config
+        .providers
+        .iter()
+        .filter(|(_, provider)| provider.api_format == ApiFormat::Vertex)
+        .map(|(name, provider)| {
+            Ok((
+                name.clone(),
+                Arc::new(VertexProvider::from_config(name, provider)?),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn provider() -> VertexProvider {
+        VertexProvider {
+            project: "my-project".to_string(),
+            region: "us-east5".to_string(),
+            tokens: TokenSource::metadata_server(),
+        }
+    }
+
+    #[test]
+    fn model_versions_use_at_sign() {
+        assert_eq!(
+            model_id("claude-sonnet-4-5-20250929"),
+            "claude-sonnet-4-5@20250929"
+        );
+        assert_eq!(
+            model_id("claude-opus-4-1@20250805"),
+            "claude-opus-4-1@20250805"
+        );
+        assert_eq!(model_id("claude-sonnet-4-5"), "claude-sonnet-4-5");
+    }
+
+    #[test]
+    fn urls_follow_model_and_region() {
+        let vertex = provider();
+        let base = "https://us-east5-aiplatform.googleapis.com/";
+        assert_eq!(
+            vertex
+                .url(base, "/v1/messages", "claude-sonnet-4-5-20250929", true)
+                .unwrap(),
+            "https://us-east5-aiplatform.googleapis.com/v1/projects/my-project/locations/us-east5\
+             /publishers/anthropic/models/claude-sonnet-4-5@20250929:streamRawPredict"
+        );
+        assert!(
+            vertex
+                .url(base, "/v1/messages", "claude-haiku-4-5", false)
+                .unwrap()
+                .ends_with("/models/claude-haiku-4-5:rawPredict")
+        );
+        assert!(
+            vertex
+                .url(base, "/v1/messages/count_tokens", "claude-haiku-4-5", false)
+                .unwrap()
+                .ends_with("/models/count-tokens:rawPredict")
+        );
+        assert!(vertex.url(base, "/v1/models", "m", false).is_none());
+    }
+
+    #[test]
+    fn body_swaps_model_for_version() {
+        let mut body = json!({"model": "claude-sonnet-4-5", "max_tokens": 10, "messages": []});
+        prepare_body(&mut body, "/v1/messages");
+        assert_eq!(
+            body,
+            json!({"max_tokens": 10, "messages": [], "anthropic_version": "vertex-2023-10-16"})
+        );
+
+        let mut body = json!({"model": "claude-sonnet-4-5-20250929", "messages": []});
+        prepare_body(&mut body, "/v1/messages/count_tokens");
+        assert_eq!(body["model"], "claude-sonnet-4-5@20250929");
+    }
+
+    #[test]
+    fn missing_project_is_an_error() {
+        let provider: ProviderConfig = serde_json::from_value(json!({
+            "url": "https://us-east5-aiplatform.googleapis.com",
+            "api_format": "vertex",
+            "region": "us-east5"
+        }))
+        .unwrap();
+        let err = VertexProvider::from_config("vertex", &provider)
+            .err()
+            .unwrap();
+        assert_eq!(err, "provider 'vertex': project is required for vertex");
+    }
+}
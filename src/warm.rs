@@ -0,0 +1,340 @@
+//! Warm-up pings for local model servers. Ollama and similar servers
+//! unload a model once it sits idle, and the next request waits for it to
+//! load again, often for 30 seconds or more. Pinging a provider on an
+//! interval keeps its model loaded, and `croxy warm` loads it on demand.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::config::{ApiFormat, Config};
+use crate::proxy::AppState;
+use crate::router::ResolvedRoute;
+
+/// How long a model stays loaded after a ping without an interval to go
+/// by: Ollama's own default `keep_alive`.
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(300);
+
+/// Loading a large model from disk can take minutes.
+const PING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A provider whose model is pinged on an interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub provider: String,
+    pub model: String,
+    pub interval: Duration,
+}
+
+/// The outcome of a provider's latest warm-up ping.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Warmth {
+    pub provider: String,
+    pub model: String,
+    pub checked: DateTime<Utc>,
+    /// When the model is expected to unload if nothing else uses it.
+    pub until: DateTime<Utc>,
+    /// Whether the model was loaded before the ping, when the provider
+    /// can say.
+    #[serde(default)]
+    pub was_loaded: Option<bool>,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Warmth {
+    pub fn is_warm(&self, now: DateTime<Utc>) -> bool {
+        self.error.is_none() && now < self.until
+    }
+}
+
+/// Providers with `warm_interval_secs`, and the model each keeps loaded.
+pub fn targets(config: &Config) -> Vec<Target> {
+    let mut targets: Vec<Target> = config
+        .providers
+        .iter()
+        .filter_map(|(name, provider)| {
+            Some(Target {
+                provider: name.clone(),
+                model: model_for(config, name)?,
+                interval: Duration::from_secs(provider.warm_interval_secs?),
+            })
+        })
+        .collect();
+    targets.sort_by(|a, b| a.provider.cmp(&b.provider));
+    targets
+}
+
+/// The model pings load on `provider`: its `warm_model`, or else the model
+/// its first route rewrites to.
+pub fn model_for(config: &Config, provider: &str) -> Option<String> {
+    config
+        .providers
+        .get(provider)?
+        .warm_model
+        .clone()
+        .or_else(|| {
+            config
+                .routes
+                .iter()
+                .filter(|route| route.provider == provider)
+                .find_map(|route| route.model.clone())
+        })
+}
+
+/// How long to ask the server to keep a model loaded: two intervals, so
+/// one late ping doesn't let it unload.
+pub fn keep_alive(interval: Option<Duration>) -> Duration {
+    interval.map_or(DEFAULT_KEEP_ALIVE, |interval| interval * 2)
+}
+
+/// Loads `model` on `route`'s provider and asks for it to stay loaded for
+/// `keep_alive`. Ollama is sent an empty generate request, which loads the
+/// model without running it; other providers a one-token message.
+pub async fn ping(
+    client: &reqwest::Client,
+    route: &ResolvedRoute,
+    model: &str,
+    keep_alive: Duration,
+) -> Warmth {
+    let checked = Utc::now();
+    let start = Instant::now();
+    let base = route.provider_url.trim_end_matches('/');
+    let result = match route.api_format {
+        ApiFormat::Ollama => ping_ollama(client, base, model, keep_alive).await,
+        _ => match route.api_key.load().await {
+            Ok(api_key) => ping_messages(client, base, api_key, model)
+                .await
+                .map(|()| None),
+            Err(e) => Err(e),
+        },
+    };
+    let (was_loaded, error) = match result {
+        Ok(was_loaded) => (was_loaded, None),
+        Err(e) => (None, Some(e)),
+    };
+    Warmth {
+        provider: route.provider_name.clone(),
+        model: model.to_string(),
+        checked,
+        until: checked + keep_alive,
+        was_loaded,
+        duration_ms: start.elapsed().as_millis() as u64,
+        error,
+    }
+}
+
+async fn ping_ollama(
+    client: &reqwest::Client,
+    base: &str,
+    model: &str,
+    keep_alive: Duration,
+) -> Result<Option<bool>, String> {
+    // Servers too old for /api/ps just can't say
+    let was_loaded = match client.get(format!("{base}/api/ps")).send().await {
+        Ok(response) if response.status().is_success() => response
+            .json::<Value>()
+            .await
+            .ok()
+            .map(|ps| is_loaded(&ps, model)),
+        _ => None,
+    };
+    let body = json!({
+        "model": model,
+        "keep_alive": format!("{}s", keep_alive.as_secs()),
+    });
+    send(client.post(format!("{base}/api/generate")).json(&body)).await?;
+    Ok(was_loaded)
+}
+
+async fn ping_messages(
+    client: &reqwest::Client,
+    base: &str,
+    api_key: Option<&str>,
+    model: &str,
+) -> Result<(), String> {
+    let body = json!({
+        "model": model,
+        "max_tokens": 1,
+        "messages": [{"role": "user", "content": "hi"}],
+    });
+    let mut request = client
+        .post(format!("{base}/v1/messages"))
+        .header("anthropic-version", "2023-06-01")
+        .json(&body);
+    if let Some(api_key) = api_key {
+        request = request.header("x-api-key", api_key);
+    }
+    send(request).await
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<(), String> {
+    let response = request
+        .timeout(PING_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| format!("provider unreachable: {e}"))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|v| {
+            v["error"]
+                .as_str()
+                .or(v["error"]["message"].as_str())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| status.to_string());
+    Err(message)
+}
+
+/// Whether Ollama's `/api/ps` lists `model`, which may omit the `:latest`
+/// tag the server reports.
+fn is_loaded(ps: &Value, model: &str) -> bool {
+    let tagged = format!("{model}:latest");
+    ps["models"].as_array().is_some_and(|models| {
+        models.iter().any(|loaded| {
+            [&loaded["name"], &loaded["model"]]
+                .iter()
+                .filter_map(|name| name.as_str())
+                .any(|name| name == model || name == tagged)
+        })
+    })
+}
+
+/// Pings every target on its interval, starting now, until dropped.
+pub async fn run(state: Arc<AppState>, targets: Vec<Target>) {
+    futures::future::join_all(
+        targets
+            .into_iter()
+            .map(|target| keep_warm(state.clone(), target)),
+    )
+    .await;
+}
+
+async fn keep_warm(state: Arc<AppState>, target: Target) {
+    let mut interval = tokio::time::interval(target.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Looked up each time, so keys follow config reloads
+        let Some(route) = state.router().provider_route(&target.provider) else {
+            continue;
+        };
+        let warmth = ping(
+            state.client_for(&target.provider),
+            &route,
+            &target.model,
+            keep_alive(Some(target.interval)),
+        )
+        .await;
+        match warmth.error {
+            Some(ref e) => tracing::warn!(
+                provider = %target.provider,
+                model = %target.model,
+                "warm-up ping failed: {e}"
+            ),
+            None => tracing::debug!(
+                provider = %target.provider,
+                model = %target.model,
+                duration_ms = warmth.duration_ms,
+                "warm-up ping"
+            ),
+        }
+        state.metrics.record_warmth(warmth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn config(toml: &str) -> Config {
+        Figment::new().merge(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[test]
+    fn targets_take_the_first_routed_model() {
+        let cfg = config(
+            r#"
+            [provider.anthropic]
+            url = "https://api.anthropic.com"
+            [provider.ollama]
+            url = "http://localhost:11434"
+            api_format = "ollama"
+            warm_interval_secs = 120
+            [provider.lmstudio]
+            url = "http://localhost:1234"
+            warm_interval_secs = 60
+            warm_model = "qwen3-8b"
+            [[routes]]
+            pattern = "opus"
+            provider = "anthropic"
+            [[routes]]
+            pattern = "haiku"
+            provider = "ollama"
+            model = "qwen3-coder:30b"
+            [[routes]]
+            pattern = "sonnet"
+            provider = "ollama"
+            model = "gpt-oss:20b"
+            "#,
+        );
+        assert_eq!(
+            targets(&cfg),
+            vec![
+                Target {
+                    provider: "lmstudio".to_string(),
+                    model: "qwen3-8b".to_string(),
+                    interval: Duration::from_secs(60),
+                },
+                Target {
+                    provider: "ollama".to_string(),
+                    model: "qwen3-coder:30b".to_string(),
+                    interval: Duration::from_secs(120),
+                },
+            ]
+        );
+        assert_eq!(model_for(&cfg, "anthropic"), None);
+        assert_eq!(
+            keep_alive(Some(Duration::from_secs(120))),
+            Duration::from_secs(240)
+        );
+    }
+
+    #[test]
+    fn loaded_models_match_with_or_without_a_tag() {
+        let ps = json!({"models": [{"name": "llama3.2:latest", "model": "llama3.2:latest"}]});
+        assert!(is_loaded(&ps, "llama3.2"));
+        assert!(is_loaded(&ps, "llama3.2:latest"));
+        assert!(!is_loaded(&ps, "llama3.2:1b"));
+        assert!(!is_loaded(&json!({}), "llama3.2"));
+    }
+
+    #[test]
+    fn warmth_lapses_after_keep_alive_or_an_error() {
+        let now = Utc::now();
+        let mut warmth = Warmth {
+            provider: "ollama".to_string(),
+            model: "llama3.2".to_string(),
+            checked: now,
+            until: now + chrono::Duration::seconds(60),
+            was_loaded: Some(false),
+            duration_ms: 31_000,
+            error: None,
+        };
+        assert!(warmth.is_warm(now));
+        assert!(!warmth.is_warm(now + chrono::Duration::seconds(61)));
+        warmth.error = Some("model not found".to_string());
+        assert!(!warmth.is_warm(now));
+    }
+}
@@ -1,10 +1,11 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
 
 use axum::Router as AxumRouter;
 use axum::body::Body;
 use axum::extract::Request;
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::routing::any;
 use figment::Figment;
 use figment::providers::{Format, Toml};
@@ -12,9 +13,13 @@ use http::HeaderValue;
 use tokio::net::TcpListener;
 
 use croxy::config::Config;
+use croxy::events::{EventFilter, Stage};
+use croxy::keys::{self, KeyStore};
+use croxy::listeners::{Ingress, Peer};
 use croxy::metrics::{MetricsStore, RoutingMethod};
+use croxy::model_check::{self, Checker};
 use croxy::proxy::{AppState, handle_request};
-use croxy::router::Router;
+use croxy::{CroxyError, Server};
 
 struct AbortOnDrop(tokio::task::JoinHandle<()>);
 
@@ -76,6 +81,45 @@ async fn echo_handler(request: Request) -> Response {
     response
 }
 
+/// Starts a mock Ollama that lists `pulled` and pulls models on request.
+async fn start_model_server(
+    pulled: &[&str],
+) -> (String, Arc<std::sync::Mutex<Vec<String>>>, AbortOnDrop) {
+    let models = Arc::new(std::sync::Mutex::new(
+        pulled.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+    ));
+    let tags = models.clone();
+    let pulls = models.clone();
+    let app = AxumRouter::new()
+        .route(
+            "/api/tags",
+            axum::routing::get(move || {
+                let names = tags.lock().unwrap().clone();
+                async move {
+                    let models: Vec<_> = names
+                        .iter()
+                        .map(|name| serde_json::json!({"name": name}))
+                        .collect();
+                    axum::Json(serde_json::json!({"models": models}))
+                }
+            }),
+        )
+        .route(
+            "/api/pull",
+            axum::routing::post(move |axum::Json(body): axum::Json<serde_json::Value>| {
+                let model = body["model"].as_str().unwrap_or_default().to_string();
+                pulls.lock().unwrap().push(model);
+                async { axum::Json(serde_json::json!({"status": "success"})) }
+            }),
+        );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, models, AbortOnDrop(handle))
+}
+
 /// Starts a mock provider that returns an error with the given status and body size.
 async fn start_error_provider(status: u16, body_size: usize) -> (String, AbortOnDrop) {
     let app = AxumRouter::new().fallback(any(move |_req: Request| async move {
@@ -99,27 +143,30 @@ async fn start_error_provider(status: u16, body_size: usize) -> (String, AbortOn
 
 /// Starts croxy with the given TOML config. Returns (proxy_url, state, abort_handle).
 async fn start_proxy(config_toml: &str) -> (String, Arc<AppState>, AbortOnDrop) {
+    start_proxy_with_keys(config_toml, KeyStore::disabled()).await
+}
+
+async fn start_proxy_with_keys(
+    config_toml: &str,
+    keys: KeyStore,
+) -> (String, Arc<AppState>, AbortOnDrop) {
     let config: Config = Figment::new()
         .merge(Toml::string(config_toml))
         .extract()
         .unwrap();
 
-    let router = Router::from_config(&config).unwrap();
-
-    let state = Arc::new(AppState {
-        router,
-        client: reqwest::Client::builder()
-            .no_proxy()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .unwrap(),
-        metrics: Arc::new(MetricsStore::new(Duration::from_secs(1800))),
-        max_body_size: config.server.max_body_size,
-    });
-
-    let app = AxumRouter::new()
-        .fallback(any(handle_request))
-        .with_state(state.clone());
+    let server = Server::builder(&config)
+        .metrics(Arc::new(MetricsStore::new(Duration::from_secs(1800))))
+        .keys(keys)
+        .reload(Box::new(|| {
+            Err(CroxyError::Config(
+                "reload is not supported in tests".to_string(),
+            ))
+        }))
+        .build()
+        .unwrap();
+    let state = server.state().clone();
+    let app = server.into_app();
 
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
@@ -275,6 +322,100 @@ async fn preserves_auth_headers_for_anthropic() {
     );
 }
 
+#[tokio::test]
+async fn provider_default_headers_sit_under_the_clients_own() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (proxy_url, _state, _h2) = start_proxy(&format!(
+        r#"
+        [provider.a]
+        url = "{provider_url}"
+        [provider.a.default_headers]
+        anthropic-version = "2023-06-01"
+        x-ollama-keepalive = "30m"
+        [default]
+        provider = "a"
+        "#
+    ))
+    .await;
+
+    let echo: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("anthropic-version", "2024-01-01")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let headers = &echo["echo_headers"];
+    assert_eq!(headers["anthropic-version"], "2024-01-01");
+    assert_eq!(headers["x-ollama-keepalive"], "30m");
+}
+
+#[tokio::test]
+async fn calls_a_provider_lacks_get_501_or_go_to_its_fallback() {
+    let (local_url, _h1) = start_echo_provider().await;
+    let (anthropic_url, _h2) = start_echo_provider().await;
+    let config = |fallback: &str| {
+        format!(
+            r#"
+            [provider.local]
+            url = "{local_url}"
+            {fallback}
+            [provider.local.capabilities]
+            batches = false
+            streaming = false
+            [provider.anthropic]
+            url = "{anthropic_url}"
+            [[routes]]
+            pattern = "opus"
+            provider = "anthropic"
+            [default]
+            provider = "local"
+            "#
+        )
+    };
+    let send = |proxy_url: String, path: &'static str, body: serde_json::Value| async move {
+        let response = client()
+            .post(format!("{proxy_url}{path}"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        (status, response.json::<serde_json::Value>().await.unwrap())
+    };
+    let batch = serde_json::json!({"requests": [
+        {"custom_id": "a", "params": {"model": "qwen3", "messages": []}}
+    ]});
+    let stream = serde_json::json!({"model": "qwen3", "messages": [], "stream": true});
+
+    let (proxy_url, _state, _h3) = start_proxy(&config("")).await;
+    let (status, body) = send(proxy_url.clone(), "/v1/messages/batches", batch.clone()).await;
+    assert_eq!(status, 501);
+    assert_eq!(
+        body["error"]["message"],
+        "provider 'local' does not support batches"
+    );
+    let (status, _) = send(proxy_url.clone(), "/v1/messages", stream.clone()).await;
+    assert_eq!(status, 501);
+    let (status, body) = send(
+        proxy_url,
+        "/v1/messages",
+        serde_json::json!({"model": "qwen3", "messages": []}),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["echo_path"], "/v1/messages");
+
+    let (proxy_url, state, _h4) = start_proxy(&config("capability_fallback = \"anthropic\"")).await;
+    let (status, body) = send(proxy_url, "/v1/messages", stream).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["echo_body"]["stream"], true);
+    assert_eq!(state.metrics.snapshot()[0].provider, "anthropic");
+}
+
 #[tokio::test]
 async fn stubs_count_tokens_for_ollama_route() {
     let f = DualProviderFixture::new().await;
@@ -348,6 +489,64 @@ async fn records_metrics_for_proxied_request() {
     assert!(snap[0].error_body.is_none());
 }
 
+#[tokio::test]
+async fn requests_announce_each_stage_of_their_life() {
+    let f = DualProviderFixture::new().await;
+    let mut events = f.state.metrics.lifecycle();
+
+    client()
+        .post(format!("{}/v1/messages", f.proxy_url))
+        .json(&serde_json::json!({"model": "claude-sonnet-4-5", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let mut stages = Vec::new();
+    while let Ok(event) = tokio::time::timeout(Duration::from_secs(1), events.recv()).await {
+        let event = event.unwrap();
+        stages.push(event.stage);
+        match event.stage {
+            Stage::Received => assert_eq!(event.path.as_deref(), Some("/v1/messages")),
+            Stage::Routed => {
+                assert_eq!(event.provider.as_deref(), Some("ollama"));
+                assert_eq!(event.model.as_deref(), Some("claude-sonnet-4-5"));
+            }
+            Stage::FirstByte => assert_eq!(event.status, Some(200)),
+            Stage::Completed => {
+                assert_eq!(event.status, Some(200));
+                break;
+            }
+        }
+    }
+    assert_eq!(
+        stages,
+        [
+            Stage::Received,
+            Stage::Routed,
+            Stage::FirstByte,
+            Stage::Completed
+        ]
+    );
+
+    // Failures that never reach a provider complete all the same
+    let (proxy_url, state, _h) = start_proxy(&single_provider_config("http://127.0.0.1:1")).await;
+    let mut events = state.metrics.lifecycle();
+    client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "anything", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    let completed = loop {
+        let event = events.recv().await.unwrap();
+        if event.stage == Stage::Completed {
+            break event;
+        }
+    };
+    assert_eq!(completed.status, Some(502));
+    assert!(EventFilter::Errors.matches(&completed));
+}
+
 #[tokio::test]
 async fn returns_502_when_provider_unreachable() {
     let (proxy_url, _state, _h) = start_proxy(&single_provider_config("http://127.0.0.1:1")).await;
@@ -363,6 +562,196 @@ async fn returns_502_when_provider_unreachable() {
     assert_eq!(resp.status(), 502);
 }
 
+/// An OAuth token endpoint handing out `token-1`, `token-2`, and so on,
+/// or refusing every request when `deny` is set.
+async fn start_token_server(deny: bool) -> (String, Arc<AtomicU64>, AbortOnDrop) {
+    let calls = Arc::new(AtomicU64::new(0));
+    let counter = calls.clone();
+    let app = AxumRouter::new().fallback(any(move |body: String| {
+        let counter = counter.clone();
+        async move {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            assert!(body.contains("grant_type=client_credentials"), "{body}");
+            if deny {
+                return (http::StatusCode::UNAUTHORIZED, "invalid_client").into_response();
+            }
+            axum::Json(
+                serde_json::json!({"access_token": format!("token-{n}"), "expires_in": 3600}),
+            )
+            .into_response()
+        }
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, calls, AbortOnDrop(handle))
+}
+
+#[tokio::test]
+async fn oauth_providers_get_a_bearer_token_in_place_of_the_clients_key() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (token_url, calls, _h2) = start_token_server(false).await;
+    let config = format!(
+        r#"
+        [provider.gateway]
+        url = "{provider_url}"
+        [provider.gateway.oauth]
+        token_url = "{token_url}/token"
+        client_id = "croxy"
+        client_secret = "s3cret"
+        [default]
+        provider = "gateway"
+        "#
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    for _ in 0..2 {
+        let echo: serde_json::Value = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .header("x-api-key", "sk-ant-client")
+            .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(echo["echo_headers"]["authorization"], "Bearer token-1");
+        assert!(echo["echo_headers"].get("x-api-key").is_none());
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    let auth = state.metrics.auth();
+    assert_eq!(auth[0].provider, "gateway");
+    assert_eq!(auth[0].error, None);
+}
+
+#[tokio::test]
+async fn failed_token_fetches_fail_the_request_and_show_in_health() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (token_url, _calls, _h2) = start_token_server(true).await;
+    let config = format!(
+        r#"
+        [provider.gateway]
+        url = "{provider_url}"
+        [provider.gateway.oauth]
+        token_url = "{token_url}/token"
+        client_id = "croxy"
+        client_secret = "wrong"
+        [default]
+        provider = "gateway"
+        "#
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 502);
+    let error = state.metrics.auth()[0].error.clone().unwrap();
+    assert!(error.contains("HTTP 401"), "{error}");
+}
+
+#[tokio::test]
+async fn requests_past_the_timeout_fail_with_504() {
+    let app = AxumRouter::new().fallback(any(|| async {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        "too late"
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _provider = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let (proxy_url, state, _h) = start_proxy(&single_provider_config_with(
+        &provider_url,
+        "request_timeout_ms = 100",
+    ))
+    .await;
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 504);
+    assert!(resp.text().await.unwrap().contains("request_timeout_ms"));
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].status, 504);
+    assert_eq!(snap[0].provider, "a");
+    assert_eq!(snap[0].cutoff.as_deref(), Some("request_timeout_ms"));
+}
+
+#[tokio::test]
+async fn identical_requests_in_flight_are_deduplicated() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let counter = calls.clone();
+    let app = AxumRouter::new().fallback(any(move || {
+        let counter = counter.clone();
+        async move {
+            let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            format!("call {call}")
+        }
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _provider = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+
+    for mode in ["attach", "reject"] {
+        calls.store(0, Ordering::SeqCst);
+        let (proxy_url, state, _h) = start_proxy(&format!(
+            r#"
+            [server]
+            [provider.a]
+            url = "{provider_url}"
+            [[routes]]
+            pattern = ".*"
+            provider = "a"
+            dedup = "{mode}"
+            [default]
+            provider = "a"
+            "#
+        ))
+        .await;
+        let send = || async {
+            let resp = client()
+                .post(format!("{proxy_url}/v1/messages"))
+                .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+                .send()
+                .await
+                .unwrap();
+            (resp.status().as_u16(), resp.text().await.unwrap())
+        };
+
+        let (first, second) = tokio::join!(send(), send());
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "{mode}");
+        assert_eq!(state.metrics.requests_deduplicated(), 1);
+        let mut statuses = [first.0, second.0];
+        statuses.sort();
+        if mode == "attach" {
+            assert_eq!(statuses, [200, 200]);
+            assert_eq!(first.1, "call 1");
+            assert_eq!(second.1, "call 1");
+        } else {
+            assert_eq!(statuses, [200, 409]);
+        }
+
+        // Once the first has finished, the same request is sent again
+        assert_eq!(send().await.1, "call 2");
+    }
+}
+
 #[tokio::test]
 async fn returns_400_for_invalid_json_body() {
     let (provider_url, _h1) = start_echo_provider().await;
@@ -379,6 +768,44 @@ async fn returns_400_for_invalid_json_body() {
     assert_eq!(resp.status(), 400);
 }
 
+#[tokio::test]
+async fn malformed_messages_are_rejected_when_requests_are_validated() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let config = single_provider_config_with(&provider_url, "validate_requests = true");
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "test",
+            "max_tokens": 16,
+            "messages": [{"role": "human", "content": "hi"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(
+        body["error"]["message"],
+        "messages.0.role: expected \"user\" or \"assistant\", got \"human\""
+    );
+    assert!(state.metrics.snapshot().is_empty());
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "test",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "hi"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+}
+
 #[tokio::test]
 async fn rejects_oversized_request_body() {
     let (provider_url, _h1) = start_echo_provider().await;
@@ -404,6 +831,85 @@ async fn rejects_oversized_request_body() {
     assert_eq!(resp.status(), 400);
 }
 
+#[tokio::test]
+async fn status_reports_how_close_bodies_come_to_the_limit() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (proxy_url, _state, _h2) = start_proxy(&single_provider_config_with(
+        &provider_url,
+        "max_body_size = 1024",
+    ))
+    .await;
+    let send = |size: usize| {
+        client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": "test",
+                "messages": [{"role": "user", "content": "x".repeat(size)}]
+            }))
+            .send()
+    };
+    assert_eq!(send(10).await.unwrap().status(), 200);
+    assert_eq!(send(900).await.unwrap().status(), 200);
+    assert_eq!(send(2000).await.unwrap().status(), 400);
+
+    let host = proxy_url.trim_start_matches("http://");
+    let status = croxy::control::remote_status(host, None, None)
+        .await
+        .unwrap();
+    let sizes = status.body_sizes.unwrap();
+    assert_eq!(sizes.max_body_size, 1024);
+    assert_eq!(sizes.requests.count, 3);
+    assert_eq!(sizes.requests.buckets, [1, 0, 0, 0, 1, 1]);
+    // The rejected request was turned away before it was routed
+    assert_eq!(sizes.routes.len(), 1);
+    assert_eq!(sizes.routes[0].route, "a");
+    assert_eq!(sizes.routes[0].requests.count, 2);
+    // Too few requests yet to suggest a larger limit
+    assert_eq!(sizes.hint, None);
+}
+
+#[tokio::test]
+async fn truncates_oversized_tool_results_instead_of_rejecting() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (proxy_url, state, _h2) = start_proxy(&single_provider_config_with(
+        &provider_url,
+        "max_body_size = 4096\n[tool_results]\nmax_size = 1000",
+    ))
+    .await;
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "test",
+            "messages": [{"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "t1", "content": "x".repeat(20_000)}
+            ]}]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let echoed: serde_json::Value = resp.json().await.unwrap();
+    let content = echoed["echo_body"]["messages"][0]["content"][0]["content"]
+        .as_str()
+        .unwrap();
+    assert!(content.len() < 1100);
+    assert!(content.contains("croxy truncated 19000 bytes"));
+    assert_eq!(state.metrics.tool_results_truncated(), 1);
+
+    // Bodies still too large once tool results are cut are rejected
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "test",
+            "messages": [{"role": "user", "content": "x".repeat(20_000)}]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+}
+
 #[tokio::test]
 async fn accepts_body_within_configured_limit() {
     let (provider_url, _h1) = start_echo_provider().await;
@@ -458,133 +964,2409 @@ async fn caps_error_response_body() {
 }
 
 #[tokio::test]
-async fn records_error_metrics_for_provider_errors() {
-    let (error_url, _h1) = start_error_provider(429, 32).await;
-    let (proxy_url, state, _h2) = start_proxy(&single_provider_config(&error_url)).await;
+async fn secrets_are_scrubbed_from_error_records_and_captures() {
+    // A provider that echoes the key it was sent back in its error
+    let app = AxumRouter::new().fallback(any(|request: Request| async move {
+        let key = request.headers()["x-api-key"].to_str().unwrap().to_string();
+        let mut response = Response::new(Body::from(format!(
+            r#"{{"type":"error","error":{{"type":"authentication_error","message":"invalid key {key}"}}}}"#
+        )));
+        *response.status_mut() = http::StatusCode::UNAUTHORIZED;
+        response
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let dir = tempfile::tempdir().unwrap();
+    let config = single_provider_config(&provider_url).replace(
+        "[[routes]]",
+        &format!(
+            "api_key = \"provider-secret-0123456789\"\n        [capture]\n        enabled = true\n        dir = \"{}\"\n        [logging]\n        redact_patterns = [\"ticket-[0-9]+\"]\n        [[routes]]",
+            dir.path().display()
+        ),
+    );
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+    state.scrubber.set_secrets(state.router().api_keys());
 
-    let resp = client()
+    let response = client()
         .post(format!("{proxy_url}/v1/messages"))
-        .header("content-type", "application/json")
-        .json(&serde_json::json!({"model": "test-model", "messages": []}))
+        .json(&serde_json::json!({
+            "model": "claude-opus-4-6",
+            "messages": [{"role": "user", "content": "see ticket-42, key provider-secret-0123456789"}]
+        }))
         .send()
         .await
         .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let error = state.metrics.snapshot()[0].error_body.clone().unwrap();
+    assert!(error.starts_with("HTTP 401 Unauthorized: "), "{error}");
+    assert!(error.contains("invalid key [REDACTED]"), "{error}");
+    let mut captures = Vec::new();
+    for _ in 0..50 {
+        captures = croxy::capture::recent(dir.path(), 10);
+        if !captures.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let capture = captures.pop().expect("request was captured");
+    assert_eq!(
+        capture.body.unwrap()["messages"][0]["content"],
+        "see [REDACTED], key [REDACTED]"
+    );
+}
 
-    assert_eq!(resp.status(), 429);
+#[tokio::test]
+async fn provider_key_files_are_read_when_first_needed() {
+    let (echo_url, _h1) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let key_path = dir.path().join("provider.key");
+    let config = single_provider_config(&echo_url).replace(
+        "[[routes]]",
+        &format!(
+            "api_key_file = \"{}\"\n        [[routes]]",
+            key_path.display()
+        ),
+    );
+    // The file doesn't exist yet, which only matters once a request needs it
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+    let send = || {
+        client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+            .send()
+    };
+    assert_eq!(send().await.unwrap().status(), 502);
 
-    let snap = state.metrics.snapshot();
-    assert_eq!(snap.len(), 1);
-    assert_eq!(snap[0].status, 429);
-    assert_eq!(snap[0].model, "test-model");
-    assert!(snap[0].error_body.is_some());
+    std::fs::write(&key_path, "provider-secret-from-file\n").unwrap();
+    let echoed: serde_json::Value = send().await.unwrap().json().await.unwrap();
+    assert_eq!(
+        echoed["echo_headers"]["x-api-key"],
+        "provider-secret-from-file"
+    );
+    assert_eq!(
+        state.scrubber.scrub("key provider-secret-from-file"),
+        "key [REDACTED]"
+    );
 }
 
 #[tokio::test]
-async fn get_request_without_body_routes_to_default() {
-    let (provider_url, _h1) = start_echo_provider().await;
-    let (proxy_url, _state, _h2) = start_proxy(&single_provider_config(&provider_url)).await;
-
-    let resp: serde_json::Value = client()
-        .get(format!("{proxy_url}/v1/models"))
-        .send()
-        .await
-        .unwrap()
-        .json()
+async fn archived_routes_save_redacted_requests_and_final_text() {
+    let app = AxumRouter::new().fallback(any(|| async {
+        axum::Json(serde_json::json!({
+            "type": "message",
+            "role": "assistant",
+            "content": [{"type": "text", "text": "Deployed to build.corp.internal"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 12, "output_tokens": 5}
+        }))
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("outputs.jsonl");
+    let (proxy_url, _state, _h2) = start_proxy(&format!(
+        r#"
+        [provider.a]
+        url = "{provider_url}"
+        [archive]
+        enabled = true
+        path = "{}"
+        [logging]
+        redact_patterns = ["build\\.corp\\.internal"]
+        [[middleware]]
+        type = "redact"
+        words = ["Project Falcon"]
+        [[routes]]
+        pattern = "opus"
+        provider = "a"
+        archive = true
+        [default]
+        provider = "a"
+        "#,
+        archive.display()
+    ))
+    .await;
+
+    for model in ["claude-opus-4-6", "claude-haiku-4-5"] {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": model,
+                "messages": [{"role": "user", "content": "Ship Project Falcon"}]
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.text().await.unwrap();
+    }
+
+    let mut lines = Vec::new();
+    for _ in 0..50 {
+        lines = std::fs::read_to_string(&archive)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        if !lines.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Only the archived route's response is saved
+    assert_eq!(lines.len(), 1);
+    let entry: croxy::archive::Entry = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(entry.model, "claude-opus-4-6");
+    assert_eq!(entry.text, "Deployed to [REDACTED]");
+    assert_eq!(entry.stop_reason.as_deref(), Some("end_turn"));
+    assert_eq!(entry.output_tokens, 5);
+    let prompt = &entry.request.unwrap()["messages"][0]["content"];
+    assert!(!prompt.as_str().unwrap().contains("Falcon"), "{prompt}");
+}
+
+#[tokio::test]
+async fn chaos_faults_are_injected_and_flagged() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let config = format!(
+        r#"
+        [provider.a]
+        url = "{provider_url}"
+        [[routes]]
+        name = "opus"
+        pattern = "opus"
+        provider = "a"
+        [default]
+        provider = "a"
+        [chaos]
+        enabled = true
+        [[chaos.rules]]
+        routes = ["opus"]
+        error_probability = 1.0
+        error_statuses = [429]
+        [[chaos.rules]]
+        truncate_probability = 1.0
+        "#
+    );
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["retry-after"], "1");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "rate_limit_error");
+
+    // The echoed body is well past where truncated responses are dropped
+    let text = "x".repeat(16 * 1024);
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "haiku", "messages": [{"content": text}]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.bytes().await.is_err());
+
+    let records = state.metrics.snapshot();
+    let flags: Vec<_> = records.iter().map(|r| r.chaos.as_deref()).collect();
+    assert_eq!(flags, [Some("error 429"), Some("truncated")]);
+    assert!(MetricsStore::without_synthetic(&records).is_empty());
+}
+
+#[tokio::test]
+async fn nearly_exhausted_rate_limits_reroute_to_the_fallback() {
+    let app = AxumRouter::new().fallback(any(|| async {
+        let mut response = Response::new(Body::from(r#"{"type":"message","content":[]}"#));
+        let headers = response.headers_mut();
+        headers.insert("anthropic-ratelimit-requests-limit", "100".parse().unwrap());
+        headers.insert(
+            "anthropic-ratelimit-requests-remaining",
+            "2".parse().unwrap(),
+        );
+        headers.insert(
+            "anthropic-ratelimit-requests-reset",
+            "2099-01-01T00:00:00Z".parse().unwrap(),
+        );
+        response
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let limited_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let (fallback_url, _h2) = start_echo_provider().await;
+    let config = format!(
+        r#"
+        [provider.limited]
+        url = "{limited_url}"
+        ratelimit_reserve = 0.05
+        ratelimit_fallback = "spare"
+        [provider.spare]
+        url = "{fallback_url}"
+        [[routes]]
+        pattern = "haiku"
+        provider = "spare"
+        [default]
+        provider = "limited"
+        "#
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    for _ in 0..2 {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.bytes().await.unwrap();
+    }
+
+    let limit = state.metrics.rate_limit("limited").unwrap();
+    let requests = limit.requests.unwrap();
+    assert_eq!((requests.limit, requests.remaining), (Some(100), 2));
+    let providers: Vec<_> = state
+        .metrics
+        .snapshot()
+        .into_iter()
+        .map(|r| r.provider)
+        .collect();
+    assert_eq!(providers, ["limited", "spare"]);
+}
+
+/// A provider that answers every other request with a 429, and the rest
+/// with an event stream.
+async fn start_flaky_provider() -> (String, Arc<AtomicU64>, AbortOnDrop) {
+    let calls = Arc::new(AtomicU64::new(0));
+    let counter = calls.clone();
+    let app = AxumRouter::new().fallback(any(move || {
+        let counter = counter.clone();
+        async move {
+            if counter.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+                let mut response = Response::new(Body::from(
+                    r#"{"type":"error","error":{"type":"rate_limit_error","message":"slow down"}}"#,
+                ));
+                *response.status_mut() = http::StatusCode::TOO_MANY_REQUESTS;
+                response
+                    .headers_mut()
+                    .insert("retry-after", "0".parse().unwrap());
+                return response;
+            }
+            let mut response = Response::new(Body::from("event: message_stop\ndata: {}\n\n"));
+            response
+                .headers_mut()
+                .insert("content-type", "text/event-stream".parse().unwrap());
+            response
+        }
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, calls, AbortOnDrop(handle))
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried_transparently() {
+    let (provider_url, calls, _h1) = start_flaky_provider().await;
+    let config = single_provider_config(&provider_url).replace(
+        "[[routes]]",
+        "retry_429_max_wait_secs = 5\n        [[routes]]",
+    );
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+
+    for stream in [false, true] {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(
+                &serde_json::json!({"model": "claude-opus-4-6", "messages": [], "stream": stream}),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let body = response.text().await.unwrap();
+        assert!(body.contains("event: message_stop"), "{body}");
+    }
+
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+    let statuses: Vec<_> = state
+        .metrics
+        .snapshot()
+        .into_iter()
+        .map(|r| r.status)
+        .collect();
+    assert_eq!(statuses, [200, 200]);
+}
+
+#[tokio::test]
+async fn streams_wait_for_slow_clients_once_their_buffer_is_full() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (_proxy_url, state, _h2) = start_proxy(&single_provider_config_with(
+        &provider_url,
+        "stream_buffer_size = 1024",
+    ))
+    .await;
+
+    let text = "x".repeat(64 * 1024);
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/messages")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            serde_json::json!({"model": "test", "messages": [{"content": text}]}).to_string(),
+        ))
+        .unwrap();
+    let response = handle_request(axum::extract::State(state.clone()), request)
+        .await
+        .unwrap();
+    // Nothing reads the body for a while, so the buffer fills
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let echoed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(echoed["echo_body"]["messages"][0]["content"], text);
+
+    let (stalled, waited) = state.metrics.stalled_streams();
+    assert_eq!(stalled, 1);
+    assert!(waited >= Duration::from_millis(50), "{waited:?}");
+}
+
+#[tokio::test]
+async fn requests_are_tagged_with_their_listener() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (_proxy_url, state, _h2) = start_proxy(&single_provider_config(&provider_url)).await;
+    let send = |ingress: Ingress| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/v1/messages")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"model": "test", "messages": []}"#))
+            .unwrap();
+        request.extensions_mut().insert(ingress);
+        handle_request(axum::extract::State(state.clone()), request)
+    };
+
+    let response = send(Ingress {
+        tag: Some("lan".to_string()),
+        require_key: true,
+    })
+    .await
+    .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let response = send(Ingress {
+        tag: Some("local".to_string()),
+        require_key: false,
+    })
+    .await
+    .unwrap();
+    assert_eq!(response.status(), 200);
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].listener.as_deref(), Some("local"));
+}
+
+#[tokio::test]
+async fn requests_are_attributed_to_their_client() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let config = single_provider_config_with(&provider_url, "client_header = \"x-client-name\"");
+    let (_proxy_url, state, _h2) = start_proxy(&config).await;
+    let send = |name: Option<&str>| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/v1/messages")
+            .header("content-type", "application/json");
+        if let Some(name) = name {
+            request = request.header("x-client-name", name);
+        }
+        let mut request = request
+            .body(Body::from(r#"{"model": "test", "messages": []}"#))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(Peer::new(Some(
+                [10, 0, 0, 7].into(),
+            ))));
+        handle_request(axum::extract::State(state.clone()), request)
+    };
+
+    for name in [Some("ci-bot"), None] {
+        let response = send(name).await.unwrap();
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+    }
+    let snap = state.metrics.snapshot();
+    let clients: Vec<_> = snap.iter().map(|r| r.client.as_deref()).collect();
+    assert_eq!(clients, vec![Some("ci-bot"), Some("10.0.0.7")]);
+}
+
+#[tokio::test]
+async fn bench_requests_are_tagged_and_told_their_provider() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (_proxy_url, state, _h2) = start_proxy(&single_provider_config(&provider_url)).await;
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/messages")
+        .header("content-type", "application/json")
+        .header(croxy::bench::HEADER, "bench-1")
+        .body(Body::from(r#"{"model": "test", "messages": []}"#))
+        .unwrap();
+    let response = handle_request(axum::extract::State(state.clone()), request)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()[croxy::bench::PROVIDER_HEADER], "a");
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let echo: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert!(echo["echo_headers"][croxy::bench::HEADER].is_null());
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap[0].bench.as_deref(), Some("bench-1"));
+    assert!(MetricsStore::without_synthetic(&snap).is_empty());
+}
+
+#[tokio::test]
+async fn requests_near_their_models_context_window_are_flagged() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let config = format!(
+        r#"
+        {}
+        [models.small]
+        context_window = 100
+        "#,
+        single_provider_config(&provider_url)
+    );
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+
+    for (model, max_tokens) in [("small", 10), ("small", 90), ("large", 90)] {
+        client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": model,
+                "max_tokens": max_tokens,
+                "messages": [{"role": "user", "content": "hi"}],
+            }))
+            .send()
+            .await
+            .unwrap();
+    }
+    let near: Vec<_> = state
+        .metrics
+        .snapshot()
+        .iter()
+        .map(|r| (r.model.clone(), r.near_limit))
+        .collect();
+    assert_eq!(
+        near,
+        [
+            ("small".to_string(), false),
+            ("small".to_string(), true),
+            ("large".to_string(), false),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn records_break_down_where_the_time_went() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (proxy_url, state, _h2) = start_proxy(&single_provider_config(&provider_url)).await;
+
+    for _ in 0..2 {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": "claude-sonnet-4-5",
+                "messages": [{"role": "user", "content": "hi"}],
+            }))
+            .send()
+            .await
+            .unwrap();
+        response.bytes().await.unwrap();
+    }
+    // Streams are finalized once the client has the last byte
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let snap = state.metrics.snapshot();
+    let [first, second] = snap.as_slice() else {
+        panic!("expected two records, got {}", snap.len());
+    };
+    for record in [first, second] {
+        let timings = record.timings;
+        assert!(timings.body_read_ms.is_some());
+        assert!(timings.routing_ms.is_some());
+        assert!(timings.ttfb_ms.is_some());
+        assert!(timings.stream_ms.is_some());
+        assert_eq!(timings.classifier_ms, None);
+    }
+    // The second request reuses the first one's connection
+    assert!(first.timings.connect_ms.is_some());
+    assert_eq!(second.timings.connect_ms, None);
+}
+
+/// Starts a mock provider whose tool calls have a trailing comma in their
+/// input, streamed or not as the request asks.
+async fn start_sloppy_tool_provider() -> (String, AbortOnDrop) {
+    let app = AxumRouter::new().fallback(any(|request: Request| async move {
+        let body = axum::body::to_bytes(request.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        if body["stream"] == true {
+            let events = [
+                r#"{"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"t1","name":"read","input":{}}}"#,
+                r#"{"type":"content_block_delta","index":0,"delta":{"type":"input_json_delta","partial_json":"{\"path\": \"a.rs\","}}"#,
+                r#"{"type":"content_block_delta","index":0,"delta":{"type":"input_json_delta","partial_json":"}"}}"#,
+                r#"{"type":"content_block_stop","index":0}"#,
+            ];
+            let sse: String = events
+                .iter()
+                .map(|data| {
+                    let name = serde_json::from_str::<serde_json::Value>(data).unwrap()["type"]
+                        .as_str()
+                        .unwrap()
+                        .to_string();
+                    format!("event: {name}\ndata: {data}\n\n")
+                })
+                .collect();
+            return ([(http::header::CONTENT_TYPE, "text/event-stream")], sse).into_response();
+        }
+        axum::Json(serde_json::json!({
+            "type": "message",
+            "content": [{"type": "tool_use", "id": "t1", "name": "read", "input": "{\"path\": \"a.rs\",}"}],
+        }))
+        .into_response()
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, AbortOnDrop(handle))
+}
+
+#[tokio::test]
+async fn malformed_tool_calls_are_repaired_on_routes_that_ask() {
+    let (provider_url, _h1) = start_sloppy_tool_provider().await;
+    let config = format!(
+        r#"
+        [provider.local]
+        url = "{provider_url}"
+        [[routes]]
+        pattern = "qwen"
+        provider = "local"
+        repair_tool_calls = true
+        [default]
+        provider = "local"
+        "#
+    );
+    let (proxy_url, state, _h2) = start_proxy(&config).await;
+    let send = |model: &str, stream: bool| {
+        client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({"model": model, "stream": stream, "messages": []}))
+            .send()
+    };
+
+    let message: serde_json::Value = send("qwen3", false).await.unwrap().json().await.unwrap();
+    assert_eq!(
+        message["content"][0]["input"],
+        serde_json::json!({"path": "a.rs"})
+    );
+
+    let events = send("qwen3", true).await.unwrap().text().await.unwrap();
+    assert!(events.contains(r#""partial_json":"{\"path\":\"a.rs\"}""#));
+    assert!(!events.contains("a.rs\\\","));
+
+    let message: serde_json::Value = send("llama3", false).await.unwrap().json().await.unwrap();
+    assert_eq!(message["content"][0]["input"], r#"{"path": "a.rs",}"#);
+    assert_eq!(state.metrics.tool_calls_repaired(), 2);
+}
+
+#[tokio::test]
+async fn records_error_metrics_for_provider_errors() {
+    let (error_url, _h1) = start_error_provider(429, 32).await;
+    let (proxy_url, state, _h2) = start_proxy(&single_provider_config(&error_url)).await;
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({"model": "test-model", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 429);
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].status, 429);
+    assert_eq!(snap[0].model, "test-model");
+    assert!(snap[0].error_body.is_some());
+}
+
+#[tokio::test]
+async fn cache_control_is_injected_or_stripped_per_provider() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (local_url, _h2) = start_echo_provider().await;
+    let config = format!(
+        r#"
+        [server]
+        [provider.anthropic]
+        url = "{anthropic_url}"
+        cache_control = "inject"
+        [provider.local]
+        url = "{local_url}"
+        cache_control = "strip"
+        [[routes]]
+        pattern = "haiku"
+        provider = "local"
+        [default]
+        provider = "anthropic"
+        "#
+    );
+    let (proxy_url, _state, _h3) = start_proxy(&config).await;
+    let send = |model: &str| {
+        client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": model,
+                "system": "x".repeat(8192),
+                "messages": [{"role": "user", "content": [
+                    {"type": "text", "text": "hi", "cache_control": {"type": "ephemeral"}}
+                ]}]
+            }))
+            .send()
+    };
+
+    // The client's own breakpoint is kept, so nothing is injected
+    let resp: serde_json::Value = send("claude-opus-4-1").await.unwrap().json().await.unwrap();
+    assert!(resp["echo_body"]["system"].is_string());
+    assert_eq!(
+        resp["echo_body"]["messages"][0]["content"][0]["cache_control"]["type"],
+        "ephemeral"
+    );
+
+    let resp: serde_json::Value = send("claude-haiku-4-5")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(
+        resp["echo_body"]["messages"][0]["content"][0]
+            .get("cache_control")
+            .is_none()
+    );
+
+    let resp: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "claude-opus-4-1",
+            "system": "x".repeat(8192),
+            "messages": [{"role": "user", "content": "hi"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp["echo_body"]["system"][0]["cache_control"]["type"],
+        "ephemeral"
+    );
+}
+
+#[tokio::test]
+async fn get_request_without_body_routes_to_default() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (proxy_url, _state, _h2) = start_proxy(&single_provider_config(&provider_url)).await;
+
+    let resp: serde_json::Value = client()
+        .get(format!("{proxy_url}/v1/models"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(resp["echo_method"].as_str().unwrap(), "GET");
+    assert!(resp["echo_path"].as_str().unwrap().contains("/v1/models"));
+}
+
+#[tokio::test]
+async fn model_lookups_use_their_own_default() {
+    let (catalog_url, _h1) = start_echo_provider().await;
+    let (local_url, _h2) = start_echo_provider().await;
+    let (proxy_url, state, _h3) = start_proxy(&format!(
+        r#"
+        [server]
+        [provider.catalog]
+        url = "{catalog_url}"
+        [provider.local]
+        url = "{local_url}"
+        [default]
+        provider = "local"
+        [default.models]
+        provider = "catalog"
+        "#
+    ))
+    .await;
+
+    client()
+        .get(format!("{proxy_url}/v1/models"))
+        .send()
+        .await
+        .unwrap();
+    client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "sonnet", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let providers: Vec<_> = state
+        .metrics
+        .snapshot()
+        .iter()
+        .map(|r| r.provider.clone())
+        .collect();
+    assert_eq!(providers, vec!["catalog", "local"]);
+}
+
+/// Starts a mock provider that creates batches and names itself in replies.
+async fn start_batch_provider(name: &'static str) -> (String, AbortOnDrop) {
+    let app = AxumRouter::new().fallback(any(move |request: Request| async move {
+        let path = request.uri().path().to_string();
+        let body = axum::body::to_bytes(request.into_body(), 1 << 20)
+            .await
+            .unwrap();
+        let body: Option<serde_json::Value> = serde_json::from_slice(&body).ok();
+        axum::Json(serde_json::json!({
+            "id": "msgbatch_1",
+            "provider": name,
+            "path": path,
+            "body": body
+        }))
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, AbortOnDrop(handle))
+}
+
+#[tokio::test]
+async fn batches_are_routed_per_request_and_followed_up_on_their_provider() {
+    let (anthropic_url, _h1) = start_batch_provider("anthropic").await;
+    let (ollama_url, _h2) = start_batch_provider("ollama").await;
+    let (proxy_url, state, _h3) = start_proxy(&make_config(&anthropic_url, &ollama_url)).await;
+    let batch = |models: &[&str]| {
+        let requests: Vec<serde_json::Value> = models
+            .iter()
+            .enumerate()
+            .map(|(i, model)| {
+                serde_json::json!({
+                    "custom_id": format!("req-{i}"),
+                    "params": {"model": model, "max_tokens": 10, "messages": []}
+                })
+            })
+            .collect();
+        serde_json::json!({"requests": requests})
+    };
+
+    let created: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages/batches"))
+        .json(&batch(&["claude-haiku-4-5", "claude-sonnet-4-5"]))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(created["provider"], "ollama");
+    assert_eq!(
+        created["body"]["requests"][1]["params"]["model"],
+        "qwen3-coder:30b"
+    );
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap[0].batch_size, Some(2));
+    assert_eq!(snap[0].model, "claude-haiku-4-5,claude-sonnet-4-5");
+
+    // The default provider is anthropic, but the batch lives on ollama
+    let results: serde_json::Value = client()
+        .get(format!(
+            "{proxy_url}/v1/messages/batches/msgbatch_1/results"
+        ))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(results["provider"], "ollama");
+    assert_eq!(results["path"], "/v1/messages/batches/msgbatch_1/results");
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages/batches"))
+        .json(&batch(&["claude-opus-4-1", "claude-haiku-4-5"]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(error["error"]["type"], "invalid_request_error");
+    assert!(
+        error["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("batch mixes providers")
+    );
+}
+
+#[tokio::test]
+async fn virtual_keys_swap_in_provider_credentials_and_restrict_routes() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let keys_path = dir.path().join("keys.json");
+    let key = keys::create(
+        &keys_path,
+        keys::NewKey {
+            name: "ci".to_string(),
+            budget_usd: None,
+            requests_per_minute: None,
+            routes: vec!["anthropic".to_string()],
+        },
+    )
+    .unwrap();
+    let config = make_config(&anthropic_url, &ollama_url).replace(
+        "[provider.ollama]",
+        "api_key = \"sk-ant-real\"\n        [provider.ollama]",
+    );
+    let (proxy_url, _state, _h3) =
+        start_proxy_with_keys(&config, KeyStore::open(keys_path, true)).await;
+    let send = |api_key: Option<&str>, model: &str| {
+        let mut request = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({"model": model, "messages": []}));
+        if let Some(api_key) = api_key {
+            request = request.header("authorization", format!("Bearer {api_key}"));
+        }
+        request.send()
+    };
+
+    let response = send(Some(&key), "claude-opus-4-6").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let echo: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(echo["echo_headers"]["x-api-key"], "sk-ant-real");
+    assert_eq!(echo["echo_headers"].get("authorization"), None);
+
+    let response = send(Some(&key), "claude-haiku-4-5").await.unwrap();
+    assert_eq!(response.status(), 403);
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(error["error"]["type"], "permission_error");
+    assert_eq!(
+        error["error"]["message"],
+        "key 'ci' may not use provider 'ollama'"
+    );
+
+    let response = send(Some("sk-croxy-unknown"), "claude-opus-4-6")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let response = send(None, "claude-opus-4-6").await.unwrap();
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test]
+async fn clients_over_their_token_quota_are_refused() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let keys_path = dir.path().join("keys.json");
+    let key = keys::create(
+        &keys_path,
+        keys::NewKey {
+            name: "intern".to_string(),
+            budget_usd: None,
+            requests_per_minute: None,
+            routes: Vec::new(),
+        },
+    )
+    .unwrap();
+    let config = format!(
+        "{}\n[clients.intern]\ndaily_tokens = 1\n",
+        make_config(&anthropic_url, &ollama_url)
+    );
+    let (proxy_url, state, _h3) =
+        start_proxy_with_keys(&config, KeyStore::open(keys_path, true)).await;
+    assert_eq!(state.metrics.quotas()[0].daily_used, 0);
+    let send = || {
+        client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .bearer_auth(&key)
+            .json(&serde_json::json!({
+                "model": "claude-opus-4-6",
+                "messages": [{"role": "user", "content": "hello there"}]
+            }))
+            .send()
+    };
+
+    let response = send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.bytes().await.unwrap();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while state.metrics.quotas()[0].daily_used == 0 {
+        assert!(tokio::time::Instant::now() < deadline, "tokens not counted");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let response = send().await.unwrap();
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().contains_key("retry-after"));
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(error["error"]["type"], "rate_limit_error");
+    assert!(
+        error["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("key 'intern' has used its daily quota of 1 tokens"),
+        "{error}"
+    );
+    assert_eq!(error["quota"]["client"], "intern");
+    assert_eq!(error["quota"]["period"], "daily");
+    assert_eq!(error["quota"]["limit_tokens"], 1);
+}
+
+#[tokio::test]
+async fn tenants_get_their_own_pins_and_see_only_their_own_status() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let keys_path = dir.path().join("keys.json");
+    let new_key = |name: &str| {
+        keys::create(
+            &keys_path,
+            keys::NewKey {
+                name: name.to_string(),
+                budget_usd: None,
+                requests_per_minute: None,
+                routes: Vec::new(),
+            },
+        )
+        .unwrap()
+    };
+    let alice = new_key("alice");
+    let bob = new_key("bob");
+    let config = format!(
+        "{}
+[admin]
+token = \"s3cret\"
+[[clients.alice.pins]]
+pattern = \"opus\"
+provider = \"ollama\"
+",
+        make_config(&anthropic_url, &ollama_url).replace(
+            "[provider.ollama]",
+            "api_key = \"sk-ant-real\"\n        [provider.ollama]",
+        )
+    );
+    let (proxy_url, state, _h3) =
+        start_proxy_with_keys(&config, KeyStore::open(keys_path, true)).await;
+    for key in [&alice, &bob] {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .bearer_auth(key)
+            .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.bytes().await.unwrap();
+    }
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while state.metrics.snapshot().len() < 2 {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "requests not recorded"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let provider = |client: &str| {
+        state
+            .metrics
+            .snapshot()
+            .into_iter()
+            .find(|r| r.client.as_deref() == Some(client))
+            .unwrap()
+            .provider
+    };
+    assert_eq!(provider("alice"), "ollama");
+    assert_eq!(provider("bob"), "anthropic");
+
+    let host = proxy_url.trim_start_matches("http://");
+    let status = croxy::control::remote_status(host, Some("s3cret"), None)
+        .await
+        .unwrap();
+    let names: Vec<&str> = status.tenants.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, ["alice", "bob"]);
+    assert!(status.body_sizes.is_some());
+
+    // A key holder sees their own usage and nothing else, whatever they ask
+    let status = croxy::control::remote_status(host, Some(&alice), Some("bob"))
+        .await
+        .unwrap();
+    assert_eq!(status.tenants.len(), 1);
+    assert_eq!(status.tenants[0].name, "alice");
+    assert_eq!(status.tenants[0].requests, 1);
+    assert_eq!(status.tenants[0].models[0].model, "claude-opus-4-6");
+    assert!(status.body_sizes.is_none());
+
+    let err = croxy::control::remote_status(host, Some("s3cret"), Some("carol"))
+        .await
+        .unwrap_err();
+    assert_eq!(err, "no virtual key named 'carol'");
+    assert!(
+        croxy::control::remote_status(host, None, Some("alice"))
+            .await
+            .is_err()
+    );
+    // The key opens nothing else on the admin API
+    let response = client()
+        .get(format!("{proxy_url}/_croxy/retention"))
+        .bearer_auth(&alice)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test]
+async fn rejected_credentials_and_commands_are_audited() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let keys_path = dir.path().join("keys.json");
+    let audit_path = dir.path().join("audit.jsonl");
+    let config = format!(
+        "{}\n[admin]\ntoken = \"s3cret\"\n[logging.audit]\nenabled = true\npath = \"{}\"\n",
+        make_config(&anthropic_url, &ollama_url),
+        audit_path.display()
+    );
+    let (proxy_url, _state, _h3) =
+        start_proxy_with_keys(&config, KeyStore::open(keys_path, true)).await;
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("authorization", "Bearer sk-croxy-unknown")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let command = serde_json::json!({"command": "set_route_enabled", "index": 0, "enabled": false});
+    let response = client()
+        .post(format!("{proxy_url}/_croxy/command"))
+        .json(&command)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let response = client()
+        .post(format!("{proxy_url}/_croxy/command"))
+        .bearer_auth("s3cret")
+        .json(&command)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(&audit_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|e| e["event"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds, vec!["auth_failed", "admin_auth_failed", "command"]);
+    assert_eq!(events[0]["path"], "/v1/messages");
+    assert_eq!(events[1]["path"], "/command");
+    assert_eq!(events[2]["command"], "set_route_enabled");
+    assert_eq!(events[2]["source"], "admin api");
+    assert_eq!(events[2]["ok"], true);
+}
+
+#[tokio::test]
+async fn captured_requests_replay_through_another_provider() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let config = format!(
+        "{}\n[capture]\nenabled = true\ndir = \"{}\"\n",
+        make_config(&anthropic_url, &ollama_url),
+        dir.path().display()
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("x-api-key", "sk-real-key")
+        .header("anthropic-version", "2023-06-01")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response.bytes().await.unwrap();
+    // The capture is saved once the streamed response finishes
+    let mut captures = Vec::new();
+    for _ in 0..50 {
+        captures = croxy::capture::recent(dir.path(), 10);
+        if !captures.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let capture = captures.pop().expect("request was captured");
+    assert_eq!(capture.path, "/v1/messages");
+    assert_eq!(capture.model(), "claude-opus-4-6");
+    assert_eq!(
+        capture.headers.get("anthropic-version").unwrap(),
+        "2023-06-01"
+    );
+    assert!(!capture.headers.contains_key("x-api-key"));
+    let original = capture.outcome.clone().unwrap();
+    assert_eq!(
+        (original.provider.as_str(), original.status),
+        ("anthropic", 200)
+    );
+
+    state.router().force_provider(Some("ollama")).unwrap();
+    let replayed = croxy::replay::replay(state.clone(), &capture, Some("qwen3:8b"), None)
+        .await
+        .unwrap();
+    assert_eq!(
+        (replayed.provider.as_str(), replayed.status),
+        ("ollama", 200)
+    );
+}
+
+#[tokio::test]
+async fn redacts_request_bodies_for_listed_providers() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let config = format!(
+        "{}\n[[middleware]]\ntype = \"redact\"\npattern = \"sk-[a-z0-9]{{8,}}\"\nproviders = [\"anthropic\"]\n",
+        make_config(&anthropic_url, &ollama_url)
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    for model in ["claude-opus-4-6", "claude-sonnet-4-5"] {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": model,
+                "messages": [{"role": "user", "content": "my key is sk-abc123def456"}]
+            }))
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        let expected = if model.contains("opus") {
+            "my key is [REDACTED]"
+        } else {
+            "my key is sk-abc123def456"
+        };
+        assert_eq!(body["echo_body"]["messages"][0]["content"], expected);
+    }
+
+    let snap = state.metrics.snapshot();
+    let redactions: Vec<_> = snap
+        .iter()
+        .map(|r| (r.provider.as_str(), r.redactions))
+        .collect();
+    assert_eq!(redactions, vec![("anthropic", 1), ("ollama", 0)]);
+}
+
+#[cfg(feature = "scripting")]
+#[tokio::test]
+async fn scripts_can_override_the_route() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("policy.lua");
+    std::fs::write(
+        &script,
+        r#"
+        function on_request(request)
+            if request.headers["x-team"] == "research" then
+                return { provider = "ollama", model = "qwen3:8b" }
+            end
+        end
+        "#,
+    )
+    .unwrap();
+    let config = format!(
+        "{}\n[script]\npath = \"{}\"\n",
+        make_config(&anthropic_url, &ollama_url),
+        script.display()
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    for team in ["research", "platform"] {
+        let response = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .header("x-team", team)
+            .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = response.json().await.unwrap();
+        let expected = if team == "research" {
+            "qwen3:8b"
+        } else {
+            "claude-opus-4-6"
+        };
+        assert_eq!(body["echo_body"]["model"], expected);
+    }
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap[0].provider, "ollama");
+    assert_eq!(snap[0].routing_method, RoutingMethod::Script);
+    assert_eq!(snap[1].provider, "anthropic");
+}
+
+#[tokio::test]
+async fn compared_requests_are_also_sent_to_the_candidate() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let config = format!(
+        "{}\n[[compare]]\npattern = \"opus\"\nprovider = \"ollama\"\nmodel = \"qwen3:8b\"\n",
+        make_config(&anthropic_url, &ollama_url)
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("x-api-key", "sk-real-key")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": [], "stream": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["echo_body"]["model"], "claude-opus-4-6");
+
+    // The comparison is recorded once both responses are in
+    let mut comparisons = Vec::new();
+    for _ in 0..50 {
+        comparisons = state.metrics.comparisons();
+        if !comparisons.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let comparison = comparisons.pop().expect("request was compared");
+    assert_eq!(comparison.model, "claude-opus-4-6");
+    assert_eq!(
+        (
+            comparison.primary.provider.as_str(),
+            comparison.primary.status
+        ),
+        ("anthropic", 200)
+    );
+    assert_eq!(
+        (
+            comparison.candidate.provider.as_str(),
+            comparison.candidate.model.as_str(),
+            comparison.candidate.status
+        ),
+        ("ollama", "qwen3:8b", 200)
+    );
+    // Only the primary's request is counted as traffic
+    assert_eq!(state.metrics.snapshot().len(), 1);
+}
+
+// --- Auto-router integration tests ---
+
+/// Starts a mock auto-router that always returns the given route name.
+async fn start_mock_auto_router(route_name: &str) -> (String, AbortOnDrop) {
+    let response_body = serde_json::json!({
+        "choices": [{"message": {"content": format!(r#"{{"route": "{route_name}"}}"#)}}]
+    });
+    let body_bytes = serde_json::to_vec(&response_body).unwrap();
+
+    let app = AxumRouter::new().fallback(any(move |_req: Request| {
+        let body_bytes = body_bytes.clone();
+        async move {
+            let mut response = Response::new(Body::from(body_bytes));
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let url = format!("http://{addr}/v1/chat/completions");
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, AbortOnDrop(handle))
+}
+
+/// Starts a mock auto-router that returns HTTP 500.
+async fn start_failing_auto_router() -> (String, AbortOnDrop) {
+    let app = AxumRouter::new().fallback(any(|_req: Request| async {
+        let mut response = Response::new(Body::from("internal error"));
+        *response.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let url = format!("http://{addr}/v1/chat/completions");
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, AbortOnDrop(handle))
+}
+
+fn auto_router_config(provider_url: &str, auto_router_url: &str) -> String {
+    format!(
+        r#"
+        [server]
+        [auto_router]
+        enabled = true
+        url = "{auto_router_url}"
+        model = "test-router"
+        timeout_ms = 2000
+        [provider.coding_provider]
+        url = "{provider_url}"
+        [provider.fallback]
+        url = "{provider_url}"
+        [[routes]]
+        name = "coding"
+        description = "Code generation and programming tasks"
+        provider = "coding_provider"
+        model = "coding-model-v1"
+        [[routes]]
+        name = "writing"
+        description = "Creative writing and content drafting"
+        pattern = "writer-.*"
+        provider = "fallback"
+        model = "writing-model-v1"
+        [default]
+        provider = "fallback"
+        "#
+    )
+}
+
+#[tokio::test]
+async fn auto_routes_to_classified_provider() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (router_url, _h2) = start_mock_auto_router("coding").await;
+    let (proxy_url, state, _h3) =
+        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+
+    let resp: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({
+            "model": "auto",
+            "messages": [{"role": "user", "content": "write a function"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Model should be rewritten to the coding route's model
+    assert_eq!(
+        resp["echo_body"]["model"].as_str().unwrap(),
+        "coding-model-v1"
+    );
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].routing_method, RoutingMethod::Auto);
+    assert_eq!(snap[0].provider, "coding_provider");
+}
+
+#[tokio::test]
+async fn classification_is_reused_for_count_tokens_and_tool_results() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (router_url, _h2) = start_mock_auto_router("coding").await;
+    let (proxy_url, state, _h3) =
+        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+
+    let opening = serde_json::json!({"role": "user", "content": "write a function"});
+    let send = |path: &str, messages: serde_json::Value| {
+        client()
+            .post(format!("{proxy_url}{path}"))
+            .header("content-type", "application/json")
+            .json(&serde_json::json!({"model": "auto", "messages": messages}))
+            .send()
+    };
+
+    // Nothing to reuse yet, so count_tokens goes to the default
+    send("/v1/messages/count_tokens", serde_json::json!([opening]))
+        .await
+        .unwrap();
+    assert_eq!(state.metrics.classifications_skipped(), 1);
+
+    send("/v1/messages", serde_json::json!([opening]))
+        .await
+        .unwrap();
+    assert_eq!(state.metrics.classifications_skipped(), 1);
+
+    let tool_turn = serde_json::json!([
+        opening,
+        {"role": "assistant", "content": [{"type": "tool_use", "id": "t1", "name": "ls", "input": {}}]},
+        {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "t1", "content": "src"}]}
+    ]);
+    send("/v1/messages", tool_turn.clone()).await.unwrap();
+    send("/v1/messages/count_tokens", tool_turn).await.unwrap();
+    assert_eq!(state.metrics.classifications_skipped(), 3);
+
+    let providers: Vec<_> = state
+        .metrics
+        .snapshot()
+        .iter()
+        .map(|r| (r.provider.clone(), r.routing_method))
+        .collect();
+    assert_eq!(
+        providers,
+        vec![
+            ("fallback".to_string(), RoutingMethod::Default),
+            ("coding_provider".to_string(), RoutingMethod::Auto),
+            ("coding_provider".to_string(), RoutingMethod::Auto),
+            ("coding_provider".to_string(), RoutingMethod::Auto),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn auto_falls_through_to_default_on_router_failure() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (router_url, _h2) = start_failing_auto_router().await;
+    let (proxy_url, state, _h3) =
+        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+
+    let resp: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({
+            "model": "auto",
+            "messages": [{"role": "user", "content": "hello"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Should still get a 200 from the default provider
+    assert!(resp.get("echo_method").is_some());
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].routing_method, RoutingMethod::Default);
+    assert_eq!(snap[0].provider, "fallback");
+}
+
+#[tokio::test]
+async fn auto_falls_through_when_router_returns_other() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (router_url, _h2) = start_mock_auto_router("other").await;
+    let (proxy_url, state, _h3) =
+        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+
+    let _resp: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({
+            "model": "auto",
+            "messages": [{"role": "user", "content": "what time is it"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].routing_method, RoutingMethod::Default);
+}
+
+#[tokio::test]
+async fn auto_falls_through_when_router_unreachable() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    // Point to a port where nothing is listening
+    let (proxy_url, state, _h2) = start_proxy(&auto_router_config(
+        &provider_url,
+        "http://127.0.0.1:1/v1/chat/completions",
+    ))
+    .await;
+
+    let resp: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({
+            "model": "auto",
+            "messages": [{"role": "user", "content": "hello"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert!(resp.get("echo_method").is_some());
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].routing_method, RoutingMethod::Default);
+}
+
+#[tokio::test]
+async fn pattern_route_still_works_with_auto_router_enabled() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (router_url, _h2) = start_mock_auto_router("coding").await;
+    let (proxy_url, state, _h3) =
+        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+
+    // "writer-pro" matches the pattern "writer-.*" on the writing route
+    let resp: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({
+            "model": "writer-pro",
+            "messages": [{"role": "user", "content": "write a poem"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Pattern match should rewrite model, not use auto-router
+    assert_eq!(
+        resp["echo_body"]["model"].as_str().unwrap(),
+        "writing-model-v1"
+    );
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].routing_method, RoutingMethod::Pattern);
+}
+
+#[tokio::test]
+async fn admin_retention_can_be_read_and_changed() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let (proxy_url, state, _h2) = start_proxy(&make_config(&provider_url, &provider_url)).await;
+
+    let resp: serde_json::Value = client()
+        .get(format!("{proxy_url}/_croxy/retention"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(resp["retention_minutes"], 30);
+    assert_eq!(resp["display_minutes"], 30);
+
+    let resp: serde_json::Value = client()
+        .put(format!("{proxy_url}/_croxy/retention"))
+        .json(&serde_json::json!({"retention_minutes": 120, "display_minutes": 15}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(resp["retention_minutes"], 120);
+    assert_eq!(resp["display_minutes"], 15);
+    assert_eq!(state.metrics.retention(), Duration::from_secs(7200));
+    assert_eq!(state.metrics.window(), Duration::from_secs(900));
+
+    let status = client()
+        .put(format!("{proxy_url}/_croxy/retention"))
+        .json(&serde_json::json!({"retention_minutes": 0}))
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, 400);
+
+    // Admin requests are never forwarded or recorded
+    assert!(state.metrics.snapshot().is_empty());
+}
+
+#[tokio::test]
+async fn admin_requires_token_when_configured() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let config = format!(
+        "{}\n[admin]\ntoken = \"s3cret\"\n",
+        make_config(&provider_url, &provider_url)
+    );
+    let (proxy_url, _state, _h2) = start_proxy(&config).await;
+
+    let status = client()
+        .get(format!("{proxy_url}/_croxy/retention"))
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, 401);
+
+    let status = client()
+        .get(format!("{proxy_url}/_croxy/retention"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn remote_attach_mirrors_metrics_stream() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let config = format!(
+        "{}\n[admin]\ntoken = \"s3cret\"\n",
+        make_config(&provider_url, &provider_url)
+    );
+    let (proxy_url, _state, _h2) = start_proxy(&config).await;
+    let host = proxy_url.trim_start_matches("http://");
+
+    let err = croxy::control::connect_remote(host, None)
+        .await
+        .expect_err("should require token");
+    assert!(err.contains("admin token"), "{err}");
+
+    let response = croxy::control::connect_remote(host, Some("s3cret"))
+        .await
+        .unwrap();
+    let mirror = Arc::new(croxy::metrics::MetricsStore::new(Duration::from_secs(60)));
+    let follow_store = mirror.clone();
+    let follow = tokio::spawn(async move {
+        croxy::control::follow_remote(response, &follow_store).await;
+    });
+
+    client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+    while mirror.snapshot().is_empty() && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let status: serde_json::Value = client()
+        .get(format!("{proxy_url}/_croxy/status"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap()
+        .json()
         .await
         .unwrap();
+    assert_eq!(status["viewers"], 1);
 
-    assert_eq!(resp["echo_method"].as_str().unwrap(), "GET");
-    assert!(resp["echo_path"].as_str().unwrap().contains("/v1/models"));
+    follow.abort();
+
+    let snap = mirror.snapshot();
+    assert_eq!(snap.len(), 1);
+    assert_eq!(snap[0].model, "claude-opus-4-6");
 }
 
-// --- Auto-router integration tests ---
+#[tokio::test]
+async fn prometheus_scrapes_latencies_in_the_configured_buckets() {
+    let (provider_url, _h1) = start_echo_provider().await;
+    let base = make_config(&provider_url, &provider_url);
+    let (proxy_url, _state, _h2) = start_proxy(&base).await;
+    let scrape = |proxy_url: String| async move {
+        client()
+            .get(format!("{proxy_url}/_croxy/metrics"))
+            .send()
+            .await
+            .unwrap()
+    };
+    assert_eq!(scrape(proxy_url).await.status(), 404);
 
-/// Starts a mock auto-router that always returns the given route name.
-async fn start_mock_auto_router(route_name: &str) -> (String, AbortOnDrop) {
-    let response_body = serde_json::json!({
-        "choices": [{"message": {"content": format!(r#"{{"route": "{route_name}"}}"#)}}]
+    let config = format!(
+        "{base}\n[telemetry.prometheus]\nenabled = true\nlatency_buckets_ms = [100, 300000]\n"
+    );
+    let (proxy_url, _state, _h3) = start_proxy(&config).await;
+    client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("content-type", "application/json")
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let response = scrape(proxy_url).await;
+    assert_eq!(response.status(), 200);
+    let text = response.text().await.unwrap();
+    assert!(
+        text.contains(r#"croxy_requests_total{provider="anthropic",status="200"} 1"#),
+        "{text}"
+    );
+    assert!(
+        text.contains(r#"croxy_request_duration_seconds_bucket{provider="anthropic",le="300"} 1"#),
+        "{text}"
+    );
+    assert!(
+        text.contains(r#"croxy_request_duration_seconds_count{provider="anthropic"} 1"#),
+        "{text}"
+    );
+}
+
+#[tokio::test]
+async fn admin_commands_override_routing() {
+    let (url_a, _h1) = start_echo_provider().await;
+    let (url_b, _h2) = start_echo_provider().await;
+    let config = format!(
+        "{}\n[admin]\ntoken = \"s3cret\"\n",
+        make_config(&url_a, &url_b)
+    );
+    let (proxy_url, state, _h3) = start_proxy(&config).await;
+
+    let send_command = |command: serde_json::Value| {
+        let proxy_url = proxy_url.clone();
+        async move {
+            client()
+                .post(format!("{proxy_url}/_croxy/command"))
+                .bearer_auth("s3cret")
+                .json(&command)
+                .send()
+                .await
+                .unwrap()
+                .json::<croxy::control::Reply>()
+                .await
+                .unwrap()
+        }
+    };
+    let send_request = |model: &'static str| {
+        let proxy_url = proxy_url.clone();
+        async move {
+            client()
+                .post(format!("{proxy_url}/v1/messages"))
+                .json(&serde_json::json!({"model": model, "messages": []}))
+                .send()
+                .await
+                .unwrap();
+        }
+    };
+
+    let reply = send_command(
+        serde_json::json!({"command": "set_route_enabled", "index": 1, "enabled": false}),
+    )
+    .await;
+    assert!(reply.ok, "{}", reply.message);
+    assert!(!reply.routing.unwrap().routes[1].enabled);
+    send_request("claude-sonnet-4-5").await;
+
+    let reply =
+        send_command(serde_json::json!({"command": "force_provider", "provider": "ollama"})).await;
+    assert!(reply.ok, "{}", reply.message);
+    assert_eq!(reply.routing.unwrap().forced.as_deref(), Some("ollama"));
+    send_request("claude-opus-4-6").await;
+
+    let reply =
+        send_command(serde_json::json!({"command": "force_provider", "provider": "missing"})).await;
+    assert!(!reply.ok);
+
+    let reply = send_command(serde_json::json!({"command": "reload"})).await;
+    assert!(!reply.ok);
+
+    let providers: Vec<String> = state
+        .metrics
+        .snapshot()
+        .iter()
+        .map(|r| r.provider.clone())
+        .collect();
+    assert_eq!(providers, vec!["anthropic", "ollama"]);
+}
+
+#[tokio::test]
+async fn commands_and_the_record_stream_need_an_admin_token() {
+    let (url_a, _h1) = start_echo_provider().await;
+    let (url_b, _h2) = start_echo_provider().await;
+    let (proxy_url, _state, _h3) = start_proxy(&make_config(&url_a, &url_b)).await;
+
+    let command = client()
+        .post(format!("{proxy_url}/_croxy/command"))
+        .json(&serde_json::json!({"command": "drain"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(command.status(), 404);
+    assert_eq!(command.text().await.unwrap(), croxy::admin::NEEDS_TOKEN);
+    let stream = client()
+        .get(format!("{proxy_url}/_croxy/stream"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), 404);
+    let error = croxy::control::connect_remote(proxy_url.trim_start_matches("http://"), None)
+        .await
+        .unwrap_err();
+    assert!(error.contains("admin.token"), "{error}");
+
+    let status = client()
+        .get(format!("{proxy_url}/_croxy/status"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(status.status(), 200);
+}
+
+/// Starts a mock Ollama that answers `/api/chat` with the model and message
+/// count it received, as NDJSON when streaming.
+async fn start_ollama_provider() -> (String, AbortOnDrop) {
+    async fn chat(axum::Json(body): axum::Json<serde_json::Value>) -> Response {
+        let text = format!(
+            "{} got {} messages",
+            body["model"].as_str().unwrap_or(""),
+            body["messages"].as_array().map_or(0, |m| m.len())
+        );
+        if body["stream"] == true {
+            let (head, tail) = text.split_at(4);
+            let lines = [
+                serde_json::json!({"message": {"role": "assistant", "content": head}, "done": false}),
+                serde_json::json!({"message": {"role": "assistant", "content": tail}, "done": false}),
+                serde_json::json!({"message": {"role": "assistant", "content": ""}, "done": true, "done_reason": "stop", "prompt_eval_count": 9, "eval_count": 5}),
+            ];
+            let ndjson: String = lines.iter().map(|l| format!("{l}\n")).collect();
+            Response::new(Body::from(ndjson))
+        } else {
+            axum::Json(serde_json::json!({
+                "message": {"role": "assistant", "content": text},
+                "done": true,
+                "done_reason": "stop",
+                "prompt_eval_count": 9,
+                "eval_count": 5
+            }))
+            .into_response()
+        }
+    }
+
+    let app = AxumRouter::new().route("/api/chat", axum::routing::post(chat));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
     });
-    let body_bytes = serde_json::to_vec(&response_body).unwrap();
+    (url, AbortOnDrop(handle))
+}
 
-    let app = AxumRouter::new().fallback(any(move |_req: Request| {
-        let body_bytes = body_bytes.clone();
+fn ollama_config(provider_url: &str) -> String {
+    format!(
+        r#"
+        [server]
+        [provider.ollama]
+        url = "{provider_url}"
+        api_format = "ollama"
+        [[routes]]
+        pattern = ".*"
+        provider = "ollama"
+        model = "qwen3-coder:30b"
+        [default]
+        provider = "ollama"
+        "#
+    )
+}
+
+#[tokio::test]
+async fn ollama_format_translates_messages() {
+    let (provider_url, _h1) = start_ollama_provider().await;
+    let (proxy_url, state, _h2) = start_proxy(&ollama_config(&provider_url)).await;
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "system": "be brief",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": "hi"}]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let message: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(message["type"], "message");
+    assert_eq!(message["model"], "claude-sonnet-4-5");
+    assert_eq!(
+        message["content"][0]["text"],
+        "qwen3-coder:30b got 2 messages"
+    );
+    assert_eq!(message["stop_reason"], "end_turn");
+
+    let snap = state.metrics.snapshot();
+    assert_eq!(snap[0].input_tokens, 9);
+    assert_eq!(snap[0].output_tokens, 5);
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages/count_tokens"))
+        .json(&serde_json::json!({"model": "claude-sonnet-4-5", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    let count: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(count["input_tokens"], 0);
+}
+
+#[tokio::test]
+async fn ollama_format_streams_sse() {
+    let (provider_url, _h1) = start_ollama_provider().await;
+    let (proxy_url, state, _h2) = start_proxy(&ollama_config(&provider_url)).await;
+
+    let resp = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "stream": true,
+            "messages": [{"role": "user", "content": "hi"}]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+    let body = resp.text().await.unwrap();
+    let text: String = body
+        .lines()
+        .filter_map(|l| l.strip_prefix("data: "))
+        .map(|d| serde_json::from_str::<serde_json::Value>(d).unwrap())
+        .filter(|e| e["type"] == "content_block_delta")
+        .map(|e| e["delta"]["text"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(text, "qwen3-coder:30b got 1 messages");
+    assert!(body.contains("event: message_stop"));
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+    while state.metrics.snapshot()[0].output_tokens == 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(state.metrics.snapshot()[0].output_tokens, 5);
+}
+
+#[tokio::test]
+async fn idle_event_streams_get_heartbeats_between_events() {
+    use futures::StreamExt;
+
+    let app = AxumRouter::new().fallback(any(|| async {
+        let chunks = [
+            (0, "event: a\ndata: 1\n\n"),
+            (1200, "event: b\ndata: "),
+            (1200, "2\n\n"),
+        ];
+        let events = futures::stream::iter(chunks).then(|(delay, chunk)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok::<_, std::convert::Infallible>(chunk)
+        });
+        let mut response = Response::new(Body::from_stream(events));
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        response
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let (proxy_url, _state, _h2) = start_proxy(&single_provider_config_with(
+        &provider_url,
+        "sse_heartbeat_secs = 1",
+    ))
+    .await;
+
+    let body = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "test", "stream": true, "messages": []}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    // The second pause is mid-event, where a comment would corrupt it
+    assert_eq!(body, "event: a\ndata: 1\n\n: ping\n\nevent: b\ndata: 2\n\n");
+}
+
+#[tokio::test]
+async fn runaway_responses_are_cut_off_at_route_limits() {
+    use futures::StreamExt;
+
+    // A model stuck in a loop: one event every 50ms, forever
+    let app = AxumRouter::new().fallback(any(|| async {
+        let events =
+            futures::stream::repeat("event: ping\ndata: {}\n\n").then(|event| async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, std::convert::Infallible>(event)
+            });
+        let mut response = Response::new(Body::from_stream(events));
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        response
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let looping_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let (echo_url, _h2) = start_echo_provider().await;
+    let (proxy_url, state, _h3) = start_proxy(&format!(
+        r#"
+        [provider.looping]
+        url = "{looping_url}"
+        [provider.echo]
+        url = "{echo_url}"
+        [[routes]]
+        pattern = "loop"
+        provider = "looping"
+        max_stream_secs = 1
+        [default]
+        provider = "echo"
+        max_response_bytes = 100
+        "#
+    ))
+    .await;
+
+    let body = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "loop", "stream": true, "messages": []}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let last = body.trim_end().rsplit("\n\n").next().unwrap();
+    assert!(last.starts_with("event: error\ndata: "), "{last}");
+    let error: serde_json::Value =
+        serde_json::from_str(last.strip_prefix("event: error\ndata: ").unwrap()).unwrap();
+    assert_eq!(error["error"]["type"], "api_error");
+
+    // A plain response can't carry an error event, so it is aborted
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "echo", "messages": [{"content": "x".repeat(500)}]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.bytes().await.is_err());
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+    let cutoffs = loop {
+        let cutoffs: Vec<_> = state
+            .metrics
+            .snapshot()
+            .iter()
+            .map(|r| r.cutoff.clone())
+            .collect();
+        if cutoffs.iter().all(Option::is_some) || tokio::time::Instant::now() > deadline {
+            break cutoffs;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(
+        cutoffs,
+        vec![
+            Some("max_stream_secs".to_string()),
+            Some("max_response_bytes".to_string())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn clients_that_go_away_mid_stream_stop_the_provider() {
+    use futures::StreamExt;
+
+    struct Dropped(Arc<AtomicBool>);
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // One event, then a long think before the next
+    let dropped = Arc::new(AtomicBool::new(false));
+    let flag = dropped.clone();
+    let app = AxumRouter::new().fallback(any(move || {
+        let guard = Dropped(flag.clone());
         async move {
-            let mut response = Response::new(Body::from(body_bytes));
+            let events = futures::stream::iter(0..2).then(move |i| {
+                let _hold = &guard;
+                async move {
+                    if i > 0 {
+                        tokio::time::sleep(Duration::from_secs(30)).await;
+                    }
+                    Ok::<_, std::convert::Infallible>("event: ping\ndata: {}\n\n")
+                }
+            });
+            let mut response = Response::new(Body::from_stream(events));
             response.headers_mut().insert(
                 http::header::CONTENT_TYPE,
-                HeaderValue::from_static("application/json"),
+                HeaderValue::from_static("text/event-stream"),
             );
             response
         }
     }));
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let addr = listener.local_addr().unwrap();
-    let url = format!("http://{addr}/v1/chat/completions");
-    let handle = tokio::spawn(async move {
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
         axum::serve(listener, app).await.unwrap();
-    });
-    (url, AbortOnDrop(handle))
+    }));
+    let (proxy_url, state, _h2) = start_proxy(&single_provider_config(&provider_url)).await;
+
+    let mut response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "slow", "stream": true, "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    let first = response.chunk().await.unwrap().unwrap();
+    assert_eq!(&first[..], b"event: ping\ndata: {}\n\n");
+    drop(response);
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while !dropped.load(Ordering::SeqCst) {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "provider still streaming"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let record = loop {
+        let record = state.metrics.snapshot().pop().unwrap();
+        if record.client_aborted.is_some() {
+            break record;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "abort not recorded");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(record.client_aborted, Some(first.len() as u64));
+    assert_eq!(state.metrics.client_aborts()["slow"], 1);
+}
+
+#[tokio::test]
+async fn response_headers_follow_the_configured_policy() {
+    let app = AxumRouter::new().fallback(any(|| async {
+        let mut response = axum::Json(serde_json::json!({"ok": true})).into_response();
+        for (name, value) in [
+            ("request-id", "req_1"),
+            ("cf-ray", "8a1b"),
+            ("anthropic-ratelimit-requests-remaining", "49"),
+        ] {
+            response
+                .headers_mut()
+                .insert(name, HeaderValue::from_static(value));
+        }
+        response
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let (proxy_url, _state, _h2) = start_proxy(&format!(
+        "{}\n[response_headers]\ndeny = [\"cf-ray\", \"anthropic-ratelimit-*\"]\nadd = {{ x-croxy-gateway = \"team\" }}\n",
+        single_provider_config_with(&provider_url, "max_body_size = 1024")
+    ))
+    .await;
+
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "test", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let headers = response.headers();
+    assert_eq!(headers["request-id"], "req_1");
+    assert_eq!(headers["content-type"], "application/json");
+    assert!(headers.get("cf-ray").is_none());
+    assert!(
+        headers
+            .get("anthropic-ratelimit-requests-remaining")
+            .is_none()
+    );
+    assert_eq!(headers["x-croxy-gateway"], "team");
+
+    // croxy's own answers carry the added headers too
+    let response = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .json(&serde_json::json!({"model": "test", "messages": [{"content": "x".repeat(2000)}]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(response.headers()["x-croxy-gateway"], "team");
+}
+
+#[tokio::test]
+async fn gemini_format_sends_key_in_query_only() {
+    async fn generate(request: Request) -> Response {
+        let text = format!(
+            "{} key={} x-api-key={}",
+            request.uri().path(),
+            request.uri().query().unwrap_or(""),
+            request.headers().contains_key("x-api-key")
+        );
+        axum::Json(serde_json::json!({
+            "candidates": [{"content": {"role": "model", "parts": [{"text": text}]}, "finishReason": "STOP"}],
+            "usageMetadata": {"promptTokenCount": 3, "candidatesTokenCount": 2}
+        }))
+        .into_response()
+    }
+    let app = AxumRouter::new().route("/v1beta/models/{*action}", axum::routing::post(generate));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+
+    let config = format!(
+        r#"
+        [server]
+        [provider.gemini]
+        url = "{provider_url}"
+        api_format = "gemini"
+        api_key = "AIza-test"
+        [[routes]]
+        pattern = ".*"
+        provider = "gemini"
+        model = "gemini-2.5-flash"
+        [default]
+        provider = "gemini"
+        "#
+    );
+    let (proxy_url, _state, _h2) = start_proxy(&config).await;
+
+    let message: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages"))
+        .header("x-api-key", "sk-ant-client")
+        .json(&serde_json::json!({
+            "model": "claude-haiku-4-5",
+            "messages": [{"role": "user", "content": "hi"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        message["content"][0]["text"],
+        "/v1beta/models/gemini-2.5-flash:generateContent key=key=AIza-test x-api-key=false"
+    );
+    assert_eq!(message["usage"]["output_tokens"], 2);
 }
 
-/// Starts a mock auto-router that returns HTTP 500.
-async fn start_failing_auto_router() -> (String, AbortOnDrop) {
-    let app = AxumRouter::new().fallback(any(|_req: Request| async {
-        let mut response = Response::new(Body::from("internal error"));
-        *response.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
-        response
-    }));
+#[tokio::test]
+async fn azure_format_uses_deployment_path_and_api_key_header() {
+    async fn complete(request: Request) -> Response {
+        let text = format!(
+            "{} {} api-key={} x-api-key={}",
+            request.uri().path(),
+            request.uri().query().unwrap_or(""),
+            request
+                .headers()
+                .get("api-key")
+                .map_or("", |v| v.to_str().unwrap()),
+            request.headers().contains_key("x-api-key")
+        );
+        axum::Json(serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": text}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 2}
+        }))
+        .into_response()
+    }
+    let app = AxumRouter::new().route(
+        "/openai/deployments/{deployment}/chat/completions",
+        axum::routing::post(complete),
+    );
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let addr = listener.local_addr().unwrap();
-    let url = format!("http://{addr}/v1/chat/completions");
-    let handle = tokio::spawn(async move {
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
         axum::serve(listener, app).await.unwrap();
-    });
-    (url, AbortOnDrop(handle))
-}
+    }));
 
-fn auto_router_config(provider_url: &str, auto_router_url: &str) -> String {
-    format!(
+    let config = format!(
         r#"
         [server]
-        [auto_router]
-        enabled = true
-        url = "{auto_router_url}"
-        model = "test-router"
-        timeout_ms = 2000
-        [provider.coding_provider]
-        url = "{provider_url}"
-        [provider.fallback]
+        [provider.azure]
         url = "{provider_url}"
+        api_format = "azure"
+        api_key = "azure-test"
+        api_version = "2024-06-01"
         [[routes]]
-        name = "coding"
-        description = "Code generation and programming tasks"
-        provider = "coding_provider"
-        model = "coding-model-v1"
-        [[routes]]
-        name = "writing"
-        description = "Creative writing and content drafting"
-        pattern = "writer-.*"
-        provider = "fallback"
-        model = "writing-model-v1"
+        pattern = ".*"
+        provider = "azure"
+        model = "gpt-4o-mini"
         [default]
-        provider = "fallback"
+        provider = "azure"
         "#
-    )
-}
-
-#[tokio::test]
-async fn auto_routes_to_classified_provider() {
-    let (provider_url, _h1) = start_echo_provider().await;
-    let (router_url, _h2) = start_mock_auto_router("coding").await;
-    let (proxy_url, state, _h3) =
-        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+    );
+    let (proxy_url, _state, _h2) = start_proxy(&config).await;
 
-    let resp: serde_json::Value = client()
+    let message: serde_json::Value = client()
         .post(format!("{proxy_url}/v1/messages"))
-        .header("content-type", "application/json")
+        .header("x-api-key", "sk-ant-client")
         .json(&serde_json::json!({
-            "model": "auto",
-            "messages": [{"role": "user", "content": "write a function"}]
+            "model": "claude-haiku-4-5",
+            "max_tokens": 10,
+            "messages": [{"role": "user", "content": "hi"}]
         }))
         .send()
         .await
@@ -592,32 +3374,90 @@ async fn auto_routes_to_classified_provider() {
         .json()
         .await
         .unwrap();
-
-    // Model should be rewritten to the coding route's model
     assert_eq!(
-        resp["echo_body"]["model"].as_str().unwrap(),
-        "coding-model-v1"
+        message["content"][0]["text"],
+        "/openai/deployments/gpt-4o-mini/chat/completions api-version=2024-06-01 \
+         api-key=azure-test x-api-key=false"
     );
-
-    let snap = state.metrics.snapshot();
-    assert_eq!(snap.len(), 1);
-    assert_eq!(snap[0].routing_method, RoutingMethod::Auto);
-    assert_eq!(snap[0].provider, "coding_provider");
+    assert_eq!(message["model"], "claude-haiku-4-5");
+    assert_eq!(message["usage"]["output_tokens"], 2);
 }
 
 #[tokio::test]
-async fn auto_falls_through_to_default_on_router_failure() {
-    let (provider_url, _h1) = start_echo_provider().await;
-    let (router_url, _h2) = start_failing_auto_router().await;
-    let (proxy_url, state, _h3) =
-        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+async fn vertex_format_moves_model_to_url_and_uses_google_token() {
+    async fn token() -> Response {
+        axum::Json(serde_json::json!({"access_token": "ya29.test", "expires_in": 3600}))
+            .into_response()
+    }
+    async fn predict(request: Request) -> Response {
+        let path = request.uri().path().to_string();
+        let auth = request
+            .headers()
+            .get("authorization")
+            .map(|v| v.to_str().unwrap().to_string());
+        let api_key = request.headers().contains_key("x-api-key");
+        let body = axum::body::to_bytes(request.into_body(), 1 << 20)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        axum::Json(serde_json::json!({
+            "path": path,
+            "authorization": auth,
+            "x_api_key": api_key,
+            "body": body
+        }))
+        .into_response()
+    }
+    let app = AxumRouter::new()
+        .route("/token", axum::routing::post(token))
+        .route("/v1/projects/{*rest}", axum::routing::post(predict));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h1 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
 
-    let resp: serde_json::Value = client()
-        .post(format!("{proxy_url}/v1/messages"))
-        .header("content-type", "application/json")
+    let dir = tempfile::tempdir().unwrap();
+    let credentials = dir.path().join("adc.json");
+    std::fs::write(
+        &credentials,
+        serde_json::json!({
+            "type": "authorized_user",
+            "client_id": "id",
+            "client_secret": "secret",
+            "refresh_token": "refresh",
+            "token_uri": format!("{provider_url}/token")
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    let config = format!(
+        r#"
+        [server]
+        [provider.vertex]
+        url = "{provider_url}"
+        api_format = "vertex"
+        project = "my-project"
+        region = "us-east5"
+        credentials_file = "{}"
+        [[routes]]
+        pattern = ".*"
+        provider = "vertex"
+        [default]
+        provider = "vertex"
+        "#,
+        credentials.display()
+    );
+    let (proxy_url, _state, _h2) = start_proxy(&config).await;
+
+    let echoed: serde_json::Value = client()
+        .post(format!("{proxy_url}/v1/messages?beta=true"))
+        .header("x-api-key", "sk-ant-client")
         .json(&serde_json::json!({
-            "model": "auto",
-            "messages": [{"role": "user", "content": "hello"}]
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 10,
+            "messages": [{"role": "user", "content": "hi"}]
         }))
         .send()
         .await
@@ -625,29 +3465,124 @@ async fn auto_falls_through_to_default_on_router_failure() {
         .json()
         .await
         .unwrap();
+    assert_eq!(
+        echoed["path"],
+        "/v1/projects/my-project/locations/us-east5/publishers/anthropic/models/claude-sonnet-4-5@20250929:rawPredict"
+    );
+    assert_eq!(echoed["authorization"], "Bearer ya29.test");
+    assert_eq!(echoed["x_api_key"], false);
+    assert_eq!(echoed["body"]["anthropic_version"], "vertex-2023-10-16");
+    assert!(echoed["body"].get("model").is_none());
+}
 
-    // Should still get a 200 from the default provider
-    assert!(resp.get("echo_method").is_some());
+/// Starts a mock Ollama that reports a model as loaded once a generate
+/// request has asked for it. Returns the keep-alive of the last request.
+async fn start_loading_ollama() -> (String, Arc<std::sync::Mutex<Option<String>>>, AbortOnDrop) {
+    let keep_alive = Arc::new(std::sync::Mutex::new(None::<String>));
+    let seen = keep_alive.clone();
+    let app = AxumRouter::new()
+        .route(
+            "/api/ps",
+            any({
+                let seen = seen.clone();
+                move || {
+                    let loaded = seen.lock().unwrap().is_some();
+                    async move {
+                        let models = if loaded {
+                            serde_json::json!([{"name": "llama3.2:latest"}])
+                        } else {
+                            serde_json::json!([])
+                        };
+                        axum::Json(serde_json::json!({"models": models}))
+                    }
+                }
+            }),
+        )
+        .route(
+            "/api/generate",
+            any(move |axum::Json(body): axum::Json<serde_json::Value>| {
+                let seen = seen.clone();
+                async move {
+                    *seen.lock().unwrap() = body["keep_alive"].as_str().map(str::to_string);
+                    axum::Json(serde_json::json!({"model": body["model"], "done": true}))
+                }
+            }),
+        );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (url, keep_alive, AbortOnDrop(handle))
+}
 
-    let snap = state.metrics.snapshot();
-    assert_eq!(snap.len(), 1);
-    assert_eq!(snap[0].routing_method, RoutingMethod::Default);
-    assert_eq!(snap[0].provider, "fallback");
+#[tokio::test]
+async fn warm_up_pings_load_the_model_and_keep_it_loaded() {
+    let (provider_url, keep_alive, _h1) = start_loading_ollama().await;
+    let config_str = format!(
+        r#"
+        [server]
+        [provider.ollama]
+        url = "{provider_url}"
+        api_format = "ollama"
+        warm_interval_secs = 1
+        [[routes]]
+        pattern = ".*"
+        provider = "ollama"
+        model = "llama3.2"
+        [default]
+        provider = "ollama"
+        "#
+    );
+    let config: Config = Figment::new()
+        .merge(Toml::string(&config_str))
+        .extract()
+        .unwrap();
+    let (_proxy_url, state, _h2) = start_proxy(&config_str).await;
+    let warming = AbortOnDrop(tokio::spawn(croxy::warm::run(
+        state.clone(),
+        croxy::warm::targets(&config),
+    )));
+
+    let mut pings = Vec::new();
+    for _ in 0..100 {
+        if let Some(warmth) = state.metrics.warmth().pop()
+            && pings.last() != Some(&warmth)
+        {
+            pings.push(warmth);
+            if pings.len() == 2 {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    drop(warming);
+
+    assert_eq!(pings.len(), 2, "{pings:?}");
+    assert_eq!(pings[0].model, "llama3.2");
+    assert_eq!(pings[0].error, None);
+    assert_eq!(pings[0].was_loaded, Some(false));
+    assert_eq!(pings[1].was_loaded, Some(true));
+    assert!(pings[1].is_warm(chrono::Utc::now()));
+    assert_eq!(keep_alive.lock().unwrap().as_deref(), Some("2s"));
 }
 
 #[tokio::test]
-async fn auto_falls_through_when_router_returns_other() {
+async fn route_sampling_overrides_replace_the_clients() {
     let (provider_url, _h1) = start_echo_provider().await;
-    let (router_url, _h2) = start_mock_auto_router("other").await;
-    let (proxy_url, state, _h3) =
-        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+    let config = single_provider_config(&provider_url).replace(
+        "pattern = \".*\"",
+        "pattern = \".*\"\n        temperature = 0.2\n        top_k = 40",
+    );
+    let (proxy_url, _state, _h2) = start_proxy(&config).await;
 
-    let _resp: serde_json::Value = client()
+    let echoed: serde_json::Value = client()
         .post(format!("{proxy_url}/v1/messages"))
-        .header("content-type", "application/json")
         .json(&serde_json::json!({
-            "model": "auto",
-            "messages": [{"role": "user", "content": "what time is it"}]
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 10,
+            "temperature": 1.0,
+            "messages": [{"role": "user", "content": "hi"}]
         }))
         .send()
         .await
@@ -655,71 +3590,327 @@ async fn auto_falls_through_when_router_returns_other() {
         .json()
         .await
         .unwrap();
+    assert_eq!(echoed["echo_body"]["temperature"], 0.2);
+    assert_eq!(echoed["echo_body"]["top_k"], 40);
+    assert_eq!(echoed["echo_body"]["max_tokens"], 10);
+}
 
-    let snap = state.metrics.snapshot();
-    assert_eq!(snap.len(), 1);
-    assert_eq!(snap[0].routing_method, RoutingMethod::Default);
+#[tokio::test]
+async fn context_guards_trim_or_summarize_old_messages() {
+    let (echo_url, _h1) = start_echo_provider().await;
+    let app = AxumRouter::new().fallback(any(|| async {
+        axum::Json(serde_json::json!({
+            "type": "message",
+            "role": "assistant",
+            "content": [{"type": "text", "text": "they said hello"}],
+            "usage": {"input_tokens": 10, "output_tokens": 4}
+        }))
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let summary_url = format!("http://{}", listener.local_addr().unwrap());
+    let _h2 = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let config = format!(
+        r#"
+        [server]
+        [provider.local]
+        url = "{echo_url}"
+        context_window = 200
+        [provider.plain]
+        url = "{echo_url}"
+        [provider.cheap]
+        url = "{summary_url}"
+        [models.tiny]
+        context_window = 200
+        [[routes]]
+        pattern = "tiny"
+        provider = "plain"
+        context_guard = "trim"
+        [[routes]]
+        pattern = "trim"
+        provider = "local"
+        context_guard = "trim"
+        [[routes]]
+        pattern = "summarize"
+        provider = "local"
+        context_guard = "summarize"
+        [[routes]]
+        pattern = "haiku"
+        provider = "cheap"
+        [default]
+        provider = "local"
+        [context_guard]
+        summary_provider = "cheap"
+        summary_model = "claude-haiku-4-5"
+        "#
+    );
+    let (proxy_url, _state, _h3) = start_proxy(&config).await;
+
+    let filler = "hello ".repeat(100);
+    // `tiny`'s window comes from [models] rather than its provider
+    for model in ["trim", "summarize", "tiny"] {
+        let echoed: serde_json::Value = client()
+            .post(format!("{proxy_url}/v1/messages"))
+            .json(&serde_json::json!({
+                "model": model,
+                "max_tokens": 10,
+                "messages": [
+                    {"role": "user", "content": filler},
+                    {"role": "assistant", "content": filler},
+                    {"role": "user", "content": "what did I say?"}
+                ]
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let messages = echoed["echo_body"]["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1, "{model}: {messages:?}");
+        match model {
+            "summarize" => assert_eq!(
+                messages[0]["content"],
+                serde_json::json!([
+                    {"type": "text", "text": "Summary of the earlier conversation:\nthey said hello"},
+                    {"type": "text", "text": "what did I say?"}
+                ])
+            ),
+            _ => assert_eq!(messages[0]["content"], "what did I say?"),
+        }
+    }
 }
 
 #[tokio::test]
-async fn auto_falls_through_when_router_unreachable() {
-    let (provider_url, _h1) = start_echo_provider().await;
-    // Point to a port where nothing is listening
-    let (proxy_url, state, _h2) = start_proxy(&auto_router_config(
-        &provider_url,
-        "http://127.0.0.1:1/v1/chat/completions",
-    ))
-    .await;
+async fn routes_to_models_ollama_lacks_are_handled_as_configured() {
+    let (ollama_url, models, _h1) = start_model_server(&["qwen3:8b", "llama3:latest"]).await;
+    let config = |action: &str| {
+        format!(
+            r#"
+            [provider.anthropic]
+            url = "http://127.0.0.1:1"
+            [provider.ollama]
+            url = "{ollama_url}"
+            missing_models = "{action}"
+            [[routes]]
+            pattern = "sonnet"
+            provider = "ollama"
+            model = "qwen3-coder:30b"
+            [[routes]]
+            pattern = "haiku"
+            provider = "ollama"
+            model = "llama3"
+            [default]
+            provider = "anthropic"
+            "#
+        )
+    };
+    let target = |config: &str| {
+        let config: Config = Figment::new()
+            .merge(Toml::string(config))
+            .extract()
+            .unwrap();
+        model_check::targets(&config).remove(0)
+    };
+    let enabled = |state: &AppState| {
+        state
+            .router()
+            .routes()
+            .iter()
+            .map(|route| route.enabled)
+            .collect::<Vec<_>>()
+    };
 
-    let resp: serde_json::Value = client()
-        .post(format!("{proxy_url}/v1/messages"))
-        .header("content-type", "application/json")
-        .json(&serde_json::json!({
-            "model": "auto",
-            "messages": [{"role": "user", "content": "hello"}]
-        }))
+    let fallback = config("fallback");
+    let (_url, state, _h2) = start_proxy(&fallback).await;
+    let mut checker = Checker::new(target(&fallback));
+    checker.check(&state).await;
+    assert_eq!(enabled(&state), [false, true]);
+    models.lock().unwrap().push("qwen3-coder:30b".to_string());
+    checker.check(&state).await;
+    assert_eq!(enabled(&state), [true, true]);
+
+    models.lock().unwrap().pop();
+    let pull = config("pull");
+    let (_url, state, _h3) = start_proxy(&pull).await;
+    Checker::new(target(&pull)).check(&state).await;
+    assert_eq!(enabled(&state), [true, true]);
+    assert_eq!(
+        models.lock().unwrap().last().map(String::as_str),
+        Some("qwen3-coder:30b")
+    );
+}
+
+#[tokio::test]
+async fn embedded_server_serves_until_shut_down() {
+    let (provider_url, _provider) = start_echo_provider().await;
+    let config: Config = Figment::new()
+        .merge(Toml::string(&single_provider_config(&provider_url)))
+        .extract()
+        .unwrap();
+    let server = Server::builder(&config).build().unwrap();
+    let metrics = server.state().metrics.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = server.serve(listener).unwrap();
+    let url = format!("http://{}", handle.local_addr());
+
+    let response = client()
+        .post(format!("{url}/v1/messages"))
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
         .send()
         .await
-        .unwrap()
-        .json()
-        .await
         .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(metrics.snapshot().len(), 1);
 
-    assert!(resp.get("echo_method").is_some());
-    let snap = state.metrics.snapshot();
-    assert_eq!(snap.len(), 1);
-    assert_eq!(snap[0].routing_method, RoutingMethod::Default);
+    handle.shutdown().await.unwrap();
+    assert!(client().get(format!("{url}/health")).send().await.is_err());
 }
 
 #[tokio::test]
-async fn pattern_route_still_works_with_auto_router_enabled() {
-    let (provider_url, _h1) = start_echo_provider().await;
-    let (router_url, _h2) = start_mock_auto_router("coding").await;
-    let (proxy_url, state, _h3) =
-        start_proxy(&auto_router_config(&provider_url, &router_url)).await;
+async fn http2_clients_multiplex_requests_over_one_connection() {
+    let app = AxumRouter::new().fallback(any(|| async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        "done"
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider_url = format!("http://{}", listener.local_addr().unwrap());
+    let _provider = AbortOnDrop(tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    }));
+    let config: Config = Figment::new()
+        .merge(Toml::string(&single_provider_config(&provider_url)))
+        .extract()
+        .unwrap();
+    let server = Server::builder(&config).build().unwrap();
+    let metrics = server.state().metrics.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let handle = server.serve(listener).unwrap();
+    let url = format!("http://{}", handle.local_addr());
 
-    // "writer-pro" matches the pattern "writer-.*" on the writing route
-    let resp: serde_json::Value = client()
+    let h2 = reqwest::Client::builder()
+        .no_proxy()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    let send = || async {
+        let response = h2
+            .post(format!("{url}/v1/messages"))
+            .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        response.text().await.unwrap()
+    };
+    let watch = async {
+        loop {
+            let connections = metrics.connections();
+            if connections.iter().any(|c| c.streams == 2) {
+                break connections;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    let (first, second, connections) = tokio::join!(send(), send(), watch);
+    assert_eq!((first.as_str(), second.as_str()), ("done", "done"));
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].http, "HTTP/2");
+    assert_eq!(connections[0].peer, Some([127, 0, 0, 1].into()));
+    assert!(metrics.connections().is_empty());
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn access_log_has_a_line_per_request() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+    let (provider_url, _provider) = start_echo_provider().await;
+    let config = format!(
+        "{}\n[logging.access]\nenabled = true\nformat = \"json\"\npath = \"{}\"\n",
+        single_provider_config(&provider_url),
+        path.display()
+    );
+    let (proxy_url, _state, _proxy) = start_proxy(&config).await;
+
+    let response = client()
         .post(format!("{proxy_url}/v1/messages"))
-        .header("content-type", "application/json")
-        .json(&serde_json::json!({
-            "model": "writer-pro",
-            "messages": [{"role": "user", "content": "write a poem"}]
-        }))
+        .json(&serde_json::json!({"model": "claude-opus-4-6", "messages": []}))
         .send()
         .await
-        .unwrap()
-        .json()
-        .await
         .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.bytes().await.unwrap();
+    // The line is written as the body finishes, just after the client has it
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["method"], "POST");
+    assert_eq!(lines[0]["path"], "/v1/messages");
+    assert_eq!(lines[0]["model"], "claude-opus-4-6");
+    assert_eq!(lines[0]["provider"], "a");
+    assert_eq!(lines[0]["status"], 200);
+    assert_eq!(lines[0]["bytes"], body.len());
+}
 
-    // Pattern match should rewrite model, not use auto-router
-    assert_eq!(
-        resp["echo_body"]["model"].as_str().unwrap(),
-        "writing-model-v1"
+#[tokio::test]
+async fn routes_can_match_on_metadata_user_id() {
+    let (anthropic_url, _h1) = start_echo_provider().await;
+    let (ollama_url, _h2) = start_echo_provider().await;
+    let config = make_config(&anthropic_url, &ollama_url).replace(
+        "[[routes]]\n        pattern = \"opus\"",
+        "[[routes]]\n        pattern = \"opus\"\n        user_id = \"^user_experiments\"\n        \
+         provider = \"ollama\"\n        model = \"qwen3-coder:30b\"\n        \
+         [[routes]]\n        pattern = \"opus\"",
     );
+    let (proxy_url, _state, _h3) = start_proxy(&config).await;
 
-    let snap = state.metrics.snapshot();
-    assert_eq!(snap.len(), 1);
-    assert_eq!(snap[0].routing_method, RoutingMethod::Pattern);
+    let send = |body: serde_json::Value| {
+        let url = format!("{proxy_url}/v1/messages");
+        async move {
+            let response = client().post(url).json(&body).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            let echo: serde_json::Value = response.json().await.unwrap();
+            echo["echo_body"]["model"].as_str().unwrap().to_string()
+        }
+    };
+    let experiment = send(serde_json::json!({
+        "model": "claude-opus-4-6",
+        "messages": [],
+        "metadata": {"user_id": "user_experiments_session_7"},
+    }))
+    .await;
+    assert_eq!(experiment, "qwen3-coder:30b");
+
+    let everyone_else = send(serde_json::json!({
+        "model": "claude-opus-4-6",
+        "messages": [],
+        "metadata": {"user_id": "user_main"},
+    }))
+    .await;
+    assert_eq!(everyone_else, "claude-opus-4-6");
+    let anonymous = send(serde_json::json!({"model": "claude-opus-4-6", "messages": []})).await;
+    assert_eq!(anonymous, "claude-opus-4-6");
+
+    let upload = client()
+        .post(format!("{proxy_url}/v1/files"))
+        .header(
+            "content-type",
+            "multipart/form-data; boundary=croxy-boundary",
+        )
+        .body("--croxy-boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nhello\r\n--croxy-boundary--\r\n")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(upload.status(), 200);
+    let echo: serde_json::Value = upload.json().await.unwrap();
+    assert_eq!(echo["echo_path"], "/v1/files");
 }